@@ -22,8 +22,8 @@ use syn::{
     parse_macro_input, parse_quote,
     spanned::Spanned,
     token::Comma,
-    Attribute, FnArg, Ident, Lit, LitBool, MetaNameValue, Pat, PatType, ReturnType, Token, Type,
-    Visibility,
+    Attribute, FnArg, GenericArgument, Ident, Lit, LitBool, MetaNameValue, Pat, PatType, Path,
+    PathArguments, ReturnType, Token, Type, TypeParamBound, Visibility,
 };
 
 /// Accumulates multiple errors into a result.
@@ -87,6 +87,25 @@ impl Parse for Service {
                     )
                 );
             }
+            if rpc.args.iter().any(|arg| stream_item(&arg.ty).is_some()) {
+                let item_variant =
+                    format!("{}Item", snake_to_camel(&rpc.ident.unraw().to_string()));
+                for other in &rpcs {
+                    if snake_to_camel(&other.ident.unraw().to_string()) == item_variant {
+                        extend_errors!(
+                            ident_errors,
+                            syn::Error::new(
+                                other.ident.span(),
+                                format!(
+                                    "method name conflicts with generated variant \
+                                     `{}Request::{item_variant}`",
+                                    ident.unraw()
+                                )
+                            )
+                        );
+                    }
+                }
+            }
         }
         ident_errors?;
 
@@ -128,8 +147,18 @@ impl Parse for RpcMethod {
                 }
             }
         }
+        let stream_args = args
+            .iter()
+            .filter(|arg| stream_item(&arg.ty).is_some())
+            .collect::<Vec<_>>();
+        for arg in stream_args.iter().skip(1) {
+            extend_errors!(
+                errors,
+                syn::Error::new(arg.span(), "RPCs can take at most one stream arg")
+            );
+        }
+        let output: ReturnType = input.parse()?;
         errors?;
-        let output = input.parse()?;
         input.parse::<Token![;]>()?;
 
         Ok(Self {
@@ -266,6 +295,13 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         None
     };
 
+    let return_types = &rpcs
+        .iter()
+        .map(|rpc| match rpc.output {
+            ReturnType::Type(_, ref ty) => ty,
+            ReturnType::Default => unit_type,
+        })
+        .collect::<Vec<_>>();
     let methods = rpcs.iter().map(|rpc| &rpc.ident).collect::<Vec<_>>();
     let request_names = methods
         .iter()
@@ -279,6 +315,7 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         client_ident: &format_ident!("{}Client", ident),
         request_ident: &format_ident!("{}Request", ident),
         response_ident: &format_ident!("{}Response", ident),
+        methods_mod_ident: &format_ident!("{}_methods", camel_to_snake(&ident.unraw().to_string())),
         vis,
         args,
         method_attrs: &rpcs.iter().map(|rpc| &*rpc.attrs).collect::<Vec<_>>(),
@@ -286,11 +323,24 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         request_names: &request_names,
         attrs,
         rpcs,
-        return_types: &rpcs
+        return_types,
+        stream_items: &return_types
+            .iter()
+            .map(|ty| stream_item(ty))
+            .collect::<Vec<_>>(),
+        request_args: &args
+            .iter()
+            .map(|args| {
+                args.iter()
+                    .filter(|arg| stream_item(&arg.ty).is_none())
+                    .collect()
+            })
+            .collect::<Vec<_>>(),
+        stream_args: &args
             .iter()
-            .map(|rpc| match rpc.output {
-                ReturnType::Type(_, ref ty) => ty,
-                ReturnType::Default => unit_type,
+            .map(|args| {
+                args.iter()
+                    .find_map(|arg| Some((&*arg.pat, stream_item(&arg.ty)?)))
             })
             .collect::<Vec<_>>(),
         arg_pats: &args
@@ -308,6 +358,193 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
     .into()
 }
 
+struct ServiceGroup {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    ident: Ident,
+    members: Vec<GroupMember>,
+}
+
+struct GroupMember {
+    attrs: Vec<Attribute>,
+    ident: Ident,
+    service: Path,
+}
+
+impl Parse for ServiceGroup {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<Token![struct]>()?;
+        let ident: Ident = input.parse()?;
+        let content;
+        let brace = braced!(content in input);
+        let members: Vec<GroupMember> = content
+            .parse_terminated::<GroupMember, Comma>(GroupMember::parse)?
+            .into_iter()
+            .collect();
+        if members.is_empty() {
+            return Err(syn::Error::new(
+                brace.span,
+                "a service group needs at least one service",
+            ));
+        }
+
+        Ok(Self {
+            attrs,
+            vis,
+            ident,
+            members,
+        })
+    }
+}
+
+impl Parse for GroupMember {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let service = input.parse()?;
+
+        Ok(Self {
+            attrs,
+            ident,
+            service,
+        })
+    }
+}
+
+/// Returns the path of the item generated for the service at `service`, e.g. `foo::BarRequest`
+/// for `foo::Bar` and suffix `Request`.
+fn service_item(service: &Path, suffix: &str) -> Path {
+    let mut path = service.clone();
+    let last = path.segments.last_mut().unwrap();
+    last.ident = format_ident!("{}{}", last.ident.unraw(), suffix);
+    path
+}
+
+/// Generates:
+/// - a struct with a field per service, holding its serving function
+/// - Request and Response enums with a variant per service
+/// - a Serve impl for the struct that dispatches each request to its service
+/// - conversions between the group's and each service's requests and responses
+#[proc_macro]
+pub fn service_group(input: TokenStream) -> TokenStream {
+    let ServiceGroup {
+        attrs,
+        vis,
+        ident,
+        members,
+    } = parse_macro_input!(input as ServiceGroup);
+    let request_ident = format_ident!("{}Request", ident);
+    let response_ident = format_ident!("{}Response", ident);
+    let derive_serialize = if cfg!(feature = "serde1") {
+        Some(quote! {
+            #[derive(tarpc::serde::Serialize, tarpc::serde::Deserialize)]
+            #[serde(crate = "tarpc::serde")]
+        })
+    } else {
+        None
+    };
+
+    let member_attrs = members.iter().map(|m| &m.attrs).collect::<Vec<_>>();
+    let fields = members.iter().map(|m| &m.ident).collect::<Vec<_>>();
+    let variants = members
+        .iter()
+        .map(|m| {
+            Ident::new(
+                &snake_to_camel(&m.ident.unraw().to_string()),
+                m.ident.span(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let params = (0..members.len())
+        .map(|i| format_ident!("S{}", i))
+        .collect::<Vec<_>>();
+    let member_requests = members
+        .iter()
+        .map(|m| service_item(&m.service, "Request"))
+        .collect::<Vec<_>>();
+    let member_responses = members
+        .iter()
+        .map(|m| service_item(&m.service, "Response"))
+        .collect::<Vec<_>>();
+
+    quote! {
+        #( #attrs )*
+        #[derive(Clone, Debug)]
+        #vis struct #ident<#( #params ),*> {
+            #(
+                #( #member_attrs )*
+                pub #fields: #params,
+            )*
+        }
+
+        impl<#( #params ),*> tarpc::server::Serve for #ident<#( #params ),*>
+            where #( #params: tarpc::server::Serve<
+                Req = #member_requests,
+                Resp = #member_responses> ),*
+        {
+            type Req = #request_ident;
+            type Resp = #response_ident;
+
+            fn method(&self, req: &#request_ident) -> Option<&'static str> {
+                match req {
+                    #( #request_ident::#variants(req) => self.#fields.method(req), )*
+                }
+            }
+
+            async fn serve(self, ctx: tarpc::context::Context, req: #request_ident)
+                -> Result<#response_ident, tarpc::ServerError> {
+                match req {
+                    #(
+                        #request_ident::#variants(req) => {
+                            self.#fields.serve(ctx, req).await.map(#response_ident::#variants)
+                        }
+                    )*
+                }
+            }
+        }
+
+        /// The request sent over the wire from the client to the server.
+        #[allow(missing_docs)]
+        #[derive(Debug)]
+        #derive_serialize
+        #vis enum #request_ident {
+            #( #variants(#member_requests), )*
+        }
+
+        /// The response sent over the wire from the server to the client.
+        #[allow(missing_docs)]
+        #[derive(Debug)]
+        #derive_serialize
+        #vis enum #response_ident {
+            #( #variants(#member_responses), )*
+        }
+
+        #(
+            impl From<#member_requests> for #request_ident {
+                fn from(req: #member_requests) -> Self {
+                    #request_ident::#variants(req)
+                }
+            }
+
+            impl std::convert::TryFrom<#response_ident> for #member_responses {
+                type Error = #response_ident;
+
+                #[allow(unreachable_patterns)]
+                fn try_from(resp: #response_ident) -> Result<Self, #response_ident> {
+                    match resp {
+                        #response_ident::#variants(resp) => Ok(resp),
+                        resp => Err(resp),
+                    }
+                }
+            }
+        )*
+    }
+    .into()
+}
+
 // Things needed to generate the service items: trait, serve impl, request/response enums, and
 // the client stub.
 struct ServiceGenerator<'a> {
@@ -317,6 +554,8 @@ struct ServiceGenerator<'a> {
     client_ident: &'a Ident,
     request_ident: &'a Ident,
     response_ident: &'a Ident,
+    /// The module of the marker types that select a method for `sink_for`.
+    methods_mod_ident: &'a Ident,
     vis: &'a Visibility,
     attrs: &'a [Attribute],
     rpcs: &'a [RpcMethod],
@@ -326,6 +565,14 @@ struct ServiceGenerator<'a> {
     method_attrs: &'a [&'a [Attribute]],
     args: &'a [&'a [PatType]],
     return_types: &'a [&'a Type],
+    /// The item types of streaming methods, which return `impl Stream<Item = T>`.
+    stream_items: &'a [Option<&'a Type>],
+    /// The args sent in the request, i.e. all but the stream arg of client-streaming and duplex
+    /// methods.
+    request_args: &'a [Vec<&'a PatType>],
+    /// The stream arg and its item type, of client-streaming and duplex methods, which take an
+    /// arg of type `impl Stream<Item = T>`.
+    stream_args: &'a [Option<(&'a Pat, &'a Type)>],
     arg_pats: &'a [Vec<&'a Pat>],
     derive_serialize: Option<&'a TokenStream2>,
 }
@@ -410,9 +657,56 @@ impl<'a> ServiceGenerator<'a> {
             arg_pats,
             method_idents,
             request_names,
+            stream_items,
+            request_args,
+            stream_args,
             ..
         } = self;
 
+        let request_pats = request_args
+            .iter()
+            .map(|args| args.iter().map(|arg| &arg.pat).collect::<Vec<_>>());
+        let responses = (0..method_idents.len()).map(|i| {
+            let (camel_case_ident, method_ident) = (&camel_case_idents[i], method_idents[i]);
+            let arg_pats = &arg_pats[i];
+            let output = quote! {
+                #service_ident::#method_ident(self.service, ctx, #( #arg_pats ),*).await
+            };
+            let response = match stream_items[i] {
+                Some(_) => quote! {
+                    tarpc::server::response_sink::respond(
+                        #output,
+                        #response_ident::#camel_case_ident,
+                    ).await
+                },
+                None => quote! {
+                    Ok(#response_ident::#camel_case_ident(#output))
+                },
+            };
+            match stream_args[i] {
+                Some((stream_pat, _)) => {
+                    let item_ident = format_ident!("{}Item", camel_case_ident);
+                    quote! {
+                        let #stream_pat = tarpc::server::request_stream::items(|req| match req {
+                            #request_ident::#item_ident(item) => Some(item),
+                            _ => None,
+                        })?;
+                        #response
+                    }
+                }
+                None => response,
+            }
+        });
+        let (item_idents, item_request_names): (Vec<_>, Vec<_>) = camel_case_idents
+            .iter()
+            .zip(request_names)
+            .zip(stream_args)
+            .filter(|(_, stream_arg)| stream_arg.is_some())
+            .map(|((camel_case_ident, request_name), _)| {
+                (format_ident!("{}Item", camel_case_ident), request_name)
+            })
+            .unzip();
+
         quote! {
             impl<S> tarpc::server::Serve for #server_ident<S>
                 where S: #service_ident
@@ -427,6 +721,11 @@ impl<'a> ServiceGenerator<'a> {
                                 #request_names
                             }
                         )*
+                        #(
+                            #request_ident::#item_idents(..) => {
+                                #item_request_names
+                            }
+                        )*
                     })
                 }
 
@@ -434,11 +733,15 @@ impl<'a> ServiceGenerator<'a> {
                     -> Result<#response_ident, tarpc::ServerError> {
                     match req {
                         #(
-                            #request_ident::#camel_case_idents{ #( #arg_pats ),* } => {
-                                Ok(#response_ident::#camel_case_idents(
-                                    #service_ident::#method_idents(
-                                        self.service, ctx, #( #arg_pats ),*
-                                    ).await
+                            #request_ident::#camel_case_idents{ #( #request_pats ),* } => {
+                                #responses
+                            }
+                        )*
+                        #(
+                            #request_ident::#item_idents(..) => {
+                                Err(tarpc::ServerError::new(
+                                    std::io::ErrorKind::InvalidInput,
+                                    "A stream item was sent as a request.".into(),
                                 ))
                             }
                         )*
@@ -454,17 +757,75 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             request_ident,
             camel_case_idents,
-            args,
+            request_args,
+            stream_args,
             ..
         } = self;
 
+        // The items of a client-streaming method are sent after its request, one per message.
+        let (item_idents, item_types): (Vec<_>, Vec<&Type>) = camel_case_idents
+            .iter()
+            .zip(stream_args)
+            .filter_map(|(camel_case_ident, stream_arg)| {
+                let (_, item) = (*stream_arg)?;
+                Some((format_ident!("{}Item", camel_case_ident), item))
+            })
+            .unzip();
+
         quote! {
             /// The request sent over the wire from the client to the server.
             #[allow(missing_docs)]
             #[derive(Debug)]
             #derive_serialize
             #vis enum #request_ident {
-                #( #camel_case_idents{ #( #args ),* } ),*
+                #( #camel_case_idents{ #( #request_args ),* }, )*
+                #( #[doc(hidden)] #item_idents(#item_types), )*
+            }
+        }
+    }
+
+    fn impl_schema(&self) -> TokenStream2 {
+        let &Self {
+            vis,
+            service_ident,
+            request_ident,
+            method_idents,
+            args,
+            return_types,
+            ..
+        } = self;
+        let service_name = service_ident.unraw().to_string();
+        let ids = 0..method_idents.len() as u32;
+        let method_names = method_idents.iter().map(|ident| ident.unraw().to_string());
+        let arg_names = args.iter().map(|args| {
+            args.iter()
+                .map(|arg| tokens_to_string(&arg.pat))
+                .collect::<Vec<_>>()
+        });
+        let arg_types = args.iter().map(|args| {
+            args.iter()
+                .map(|arg| tokens_to_string(&arg.ty))
+                .collect::<Vec<_>>()
+        });
+        let return_types = return_types.iter().map(tokens_to_string);
+
+        quote! {
+            impl #request_ident {
+                /// Returns the wire format of the service, to check that a new version of the
+                /// service is compatible with an old one.
+                #[allow(unused)]
+                #vis fn schema() -> tarpc::schema::ServiceSchema {
+                    tarpc::schema::ServiceSchema::new(#service_name, vec![
+                        #(
+                            tarpc::schema::MethodSchema::new(
+                                #ids,
+                                #method_names,
+                                vec![ #( (#arg_names, #arg_types) ),* ],
+                                #return_types,
+                            )
+                        ),*
+                    ])
+                }
             }
         }
     }
@@ -476,16 +837,25 @@ impl<'a> ServiceGenerator<'a> {
             response_ident,
             camel_case_idents,
             return_types,
+            stream_items,
             ..
         } = self;
 
+        // The responses to a streaming method carry one item each, and the last carries none.
+        let response_types = return_types.iter().zip(stream_items).map(
+            |(return_type, stream_item)| match stream_item {
+                Some(item) => quote!(Option<#item>),
+                None => quote!(#return_type),
+            },
+        );
+
         quote! {
             /// The response sent over the wire from the server to the client.
             #[allow(missing_docs)]
             #[derive(Debug)]
             #derive_serialize
             #vis enum #response_ident {
-                #( #camel_case_idents(#return_types) ),*
+                #( #camel_case_idents(#response_types) ),*
             }
         }
     }
@@ -516,8 +886,16 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             request_ident,
             response_ident,
+            service_ident,
+            server_ident,
+            methods_mod_ident,
             ..
         } = self;
+        let sink_for_doc = format!(
+            " Returns a sink of calls to the method `M`, one of the marker types in \
+             [`{methods_mod_ident}`], that is ready while the channel has room for more requests \
+             in flight. See [`CallSink`](tarpc::client::sink::CallSink)."
+        );
 
         quote! {
             impl #client_ident {
@@ -549,6 +927,27 @@ impl<'a> ServiceGenerator<'a> {
                 }
 
             }
+
+            impl #client_ident {
+                #[doc = #sink_for_doc]
+                #[allow(unused)]
+                #vis fn sink_for<M>(&self) -> tarpc::client::sink::CallSink<M>
+                where
+                    M: tarpc::client::sink::Method<Req = #request_ident, Resp = #response_ident>
+                {
+                    tarpc::client::sink::CallSink::new(self.0.clone())
+                }
+            }
+
+            impl<S> #client_ident<tarpc::client::stub::local::Local<#server_ident<S>>>
+                where S: #service_ident + Clone
+            {
+                /// Returns a new client stub that calls `service` in process, without a
+                /// transport. See [`Local`](tarpc::client::stub::local::Local).
+                #vis fn in_process(service: S) -> Self {
+                    #client_ident(tarpc::client::stub::local::Local::new(service.serve()))
+                }
+            }
         }
     }
 
@@ -563,11 +962,102 @@ impl<'a> ServiceGenerator<'a> {
             request_names,
             args,
             return_types,
-            arg_pats,
+            stream_items,
+            request_args,
+            stream_args,
             camel_case_idents,
             ..
         } = self;
 
+        let methods = (0..method_idents.len()).map(|i| {
+            let (method_ident, args) = (method_idents[i], args[i]);
+            let arg_pats = request_args[i].iter().map(|arg| &arg.pat).collect::<Vec<_>>();
+            let (camel_case_ident, request_name) = (&camel_case_idents[i], &request_names[i]);
+            let return_type = return_types[i];
+            if let (Some((stream_pat, _)), Some(item)) = (stream_args[i], stream_items[i]) {
+                let item_ident = format_ident!("{}Item", camel_case_ident);
+                return quote! {
+                    #vis async fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> Result<
+                            impl tarpc::futures::Stream<
+                                Item = Result<#item, tarpc::client::RpcError>
+                            >,
+                            tarpc::client::RpcError,
+                        >
+                    where
+                        Stub: tarpc::client::stub::StreamStub
+                    {
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                        let items = tarpc::futures::StreamExt::map(
+                            #stream_pat,
+                            #request_ident::#item_ident,
+                        );
+                        let resp = self.0.call_duplex(ctx, #request_name, request, items).await?;
+                        Ok(resp.items(|resp| match resp {
+                            #response_ident::#camel_case_ident(item) => item,
+                            _ => unreachable!(),
+                        }))
+                    }
+                };
+            }
+            if let Some((stream_pat, _)) = stream_args[i] {
+                let item_ident = format_ident!("{}Item", camel_case_ident);
+                return quote! {
+                    #vis async fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> Result<#return_type, tarpc::client::RpcError>
+                    where
+                        Stub: tarpc::client::stub::StreamStub
+                    {
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                        let items = tarpc::futures::StreamExt::map(
+                            #stream_pat,
+                            #request_ident::#item_ident,
+                        );
+                        match self.0.call_with_stream(ctx, #request_name, request, items).await? {
+                            #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                            _ => unreachable!(),
+                        }
+                    }
+                };
+            }
+            match stream_items[i] {
+                Some(item) => quote! {
+                    #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> impl std::future::Future<Output = Result<
+                            impl tarpc::futures::Stream<
+                                Item = Result<#item, tarpc::client::RpcError>
+                            >,
+                            tarpc::client::RpcError,
+                        >> + '_
+                    where
+                        Stub: tarpc::client::stub::StreamStub
+                    {
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                        let resp = self.0.call_stream(ctx, #request_name, request);
+                        async move {
+                            Ok(resp.await?.items(|resp| match resp {
+                                #response_ident::#camel_case_ident(item) => item,
+                                _ => unreachable!(),
+                            }))
+                        }
+                    }
+                },
+                None => quote! {
+                    #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> impl std::future::Future<Output = Result<#return_type, tarpc::client::RpcError>> + '_ {
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                        let resp = self.0.call(ctx, #request_name, request);
+                        async move {
+                            match resp.await? {
+                                #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+                },
+            }
+        });
+
         quote! {
             impl<Stub> #client_ident<Stub>
                 where Stub: tarpc::client::stub::Stub<
@@ -577,19 +1067,92 @@ impl<'a> ServiceGenerator<'a> {
                 #(
                     #[allow(unused)]
                     #( #method_attrs )*
-                    #vis fn #method_idents(&self, ctx: tarpc::context::Context, #( #args ),*)
-                        -> impl std::future::Future<Output = Result<#return_types, tarpc::client::RpcError>> + '_ {
-                        let request = #request_ident::#camel_case_idents { #( #arg_pats ),* };
-                        let resp = self.0.call(ctx, #request_names, request);
-                        async move {
-                            match resp.await? {
-                                #response_ident::#camel_case_idents(msg) => std::result::Result::Ok(msg),
-                                _ => unreachable!(),
-                            }
+                    #methods
+                )*
+            }
+        }
+    }
+
+    fn mod_methods(&self) -> TokenStream2 {
+        let &Self {
+            vis,
+            service_ident,
+            request_ident,
+            response_ident,
+            methods_mod_ident,
+            camel_case_idents,
+            method_idents,
+            request_names,
+            args,
+            stream_items,
+            stream_args,
+            ..
+        } = self;
+
+        // Items in the module must be visible wherever the service is.
+        let marker_vis = match vis {
+            Visibility::Inherited => quote!(pub(super)),
+            Visibility::Restricted(restricted) if restricted.path.is_ident("self") => {
+                quote!(pub(super))
+            }
+            Visibility::Restricted(restricted) if restricted.path.segments[0].ident == "super" => {
+                let path = &restricted.path;
+                quote!(pub(in super::#path))
+            }
+            vis => quote!(#vis),
+        };
+        let mod_doc = format!(
+            " Marker types that select a method of [`{service_ident}`](super::{service_ident}) \
+             to make calls to through a sink."
+        );
+
+        // Methods that take or return a stream cannot be called through a sink.
+        let (markers, impls): (Vec<_>, Vec<_>) = (0..method_idents.len())
+            .filter(|&i| stream_items[i].is_none() && stream_args[i].is_none())
+            .map(|i| {
+                let camel_case_ident = &camel_case_idents[i];
+                let request_name = &request_names[i];
+                let pats = args[i].iter().map(|arg| &arg.pat).collect::<Vec<_>>();
+                let (args_type, args_pat) = match args[i] {
+                    [arg] => {
+                        let (ty, pat) = (&arg.ty, &arg.pat);
+                        (quote!(#ty), quote!(#pat))
+                    }
+                    args => {
+                        let tys = args.iter().map(|arg| &arg.ty);
+                        (quote!(( #( #tys ),* )), quote!(( #( #pats ),* )))
+                    }
+                };
+                let doc = format!(" Selects the `{}` method.", method_idents[i].unraw());
+                let marker = quote! {
+                    #[doc = #doc]
+                    #[derive(Clone, Copy, Debug)]
+                    #marker_vis struct #camel_case_ident;
+                };
+                let method_impl = quote! {
+                    impl tarpc::client::sink::Method for #methods_mod_ident::#camel_case_ident {
+                        type Req = #request_ident;
+                        type Resp = #response_ident;
+                        type Args = #args_type;
+                        const NAME: &'static str = #request_name;
+
+                        fn request(#args_pat: Self::Args) -> #request_ident {
+                            #request_ident::#camel_case_ident { #( #pats ),* }
                         }
                     }
-                )*
+                };
+                (marker, method_impl)
+            })
+            .unzip();
+
+        quote! {
+            #[doc = #mod_doc]
+            #[allow(unused)]
+            #vis mod #methods_mod_ident {
+                #( #markers )*
             }
+
+            #( #impls )*
         }
     }
 }
@@ -601,14 +1164,82 @@ impl<'a> ToTokens for ServiceGenerator<'a> {
             self.struct_server(),
             self.impl_serve_for_server(),
             self.enum_request(),
+            self.impl_schema(),
             self.enum_response(),
             self.struct_client(),
             self.impl_client_new(),
             self.impl_client_rpc_methods(),
+            self.mod_methods(),
         ])
     }
 }
 
+/// Returns the tokens as written, without the spaces that token streams insert between
+/// punctuation, e.g. `Vec<String>` instead of `Vec < String >`.
+fn tokens_to_string(tokens: &impl ToTokens) -> String {
+    let spaced = tokens.to_token_stream().to_string();
+    let mut string = String::with_capacity(spaced.len());
+    let mut chars = spaced.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' {
+            let after_opening = string.ends_with(['<', '(', '[', '&', ':', '.', '!']);
+            let before_closing = chars
+                .peek()
+                .map_or(true, |next| "<>()[],:;.".contains(*next));
+            if after_opening || before_closing {
+                continue;
+            }
+        }
+        string.push(c);
+    }
+    string
+}
+
+/// Returns `T` if `ty` is `impl Stream<Item = T>`, the return type of a streaming method.
+fn stream_item(ty: &Type) -> Option<&Type> {
+    let impl_trait = match ty {
+        Type::ImplTrait(impl_trait) => impl_trait,
+        _ => return None,
+    };
+    impl_trait.bounds.iter().find_map(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+            _ => return None,
+        };
+        match &segment.arguments {
+            PathArguments::AngleBracketed(args) if segment.ident == "Stream" => {
+                args.args.iter().find_map(|arg| match arg {
+                    GenericArgument::Binding(binding) if binding.ident == "Item" => {
+                        Some(&binding.ty)
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+fn camel_to_snake(ident_str: &str) -> String {
+    let mut snake = String::with_capacity(ident_str.len() + 4);
+
+    let mut last_char_was_uppercase = true;
+    let mut chars = ident_str.chars().peekable();
+    while let Some(c) = chars.next() {
+        // A word starts at an uppercase letter after a lowercase one, and at the last uppercase
+        // letter of an acronym, as in `HTTPService`.
+        let next_is_lowercase = chars.peek().map_or(false, |next| next.is_lowercase());
+        if c.is_uppercase() && (!last_char_was_uppercase || next_is_lowercase) && !snake.is_empty()
+        {
+            snake.push('_');
+        }
+        last_char_was_uppercase = c.is_uppercase() || c == '_';
+        snake.extend(c.to_lowercase());
+    }
+
+    snake
+}
+
 fn snake_to_camel(ident_str: &str) -> String {
     let mut camel_ty = String::with_capacity(ident_str.len());
 
@@ -652,3 +1283,27 @@ fn snake_to_camel_underscore_consecutive() {
 fn snake_to_camel_capital_in_middle() {
     assert_eq!(snake_to_camel("aBc_dEf"), "AbcDef");
 }
+
+#[test]
+fn camel_to_snake_basic() {
+    assert_eq!(camel_to_snake("HelloWorld"), "hello_world");
+    assert_eq!(camel_to_snake("World2Service"), "world2_service");
+    assert_eq!(camel_to_snake("HTTPService"), "http_service");
+}
+
+#[test]
+fn stream_item_of_streaming_method() {
+    let ty: Type = parse_quote!(impl futures::Stream<Item = Vec<u8>> + Send);
+    assert_eq!(tokens_to_string(&stream_item(&ty).unwrap()), "Vec<u8>");
+    assert!(stream_item(&parse_quote!(Vec<u8>)).is_none());
+    assert!(stream_item(&parse_quote!(impl Iterator<Item = u8>)).is_none());
+}
+
+#[test]
+fn tokens_to_string_removes_spaces_around_punctuation() {
+    let ty: Type = parse_quote!(std::collections::HashMap<&'static str, Vec<(u8, dyn Fn() -> u8)>>);
+    assert_eq!(
+        tokens_to_string(&ty),
+        "std::collections::HashMap<&'static str, Vec<(u8, dyn Fn() -> u8)>>"
+    );
+}
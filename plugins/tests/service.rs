@@ -18,9 +18,7 @@ fn att_service_trait() {
             s
         }
 
-        async fn baz(self, _: context::Context) {
-            ()
-        }
+        async fn baz(self, _: context::Context) {}
     }
 }
 
@@ -50,9 +48,7 @@ fn raw_idents() {
             r#impl
         }
 
-        async fn r#async(self, _: context::Context) {
-            ()
-        }
+        async fn r#async(self, _: context::Context) {}
     }
 }
 
@@ -78,3 +74,25 @@ fn syntax() {
         async fn one_arg_implicit_return_error(one: String);
     }
 }
+
+#[test]
+fn service_group_of_one() {
+    #[tarpc::service]
+    trait r#Single {
+        async fn r#type() -> String;
+    }
+
+    tarpc::service_group! {
+        #[allow(unused)]
+        struct Group {
+            #[doc = "attr"]
+            r#single: r#Single,
+        }
+    }
+
+    let response = GroupResponse::Single(SingleResponse::Type("".into()));
+    assert!(matches!(
+        SingleResponse::try_from(response),
+        Ok(SingleResponse::Type(_))
+    ));
+}
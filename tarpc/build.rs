@@ -0,0 +1,13 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+fn main() {
+    // `tokio_unstable` is set by users building with `--cfg tokio_unstable` to enable task names
+    // for tokio-console. The single-colon form is ignored by cargo versions that predate
+    // check-cfg, so this does not raise the minimum supported Rust version.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(tokio_unstable)");
+}
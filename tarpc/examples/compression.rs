@@ -14,6 +14,7 @@ use tarpc::{
     serde_transport::tcp,
     server::{BaseChannel, Channel},
     tokio_serde::formats::Bincode,
+    ClientMessage, Request,
 };
 
 /// Type of compression that should be enabled on the request. The transport is free to ignore this.
@@ -31,16 +32,50 @@ pub enum CompressedMessage<T> {
     },
 }
 
-#[derive(Deserialize, Serialize)]
-enum CompressionType {
-    Uncompressed,
-    Compressed,
+/// Decides for each message whether it is worth compressing. The decision is carried in the frame
+/// header, i.e. the [`CompressedMessage`] variant, so the receiver needs no configuration.
+pub struct CompressionPolicy<T> {
+    /// Messages that serialize to fewer bytes than this are sent uncompressed.
+    pub min_len: u64,
+    /// Returns false for messages that should never be compressed, e.g. latency-sensitive calls.
+    pub filter: fn(&T) -> bool,
 }
 
-async fn compress<T>(message: T) -> io::Result<CompressedMessage<T>>
+impl<T> Clone for CompressionPolicy<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for CompressionPolicy<T> {}
+
+impl<T> Default for CompressionPolicy<T> {
+    fn default() -> Self {
+        Self {
+            min_len: 0,
+            filter: |_| true,
+        }
+    }
+}
+
+impl<T: Serialize> CompressionPolicy<T> {
+    fn should_compress(&self, message: &T) -> io::Result<bool> {
+        if !(self.filter)(message) {
+            return Ok(false);
+        }
+        let len = bincode::serialized_size(message)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(len >= self.min_len)
+    }
+}
+
+async fn compress<T>(policy: CompressionPolicy<T>, message: T) -> io::Result<CompressedMessage<T>>
 where
     T: Serialize,
 {
+    if !policy.should_compress(&message)? {
+        return Ok(CompressedMessage::Uncompressed(message));
+    }
     let message = serialize(message)?;
     let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
     encoder.write_all(&message).unwrap();
@@ -89,17 +124,21 @@ where
 fn add_compression<In, Out>(
     transport: impl Stream<Item = io::Result<CompressedMessage<In>>>
         + Sink<CompressedMessage<Out>, Error = io::Error>,
+    policy: CompressionPolicy<Out>,
 ) -> impl Stream<Item = io::Result<In>> + Sink<Out, Error = io::Error>
 where
     Out: Serialize,
     for<'a> In: Deserialize<'a>,
 {
-    transport.with(compress).and_then(decompress)
+    transport
+        .with(move |message| compress(policy, message))
+        .and_then(decompress)
 }
 
 #[tarpc::service]
 pub trait World {
     async fn hello(name: String) -> String;
+    async fn ping() -> ();
 }
 
 #[derive(Clone, Debug)]
@@ -109,6 +148,8 @@ impl World for HelloServer {
     async fn hello(self, _: context::Context, name: String) -> String {
         format!("Hey, {name}!")
     }
+
+    async fn ping(self, _: context::Context) {}
 }
 
 async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
@@ -121,18 +162,48 @@ async fn main() -> anyhow::Result<()> {
     let addr = incoming.local_addr();
     tokio::spawn(async move {
         let transport = incoming.next().await.unwrap().unwrap();
-        BaseChannel::with_defaults(add_compression(transport))
+        // Only compress responses large enough to benefit.
+        let policy = CompressionPolicy {
+            min_len: 1024,
+            ..Default::default()
+        };
+        BaseChannel::with_defaults(add_compression(transport, policy))
             .execute(HelloServer.serve())
             .for_each(spawn)
             .await;
     });
 
     let transport = tcp::connect(addr, Bincode::default).await?;
-    let client = WorldClient::new(client::Config::default(), add_compression(transport)).spawn();
-
+    // Pings are latency sensitive, so never compress them.
+    let policy = CompressionPolicy {
+        min_len: 1024,
+        filter: |message: &ClientMessage<WorldRequest>| {
+            !matches!(
+                message,
+                ClientMessage::Request(Request {
+                    message: WorldRequest::Ping {},
+                    ..
+                })
+            )
+        },
+    };
+    let client = WorldClient::new(
+        client::Config::default(),
+        add_compression(transport, policy),
+    )
+    .spawn();
+
+    client.ping(context::current()).await?;
     println!(
         "{}",
         client.hello(context::current(), "friend".into()).await?
     );
+    println!(
+        "{}",
+        client
+            .hello(context::current(), "friend".repeat(1000))
+            .await?
+            .len()
+    );
     Ok(())
 }
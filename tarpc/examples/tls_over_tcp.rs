@@ -13,12 +13,13 @@ use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::sync::watch;
 use tokio_rustls::rustls::{self, RootCertStore};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_rustls::{server, TlsAcceptor, TlsConnector};
 
 use tarpc::context::Context;
 use tarpc::serde_transport as transport;
-use tarpc::server::{BaseChannel, Channel};
+use tarpc::server::{route::Router, BaseChannel, Channel};
 use tarpc::tokio_serde::formats::Bincode;
 use tarpc::tokio_util::codec::length_delimited::LengthDelimitedCodec;
 
@@ -36,6 +37,21 @@ impl PingService for Service {
     }
 }
 
+#[tarpc::service]
+pub trait EchoService {
+    async fn echo(message: String) -> String;
+}
+
+impl EchoService for Service {
+    async fn echo(self, _: Context, message: String) -> String {
+        message
+    }
+}
+
+// ALPN protocol names used to route connections to services sharing one port.
+const PING_PROTOCOL: &[u8] = b"tarpc-ping";
+const ECHO_PROTOCOL: &[u8] = b"tarpc-echo";
+
 // certs were generated with openssl 3 https://github.com/rustls/rustls/tree/main/test-ca
 // used on client-side for server tls
 const END_CHAIN: &str = include_str!("certs/eddsa/end.chain");
@@ -71,18 +87,11 @@ pub fn load_private_key(key: &str) -> rustls::PrivateKey {
     panic!("no keys found in {:?} (encrypted keys not supported)", key);
 }
 
-async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
-    tokio::spawn(fut);
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // -------------------- start here to setup tls tcp tokio stream --------------------------
-    // ref certs and loading from: https://github.com/tokio-rs/tls/blob/master/tokio-rustls/tests/test.rs
-    // ref basic tls server setup from: https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/server/src/main.rs
+/// Builds the server's TLS config from its certificate and the trust anchors for client
+/// certificates. Certificates that rotate would be read from disk here.
+fn load_server_config() -> rustls::ServerConfig {
     let cert = load_certs(END_CERT);
     let key = load_private_key(END_PRIVATEKEY);
-    let server_addr = (IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
 
     // ------------- server side client_auth cert loading start
     let mut client_auth_roots = RootCertStore::empty();
@@ -92,33 +101,18 @@ async fn main() -> anyhow::Result<()> {
     let client_auth = AllowAnyAuthenticatedClient::new(client_auth_roots);
     // ------------- server side client_auth cert loading end
 
-    let config = rustls::ServerConfig::builder()
+    let mut config = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_client_cert_verifier(client_auth) // use .with_no_client_auth() instead if you don't want client-auth
         .with_single_cert(cert, key)
         .unwrap();
-    let acceptor = TlsAcceptor::from(Arc::new(config));
-    let listener = TcpListener::bind(&server_addr).await.unwrap();
-    let codec_builder = LengthDelimitedCodec::builder();
-
-    // ref ./custom_transport.rs server side
-    tokio::spawn(async move {
-        loop {
-            let (stream, _peer_addr) = listener.accept().await.unwrap();
-            let tls_stream = acceptor.accept(stream).await.unwrap();
-            let framed = codec_builder.new_framed(tls_stream);
-
-            let transport = transport::new(framed, Bincode::default());
-
-            let fut = BaseChannel::with_defaults(transport)
-                .execute(Service.serve())
-                .for_each(spawn);
-            tokio::spawn(fut);
-        }
-    });
+    config.alpn_protocols = vec![PING_PROTOCOL.to_vec(), ECHO_PROTOCOL.to_vec()];
+    config
+}
 
-    // ---------------------- client connection ---------------------
-    // tls client connection from https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/client/src/main.rs
+/// Builds the client's TLS config from the trust anchors for server certificates and the
+/// client's own certificate. Certificates that rotate would be read from disk here.
+fn load_client_config() -> rustls::ClientConfig {
     let mut root_store = rustls::RootCertStore::empty();
     for root in load_certs(END_CHAIN) {
         root_store.add(&root).unwrap();
@@ -127,24 +121,116 @@ async fn main() -> anyhow::Result<()> {
     let client_auth_private_key = load_private_key(CLIENT_PRIVATEKEY_CLIENT_AUTH);
     let client_auth_certs = load_certs(CLIENT_CERT_CLIENT_AUTH);
 
-    let config = rustls::ClientConfig::builder()
+    rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(root_store)
-        .with_single_cert(client_auth_certs, client_auth_private_key)?; // use .with_no_client_auth() instead if you don't want client-auth
+        .with_single_cert(client_auth_certs, client_auth_private_key) // use .with_no_client_auth() instead if you don't want client-auth
+        .unwrap()
+}
 
-    let domain = rustls::ServerName::try_from("localhost")?;
-    let connector = TlsConnector::from(Arc::new(config));
+async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(fut);
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // -------------------- start here to setup tls tcp tokio stream --------------------------
+    // ref certs and loading from: https://github.com/tokio-rs/tls/blob/master/tokio-rustls/tests/test.rs
+    // ref basic tls server setup from: https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/server/src/main.rs
+    let server_addr = (IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
 
-    let stream = TcpStream::connect(server_addr).await?;
-    let stream = connector.connect(domain, stream).await?;
+    // The TLS config is only consulted during the handshake, so replacing it rotates the
+    // certificates used by new connections without affecting established ones.
+    let (server_config_tx, server_config) = watch::channel(Arc::new(load_server_config()));
+    let listener = TcpListener::bind(&server_addr).await.unwrap();
+    let codec_builder = LengthDelimitedCodec::builder();
 
-    let transport = transport::new(codec_builder.new_framed(stream), Bincode::default());
-    let answer = PingServiceClient::new(Default::default(), transport)
-        .spawn()
-        .ping(tarpc::context::current())
-        .await?;
+    // ref ./custom_transport.rs server side
+    let connections = stream::unfold(listener, |listener| async move {
+        let (stream, _peer_addr) = listener.accept().await.unwrap();
+        Some((stream, listener))
+    })
+    .then(move |stream| {
+        let acceptor = TlsAcceptor::from(server_config.borrow().clone());
+        async move { acceptor.accept(stream).await.unwrap() }
+    });
 
+    // Each service has its own request type, so connections are routed to a service before
+    // they are wrapped in a channel. The SNI hostname, available via `server_name()`, can be
+    // used the same way. Connections without a supported ALPN protocol are dropped.
+    let mut router = Router::new(connections, |tls_stream: &server::TlsStream<TcpStream>| {
+        tls_stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+    });
+    let ping_connections = router.route(Some(PING_PROTOCOL.to_vec()));
+    let echo_connections = router.route(Some(ECHO_PROTOCOL.to_vec()));
+    tokio::spawn(
+        ping_connections
+            .map(move |tls_stream| {
+                let transport =
+                    transport::new(codec_builder.new_framed(tls_stream), Bincode::default());
+                BaseChannel::with_defaults(transport)
+                    .execute(PingService::serve(Service))
+                    .for_each(spawn)
+            })
+            .for_each(spawn),
+    );
+    tokio::spawn(
+        echo_connections
+            .map(move |tls_stream| {
+                let transport =
+                    transport::new(codec_builder.new_framed(tls_stream), Bincode::default());
+                BaseChannel::with_defaults(transport)
+                    .execute(EchoService::serve(Service))
+                    .for_each(spawn)
+            })
+            .for_each(spawn),
+    );
+
+    // ---------------------- client connection ---------------------
+    // tls client connection from https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/client/src/main.rs
+    // Like the server's, the client's TLS config is only consulted during the handshake, so
+    // replacing it rotates the trust anchors and client certificate used by new connections.
+    let (client_config_tx, client_config) = watch::channel(Arc::new(load_client_config()));
+
+    let domain = rustls::ServerName::try_from("localhost")?;
+
+    // Each client requests the protocol of the service it wants to talk to.
+    let connect = |protocol: &[u8]| {
+        let mut config = rustls::ClientConfig::clone(&client_config.borrow());
+        config.alpn_protocols = vec![protocol.to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        let domain = domain.clone();
+        async move {
+            let stream = TcpStream::connect(server_addr).await?;
+            let stream = connector.connect(domain, stream).await?;
+            anyhow::Ok(codec_builder.new_framed(stream))
+        }
+    };
+
+    let ping_client = PingServiceClient::new(
+        Default::default(),
+        transport::new(connect(PING_PROTOCOL).await?, Bincode::default()),
+    )
+    .spawn();
+    let answer = ping_client.ping(tarpc::context::current()).await?;
     println!("ping answer: {answer}");
 
+    // Reload the certificates and trust anchors of both sides, e.g. in response to SIGHUP or a
+    // file watcher. The ping connection established above keeps working, and the echo
+    // connection below is established with the reloaded configs.
+    server_config_tx.send_replace(Arc::new(load_server_config()));
+    client_config_tx.send_replace(Arc::new(load_client_config()));
+    let answer = ping_client.ping(tarpc::context::current()).await?;
+    println!("ping answer after reload: {answer}");
+
+    let answer = EchoServiceClient::new(
+        Default::default(),
+        transport::new(connect(ECHO_PROTOCOL).await?, Bincode::default()),
+    )
+    .spawn()
+    .echo(tarpc::context::current(), "hello".into())
+    .await?;
+    println!("echo answer: {answer}");
+
     Ok(())
 }
@@ -108,6 +108,7 @@ where
     Ok((listener, addr))
 }
 
+#[allow(clippy::type_complexity)]
 fn make_stub<Req, Resp, const N: usize>(
     backends: [impl Transport<ClientMessage<Arc<Req>>, Response<Resp>> + Send + Sync + 'static; N],
 ) -> retry::Retry<
@@ -0,0 +1,264 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides policies for how long to wait between repeated attempts.
+//!
+//! A [`Backoff`] is shared by every layer that repeats an attempt after a failure: the
+//! [supervisor](crate::client::supervisor) waits according to it before reconnecting, and the
+//! [`Retry`](crate::client::stub::retry::Retry) stub before retrying a request. Each sequence of
+//! attempts draws its waits from its own [`Delays`].
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use tarpc::backoff::Backoff;
+//!
+//! let backoff = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(10))
+//!     .with_jitter();
+//! let mut delays = backoff.delays();
+//! assert!(delays.next_delay() <= Duration::from_millis(100));
+//! assert!(delays.next_delay() <= Duration::from_millis(200));
+//! ```
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Controls how long to wait between consecutive failed attempts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    strategy: Strategy,
+    max: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Strategy {
+    Fixed,
+    Exponential {
+        initial: Duration,
+        multiplier: u32,
+        jitter: bool,
+    },
+    Decorrelated {
+        base: Duration,
+    },
+}
+
+/// Waits 100 milliseconds after the first failed attempt, doubling the wait after every
+/// consecutive failure up to 30 seconds.
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::exponential(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    /// Returns a policy that waits `delay` after every failed attempt.
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            strategy: Strategy::Fixed,
+            max: delay,
+        }
+    }
+
+    /// Returns a policy that waits `initial` after the first failed attempt, doubling the wait
+    /// after every consecutive failure up to `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self {
+            strategy: Strategy::Exponential {
+                initial,
+                multiplier: 2,
+                jitter: false,
+            },
+            max,
+        }
+    }
+
+    /// Returns a policy with decorrelated jitter: each wait is drawn at random between `base` and
+    /// three times the previous wait, up to `max`. The waits grow about as fast as exponential
+    /// backoff's, but clients that failed at the same time spread out their attempts.
+    pub fn decorrelated(base: Duration, max: Duration) -> Self {
+        Self {
+            strategy: Strategy::Decorrelated { base },
+            max,
+        }
+    }
+
+    /// Sets the factor by which an exponential wait grows after every consecutive failure.
+    /// Has no effect on other policies.
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        if let Strategy::Exponential {
+            multiplier: current,
+            ..
+        } = &mut self.strategy
+        {
+            *current = multiplier;
+        }
+        self
+    }
+
+    /// Draws each exponential wait at random between zero and the wait without jitter, so that
+    /// clients that failed at the same time spread out their attempts. Has no effect on other
+    /// policies.
+    pub fn with_jitter(mut self) -> Self {
+        if let Strategy::Exponential { jitter, .. } = &mut self.strategy {
+            *jitter = true;
+        }
+        self
+    }
+
+    /// Returns the upper bound on the wait between attempts.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the waits of a new sequence of attempts.
+    pub fn delays(&self) -> Delays {
+        Delays {
+            backoff: self.clone(),
+            failed_attempts: 0,
+            previous: None,
+            rng: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+}
+
+/// The waits between the attempts of one sequence of attempts, as drawn from a [`Backoff`]. The
+/// sequence never ends.
+#[derive(Clone, Debug)]
+pub struct Delays {
+    backoff: Backoff,
+    failed_attempts: u32,
+    previous: Option<Duration>,
+    rng: u64,
+}
+
+impl Delays {
+    /// Returns the wait after the next failed attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let max = self.backoff.max;
+        let delay = match self.backoff.strategy {
+            Strategy::Fixed => max,
+            Strategy::Exponential {
+                initial,
+                multiplier,
+                jitter,
+            } => {
+                let factor = multiplier
+                    .checked_pow(self.failed_attempts)
+                    .unwrap_or(u32::MAX);
+                let delay = initial.checked_mul(factor).unwrap_or(max).min(max);
+                if jitter {
+                    self.random_between(Duration::ZERO, delay)
+                } else {
+                    delay
+                }
+            }
+            Strategy::Decorrelated { base } => {
+                let previous = self.previous.unwrap_or(base);
+                let high = previous.checked_mul(3).unwrap_or(max).max(base);
+                self.random_between(base, high).min(max)
+            }
+        };
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+        self.previous = Some(delay);
+        delay
+    }
+
+    /// Returns the number of waits drawn since the sequence started or was reset.
+    pub fn failed_attempts(&self) -> u32 {
+        self.failed_attempts
+    }
+
+    /// Starts the sequence over, e.g. after an attempt succeeded.
+    pub fn reset(&mut self) {
+        self.failed_attempts = 0;
+        self.previous = None;
+    }
+
+    /// Returns a duration drawn uniformly from `low..=high`.
+    fn random_between(&mut self, low: Duration, high: Duration) -> Duration {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let random = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let range = u64::try_from((high - low).as_nanos()).unwrap_or(u64::MAX);
+        low + Duration::from_nanos(random % range.saturating_add(1))
+    }
+}
+
+impl Iterator for Delays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        Some(self.next_delay())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn exponential_grows_up_to_max() {
+        let backoff = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<_> = backoff.delays().take(6).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+
+        let mut delays = backoff.delays();
+        delays.nth(2);
+        delays.reset();
+        assert_eq!(delays.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn exponential_with_multiplier() {
+        let backoff = Backoff::exponential(Duration::from_millis(1), Duration::from_secs(1))
+            .with_multiplier(10);
+        let delays: Vec<_> = backoff.delays().take(5).collect();
+        assert_eq!(delays, [1, 10, 100, 1000, 1000].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn jitter_stays_below_exponential() {
+        let backoff =
+            Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1)).with_jitter();
+        let expected = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        for (delay, bound) in backoff.delays().zip(expected.delays()).take(100) {
+            assert!(delay <= bound, "{delay:?} > {bound:?}");
+        }
+    }
+
+    #[test]
+    fn decorrelated_stays_within_bounds() {
+        let base = Duration::from_millis(10);
+        let max = Duration::from_secs(1);
+        let mut previous = base;
+        for delay in Backoff::decorrelated(base, max).delays().take(100) {
+            assert!(delay >= base, "{delay:?} < {base:?}");
+            assert!(
+                delay <= (previous * 3).min(max),
+                "{delay:?} > 3 * {previous:?}"
+            );
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn fixed() {
+        let delays: Vec<_> = Backoff::fixed(Duration::from_secs(1))
+            .delays()
+            .take(3)
+            .collect();
+        assert_eq!(delays, [Duration::from_secs(1); 3]);
+    }
+}
@@ -1,3 +1,10 @@
+//! Provides a channel over which request cancellations are signaled.
+//!
+//! Clients and servers both track requests that can be dropped before they complete. The side
+//! that drops a request holds a [`RequestCancellation`], and the side that tracks it polls the
+//! matching [`CanceledRequests`] to clean up after it. Custom client dispatch loops use the pair
+//! together with [`ResponseGuard`](crate::client::ResponseGuard).
+
 use futures::{prelude::*, task::*};
 use std::pin::Pin;
 use tokio::sync::mpsc;
@@ -6,48 +6,511 @@
 
 //! Provides a client that connects to a server and sends multiplexed requests.
 
-mod in_flight_requests;
+pub mod circuit_breaker;
+pub mod dispatch_log;
+pub mod in_flight_requests;
+pub mod journal;
+mod ordered_responses;
+pub mod pool;
+mod reconnect;
+pub mod retry_policy;
+pub mod sink;
 pub mod stub;
+#[cfg(feature = "tokio1")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
+pub mod supervisor;
+mod tuning;
+pub mod watchdog;
 
+use crate::util::tracing::{self, Span};
 use crate::{
+    backoff::Backoff,
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
-    context, trace, ChannelError, ClientMessage, Request, Response, ServerError, Transport,
+    clock, context, trace,
+    transport::{MalformedFrame, MalformedFramePolicy},
+    util::TimeUntil,
+    ChannelError, ClientMessage, CloseReason, InvalidConfig, Request, Response, ServerError,
+    Transport,
+};
+use circuit_breaker::CircuitBreaker;
+use dispatch_log::{DispatchLog, Op};
+use fnv::FnvHashMap;
+use futures::{
+    prelude::*,
+    ready,
+    stream::{Fuse, SelectAll},
+    task::*,
 };
-use futures::{prelude::*, ready, stream::Fuse, task::*};
 use in_flight_requests::InFlightRequests;
+use journal::Journal;
+use ordered_responses::OrderedResponses;
 use pin_project::pin_project;
+use reconnect::Reconnect;
+use retry_policy::RetryPolicy;
 use std::{
+    collections::hash_map::RandomState,
     convert::TryFrom,
     fmt,
+    hash::{BuildHasher, Hasher},
+    num::NonZeroUsize,
     pin::Pin,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    time::{Instant, Sleep},
 };
-use tokio::sync::{mpsc, oneshot};
-use tracing::Span;
+use tokio_util::sync::PollSender;
+use tuning::Tuner;
+use watchdog::{Watch, Watchdog};
 
 /// Settings that control the behavior of the client.
+///
+/// A config is created with [`Config::builder`], which validates the settings, or from one of the
+/// presets: [`Config::default`], [`Config::low_latency`], and [`Config::high_throughput`].
 #[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct Config {
+    max_in_flight_requests: usize,
+    pending_request_buffer: usize,
+    malformed_frame_policy: MalformedFramePolicy,
+    max_request_len: Option<usize>,
+    auto_tune: bool,
+    ordered_responses: bool,
+    request_ids: RequestIds,
+    dispatch_log: Option<DispatchLog>,
+    journal: Option<Journal>,
+    retry_policy: Option<RetryPolicy>,
+    circuit_breaker: Option<CircuitBreaker>,
+    watchdog: Option<Watchdog>,
+    write_timeout: Option<Duration>,
+    write_batch: Option<WriteBatch>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_in_flight_requests: 1_000,
+            pending_request_buffer: 100,
+            malformed_frame_policy: MalformedFramePolicy::default(),
+            max_request_len: None,
+            auto_tune: false,
+            ordered_responses: false,
+            request_ids: RequestIds::default(),
+            dispatch_log: None,
+            journal: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            watchdog: None,
+            write_timeout: None,
+            write_batch: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns a builder initialized with the [default](Config::default) settings.
+    pub fn builder() -> ConfigBuilder {
+        Config::default().into_builder()
+    }
+
+    /// Returns a builder initialized with the settings of `self`.
+    pub fn into_builder(self) -> ConfigBuilder {
+        ConfigBuilder { config: self }
+    }
+
+    /// Settings for interactive clients that send requests one or a few at a time. The small
+    /// buffers make callers feel backpressure as soon as the server falls behind, rather than
+    /// queueing requests that would time out anyway.
+    pub fn low_latency() -> Self {
+        Config {
+            max_in_flight_requests: 100,
+            pending_request_buffer: 10,
+            ..Config::default()
+        }
+    }
+
+    /// Settings for clients that pipeline many concurrent requests over one connection. The large
+    /// buffers let the dispatch batch writes to the transport.
+    pub fn high_throughput() -> Self {
+        Config {
+            max_in_flight_requests: 10_000,
+            pending_request_buffer: 1_000,
+            ..Config::default()
+        }
+    }
+
+    /// Settings for clients whose load is not known in advance. The buffers are as large as those
+    /// of [`Config::high_throughput`], and [auto-tuning](Config::auto_tune) keeps them from
+    /// adding latency when the server is slow.
+    pub fn adaptive() -> Self {
+        Config {
+            auto_tune: true,
+            ..Config::high_throughput()
+        }
+    }
+
     /// The number of requests that can be in flight at once.
     /// `max_in_flight_requests` controls the size of the map used by the client
     /// for storing pending requests.
-    pub max_in_flight_requests: usize,
+    pub fn max_in_flight_requests(&self) -> usize {
+        self.max_in_flight_requests
+    }
+
     /// The number of requests that can be buffered client-side before being sent.
     /// `pending_requests_buffer` controls the size of the channel clients use
     /// to communicate with the request dispatch task.
-    pub pending_request_buffer: usize,
+    pub fn pending_request_buffer(&self) -> usize {
+        self.pending_request_buffer
+    }
+
+    /// Controls what happens when the transport yields a frame that cannot be decoded.
+    pub fn malformed_frame_policy(&self) -> MalformedFramePolicy {
+        self.malformed_frame_policy
+    }
+
+    /// The maximum length of a request, as measured by the function passed to
+    /// [`Channel::with_request_len`]. Larger requests fail with [`RpcError::RequestTooLarge`]
+    /// without being sent. If `None`, there is no limit.
+    pub fn max_request_len(&self) -> Option<usize> {
+        self.max_request_len
+    }
+
+    /// Whether the dispatch adapts to the observed round-trip times of requests. If true,
+    /// [`max_in_flight_requests`](Config::max_in_flight_requests) is an upper bound: the dispatch
+    /// admits fewer requests while round trips are slower than usual, leaving the rest in the
+    /// pending request buffer, and it flushes the transport after a number of writes that grows
+    /// with the number of requests admitted. If false, the dispatch admits up to
+    /// `max_in_flight_requests` and flushes only when no more requests are ready to be written.
+    ///
+    /// The [`pending_request_buffer`](Config::pending_request_buffer) itself is not resized: it
+    /// is the capacity of the channel between callers and the dispatch, which is fixed when the
+    /// channel is created. Tuning the number of requests admitted from the buffer instead decides
+    /// how many requests wait in it, so the buffer only needs to be large enough to absorb
+    /// bursts; once it is full, callers wait to send.
+    pub fn auto_tune(&self) -> bool {
+        self.auto_tune
+    }
+
+    /// Whether requests complete in the order they were written to the transport. If true, a
+    /// response that arrives before the responses to requests written earlier is held until
+    /// those requests complete, are cancelled, or expire. This suits callers that replace an
+    /// ordered protocol, at the cost of head-of-line blocking: one slow request delays the
+    /// completion of every request written after it.
+    pub fn ordered_responses(&self) -> bool {
+        self.ordered_responses
+    }
+
+    /// How the client chooses the IDs of its requests.
+    pub fn request_ids(&self) -> RequestIds {
+        self.request_ids
+    }
+
+    /// The log in which the dispatch records the state transitions of requests, if any. See the
+    /// [`dispatch_log`] module.
+    pub fn dispatch_log(&self) -> Option<&DispatchLog> {
+        self.dispatch_log.as_ref()
+    }
+
+    /// The journal in which the client's channels record their calls, if any. See the
+    /// [`journal`] module.
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// The policy by which channels re-send requests that fail, if any. See the
+    /// [`retry_policy`] module.
+    ///
+    /// Channels can only re-send requests they can copy, so a channel must also be given a
+    /// function that copies requests with [`Channel::with_request_clone`]. Until it is, the
+    /// channel fails its calls with a [`MissingRequestClone`](retry_policy::MissingRequestClone)
+    /// error. The stubs generated by [`service`](crate::service) do not set one, so to retry their
+    /// calls, build the stub from a [`Channel`] that has one.
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    /// The circuit breaker that fails channels' calls fast while the server is unhealthy, if any.
+    /// See the [`circuit_breaker`] module.
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    /// The watchdog that tracks the requests of the dispatch, if any. See the [`watchdog`]
+    /// module.
+    pub fn watchdog(&self) -> Option<&Watchdog> {
+        self.watchdog.as_ref()
+    }
+
+    /// How long writing to the transport may make no progress, e.g. because the peer stopped
+    /// reading, before the dispatch fails with [`ChannelError::WriteStalled`]. The timeout
+    /// restarts whenever a message is written or a flush completes. Outstanding
+    /// requests then fail with [`RpcError::Disconnected`] rather than waiting for their
+    /// deadlines. If `None`, the dispatch waits for the transport indefinitely.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// How the dispatch batches the messages it writes before flushing the transport, if at all.
+    /// If `None`, the dispatch flushes whenever no more messages are ready to be written. See
+    /// [`WriteBatch`].
+    pub fn write_batch(&self) -> Option<WriteBatch> {
+        self.write_batch
+    }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            max_in_flight_requests: 1_000,
-            pending_request_buffer: 100,
+/// How the dispatch batches the messages it writes to the transport before flushing it.
+///
+/// Each flush of a socket transport costs a syscall, so a client that sends many small requests
+/// spends much of its time flushing. With a write batch, the dispatch holds the messages written
+/// to the transport until `max_messages` of them are unflushed, or until the first of them has
+/// waited `max_delay`, whichever comes first, trading up to `max_delay` of added latency for
+/// fewer, larger writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteBatch {
+    max_messages: usize,
+    max_delay: Duration,
+}
+
+impl WriteBatch {
+    /// Returns a batch that flushes the transport every `max_messages` messages, or once the
+    /// first unflushed message has waited `max_delay`.
+    pub fn new(max_messages: usize, max_delay: Duration) -> Self {
+        Self {
+            max_messages,
+            max_delay,
+        }
+    }
+
+    /// The number of messages after which the transport is flushed.
+    pub fn max_messages(&self) -> usize {
+        self.max_messages
+    }
+
+    /// How long a message may wait for more messages to be batched with it before the transport
+    /// is flushed.
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+}
+
+/// How a client chooses the IDs of its requests, which servers echo in their responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RequestIds {
+    /// IDs count up from zero. They are compact and easy to follow in logs, but they are only
+    /// unique within a connection.
+    #[default]
+    Sequential,
+    /// Each ID is an opaque correlation token, drawn from a sequence that is randomly permuted
+    /// for each client. Tokens never repeat within a client and are unlikely to repeat across
+    /// clients, so transports that retransmit requests, such as datagram or QUIC transports, can
+    /// deduplicate requests and responses by ID alone, even across reconnects; servers already
+    /// ignore a request whose ID is in flight. Nothing depends on the order of the IDs, so
+    /// reordered messages are matched up all the same.
+    CorrelationTokens,
+}
+
+/// Derives the correlation token for the `seq`th request of a client from the client's `key`.
+/// The mixing function is a bijection, so distinct requests get distinct tokens.
+fn correlation_token(seq: u64, key: u64) -> u64 {
+    let mut token = seq.wrapping_add(key);
+    token = (token ^ (token >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    token = (token ^ (token >> 27)).wrapping_mul(0x94d049bb133111eb);
+    token ^ (token >> 31)
+}
+
+/// The state of a client that outlives its connection: where its request IDs continue from.
+///
+/// A process that hands a connection off to another process, e.g. by passing the socket's file
+/// descriptor, captures the session with [`Channel::session`] and sends it along. The other
+/// process adopts the connection with [`resume`], so that its requests never reuse the ID of a
+/// request that the server may still have in flight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Session {
+    next_request_id: u64,
+    correlation_key: Option<u64>,
+}
+
+impl Session {
+    /// Returns the session of a new client, whose request IDs are chosen as `request_ids`
+    /// specifies.
+    pub fn new(request_ids: RequestIds) -> Self {
+        Self {
+            next_request_id: 0,
+            correlation_key: match request_ids {
+                RequestIds::Sequential => None,
+                RequestIds::CorrelationTokens => Some(RandomState::new().build_hasher().finish()),
+            },
+        }
+    }
+
+    /// Returns how the session's request IDs are chosen.
+    pub fn request_ids(&self) -> RequestIds {
+        match self.correlation_key {
+            Some(_) => RequestIds::CorrelationTokens,
+            None => RequestIds::Sequential,
+        }
+    }
+
+    /// Returns the number of requests the session has issued.
+    pub fn requests_issued(&self) -> u64 {
+        self.next_request_id
+    }
+}
+
+/// Builds a validated [`Config`].
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Sets [`Config::max_in_flight_requests`]. Must be greater than zero.
+    pub fn max_in_flight_requests(mut self, max_in_flight_requests: usize) -> Self {
+        self.config.max_in_flight_requests = max_in_flight_requests;
+        self
+    }
+
+    /// Sets [`Config::pending_request_buffer`]. Must be greater than zero.
+    pub fn pending_request_buffer(mut self, pending_request_buffer: usize) -> Self {
+        self.config.pending_request_buffer = pending_request_buffer;
+        self
+    }
+
+    /// Sets [`Config::malformed_frame_policy`].
+    pub fn malformed_frame_policy(mut self, malformed_frame_policy: MalformedFramePolicy) -> Self {
+        self.config.malformed_frame_policy = malformed_frame_policy;
+        self
+    }
+
+    /// Sets [`Config::max_request_len`]. Must be greater than zero, if set.
+    pub fn max_request_len(mut self, max_request_len: Option<usize>) -> Self {
+        self.config.max_request_len = max_request_len;
+        self
+    }
+
+    /// Sets [`Config::auto_tune`].
+    pub fn auto_tune(mut self, auto_tune: bool) -> Self {
+        self.config.auto_tune = auto_tune;
+        self
+    }
+
+    /// Sets [`Config::ordered_responses`].
+    pub fn ordered_responses(mut self, ordered_responses: bool) -> Self {
+        self.config.ordered_responses = ordered_responses;
+        self
+    }
+
+    /// Sets [`Config::request_ids`].
+    pub fn request_ids(mut self, request_ids: RequestIds) -> Self {
+        self.config.request_ids = request_ids;
+        self
+    }
+
+    /// Sets [`Config::dispatch_log`].
+    pub fn dispatch_log(mut self, dispatch_log: Option<DispatchLog>) -> Self {
+        self.config.dispatch_log = dispatch_log;
+        self
+    }
+
+    /// Sets [`Config::journal`].
+    pub fn journal(mut self, journal: Option<Journal>) -> Self {
+        self.config.journal = journal;
+        self
+    }
+
+    /// Sets [`Config::retry_policy`]. The policy must allow at least one attempt, if set.
+    pub fn retry_policy(mut self, retry_policy: Option<RetryPolicy>) -> Self {
+        self.config.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets [`Config::circuit_breaker`]. The breaker's failure rate must be greater than zero
+    /// and at most one, and its window greater than zero, if set.
+    pub fn circuit_breaker(mut self, circuit_breaker: Option<CircuitBreaker>) -> Self {
+        self.config.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Sets [`Config::watchdog`].
+    pub fn watchdog(mut self, watchdog: Option<Watchdog>) -> Self {
+        self.config.watchdog = watchdog;
+        self
+    }
+
+    /// Sets [`Config::write_timeout`]. Must be greater than zero, if set.
+    pub fn write_timeout(mut self, write_timeout: Option<Duration>) -> Self {
+        self.config.write_timeout = write_timeout;
+        self
+    }
+
+    /// Sets [`Config::write_batch`]. The batch's `max_messages` must be greater than zero, if
+    /// set.
+    pub fn write_batch(mut self, write_batch: Option<WriteBatch>) -> Self {
+        self.config.write_batch = write_batch;
+        self
+    }
+
+    /// Returns the config, or an error if any setting is invalid.
+    pub fn build(self) -> Result<Config, InvalidConfig> {
+        let config = self.config;
+        if config.max_in_flight_requests == 0 {
+            return Err(InvalidConfig::new(
+                "max_in_flight_requests",
+                "must be greater than zero",
+            ));
+        }
+        if config.pending_request_buffer == 0 {
+            return Err(InvalidConfig::new(
+                "pending_request_buffer",
+                "must be greater than zero",
+            ));
+        }
+        if config.max_request_len == Some(0) {
+            return Err(InvalidConfig::new(
+                "max_request_len",
+                "must be greater than zero",
+            ));
+        }
+        if config.write_timeout == Some(Duration::ZERO) {
+            return Err(InvalidConfig::new(
+                "write_timeout",
+                "must be greater than zero",
+            ));
+        }
+        if matches!(config.write_batch, Some(batch) if batch.max_messages == 0) {
+            return Err(InvalidConfig::new(
+                "write_batch",
+                "must batch at least one message",
+            ));
         }
+        if matches!(&config.retry_policy, Some(policy) if policy.max_attempts() == 0) {
+            return Err(InvalidConfig::new(
+                "retry_policy",
+                "must allow at least one attempt",
+            ));
+        }
+        if let Some(breaker) = &config.circuit_breaker {
+            if !(breaker.failure_rate() > 0.0 && breaker.failure_rate() <= 1.0) {
+                return Err(InvalidConfig::new(
+                    "circuit_breaker",
+                    "must have a failure rate greater than zero and at most one",
+                ));
+            }
+            if breaker.window() == Duration::ZERO {
+                return Err(InvalidConfig::new(
+                    "circuit_breaker",
+                    "must have a window greater than zero",
+                ));
+            }
+        }
+        Ok(config)
     }
 }
 
@@ -65,19 +528,36 @@ where
     D: Future<Output = Result<(), E>> + Send + 'static,
     E: std::error::Error + Send + Sync + 'static,
 {
-    /// Helper method to spawn the dispatch on the default executor.
+    /// Helper method to spawn the dispatch on the default executor. The task is named
+    /// `tarpc::client::dispatch` in tokio-console; see the `tokio-console` feature.
     #[cfg(feature = "tokio1")]
     #[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
     pub fn spawn(self) -> C {
         let dispatch = self.dispatch.unwrap_or_else(move |e| {
-            let e = anyhow::Error::new(e);
-            tracing::warn!("Connection broken: {:?}", e);
+            tracing::warn!("Connection broken: {}", crate::util::print_err(&e));
         });
-        tokio::spawn(dispatch);
+        crate::util::spawn(format_args!("tarpc::client::dispatch"), dispatch);
         self.client
     }
 }
 
+impl<Req, Resp, C> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>> {
+    /// Makes the dispatch re-establish its connection with `connect` instead of shutting down
+    /// when the connection is lost. See [`RequestDispatch::with_reconnect`].
+    pub fn with_reconnect<F, Fut, E>(self, backoff: Backoff, connect: F) -> Self
+    where
+        C: Send + 'static,
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, E>> + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        NewClient {
+            client: self.client,
+            dispatch: self.dispatch.with_reconnect(backoff, connect),
+        }
+    }
+}
+
 impl<C, D> fmt::Debug for NewClient<C, D> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(fmt, "NewClient")
@@ -97,6 +577,76 @@ pub struct Channel<Req, Resp> {
     cancellation: RequestCancellation,
     /// The ID to use for the next request to stage.
     next_request_id: Arc<AtomicUsize>,
+    /// The key from which request IDs are derived, if they are correlation tokens.
+    correlation_key: Option<u64>,
+    /// Requests longer than this are rejected before being sent.
+    max_request_len: Option<usize>,
+    /// Measures the length of a request, to be compared against `max_request_len`.
+    request_len: fn(&Req) -> usize,
+    /// Tells the dispatch that the channel has finished sending requests.
+    half_close: Arc<HalfClose>,
+    /// Records the channel's calls, if configured.
+    journal: Option<Journal>,
+    /// Decides which failed requests are re-sent, if configured.
+    retry_policy: Option<RetryPolicy>,
+    /// Fails calls fast while the server is unhealthy, if configured.
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Copies requests so that they can be re-sent, if set.
+    request_clone: Option<fn(&Req) -> Req>,
+    /// The number of requests the dispatch was configured to keep in flight at once.
+    max_in_flight_requests: usize,
+}
+
+/// Lets channels tell their dispatch to close the write half of the connection, or to stop at
+/// once, and wait for it to finish.
+#[derive(Debug, Default)]
+struct HalfClose {
+    requested: AtomicBool,
+    aborted: AtomicBool,
+    dispatch: AtomicWaker,
+    /// Set by the dispatch once it has finished or been dropped.
+    finished: AtomicBool,
+    finished_notify: Notify,
+    /// The number of requests in flight as of the dispatch's last poll.
+    in_flight: AtomicUsize,
+    /// The reason to tell the server before closing the write half, if any, until it is sent.
+    reason: Mutex<Option<CloseReason>>,
+}
+
+impl HalfClose {
+    fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+        self.dispatch.wake();
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.dispatch.wake();
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+        self.finished_notify.notify_waiters();
+    }
+
+    async fn finished(&self) {
+        loop {
+            // Created before checking the flag, so that a notification in between is not missed.
+            let notified = self.finished_notify.notified();
+            if self.finished.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
 }
 
 impl<Req, Resp> Clone for Channel<Req, Resp> {
@@ -105,73 +655,718 @@ impl<Req, Resp> Clone for Channel<Req, Resp> {
             to_dispatch: self.to_dispatch.clone(),
             cancellation: self.cancellation.clone(),
             next_request_id: self.next_request_id.clone(),
+            correlation_key: self.correlation_key,
+            max_request_len: self.max_request_len,
+            request_len: self.request_len,
+            half_close: self.half_close.clone(),
+            journal: self.journal.clone(),
+            retry_policy: self.retry_policy.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            request_clone: self.request_clone,
+            max_in_flight_requests: self.max_in_flight_requests,
         }
     }
 }
 
 impl<Req, Resp> Channel<Req, Resp> {
+    /// Sets the function used to measure the length of requests, which is compared against
+    /// [`Config::max_request_len`]. To catch requests that the server would reject for exceeding
+    /// its frame limit, pass a function that returns the serialized size of the request. By
+    /// default, only the shallow size of each request is measured.
+    pub fn with_request_len(mut self, request_len: fn(&Req) -> usize) -> Self {
+        self.request_len = request_len;
+        self
+    }
+
+    /// Sets the function used to copy requests, so that requests that fail can be re-sent as
+    /// [`Config::retry_policy`] specifies. For requests that implement [`Clone`], pass
+    /// `Clone::clone`. By default, requests are not copied, so a channel configured with a retry
+    /// policy fails its calls with a [`MissingRequestClone`](retry_policy::MissingRequestClone)
+    /// error until this is set.
+    pub fn with_request_clone(mut self, request_clone: fn(&Req) -> Req) -> Self {
+        self.request_clone = Some(request_clone);
+        self
+    }
+
+    /// Returns the channel's [`Session`], which another client can [`resume`] on the same
+    /// connection. Requests issued after the session is captured are not reflected in it, so
+    /// capture it once the channel has stopped sending requests.
+    pub fn session(&self) -> Session {
+        Session {
+            next_request_id: u64::try_from(self.next_request_id.load(Ordering::Relaxed)).unwrap(),
+            correlation_key: self.correlation_key,
+        }
+    }
+
+    /// Waits until the channel can accept a request without waiting: its dispatch is running and
+    /// has room in its pending request buffer. Returns [`RpcError::Shutdown`] if the dispatch
+    /// has stopped. The channel's transport is connected before the channel is created; to
+    /// connect ahead of the first request when connections are made on demand, see
+    /// [`SupervisedChannel::ready`](supervisor::SupervisedChannel::ready).
+    pub async fn ready(&self) -> Result<(), RpcError> {
+        if self.half_close.is_requested() {
+            return Err(RpcError::Shutdown);
+        }
+        self.to_dispatch
+            .reserve()
+            .await
+            .map(drop)
+            .map_err(|_| RpcError::Shutdown)
+    }
+
+    /// Half-closes the connection: the channel and all its clones stop sending requests, while
+    /// responses to requests already sent are still received.
+    ///
+    /// The dispatch writes the requests already handed to it, then closes the write half of its
+    /// transport, which the server observes as the end of requests. The server keeps handling the
+    /// requests in flight and writing their responses, and the dispatch resolves once all of them
+    /// have completed, been canceled, or expired. Requests made after this call fail with
+    /// [`RpcError::Shutdown`]. Requests canceled after the write half is closed are completed
+    /// locally, without telling the server, which stops handling them when their deadlines pass.
+    ///
+    /// How the connection shuts down depends on which side initiates it:
+    ///
+    /// | Event | Client | Server |
+    /// |-------|--------|--------|
+    /// | `finish_sending` | Stops sending, then closes the write half; keeps reading responses. | Sees the end of requests; finishes in-flight requests and writes their responses. |
+    /// | All channels and response futures dropped | Closes the write half; keeps reading responses to requests still in flight. | As for `finish_sending`. |
+    /// | Server closes the connection | Resolves the dispatch; fails outstanding requests. | — |
+    /// | Transport error | Resolves the dispatch to a [`ChannelError`]; fails outstanding requests. | Closes the channel; aborts in-flight requests. |
+    ///
+    /// Transports that cannot close their write half without closing the connection, such as
+    /// [unbounded channels](crate::transport::channel::unbounded), never signal the end of
+    /// requests to the server.
+    pub fn finish_sending(&self) {
+        self.half_close.request();
+    }
+
+    /// Half-closes the connection as [`finish_sending`](Self::finish_sending) does, but first
+    /// tells the server why, by sending [`ClientMessage::Close`] just before closing the write
+    /// half. Servers that predate the message fail to read it, so only send it to servers known
+    /// to understand it.
+    pub fn close(&self, reason: CloseReason) {
+        *self.half_close.reason.lock().unwrap() = Some(reason);
+        self.half_close.request();
+    }
+
+    /// Shuts the connection down gracefully: [half-closes](Self::finish_sending) it, then waits
+    /// for the dispatch to finish, which it does once the requests in flight have completed and
+    /// the transport has been flushed and closed. Requests made after this call fail with
+    /// [`RpcError::Shutdown`].
+    ///
+    /// If `timeout` elapses before the dispatch finishes, the dispatch stops at once, failing the
+    /// requests still in flight with [`RpcError::Disconnected`], and [`ShutdownTimedOut`] is
+    /// returned. Unlike dropping every clone of the channel, this does not depend on the caller
+    /// knowing where all the clones are.
+    pub async fn shutdown(&self, timeout: Option<Duration>) -> Result<(), ShutdownTimedOut> {
+        self.finish_sending();
+        let Some(timeout) = timeout else {
+            self.half_close.finished().await;
+            return Ok(());
+        };
+        if tokio::time::timeout(timeout, self.half_close.finished())
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        tracing::warn!(?timeout, "ShutdownTimedOut");
+        self.half_close.abort();
+        self.half_close.finished().await;
+        Err(ShutdownTimedOut { timeout })
+    }
+
+    /// Returns the number of requests written to the transport that are awaiting responses, as
+    /// of the last time the dispatch ran. Compared against [`Config::max_in_flight_requests`],
+    /// it shows how close the connection is to saturation; the dispatch stops writing requests
+    /// once the limit is reached, and they wait in the pending request buffer.
+    pub fn in_flight_requests(&self) -> usize {
+        self.half_close.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Returns the [`Config::max_in_flight_requests`] the channel was created with.
+    pub fn max_in_flight_requests(&self) -> usize {
+        self.max_in_flight_requests
+    }
+
+    /// Returns the number of requests waiting in the pending request buffer to be written, out of
+    /// [`Config::pending_request_buffer`]. Once the buffer is full, calls wait for room in it,
+    /// so applications that would rather reject work than wait can check this first.
+    pub fn pending_requests(&self) -> usize {
+        self.to_dispatch.max_capacity() - self.to_dispatch.capacity()
+    }
+
+    /// Returns the ID that the next request made on the channel or any of its clones will get,
+    /// unless another request is made first.
+    pub fn next_request_id(&self) -> u64 {
+        self.request_id(u64::try_from(self.next_request_id.load(Ordering::Relaxed)).unwrap())
+    }
+
+    /// Returns the ID of the `seq`th request of the channel.
+    fn request_id(&self, seq: u64) -> u64 {
+        match self.correlation_key {
+            Some(key) => correlation_token(seq, key),
+            None => seq,
+        }
+    }
+
+    /// Whether the channel's dispatch has stopped, so that requests fail without being sent.
+    fn is_shut_down(&self) -> bool {
+        self.to_dispatch.is_closed()
+    }
+
+    /// Returns the journal in which the channel records its calls, if one was configured with
+    /// [`Config::journal`].
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves to the response.
-    #[tracing::instrument(
-        name = "RPC",
-        skip(self, ctx, request_name, request),
-        fields(
-            rpc.trace_id = tracing::field::Empty,
-            rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
-            otel.kind = "client",
-            otel.name = request_name)
-        )]
+    ///
+    /// If the call is made from a future [linked](context::Cancellation::link) to a
+    /// cancellation, such as a server's request handler, the call fails with
+    /// [`RpcError::Canceled`] and the request is canceled once the cancellation fires. If a
+    /// [circuit breaker](Config::circuit_breaker) is configured and open, the call fails with
+    /// [`RpcError::CircuitOpen`] without sending the request.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "RPC",
+            skip(self, ctx, request_name, request),
+            fields(
+                rpc.trace_id = tracing::field::Empty,
+                rpc.request_id = tracing::field::Empty,
+                rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
+                rpc.queue_time.client = tracing::field::Empty,
+                otel.kind = "client",
+                otel.name = request_name)
+        )
+    )]
     pub async fn call(
         &self,
-        mut ctx: context::Context,
+        ctx: context::Context,
         request_name: &'static str,
         request: Req,
     ) -> Result<Resp, RpcError> {
-        let span = Span::current();
-        ctx.trace_context = trace::Context::try_from(&span).unwrap_or_else(|_| {
-            tracing::trace!(
-                "OpenTelemetry subscriber not installed; making unsampled child context."
-            );
-            ctx.trace_context.new_child()
-        });
-        span.record("rpc.trace_id", &tracing::field::display(ctx.trace_id()));
+        if self.retry_policy.is_some() && self.request_clone.is_none() {
+            tracing::warn!("RetryPolicyWithoutRequestClone");
+            return Err(RpcError::Send(Box::new(retry_policy::MissingRequestClone)));
+        }
+        let mut journaled = self
+            .journal
+            .as_ref()
+            .map(|journal| journal.start(request_name, ctx.deadline));
+        let result = match self.circuit_breaker.as_ref().map(CircuitBreaker::attempt) {
+            None => self.send(ctx, request, &mut journaled).await,
+            Some(Err(e)) => {
+                tracing::info!("CircuitOpen");
+                Err(e)
+            }
+            Some(Ok(attempt)) => {
+                let result = self.send(ctx, request, &mut journaled).await;
+                attempt.finish(&result);
+                result
+            }
+        };
+        if let Some(journaled) = journaled {
+            journaled.finish(&result);
+        }
+        result
+    }
+
+    /// Sends a request like [`call`](Self::call), canceling it once `token` fires.
+    ///
+    /// When `token` fires, the call fails with [`RpcError::Canceled`] and the request is canceled
+    /// on the server, without the caller having to drop the response future. Since the token can
+    /// be cloned and fired from anywhere, a call can be canceled from another task, e.g. one
+    /// watching for the user to abort. A cancellation the call is
+    /// [linked](context::Cancellation::link) to still applies as well.
+    ///
+    /// ```rust
+    /// use tarpc::context::{self, Cancellation};
+    ///
+    /// # async fn call(client: tarpc::client::Channel<String, String>) {
+    /// let token = Cancellation::new();
+    /// tokio::spawn({
+    ///     let token = token.clone();
+    ///     async move {
+    ///         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    ///         token.cancel();
+    ///     }
+    /// });
+    /// let response = client
+    ///     .call_with_token(context::current(), "Slow", "request".into(), token)
+    ///     .await;
+    /// # }
+    /// ```
+    pub async fn call_with_token(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+        token: context::Cancellation,
+    ) -> Result<Resp, RpcError> {
+        if token.is_canceled() {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        unless_canceled(Some(token), self.call(ctx, request_name, request)).await
+    }
+
+    /// Sends the request, re-sending it as the retry policy specifies, and waits for the
+    /// response.
+    async fn send(
+        &self,
+        ctx: context::Context,
+        request: Req,
+        journaled: &mut Option<journal::Call>,
+    ) -> Result<Resp, RpcError> {
+        match (&self.retry_policy, self.request_clone) {
+            (Some(policy), Some(request_clone)) => {
+                self.send_with_retries(ctx, request, policy, request_clone, journaled)
+                    .await
+            }
+            _ => self.send_and_wait(ctx, request, journaled).await,
+        }
+    }
+
+    async fn send_with_retries(
+        &self,
+        ctx: context::Context,
+        mut request: Req,
+        policy: &RetryPolicy,
+        request_clone: fn(&Req) -> Req,
+        journaled: &mut Option<journal::Call>,
+    ) -> Result<Resp, RpcError> {
+        let mut delays = policy.backoff().delays();
+        for attempt in 1..policy.max_attempts() {
+            let retry = request_clone(&request);
+            match self.send_and_wait(ctx, request, journaled).await {
+                Err(e) if policy.is_retryable(&e) => {
+                    let retry_in = delays.next_delay();
+                    if retry_in >= ctx.deadline.time_until() {
+                        tracing::info!("RetryPastDeadline on attempt {}", attempt);
+                        return Err(e);
+                    }
+                    tracing::info!(
+                        "Retrying: {}; attempt {} failed, retrying in {:?}",
+                        crate::util::print_err(&e),
+                        attempt,
+                        retry_in
+                    );
+                    tokio::time::sleep(retry_in).await;
+                    request = retry;
+                }
+                result => return result,
+            }
+        }
+        self.send_and_wait(ctx, request, journaled).await
+    }
+
+    async fn send_and_wait(
+        &self,
+        mut ctx: context::Context,
+        request: Req,
+        journaled: &mut Option<journal::Call>,
+    ) -> Result<Resp, RpcError> {
+        let cancellation = context::Cancellation::current();
+        if cancellation.as_ref().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let (span, request_id) = self.prepare(&mut ctx, &request, journaled)?;
         let (response_completion, mut response) = oneshot::channel();
-        let request_id =
-            u64::try_from(self.next_request_id.fetch_add(1, Ordering::Relaxed)).unwrap();
 
         // ResponseGuard impls Drop to cancel in-flight requests. It should be created before
         // sending out the request; otherwise, the response future could be dropped after the
         // request is sent out but before ResponseGuard is created, rendering the cancellation
         // logic inactive.
-        let response_guard = ResponseGuard {
-            response: &mut response,
-            request_id,
-            cancellation: &self.cancellation,
-            cancel: true,
+        let response_guard = ResponseGuard::new(&mut response, &self.cancellation, request_id);
+        let response = async {
+            self.to_dispatch
+                .send(DispatchRequest {
+                    ctx,
+                    span,
+                    request_id,
+                    request,
+                    completion: Completion::Response(response_completion),
+                    items: None,
+                    enqueued_at: Instant::now(),
+                })
+                .await
+                .map_err(|mpsc::error::SendError(_)| RpcError::Shutdown)?;
+            response_guard.response().await
         };
-        self.to_dispatch
-            .send(DispatchRequest {
-                ctx,
+        unless_canceled(cancellation, response).await
+    }
+
+    /// Sends a one-way request, to which the server sends no response, returning a [`Future`]
+    /// that resolves once the request is flushed to the transport.
+    ///
+    /// A one-way request takes up no in-flight slot, and its handler's response is discarded by
+    /// the server instead of being sent. Use it for events and telemetry, where the caller has
+    /// no use for a reply. The call succeeding means only that the request was written; whether
+    /// the server handled it is unknown. Dropping the future before the request is written
+    /// abandons the request.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "RPC",
+            skip(self, ctx, request_name, request),
+            fields(
+                rpc.trace_id = tracing::field::Empty,
+                rpc.request_id = tracing::field::Empty,
+                rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
+                rpc.queue_time.client = tracing::field::Empty,
+                otel.kind = "producer",
+                otel.name = request_name)
+        )
+    )]
+    pub async fn notify(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<(), RpcError> {
+        if context::Cancellation::current().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let (span, request_id) = self.prepare(&mut ctx, &request, &mut None)?;
+        let (flushed, written) = oneshot::channel();
+        self.to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span,
+                request_id,
+                request,
+                completion: Completion::Flushed(flushed),
+                enqueued_at: Instant::now(),
+                items: None,
+            })
+            .await
+            .map_err(|mpsc::error::SendError(_)| RpcError::Shutdown)?;
+        // The oneshot is Canceled when the dispatch task ends.
+        written.await.unwrap_or(Err(RpcError::Shutdown))
+    }
+
+    /// Sends a request to which the server responds with a stream of responses, as it does for
+    /// the streaming methods of a [service](crate::service), and returns the stream.
+    ///
+    /// The stream yields each response as it arrives, ending after the server's final response
+    /// or after an error. The whole stream must arrive by the request's deadline. Dropping the
+    /// stream before it ends cancels the request. The request is neither retried nor counted by a
+    /// [circuit breaker](Config::circuit_breaker), since part of its stream may already have
+    /// been consumed when it fails.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "RPC",
+            skip(self, ctx, request_name, request),
+            fields(
+                rpc.trace_id = tracing::field::Empty,
+                rpc.request_id = tracing::field::Empty,
+                rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
+                rpc.queue_time.client = tracing::field::Empty,
+                otel.kind = "client",
+                otel.name = request_name)
+        )
+    )]
+    pub async fn call_stream(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<ResponseStream<Resp>, RpcError> {
+        if context::Cancellation::current().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let (span, request_id) = self.prepare(&mut ctx, &request, &mut None)?;
+        self.send_stream(ctx, span, request_id, request, None).await
+    }
+
+    /// Hands a streaming request to the dispatch, returning the stream of its responses.
+    async fn send_stream(
+        &self,
+        ctx: context::Context,
+        span: Span,
+        request_id: u64,
+        request: Req,
+        request_items: Option<mpsc::Receiver<Req>>,
+    ) -> Result<ResponseStream<Resp>, RpcError> {
+        let (items_tx, items) = mpsc::unbounded_channel();
+        let (response_completion, response) = oneshot::channel();
+        // Like a ResponseGuard, the stream is created before the request is handed to the
+        // dispatch, so that dropping it cancels the request from then on.
+        let stream = ResponseStream {
+            items,
+            response,
+            last: None,
+            cancellation: self.cancellation.clone(),
+            request_id,
+            done: false,
+        };
+        self.to_dispatch
+            .send(DispatchRequest {
+                ctx,
                 span,
                 request_id,
                 request,
-                response_completion,
+                completion: Completion::Stream {
+                    items: items_tx,
+                    response: response_completion,
+                },
+                enqueued_at: Instant::now(),
+                items: request_items,
             })
             .await
             .map_err(|mpsc::error::SendError(_)| RpcError::Shutdown)?;
-        response_guard.response().await
+        Ok(stream)
+    }
+
+    /// Sends a request followed by a stream of items, as it does for the client-streaming methods
+    /// of a [service](crate::service), and returns the server's response.
+    ///
+    /// The request is [streamed](Request::streamed): once it is written, each item yielded by
+    /// `items` is written as a [`ClientMessage::Item`] with the request's ID, followed by one
+    /// marking the end of the stream. The server may respond before reading all the items, in
+    /// which case the rest are not sent. Items are held to [`Config::max_request_len`] like the
+    /// request itself. Like [`call_stream`](Self::call_stream), the request is neither retried
+    /// nor counted by a [circuit breaker](Config::circuit_breaker), since its items are consumed
+    /// as they are sent.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "RPC",
+            skip(self, ctx, request_name, request, items),
+            fields(
+                rpc.trace_id = tracing::field::Empty,
+                rpc.request_id = tracing::field::Empty,
+                rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
+                rpc.queue_time.client = tracing::field::Empty,
+                otel.kind = "client",
+                otel.name = request_name)
+        )
+    )]
+    pub async fn call_with_stream(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+        items: impl Stream<Item = Req>,
+    ) -> Result<Resp, RpcError> {
+        let cancellation = context::Cancellation::current();
+        if cancellation.as_ref().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let (span, request_id) = self.prepare(&mut ctx, &request, &mut None)?;
+        let (items_tx, items_rx) = mpsc::channel(1);
+        let (response_completion, mut response) = oneshot::channel();
+        let response_guard = ResponseGuard::new(&mut response, &self.cancellation, request_id);
+        let response = async {
+            self.to_dispatch
+                .send(DispatchRequest {
+                    ctx,
+                    span,
+                    request_id,
+                    request,
+                    completion: Completion::Response(response_completion),
+                    items: Some(items_rx),
+                    enqueued_at: Instant::now(),
+                })
+                .await
+                .map_err(|mpsc::error::SendError(_)| RpcError::Shutdown)?;
+            // Dropping the sender once the items run out ends the stream.
+            let send_items = async move {
+                futures::pin_mut!(items);
+                while let Some(item) = items.next().await {
+                    if let Some(max) = self.max_request_len {
+                        let len = (self.request_len)(&item);
+                        if len > max {
+                            tracing::info!("RequestTooLarge: {} > {}", len, max);
+                            return Err(RpcError::RequestTooLarge { len, max });
+                        }
+                    }
+                    if items_tx.send(item).await.is_err() {
+                        // The request already completed.
+                        break;
+                    }
+                }
+                Ok(())
+            };
+            let response = response_guard.response();
+            futures::pin_mut!(send_items, response);
+            match future::select(response, send_items).await {
+                future::Either::Left((response, _)) => response,
+                future::Either::Right((Ok(()), response)) => response.await,
+                // Dropping the response future cancels the request.
+                future::Either::Right((Err(e), _)) => Err(e),
+            }
+        };
+        unless_canceled(cancellation, response).await
+    }
+
+    /// Sends a request followed by a stream of items, and returns the stream of the server's
+    /// responses, as it does for the duplex methods of a [service](crate::service).
+    ///
+    /// The request is [streamed](Request::streamed) like those of
+    /// [`call_with_stream`](Self::call_with_stream), and answered like those of
+    /// [`call_stream`](Self::call_stream). The items are flow controlled per request: the next
+    /// item is taken from `items` only while the returned stream is polled, and only once the
+    /// dispatch has written the previous one, so a fast producer waits for the connection instead
+    /// of buffering items. An item longer than [`Config::max_request_len`] fails the stream with
+    /// [`RpcError::RequestTooLarge`].
+    ///
+    /// Dropping the stream before it ends cancels the request, and with it the items not yet
+    /// sent. Once the server's final response arrives, the rest of the items are not sent.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "RPC",
+            skip(self, ctx, request_name, request, items),
+            fields(
+                rpc.trace_id = tracing::field::Empty,
+                rpc.request_id = tracing::field::Empty,
+                rpc.deadline = %humantime::format_rfc3339(ctx.deadline),
+                rpc.queue_time.client = tracing::field::Empty,
+                otel.kind = "client",
+                otel.name = request_name)
+        )
+    )]
+    pub async fn call_duplex<S>(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+        items: S,
+    ) -> Result<DuplexStream<Req, Resp, S>, RpcError>
+    where
+        S: Stream<Item = Req>,
+        Req: Send + 'static,
+    {
+        if context::Cancellation::current().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let (span, request_id) = self.prepare(&mut ctx, &request, &mut None)?;
+        let (items_tx, items_rx) = mpsc::channel(1);
+        let responses = self
+            .send_stream(ctx, span, request_id, request, Some(items_rx))
+            .await?;
+        Ok(DuplexStream {
+            items,
+            pending: None,
+            items_tx: Some(PollSender::new(items_tx)),
+            responses: Some(responses),
+            request_id,
+            max_request_len: self.max_request_len,
+            request_len: self.request_len,
+        })
+    }
+
+    /// Checks that a request may be sent, and assigns it a trace context and a request ID.
+    fn prepare(
+        &self,
+        ctx: &mut context::Context,
+        request: &Req,
+        journaled: &mut Option<journal::Call>,
+    ) -> Result<(Span, u64), RpcError> {
+        if self.half_close.is_requested() {
+            tracing::info!("SendingFinished");
+            return Err(RpcError::Shutdown);
+        }
+        let span = Span::current();
+        ctx.trace_context = trace::Context::try_from(&span).unwrap_or_else(|_| {
+            tracing::trace!(
+                "OpenTelemetry subscriber not installed; making unsampled child context."
+            );
+            ctx.trace_context.new_child()
+        });
+        span.record("rpc.trace_id", tracing::field::display(ctx.trace_id()));
+        if let Some(journaled) = journaled {
+            journaled.set_trace_id(*ctx.trace_id());
+        }
+        if let Some(max) = self.max_request_len {
+            let len = (self.request_len)(request);
+            if len > max {
+                tracing::info!("RequestTooLarge: {} > {}", len, max);
+                return Err(RpcError::RequestTooLarge { len, max });
+            }
+        }
+        let seq = u64::try_from(self.next_request_id.fetch_add(1, Ordering::Relaxed)).unwrap();
+        let request_id = self.request_id(seq);
+        span.record("rpc.request_id", request_id);
+        if let Some(journaled) = journaled {
+            journaled.set_request_id(request_id);
+        }
+        Ok((span, request_id))
+    }
+
+    /// Sends each request yielded by `requests`, with at most `max_concurrent` requests in flight
+    /// at once, and yields each response as it arrives.
+    ///
+    /// Responses are yielded in the order they complete, not the order of `requests`. Each is
+    /// yielded along with the index of its request in `requests` and a clone of the request, so
+    /// that callers can match responses to requests or restore the original order.
+    pub fn call_all<'a, S>(
+        &'a self,
+        request_name: &'static str,
+        requests: S,
+        max_concurrent: NonZeroUsize,
+    ) -> impl Stream<Item = (usize, Req, Result<Resp, RpcError>)> + 'a
+    where
+        S: Stream<Item = (context::Context, Req)> + 'a,
+        Req: Clone + 'a,
+        Resp: 'a,
+    {
+        requests
+            .enumerate()
+            .map(move |(index, (ctx, request))| async move {
+                let response = self.call(ctx, request_name, request.clone()).await;
+                (index, request, response)
+            })
+            .buffer_unordered(max_concurrent.get())
     }
 }
 
 /// A server response that is completed by request dispatch when the corresponding response
-/// arrives off the wire.
-struct ResponseGuard<'a, Resp> {
+/// arrives off the wire. Dropping the guard before the response arrives cancels the request.
+///
+/// [`Channel`] sends each request with a guard; custom dispatch loops can use guards to give
+/// their callers the same cancellation behavior. Create the guard before handing the request to
+/// the dispatch, so that the request is canceled even if the caller gives up while the request is
+/// being handed over. See [`in_flight_requests`] for the dispatch side.
+pub struct ResponseGuard<'a, Resp> {
     response: &'a mut oneshot::Receiver<Result<Resp, RpcError>>,
     cancellation: &'a RequestCancellation,
     request_id: u64,
     cancel: bool,
 }
 
+/// Awaits `response`, unless `cancellation` fires first, in which case `response` is dropped.
+async fn unless_canceled<Resp>(
+    cancellation: Option<context::Cancellation>,
+    response: impl Future<Output = Result<Resp, RpcError>>,
+) -> Result<Resp, RpcError> {
+    let cancellation = match cancellation {
+        Some(cancellation) => cancellation,
+        None => return response.await,
+    };
+    let canceled = cancellation.canceled();
+    futures::pin_mut!(response, canceled);
+    match future::select(response, canceled).await {
+        future::Either::Left((response, _)) => response,
+        // Dropping the response future cancels the request.
+        future::Either::Right(_) => {
+            tracing::info!("Canceled");
+            Err(RpcError::Canceled)
+        }
+    }
+}
+
 /// An error that can occur in the processing of an RPC. This is not request-specific errors but
 /// rather cross-cutting errors that can always occur.
 #[derive(thiserror::Error, Debug)]
@@ -191,10 +1386,110 @@ pub enum RpcError {
     /// The server aborted request processing.
     #[error("the server aborted request processing")]
     Server(#[from] ServerError),
+    /// The request is longer than [`Config::max_request_len`], so it was not sent.
+    #[error("the request is {len} bytes long, exceeding the maximum of {max} bytes")]
+    RequestTooLarge {
+        /// The length of the request.
+        len: usize,
+        /// The maximum allowed length.
+        max: usize,
+    },
+    /// The connection was lost before the request completed.
+    #[error(transparent)]
+    Disconnected(#[from] Disconnected),
+    /// The call was made on behalf of a request that was canceled. See
+    /// [`context::Cancellation`].
+    #[error("the call was canceled along with the request it was made for")]
+    Canceled,
+    /// The [circuit breaker](Config::circuit_breaker) is open because too many recent calls
+    /// failed, so the request was not sent.
+    #[error("the circuit breaker is open, so the request was not sent")]
+    CircuitOpen,
 }
 
-impl<Resp> ResponseGuard<'_, Resp> {
-    async fn response(mut self) -> Result<Resp, RpcError> {
+/// The connection to the server was lost while a request was outstanding.
+#[derive(thiserror::Error, Debug, Clone)]
+#[error("the connection was closed by the {closed_by} before the request completed (request written: {request_written})")]
+pub struct Disconnected {
+    /// Which side closed the connection.
+    pub closed_by: ClosedBy,
+    /// Whether the request was written to the transport. When false, the server never saw the
+    /// request, so it is safe to retry.
+    pub request_written: bool,
+    /// The transport error that caused the connection to close, if any.
+    #[source]
+    pub source: Option<Arc<dyn std::error::Error + Send + Sync + 'static>>,
+}
+
+/// The cause of a channel failure, as reported to the requests that were outstanding. The
+/// dispatch returns the [`ChannelError`] itself, which owns the transport error, so the requests
+/// get its description unless the error is shared anyway.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct ChannelFailure(String);
+
+impl ChannelFailure {
+    fn shared<E>(error: &ChannelError<E>) -> Arc<dyn std::error::Error + Send + Sync + 'static>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        match error {
+            ChannelError::Read(e) => Arc::new(ChannelError::Read(e.clone())),
+            ChannelError::WriteStalled(timeout) => {
+                Arc::new(ChannelError::<E>::WriteStalled(*timeout))
+            }
+            ChannelError::ReadIdle(timeout) => Arc::new(ChannelError::<E>::ReadIdle(*timeout)),
+            _ => Arc::new(ChannelFailure(crate::util::print_err(error))),
+        }
+    }
+}
+
+/// Requests were still in flight when the timeout of [`Channel::shutdown`] elapsed, so the
+/// dispatch was stopped and the requests failed.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("requests were still in flight {timeout:?} after shutdown began")]
+pub struct ShutdownTimedOut {
+    /// The timeout that elapsed.
+    pub timeout: Duration,
+}
+
+/// Identifies which side closed a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClosedBy {
+    /// The client closed the connection, typically because its transport failed.
+    Local,
+    /// The server closed the connection.
+    Remote,
+}
+
+impl fmt::Display for ClosedBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClosedBy::Local => write!(f, "client"),
+            ClosedBy::Remote => write!(f, "server"),
+        }
+    }
+}
+
+impl<'a, Resp> ResponseGuard<'a, Resp> {
+    /// Returns a guard for the response to the request with ID `request_id`, to be received on
+    /// `response`. Dropping the guard cancels the request via `cancellation`.
+    pub fn new(
+        response: &'a mut oneshot::Receiver<Result<Resp, RpcError>>,
+        cancellation: &'a RequestCancellation,
+        request_id: u64,
+    ) -> Self {
+        Self {
+            response,
+            cancellation,
+            request_id,
+            cancel: true,
+        }
+    }
+
+    /// Waits for the response. Returns [`RpcError::Shutdown`] if the dispatch dropped the request
+    /// without completing it.
+    pub async fn response(mut self) -> Result<Resp, RpcError> {
         let response = (&mut self.response).await;
         // Cancel drop logic once a response has been received.
         self.cancel = false;
@@ -210,6 +1505,15 @@ impl<Resp> ResponseGuard<'_, Resp> {
     }
 }
 
+impl<Resp> fmt::Debug for ResponseGuard<'_, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseGuard")
+            .field("request_id", &self.request_id)
+            .field("cancel", &self.cancel)
+            .finish_non_exhaustive()
+    }
+}
+
 // Cancels the request when dropped, if not already complete.
 impl<Resp> Drop for ResponseGuard<'_, Resp> {
     fn drop(&mut self) {
@@ -230,29 +1534,288 @@ impl<Resp> Drop for ResponseGuard<'_, Resp> {
     }
 }
 
+/// The responses to a [streaming request](Channel::call_stream), yielded as they arrive off the
+/// wire. Dropping the stream before it ends cancels the request.
+pub struct ResponseStream<Resp> {
+    /// The responses that precede the final response.
+    items: mpsc::UnboundedReceiver<Resp>,
+    response: oneshot::Receiver<Result<Resp, RpcError>>,
+    /// The final response, once received, while responses that arrived before it are yielded.
+    last: Option<Result<Resp, RpcError>>,
+    cancellation: RequestCancellation,
+    request_id: u64,
+    done: bool,
+}
+
+impl<Resp> ResponseStream<Resp> {
+    /// Returns the ID of the request.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Maps each response with `item`, ending the stream at the first response it returns `None`
+    /// for. Generated clients use this to unwrap the items of their streaming methods.
+    pub fn items<T>(
+        self,
+        item: impl FnMut(Resp) -> Option<T>,
+    ) -> impl Stream<Item = Result<T, RpcError>> {
+        unwrap_items(self, item)
+    }
+}
+
+/// Maps each response of `responses` with `item`, ending the stream at the first response it
+/// returns `None` for.
+fn unwrap_items<Resp, T>(
+    responses: impl Stream<Item = Result<Resp, RpcError>>,
+    mut item: impl FnMut(Resp) -> Option<T>,
+) -> impl Stream<Item = Result<T, RpcError>> {
+    responses
+        .map(move |response| response.map(&mut item))
+        .take_while(|item| future::ready(!matches!(item, Ok(None))))
+        .map(|item| item.map(Option::unwrap))
+}
+
+// No field is structurally pinned.
+impl<Resp> Unpin for ResponseStream<Resp> {}
+
+impl<Resp> Stream for ResponseStream<Resp> {
+    type Item = Result<Resp, RpcError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(Some(item)) = self.items.poll_recv(cx) {
+            return Poll::Ready(Some(Ok(item)));
+        }
+        if self.last.is_none() {
+            let last = match ready!(Pin::new(&mut self.response).poll(cx)) {
+                Ok(last) => last,
+                // The oneshot is Canceled when the dispatch task ends.
+                Err(oneshot::error::RecvError { .. }) => Err(RpcError::Shutdown),
+            };
+            self.last = Some(last);
+        }
+        // The dispatch sends every other response before the final one.
+        if let Ok(item) = self.items.try_recv() {
+            return Poll::Ready(Some(Ok(item)));
+        }
+        self.done = true;
+        Poll::Ready(self.last.take())
+    }
+}
+
+impl<Resp> fmt::Debug for ResponseStream<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseStream")
+            .field("request_id", &self.request_id)
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+// Cancels the request when dropped, if not already complete. See ResponseGuard.
+impl<Resp> Drop for ResponseStream<Resp> {
+    fn drop(&mut self) {
+        self.response.close();
+        if !self.done && self.last.is_none() {
+            self.cancellation.cancel(self.request_id);
+        }
+    }
+}
+
+/// The responses to a [duplex request](Channel::call_duplex), which also sends the request's
+/// items as it is polled. Dropping the stream before it ends cancels the request.
+#[pin_project]
+pub struct DuplexStream<Req, Resp, S> {
+    /// The items to send, polled only while `items_tx` is open.
+    #[pin]
+    items: S,
+    /// An item taken from `items` that is waiting for room in `items_tx`.
+    pending: Option<Req>,
+    /// Hands the items to the dispatch, which writes them after the request. Dropping it marks
+    /// the end of the items.
+    items_tx: Option<PollSender<Req>>,
+    /// The responses, until the stream ends. Dropping them cancels the request.
+    responses: Option<ResponseStream<Resp>>,
+    request_id: u64,
+    max_request_len: Option<usize>,
+    request_len: fn(&Req) -> usize,
+}
+
+impl<Req, Resp, S> DuplexStream<Req, Resp, S>
+where
+    Req: Send + 'static,
+    S: Stream<Item = Req>,
+{
+    /// Returns the ID of the request.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Maps each response with `item`, ending the stream at the first response it returns `None`
+    /// for. Generated clients use this to unwrap the items of their duplex methods.
+    pub fn items<T>(
+        self,
+        item: impl FnMut(Resp) -> Option<T>,
+    ) -> impl Stream<Item = Result<T, RpcError>> {
+        unwrap_items(self, item)
+    }
+
+    /// Hands as many items to the dispatch as it has room for.
+    fn poll_send_items(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Result<(), RpcError> {
+        let mut this = self.project();
+        while let Some(items_tx) = this.items_tx {
+            if this.pending.is_none() {
+                match this.items.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(item)) => {
+                        if let Some(max) = *this.max_request_len {
+                            let len = (this.request_len)(&item);
+                            if len > max {
+                                tracing::info!("RequestTooLarge: {} > {}", len, max);
+                                return Err(RpcError::RequestTooLarge { len, max });
+                            }
+                        }
+                        *this.pending = Some(item);
+                    }
+                    Poll::Ready(None) => {
+                        tracing::trace!(request_id = *this.request_id, "SendRequestItemsEnd");
+                        *this.items_tx = None;
+                    }
+                    Poll::Pending => break,
+                }
+                continue;
+            }
+            match items_tx.poll_reserve(cx) {
+                Poll::Ready(Ok(())) => {
+                    let item = this.pending.take().unwrap();
+                    if items_tx.send_item(item).is_err() {
+                        *this.items_tx = None;
+                    }
+                }
+                // The request already completed.
+                Poll::Ready(Err(_)) => {
+                    *this.pending = None;
+                    *this.items_tx = None;
+                }
+                Poll::Pending => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Req, Resp, S> Stream for DuplexStream<Req, Resp, S>
+where
+    Req: Send + 'static,
+    S: Stream<Item = Req>,
+{
+    type Item = Result<Resp, RpcError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let sent = self.as_mut().poll_send_items(cx);
+        let this = self.project();
+        if let Err(e) = sent {
+            // Dropping the responses cancels the request.
+            *this.responses = None;
+            *this.items_tx = None;
+            return Poll::Ready(Some(Err(e)));
+        }
+        let responses = match this.responses {
+            Some(responses) => responses,
+            None => return Poll::Ready(None),
+        };
+        match ready!(responses.poll_next_unpin(cx)) {
+            Some(response) => Poll::Ready(Some(response)),
+            None => {
+                *this.responses = None;
+                *this.pending = None;
+                *this.items_tx = None;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl<Req, Resp, S> fmt::Debug for DuplexStream<Req, Resp, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DuplexStream")
+            .field("request_id", &self.request_id)
+            .field("sending", &self.items_tx.is_some())
+            .field("done", &self.responses.is_none())
+            .finish_non_exhaustive()
+    }
+}
+
 /// Returns a channel and dispatcher that manages the lifecycle of requests initiated by the
 /// channel.
 pub fn new<Req, Resp, C>(
     config: Config,
     transport: C,
 ) -> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    let session = Session::new(config.request_ids);
+    resume(config, session, transport)
+}
+
+/// Returns a channel and dispatcher for a transport that is already connected, continuing a
+/// [`Session`] captured from another client, possibly in another process. The request IDs of the
+/// new channel pick up where the session left off; the session, not `config`, determines how
+/// they are chosen.
+///
+/// Requests that the other client had in flight are not carried over: responses to them are
+/// dropped, as are responses to any request the new client does not know of.
+pub fn resume<Req, Resp, C>(
+    config: Config,
+    session: Session,
+    transport: C,
+) -> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>>
 where
     C: Transport<ClientMessage<Req>, Response<Resp>>,
 {
     let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
     let (cancellation, canceled_requests) = cancellations();
+    let half_close = Arc::new(HalfClose::default());
 
     NewClient {
         client: Channel {
             to_dispatch,
             cancellation,
-            next_request_id: Arc::new(AtomicUsize::new(0)),
+            next_request_id: Arc::new(AtomicUsize::new(
+                usize::try_from(session.next_request_id).unwrap_or(usize::MAX),
+            )),
+            correlation_key: session.correlation_key,
+            max_request_len: config.max_request_len,
+            request_len: std::mem::size_of_val,
+            half_close: half_close.clone(),
+            journal: config.journal.clone(),
+            retry_policy: config.retry_policy.clone(),
+            circuit_breaker: config.circuit_breaker.clone(),
+            request_clone: None,
+            max_in_flight_requests: config.max_in_flight_requests,
         },
         dispatch: RequestDispatch {
+            tuner: config
+                .auto_tune
+                .then(|| Tuner::new(config.max_in_flight_requests)),
+            unflushed: 0,
+            unflushed_requests: Vec::new(),
+            unflushed_one_way: Vec::new(),
+            watch: config.watchdog.as_ref().map(Watchdog::watch),
+            write_stall: None,
+            batch_deadline: None,
+            half_close,
+            write_closed: false,
+            ordered_responses: config.ordered_responses.then(OrderedResponses::default),
+            reconnect: None,
             config,
             canceled_requests,
             transport: transport.fuse(),
             in_flight_requests: InFlightRequests::default(),
+            streams: FnvHashMap::default(),
+            request_items: SelectAll::new(),
             pending_requests,
         },
     }
@@ -260,8 +1823,15 @@ where
 
 /// Handles the lifecycle of requests, writing requests to the wire, managing cancellations,
 /// and dispatching responses to the appropriate channel.
+///
+/// The dispatch resolves once the connection is shut down. If the transport failed, it resolves
+/// to a [`ChannelError`] identifying which transport operation failed, which supervisors can
+/// match on to decide whether to reconnect. The dispatch closes the write half of its transport
+/// once its channels [finish sending](Channel::finish_sending) or are all dropped, and keeps
+/// reading responses until no requests are in flight; see [`Channel::finish_sending`] for how
+/// each side shuts down.
 #[must_use]
-#[pin_project]
+#[pin_project(PinnedDrop)]
 #[derive(Debug)]
 pub struct RequestDispatch<Req, Resp, C> {
     /// Writes requests to the wire and reads responses off the wire.
@@ -273,8 +1843,62 @@ pub struct RequestDispatch<Req, Resp, C> {
     canceled_requests: CanceledRequests,
     /// Requests already written to the wire that haven't yet received responses.
     in_flight_requests: InFlightRequests<Result<Resp, RpcError>>,
+    /// Forwards the responses that precede the final response of each streaming request in
+    /// flight.
+    streams: FnvHashMap<u64, mpsc::UnboundedSender<Resp>>,
+    /// The items waiting to be written to each streamed request in flight.
+    request_items: SelectAll<RequestItems<Req>>,
     /// Configures limits to prevent unlimited resource usage.
     config: Config,
+    /// Adapts the in-flight limit and flush batch size, if auto-tuning is enabled.
+    tuner: Option<Tuner>,
+    /// The number of messages written to the transport since it was last flushed.
+    unflushed: usize,
+    /// The IDs of requests written to the transport since it was last flushed, if the dispatch
+    /// is logged.
+    unflushed_requests: Vec<u64>,
+    /// Completes the one-way requests written to the transport since it was last flushed.
+    unflushed_one_way: Vec<oneshot::Sender<Result<(), RpcError>>>,
+    /// Tracks outstanding requests for the configured watchdog, if any.
+    watch: Option<Watch>,
+    /// Fires when writing to the transport has made no progress for the write timeout. Armed
+    /// while a flush is pending, and cleared whenever a message is written or a flush completes.
+    write_stall: Option<Pin<Box<Sleep>>>,
+    /// Fires when the first unflushed message has waited the write batch's max delay.
+    batch_deadline: Option<Pin<Box<Sleep>>>,
+    /// Set by channels that have finished sending requests.
+    half_close: Arc<HalfClose>,
+    /// Whether the write half of the transport has been closed.
+    write_closed: bool,
+    /// Responses held until earlier requests complete, if responses are ordered.
+    ordered_responses: Option<OrderedResponses<Result<Resp, RpcError>>>,
+    /// Connects a new transport when the connection is lost, if reconnecting is enabled.
+    reconnect: Option<Reconnect<C>>,
+}
+
+impl<Req, Resp, C> RequestDispatch<Req, Resp, C> {
+    /// Re-establishes the connection with `connect` whenever the transport fails or the server
+    /// closes it, instead of shutting down, waiting between attempts according to `backoff`.
+    ///
+    /// Requests in flight when the connection is lost fail right away with
+    /// [`RpcError::Disconnected`], since the server may or may not have handled them. Requests
+    /// not yet written, including those made while reconnecting, wait in the channel's buffer and
+    /// are sent over the new connection; requests whose deadline passes in the meantime fail with
+    /// [`RpcError::DeadlineExceeded`] once the dispatch reconnects.
+    ///
+    /// The dispatch stops reconnecting, and resolves, once all its channels are dropped or have
+    /// [finished sending](Channel::finish_sending); while disconnected, this is noticed before
+    /// the next connection attempt.
+    pub fn with_reconnect<F, Fut, E>(mut self, backoff: Backoff, connect: F) -> Self
+    where
+        C: Send + 'static,
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, E>> + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.reconnect = Some(Reconnect::new(backoff, connect));
+        self
+    }
 }
 
 impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
@@ -306,25 +1930,91 @@ where
     ) -> Result<(), ChannelError<C::Error>> {
         self.transport_pin_mut()
             .start_send(message)
-            .map_err(ChannelError::Write)
+            .map_err(ChannelError::Write)?;
+        let this = self.as_mut().project();
+        *this.write_stall = None;
+        if *this.unflushed == 0 {
+            if let Some(batch) = this.config.write_batch {
+                *this.batch_deadline = Some(Box::pin(tokio::time::sleep(batch.max_delay)));
+            }
+        }
+        *this.unflushed += 1;
+        Ok(())
     }
 
     fn poll_flush<'a>(
         self: &'a mut Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), ChannelError<C::Error>>> {
-        self.transport_pin_mut()
-            .poll_flush(cx)
-            .map_err(ChannelError::Flush)
+        let flushed = match self.transport_pin_mut().poll_flush(cx) {
+            Poll::Ready(flushed) => flushed,
+            Poll::Pending => {
+                if let Some(write_timeout) = self.config.write_timeout {
+                    let write_stall = self
+                        .as_mut()
+                        .project()
+                        .write_stall
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(write_timeout)));
+                    if write_stall.as_mut().poll(cx).is_ready() {
+                        tracing::warn!("WriteStalled: no progress writing for {:?}", write_timeout);
+                        return Poll::Ready(Err(ChannelError::WriteStalled(write_timeout)));
+                    }
+                }
+                return Poll::Pending;
+            }
+        };
+        flushed.map_err(ChannelError::Flush)?;
+        let this = self.as_mut().project();
+        *this.write_stall = None;
+        *this.batch_deadline = None;
+        *this.unflushed = 0;
+        for flushed in this.unflushed_one_way.drain(..) {
+            let _ = flushed.send(Ok(()));
+        }
+        for request_id in this.unflushed_requests.drain(..) {
+            if let Some(log) = &this.config.dispatch_log {
+                log.record(request_id, Op::Flushed);
+            }
+            if let Some(watch) = this.watch {
+                watch.record(request_id, Op::Flushed);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Records a state transition of a request in the dispatch log and with the watchdog, if
+    /// either is configured.
+    fn log(&self, request_id: u64, op: Op) {
+        if let Some(log) = &self.config.dispatch_log {
+            log.record(request_id, op);
+        }
+        if let Some(watch) = &self.watch {
+            watch.record(request_id, op);
+        }
     }
 
     fn poll_close<'a>(
         self: &'a mut Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), ChannelError<C::Error>>> {
-        self.transport_pin_mut()
-            .poll_close(cx)
-            .map_err(ChannelError::Close)
+        if self.write_closed {
+            return Poll::Ready(Ok(()));
+        }
+        let reason = *self.half_close.reason.lock().unwrap();
+        if let Some(reason) = reason {
+            ready!(self.poll_ready(cx)?);
+            self.start_send(ClientMessage::Close { reason })?;
+            self.half_close.reason.lock().unwrap().take();
+            tracing::info!(%reason, "SendClose");
+        }
+        ready!(self.transport_pin_mut().poll_close(cx)).map_err(ChannelError::Close)?;
+        let this = self.as_mut().project();
+        *this.write_closed = true;
+        // Closing the transport flushes it.
+        for flushed in this.unflushed_one_way.drain(..) {
+            let _ = flushed.send(Ok(()));
+        }
+        Poll::Ready(Ok(()))
     }
 
     fn canceled_requests_mut<'a>(self: &'a mut Pin<&mut Self>) -> &'a mut CanceledRequests {
@@ -337,26 +2027,177 @@ where
         self.as_mut().project().pending_requests
     }
 
-    fn pump_read(
+    fn poll_dispatch(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
-        self.transport_pin_mut()
-            .poll_next(cx)
-            .map_err(|e| {
-                let e = Arc::new(e);
-                for span in self
-                    .in_flight_requests()
-                    .complete_all_requests(|| Err(RpcError::Receive(e.clone())))
-                {
-                    let _entered = span.enter();
-                    tracing::info!("ReceiveError");
+    ) -> Poll<Result<(), ChannelError<C::Error>>> {
+        loop {
+            // A closed read half is checked before writing so that a server disconnect is not
+            // misreported as a local write failure.
+            let read = self.as_mut().pump_read(cx)?;
+            if let Poll::Ready(None) = read {
+                tracing::info!("Shutdown: read half closed, so shutting down.");
+                return Poll::Ready(Ok(()));
+            }
+            match (read, self.as_mut().pump_write(cx)?) {
+                (read, Poll::Ready(None)) => {
+                    if self.in_flight_requests.is_empty() {
+                        tracing::info!("Shutdown: write half closed, and no requests in flight.");
+                        return Poll::Ready(Ok(()));
+                    }
+                    tracing::info!(
+                        "Shutdown: write half closed, and {} requests in flight.",
+                        self.in_flight_requests().len()
+                    );
+                    match read {
+                        Poll::Ready(Some(())) => continue,
+                        _ => return Poll::Pending,
+                    }
                 }
-                ChannelError::Read(e)
-            })
-            .map_ok(|response| {
+                (Poll::Ready(Some(())), _) | (_, Poll::Ready(Some(()))) => {}
+                _ => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Completes every outstanding request with a [`Disconnected`] error. Requests already
+    /// written to the transport are distinguished from those still waiting to be sent.
+    fn fail_outstanding_requests(
+        mut self: Pin<&mut Self>,
+        closed_by: ClosedBy,
+        source: Option<Arc<dyn std::error::Error + Send + Sync + 'static>>,
+    ) {
+        let disconnected = |request_written| Disconnected {
+            closed_by,
+            request_written,
+            source: source.clone(),
+        };
+        self.as_mut()
+            .fail_in_flight_requests(closed_by, source.clone());
+        let pending_requests = self.pending_requests_mut();
+        pending_requests.close();
+        while let Ok(request) = pending_requests.try_recv() {
+            let _entered = request.span.enter();
+            tracing::info!("Disconnected");
+            request
+                .completion
+                .fail(RpcError::Disconnected(disconnected(false)));
+        }
+    }
+
+    /// Completes every request written to the transport with a [`Disconnected`] error.
+    fn fail_in_flight_requests(
+        mut self: Pin<&mut Self>,
+        closed_by: ClosedBy,
+        source: Option<Arc<dyn std::error::Error + Send + Sync + 'static>>,
+    ) {
+        if let Some(ordered_responses) = self.as_mut().project().ordered_responses {
+            ordered_responses.clear();
+        }
+        if let Some(watch) = &self.watch {
+            watch.forget_all();
+        }
+        self.as_mut().project().streams.clear();
+        *self.as_mut().project().request_items = SelectAll::new();
+        for flushed in self.as_mut().project().unflushed_one_way.drain(..) {
+            let _ = flushed.send(Err(RpcError::Disconnected(Disconnected {
+                closed_by,
+                request_written: true,
+                source: source.clone(),
+            })));
+        }
+        for span in self.in_flight_requests().complete_all_requests(|| {
+            Err(RpcError::Disconnected(Disconnected {
+                closed_by,
+                request_written: true,
+                source: source.clone(),
+            }))
+        }) {
+            let _entered = span.enter();
+            tracing::info!("Disconnected");
+        }
+    }
+
+    /// Returns true iff the dispatch should replace its lost connection rather than shut down.
+    fn should_reconnect(&self) -> bool {
+        self.reconnect.is_some()
+            && !self.write_closed
+            && !self.half_close.is_requested()
+            && !self.pending_requests.is_closed()
+    }
+
+    /// Fails the requests in flight on the lost connection and schedules a reconnect.
+    fn disconnect(
+        mut self: Pin<&mut Self>,
+        closed_by: ClosedBy,
+        source: Option<Arc<dyn std::error::Error + Send + Sync + 'static>>,
+    ) {
+        self.as_mut()
+            .fail_in_flight_requests(closed_by, source.clone());
+        let this = self.project();
+        *this.unflushed = 0;
+        this.unflushed_requests.clear();
+        *this.write_stall = None;
+        *this.batch_deadline = None;
+        if let Some(reconnect) = this.reconnect {
+            reconnect.disconnected(source.as_deref().map(|e| e as _));
+        }
+    }
+
+    fn pump_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
+        match ready!(self.transport_pin_mut().poll_next(cx)) {
+            Some(Ok(response)) => {
                 self.complete(response);
-            })
+                Poll::Ready(Some(Ok(())))
+            }
+            Some(Err(e)) => Poll::Ready(Some(self.handle_read_error(e))),
+            None => Poll::Ready(None),
+        }
+    }
+
+    /// Applies the configured [`MalformedFramePolicy`] to a transport read error. Returns an error
+    /// iff the dispatch should shut down, in which case all in-flight requests are failed.
+    fn handle_read_error(
+        mut self: Pin<&mut Self>,
+        e: C::Error,
+    ) -> Result<(), ChannelError<C::Error>> {
+        let malformed = MalformedFrame::find(&e).map(MalformedFrame::request_id);
+        match (self.config.malformed_frame_policy, malformed) {
+            (MalformedFramePolicy::Close, _) | (_, None) => {
+                if let Some(watch) = &self.watch {
+                    watch.forget_all();
+                }
+                self.as_mut().project().streams.clear();
+                *self.as_mut().project().request_items = SelectAll::new();
+                let e = Arc::new(e);
+                for span in self
+                    .in_flight_requests()
+                    .complete_all_requests(|| Err(RpcError::Receive(e.clone())))
+                {
+                    let _entered = span.enter();
+                    tracing::info!("ReceiveError");
+                }
+                Err(ChannelError::Read(e))
+            }
+            (MalformedFramePolicy::Respond, Some(Some(request_id))) => {
+                self.as_mut().project().streams.remove(&request_id);
+                if let Some(span) = self
+                    .in_flight_requests()
+                    .complete_request(request_id, Err(RpcError::Receive(Arc::new(e))))
+                {
+                    let _entered = span.enter();
+                    tracing::warn!("ReceiveMalformedResponse");
+                }
+                Ok(())
+            }
+            (_, Some(_)) => {
+                tracing::warn!("DropMalformedFrame: {}", e);
+                Ok(())
+            }
+        }
     }
 
     fn pump_write(
@@ -368,6 +2209,26 @@ where
             Closed,
         }
 
+        // Cancelled and expired requests may have been holding up ordered responses.
+        self.as_mut().release_ordered_responses();
+
+        if self.half_close.is_requested() {
+            // Requests already in the buffer are still sent.
+            self.pending_requests_mut().close();
+        } else {
+            self.half_close.dispatch.register(cx.waker());
+        }
+
+        let flush_batch = match (&self.tuner, self.config.write_batch) {
+            (Some(tuner), Some(batch)) => Some(tuner.flush_batch().min(batch.max_messages)),
+            (Some(tuner), None) => Some(tuner.flush_batch()),
+            (None, Some(batch)) => Some(batch.max_messages),
+            (None, None) => None,
+        };
+        if matches!(flush_batch, Some(flush_batch) if self.unflushed >= flush_batch) {
+            ready!(self.poll_flush(cx)?);
+        }
+
         let pending_requests_status = match self.as_mut().poll_write_request(cx)? {
             Poll::Ready(Some(())) => return Poll::Ready(Some(Ok(()))),
             Poll::Ready(None) => ReceiverStatus::Closed,
@@ -380,26 +2241,52 @@ where
             Poll::Pending => ReceiverStatus::Pending,
         };
 
+        let request_items_status = match self.as_mut().poll_write_item(cx)? {
+            Poll::Ready(Some(())) => return Poll::Ready(Some(Ok(()))),
+            Poll::Ready(None) => ReceiverStatus::Closed,
+            Poll::Pending => ReceiverStatus::Pending,
+        };
+
         // Receiving Poll::Ready(None) when polling expired requests never indicates "Closed",
         // because there can temporarily be zero in-flight rquests. Therefore, there is no need to
         // track the status like is done with pending and cancelled requests.
-        if let Poll::Ready(Some(_)) = self
+        if let Poll::Ready(Some(request_id)) = self
             .in_flight_requests()
             .poll_expired(cx, || Err(RpcError::DeadlineExceeded))
         {
+            self.as_mut().project().streams.remove(&request_id);
+            self.log(request_id, Op::Expired);
             // Expired requests are considered complete; there is no compelling reason to send a
             // cancellation message to the server, since it will have already exhausted its
             // allotted processing time.
             return Poll::Ready(Some(Ok(())));
         }
 
-        match (pending_requests_status, canceled_requests_status) {
-            (ReceiverStatus::Closed, ReceiverStatus::Closed) => {
+        match (
+            pending_requests_status,
+            canceled_requests_status,
+            request_items_status,
+        ) {
+            (ReceiverStatus::Closed, ReceiverStatus::Closed, ReceiverStatus::Closed) => {
                 ready!(self.poll_close(cx)?);
                 Poll::Ready(None)
             }
-            (ReceiverStatus::Pending, _) | (_, ReceiverStatus::Pending) => {
-                // No more messages to process, so flush any messages buffered in the transport.
+            // Channels that finished sending may still cancel requests, but that need not keep
+            // the write half open. The items of streamed requests in flight must still be sent.
+            (ReceiverStatus::Closed, ReceiverStatus::Pending, ReceiverStatus::Closed)
+                if self.half_close.is_requested() =>
+            {
+                ready!(self.poll_close(cx)?);
+                Poll::Ready(None)
+            }
+            (ReceiverStatus::Pending, _, _)
+            | (_, ReceiverStatus::Pending, _)
+            | (_, _, ReceiverStatus::Pending) => {
+                // No more messages to process, so flush any messages buffered in the transport,
+                // unless they may wait for more messages to batch with.
+                if let Some(batch_deadline) = self.as_mut().project().batch_deadline {
+                    ready!(batch_deadline.as_mut().poll(cx));
+                }
                 ready!(self.poll_flush(cx)?);
 
                 // Even if we fully-flush, we return Pending, because we have no more requests
@@ -417,11 +2304,18 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<DispatchRequest<Req, Resp>, ChannelError<C::Error>>>> {
-        if self.in_flight_requests().len() >= self.config.max_in_flight_requests {
+        if self.write_closed {
+            return Poll::Ready(None);
+        }
+        let max_in_flight_requests = match &self.tuner {
+            Some(tuner) => tuner.in_flight_limit(),
+            None => self.config.max_in_flight_requests,
+        };
+        if self.in_flight_requests().len() >= max_in_flight_requests {
             tracing::info!(
                 "At in-flight request capacity ({}/{}).",
                 self.in_flight_requests().len(),
-                self.config.max_in_flight_requests
+                max_in_flight_requests
             );
 
             // No need to schedule a wakeup, because timers and responses are responsible
@@ -434,9 +2328,19 @@ where
         loop {
             match ready!(self.pending_requests_mut().poll_recv(cx)) {
                 Some(request) => {
-                    if request.response_completion.is_closed() {
+                    if request.completion.is_closed() {
                         let _entered = request.span.enter();
                         tracing::info!("AbortRequest");
+                        self.log(request.request_id, Op::Canceled);
+                        continue;
+                    }
+                    if request.ctx.deadline <= clock::now() {
+                        // The request expired while waiting to be sent, so the server would
+                        // not have any time to process it.
+                        let _entered = request.span.enter();
+                        tracing::info!("DeadlineExceeded");
+                        self.log(request.request_id, Op::Expired);
+                        request.completion.fail(RpcError::DeadlineExceeded);
                         continue;
                     }
 
@@ -455,13 +2359,16 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<(context::Context, Span, u64), ChannelError<C::Error>>>> {
-        ready!(self.ensure_writeable(cx)?);
+        if !self.write_closed {
+            ready!(self.ensure_writeable(cx)?);
+        }
 
         loop {
             match ready!(self.canceled_requests_mut().poll_next_unpin(cx)) {
                 Some(request_id) => {
                     if let Some((ctx, span)) = self.in_flight_requests().cancel_request(request_id)
                     {
+                        self.as_mut().project().streams.remove(&request_id);
                         return Poll::Ready(Some(Ok((ctx, span, request_id))));
                     }
                 }
@@ -488,16 +2395,23 @@ where
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
         let DispatchRequest {
-            ctx,
+            mut ctx,
             span,
             request_id,
             request,
-            response_completion,
+            completion,
+            items,
+            enqueued_at,
         } = match ready!(self.as_mut().poll_next_request(cx)?) {
             Some(dispatch_request) => dispatch_request,
             None => return Poll::Ready(None),
         };
         let _entered = span.enter();
+        ctx.queue_time.client = enqueued_at.elapsed();
+        span.record(
+            "rpc.queue_time.client",
+            tracing::field::debug(ctx.queue_time.client),
+        );
         // poll_next_request only returns Ready if there is room to buffer another request.
         // Therefore, we can call write_request without fear of erroring due to a full
         // buffer.
@@ -507,14 +2421,59 @@ where
             context: context::Context {
                 deadline: ctx.deadline,
                 trace_context: ctx.trace_context,
+                queue_time: ctx.queue_time,
+                envelope: None,
             },
+            one_way: matches!(completion, Completion::Flushed(_)),
+            streamed: items.is_some(),
         });
+        let response_completion = match completion {
+            Completion::Response(response_completion) => response_completion,
+            Completion::Stream { items, response } => {
+                self.as_mut().project().streams.insert(request_id, items);
+                response
+            }
+            Completion::Flushed(flushed) => {
+                // No response will arrive, so the request is not tracked as in flight.
+                match self.start_send(request) {
+                    Ok(()) => {
+                        tracing::info!("SendOneWayRequest");
+                        self.as_mut().project().unflushed_one_way.push(flushed);
+                    }
+                    Err(e) => {
+                        let _ = flushed.send(Err(RpcError::Send(Box::new(e))));
+                    }
+                }
+                return Poll::Ready(Some(Ok(())));
+            }
+        };
         self.in_flight_requests()
             .insert_request(request_id, ctx, span.clone(), response_completion)
             .expect("Request IDs should be unique");
+        if let Some(watch) = &self.watch {
+            watch.staged(request_id, ctx.deadline.time_until());
+        }
+        self.log(request_id, Op::Staged);
         match self.start_send(request) {
-            Ok(()) => tracing::info!("SendRequest"),
+            Ok(()) => {
+                tracing::info!("SendRequest");
+                self.log(request_id, Op::Written);
+                let this = self.as_mut().project();
+                if this.config.dispatch_log.is_some() || this.watch.is_some() {
+                    this.unflushed_requests.push(request_id);
+                }
+                if let Some(ordered_responses) = self.as_mut().project().ordered_responses {
+                    ordered_responses.written(request_id);
+                }
+                if let Some(items) = items {
+                    self.as_mut().project().request_items.push(RequestItems {
+                        request_id,
+                        items: Some(items),
+                    });
+                }
+            }
             Err(e) => {
+                self.as_mut().project().streams.remove(&request_id);
                 self.in_flight_requests()
                     .complete_request(request_id, Err(RpcError::Send(Box::new(e))));
             }
@@ -522,6 +2481,39 @@ where
         Poll::Ready(Some(Ok(())))
     }
 
+    /// Writes the next item of a streamed request, if one is ready. Items of requests that are no
+    /// longer in flight are dropped.
+    fn poll_write_item<'a>(
+        self: &'a mut Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
+        if self.write_closed {
+            // The server can no longer be sent the items.
+            *self.as_mut().project().request_items = SelectAll::new();
+            return Poll::Ready(None);
+        }
+        if self.request_items.is_empty() {
+            return Poll::Ready(None);
+        }
+        ready!(self.ensure_writeable(cx)?);
+
+        loop {
+            let (request_id, item) =
+                match ready!(self.as_mut().project().request_items.poll_next_unpin(cx)) {
+                    Some(request_item) => request_item,
+                    None => return Poll::Ready(None),
+                };
+            if !self.in_flight_requests().contains(request_id) {
+                continue;
+            }
+            if item.is_none() {
+                tracing::trace!(request_id, "SendRequestItemsEnd");
+            }
+            self.start_send(ClientMessage::Item { request_id, item })?;
+            return Poll::Ready(Some(Ok(())));
+        }
+    }
+
     fn poll_write_cancel<'a>(
         self: &'a mut Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -532,27 +2524,98 @@ where
         };
         let _entered = span.enter();
 
+        if self.write_closed {
+            // The server can no longer be told; it stops handling the request at its deadline.
+            tracing::info!("CancelRequestLocally");
+            self.log(request_id, Op::Canceled);
+            return Poll::Ready(Some(Ok(())));
+        }
         let cancel = ClientMessage::Cancel {
             trace_context: context.trace_context,
             request_id,
         };
         self.start_send(cancel)?;
         tracing::info!("CancelRequest");
+        self.log(request_id, Op::Canceled);
         Poll::Ready(Some(Ok(())))
     }
 
     /// Sends a server response to the client task that initiated the associated request.
     fn complete(mut self: Pin<&mut Self>, response: Response<Resp>) -> bool {
-        if let Some(span) = self.in_flight_requests().complete_request(
-            response.request_id,
-            response.message.map_err(RpcError::Server),
-        ) {
+        if response.more {
+            return self.forward(response);
+        }
+        self.as_mut().project().streams.remove(&response.request_id);
+        let rtt = self
+            .in_flight_requests()
+            .time_in_flight(response.request_id);
+        if let (Some(tuner), Some(rtt)) = (self.as_mut().project().tuner.as_mut(), rtt) {
+            tuner.on_response(rtt);
+        }
+        let message = response.message.map_err(RpcError::Server);
+        if self.ordered_responses.is_some() {
+            if !self.in_flight_requests().contains(response.request_id) {
+                tracing::debug!(
+                    "No in-flight request found for request_id = {}.",
+                    response.request_id
+                );
+                return false;
+            }
+            self.log(response.request_id, Op::Completed);
+            let this = self.as_mut().project();
+            if let Some(ordered_responses) = this.ordered_responses {
+                ordered_responses.insert(response.request_id, message);
+                ordered_responses.release(this.in_flight_requests);
+            }
+            return true;
+        }
+        if let Some(span) = self
+            .in_flight_requests()
+            .complete_request(response.request_id, message)
+        {
             let _entered = span.enter();
             tracing::info!("ReceiveResponse");
+            self.log(response.request_id, Op::Completed);
             return true;
         }
         false
     }
+
+    /// Sends a response that precedes the final response of a streaming request to the stream.
+    /// The request stays in flight.
+    fn forward(mut self: Pin<&mut Self>, response: Response<Resp>) -> bool {
+        let (items, message) = match (self.streams.get(&response.request_id), response.message) {
+            (Some(items), Ok(message)) => (items, message),
+            (None, _) => {
+                tracing::debug!(
+                    "No streaming request found for request_id = {}.",
+                    response.request_id
+                );
+                return false;
+            }
+            (Some(_), Err(e)) => {
+                // Only the final response can be an error.
+                return self.complete(Response {
+                    request_id: response.request_id,
+                    message: Err(e),
+                    more: false,
+                });
+            }
+        };
+        if items.send(message).is_err() {
+            // The stream was dropped, and the request is being canceled.
+            self.as_mut().project().streams.remove(&response.request_id);
+            return false;
+        }
+        true
+    }
+
+    fn release_ordered_responses(self: Pin<&mut Self>) {
+        let this = self.project();
+        if let Some(ordered_responses) = this.ordered_responses {
+            ordered_responses.release(this.in_flight_requests);
+        }
+    }
 }
 
 impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
@@ -564,30 +2627,62 @@ where
     fn poll(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ChannelError<C::Error>>> {
+        let result = self.as_mut().poll_run(cx);
+        let in_flight = self.in_flight_requests().len();
+        self.half_close
+            .in_flight
+            .store(in_flight, Ordering::Relaxed);
+        let result = ready!(result);
+        self.half_close.finish();
+        Poll::Ready(result)
+    }
+}
+
+#[pin_project::pinned_drop]
+impl<Req, Resp, C> PinnedDrop for RequestDispatch<Req, Resp, C> {
+    fn drop(self: Pin<&mut Self>) {
+        self.half_close.finish();
+    }
+}
+
+impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    fn poll_run(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), ChannelError<C::Error>>> {
         loop {
-            match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
-                (Poll::Ready(None), _) => {
-                    tracing::info!("Shutdown: read half closed, so shutting down.");
+            self.half_close.dispatch.register(cx.waker());
+            if self.half_close.is_aborted() {
+                tracing::info!("Shutdown: aborted with requests still in flight.");
+                self.fail_outstanding_requests(ClosedBy::Local, None);
+                return Poll::Ready(Ok(()));
+            }
+            if self.reconnect.as_ref().map_or(false, |r| !r.is_connected()) {
+                if !self.should_reconnect() {
+                    tracing::info!("Shutdown: channels finished sending while disconnected.");
+                    self.fail_outstanding_requests(ClosedBy::Local, None);
                     return Poll::Ready(Ok(()));
                 }
-                (read, Poll::Ready(None)) => {
-                    if self.in_flight_requests.is_empty() {
-                        tracing::info!("Shutdown: write half closed, and no requests in flight.");
-                        return Poll::Ready(Ok(()));
-                    }
-                    tracing::info!(
-                        "Shutdown: write half closed, and {} requests in flight.",
-                        self.in_flight_requests().len()
-                    );
-                    match read {
-                        Poll::Ready(Some(())) => continue,
-                        _ => return Poll::Pending,
-                    }
-                }
-                (Poll::Ready(Some(())), _) | (_, Poll::Ready(Some(()))) => {}
-                _ => return Poll::Pending,
+                let mut this = self.as_mut().project();
+                let reconnect = this.reconnect.as_mut().unwrap();
+                let transport = ready!(reconnect.poll_connect(cx));
+                this.transport.set(transport.fuse());
             }
+            let result = ready!(self.as_mut().poll_dispatch(cx));
+            let (closed_by, source) = match &result {
+                Ok(()) => (ClosedBy::Remote, None),
+                Err(e) => (ClosedBy::Local, Some(ChannelFailure::shared(e))),
+            };
+            if self.should_reconnect() {
+                self.as_mut().disconnect(closed_by, source);
+                continue;
+            }
+            self.fail_outstanding_requests(closed_by, source);
+            return Poll::Ready(result);
         }
     }
 }
@@ -600,38 +2695,128 @@ struct DispatchRequest<Req, Resp> {
     pub span: Span,
     pub request_id: u64,
     pub request: Req,
-    pub response_completion: oneshot::Sender<Result<Resp, RpcError>>,
+    pub completion: Completion<Resp>,
+    /// The items to write after the request, if it is streamed.
+    pub items: Option<mpsc::Receiver<Req>>,
+    /// When the request was handed to request dispatch.
+    pub enqueued_at: Instant,
+}
+
+/// How request dispatch reports the outcome of a request to the caller.
+#[derive(Debug)]
+enum Completion<Resp> {
+    /// The caller waits for the response.
+    Response(oneshot::Sender<Result<Resp, RpcError>>),
+    /// The caller of a streaming request receives the responses that precede the final response
+    /// as they arrive, then waits for the final response.
+    Stream {
+        items: mpsc::UnboundedSender<Resp>,
+        response: oneshot::Sender<Result<Resp, RpcError>>,
+    },
+    /// The caller of a one-way request waits until the request is flushed to the transport.
+    Flushed(oneshot::Sender<Result<(), RpcError>>),
+}
+
+impl<Resp> Completion<Resp> {
+    /// Returns true iff the caller stopped waiting.
+    fn is_closed(&self) -> bool {
+        match self {
+            Completion::Response(response_completion) => response_completion.is_closed(),
+            Completion::Stream { response, .. } => response.is_closed(),
+            Completion::Flushed(flushed) => flushed.is_closed(),
+        }
+    }
+
+    /// Fails the request with `error`.
+    fn fail(self, error: RpcError) {
+        match self {
+            Completion::Response(response_completion)
+            | Completion::Stream {
+                response: response_completion,
+                ..
+            } => {
+                let _ = response_completion.send(Err(error));
+            }
+            Completion::Flushed(flushed) => {
+                let _ = flushed.send(Err(error));
+            }
+        }
+    }
+}
+
+/// The items of a streamed request, yielded with the request's ID and followed by `None` once
+/// the caller stops sending them.
+#[derive(Debug)]
+struct RequestItems<Req> {
+    request_id: u64,
+    items: Option<mpsc::Receiver<Req>>,
+}
+
+impl<Req> Stream for RequestItems<Req> {
+    type Item = (u64, Option<Req>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let items = match &mut self.items {
+            Some(items) => items,
+            None => return Poll::Ready(None),
+        };
+        let item = ready!(items.poll_recv(cx));
+        if item.is_none() {
+            self.items = None;
+        }
+        Poll::Ready(Some((self.request_id, item)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    // Some tests drive a spawned dispatch, which requires tokio1.
+    #![cfg_attr(not(feature = "tokio1"), allow(unused_imports))]
+
     use super::{
-        cancellations, Channel, DispatchRequest, RequestDispatch, ResponseGuard, RpcError,
+        cancellations, Channel, ClosedBy, Completion, Disconnected, DispatchLog, DispatchRequest,
+        HalfClose, NewClient, Op, OrderedResponses, RequestDispatch, RequestIds, ResponseGuard,
+        RpcError, Tuner, WriteBatch,
     };
+    use crate::util::tracing::Span;
     use crate::{
-        client::{in_flight_requests::InFlightRequests, Config},
+        backoff::Backoff,
+        client::{
+            circuit_breaker::CircuitBreaker,
+            in_flight_requests::InFlightRequests,
+            journal::{Entry, Journal, Outcome},
+            retry_policy::{MissingRequestClone, RetryPolicy},
+            watchdog::{StalledRequest, Watchdog},
+            Config,
+        },
         context::{self, current},
-        transport::{self, channel::UnboundedChannel},
-        ChannelError, ClientMessage, Response,
+        server::{self, request_stream, response_sink, BaseChannel, Channel as _},
+        transport::{self, channel::UnboundedChannel, MalformedFrame, MalformedFramePolicy},
+        ChannelError, ClientMessage, CloseReason, InvalidConfig, Response, ServerError,
     };
     use assert_matches::assert_matches;
-    use futures::{prelude::*, task::*};
+    use fnv::FnvHashMap;
+    use futures::{prelude::*, stream, task::*};
     use std::{
+        collections::HashSet,
         convert::TryFrom,
         fmt::Display,
+        io,
         marker::PhantomData,
+        num::NonZeroUsize,
         pin::Pin,
         sync::{
             atomic::{AtomicUsize, Ordering},
             Arc,
         },
+        time::{Duration, SystemTime},
     };
     use thiserror::Error;
     use tokio::sync::{
         mpsc::{self},
         oneshot,
     };
-    use tracing::Span;
+    use tokio::time::Instant;
 
     #[tokio::test]
     async fn response_completes_request_future() {
@@ -647,6 +2832,7 @@ mod tests {
             .send(Response {
                 request_id: 0,
                 message: Ok("Resp".into()),
+                more: false,
             })
             .await
             .unwrap();
@@ -655,227 +2841,1454 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn dispatch_response_cancels_on_drop() {
-        let (cancellation, mut canceled_requests) = cancellations();
-        let (_, mut response) = oneshot::channel();
-        drop(ResponseGuard::<u32> {
-            response: &mut response,
-            cancellation: &cancellation,
-            request_id: 3,
-            cancel: true,
-        });
-        // resp's drop() is run, which should send a cancel message.
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        assert_eq!(canceled_requests.poll_recv(cx), Poll::Ready(Some(3)));
+    async fn dispatch_response_cancels_on_drop() {
+        let (cancellation, mut canceled_requests) = cancellations();
+        let (_, mut response) = oneshot::channel();
+        drop(ResponseGuard::<u32> {
+            response: &mut response,
+            cancellation: &cancellation,
+            request_id: 3,
+            cancel: true,
+        });
+        // resp's drop() is run, which should send a cancel message.
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_eq!(canceled_requests.poll_recv(cx), Poll::Ready(Some(3)));
+    }
+
+    #[tokio::test]
+    async fn dispatch_response_doesnt_cancel_after_complete() {
+        let (cancellation, mut canceled_requests) = cancellations();
+        let (tx, mut response) = oneshot::channel();
+        tx.send(Ok(Response {
+            request_id: 0,
+            message: Ok("well done"),
+            more: false,
+        }))
+        .unwrap();
+        // resp's drop() is run, but should not send a cancel message.
+        ResponseGuard {
+            response: &mut response,
+            cancellation: &cancellation,
+            request_id: 3,
+            cancel: true,
+        }
+        .response()
+        .await
+        .unwrap();
+        drop(cancellation);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_eq!(canceled_requests.poll_recv(cx), Poll::Ready(None));
+    }
+
+    #[tokio::test]
+    async fn ordered_responses_complete_in_write_order() {
+        let (mut dispatch, mut _channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut ordered_responses = OrderedResponses::default();
+        let mut receivers = vec![];
+        for request_id in 0..3 {
+            let (tx, rx) = oneshot::channel();
+            dispatch
+                .in_flight_requests
+                .insert_request(request_id, context::current(), Span::current(), tx)
+                .unwrap();
+            ordered_responses.written(request_id);
+            receivers.push(rx);
+        }
+        dispatch.ordered_responses = Some(ordered_responses);
+        let respond = |request_id| Response {
+            request_id,
+            message: Ok(format!("Resp{request_id}")),
+            more: false,
+        };
+
+        server_channel.send(respond(2)).await.unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(receivers[2].try_recv(), Err(_));
+
+        // A cancelled request doesn't hold up the requests written after it.
+        dispatch.in_flight_requests.cancel_request(1);
+        server_channel.send(respond(0)).await.unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(receivers[0].try_recv(), Ok(Ok(resp)) if resp == "Resp0");
+        assert_matches!(receivers[2].try_recv(), Ok(Ok(resp)) if resp == "Resp2");
+    }
+
+    #[tokio::test]
+    async fn channel_reports_in_flight_and_pending_requests() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+        let observer = channel.clone();
+
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert_eq!(observer.pending_requests(), 1);
+        assert_eq!(observer.in_flight_requests(), 0);
+        assert_eq!(observer.next_request_id(), 1);
+
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_eq!(observer.pending_requests(), 0);
+        assert_eq!(observer.in_flight_requests(), 1);
+
+        let request = match server_channel.next().await.unwrap().unwrap() {
+            ClientMessage::Request(request) => request,
+            message => panic!("unexpected message: {message:?}"),
+        };
+        server_channel
+            .send(Response {
+                request_id: request.id,
+                message: Ok("Resp".into()),
+                more: false,
+            })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_eq!(observer.in_flight_requests(), 0);
+        assert_matches!(resp.response().await, Ok(resp) if resp == "Resp");
+    }
+
+    #[tokio::test]
+    async fn open_circuit_breaker_fails_calls_without_sending() {
+        let (_dispatch, mut channel, _server_channel) = set_up();
+        let breaker = CircuitBreaker::new(1.0, Duration::from_secs(10)).with_min_calls(1);
+        breaker
+            .attempt()
+            .unwrap()
+            .finish::<()>(&Err(RpcError::DeadlineExceeded));
+        channel.circuit_breaker = Some(breaker);
+
+        assert_matches!(
+            channel.call(current(), "", "hi".into()).await,
+            Err(RpcError::CircuitOpen)
+        );
+        assert_eq!(channel.pending_requests(), 0);
+        assert_eq!(channel.next_request_id(), 0);
+    }
+
+    #[tokio::test]
+    async fn ready_fails_once_dispatch_stops() {
+        let (dispatch, channel, _server_channel) = set_up();
+        channel.ready().await.unwrap();
+        drop(dispatch);
+        assert_matches!(channel.ready().await, Err(RpcError::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn stage_request() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+
+        #[allow(unstable_name_collisions)]
+        let req = dispatch.as_mut().poll_next_request(cx).ready();
+        assert!(req.is_some());
+
+        let req = req.unwrap();
+        assert_eq!(req.request_id, 0);
+        assert_eq!(req.request, "hi".to_string());
+    }
+
+    #[tokio::test]
+    async fn correlation_tokens_are_echoed() {
+        let (client_transport, mut server_transport) = transport::channel::unbounded();
+        let config = Config::builder()
+            .request_ids(RequestIds::CorrelationTokens)
+            .build()
+            .unwrap();
+        let super::NewClient {
+            client: channel,
+            dispatch,
+        } = super::new::<String, String, _>(config, client_transport);
+        tokio::spawn(dispatch);
+        let server = async move {
+            let mut ids = vec![];
+            while let Some(Ok(ClientMessage::Request(request))) = server_transport.next().await {
+                ids.push(request.id);
+                server_transport
+                    .send(Response {
+                        request_id: request.id,
+                        message: Ok(request.message.to_uppercase()),
+                        more: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+            ids
+        };
+        let client = async move {
+            for request in ["a", "b", "c"] {
+                let response = channel.call(context::current(), "", request.into()).await;
+                assert_eq!(response.unwrap(), request.to_uppercase());
+            }
+        };
+
+        let ((), ids) = future::join(client, server).await;
+        assert_eq!(ids.len(), 3);
+        assert_ne!(ids, [0, 1, 2]);
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    }
+
+    #[test]
+    fn correlation_tokens_are_distinct() {
+        let tokens: HashSet<_> = (0..10_000)
+            .map(|seq| super::correlation_token(seq, 42))
+            .collect();
+        assert_eq!(tokens.len(), 10_000);
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn resumed_session_continues_request_ids() {
+        async fn echo(mut transport: UnboundedChannel<ClientMessage<String>, Response<String>>) {
+            while let Some(Ok(ClientMessage::Request(request))) = transport.next().await {
+                transport
+                    .send(Response {
+                        request_id: request.id,
+                        message: Ok(request.id.to_string()),
+                        more: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let config = Config::builder()
+            .request_ids(RequestIds::CorrelationTokens)
+            .build()
+            .unwrap();
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        tokio::spawn(echo(server_transport));
+        let channel = super::new::<String, String, _>(config.clone(), client_transport).spawn();
+        let mut ids = HashSet::new();
+        for _ in 0..2 {
+            ids.insert(channel.call(current(), "", "".into()).await.unwrap());
+        }
+        let session = channel.session();
+        assert_eq!(session.requests_issued(), 2);
+        assert_eq!(session.request_ids(), RequestIds::CorrelationTokens);
+
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        tokio::spawn(echo(server_transport));
+        let config = Config::builder()
+            .request_ids(RequestIds::Sequential)
+            .build()
+            .unwrap();
+        let channel = super::resume::<String, String, _>(config, session, client_transport).spawn();
+        for _ in 0..2 {
+            ids.insert(channel.call(current(), "", "".into()).await.unwrap());
+        }
+        assert_eq!(ids.len(), 4);
+        assert_eq!(channel.session().requests_issued(), 4);
+    }
+
+    #[tokio::test]
+    async fn call_all_yields_responses_as_they_complete() {
+        let (client_transport, mut server_transport) = transport::channel::unbounded();
+        let super::NewClient {
+            client: channel,
+            dispatch,
+        } = super::new::<String, String, _>(Config::default(), client_transport);
+        tokio::spawn(dispatch);
+        let requests = stream::iter(["a", "b", "c"].map(|r| (context::current(), r.to_string())));
+        let server = async move {
+            let mut requests = vec![];
+            while requests.len() < 3 {
+                match server_transport.next().await {
+                    Some(Ok(ClientMessage::Request(request))) => requests.push(request),
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+            for request in requests.into_iter().rev() {
+                server_transport
+                    .send(Response {
+                        request_id: request.id,
+                        message: Ok(request.message.to_uppercase()),
+                        more: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+            server_transport
+        };
+
+        let (responses, _server_transport) = future::join(
+            channel
+                .call_all("", requests, NonZeroUsize::new(3).unwrap())
+                .collect::<Vec<_>>(),
+            server,
+        )
+        .await;
+
+        let responses: Vec<_> = responses
+            .into_iter()
+            .map(|(index, request, response)| (index, request, response.unwrap()))
+            .collect();
+        assert_eq!(
+            responses,
+            [
+                (2, "c".into(), "C".into()),
+                (1, "b".into(), "B".into()),
+                (0, "a".into(), "A".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn auto_tuned_dispatch_admits_up_to_tuned_limit() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        dispatch.tuner = Some(Tuner::new(1_000));
+        let limit = dispatch.tuner.as_ref().unwrap().in_flight_limit();
+        assert!(limit < dispatch.config.max_in_flight_requests);
+        let mut responses = vec![];
+        for request_id in 0..limit {
+            let (tx, rx) = oneshot::channel();
+            dispatch
+                .in_flight_requests
+                .insert_request(request_id as u64, context::current(), Span::current(), tx)
+                .unwrap();
+            responses.push(rx);
+        }
+        channel.next_request_id.store(limit, Ordering::Relaxed);
+        let (tx, mut rx) = oneshot::channel();
+
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_batch_flushes_after_max_messages_or_max_delay() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        dispatch.config.write_batch = Some(WriteBatch::new(2, Duration::from_millis(10)));
+        let (mut channel2, mut channel3) = (channel.clone(), channel.clone());
+
+        // A lone message waits for the max delay.
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Pending);
+        assert_eq!(dispatch.unflushed, 1);
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Pending);
+        assert_eq!(dispatch.unflushed, 0);
+
+        // A full batch is flushed right away.
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut channel2, "hi", tx, &mut rx).await;
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Ready(Some(Ok(()))));
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut channel3, "hi", tx, &mut rx).await;
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Ready(Some(Ok(()))));
+        assert_eq!(dispatch.unflushed, 2);
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Pending);
+        assert_eq!(dispatch.unflushed, 0);
+    }
+
+    // Regression test for  https://github.com/google/tarpc/issues/220
+    #[tokio::test]
+    async fn stage_request_channel_dropped_doesnt_panic() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let _ = send_request(&mut channel, "hi", tx, &mut rx).await;
+        drop(channel);
+
+        assert!(dispatch.as_mut().poll(cx).is_ready());
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+                more: false,
+            },
+        )
+        .await;
+        dispatch.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stage_request_expired_is_failed_before_sending() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() - Duration::from_secs(1);
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span: Span::current(),
+                request_id: 0,
+                request: "hi".into(),
+                completion: Completion::Response(tx),
+                enqueued_at: Instant::now(),
+                items: None,
+            })
+            .await
+            .unwrap();
+
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+        assert_matches!(rx.try_recv(), Ok(Err(RpcError::DeadlineExceeded)));
+        assert!(dispatch.in_flight_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sent_request_records_client_queue_time() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, _rx) = oneshot::channel();
+
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx: context::current(),
+                span: Span::current(),
+                request_id: 0,
+                request: "hi".into(),
+                completion: Completion::Response(tx),
+                enqueued_at: Instant::now() - Duration::from_secs(1),
+                items: None,
+            })
+            .await
+            .unwrap();
+
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(request)))
+                if request.context.queue_time.client >= Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn notify_completes_once_flushed_without_occupying_in_flight_slot() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let notify = channel.notify(context::current(), "", "hi".into());
+        futures::pin_mut!(notify);
+        assert!(notify.as_mut().poll(cx).is_pending());
+        assert_matches!(dispatch.as_mut().pump_write(cx), Poll::Ready(Some(Ok(()))));
+        assert!(dispatch.in_flight_requests.is_empty());
+        assert!(notify.as_mut().poll(cx).is_pending());
+
+        assert!(dispatch.as_mut().pump_write(cx).is_pending());
+        assert_matches!(notify.await, Ok(()));
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(request))) if request.one_way
+        );
+    }
+
+    #[allow(unstable_name_collisions)]
+    #[tokio::test]
+    async fn stage_request_response_future_dropped_is_canceled_before_sending() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let _ = send_request(&mut channel, "hi", tx, &mut rx).await;
+
+        // Drop the channel so polling returns none if no requests are currently ready.
+        drop(channel);
+        // Test that a request future dropped before it's processed by dispatch will cause the request
+        // to not be added to the in-flight request map.
+        assert!(dispatch.as_mut().poll_next_request(cx).ready().is_none());
+    }
+
+    #[allow(unstable_name_collisions)]
+    #[tokio::test]
+    async fn stage_request_response_future_dropped_is_canceled_after_sending() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let req = send_request(&mut channel, "hi", tx, &mut rx).await;
+
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(!dispatch.in_flight_requests.is_empty());
+
+        // Test that a request future dropped after it's processed by dispatch will cause the request
+        // to be removed from the in-flight request map.
+        drop(req);
+        assert_matches!(
+            dispatch.as_mut().poll_next_cancellation(cx),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert!(dispatch.in_flight_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stage_request_response_closed_skipped() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        // Test that a request future that's closed its receiver but not yet canceled its request --
+        // i.e. still in `drop fn` -- will cause the request to not be added to the in-flight request
+        // map.
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        resp.response.close();
+
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_error() {
+        let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+        let (dispatch, mut channel, _) = set_up();
+        let (tx, mut rx) = oneshot::channel();
+        // send succeeds
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        drop(dispatch);
+        // error on receive
+        assert_matches!(resp.response().await, Err(RpcError::Shutdown));
+        let (dispatch, channel, _) = set_up();
+        drop(dispatch);
+        // error on send
+        let resp = channel
+            .call(current(), "test_request", "hi".to_string())
+            .await;
+        assert_matches!(resp, Err(RpcError::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_write() {
+        let cause = TransportError::Write;
+        let (mut dispatch, mut channel, mut cx) = setup_always_err(cause);
+        let (tx, mut rx) = oneshot::channel();
+
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        let res = resp.response().await;
+        assert_matches!(res, Err(RpcError::Send(_)));
+        let client_error: anyhow::Error = res.unwrap_err().into();
+        let mut chain = client_error.chain();
+        chain.next(); // original RpcError
+        assert_eq!(
+            chain
+                .next()
+                .unwrap()
+                .downcast_ref::<ChannelError<TransportError>>(),
+            Some(&ChannelError::Write(cause))
+        );
+        assert_eq!(
+            client_error.root_cause().downcast_ref::<TransportError>(),
+            Some(&cause)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_read() {
+        let cause = TransportError::Read;
+        let (mut dispatch, mut channel, mut cx) = setup_always_err(cause);
+        let (tx, mut rx) = oneshot::channel();
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert_eq!(
+            dispatch.as_mut().pump_write(&mut cx),
+            Poll::Ready(Some(Ok(())))
+        );
+        assert_eq!(
+            dispatch.as_mut().pump_read(&mut cx),
+            Poll::Ready(Some(Err(ChannelError::Read(Arc::new(cause)))))
+        );
+        assert_matches!(resp.response().await, Err(RpcError::Receive(_)));
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_ready() {
+        let cause = TransportError::Ready;
+        let (mut dispatch, _, mut cx) = setup_always_err(cause);
+        assert_eq!(
+            dispatch.as_mut().poll(&mut cx),
+            Poll::Ready(Err(ChannelError::Ready(cause)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_flush() {
+        let cause = TransportError::Flush;
+        let (mut dispatch, _, mut cx) = setup_always_err(cause);
+        assert_eq!(
+            dispatch.as_mut().poll(&mut cx),
+            Poll::Ready(Err(ChannelError::Flush(cause)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_close() {
+        let cause = TransportError::Close;
+        let (mut dispatch, channel, mut cx) = setup_always_err(cause);
+        drop(channel);
+        assert_eq!(
+            dispatch.as_mut().poll(&mut cx),
+            Poll::Ready(Err(ChannelError::Close(cause)))
+        );
+    }
+
+    #[test]
+    fn config_builder_validates_settings() {
+        let config = Config::builder()
+            .max_in_flight_requests(5)
+            .max_request_len(Some(10))
+            .build()
+            .unwrap();
+        assert_eq!(config.max_in_flight_requests(), 5);
+        assert_eq!(config.max_request_len(), Some(10));
+        assert_eq!(config.pending_request_buffer(), 100);
+
+        assert_matches!(
+            Config::builder().pending_request_buffer(0).build(),
+            Err(InvalidConfig {
+                field: "pending_request_buffer",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder()
+                .write_timeout(Some(Duration::ZERO))
+                .build(),
+            Err(InvalidConfig {
+                field: "write_timeout",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder()
+                .retry_policy(Some(RetryPolicy::new(0)))
+                .build(),
+            Err(InvalidConfig {
+                field: "retry_policy",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder()
+                .circuit_breaker(Some(CircuitBreaker::new(1.5, Duration::from_secs(1))))
+                .build(),
+            Err(InvalidConfig {
+                field: "circuit_breaker",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::low_latency()
+                .into_builder()
+                .max_in_flight_requests(0)
+                .build(),
+            Err(InvalidConfig {
+                field: "max_in_flight_requests",
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_request_fails_before_sending() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut channel = channel.with_request_len(|request: &String| request.len());
+        channel.max_request_len = Some(5);
+
+        let resp = channel
+            .call(context::current(), "", "too long".to_string())
+            .await;
+        assert_matches!(resp, Err(RpcError::RequestTooLarge { len: 8, max: 5 }));
+        assert_eq!(channel.next_request_id.load(Ordering::Relaxed), 0);
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn linked_call_is_canceled_with_its_cancellation() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let cancellation = context::Cancellation::new();
+        let mut call = Box::pin(cancellation.clone().link(channel.call(
+            context::current(),
+            "",
+            "hi".to_string(),
+        )));
+
+        assert!(call.as_mut().poll(cx).is_pending());
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(!dispatch.in_flight_requests.is_empty());
+
+        cancellation.cancel();
+        assert_matches!(call.as_mut().poll(cx), Poll::Ready(Err(RpcError::Canceled)));
+        assert_matches!(
+            dispatch.as_mut().poll_next_cancellation(cx),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert!(dispatch.in_flight_requests.is_empty());
+
+        let resp = cancellation.link(channel.call(context::current(), "", "hi".to_string()));
+        assert_matches!(resp.await, Err(RpcError::Canceled));
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn call_with_token_is_canceled_from_another_task() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let token = context::Cancellation::new();
+        let mut call = Box::pin(channel.call_with_token(
+            context::current(),
+            "",
+            "hi".to_string(),
+            token.clone(),
+        ));
+
+        assert!(call.as_mut().poll(cx).is_pending());
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(!dispatch.in_flight_requests.is_empty());
+
+        tokio::spawn({
+            let token = token.clone();
+            async move { token.cancel() }
+        })
+        .await
+        .unwrap();
+        assert_matches!(call.as_mut().poll(cx), Poll::Ready(Err(RpcError::Canceled)));
+        assert_matches!(
+            dispatch.as_mut().poll_next_cancellation(cx),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert!(dispatch.in_flight_requests.is_empty());
+
+        let resp = channel.call_with_token(context::current(), "", "hi".to_string(), token);
+        assert_matches!(resp.await, Err(RpcError::Canceled));
+        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn dispatch_log_records_request_transitions() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let log = DispatchLog::new(10);
+        dispatch.config.dispatch_log = Some(log.clone());
+
+        let (tx, mut rx) = oneshot::channel();
+        let completed = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(dispatch.as_mut().pump_write(cx).is_pending());
+        assert_eq!(log.request(0), [Op::Staged, Op::Written, Op::Flushed]);
+
+        server_channel
+            .send(Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+                more: false,
+            })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().pump_read(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(completed.response().await, Ok(response) if response == "hello");
+        assert_eq!(
+            log.request(0),
+            [Op::Staged, Op::Written, Op::Flushed, Op::Completed]
+        );
+
+        let (tx, mut rx) = oneshot::channel();
+        let canceled = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        drop(canceled);
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert_eq!(log.request(1), [Op::Staged, Op::Written, Op::Canceled]);
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn journal_records_calls() {
+        let (client_transport, mut server_transport) = transport::channel::unbounded();
+        let journal = Journal::new(10);
+        let config = Config::builder()
+            .journal(Some(journal.clone()))
+            .build()
+            .unwrap();
+        let channel = super::new::<String, String, _>(config, client_transport).spawn();
+        tokio::spawn(async move {
+            while let Some(Ok(ClientMessage::Request(request))) = server_transport.next().await {
+                let message = match &*request.message {
+                    "fail" => Err(ServerError::new(io::ErrorKind::Other, "failed".into())),
+                    "hang" => continue,
+                    _ => Ok(request.message),
+                };
+                server_transport
+                    .send(Response {
+                        request_id: request.id,
+                        message,
+                        more: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let ctx = context::current();
+        assert_matches!(channel.call(ctx, "echo", "hi".into()).await, Ok(_));
+        assert_matches!(channel.call(ctx, "fail", "fail".into()).await, Err(_));
+        let hang = channel.call(ctx, "hang", "hang".into());
+        assert!(tokio::time::timeout(Duration::from_millis(10), hang)
+            .await
+            .is_err());
+
+        let entries = channel.journal().unwrap().entries();
+        assert_matches!(
+            entries.as_slice(),
+            [
+                Entry {
+                    method: "echo",
+                    request_id: Some(0),
+                    outcome: Outcome::Succeeded,
+                    ..
+                },
+                Entry {
+                    method: "fail",
+                    request_id: Some(1),
+                    outcome: Outcome::Failed(_),
+                    ..
+                },
+                Entry {
+                    method: "hang",
+                    request_id: Some(2),
+                    outcome: Outcome::Abandoned,
+                    ..
+                },
+            ]
+        );
+        assert!(entries.iter().all(|entry| entry.deadline == ctx.deadline));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_flags_request_of_stuck_dispatch() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let watchdog = Watchdog::new(2);
+        dispatch.watch = Some(watchdog.watch());
+
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(watchdog.stalled().is_empty());
+
+        // The dispatch is no longer polled, so it does not expire the request.
+        tokio::time::advance(Duration::from_secs(25)).await;
+        assert_matches!(
+            watchdog.stalled().as_slice(),
+            [StalledRequest {
+                request_id: 0,
+                last_op: Op::Written,
+                ..
+            }]
+        );
+
+        drop(dispatch);
+        assert!(watchdog.stalled().is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_disconnect_fails_in_flight_requests() {
+        let (mut dispatch, mut channel, server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().poll(cx).is_pending());
+        drop(server_channel);
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Ready(Ok(())));
+
+        assert_matches!(
+            resp.response().await,
+            Err(RpcError::Disconnected(super::Disconnected {
+                closed_by: super::ClosedBy::Remote,
+                request_written: true,
+                source: None,
+            }))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_flush_fails_dispatch_after_write_timeout() {
+        let (mut dispatch, mut channel, mut cx) = setup_always_err(TransportError::Stall);
+        dispatch.config.write_timeout = Some(Duration::from_secs(5));
+        let (tx, mut rx) = oneshot::channel();
+
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_matches!(
+            dispatch.as_mut().poll(&mut cx),
+            Poll::Ready(Err(ChannelError::WriteStalled(timeout))) if timeout == Duration::from_secs(5)
+        );
+        assert_matches!(
+            resp.response().await,
+            Err(RpcError::Disconnected(super::Disconnected {
+                closed_by: super::ClosedBy::Local,
+                request_written: true,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writing_restarts_write_timeout() {
+        let (mut dispatch, mut channel, mut cx) = setup_always_err(TransportError::Stall);
+        dispatch.config.write_timeout = Some(Duration::from_secs(5));
+        let mut second_channel = channel.clone();
+        let (tx, mut rx) = oneshot::channel();
+
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut second_channel, "hi", tx, &mut rx).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_matches!(
+            dispatch.as_mut().poll(&mut cx),
+            Poll::Ready(Err(ChannelError::WriteStalled(_)))
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_dispatch_reuses_cancellation_handling() {
+        let (cancellation, mut canceled_requests) = cancellations();
+        let mut in_flight_requests = InFlightRequests::<Result<String, RpcError>>::default();
+
+        let (tx, mut completed) = oneshot::channel();
+        let completed = ResponseGuard::new(&mut completed, &cancellation, 0);
+        in_flight_requests
+            .insert_request(0, current(), Span::none(), tx)
+            .unwrap();
+        let (tx, mut dropped) = oneshot::channel();
+        let dropped = ResponseGuard::new(&mut dropped, &cancellation, 1);
+        in_flight_requests
+            .insert_request(1, current(), Span::none(), tx)
+            .unwrap();
+        let (unseen_tx, mut unseen) = oneshot::channel::<Result<String, RpcError>>();
+        let unseen = ResponseGuard::new(&mut unseen, &cancellation, 2);
+
+        drop(dropped);
+        drop(unseen);
+        // The dispatch skips requests whose callers gave up before it saw them.
+        assert!(unseen_tx.is_closed());
+        assert_eq!(canceled_requests.next().await, Some(1));
+        assert!(in_flight_requests.cancel_request(1).is_some());
+        assert_eq!(canceled_requests.next().await, Some(2));
+        assert!(in_flight_requests.cancel_request(2).is_none());
+
+        in_flight_requests.complete_request(0, Ok("Hi".into()));
+        assert_matches!(completed.response().await, Ok(response) if response == "Hi");
+        assert!(in_flight_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn finish_sending_half_closes_connection() {
+        let (client_transport, server_transport) = transport::channel::bounded(8);
+        let NewClient { client, dispatch } = super::new(Config::default(), client_transport);
+        let dispatch = tokio::spawn(dispatch);
+        let mut server = BaseChannel::with_defaults(server_transport);
+
+        let response = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(current(), "", "ping".to_string()).await }
+        });
+        let request = server.next().await.unwrap().unwrap().request;
+        client.finish_sending();
+        assert_matches!(
+            client.call(current(), "", "late".to_string()).await,
+            Err(RpcError::Shutdown)
+        );
+
+        // The server sees the end of requests, but keeps the channel open for the request in
+        // flight.
+        future::poll_fn(|cx| {
+            assert!(server.poll_next_unpin(cx).is_pending());
+            if server.requests_finished() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        server
+            .send(Response {
+                request_id: request.id,
+                message: Ok(request.message + " pong"),
+                more: false,
+            })
+            .await
+            .unwrap();
+
+        assert_matches!(response.await.unwrap(), Ok(response) if response == "ping pong");
+        assert_matches!(dispatch.await.unwrap(), Ok(()));
+        assert_matches!(server.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_tells_server_the_reason() {
+        let (client_transport, server_transport) = transport::channel::bounded(8);
+        let NewClient { client, dispatch } =
+            super::new::<String, String, _>(Config::default(), client_transport);
+        let dispatch = tokio::spawn(dispatch);
+        let mut server = BaseChannel::with_defaults(server_transport);
+
+        client.close(CloseReason::Idle);
+        assert_matches!(server.next().await, None);
+        assert_eq!(server.peer_close_reason(), Some(CloseReason::Idle));
+        assert_matches!(dispatch.await.unwrap(), Ok(()));
     }
 
+    #[cfg(feature = "tokio1")]
     #[tokio::test]
-    async fn dispatch_response_doesnt_cancel_after_complete() {
-        let (cancellation, mut canceled_requests) = cancellations();
-        let (tx, mut response) = oneshot::channel();
-        tx.send(Ok(Response {
-            request_id: 0,
-            message: Ok("well done"),
-        }))
-        .unwrap();
-        // resp's drop() is run, but should not send a cancel message.
-        ResponseGuard {
-            response: &mut response,
-            cancellation: &cancellation,
-            request_id: 3,
-            cancel: true,
+    async fn call_stream_yields_each_response_then_completes() {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        let client = super::new(Config::default(), client_transport).spawn();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(server::serve(|_, n: u32| {
+                    // The stream of a single item never ends.
+                    let hang = stream::pending().take(usize::from(n == 1));
+                    response_sink::respond(stream::iter(0..n).chain(hang), |item| item)
+                }))
+                .for_each(|response| async {
+                    tokio::spawn(response);
+                }),
+        );
+
+        let items = client
+            .call_stream(current(), "", 3)
+            .await
+            .unwrap()
+            .items(|item| item);
+        let items: Vec<_> = items.map(Result::unwrap).collect().await;
+        assert_eq!(items, [0, 1, 2]);
+        assert_eq!(client.in_flight_requests(), 0);
+
+        // A stream dropped before it ends cancels the request.
+        let mut stream = client.call_stream(current(), "", 1).await.unwrap();
+        assert_matches!(stream.next().await, Some(Ok(Some(0))));
+        drop(stream);
+        while client.in_flight_requests() > 0 {
+            tokio::task::yield_now().await;
         }
-        .response()
-        .await
-        .unwrap();
-        drop(cancellation);
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        assert_eq!(canceled_requests.poll_recv(cx), Poll::Ready(None));
     }
 
+    #[cfg(feature = "tokio1")]
     #[tokio::test]
-    async fn stage_request() {
-        let (mut dispatch, mut channel, _server_channel) = set_up();
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        let (tx, mut rx) = oneshot::channel();
-
-        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
-
-        #[allow(unstable_name_collisions)]
-        let req = dispatch.as_mut().poll_next_request(cx).ready();
-        assert!(req.is_some());
+    async fn call_with_stream_sends_items_after_the_request() {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        let client = super::new(Config::default(), client_transport).spawn();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(server::serve(|_, n: u32| async move {
+                    // Reads only the first `n` items.
+                    let items = request_stream::items(Some::<u32>)?.take(n as usize);
+                    Ok(items.fold(0, |sum, item| async move { sum + item }).await)
+                }))
+                .for_each(|response| async {
+                    tokio::spawn(response);
+                }),
+        );
 
-        let req = req.unwrap();
-        assert_eq!(req.request_id, 0);
-        assert_eq!(req.request, "hi".to_string());
+        assert_matches!(
+            client
+                .call_with_stream(current(), "", 2, stream::iter([1, 2, 3]))
+                .await,
+            Ok(3)
+        );
+        // The server responds before the stream ends, and the rest of the stream is not sent.
+        let items = stream::iter([4]).chain(stream::pending());
+        assert_matches!(
+            client.call_with_stream(current(), "", 1, items).await,
+            Ok(4)
+        );
+        assert_eq!(client.in_flight_requests(), 0);
+        // A request that is not streamed has no items.
+        assert_matches!(
+            client.call(current(), "", 1).await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::Unsupported,
+                ..
+            }))
+        );
     }
 
-    // Regression test for  https://github.com/google/tarpc/issues/220
+    #[cfg(feature = "tokio1")]
     #[tokio::test]
-    async fn stage_request_channel_dropped_doesnt_panic() {
-        let (mut dispatch, mut channel, mut server_channel) = set_up();
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        let (tx, mut rx) = oneshot::channel();
-
-        let _ = send_request(&mut channel, "hi", tx, &mut rx).await;
-        drop(channel);
+    async fn call_duplex_exchanges_streams_under_one_request() {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        let client = super::new(Config::default(), client_transport).spawn();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(server::serve(|_, n: u32| async move {
+                    // Responds to each item with the item plus `n`, and finally with 0.
+                    let items = request_stream::items(Some::<u32>)?;
+                    response_sink::respond(items.map(move |item| item + n), |item| {
+                        item.unwrap_or(0)
+                    })
+                    .await
+                }))
+                .for_each(|response| async {
+                    tokio::spawn(response);
+                }),
+        );
 
-        assert!(dispatch.as_mut().poll(cx).is_ready());
-        send_response(
-            &mut server_channel,
-            Response {
-                request_id: 0,
-                message: Ok("hello".into()),
-            },
-        )
-        .await;
-        dispatch.await.unwrap();
+        let (items_tx, items) = futures::channel::mpsc::unbounded();
+        let mut responses = client
+            .call_duplex(current(), "", 10, items)
+            .await
+            .unwrap()
+            .items(|response| (response != 0).then_some(response));
+        // Each response arrives while the client's stream is still open.
+        items_tx.unbounded_send(1).unwrap();
+        assert_matches!(responses.next().await, Some(Ok(11)));
+        items_tx.unbounded_send(2).unwrap();
+        assert_matches!(responses.next().await, Some(Ok(12)));
+        drop(items_tx);
+        assert_matches!(responses.next().await, None);
+        assert_eq!(client.in_flight_requests(), 0);
     }
 
-    #[allow(unstable_name_collisions)]
     #[tokio::test]
-    async fn stage_request_response_future_dropped_is_canceled_before_sending() {
-        let (mut dispatch, mut channel, _server_channel) = set_up();
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        let (tx, mut rx) = oneshot::channel();
+    async fn shutdown_waits_for_requests_in_flight() {
+        let (client_transport, server_transport) = transport::channel::bounded(8);
+        let NewClient { client, dispatch } = super::new(Config::default(), client_transport);
+        let dispatch = tokio::spawn(dispatch);
+        let mut server = BaseChannel::with_defaults(server_transport);
 
-        let _ = send_request(&mut channel, "hi", tx, &mut rx).await;
+        let response = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(current(), "", "ping".to_string()).await }
+        });
+        let request = server.next().await.unwrap().unwrap().request;
+        let mut shutdown = Box::pin(client.shutdown(None));
+        assert!(futures::poll!(shutdown.as_mut()).is_pending());
+        assert_matches!(
+            client.call(current(), "", "late".to_string()).await,
+            Err(RpcError::Shutdown)
+        );
 
-        // Drop the channel so polling returns none if no requests are currently ready.
-        drop(channel);
-        // Test that a request future dropped before it's processed by dispatch will cause the request
-        // to not be added to the in-flight request map.
-        assert!(dispatch.as_mut().poll_next_request(cx).ready().is_none());
+        server
+            .send(Response {
+                request_id: request.id,
+                message: Ok(request.message + " pong"),
+                more: false,
+            })
+            .await
+            .unwrap();
+        assert_matches!(shutdown.await, Ok(()));
+        assert_matches!(response.await.unwrap(), Ok(response) if response == "ping pong");
+        assert_matches!(dispatch.await.unwrap(), Ok(()));
     }
 
-    #[allow(unstable_name_collisions)]
-    #[tokio::test]
-    async fn stage_request_response_future_dropped_is_canceled_after_sending() {
-        let (mut dispatch, mut channel, _server_channel) = set_up();
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        let (tx, mut rx) = oneshot::channel();
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_timeout_fails_requests_in_flight() {
+        let (client_transport, server_transport) = transport::channel::bounded(8);
+        let NewClient { client, dispatch } =
+            super::new::<String, String, _>(Config::default(), client_transport);
+        let dispatch = tokio::spawn(dispatch);
+        let mut server = BaseChannel::with_defaults(server_transport);
 
-        let req = send_request(&mut channel, "hi", tx, &mut rx).await;
+        let response = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(current(), "", "ping".to_string()).await }
+        });
+        server.next().await.unwrap().unwrap();
+        assert_matches!(
+            client.shutdown(Some(Duration::from_secs(1))).await,
+            Err(super::ShutdownTimedOut { timeout }) if timeout == Duration::from_secs(1)
+        );
+        assert_matches!(
+            response.await.unwrap(),
+            Err(RpcError::Disconnected(super::Disconnected {
+                closed_by: super::ClosedBy::Local,
+                ..
+            }))
+        );
+        assert_matches!(dispatch.await.unwrap(), Ok(()));
+    }
 
-        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
-        assert!(!dispatch.in_flight_requests.is_empty());
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn reconnect_fails_in_flight_requests_and_sends_new_ones() {
+        let (servers_tx, mut servers) = mpsc::unbounded_channel();
+        let (client_transport, mut server) = transport::channel::unbounded();
+        let client = super::new(Config::default(), client_transport)
+            .with_reconnect(Backoff::fixed(Duration::from_millis(10)), move || {
+                let (client_transport, server_transport) = transport::channel::unbounded();
+                servers_tx.send(server_transport).unwrap();
+                future::ready(Ok::<_, io::Error>(client_transport))
+            })
+            .spawn();
 
-        // Test that a request future dropped after it's processed by dispatch will cause the request
-        // to be removed from the in-flight request map.
-        drop(req);
+        let call = client.call(current(), "", 1);
+        let disconnect = async {
+            assert_matches!(server.next().await, Some(Ok(ClientMessage::Request(_))));
+            drop(server);
+        };
+        let (response, ()) = future::join(call, disconnect).await;
         assert_matches!(
-            dispatch.as_mut().poll_next_cancellation(cx),
-            Poll::Ready(Some(Ok(_)))
+            response,
+            Err(RpcError::Disconnected(Disconnected {
+                closed_by: ClosedBy::Remote,
+                request_written: true,
+                ..
+            }))
         );
-        assert!(dispatch.in_flight_requests.is_empty());
+
+        // Made while reconnecting, so the request waits for the new connection.
+        let call = client.call(current(), "", 2);
+        let respond = async {
+            let mut server: UnboundedChannel<ClientMessage<u32>, Response<u32>> =
+                servers.recv().await.unwrap();
+            let request = match server.next().await {
+                Some(Ok(ClientMessage::Request(request))) => request,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            server
+                .send(Response {
+                    request_id: request.id,
+                    message: Ok(request.message + 1),
+                    more: false,
+                })
+                .await
+                .unwrap();
+            server
+        };
+        let (response, _server) = future::join(call, respond).await;
+        assert_matches!(response, Ok(3));
     }
 
     #[tokio::test]
-    async fn stage_request_response_closed_skipped() {
-        let (mut dispatch, mut channel, _server_channel) = set_up();
-        let cx = &mut Context::from_waker(noop_waker_ref());
-        let (tx, mut rx) = oneshot::channel();
+    async fn retry_policy_without_request_clone_fails_calls() {
+        let (client_transport, _server) =
+            transport::channel::unbounded::<Response<u32>, ClientMessage<u32>>();
+        let config = Config::builder()
+            .retry_policy(Some(RetryPolicy::new(2)))
+            .build()
+            .unwrap();
+        let NewClient {
+            client,
+            dispatch: _dispatch,
+        } = super::new(config, client_transport);
 
-        // Test that a request future that's closed its receiver but not yet canceled its request --
-        // i.e. still in `drop fn` -- will cause the request to not be added to the in-flight request
-        // map.
-        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
-        resp.response.close();
+        let error = client.call(current(), "", 1).await.unwrap_err();
+        assert_matches!(&error, RpcError::Send(e)
+            if e.is::<MissingRequestClone>());
 
-        assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
+        // Once requests can be copied, the call is sent.
+        let client = client.with_request_clone(Clone::clone);
+        assert_matches!(client.call(current(), "", 1).now_or_never(), None);
     }
 
+    #[cfg(feature = "tokio1")]
     #[tokio::test]
-    async fn test_shutdown_error() {
-        let _ = tracing_subscriber::fmt().with_test_writer().try_init();
-        let (dispatch, mut channel, _) = set_up();
-        let (tx, mut rx) = oneshot::channel();
-        // send succeeds
-        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
-        drop(dispatch);
-        // error on receive
-        assert_matches!(resp.response().await, Err(RpcError::Shutdown));
-        let (dispatch, channel, _) = set_up();
-        drop(dispatch);
-        // error on send
-        let resp = channel
-            .call(current(), "test_request", "hi".to_string())
-            .await;
-        assert_matches!(resp, Err(RpcError::Shutdown));
+    async fn retry_policy_resends_request_lost_with_connection() {
+        let (servers_tx, mut servers) = mpsc::unbounded_channel();
+        let (client_transport, mut server) = transport::channel::unbounded();
+        let config = Config::builder()
+            .retry_policy(Some(
+                RetryPolicy::new(2).with_backoff(Backoff::fixed(Duration::from_millis(10))),
+            ))
+            .build()
+            .unwrap();
+        let client = super::new(config, client_transport)
+            .with_reconnect(Backoff::fixed(Duration::from_millis(10)), move || {
+                let (client_transport, server_transport) = transport::channel::unbounded();
+                servers_tx.send(server_transport).unwrap();
+                future::ready(Ok::<_, io::Error>(client_transport))
+            })
+            .spawn()
+            .with_request_clone(Clone::clone);
+
+        let call = client.call(current(), "", 1);
+        let respond = async {
+            assert_matches!(server.next().await, Some(Ok(ClientMessage::Request(_))));
+            drop(server);
+            let mut server: UnboundedChannel<ClientMessage<u32>, Response<u32>> =
+                servers.recv().await.unwrap();
+            let request = match server.next().await {
+                Some(Ok(ClientMessage::Request(request))) => request,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            server
+                .send(Response {
+                    request_id: request.id,
+                    message: Err(ServerError::new(io::ErrorKind::Other, "failed".into())),
+                    more: false,
+                })
+                .await
+                .unwrap();
+            server
+        };
+        let (response, mut server) = future::join(call, respond).await;
+        // Server errors are not retried.
+        assert_matches!(response, Err(RpcError::Server(_)));
+        drop(client);
+        assert_matches!(server.next().await, None);
     }
 
     #[tokio::test]
-    async fn test_transport_error_write() {
-        let cause = TransportError::Write;
-        let (mut dispatch, mut channel, mut cx) = setup_always_err(cause);
-        let (tx, mut rx) = oneshot::channel();
+    async fn reconnect_stops_when_channels_dropped() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (client_transport, server) =
+            transport::channel::unbounded::<Response<u32>, ClientMessage<u32>>();
+        let NewClient { client, dispatch } = super::new(Config::default(), client_transport)
+            .with_reconnect(Backoff::fixed(Duration::from_millis(1)), {
+                let attempts = attempts.clone();
+                move || {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    future::ready(Err::<UnboundedChannel<_, _>, _>(io::Error::from(
+                        io::ErrorKind::ConnectionRefused,
+                    )))
+                }
+            });
+        let dispatch = tokio::spawn(dispatch);
+        drop(server);
+        while attempts.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(!dispatch.is_finished());
 
-        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
-        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
-        let res = resp.response().await;
-        assert_matches!(res, Err(RpcError::Send(_)));
-        let client_error: anyhow::Error = res.unwrap_err().into();
-        let mut chain = client_error.chain();
-        chain.next(); // original RpcError
-        assert_eq!(
-            chain
-                .next()
-                .unwrap()
-                .downcast_ref::<ChannelError<TransportError>>(),
-            Some(&ChannelError::Write(cause))
-        );
-        assert_eq!(
-            client_error.root_cause().downcast_ref::<TransportError>(),
-            Some(&cause)
-        );
+        drop(client);
+        assert_matches!(dispatch.await, Ok(Ok(())));
     }
 
     #[tokio::test]
-    async fn test_transport_error_read() {
-        let cause = TransportError::Read;
+    async fn transport_error_fails_unsent_requests() {
+        let cause = TransportError::Ready;
         let (mut dispatch, mut channel, mut cx) = setup_always_err(cause);
         let (tx, mut rx) = oneshot::channel();
+
         let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
-        assert_eq!(
-            dispatch.as_mut().pump_write(&mut cx),
-            Poll::Ready(Some(Ok(())))
+        assert_matches!(dispatch.as_mut().poll(&mut cx), Poll::Ready(Err(_)));
+
+        let res = resp.response().await;
+        assert_matches!(
+            &res,
+            Err(RpcError::Disconnected(super::Disconnected {
+                closed_by: super::ClosedBy::Local,
+                request_written: false,
+                source: Some(_),
+            }))
         );
+        let client_error: anyhow::Error = res.unwrap_err().into();
         assert_eq!(
-            dispatch.as_mut().pump_read(&mut cx),
-            Poll::Ready(Some(Err(ChannelError::Read(Arc::new(cause)))))
+            client_error.root_cause().to_string(),
+            "could not ready the transport for writes: Ready"
         );
-        assert_matches!(resp.response().await, Err(RpcError::Receive(_)));
     }
 
     #[tokio::test]
-    async fn test_transport_error_ready() {
-        let cause = TransportError::Ready;
-        let (mut dispatch, _, mut cx) = setup_always_err(cause);
-        assert_eq!(
-            dispatch.as_mut().poll(&mut cx),
-            Poll::Ready(Err(ChannelError::Ready(cause)))
-        );
+    async fn test_malformed_response_respond_policy() {
+        let (mut dispatch, mut rx) = set_up_malformed(MalformedFramePolicy::Respond);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_matches!(dispatch.as_mut().pump_read(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(rx.try_recv(), Ok(Err(RpcError::Receive(_))));
+        assert_eq!(dispatch.in_flight_requests.len(), 0);
     }
 
     #[tokio::test]
-    async fn test_transport_error_flush() {
-        let cause = TransportError::Flush;
-        let (mut dispatch, _, mut cx) = setup_always_err(cause);
-        assert_eq!(
-            dispatch.as_mut().poll(&mut cx),
-            Poll::Ready(Err(ChannelError::Flush(cause)))
-        );
+    async fn test_malformed_response_drop_policy() {
+        let (mut dispatch, mut rx) = set_up_malformed(MalformedFramePolicy::Drop);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_matches!(dispatch.as_mut().pump_read(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Empty));
+        assert_eq!(dispatch.in_flight_requests.len(), 1);
     }
 
     #[tokio::test]
-    async fn test_transport_error_close() {
-        let cause = TransportError::Close;
-        let (mut dispatch, channel, mut cx) = setup_always_err(cause);
-        drop(channel);
-        assert_eq!(
-            dispatch.as_mut().poll(&mut cx),
-            Poll::Ready(Err(ChannelError::Close(cause)))
+    async fn test_malformed_response_close_policy() {
+        let (mut dispatch, mut rx) = set_up_malformed(MalformedFramePolicy::Close);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_matches!(
+            dispatch.as_mut().pump_read(cx),
+            Poll::Ready(Some(Err(ChannelError::Read(_))))
         );
+        assert_matches!(rx.try_recv(), Ok(Err(RpcError::Receive(_))));
+    }
+
+    /// Sets up a dispatch with one in-flight request, id 0, whose response frame is malformed.
+    fn set_up_malformed(
+        policy: MalformedFramePolicy,
+    ) -> (
+        Pin<Box<RequestDispatch<String, String, MalformedResponseTransport>>>,
+        oneshot::Receiver<Result<String, RpcError>>,
+    ) {
+        let (_, pending_requests) = mpsc::channel(1);
+        let (_, canceled_requests) = cancellations();
+        let mut dispatch = Box::pin(RequestDispatch::<String, String, _> {
+            transport: MalformedResponseTransport(Some(0)).fuse(),
+            pending_requests,
+            canceled_requests,
+            in_flight_requests: InFlightRequests::default(),
+            streams: FnvHashMap::default(),
+            tuner: None,
+            unflushed: 0,
+            unflushed_requests: Vec::new(),
+            unflushed_one_way: Vec::new(),
+            watch: None,
+            write_stall: None,
+            batch_deadline: None,
+            half_close: Arc::default(),
+            write_closed: false,
+            ordered_responses: None,
+            reconnect: None,
+            config: Config {
+                malformed_frame_policy: policy,
+                ..Config::default()
+            },
+            request_items: stream::SelectAll::new(),
+        });
+        let (tx, rx) = oneshot::channel();
+        dispatch
+            .in_flight_requests
+            .insert_request(0, context::current(), Span::current(), tx)
+            .unwrap();
+        (dispatch, rx)
+    }
+
+    /// Yields a single malformed frame for the given request ID, then nothing.
+    struct MalformedResponseTransport(Option<u64>);
+
+    impl Sink<ClientMessage<String>> for MalformedResponseTransport {
+        type Error = io::Error;
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, _: ClientMessage<String>) -> io::Result<()> {
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Stream for MalformedResponseTransport {
+        type Item = io::Result<Response<String>>;
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.0.take() {
+                Some(request_id) => Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    MalformedFrame::new("bad frame").with_request_id(request_id),
+                )))),
+                None => Poll::Pending,
+            }
+        }
     }
 
     fn setup_always_err(
@@ -887,18 +4300,41 @@ mod tests {
     ) {
         let (to_dispatch, pending_requests) = mpsc::channel(1);
         let (cancellation, canceled_requests) = cancellations();
+        let half_close = Arc::new(HalfClose::default());
         let transport: AlwaysErrorTransport<String> = AlwaysErrorTransport(cause, PhantomData);
         let dispatch = Box::pin(RequestDispatch::<String, String, _> {
             transport: transport.fuse(),
             pending_requests,
             canceled_requests,
             in_flight_requests: InFlightRequests::default(),
+            streams: FnvHashMap::default(),
+            tuner: None,
+            unflushed: 0,
+            unflushed_requests: Vec::new(),
+            unflushed_one_way: Vec::new(),
+            watch: None,
+            write_stall: None,
+            batch_deadline: None,
+            half_close: half_close.clone(),
+            write_closed: false,
+            ordered_responses: None,
+            reconnect: None,
             config: Config::default(),
+            request_items: stream::SelectAll::new(),
         });
         let channel = Channel {
             to_dispatch,
             cancellation,
             next_request_id: Arc::new(AtomicUsize::new(0)),
+            correlation_key: None,
+            max_request_len: None,
+            request_len: std::mem::size_of_val,
+            half_close,
+            journal: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            request_clone: None,
+            max_in_flight_requests: Config::default().max_in_flight_requests(),
         };
         let cx = Context::from_waker(noop_waker_ref());
         (dispatch, channel, cx)
@@ -913,6 +4349,8 @@ mod tests {
         Write,
         Flush,
         Close,
+        /// Flushing never completes.
+        Stall,
     }
 
     impl Display for TransportError {
@@ -938,10 +4376,10 @@ mod tests {
             }
         }
         fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            if matches!(self.0, TransportError::Flush) {
-                Poll::Ready(Err(self.0))
-            } else {
-                Poll::Ready(Ok(()))
+            match self.0 {
+                TransportError::Flush => Poll::Ready(Err(self.0)),
+                TransportError::Stall => Poll::Pending,
+                _ => Poll::Ready(Ok(())),
             }
         }
         fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -981,6 +4419,7 @@ mod tests {
 
         let (to_dispatch, pending_requests) = mpsc::channel(1);
         let (cancellation, canceled_requests) = cancellations();
+        let half_close = Arc::new(HalfClose::default());
         let (client_channel, server_channel) = transport::channel::unbounded();
 
         let dispatch = RequestDispatch::<String, String, _> {
@@ -988,13 +4427,35 @@ mod tests {
             pending_requests,
             canceled_requests,
             in_flight_requests: InFlightRequests::default(),
+            streams: FnvHashMap::default(),
+            tuner: None,
+            unflushed: 0,
+            unflushed_requests: Vec::new(),
+            unflushed_one_way: Vec::new(),
+            watch: None,
+            write_stall: None,
+            batch_deadline: None,
+            half_close: half_close.clone(),
+            write_closed: false,
+            ordered_responses: None,
+            reconnect: None,
             config: Config::default(),
+            request_items: stream::SelectAll::new(),
         };
 
         let channel = Channel {
             to_dispatch,
             cancellation,
             next_request_id: Arc::new(AtomicUsize::new(0)),
+            correlation_key: None,
+            max_request_len: None,
+            request_len: std::mem::size_of_val,
+            half_close,
+            journal: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            request_clone: None,
+            max_in_flight_requests: Config::default().max_in_flight_requests(),
         };
 
         (Box::pin(dispatch), channel, server_channel)
@@ -1013,7 +4474,9 @@ mod tests {
             span: Span::current(),
             request_id,
             request: request.to_string(),
-            response_completion,
+            completion: Completion::Response(response_completion),
+            enqueued_at: Instant::now(),
+            items: None,
         };
         let response_guard = ResponseGuard {
             response,
@@ -1034,7 +4497,6 @@ mod tests {
 
     trait PollTest {
         type T;
-        fn unwrap(self) -> Poll<Self::T>;
         fn ready(self) -> Self::T;
     }
 
@@ -1044,15 +4506,6 @@ mod tests {
     {
         type T = Option<T>;
 
-        fn unwrap(self) -> Poll<Option<T>> {
-            match self {
-                Poll::Ready(Some(Ok(t))) => Poll::Ready(Some(t)),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Ready(Some(Err(e))) => panic!("{}", e.to_string()),
-                Poll::Pending => Poll::Pending,
-            }
-        }
-
         fn ready(self) -> Option<T> {
             match self {
                 Poll::Ready(Some(Ok(t))) => Some(t),
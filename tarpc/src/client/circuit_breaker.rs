@@ -0,0 +1,342 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a circuit breaker that fails calls fast while the server is unhealthy.
+//!
+//! During an outage, every call waits for its deadline or for the connection to fail, and calls
+//! made meanwhile pile up in the channel's pending request buffer. A [`CircuitBreaker`],
+//! configured via [`Config::circuit_breaker`](super::Config::circuit_breaker), watches the
+//! outcomes of calls over a sliding window. Once enough of them
+//! [fail](CircuitBreaker::with_failure), the breaker opens, and
+//! [`Channel::call`](super::Channel::call) fails with [`RpcError::CircuitOpen`] without sending
+//! the request. After a cooldown, the breaker is half open: it lets a few probe calls through,
+//! closing again if they all succeed and reopening if any fails.
+//!
+//! Clients configured with the same breaker, e.g. the clients of a pool created from one config,
+//! share its state, so that they trip together.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use tarpc::client::{self, circuit_breaker::CircuitBreaker};
+//!
+//! let config = client::Config::builder()
+//!     .circuit_breaker(Some(
+//!         CircuitBreaker::new(0.5, Duration::from_secs(10))
+//!             .with_min_calls(20)
+//!             .with_open_for(Duration::from_secs(5)),
+//!     ))
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use crate::client::RpcError;
+use crate::util::tracing;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls are made, and their outcomes recorded.
+    Closed,
+    /// Calls fail without being made.
+    Open,
+    /// A limited number of probe calls are made to test whether the server has recovered.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        /// When each call in the window completed, and whether it failed.
+        outcomes: VecDeque<(Instant, bool)>,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        /// The number of probe calls made so far.
+        probes: u32,
+        /// The number of probe calls that succeeded.
+        succeeded: u32,
+    },
+}
+
+/// Trips when the failure rate of calls exceeds a threshold, failing calls fast until the server
+/// recovers. Cloning the breaker produces a handle to the same state.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    window: Duration,
+    min_calls: usize,
+    open_for: Duration,
+    probes: u32,
+    failure: fn(&RpcError) -> bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl CircuitBreaker {
+    /// Returns a breaker that opens once at least `failure_rate` of the calls completed in the
+    /// last `window` failed. By default, the window must hold at least 10 calls, the breaker
+    /// stays open for 10 seconds, and 1 probe call is made while half open.
+    pub fn new(failure_rate: f64, window: Duration) -> Self {
+        Self {
+            failure_rate,
+            window,
+            min_calls: 10,
+            open_for: Duration::from_secs(10),
+            probes: 1,
+            failure: is_outage,
+            state: Arc::new(Mutex::new(State::Closed {
+                outcomes: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Requires the window to hold at least `min_calls` calls before the breaker can open, so
+    /// that a few failures after a quiet period do not trip it.
+    pub fn with_min_calls(mut self, min_calls: usize) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    /// Keeps the breaker open for `open_for` before it lets probe calls through.
+    pub fn with_open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    /// Makes `probes` probe calls while half open, all of which must succeed for the breaker to
+    /// close.
+    pub fn with_probes(mut self, probes: u32) -> Self {
+        self.probes = probes;
+        self
+    }
+
+    /// Counts the calls whose errors `failure` returns true for as failures. By default,
+    /// [outages](is_outage) are counted.
+    pub fn with_failure(mut self, failure: fn(&RpcError) -> bool) -> Self {
+        self.failure = failure;
+        self
+    }
+
+    /// The fraction of calls in the window that must fail for the breaker to open.
+    pub fn failure_rate(&self) -> f64 {
+        self.failure_rate
+    }
+
+    /// How far back the outcomes of calls are considered.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// The number of probe calls made while half open.
+    pub fn probes(&self) -> u32 {
+        self.probes
+    }
+
+    /// Returns the current state of the breaker.
+    pub fn state(&self) -> CircuitState {
+        let mut state = self.state.lock().unwrap();
+        self.half_open_if_cooled_down(&mut state, Instant::now());
+        match *state {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    fn half_open_if_cooled_down(&self, state: &mut State, now: Instant) {
+        if matches!(*state, State::Open { until } if now >= until) {
+            tracing::info!("CircuitHalfOpen");
+            *state = State::HalfOpen {
+                probes: 0,
+                succeeded: 0,
+            };
+        }
+    }
+
+    /// Returns a permit to make a call, or [`RpcError::CircuitOpen`] if the call must not be
+    /// made.
+    pub(super) fn attempt(&self) -> Result<Attempt<'_>, RpcError> {
+        let mut state = self.state.lock().unwrap();
+        self.half_open_if_cooled_down(&mut state, Instant::now());
+        match &mut *state {
+            State::Closed { .. } => Ok(Attempt {
+                breaker: self,
+                probe: false,
+            }),
+            State::HalfOpen { probes, .. } if *probes < self.probes => {
+                *probes += 1;
+                Ok(Attempt {
+                    breaker: self,
+                    probe: true,
+                })
+            }
+            State::Open { .. } | State::HalfOpen { .. } => Err(RpcError::CircuitOpen),
+        }
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            State::Closed { outcomes } if !probe => {
+                outcomes.push_back((now, failed));
+                while matches!(outcomes.front(), Some((at, _)) if now - *at > self.window) {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|(_, failed)| *failed).count();
+                if outcomes.len() >= self.min_calls
+                    && failures as f64 >= self.failure_rate * outcomes.len() as f64
+                {
+                    tracing::warn!(
+                        "CircuitOpened: {} of {} calls failed",
+                        failures,
+                        outcomes.len()
+                    );
+                    *state = State::Open {
+                        until: now + self.open_for,
+                    };
+                }
+            }
+            State::HalfOpen { succeeded, .. } if probe => {
+                if failed {
+                    tracing::warn!("CircuitOpened: probe call failed");
+                    *state = State::Open {
+                        until: now + self.open_for,
+                    };
+                } else {
+                    *succeeded += 1;
+                    if *succeeded >= self.probes {
+                        tracing::info!("CircuitClosed");
+                        *state = State::Closed {
+                            outcomes: VecDeque::new(),
+                        };
+                    }
+                }
+            }
+            // Calls that started before the breaker changed state do not count.
+            _ => {}
+        }
+    }
+
+    /// Gives back the probe slot of a probe call that was abandoned before it completed.
+    fn abandon_probe(&self) {
+        if let State::HalfOpen { probes, .. } = &mut *self.state.lock().unwrap() {
+            *probes = probes.saturating_sub(1);
+        }
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("failure_rate", &self.failure_rate)
+            .field("window", &self.window)
+            .field("min_calls", &self.min_calls)
+            .field("open_for", &self.open_for)
+            .field("probes", &self.probes)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+/// A call let through by a [`CircuitBreaker`], whose outcome is recorded when it finishes.
+#[derive(Debug)]
+pub(super) struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl Attempt<'_> {
+    /// Records the outcome of the call.
+    pub(super) fn finish<T>(self, result: &Result<T, RpcError>) {
+        let failed = matches!(result, Err(e) if (self.breaker.failure)(e));
+        self.breaker.record(self.probe, failed);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.abandon_probe();
+        }
+    }
+}
+
+/// Returns true for errors that indicate the server is unreachable or unresponsive: the
+/// connection was lost or could not carry the request, or the request exceeded its deadline.
+pub fn is_outage(error: &RpcError) -> bool {
+    matches!(
+        error,
+        RpcError::Disconnected(_)
+            | RpcError::Send(_)
+            | RpcError::Shutdown
+            | RpcError::DeadlineExceeded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use crate::client::RpcError;
+    use assert_matches::assert_matches;
+    use std::time::Duration;
+
+    fn call(breaker: &CircuitBreaker, result: Result<(), RpcError>) -> Result<(), RpcError> {
+        let attempt = breaker.attempt()?;
+        attempt.finish(&result);
+        result
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_on_failure_rate_and_closes_after_probes_succeed() {
+        let breaker = CircuitBreaker::new(0.5, Duration::from_secs(10))
+            .with_min_calls(4)
+            .with_open_for(Duration::from_secs(5));
+
+        for _ in 0..3 {
+            call(&breaker, Err(RpcError::DeadlineExceeded)).unwrap_err();
+        }
+        // Too few calls in the window to open.
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        call(&breaker, Ok(())).unwrap();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_matches!(call(&breaker, Ok(())), Err(RpcError::CircuitOpen));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        // An abandoned probe does not use up the probe.
+        drop(breaker.attempt().unwrap());
+        let probe = breaker.attempt().unwrap();
+        assert_matches!(breaker.attempt(), Err(RpcError::CircuitOpen));
+        probe.finish::<()>(&Err(RpcError::Shutdown));
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        call(&breaker, Ok(())).unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn old_failures_leave_the_window() {
+        let breaker = CircuitBreaker::new(0.6, Duration::from_secs(10)).with_min_calls(2);
+        call(&breaker, Err(RpcError::DeadlineExceeded)).unwrap_err();
+        tokio::time::advance(Duration::from_secs(11)).await;
+        call(&breaker, Err(RpcError::DeadlineExceeded)).unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        // Errors that are not outages are not failures.
+        call(&breaker, Err(RpcError::Canceled)).unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
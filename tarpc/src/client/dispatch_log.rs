@@ -0,0 +1,138 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a log of the most recent state transitions of requests in a client's dispatch.
+//!
+//! Debugging a stuck request otherwise means correlating trace-level logs of the client and the
+//! server. A [`DispatchLog`], configured via
+//! [`Config::dispatch_log`](super::Config::dispatch_log), keeps the last transitions of every
+//! request in memory, where a debug endpoint or a test can inspect them: a request that was
+//! [written](Op::Written) but never [flushed](Op::Flushed) points at the transport, while one
+//! that was flushed but never [completed](Op::Completed) points at the server.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::time::Instant;
+
+/// A state transition of a request in the dispatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Op {
+    /// The dispatch took the request from the pending request buffer to write it.
+    Staged,
+    /// The request was written to the transport.
+    Written,
+    /// The transport was flushed after the request was written.
+    Flushed,
+    /// The response to the request was received.
+    Completed,
+    /// The request was canceled. If it was written, a cancellation was written to the transport.
+    Canceled,
+    /// The request's deadline passed before its response was received.
+    Expired,
+}
+
+/// A state transition recorded in a [`DispatchLog`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Transition {
+    /// The ID of the request.
+    pub request_id: u64,
+    /// The transition.
+    pub op: Op,
+    /// When the transition happened.
+    pub at: Instant,
+}
+
+/// A ring buffer of the most recent [transitions](Transition) of requests in a client's dispatch.
+/// Cloning the log produces a handle to the same buffer, so clients configured with the same log
+/// share it.
+#[derive(Clone)]
+pub struct DispatchLog {
+    capacity: usize,
+    transitions: Arc<Mutex<VecDeque<Transition>>>,
+}
+
+impl DispatchLog {
+    /// Returns a log that keeps the last `capacity` transitions.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            transitions: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Returns the number of transitions the log keeps.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the transitions in the log, oldest first.
+    pub fn transitions(&self) -> Vec<Transition> {
+        self.transitions.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Returns the operations still in the log for the request with ID `request_id`, oldest
+    /// first.
+    pub fn request(&self, request_id: u64) -> Vec<Op> {
+        self.transitions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|transition| transition.request_id == request_id)
+            .map(|transition| transition.op)
+            .collect()
+    }
+
+    pub(crate) fn record(&self, request_id: u64, op: Op) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut transitions = self.transitions.lock().unwrap();
+        if transitions.len() == self.capacity {
+            transitions.pop_front();
+        }
+        transitions.push_back(Transition {
+            request_id,
+            op,
+            at: Instant::now(),
+        });
+    }
+}
+
+impl fmt::Debug for DispatchLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DispatchLog")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DispatchLog, Op};
+
+    #[tokio::test]
+    async fn keeps_most_recent_transitions() {
+        let log = DispatchLog::new(3);
+        log.record(1, Op::Staged);
+        log.record(1, Op::Written);
+        log.record(2, Op::Staged);
+        log.record(1, Op::Completed);
+
+        let ops: Vec<_> = log
+            .transitions()
+            .into_iter()
+            .map(|transition| (transition.request_id, transition.op))
+            .collect();
+        assert_eq!(ops, [(1, Op::Written), (2, Op::Staged), (1, Op::Completed)]);
+        assert_eq!(log.request(1), [Op::Written, Op::Completed]);
+        assert_eq!(log.clone().request(2), [Op::Staged]);
+    }
+}
@@ -1,3 +1,24 @@
+//! Tracks the requests of a client dispatch that are awaiting responses.
+//!
+//! [`RequestDispatch`](super::RequestDispatch) is built from the same parts that are public
+//! here, so a custom dispatch loop, e.g. one driving a completion-based transport, can reuse its
+//! cancellation handling rather than reimplement it:
+//!
+//! * Callers create a [`ResponseGuard`](super::ResponseGuard) for each request before handing
+//!   the request and the sender of its response to the dispatch. Dropping the guard cancels the
+//!   request through a [`RequestCancellation`](crate::cancellations::RequestCancellation).
+//! * The dispatch skips requests whose response sender
+//!   [is closed](tokio::sync::oneshot::Sender::is_closed), since their callers gave up before
+//!   the dispatch saw them, and [inserts](InFlightRequests::insert_request) the rest before
+//!   writing them to the transport.
+//! * The dispatch [completes](InFlightRequests::complete_request) requests as responses arrive,
+//!   and polls [`CanceledRequests`](crate::cancellations::CanceledRequests), writing a
+//!   [cancel message](crate::ClientMessage::Cancel) for each request that
+//!   [was still in flight](InFlightRequests::cancel_request).
+//! * The dispatch [polls for expired requests](InFlightRequests::poll_expired), which are
+//!   completed with an error of the dispatch's choosing.
+
+use crate::util::tracing::{self, Span};
 use crate::{
     context,
     util::{Compact, TimeUntil},
@@ -6,10 +27,10 @@ use fnv::FnvHashMap;
 use std::{
     collections::hash_map,
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::sync::oneshot;
+use tokio::{sync::oneshot, time::Instant};
 use tokio_util::time::delay_queue::{self, DelayQueue};
-use tracing::Span;
 
 /// Requests already written to the wire that haven't yet received responses.
 #[derive(Debug)]
@@ -34,6 +55,8 @@ struct RequestData<Res> {
     response_completion: oneshot::Sender<Res>,
     /// The key to remove the timer for the request's deadline.
     deadline_key: delay_queue::Key,
+    /// When the request was written to the transport.
+    sent_at: Instant,
 }
 
 /// An error returned when an attempt is made to insert a request with an ID that is already in
@@ -69,6 +92,7 @@ impl<Res> InFlightRequests<Res> {
                     span,
                     response_completion,
                     deadline_key,
+                    sent_at: Instant::now(),
                 });
                 Ok(())
             }
@@ -76,6 +100,18 @@ impl<Res> InFlightRequests<Res> {
         }
     }
 
+    /// Returns true iff the request is in flight.
+    pub fn contains(&self, request_id: u64) -> bool {
+        self.request_data.contains_key(&request_id)
+    }
+
+    /// Returns how long the request has been in flight, if it is.
+    pub fn time_in_flight(&self, request_id: u64) -> Option<Duration> {
+        self.request_data
+            .get(&request_id)
+            .map(|request_data| request_data.sent_at.elapsed())
+    }
+
     /// Removes a request without aborting. Returns true iff the request was found.
     pub fn complete_request(&mut self, request_id: u64, result: Res) -> Option<Span> {
         if let Some(request_data) = self.request_data.remove(&request_id) {
@@ -0,0 +1,206 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a journal of the most recent calls a client made, for postmortems.
+//!
+//! A [`Journal`], configured via [`Config::journal`](super::Config::journal), keeps an
+//! [`Entry`] for each of the last calls of a client's channels: the method, the request's trace
+//! ID and deadline, when the call started and how long it took, and how it ended. When an
+//! incident happens, [`Journal::entries`], reached from the journal handle or via
+//! [`Channel::journal`](super::Channel::journal), shows exactly what the client was doing in
+//! its final seconds.
+//!
+//! ```rust
+//! # use tarpc::client::{self, journal::Journal};
+//! let journal = Journal::new(1_000);
+//! let config = client::Config::builder()
+//!     .journal(Some(journal.clone()))
+//!     .build()
+//!     .unwrap();
+//! // ... later, e.g. from a panic hook or an admin endpoint:
+//! for entry in journal.entries() {
+//!     println!("{:?}", entry);
+//! }
+//! ```
+
+use crate::{client::RpcError, trace::TraceId};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::time::Instant;
+
+/// How a journaled call ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The call received a response.
+    Succeeded,
+    /// The call failed with the given error.
+    Failed(String),
+    /// The caller dropped the call before it completed.
+    Abandoned,
+}
+
+/// A call recorded in a [`Journal`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Entry {
+    /// The ID of the request, if the call got far enough to be assigned one.
+    pub request_id: Option<u64>,
+    /// The name of the method called.
+    pub method: &'static str,
+    /// The trace ID of the request.
+    pub trace_id: TraceId,
+    /// The deadline of the request.
+    pub deadline: SystemTime,
+    /// When the call started.
+    pub started: Instant,
+    /// How long the call took.
+    pub elapsed: Duration,
+    /// How the call ended.
+    pub outcome: Outcome,
+}
+
+/// A ring buffer of the most recent calls of a client. Cloning the journal produces a handle to
+/// the same buffer, so clients configured with the same journal share it.
+#[derive(Clone)]
+pub struct Journal {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+}
+
+impl Journal {
+    /// Returns a journal that keeps the last `capacity` calls.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Returns the number of calls the journal keeps.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the calls in the journal, in the order they ended, oldest first.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Starts the entry for a call of `method`. The entry is recorded when the returned
+    /// [`Call`] is finished or dropped.
+    pub(crate) fn start(&self, method: &'static str, deadline: SystemTime) -> Call {
+        Call {
+            journal: self.clone(),
+            entry: Some(Entry {
+                request_id: None,
+                method,
+                trace_id: TraceId::default(),
+                deadline,
+                started: Instant::now(),
+                elapsed: Duration::ZERO,
+                outcome: Outcome::Abandoned,
+            }),
+        }
+    }
+
+    fn record(&self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A call in progress. Records the call as [abandoned](Outcome::Abandoned) if dropped before
+/// it is finished.
+pub(crate) struct Call {
+    journal: Journal,
+    entry: Option<Entry>,
+}
+
+impl Call {
+    pub(crate) fn set_trace_id(&mut self, trace_id: TraceId) {
+        if let Some(entry) = &mut self.entry {
+            entry.trace_id = trace_id;
+        }
+    }
+
+    pub(crate) fn set_request_id(&mut self, request_id: u64) {
+        if let Some(entry) = &mut self.entry {
+            entry.request_id = Some(request_id);
+        }
+    }
+
+    pub(crate) fn finish<Resp>(mut self, result: &Result<Resp, RpcError>) {
+        if let Some(entry) = &mut self.entry {
+            entry.outcome = match result {
+                Ok(_) => Outcome::Succeeded,
+                Err(e) => Outcome::Failed(crate::util::print_err(e)),
+            };
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.elapsed = entry.started.elapsed();
+            self.journal.record(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, Outcome};
+    use crate::client::RpcError;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn keeps_most_recent_calls() {
+        let journal = Journal::new(2);
+        journal.start("a", SystemTime::UNIX_EPOCH).finish(&Ok(()));
+        let mut call = journal.start("b", SystemTime::UNIX_EPOCH);
+        call.set_request_id(1);
+        call.finish::<()>(&Err(RpcError::DeadlineExceeded));
+        drop(journal.start("c", SystemTime::UNIX_EPOCH));
+
+        let entries: Vec<_> = journal
+            .clone()
+            .entries()
+            .into_iter()
+            .map(|entry| (entry.method, entry.request_id, entry.outcome))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (
+                    "b",
+                    Some(1),
+                    Outcome::Failed("the request exceeded its deadline".into())
+                ),
+                ("c", None, Outcome::Abandoned),
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::in_flight_requests::InFlightRequests;
+use crate::util::tracing;
+use fnv::FnvHashMap;
+use std::collections::VecDeque;
+
+/// Holds responses that arrived before the responses to requests written earlier, so that
+/// requests complete in the order they were written to the transport.
+#[derive(Debug)]
+pub(crate) struct OrderedResponses<Res> {
+    /// The IDs of requests in the order they were written.
+    written: VecDeque<u64>,
+    /// Responses waiting for the requests written before theirs to complete.
+    ready: FnvHashMap<u64, Res>,
+}
+
+impl<Res> Default for OrderedResponses<Res> {
+    fn default() -> Self {
+        Self {
+            written: VecDeque::new(),
+            ready: FnvHashMap::default(),
+        }
+    }
+}
+
+impl<Res> OrderedResponses<Res> {
+    /// Records that a request was written to the transport.
+    pub(crate) fn written(&mut self, request_id: u64) {
+        self.written.push_back(request_id);
+    }
+
+    /// Holds a response until it can be released in order.
+    pub(crate) fn insert(&mut self, request_id: u64, response: Res) {
+        self.ready.insert(request_id, response);
+    }
+
+    /// Completes the held responses whose turn has come. Requests that are no longer in flight,
+    /// because they were cancelled or expired, don't hold up the requests written after them.
+    pub(crate) fn release(&mut self, in_flight_requests: &mut InFlightRequests<Res>) {
+        while let Some(&request_id) = self.written.front() {
+            if let Some(response) = self.ready.remove(&request_id) {
+                if let Some(span) = in_flight_requests.complete_request(request_id, response) {
+                    let _entered = span.enter();
+                    tracing::info!("ReceiveResponse");
+                }
+            } else if in_flight_requests.contains(request_id) {
+                break;
+            }
+            self.written.pop_front();
+        }
+    }
+
+    /// Drops all held responses.
+    pub(crate) fn clear(&mut self) {
+        self.written.clear();
+        self.ready.clear();
+    }
+}
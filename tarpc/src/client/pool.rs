@@ -0,0 +1,361 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a client that spreads its requests across a pool of connections.
+//!
+//! A single connection serializes all of a client's requests through one transport, which
+//! becomes the bottleneck of clients that make many requests per second. [`new`] creates a
+//! [`RequestDispatch`] for each of several transports, and a [`PooledChannel`] that sends each
+//! request over one of them, as chosen by its [`Balance`]. The pool's dispatches are driven
+//! together by a [`PoolDispatch`]. A connection that fails is taken out of rotation, and its
+//! failure is logged; the pool keeps sending requests over the connections that remain.
+//!
+//! ```rust
+//! use tarpc::{client::{self, pool::{self, Balance}}, context, transport};
+//! # use futures::prelude::*;
+//! # use tarpc::server::{self, BaseChannel, Channel};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let mut transports = vec![];
+//! for _ in 0..4 {
+//!     let (client_transport, server_transport) = transport::channel::unbounded();
+//!     # tokio::spawn(
+//!     #     BaseChannel::with_defaults(server_transport)
+//!     #         .execute(server::serve(|_, x: u64| async move { Ok(x * 2) }))
+//!     #         .for_each(|response| response),
+//!     # );
+//!     transports.push(client_transport);
+//! }
+//! let client =
+//!     pool::new(client::Config::default(), transports, Balance::LeastInFlight).spawn();
+//! assert_eq!(client.call(context::current(), "double", 2).await?, 4);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing;
+use crate::{
+    client::{self, Channel, Config, NewClient, RequestDispatch, RpcError},
+    context, ChannelError, ClientMessage, Response, Transport,
+};
+use futures::{prelude::*, stream::FuturesUnordered};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// How a [`PooledChannel`] chooses the connection to send a request over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Balance {
+    /// Each request goes to the next connection in turn.
+    #[default]
+    RoundRobin,
+    /// Each request goes to the connection with the fewest requests of this pool in flight, so
+    /// that a connection slowed by a few expensive requests receives fewer new ones.
+    LeastInFlight,
+}
+
+/// Returns a channel that sends requests over `transports`, and the dispatch that drives all of
+/// them. Each transport gets its own dispatch, configured with `config`.
+///
+/// # Panics
+///
+/// If `transports` is empty.
+pub fn new<Req, Resp, C>(
+    config: Config,
+    transports: impl IntoIterator<Item = C>,
+    balance: Balance,
+) -> NewClient<PooledChannel<Req, Resp>, PoolDispatch<Req, Resp, C>>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    let mut members = vec![];
+    let dispatches = FuturesUnordered::new();
+    for transport in transports {
+        let NewClient { client, dispatch } = client::new(config.clone(), transport);
+        members.push(Member {
+            channel: client,
+            in_flight: AtomicUsize::new(0),
+        });
+        dispatches.push(dispatch);
+    }
+    assert!(!members.is_empty(), "a pool needs at least one transport");
+    NewClient {
+        client: PooledChannel {
+            members: members.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+            balance,
+        },
+        dispatch: PoolDispatch {
+            dispatches,
+            error: None,
+        },
+    }
+}
+
+/// A connection of a pool.
+#[derive(Debug)]
+struct Member<Req, Resp> {
+    channel: Channel<Req, Resp>,
+    /// The number of requests of the pool in flight on this connection.
+    in_flight: AtomicUsize,
+}
+
+/// Decrements a member's in-flight count when a call ends.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A client that spreads its requests across a pool of connections. Cloning the channel produces
+/// a handle to the same pool.
+pub struct PooledChannel<Req, Resp> {
+    members: Arc<[Member<Req, Resp>]>,
+    /// The position of the next connection to try.
+    next: Arc<AtomicUsize>,
+    balance: Balance,
+}
+
+impl<Req, Resp> Clone for PooledChannel<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            members: self.members.clone(),
+            next: self.next.clone(),
+            balance: self.balance,
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for PooledChannel<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledChannel")
+            .field("connections", &self.members.len())
+            .field("balance", &self.balance)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Req, Resp> PooledChannel<Req, Resp> {
+    /// Returns the number of connections in the pool, including those that have shut down.
+    pub fn connections(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns the number of connections in the pool whose dispatches are still running.
+    pub fn live(&self) -> usize {
+        self.members
+            .iter()
+            .filter(|member| !member.channel.is_shut_down())
+            .count()
+    }
+
+    /// Sends a request over one of the pool's connections, returning a [`Future`] that resolves
+    /// to the response. Connections whose dispatches have shut down are skipped; if all have,
+    /// the call fails with [`RpcError::Shutdown`].
+    pub async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        let member = self.choose().ok_or(RpcError::Shutdown)?;
+        member.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = InFlight(&member.in_flight);
+        member.channel.call(ctx, request_name, request).await
+    }
+
+    fn choose(&self) -> Option<&Member<Req, Resp>> {
+        let len = self.members.len();
+        // Starting from a rotating position spreads ties among the least loaded connections.
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut live = (0..len)
+            .map(|i| &self.members[start.wrapping_add(i) % len])
+            .filter(|member| !member.channel.is_shut_down());
+        match self.balance {
+            Balance::RoundRobin => live.next(),
+            Balance::LeastInFlight => {
+                live.min_by_key(|member| member.in_flight.load(Ordering::Relaxed))
+            }
+        }
+    }
+}
+
+impl<Req, Resp> client::stub::Stub for PooledChannel<Req, Resp> {
+    type Req = Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        Self::call(self, ctx, request_name, request).await
+    }
+}
+
+/// Drives the dispatches of a [`PooledChannel`]. Resolves once every connection has shut down,
+/// to the error of the first connection that failed, if any.
+#[must_use]
+pub struct PoolDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    dispatches: FuturesUnordered<RequestDispatch<Req, Resp, C>>,
+    error: Option<ChannelError<C::Error>>,
+}
+
+// The dispatches are pinned by `FuturesUnordered`, and the error is never pinned.
+impl<Req, Resp, C> Unpin for PoolDispatch<Req, Resp, C> where
+    C: Transport<ClientMessage<Req>, Response<Resp>>
+{
+}
+
+impl<Req, Resp, C> Future for PoolDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    type Output = Result<(), ChannelError<C::Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match futures::ready!(self.dispatches.poll_next_unpin(cx)) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    tracing::warn!(
+                        "PooledConnectionBroken: {}; {} connections remain",
+                        crate::util::print_err(&e),
+                        self.dispatches.len()
+                    );
+                    self.error.get_or_insert(e);
+                }
+                None => return Poll::Ready(self.error.take().map_or(Ok(()), Err)),
+            }
+        }
+    }
+}
+
+impl<Req, Resp, C> fmt::Debug for PoolDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolDispatch")
+            .field("connections", &self.dispatches.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "tokio1"))]
+mod tests {
+    use super::Balance;
+    use crate::{
+        client::{self, RpcError},
+        context,
+        transport::{self, channel::UnboundedChannel},
+        ClientMessage, Response,
+    };
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+
+    type ServerTransport = UnboundedChannel<ClientMessage<u32>, Response<u32>>;
+
+    fn pool(n: usize, balance: Balance) -> (super::PooledChannel<u32, u32>, Vec<ServerTransport>) {
+        let (clients, servers): (Vec<_>, Vec<_>) =
+            (0..n).map(|_| transport::channel::unbounded()).unzip();
+        let client = super::new(client::Config::default(), clients, balance).spawn();
+        (client, servers)
+    }
+
+    /// Answers the next request on `server` with the request plus `offset`.
+    async fn answer(server: &mut ServerTransport, offset: u32) {
+        let request = match server.next().await {
+            Some(Ok(ClientMessage::Request(request))) => request,
+            other => panic!("unexpected message: {other:?}"),
+        };
+        server
+            .send(Response {
+                request_id: request.id,
+                message: Ok(request.message + offset),
+                more: false,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_robin_rotates_connections() {
+        let (client, mut servers) = pool(2, Balance::RoundRobin);
+        for i in 0..4 {
+            let server = &mut servers[i % 2];
+            let offset = 10 * (i as u32 % 2);
+            let (response, ()) = future::join(
+                client.call(context::current(), "", 1),
+                answer(server, offset),
+            )
+            .await;
+            assert_matches!(response, Ok(r) if r == 1 + offset);
+        }
+    }
+
+    #[tokio::test]
+    async fn least_in_flight_avoids_busy_connection() {
+        let (client, mut servers) = pool(2, Balance::LeastInFlight);
+
+        // The first request stays in flight on one connection.
+        let mut slow = Box::pin(client.call(context::current(), "", 1));
+        assert!(futures::poll!(slow.as_mut()).is_pending());
+        let (first, second) = servers.split_at_mut(1);
+        let idle = match future::select(first[0].next(), second[0].next()).await {
+            future::Either::Left(_) => 1,
+            future::Either::Right(_) => 0,
+        };
+
+        for _ in 0..3 {
+            let (response, ()) = future::join(
+                client.call(context::current(), "", 2),
+                answer(&mut servers[idle], 0),
+            )
+            .await;
+            assert_matches!(response, Ok(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_connections_that_shut_down() {
+        let (client, mut servers) = pool(2, Balance::RoundRobin);
+        drop(servers.remove(0));
+        while client.live() > 1 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..3 {
+            let (response, ()) = future::join(
+                client.call(context::current(), "", 1),
+                answer(&mut servers[0], 0),
+            )
+            .await;
+            assert_matches!(response, Ok(1));
+        }
+        drop(servers);
+        while client.live() > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_matches!(
+            client.call(context::current(), "", 1).await,
+            Err(RpcError::Shutdown)
+        );
+    }
+}
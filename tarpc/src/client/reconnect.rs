@@ -0,0 +1,110 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::backoff::{Backoff, Delays};
+use crate::util::tracing;
+use futures::{future::BoxFuture, prelude::*, ready};
+use std::{
+    error::Error,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::time::Sleep;
+
+type Connect<C> =
+    Box<dyn FnMut() -> BoxFuture<'static, Result<C, Box<dyn Error + Send + Sync>>> + Send>;
+
+/// Connects new transports for a dispatch whose connection was lost, waiting between attempts
+/// according to a [`Backoff`].
+pub(super) struct Reconnect<C> {
+    connect: Connect<C>,
+    delays: Delays,
+    state: State<C>,
+}
+
+enum State<C> {
+    Connected,
+    Waiting(Pin<Box<Sleep>>),
+    Connecting(BoxFuture<'static, Result<C, Box<dyn Error + Send + Sync>>>),
+}
+
+impl<C> Reconnect<C> {
+    pub(super) fn new<F, Fut, E>(backoff: Backoff, mut connect: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        Self {
+            connect: Box::new(move || connect().map_err(|e| e.into()).boxed()),
+            delays: backoff.delays(),
+            state: State::Connected,
+        }
+    }
+
+    pub(super) fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected)
+    }
+
+    /// Schedules the first attempt to replace a lost connection.
+    pub(super) fn disconnected(&mut self, error: Option<&(dyn Error + 'static)>) {
+        let retry_in = self.delays.next_delay();
+        match error {
+            Some(e) => tracing::warn!(
+                "Disconnected: {}; reconnecting in {:?}",
+                crate::util::print_err(e),
+                retry_in
+            ),
+            None => tracing::info!("Disconnected by server; reconnecting in {:?}", retry_in),
+        }
+        self.state = State::Waiting(Box::pin(tokio::time::sleep(retry_in)));
+    }
+
+    /// Resolves to a new transport once an attempt succeeds.
+    pub(super) fn poll_connect(&mut self, cx: &mut Context<'_>) -> Poll<C> {
+        loop {
+            match &mut self.state {
+                State::Connected => unreachable!("poll_connect called while connected"),
+                State::Waiting(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.state = State::Connecting((self.connect)());
+                }
+                State::Connecting(connecting) => match ready!(connecting.as_mut().poll(cx)) {
+                    Ok(transport) => {
+                        tracing::info!("Reconnected");
+                        self.delays.reset();
+                        self.state = State::Connected;
+                        return Poll::Ready(transport);
+                    }
+                    Err(e) => {
+                        let retry_in = self.delays.next_delay();
+                        tracing::warn!(
+                            "ReconnectFailed: {}; retrying in {:?}",
+                            crate::util::print_err(&*e),
+                            retry_in
+                        );
+                        self.state = State::Waiting(Box::pin(tokio::time::sleep(retry_in)));
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<C> fmt::Debug for Reconnect<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            State::Connected => "Connected",
+            State::Waiting(_) => "Waiting",
+            State::Connecting(_) => "Connecting",
+        };
+        f.debug_struct("Reconnect")
+            .field("delays", &self.delays)
+            .field("state", &state)
+            .finish()
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a policy for re-sending requests that fail with transport errors.
+//!
+//! A [`RetryPolicy`], configured via [`Config::retry_policy`](super::Config::retry_policy),
+//! makes [`Channel::call`](super::Channel::call) re-send a request that failed with a
+//! [retryable](RetryPolicy::with_retryable) error, waiting according to a [`Backoff`] between
+//! attempts, up to a maximum number of attempts. Retries are most useful with a dispatch that
+//! [reconnects](super::RequestDispatch::with_reconnect), so that a request that was lost along
+//! with the connection is sent again over the new one.
+//!
+//! A request is consumed when it is sent, so a channel can only re-send requests that it can
+//! copy: pass the function that copies them to
+//! [`Channel::with_request_clone`](super::Channel::with_request_clone). A channel configured with
+//! a retry policy but no such function fails every call with a [`MissingRequestClone`] error,
+//! rather than silently making a single attempt. Requests that fail after
+//! being written to the transport may have been handled by the server, so only enable retries
+//! for requests that are safe to handle more than once.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use tarpc::{backoff::Backoff, client::{self, retry_policy::RetryPolicy}};
+//!
+//! let config = client::Config::builder()
+//!     .retry_policy(Some(
+//!         RetryPolicy::new(3).with_backoff(Backoff::fixed(Duration::from_millis(50))),
+//!     ))
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use crate::{backoff::Backoff, client::RpcError};
+
+/// Controls which failed requests a channel re-sends, and how often.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    retryable: fn(&RpcError) -> bool,
+}
+
+/// Makes up to 3 attempts, retrying [transport errors](is_transport_error) after the
+/// [default](Backoff::default) backoff.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a policy that makes up to `max_attempts` attempts, including the first, retrying
+    /// [transport errors](is_transport_error) after the [default](Backoff::default) backoff.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Backoff::default(),
+            retryable: is_transport_error,
+        }
+    }
+
+    /// Waits according to `backoff` before each retry. A retry that would start after the
+    /// request's deadline is not made.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Retries the requests whose errors `retryable` returns true for.
+    pub fn with_retryable(mut self, retryable: fn(&RpcError) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    /// The most attempts made per call, including the first.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// How long to wait before each retry.
+    pub fn backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Whether a request that failed with `error` is retried.
+    pub fn is_retryable(&self, error: &RpcError) -> bool {
+        (self.retryable)(error)
+    }
+}
+
+/// Returns true for errors caused by the transport rather than the request: the connection was
+/// lost before the request completed, or the request could not be written.
+pub fn is_transport_error(error: &RpcError) -> bool {
+    matches!(error, RpcError::Disconnected(_) | RpcError::Send(_))
+}
+
+/// The error with which a channel configured with a [`RetryPolicy`] fails calls when it has no
+/// function to copy requests. See
+/// [`Channel::with_request_clone`](super::Channel::with_request_clone).
+#[derive(thiserror::Error, Debug)]
+#[error("the channel has a retry policy but no function to copy requests for retries")]
+#[non_exhaustive]
+pub struct MissingRequestClone;
@@ -0,0 +1,277 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides [`CallSink`], a [`Sink`] of calls to one method of a service, for bulk ingestion.
+//!
+//! Producers that push many calls through a client, e.g. when loading a dataset, want to be
+//! slowed down once the server falls behind instead of piling calls up in the channel's pending
+//! request buffer. A [`CallSink`] makes each call sent into it, and is ready for another only
+//! while it holds a credit: each call in flight uses one, and the sink starts with as many as
+//! the channel's [in-flight limit](super::Channel::max_in_flight_requests). Standard [`Sink`]
+//! combinators then apply backpressure to the producer.
+//!
+//! Generated clients create a sink with `sink_for`, given the method's marker type from the
+//! `{service}_methods` module generated alongside the client:
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     server::{self, Channel},
+//! };
+//!
+//! #[tarpc::service]
+//! trait Store {
+//!     async fn put(key: String, value: u64);
+//! }
+//!
+//! #[derive(Clone)]
+//! struct Server;
+//!
+//! impl Store for Server {
+//!     async fn put(self, _: context::Context, _key: String, _value: u64) {}
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+//! let server = server::BaseChannel::with_defaults(server_transport);
+//! tokio::spawn(server.execute(Server.serve()).for_each(|response| async {
+//!     tokio::spawn(response);
+//! }));
+//! let client = StoreClient::new(client::Config::default(), client_transport).spawn();
+//!
+//! let mut rows = stream::iter(0..100).map(|i| Ok((context::current(), (i.to_string(), i))));
+//! client.sink_for::<store_methods::Put>().send_all(&mut rows).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    client::{Channel, RpcError},
+    context,
+};
+use futures::{prelude::*, stream::FuturesUnordered, task::*};
+use std::{fmt, pin::Pin};
+
+/// A method of a [service](crate::service), whose calls can be sent through a [`CallSink`]. The
+/// service macro implements this for a marker type per method that neither takes nor returns a
+/// stream.
+pub trait Method {
+    /// The request type of the service.
+    type Req;
+    /// The response type of the service.
+    type Resp;
+    /// The args of the method: the arg itself if there is one, or else a tuple of the args.
+    type Args;
+    /// The name the method's calls are made with.
+    const NAME: &'static str;
+
+    /// Returns the request that calls the method with `args`.
+    fn request(args: Self::Args) -> Self::Req;
+}
+
+type Call<Resp> = Pin<Box<dyn Future<Output = Result<Resp, RpcError>> + Send>>;
+
+/// A [`Sink`] that calls the method `M` with each context and args sent into it.
+///
+/// The sink is ready while it has fewer calls in flight than it has [credits](Self::credits).
+/// Responses are discarded; use [`Channel::call`] for calls whose responses are needed. If a call
+/// fails, the next call to [`poll_ready`](Sink::poll_ready) or [`poll_flush`](Sink::poll_flush)
+/// returns its error. Flushing waits for all calls in flight to complete, so calls sent one at a
+/// time with [`SinkExt::send`] are made one after another; stream them in with
+/// [`SinkExt::send_all`] or [`StreamExt::forward`] instead.
+///
+/// [`SinkExt::send`]: futures::SinkExt::send
+/// [`SinkExt::send_all`]: futures::SinkExt::send_all
+/// [`StreamExt::forward`]: futures::StreamExt::forward
+pub struct CallSink<M: Method> {
+    channel: Channel<M::Req, M::Resp>,
+    credits: usize,
+    calls: FuturesUnordered<Call<M::Resp>>,
+}
+
+impl<M: Method> CallSink<M> {
+    /// Returns a sink of calls made on `channel`, with as many credits as the channel's
+    /// [in-flight limit](Channel::max_in_flight_requests).
+    pub fn new(channel: Channel<M::Req, M::Resp>) -> Self {
+        Self {
+            credits: channel.max_in_flight_requests(),
+            channel,
+            calls: FuturesUnordered::new(),
+        }
+    }
+
+    /// Makes at most `credits` calls at once, e.g. to leave room in the in-flight limit for other
+    /// callers of the channel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `credits` is zero.
+    pub fn with_credits(mut self, credits: usize) -> Self {
+        assert!(credits > 0, "credits must be positive");
+        self.credits = credits;
+        self
+    }
+
+    /// The number of calls the sink makes at once.
+    pub fn credits(&self) -> usize {
+        self.credits
+    }
+
+    /// The number of calls the sink has made that have not completed.
+    pub fn calls_in_flight(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Drives the calls in flight, failing with the error of the first call that failed.
+    /// Returns Ready once no calls are in flight.
+    fn poll_calls(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        loop {
+            match self.calls.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// No field is structurally pinned.
+impl<M: Method> Unpin for CallSink<M> {}
+
+impl<M> Sink<(context::Context, M::Args)> for CallSink<M>
+where
+    M: Method,
+    M::Req: Send + 'static,
+    M::Resp: Send + 'static,
+{
+    type Error = RpcError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(e)) = this.poll_calls(cx) {
+            return Poll::Ready(Err(e));
+        }
+        // If no credit is left, polling the calls scheduled a wakeup for when one completes.
+        if this.calls.len() < this.credits {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (ctx, args): (context::Context, M::Args),
+    ) -> Result<(), RpcError> {
+        let this = self.get_mut();
+        let channel = this.channel.clone();
+        let request = M::request(args);
+        this.calls.push(Box::pin(async move {
+            channel.call(ctx, M::NAME, request).await
+        }));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        self.get_mut().poll_calls(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        self.get_mut().poll_calls(cx)
+    }
+}
+
+impl<M: Method> fmt::Debug for CallSink<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallSink")
+            .field("method", &M::NAME)
+            .field("credits", &self.credits)
+            .field("calls_in_flight", &self.calls.len())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "tokio1"))]
+mod tests {
+    use super::{CallSink, Method};
+    use crate::{
+        client::{self, RpcError},
+        context,
+        server::{self, BaseChannel, Channel as _},
+        transport, ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::{prelude::*, stream};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    struct Double;
+
+    impl Method for Double {
+        type Req = u64;
+        type Resp = u64;
+        type Args = u64;
+        const NAME: &'static str = "Double";
+
+        fn request(x: u64) -> u64 {
+            x
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_limits_calls_in_flight_to_its_credits() {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+            BaseChannel::with_defaults(server_transport)
+                .execute(server::serve(move |_, x: u64| {
+                    let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                    async move {
+                        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(now, Ordering::SeqCst);
+                        tokio::task::yield_now().await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        if x == 0 {
+                            return Err(ServerError::new(
+                                io::ErrorKind::InvalidInput,
+                                "zero".into(),
+                            ));
+                        }
+                        Ok(x * 2)
+                    }
+                }))
+                .for_each(|response| async {
+                    tokio::spawn(response);
+                })
+        });
+        let channel = client::new(client::Config::default(), client_transport).spawn();
+
+        let mut sink = CallSink::<Double>::new(channel).with_credits(2);
+        assert_eq!(sink.credits(), 2);
+        let mut calls = stream::iter(1..=20).map(|x| Ok((context::current(), x)));
+        sink.send_all(&mut calls).await.unwrap();
+        assert_eq!(sink.calls_in_flight(), 0);
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 2);
+
+        assert_matches!(
+            sink.send((context::current(), 0)).await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::InvalidInput,
+                ..
+            }))
+        );
+    }
+}
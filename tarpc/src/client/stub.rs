@@ -1,11 +1,16 @@
 //! Provides a Stub trait, implemented by types that can call remote services.
 
 use crate::{
-    client::{Channel, RpcError},
+    client::{Channel, DuplexStream, ResponseStream, RpcError},
     context,
 };
+use futures::Stream;
 
+pub mod hedge;
 pub mod load_balance;
+pub mod local;
+pub mod member;
+pub mod middleware;
 pub mod retry;
 
 #[cfg(test)]
@@ -28,6 +33,51 @@ pub trait Stub {
         request_name: &'static str,
         request: Self::Req,
     ) -> Result<Self::Resp, RpcError>;
+
+    /// Returns a stub whose calls go through `middleware`, which wraps the calls of this stub.
+    fn with_middleware<M>(self, middleware: M) -> middleware::Layered<M, Self>
+    where
+        M: middleware::ClientMiddleware<Self::Req, Self::Resp>,
+        Self: Sized,
+    {
+        middleware::Layered::new(self, middleware)
+    }
+}
+
+/// A stub that can stream requests and responses, as it does for the streaming methods of a
+/// [service](crate::service).
+#[allow(async_fn_in_trait)]
+pub trait StreamStub: Stub {
+    /// Calls a remote service, returning the stream of its responses.
+    async fn call_stream(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Self::Req,
+    ) -> Result<ResponseStream<Self::Resp>, RpcError>;
+
+    /// Calls a remote service with a request followed by a stream of items, returning its
+    /// response.
+    async fn call_with_stream(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Self::Req,
+        items: impl Stream<Item = Self::Req>,
+    ) -> Result<Self::Resp, RpcError>;
+
+    /// Calls a remote service with a request followed by a stream of items, returning the stream
+    /// of its responses, which sends the items as it is polled.
+    async fn call_duplex<S>(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Self::Req,
+        items: S,
+    ) -> Result<DuplexStream<Self::Req, Self::Resp, S>, RpcError>
+    where
+        S: Stream<Item = Self::Req>,
+        Self::Req: Send + 'static;
 }
 
 impl<Req, Resp> Stub for Channel<Req, Resp> {
@@ -43,3 +93,38 @@ impl<Req, Resp> Stub for Channel<Req, Resp> {
         Self::call(self, ctx, request_name, request).await
     }
 }
+
+impl<Req, Resp> StreamStub for Channel<Req, Resp> {
+    async fn call_stream(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<ResponseStream<Resp>, RpcError> {
+        Self::call_stream(self, ctx, request_name, request).await
+    }
+
+    async fn call_with_stream(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+        items: impl Stream<Item = Req>,
+    ) -> Result<Resp, RpcError> {
+        Self::call_with_stream(self, ctx, request_name, request, items).await
+    }
+
+    async fn call_duplex<S>(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+        items: S,
+    ) -> Result<DuplexStream<Req, Resp, S>, RpcError>
+    where
+        S: Stream<Item = Req>,
+        Req: Send + 'static,
+    {
+        Self::call_duplex(self, ctx, request_name, request, items).await
+    }
+}
@@ -0,0 +1,265 @@
+//! Provides a stub that hedges slow requests by sending a duplicate.
+
+use crate::util::tracing;
+use crate::{
+    client::{stub, stub::retry::RetryBudget, RpcError},
+    context,
+};
+use futures::prelude::*;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+impl<Stub, Req> stub::Stub for Hedge<Stub>
+where
+    Stub: stub::Stub<Req = Arc<Req>>,
+{
+    type Req = Req;
+    type Resp = Stub::Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Self::Req,
+    ) -> Result<Stub::Resp, RpcError> {
+        let request = Arc::new(request);
+        if let Some(budget) = &self.budget {
+            budget.deposit();
+        }
+        let start = Instant::now();
+        let first = self.stub.call(ctx, request_name, Arc::clone(&request));
+        let result = match self.latencies.hedge_delay() {
+            None => first.await,
+            Some(delay) => {
+                futures::pin_mut!(first);
+                let hedge_at = tokio::time::sleep(delay);
+                futures::pin_mut!(hedge_at);
+                match future::select(first, hedge_at).await {
+                    future::Either::Left((result, _)) => result,
+                    future::Either::Right(((), first)) => {
+                        if self.budget.as_ref().map_or(true, RetryBudget::try_withdraw) {
+                            tracing::trace!("Hedging after {:?}", delay);
+                            let second = self.stub.call(ctx, request_name, request);
+                            futures::pin_mut!(second);
+                            // Dropping the slower call cancels its request.
+                            future::select(first, second).await.factor_first().0
+                        } else {
+                            tracing::info!("HedgeBudgetExhausted");
+                            first.await
+                        }
+                    }
+                }
+            }
+        };
+        self.latencies.record(start.elapsed());
+        result
+    }
+}
+
+/// A Stub that sends a duplicate of a request once it has taken longer than most recent calls,
+/// and returns whichever response arrives first. The slower call is canceled.
+///
+/// Hedging trims the tail latency of a client at the cost of the load of the duplicates: with a
+/// percentile of `0.95`, about 5% of calls are sent twice. Only hedge requests that are safe to
+/// handle more than once.
+///
+/// Note: to use this stub with Serde serialization, the "rc" feature of Serde needs to be enabled.
+#[derive(Clone, Debug)]
+pub struct Hedge<Stub> {
+    stub: Stub,
+    latencies: Latencies,
+    budget: Option<RetryBudget>,
+}
+
+impl<Stub, Req> Hedge<Stub>
+where
+    Stub: stub::Stub<Req = Arc<Req>>,
+{
+    /// Creates a new Hedge stub that delegates calls to the underlying `stub`, sending a duplicate
+    /// of a request once it has taken longer than the `percentile` latency of the last 1000 calls.
+    /// No duplicates are sent until 100 calls have completed.
+    ///
+    /// # Panics
+    ///
+    /// If `percentile` is not greater than 0 and at most 1.
+    pub fn new(stub: Stub, percentile: f64) -> Self {
+        assert!(
+            percentile > 0.0 && percentile <= 1.0,
+            "percentile must be in (0, 1], got {percentile}"
+        );
+        Self {
+            stub,
+            latencies: Latencies::new(percentile, 1_000, 100),
+            budget: None,
+        }
+    }
+
+    /// Measures the percentile latency over the last `window` calls, and sends duplicates only
+    /// once `min_samples` calls have completed.
+    pub fn with_window(mut self, window: usize, min_samples: usize) -> Self {
+        self.latencies = Latencies::new(self.latencies.percentile, window, min_samples);
+        self
+    }
+
+    /// Limits duplicates to those allowed by `budget`, which counts every call to this stub as
+    /// base traffic and every duplicate as a retry. A budget can be shared with
+    /// [`Retry`](super::retry::Retry) stubs to cap the extra load of both.
+    pub fn with_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+}
+
+/// The latencies of the most recent calls of a [`Hedge`] stub, shared by its clones.
+#[derive(Clone, Debug)]
+struct Latencies {
+    percentile: f64,
+    window: usize,
+    min_samples: usize,
+    samples: Arc<Mutex<VecDeque<Duration>>>,
+}
+
+impl Latencies {
+    fn new(percentile: f64, window: usize, min_samples: usize) -> Self {
+        Self {
+            percentile,
+            window,
+            min_samples: min_samples.max(1),
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(window))),
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        if self.window == 0 {
+            return;
+        }
+        let mut samples = self.lock();
+        if samples.len() == self.window {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// Returns how long to wait for a response before sending a duplicate, or `None` if too few
+    /// calls have completed to tell.
+    fn hedge_delay(&self) -> Option<Duration> {
+        let mut samples: Vec<_> = {
+            let samples = self.lock();
+            if samples.len() < self.min_samples {
+                return None;
+            }
+            samples.iter().copied().collect()
+        };
+        let rank = (self.percentile * samples.len() as f64).ceil() as usize;
+        let index = rank.clamp(1, samples.len()) - 1;
+        Some(*samples.select_nth_unstable(index).1)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Duration>> {
+        // The samples are left consistent even if a lock holder panics.
+        self.samples.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hedge;
+    use crate::{
+        client::{stub::retry::RetryBudget, stub::Stub, RpcError},
+        context,
+    };
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use tokio::time::Instant;
+
+    /// A stub whose calls take the given latencies, in order, counting the calls dropped before
+    /// they complete.
+    #[derive(Default)]
+    struct Scripted {
+        latencies: Mutex<VecDeque<Duration>>,
+        canceled: Arc<AtomicU32>,
+    }
+
+    impl Scripted {
+        fn new(latencies: &[u64]) -> Self {
+            Self {
+                latencies: Mutex::new(
+                    latencies
+                        .iter()
+                        .copied()
+                        .map(Duration::from_millis)
+                        .collect(),
+                ),
+                ..Self::default()
+            }
+        }
+    }
+
+    struct CountCancel(Option<Arc<AtomicU32>>);
+
+    impl Drop for CountCancel {
+        fn drop(&mut self) {
+            if let Some(canceled) = self.0.take() {
+                canceled.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    impl Stub for Scripted {
+        type Req = Arc<u32>;
+        type Resp = Duration;
+
+        async fn call(
+            &self,
+            _: context::Context,
+            _: &'static str,
+            _: Arc<u32>,
+        ) -> Result<Duration, RpcError> {
+            let latency = self.latencies.lock().unwrap().pop_front().unwrap();
+            let mut guard = CountCancel(Some(self.canceled.clone()));
+            tokio::time::sleep(latency).await;
+            guard.0 = None;
+            Ok(latency)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hedges_requests_slower_than_percentile() {
+        let stub = Hedge::new(Scripted::new(&[10, 10, 1_000, 10]), 0.5).with_window(10, 2);
+        assert!(stub.latencies.hedge_delay().is_none());
+        for _ in 0..2 {
+            stub.call(context::current(), "", 0).await.unwrap();
+        }
+
+        let start = Instant::now();
+        let response = stub.call(context::current(), "", 0).await.unwrap();
+        assert_eq!(response, Duration::from_millis(10));
+        // Hedged after 10ms, and the duplicate took 10ms.
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+        assert_eq!(stub.stub.canceled.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn budget_limits_duplicates() {
+        let budget = RetryBudget::new(Duration::from_secs(10), 0.0);
+        let stub = Hedge::new(Scripted::new(&[10, 1_000]), 1.0)
+            .with_window(10, 1)
+            .with_budget(budget);
+        stub.call(context::current(), "", 0).await.unwrap();
+
+        let start = Instant::now();
+        stub.call(context::current(), "", 0).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(stub.stub.canceled.load(Ordering::Relaxed), 0);
+    }
+}
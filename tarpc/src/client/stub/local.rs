@@ -0,0 +1,170 @@
+//! Provides a stub that calls a service in process, without a transport.
+//!
+//! A [`Local`] stub hands each request straight to a [`Serve`] implementation, such as the
+//! serving function of a service, so that a dependency can be embedded in the process that calls
+//! it rather than run remotely, without changing the call sites. Generated clients are created
+//! from one with `in_process`, or with `From` like from any other stub:
+//!
+//! ```rust
+//! use tarpc::context;
+//!
+//! #[tarpc::service]
+//! trait World {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! #[derive(Clone)]
+//! struct HelloServer;
+//!
+//! impl World for HelloServer {
+//!     async fn hello(self, _: context::Context, name: String) -> String {
+//!         format!("Hello, {name}!")
+//!     }
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let client = WorldClient::in_process(HelloServer);
+//! assert_eq!(client.hello(context::current(), "Stim".into()).await?, "Hello, Stim!");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Calls behave as they would against a server: a call fails with [`RpcError::DeadlineExceeded`]
+//! once its deadline passes, and with [`RpcError::Canceled`] once the
+//! [cancellation](context::Cancellation) it is linked to fires, dropping the handler either way.
+//! The handler runs linked to a cancellation of its own, so the calls it makes are canceled along
+//! with it. [Middleware](super::middleware) and server [hooks](crate::server::Serve::before)
+//! apply as usual. Requests and responses are moved rather than serialized, and the request is
+//! handled on the caller's task.
+
+use crate::util::tracing;
+use crate::{
+    client::{stub, RpcError},
+    context,
+    server::Serve,
+    util::TimeUntil,
+};
+use futures::prelude::*;
+
+/// A stub that calls a [`Serve`] implementation in process.
+#[derive(Clone, Debug)]
+pub struct Local<S> {
+    serve: S,
+}
+
+impl<S> Local<S> {
+    /// Returns a stub whose calls are handled by `serve`, which is cloned for each call.
+    pub fn new(serve: S) -> Self {
+        Self { serve }
+    }
+
+    /// Returns the serving function that handles the stub's calls.
+    pub fn get_ref(&self) -> &S {
+        &self.serve
+    }
+}
+
+impl<S> stub::Stub for Local<S>
+where
+    S: Serve + Clone,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        _request_name: &'static str,
+        request: S::Req,
+    ) -> Result<S::Resp, RpcError> {
+        let caller = context::Cancellation::current();
+        if caller.as_ref().map_or(false, |c| c.is_canceled()) {
+            tracing::info!("Canceled");
+            return Err(RpcError::Canceled);
+        }
+        let timeout = ctx.deadline.time_until();
+        if timeout.is_zero() {
+            tracing::info!("DeadlineExceeded");
+            return Err(RpcError::DeadlineExceeded);
+        }
+
+        let cancellation = context::Cancellation::new();
+        let cancel_linked_calls = cancellation.clone().cancel_on_drop();
+        let handler = cancellation.link(self.serve.clone().serve(ctx, request));
+        let caller_canceled = async {
+            match &caller {
+                Some(caller) => caller.canceled().await,
+                None => future::pending().await,
+            }
+        };
+        let deadline = tokio::time::sleep(timeout);
+        futures::pin_mut!(handler, deadline, caller_canceled);
+        let result = match future::select(handler, future::select(deadline, caller_canceled)).await
+        {
+            future::Either::Left((response, _)) => response.map_err(RpcError::Server),
+            future::Either::Right((future::Either::Left(_), _)) => {
+                tracing::info!("DeadlineExceeded");
+                return Err(RpcError::DeadlineExceeded);
+            }
+            future::Either::Right((future::Either::Right(_), _)) => {
+                tracing::info!("Canceled");
+                return Err(RpcError::Canceled);
+            }
+        };
+        cancel_linked_calls.disarm();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Local;
+    use crate::{
+        client::{stub::Stub, RpcError},
+        context, server, ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::future;
+    use std::{io, time::Duration};
+
+    #[tokio::test(start_paused = true)]
+    async fn honors_deadlines_and_errors() {
+        let stub = Local::new(server::serve(|_, delay: u64| async move {
+            if delay == u64::MAX {
+                return Err(ServerError::new(io::ErrorKind::Other, "no".into()));
+            }
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            Ok(delay)
+        }));
+
+        let mut ctx = context::current();
+        ctx.deadline = crate::clock::now() + Duration::from_secs(5);
+        assert_matches!(stub.call(ctx, "", 1).await, Ok(1));
+        assert_matches!(
+            stub.call(ctx, "", u64::MAX).await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::Other,
+                ..
+            }))
+        );
+        assert_matches!(
+            stub.call(ctx, "", 10).await,
+            Err(RpcError::DeadlineExceeded)
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_once_linked_cancellation_fires() {
+        let stub = Local::new(server::serve(|_, ()| future::pending::<Result<(), _>>()));
+        let cancellation = context::Cancellation::new();
+        let call = tokio::spawn(
+            cancellation
+                .clone()
+                .link(async move { stub.call(context::current(), "", ()).await }),
+        );
+        tokio::task::yield_now().await;
+        cancellation.cancel();
+        assert_matches!(call.await.unwrap(), Err(RpcError::Canceled));
+    }
+}
@@ -0,0 +1,136 @@
+//! Provides a stub that calls one service of a [service group](crate::service_group).
+//!
+//! A server that serves a group of services over one connection receives the group's request
+//! type, which has a variant per service. A [`Member`] stub wraps a stub of the group, such as a
+//! [`Channel`](crate::client::Channel), converting each service's requests into the group's and
+//! the group's responses back into the service's, so that the generated client of each service
+//! can share the one connection:
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client::{self, stub::member::Member},
+//!     context,
+//!     server::{self, Channel},
+//!     transport::channel,
+//! };
+//!
+//! #[tarpc::service]
+//! trait Hello {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! #[tarpc::service]
+//! trait Add {
+//!     async fn add(x: i32, y: i32) -> i32;
+//! }
+//!
+//! tarpc::service_group! {
+//!     struct Services {
+//!         hello: Hello,
+//!         add: Add,
+//!     }
+//! }
+//!
+//! #[derive(Clone)]
+//! struct Server;
+//!
+//! impl Hello for Server {
+//!     async fn hello(self, _: context::Context, name: String) -> String {
+//!         format!("Hello, {name}!")
+//!     }
+//! }
+//!
+//! impl Add for Server {
+//!     async fn add(self, _: context::Context, x: i32, y: i32) -> i32 {
+//!         x + y
+//!     }
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let (client_transport, server_transport) = channel::unbounded();
+//! let services = Services {
+//!     hello: Hello::serve(Server),
+//!     add: Add::serve(Server),
+//! };
+//! tokio::spawn(
+//!     server::BaseChannel::with_defaults(server_transport)
+//!         .execute(services)
+//!         .for_each(|response| response),
+//! );
+//!
+//! let channel = client::new(client::Config::default(), client_transport).spawn();
+//! let hello = HelloClient::from(Member::new(channel.clone()));
+//! let add = AddClient::from(Member::new(channel));
+//! assert_eq!(hello.hello(context::current(), "Stim".into()).await?, "Hello, Stim!");
+//! assert_eq!(add.add(context::current(), 1, 2).await?, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Streaming methods are not supported by service groups.
+
+use crate::{
+    client::{stub, RpcError},
+    context,
+};
+use std::{fmt, io, marker::PhantomData, sync::Arc};
+
+/// A stub that calls one service over a stub of the service group it belongs to.
+pub struct Member<Stub, Req, Resp> {
+    stub: Stub,
+    ghost: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Stub, Req, Resp> Member<Stub, Req, Resp> {
+    /// Returns a stub whose calls are sent through `stub`, a stub of the service group.
+    pub fn new(stub: Stub) -> Self {
+        Self {
+            stub,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Returns the stub of the service group.
+    pub fn get_ref(&self) -> &Stub {
+        &self.stub
+    }
+}
+
+impl<Stub: Clone, Req, Resp> Clone for Member<Stub, Req, Resp> {
+    fn clone(&self) -> Self {
+        Self::new(self.stub.clone())
+    }
+}
+
+impl<Stub: fmt::Debug, Req, Resp> fmt::Debug for Member<Stub, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Member").field("stub", &self.stub).finish()
+    }
+}
+
+impl<Stub, Req, Resp> stub::Stub for Member<Stub, Req, Resp>
+where
+    Stub: stub::Stub,
+    Stub::Req: From<Req>,
+    Resp: TryFrom<Stub::Resp>,
+{
+    type Req = Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        let response = self.stub.call(ctx, request_name, request.into()).await?;
+        Resp::try_from(response).map_err(|_| {
+            RpcError::Receive(Arc::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the response belongs to another service of the group",
+            )))
+        })
+    }
+}
@@ -0,0 +1,179 @@
+//! Provides middleware that wraps every call of a stub.
+//!
+//! A [`ClientMiddleware`] sees each outgoing request and its response, and decides how to make
+//! the call through the stub it wraps: it can log or measure calls, add credentials to requests,
+//! rewrite requests or responses, or answer without calling the stub at all. Middleware is applied
+//! with [`Stub::with_middleware`], and layers stack, the last applied running first. Since a
+//! generated client can be created from any stub, middleware applies to generated clients without
+//! changes to them:
+//!
+//! ```rust
+//! use std::time::Instant;
+//! use tarpc::{
+//!     client::{self, stub::{middleware::ClientMiddleware, Stub}, RpcError},
+//!     context,
+//! };
+//!
+//! #[tarpc::service]
+//! trait World {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! /// Logs how long each call takes.
+//! struct LogLatency;
+//!
+//! impl<Req, Resp> ClientMiddleware<Req, Resp> for LogLatency {
+//!     async fn call<S>(
+//!         &self,
+//!         next: &S,
+//!         ctx: context::Context,
+//!         request_name: &'static str,
+//!         request: Req,
+//!     ) -> Result<Resp, RpcError>
+//!     where
+//!         S: Stub<Req = Req, Resp = Resp>,
+//!     {
+//!         let start = Instant::now();
+//!         let response = next.call(ctx, request_name, request).await;
+//!         println!("{request_name} took {:?}", start.elapsed());
+//!         response
+//!     }
+//! }
+//!
+//! # fn wrap(channel: client::Channel<WorldRequest, WorldResponse>) {
+//! let client = WorldClient::from(channel.with_middleware(LogLatency));
+//! # }
+//! ```
+
+use crate::{
+    client::{stub, RpcError},
+    context,
+};
+
+/// Wraps the calls of a stub.
+#[allow(async_fn_in_trait)]
+pub trait ClientMiddleware<Req, Resp> {
+    /// Makes a call through `next`, the stub being wrapped. The middleware may modify the context
+    /// and request before passing them on, and the result before returning it.
+    async fn call<S>(
+        &self,
+        next: &S,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError>
+    where
+        S: stub::Stub<Req = Req, Resp = Resp>;
+}
+
+/// A Stub whose calls go through a [`ClientMiddleware`]. Created by [`Stub::with_middleware`].
+///
+/// [`Stub::with_middleware`]: stub::Stub::with_middleware
+#[derive(Clone, Debug)]
+pub struct Layered<M, Stub> {
+    middleware: M,
+    stub: Stub,
+}
+
+impl<M, Stub> Layered<M, Stub> {
+    pub(crate) fn new(stub: Stub, middleware: M) -> Self {
+        Self { middleware, stub }
+    }
+
+    /// Returns the middleware.
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+
+    /// Returns the stub that the middleware wraps.
+    pub fn inner(&self) -> &Stub {
+        &self.stub
+    }
+}
+
+impl<M, Stub> stub::Stub for Layered<M, Stub>
+where
+    Stub: stub::Stub,
+    M: ClientMiddleware<Stub::Req, Stub::Resp>,
+{
+    type Req = Stub::Req;
+    type Resp = Stub::Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Self::Req,
+    ) -> Result<Stub::Resp, RpcError> {
+        self.middleware
+            .call(&self.stub, ctx, request_name, request)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientMiddleware;
+    use crate::{
+        client::{
+            stub::{mock::Mock, Stub},
+            RpcError,
+        },
+        context,
+    };
+    use assert_matches::assert_matches;
+    use std::sync::Mutex;
+
+    /// Appends a suffix to requests and records the responses it sees.
+    struct Suffix {
+        suffix: &'static str,
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl Suffix {
+        fn new(suffix: &'static str) -> Self {
+            Self {
+                suffix,
+                seen: Mutex::default(),
+            }
+        }
+    }
+
+    impl ClientMiddleware<String, String> for Suffix {
+        async fn call<S>(
+            &self,
+            next: &S,
+            ctx: context::Context,
+            request_name: &'static str,
+            request: String,
+        ) -> Result<String, RpcError>
+        where
+            S: Stub<Req = String, Resp = String>,
+        {
+            let response = next.call(ctx, request_name, request + self.suffix).await;
+            if let Ok(response) = &response {
+                self.seen.lock().unwrap().push(response.clone());
+            }
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn last_layer_applied_runs_first() {
+        let stub = Mock::new([("hi-outer-inner".to_string(), "hello".to_string())])
+            .with_middleware(Suffix::new("-inner"))
+            .with_middleware(Suffix::new("-outer"));
+        assert_matches!(
+            stub.call(context::current(), "", "hi".into()).await,
+            Ok(response) if response == "hello"
+        );
+        assert_eq!(*stub.middleware().seen.lock().unwrap(), ["hello"]);
+        assert_eq!(*stub.inner().middleware().seen.lock().unwrap(), ["hello"]);
+
+        assert_matches!(
+            stub.call(context::current(), "", "bye".into()).await,
+            Err(_)
+        );
+        assert_eq!(stub.middleware().seen.lock().unwrap().len(), 1);
+    }
+}
@@ -1,10 +1,16 @@
 //! Provides a stub that retries requests based on response contents..
 
+use crate::util::tracing;
 use crate::{
+    backoff::Backoff,
     client::{stub, RpcError},
     context,
 };
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
 
 impl<Stub, Req, F> stub::Stub for Retry<F, Stub>
 where
@@ -21,13 +27,26 @@ where
         request: Self::Req,
     ) -> Result<Stub::Resp, RpcError> {
         let request = Arc::new(request);
+        if let Some(budget) = &self.budget {
+            budget.deposit();
+        }
+        let mut delays = self.backoff.as_ref().map(Backoff::delays);
         for i in 1.. {
             let result = self
                 .stub
                 .call(ctx, request_name, Arc::clone(&request))
                 .await;
             if (self.should_retry)(&result, i) {
+                if let Some(budget) = &self.budget {
+                    if !budget.try_withdraw() {
+                        tracing::info!("RetryBudgetExhausted on attempt {i}");
+                        return result;
+                    }
+                }
                 tracing::trace!("Retrying on attempt {i}");
+                if let Some(delays) = &mut delays {
+                    tokio::time::sleep(delays.next_delay()).await;
+                }
                 continue;
             }
             return result;
@@ -42,6 +61,8 @@ where
 pub struct Retry<F, Stub> {
     should_retry: F,
     stub: Stub,
+    budget: Option<RetryBudget>,
+    backoff: Option<Backoff>,
 }
 
 impl<Stub, Req, F> Retry<F, Stub>
@@ -51,6 +72,227 @@ where
 {
     /// Creates a new Retry stub that delegates calls to the underlying `stub`.
     pub fn new(stub: Stub, should_retry: F) -> Self {
-        Self { stub, should_retry }
+        Self {
+            stub,
+            should_retry,
+            budget: None,
+            backoff: None,
+        }
+    }
+
+    /// Limits retries to those allowed by `budget`. When the budget is exhausted, the result of
+    /// the last attempt is returned even if `should_retry` would retry it.
+    ///
+    /// Every call to this stub counts as base traffic for the budget, so a budget shared by nested
+    /// `Retry` stubs also counts the attempts of outer stubs as base traffic of inner ones.
+    pub fn with_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Waits according to `backoff` before each retry. By default, requests are retried
+    /// immediately.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+}
+
+/// Limits the number of retries to a fraction of the number of calls, over a sliding window of
+/// time.
+///
+/// A budget can be cloned and shared by all the [`Retry`] stubs of a client, or of a pool of
+/// clients, so that retries across all of them are capped. This prevents retries from amplifying
+/// the load on servers that are already failing.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+    state: Arc<Mutex<BudgetState>>,
+}
+
+/// The number of buckets that a budget's window is divided into.
+const BUCKETS: u32 = 10;
+
+#[derive(Debug)]
+struct BudgetState {
+    max_retry_ratio: f64,
+    min_retries: u64,
+    bucket_len: Duration,
+    start: Instant,
+    buckets: [Bucket; BUCKETS as usize],
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    /// The index of the time slice counted by this bucket, since the budget started.
+    slice: u64,
+    calls: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    /// Returns a budget that allows retries to reach `max_retry_ratio` times the number of calls
+    /// made in the past `window`. For example, with a ratio of `0.2`, retries may not exceed 20% of
+    /// base traffic.
+    pub fn new(window: Duration, max_retry_ratio: f64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BudgetState {
+                max_retry_ratio,
+                min_retries: 0,
+                bucket_len: (window / BUCKETS).max(Duration::from_millis(1)),
+                start: Instant::now(),
+                buckets: Default::default(),
+            })),
+        }
+    }
+
+    /// Allows `min_retries` retries per window regardless of the number of calls, so that clients
+    /// with little traffic can still retry.
+    pub fn with_min_retries(self, min_retries: u64) -> Self {
+        self.lock().min_retries = min_retries;
+        self
+    }
+
+    /// Records a call.
+    pub fn deposit(&self) {
+        self.lock().current_bucket().calls += 1;
+    }
+
+    /// Records a retry and returns true, if the budget allows one more retry. Otherwise, returns
+    /// false.
+    pub fn try_withdraw(&self) -> bool {
+        let mut state = self.lock();
+        let slice = state.current_slice();
+        let (calls, retries) = state
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.slice + u64::from(BUCKETS) > slice)
+            .fold((0, 0), |(calls, retries), bucket| {
+                (calls + bucket.calls, retries + bucket.retries)
+            });
+        let allowed = state.min_retries as f64 + state.max_retry_ratio * calls as f64;
+        if (retries + 1) as f64 > allowed {
+            return false;
+        }
+        state.current_bucket().retries += 1;
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BudgetState> {
+        // The state is left consistent even if a lock holder panics.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl BudgetState {
+    fn current_slice(&self) -> u64 {
+        (self.start.elapsed().as_nanos() / self.bucket_len.as_nanos()) as u64
+    }
+
+    /// Returns the bucket for the current time slice, resetting it if it last counted an older
+    /// slice.
+    fn current_bucket(&mut self) -> &mut Bucket {
+        let slice = self.current_slice();
+        let bucket = &mut self.buckets[(slice % u64::from(BUCKETS)) as usize];
+        if bucket.slice != slice {
+            *bucket = Bucket {
+                slice,
+                ..Bucket::default()
+            };
+        }
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retry, RetryBudget};
+    use crate::{
+        backoff::Backoff,
+        client::{stub::Stub, RpcError},
+        context, ServerError,
+    };
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// A stub that always fails, counting the calls it receives.
+    #[derive(Default)]
+    struct AlwaysFails(AtomicU32);
+
+    impl Stub for AlwaysFails {
+        type Req = Arc<()>;
+        type Resp = ();
+
+        async fn call(
+            &self,
+            _: context::Context,
+            _: &'static str,
+            _: Arc<()>,
+        ) -> Result<(), RpcError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(RpcError::Server(ServerError::new(
+                io::ErrorKind::Other,
+                "unavailable".into(),
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn budget_limits_retries() {
+        let budget = RetryBudget::new(Duration::from_secs(10), 0.5);
+        let stub = Retry::new(
+            AlwaysFails::default(),
+            |_: &Result<(), RpcError>, attempt| attempt < 3,
+        )
+        .with_budget(budget);
+
+        for _ in 0..4 {
+            assert!(stub.call(context::current(), "", ()).await.is_err());
+        }
+        // 4 calls allow 2 retries.
+        assert_eq!(stub.stub.0.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn backoff_delays_retries() {
+        tokio::time::pause();
+        let stub = Retry::new(
+            AlwaysFails::default(),
+            |_: &Result<(), RpcError>, attempt| attempt < 3,
+        )
+        .with_backoff(Backoff::exponential(
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        ));
+
+        let start = tokio::time::Instant::now();
+        assert!(stub.call(context::current(), "", ()).await.is_err());
+        assert_eq!(stub.stub.0.load(Ordering::Relaxed), 3);
+        // Waits 1s, then 2s.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(3) && elapsed < Duration::from_secs(4),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn budget_is_replenished_over_time() {
+        tokio::time::pause();
+        let budget = RetryBudget::new(Duration::from_secs(10), 1.0).with_min_retries(1);
+
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+        budget.deposit();
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(budget.try_withdraw());
     }
 }
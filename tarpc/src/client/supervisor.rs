@@ -0,0 +1,512 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a client that reconnects to the server when its connection fails.
+//!
+//! [`spawn`] takes a factory that connects a new transport and spawns a task that owns the
+//! client's [`RequestDispatch`](crate::client::RequestDispatch). Whenever the dispatch
+//! terminates, the task waits according to its [`Backoff`] and then connects again. The returned
+//! [`SupervisedChannel`] always sends requests over the current connection and reports the
+//! lifecycle of the connection as a [`ConnectionState`].
+//!
+//! Connecting, including any handshakes performed by the factory such as TLS, starts as soon as
+//! the task is spawned. [`SupervisedChannel::ready`] waits for it to complete, so that the
+//! latency of the first requests doesn't include the cost of connecting. A [`Pool`] spreads
+//! requests over several supervised connections, which [`Pool::warm_up`] establishes ahead of
+//! time.
+
+use crate::util::tracing;
+use crate::{
+    client::{self, stub, Channel, RpcError},
+    context, util, ClientMessage, Response, Transport,
+};
+use futures::future;
+use std::{
+    error::Error,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::sync::watch;
+
+/// Controls how long the supervisor waits between connection attempts.
+pub use crate::backoff::Backoff;
+
+/// The state of a supervised connection.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// The supervisor is establishing a connection.
+    Connecting {
+        /// The number of consecutive attempts that failed before this one.
+        failed_attempts: u32,
+    },
+    /// The client is connected, and requests are sent to the server.
+    Connected,
+    /// The connection was lost or could not be established. The supervisor will reconnect after
+    /// `retry_in` has elapsed.
+    Disconnected {
+        /// The error that caused the disconnect. `None` if the server closed the connection.
+        error: Option<Arc<dyn Error + Send + Sync + 'static>>,
+        /// How long until the next connection attempt.
+        retry_in: Duration,
+    },
+    /// All [`SupervisedChannel`] handles were dropped, so the supervisor stopped.
+    Stopped,
+}
+
+impl ConnectionState {
+    /// Returns true iff the client is connected.
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected)
+    }
+}
+
+/// A client channel whose connection is maintained by a supervisor task.
+///
+/// Requests made while disconnected fail with [`RpcError::Shutdown`] rather than waiting for the
+/// connection to be re-established; compose with a [`Retry`](stub::retry::Retry) stub to ride
+/// out reconnects.
+#[derive(Debug)]
+pub struct SupervisedChannel<Req, Resp> {
+    shared: Arc<Mutex<Option<Channel<Req, Resp>>>>,
+    state: watch::Receiver<ConnectionState>,
+}
+
+impl<Req, Resp> Clone for SupervisedChannel<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> SupervisedChannel<Req, Resp> {
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified of every change to the state of the connection.
+    pub fn state_changes(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// Waits until the client is connected. Returns [`RpcError::Shutdown`] if the supervisor
+    /// stopped. While the server is unreachable, the supervisor keeps reconnecting and this
+    /// method keeps waiting, so callers that need a bound should apply a timeout.
+    pub async fn ready(&self) -> Result<(), RpcError> {
+        let mut state = self.state.clone();
+        let state = state
+            .wait_for(|state| {
+                matches!(state, ConnectionState::Connected | ConnectionState::Stopped)
+            })
+            .await
+            .map_err(|_| RpcError::Shutdown)?;
+        match *state {
+            ConnectionState::Connected => Ok(()),
+            _ => Err(RpcError::Shutdown),
+        }
+    }
+
+    fn channel(&self) -> Option<Channel<Req, Resp>> {
+        self.shared.lock().unwrap().clone()
+    }
+}
+
+impl<Req, Resp> stub::Stub for SupervisedChannel<Req, Resp> {
+    type Req = Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        match self.channel() {
+            Some(channel) => channel.call(ctx, request_name, request).await,
+            None => Err(RpcError::Shutdown),
+        }
+    }
+}
+
+/// Spawns a task that connects to the server using `connect` and keeps the connection alive,
+/// reconnecting with `backoff` whenever the connection fails or is closed by the server.
+///
+/// The task stops once every returned [`SupervisedChannel`] is dropped and the in-flight
+/// requests of the current connection have completed.
+pub fn spawn<Req, Resp, C, E, F, Fut>(
+    config: client::Config,
+    backoff: Backoff,
+    mut connect: F,
+) -> SupervisedChannel<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Transport<ClientMessage<Req>, Response<Resp>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<C, E>> + Send,
+{
+    let shared = Arc::new(Mutex::new(None));
+    let (state_tx, state) = watch::channel(ConnectionState::Connecting { failed_attempts: 0 });
+    let weak = Arc::downgrade(&shared);
+    util::spawn(format_args!("tarpc::client::supervisor"), async move {
+        supervise(config, backoff, &mut connect, weak, &state_tx).await;
+        tracing::info!("SupervisorStopped");
+        state_tx.send_replace(ConnectionState::Stopped);
+    });
+    SupervisedChannel { shared, state }
+}
+
+/// A pool of supervised connections to the same server. Requests are spread round-robin over the
+/// connections that are connected; if none is, a request waits for the next connection in turn
+/// to connect.
+///
+/// Connections are added by [`Pool::warm_up`], or, if the pool is empty, by the first request.
+#[derive(Debug)]
+pub struct Pool<Req, Resp, F> {
+    config: client::Config,
+    backoff: Backoff,
+    connect: F,
+    channels: Mutex<Vec<SupervisedChannel<Req, Resp>>>,
+    next: AtomicUsize,
+}
+
+impl<Req, Resp, C, E, F, Fut> Pool<Req, Resp, F>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Transport<ClientMessage<Req>, Response<Resp>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+    F: FnMut() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<C, E>> + Send,
+{
+    /// Returns an empty pool whose connections are created by `connect` and supervised with
+    /// `config` and `backoff`, as by [`spawn`].
+    pub fn new(config: client::Config, backoff: Backoff, connect: F) -> Self {
+        Pool {
+            config,
+            backoff,
+            connect,
+            channels: Mutex::new(vec![]),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of connections in the pool, whether connected or not.
+    pub fn len(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    /// Returns true iff the pool has no connections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Grows the pool to at least `n` connections and waits until all of them are connected.
+    /// Like [`SupervisedChannel::ready`], this waits as long as the server is unreachable.
+    pub async fn warm_up(&self, n: usize) -> Result<(), RpcError> {
+        let channels = {
+            let mut channels = self.channels.lock().unwrap();
+            while channels.len() < n {
+                channels.push(spawn(
+                    self.config.clone(),
+                    self.backoff.clone(),
+                    self.connect.clone(),
+                ));
+            }
+            channels.clone()
+        };
+        future::try_join_all(channels.iter().map(SupervisedChannel::ready)).await?;
+        Ok(())
+    }
+
+    fn next_channel(&self) -> SupervisedChannel<Req, Resp> {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.is_empty() {
+            channels.push(spawn(
+                self.config.clone(),
+                self.backoff.clone(),
+                self.connect.clone(),
+            ));
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = channels.len();
+        (0..len)
+            .map(|i| &channels[(start + i) % len])
+            .find(|channel| channel.state().is_connected())
+            .unwrap_or(&channels[start % len])
+            .clone()
+    }
+}
+
+impl<Req, Resp, C, E, F, Fut> stub::Stub for Pool<Req, Resp, F>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Transport<ClientMessage<Req>, Response<Resp>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+    F: FnMut() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<C, E>> + Send,
+{
+    type Req = Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        let channel = self.next_channel();
+        channel.ready().await?;
+        channel.call(ctx, request_name, request).await
+    }
+}
+
+async fn supervise<Req, Resp, C, E, F, Fut>(
+    config: client::Config,
+    backoff: Backoff,
+    connect: &mut F,
+    shared: Weak<Mutex<Option<Channel<Req, Resp>>>>,
+    state: &watch::Sender<ConnectionState>,
+) where
+    C: Transport<ClientMessage<Req>, Response<Resp>>,
+    E: Error + Send + Sync + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<C, E>>,
+{
+    let mut delays = backoff.delays();
+    while shared.strong_count() > 0 {
+        state.send_replace(ConnectionState::Connecting {
+            failed_attempts: delays.failed_attempts(),
+        });
+        let error: Option<Arc<dyn Error + Send + Sync + 'static>> = match connect().await {
+            Ok(transport) => {
+                let client::NewClient { client, dispatch } = client::new(config.clone(), transport);
+                match shared.upgrade() {
+                    Some(shared) => *shared.lock().unwrap() = Some(client),
+                    None => return,
+                }
+                tracing::info!("Connected");
+                delays.reset();
+                state.send_replace(ConnectionState::Connected);
+                let result = dispatch.await;
+                if let Some(shared) = shared.upgrade() {
+                    *shared.lock().unwrap() = None;
+                }
+                match result {
+                    Ok(()) => None,
+                    Err(e) => Some(Arc::new(e)),
+                }
+            }
+            Err(e) => Some(Arc::new(e)),
+        };
+        if shared.strong_count() == 0 {
+            return;
+        }
+        let retry_in = delays.next_delay();
+        match &error {
+            Some(e) => tracing::warn!(
+                "Disconnected: {}; reconnecting in {:?}",
+                crate::util::print_err(&**e),
+                retry_in
+            ),
+            None => tracing::info!("Disconnected by server; reconnecting in {:?}", retry_in),
+        }
+        state.send_replace(ConnectionState::Disconnected { error, retry_in });
+        tokio::time::sleep(retry_in).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn, Backoff, ConnectionState, Pool};
+    use crate::{
+        client::{self, stub::Stub},
+        context,
+        transport::{self, channel::UnboundedChannel},
+        ClientMessage, Response,
+    };
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::sync::mpsc;
+
+    type ServerTransport = UnboundedChannel<ClientMessage<u32>, Response<u32>>;
+
+    fn fast_backoff() -> Backoff {
+        Backoff::exponential(Duration::from_millis(1), Duration::from_millis(10))
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_server_disconnect() {
+        let (servers_tx, mut servers) = mpsc::unbounded_channel::<ServerTransport>();
+        let channel = spawn(client::Config::default(), fast_backoff(), move || {
+            let (client_transport, server_transport) = transport::channel::unbounded();
+            servers_tx.send(server_transport).unwrap();
+            future::ready(Ok::<_, io::Error>(client_transport))
+        });
+        let mut state = channel.state_changes();
+
+        state.wait_for(ConnectionState::is_connected).await.unwrap();
+        drop(servers.recv().await.unwrap());
+        let mut server = servers.recv().await.unwrap();
+        state.wait_for(ConnectionState::is_connected).await.unwrap();
+
+        let call = channel.call(context::current(), "", 1);
+        let respond = async {
+            let request = match server.next().await {
+                Some(Ok(ClientMessage::Request(request))) => request,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            server
+                .send(Response {
+                    request_id: request.id,
+                    message: Ok(request.message + 1),
+                    more: false,
+                })
+                .await
+                .unwrap();
+        };
+        let (response, ()) = future::join(call, respond).await;
+        assert_matches!(response, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn retries_failed_connection_attempts() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (servers_tx, mut servers) = mpsc::unbounded_channel::<ServerTransport>();
+        let channel = spawn(client::Config::default(), fast_backoff(), {
+            let attempts = attempts.clone();
+            move || {
+                let result = if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                } else {
+                    let (client_transport, server_transport) = transport::channel::unbounded();
+                    servers_tx.send(server_transport).unwrap();
+                    Ok(client_transport)
+                };
+                future::ready(result)
+            }
+        });
+        let mut state = channel.state_changes();
+
+        state.wait_for(ConnectionState::is_connected).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(servers.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn stops_when_channels_dropped() {
+        let (servers_tx, mut servers) = mpsc::unbounded_channel::<ServerTransport>();
+        let channel = spawn(client::Config::default(), fast_backoff(), move || {
+            let (client_transport, server_transport) = transport::channel::unbounded();
+            servers_tx.send(server_transport).unwrap();
+            future::ready(Ok::<_, io::Error>(client_transport))
+        });
+        let mut state = channel.state_changes();
+        state.wait_for(ConnectionState::is_connected).await.unwrap();
+        let _server = servers.recv().await.unwrap();
+
+        drop(channel);
+        state
+            .wait_for(|s| matches!(s, ConnectionState::Stopped))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ready_waits_for_connection() {
+        let (servers_tx, mut servers) = mpsc::unbounded_channel::<ServerTransport>();
+        let connected = Arc::new(tokio::sync::Notify::new());
+        let channel = spawn(client::Config::default(), fast_backoff(), {
+            let connected = connected.clone();
+            move || {
+                let connected = connected.clone();
+                let servers_tx = servers_tx.clone();
+                async move {
+                    // Stands in for a slow TCP and TLS handshake.
+                    connected.notified().await;
+                    let (client_transport, server_transport) = transport::channel::unbounded();
+                    servers_tx.send(server_transport).unwrap();
+                    Ok::<_, io::Error>(client_transport)
+                }
+            }
+        });
+
+        assert!(futures::poll!(Box::pin(channel.ready())).is_pending());
+        connected.notify_one();
+        channel.ready().await.unwrap();
+        assert!(channel.state().is_connected());
+        assert!(servers.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn pool_warm_up_connects_ahead_of_requests() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (servers_tx, mut servers) = mpsc::unbounded_channel::<ServerTransport>();
+        let pool = Pool::new(client::Config::default(), fast_backoff(), {
+            let connections = connections.clone();
+            move || {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let (client_transport, server_transport) = transport::channel::unbounded();
+                servers_tx.send(server_transport).unwrap();
+                future::ready(Ok::<_, io::Error>(client_transport))
+            }
+        });
+
+        pool.warm_up(3).await.unwrap();
+        assert_eq!(pool.len(), 3);
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        pool.warm_up(2).await.unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        let mut servers = vec![
+            servers.recv().await.unwrap(),
+            servers.recv().await.unwrap(),
+            servers.recv().await.unwrap(),
+        ];
+        let server = tokio::spawn(async move {
+            let mut requests_per_server = vec![];
+            for server in &mut servers {
+                let request = match server.next().await {
+                    Some(Ok(ClientMessage::Request(request))) => request,
+                    other => panic!("unexpected message: {other:?}"),
+                };
+                requests_per_server.push(request.message);
+                server
+                    .send(Response {
+                        request_id: request.id,
+                        message: Ok(request.message),
+                        more: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+            requests_per_server
+        });
+        let calls = (0..3).map(|i| pool.call(context::current(), "", i));
+        let responses = future::try_join_all(calls).await.unwrap();
+        assert_eq!(responses, [0, 1, 2]);
+        let mut requests = server.await.unwrap();
+        requests.sort();
+        assert_eq!(requests, [0, 1, 2]);
+    }
+}
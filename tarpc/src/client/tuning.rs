@@ -0,0 +1,106 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::time::Duration;
+
+/// The in-flight limit before any responses have been observed.
+const INITIAL_LIMIT: f64 = 20.;
+/// The number of samples over which the long-term round-trip time is averaged.
+const LONG_WINDOW: f64 = 100.;
+/// How much slower than the long-term average a round trip may be before the limit shrinks.
+const TOLERANCE: f64 = 1.5;
+/// The weight of each new limit estimate.
+const SMOOTHING: f64 = 0.2;
+
+/// Adapts the dispatch's in-flight limit and flush batch size to the observed round-trip times of
+/// requests.
+///
+/// The limit follows a gradient: while round trips are about as fast as their long-term average,
+/// the server is keeping up and the limit grows; as round trips slow down, requests are queueing
+/// at the server and the limit shrinks, so that they queue in the client's pending buffer
+/// instead, where they can still be cancelled cheaply.
+///
+/// The in-flight limit, rather than the size of the pending buffer, is the knob being tuned
+/// because the buffer is a bounded channel whose capacity is fixed at creation, while the limit
+/// can change on every response. Admitting fewer requests leaves more of them in the buffer,
+/// which has the effect of a larger client-side queue without reallocating it.
+#[derive(Debug)]
+pub(crate) struct Tuner {
+    max_limit: f64,
+    limit: f64,
+    long_rtt: Option<f64>,
+}
+
+impl Tuner {
+    pub(crate) fn new(max_limit: usize) -> Self {
+        let max_limit = max_limit as f64;
+        Self {
+            max_limit,
+            limit: INITIAL_LIMIT.min(max_limit),
+            long_rtt: None,
+        }
+    }
+
+    /// The number of requests that may currently be in flight.
+    pub(crate) fn in_flight_limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// The number of requests that may be written before the transport is flushed. Small limits
+    /// indicate a latency-bound server, so requests are flushed eagerly; large limits indicate
+    /// throughput-bound traffic, which benefits from batching writes.
+    pub(crate) fn flush_batch(&self) -> usize {
+        self.limit.sqrt().ceil() as usize
+    }
+
+    /// Records the round-trip time of a request that received a response.
+    pub(crate) fn on_response(&mut self, rtt: Duration) {
+        let rtt = rtt.as_secs_f64().max(f64::EPSILON);
+        let mut long_rtt = match self.long_rtt {
+            Some(long_rtt) => long_rtt + (rtt - long_rtt) / LONG_WINDOW,
+            None => rtt,
+        };
+        // Lets the average catch up quickly after a period of slow responses ends.
+        if long_rtt / rtt > 2. {
+            long_rtt *= 0.95;
+        }
+        self.long_rtt = Some(long_rtt);
+
+        let gradient = (TOLERANCE * long_rtt / rtt).clamp(0.5, 1.);
+        let estimate = self.limit * gradient + self.limit.sqrt();
+        self.limit =
+            (self.limit * (1. - SMOOTHING) + estimate * SMOOTHING).clamp(1., self.max_limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tuner;
+    use std::time::Duration;
+
+    #[test]
+    fn steady_latency_grows_limit_to_max() {
+        let mut tuner = Tuner::new(100);
+        for _ in 0..1_000 {
+            tuner.on_response(Duration::from_millis(10));
+        }
+        assert_eq!(tuner.in_flight_limit(), 100);
+        assert_eq!(tuner.flush_batch(), 10);
+    }
+
+    #[test]
+    fn rising_latency_shrinks_limit() {
+        let mut tuner = Tuner::new(1_000);
+        for _ in 0..100 {
+            tuner.on_response(Duration::from_millis(10));
+        }
+        let before = tuner.in_flight_limit();
+        for _ in 0..20 {
+            tuner.on_response(Duration::from_millis(100));
+        }
+        assert!(tuner.in_flight_limit() < before / 2);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a watchdog that flags stalled requests.
+//!
+//! Every request a client sends is completed, canceled, or expired by its dispatch by the time
+//! its deadline passes. A request that is still outstanding long after its deadline indicates a
+//! bug in expiration or a dispatch that is no longer polled, e.g. because it is blocked on a
+//! transport. A [`Watchdog`], configured via [`Config::watchdog`](super::Config::watchdog),
+//! tracks the requests of the dispatches configured with it, independently of the dispatches
+//! themselves, and reports requests outstanding for longer than a multiple of their timeout.
+//! Reports go to a [`tracing`] event by default, or to any function, such as one feeding an
+//! admin endpoint or metrics. To see what happened to a stalled request before it stalled,
+//! configure a [`DispatchLog`](super::dispatch_log::DispatchLog) too.
+
+use super::dispatch_log::Op;
+use crate::util::tracing;
+use fnv::FnvHashMap;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// A request that has been outstanding for longer than a multiple of its timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StalledRequest {
+    /// Identifies the dispatch that sent the request, among those configured with the watchdog.
+    pub dispatch: u64,
+    /// The ID of the request.
+    pub request_id: u64,
+    /// The last state transition of the request.
+    pub last_op: Op,
+    /// How long the request has been outstanding.
+    pub elapsed: Duration,
+    /// How long the request had until its deadline when the dispatch staged it.
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Watched {
+    last_op: Op,
+    staged_at: Instant,
+    timeout: Duration,
+}
+
+struct Shared {
+    requests: Mutex<FnvHashMap<(u64, u64), Watched>>,
+    next_dispatch: AtomicU64,
+    report: Box<dyn Fn(&[StalledRequest]) + Send + Sync>,
+}
+
+/// Tracks outstanding requests and reports those that stall. Cloning the watchdog produces a
+/// handle to the same watchdog.
+#[derive(Clone)]
+pub struct Watchdog {
+    multiple: u32,
+    shared: Arc<Shared>,
+}
+
+impl Watchdog {
+    /// Returns a watchdog that flags requests outstanding for longer than `multiple` times their
+    /// timeout, and reports them as a `StalledRequests` [`tracing`] event.
+    pub fn new(multiple: u32) -> Self {
+        Self::with_report(multiple, |stalled| {
+            tracing::error!(
+                "StalledRequests: {} requests outstanding long past their deadlines: {:?}",
+                stalled.len(),
+                stalled
+            )
+        })
+    }
+
+    /// Returns a watchdog that flags requests outstanding for longer than `multiple` times their
+    /// timeout, and passes them to `report`.
+    pub fn with_report<F>(multiple: u32, report: F) -> Self
+    where
+        F: Fn(&[StalledRequest]) + Send + Sync + 'static,
+    {
+        Self {
+            multiple,
+            shared: Arc::new(Shared {
+                requests: Mutex::new(FnvHashMap::default()),
+                next_dispatch: AtomicU64::new(0),
+                report: Box::new(report),
+            }),
+        }
+    }
+
+    /// Returns the multiple of their timeout after which requests are flagged.
+    pub fn multiple(&self) -> u32 {
+        self.multiple
+    }
+
+    /// Returns the requests that are currently stalled.
+    pub fn stalled(&self) -> Vec<StalledRequest> {
+        let mut stalled: Vec<_> = self
+            .shared
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(&(dispatch, request_id), watched)| {
+                let elapsed = watched.staged_at.elapsed();
+                (elapsed > watched.timeout * self.multiple).then(|| StalledRequest {
+                    dispatch,
+                    request_id,
+                    last_op: watched.last_op,
+                    elapsed,
+                    timeout: watched.timeout,
+                })
+            })
+            .collect();
+        stalled.sort_by_key(|stalled| (stalled.dispatch, stalled.request_id));
+        stalled
+    }
+
+    /// Reports the requests that are currently stalled, if there are any. Returns the number of
+    /// stalled requests.
+    pub fn check(&self) -> usize {
+        let stalled = self.stalled();
+        if !stalled.is_empty() {
+            (self.shared.report)(&stalled);
+        }
+        stalled.len()
+    }
+
+    /// Checks for stalled requests every `period`, forever.
+    pub async fn run(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.check();
+        }
+    }
+
+    /// Returns a handle through which a dispatch registers its requests.
+    pub(crate) fn watch(&self) -> Watch {
+        Watch {
+            dispatch: self.shared.next_dispatch.fetch_add(1, Ordering::Relaxed),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("multiple", &self.multiple)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The requests of one dispatch, as tracked by a [`Watchdog`]. Dropping the handle forgets the
+/// requests of the dispatch.
+pub(crate) struct Watch {
+    dispatch: u64,
+    shared: Arc<Shared>,
+}
+
+impl Watch {
+    /// Starts tracking a request that was staged with `timeout` left until its deadline.
+    pub fn staged(&self, request_id: u64, timeout: Duration) {
+        self.shared.requests.lock().unwrap().insert(
+            (self.dispatch, request_id),
+            Watched {
+                last_op: Op::Staged,
+                staged_at: Instant::now(),
+                timeout,
+            },
+        );
+    }
+
+    /// Records a later state transition of a request. Completion, cancellation, and expiration
+    /// stop tracking the request.
+    pub fn record(&self, request_id: u64, op: Op) {
+        let mut requests = self.shared.requests.lock().unwrap();
+        let key = (self.dispatch, request_id);
+        match op {
+            Op::Completed | Op::Canceled | Op::Expired => {
+                requests.remove(&key);
+            }
+            _ => {
+                if let Some(watched) = requests.get_mut(&key) {
+                    watched.last_op = op;
+                }
+            }
+        }
+    }
+
+    /// Stops tracking all requests of the dispatch, e.g. because they were all failed.
+    pub fn forget_all(&self) {
+        let dispatch = self.dispatch;
+        self.shared
+            .requests
+            .lock()
+            .unwrap()
+            .retain(|&(d, _), _| d != dispatch);
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.forget_all();
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch")
+            .field("dispatch", &self.dispatch)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StalledRequest, Watchdog};
+    use crate::client::dispatch_log::Op;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn flags_requests_outstanding_past_multiple_of_timeout() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let watchdog = Watchdog::with_report(3, {
+            let reports = reports.clone();
+            move |stalled: &[StalledRequest]| reports.lock().unwrap().push(stalled.to_vec())
+        });
+        let watch = watchdog.watch();
+        let timeout = Duration::from_secs(1);
+        watch.staged(0, timeout);
+        watch.record(0, Op::Written);
+        watch.staged(1, timeout);
+        watch.staged(2, Duration::from_secs(10));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(watchdog.check(), 0);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        watch.record(1, Op::Completed);
+        assert_eq!(watchdog.check(), 1);
+        let reports = reports.lock().unwrap();
+        assert_matches::assert_matches!(
+            reports.as_slice(),
+            [stalled] if matches!(
+                stalled.as_slice(),
+                [StalledRequest { request_id: 0, last_op: Op::Written, .. }]
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_dispatch_is_forgotten() {
+        let watchdog = Watchdog::new(1);
+        let (first, second) = (watchdog.watch(), watchdog.watch());
+        first.staged(0, Duration::ZERO);
+        second.staged(0, Duration::ZERO);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(watchdog.stalled().len(), 2);
+
+        drop(first);
+        assert_matches::assert_matches!(
+            watchdog.stalled().as_slice(),
+            [StalledRequest { dispatch: 1, .. }]
+        );
+    }
+}
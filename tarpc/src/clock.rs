@@ -0,0 +1,110 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides the wall clock used to compute and enforce request deadlines.
+//!
+//! Deadlines are absolute [`SystemTime`]s, so by default tarpc reads the operating system's clock.
+//! Deterministic simulations, in which time only advances when the simulator says so, can replace
+//! the clock with [`set_clock`]. [`tokio_clock`] derives the wall clock from tokio's clock, which
+//! follows [paused](tokio::time::pause) and simulated time.
+//!
+//! The installed clock is process-wide: it governs deadlines computed and enforced on every
+//! thread, including the worker threads of a multi-thread runtime.
+
+use std::{
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Once,
+    },
+    time::SystemTime,
+};
+
+/// The clock installed by [`set_clock`], cast to a data pointer since `AtomicPtr` cannot hold
+/// function pointers; null until a clock is installed.
+static CLOCK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+fn clock_from_ptr(clock: *mut ()) -> fn() -> SystemTime {
+    if clock.is_null() {
+        SystemTime::now
+    } else {
+        // SAFETY: the only non-null values stored in CLOCK are `fn() -> SystemTime`s cast by
+        // `set_clock`.
+        unsafe { std::mem::transmute::<*mut (), fn() -> SystemTime>(clock) }
+    }
+}
+
+/// Returns the current time according to the installed clock.
+pub fn now() -> SystemTime {
+    clock_from_ptr(CLOCK.load(Ordering::Acquire))()
+}
+
+/// Replaces the clock used by all threads, returning the previously installed clock.
+pub fn set_clock(clock: fn() -> SystemTime) -> fn() -> SystemTime {
+    clock_from_ptr(CLOCK.swap(clock as *mut (), Ordering::AcqRel))
+}
+
+/// A clock that advances with tokio's clock. The first call in the process reads the system clock,
+/// and subsequent calls, on any thread, add the time elapsed on tokio's clock since then.
+///
+/// tokio's clock can only be paused on a current-thread runtime; on other runtimes, this clock
+/// follows the system's monotonic clock.
+pub fn tokio_clock() -> SystemTime {
+    static START_ONCE: Once = Once::new();
+    static mut START: Option<(SystemTime, tokio::time::Instant)> = None;
+    START_ONCE.call_once(|| {
+        // SAFETY: START is only written here, which `Once` runs exactly once, before any read.
+        unsafe { START = Some((SystemTime::now(), tokio::time::Instant::now())) }
+    });
+    // SAFETY: `call_once` has returned, so START was written and is never written again.
+    let (system, instant) = unsafe { START }.expect("START is set by START_ONCE");
+    system + instant.elapsed()
+}
+
+/// Held by tests that install a clock, so that they do not replace each other's clocks. Since the
+/// clock is process-wide, the clocks installed by tests must also keep close to the system clock,
+/// for the sake of tests running concurrently.
+#[cfg(test)]
+pub(crate) static TEST_CLOCK_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[cfg(test)]
+mod tests {
+    use super::{now, set_clock, tokio_clock, TEST_CLOCK_LOCK};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, SystemTime},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn tokio_clock_follows_paused_time() {
+        let _lock = TEST_CLOCK_LOCK.lock().await;
+        let previous = set_clock(tokio_clock);
+        let start = now();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(
+            now().duration_since(start).unwrap(),
+            Duration::from_secs(60)
+        );
+        set_clock(previous);
+    }
+
+    #[test]
+    fn set_clock_replaces_clock_on_all_threads() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn counting() -> SystemTime {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            SystemTime::now()
+        }
+
+        let _lock = TEST_CLOCK_LOCK.blocking_lock();
+        let previous = set_clock(counting);
+        now();
+        std::thread::spawn(now).join().unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+        set_clock(previous);
+        now();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,463 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A suite of scripted exchanges that checks whether a server speaks tarpc's protocol.
+//!
+//! Servers written in other languages, or custom servers that don't use [`BaseChannel`], can
+//! verify that they are wire-compatible with this crate's clients. The server under test
+//! implements a small conformance service, whose requests are [`ConformanceRequest`]s and whose
+//! responses are [`ConformanceResponse`]s, over any transport and serialization format. [`run`]
+//! connects to it once per check, plays the client's part of the protocol message by message, and
+//! reports which of the server's responses deviated from what a tarpc server does:
+//!
+//! - a request is answered with a response carrying the request's ID, and an error returned by
+//!   the handler is sent as a [`ServerError`];
+//! - requests written back-to-back are each answered exactly once;
+//! - a [cancellation](ClientMessage::Cancel) stops the request, which is then never answered;
+//! - a request whose deadline has already passed is never answered;
+//! - a request whose ID is already in flight is ignored.
+//!
+//! [`ConformanceService`] is the reference implementation of the conformance service. Serving it
+//! from a tarpc server lets clients written in other languages check themselves against this crate
+//! in turn.
+//!
+//! For checks of custom [`Channel`](crate::server::Channel) implementations within this crate,
+//! see [`server::conformance`](crate::server::conformance).
+//!
+//! [`BaseChannel`]: crate::server::BaseChannel
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     conformance,
+//!     server::{BaseChannel, Channel},
+//!     transport,
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let report = conformance::run(|| async {
+//!     // Normally, connect to the server under test, e.g. over TCP.
+//!     let (client, server) = transport::channel::unbounded();
+//!     tokio::spawn(
+//!         BaseChannel::with_defaults(server)
+//!             .execute(conformance::ConformanceService)
+//!             .for_each(|response| async { tokio::spawn(response); }),
+//!     );
+//!     Ok(client)
+//! })
+//! .await;
+//! report.assert_passed();
+//! # }
+//! ```
+
+use crate::util::tracing;
+use crate::{context, server, trace, ClientMessage, Request, Response, ServerError, Transport};
+use futures::prelude::*;
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    io,
+    pin::pin,
+    time::{Duration, SystemTime},
+};
+
+/// A request to the conformance service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConformanceRequest {
+    /// Answer with [`ConformanceResponse::Echo`] carrying the same string.
+    Echo(String),
+    /// Wait for the given number of milliseconds, then answer with
+    /// [`ConformanceResponse::Slept`].
+    Sleep {
+        /// How long to wait, in milliseconds.
+        millis: u64,
+    },
+    /// Fail the request with a [`ServerError`] of kind
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) and the given detail.
+    Fail(String),
+}
+
+/// A response of the conformance service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConformanceResponse {
+    /// Answers [`ConformanceRequest::Echo`].
+    Echo(String),
+    /// Answers [`ConformanceRequest::Sleep`].
+    Slept,
+}
+
+/// The reference implementation of the conformance service.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConformanceService;
+
+impl server::Serve for ConformanceService {
+    type Req = ConformanceRequest;
+    type Resp = ConformanceResponse;
+
+    async fn serve(
+        self,
+        _: context::Context,
+        request: ConformanceRequest,
+    ) -> Result<ConformanceResponse, ServerError> {
+        match request {
+            ConformanceRequest::Echo(message) => Ok(ConformanceResponse::Echo(message)),
+            ConformanceRequest::Sleep { millis } => {
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                Ok(ConformanceResponse::Slept)
+            }
+            ConformanceRequest::Fail(detail) => {
+                Err(ServerError::new(io::ErrorKind::InvalidInput, detail))
+            }
+        }
+    }
+}
+
+/// How long a check waits for a response before failing, and for a response that should never
+/// come before passing.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const SILENCE: Duration = Duration::from_millis(200);
+
+/// The outcome of one check.
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    /// The name of the check.
+    pub name: &'static str,
+    /// Why the check failed, if it did.
+    pub failure: Option<String>,
+}
+
+/// The outcomes of all checks of a [`run`].
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// The outcome of each check, in the order they ran.
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    /// Returns whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.failure.is_none())
+    }
+
+    /// Panics with the report if any check failed.
+    pub fn assert_passed(&self) {
+        assert!(self.passed(), "conformance checks failed:\n{self}");
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            match &result.failure {
+                None => writeln!(f, "ok    {}", result.name)?,
+                Some(failure) => writeln!(f, "FAIL  {}: {}", result.name, failure)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type CheckError = String;
+
+/// Runs every check against the server, connecting to it with `connect` once per check. Every
+/// check runs, even if an earlier one failed. Uses the tokio timer, so it must run within a tokio
+/// runtime.
+pub async fn run<F, Fut, T>(mut connect: F) -> Report
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    let mut results = vec![];
+    macro_rules! check {
+        ($name:ident) => {
+            let failure = match connect().await {
+                Ok(transport) => $name(pin!(transport)).await.err(),
+                Err(e) => Some(format!("could not connect: {e}")),
+            };
+            if let Some(failure) = &failure {
+                tracing::warn!(check = stringify!($name), %failure, "ConformanceCheckFailed");
+            }
+            results.push(CheckResult {
+                name: stringify!($name),
+                failure,
+            });
+        };
+    }
+    check!(echo);
+    check!(server_error);
+    check!(pipelined_requests);
+    check!(cancellation);
+    check!(expired_request);
+    check!(duplicate_request_id);
+    Report { results }
+}
+
+/// Checks that a request is answered with its ID and the handler's response.
+async fn echo<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    send(&mut transport, request(3, echo_request("hello"))).await?;
+    let response = receive(&mut transport).await?;
+    expect_id(&response, 3)?;
+    expect_message(response, Ok(ConformanceResponse::Echo("hello".into())))
+}
+
+/// Checks that a handler's error is sent back as a [`ServerError`].
+async fn server_error<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    let fail = ConformanceRequest::Fail("bad input".into());
+    send(&mut transport, request(0, fail)).await?;
+    let response = receive(&mut transport).await?;
+    expect_id(&response, 0)?;
+    expect_message(
+        response,
+        Err(ServerError::new(
+            io::ErrorKind::InvalidInput,
+            "bad input".into(),
+        )),
+    )
+}
+
+/// Checks that requests written without waiting for responses are each answered exactly once.
+async fn pipelined_requests<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    const REQUESTS: u64 = 8;
+    for id in 0..REQUESTS {
+        let message = echo_request(&id.to_string());
+        transport
+            .feed(request(id, message))
+            .await
+            .map_err(|e| format!("could not write request: {e}"))?;
+    }
+    transport
+        .flush()
+        .await
+        .map_err(|e| format!("could not flush requests: {e}"))?;
+    let mut answered = HashSet::new();
+    while answered.len() < REQUESTS as usize {
+        let response = receive(&mut transport).await?;
+        let id = response.request_id;
+        if id >= REQUESTS || !answered.insert(id) {
+            return Err(format!("unexpected response to request {id}"));
+        }
+        expect_message(response, Ok(ConformanceResponse::Echo(id.to_string())))?;
+    }
+    Ok(())
+}
+
+/// Checks that a canceled request is never answered, and that the connection stays usable.
+async fn cancellation<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    let sleep = ConformanceRequest::Sleep { millis: 60_000 };
+    let sleep = request(1, sleep);
+    let trace_context = trace_context(&sleep);
+    send(&mut transport, sleep).await?;
+    send(
+        &mut transport,
+        ClientMessage::Cancel {
+            trace_context,
+            request_id: 1,
+        },
+    )
+    .await?;
+    send(&mut transport, request(2, echo_request("after cancel"))).await?;
+    let response = receive(&mut transport).await?;
+    expect_id(&response, 2)?;
+    expect_silence(&mut transport).await
+}
+
+/// Checks that a request whose deadline has passed is never answered.
+async fn expired_request<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    let mut expired = request(1, echo_request("expired"));
+    if let ClientMessage::Request(request) = &mut expired {
+        request.context.deadline = SystemTime::now() - Duration::from_secs(1);
+    }
+    send(&mut transport, expired).await?;
+    send(&mut transport, request(2, echo_request("current"))).await?;
+    let response = receive(&mut transport).await?;
+    expect_id(&response, 2)?;
+    expect_silence(&mut transport).await
+}
+
+/// Checks that a request with the ID of a request in flight is ignored.
+async fn duplicate_request_id<T>(mut transport: std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    let sleep = ConformanceRequest::Sleep { millis: 100 };
+    send(&mut transport, request(1, sleep)).await?;
+    send(&mut transport, request(1, echo_request("duplicate"))).await?;
+    let response = receive(&mut transport).await?;
+    expect_id(&response, 1)?;
+    expect_message(response, Ok(ConformanceResponse::Slept))?;
+    expect_silence(&mut transport).await
+}
+
+fn request(id: u64, message: ConformanceRequest) -> ClientMessage<ConformanceRequest> {
+    let mut context = context::current();
+    context.deadline = SystemTime::now() + Duration::from_secs(120);
+    ClientMessage::Request(Request {
+        context,
+        id,
+        message,
+        one_way: false,
+        streamed: false,
+    })
+}
+
+fn echo_request(message: &str) -> ConformanceRequest {
+    ConformanceRequest::Echo(message.into())
+}
+
+fn trace_context(message: &ClientMessage<ConformanceRequest>) -> trace::Context {
+    match message {
+        ClientMessage::Request(request) => request.context.trace_context,
+        ClientMessage::Cancel { trace_context, .. } => *trace_context,
+        ClientMessage::Close { .. } | ClientMessage::Item { .. } => trace::Context::default(),
+    }
+}
+
+async fn send<T>(
+    transport: &mut std::pin::Pin<&mut T>,
+    message: ClientMessage<ConformanceRequest>,
+) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    transport
+        .send(message)
+        .await
+        .map_err(|e| format!("could not write message: {e}"))
+}
+
+async fn receive<T>(
+    transport: &mut std::pin::Pin<&mut T>,
+) -> Result<Response<ConformanceResponse>, CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    match tokio::time::timeout(RESPONSE_TIMEOUT, transport.next()).await {
+        Ok(Some(Ok(response))) => Ok(response),
+        Ok(Some(Err(e))) => Err(format!("could not read response: {e}")),
+        Ok(None) => Err("server closed the connection".into()),
+        Err(_) => Err(format!("no response within {RESPONSE_TIMEOUT:?}")),
+    }
+}
+
+/// Fails if the server sends anything more within a short while.
+async fn expect_silence<T>(transport: &mut std::pin::Pin<&mut T>) -> Result<(), CheckError>
+where
+    T: Transport<ClientMessage<ConformanceRequest>, Response<ConformanceResponse>>,
+{
+    match tokio::time::timeout(SILENCE, transport.next()).await {
+        Ok(Some(Ok(response))) => Err(format!(
+            "unexpected response to request {}",
+            response.request_id
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn expect_id(response: &Response<ConformanceResponse>, id: u64) -> Result<(), CheckError> {
+    if response.request_id == id {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected a response to request {id}, got one to request {}",
+            response.request_id
+        ))
+    }
+}
+
+fn expect_message(
+    response: Response<ConformanceResponse>,
+    expected: Result<ConformanceResponse, ServerError>,
+) -> Result<(), CheckError> {
+    if response.message == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {expected:?} in response to request {}, got {:?}",
+            response.request_id, response.message
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, ConformanceRequest, ConformanceResponse, ConformanceService};
+    use crate::{
+        server::{self, BaseChannel, Channel, Serve},
+        transport, ClientMessage, Response,
+    };
+    use futures::prelude::*;
+    use tokio::task::LocalSet;
+
+    type ClientTransport = transport::channel::UnboundedChannel<
+        Response<ConformanceResponse>,
+        ClientMessage<ConformanceRequest>,
+    >;
+
+    /// Serves `serve` on a new in-memory connection. Must run within a [`LocalSet`].
+    fn connect<S>(serve: S) -> ClientTransport
+    where
+        S: Serve<Req = ConformanceRequest, Resp = ConformanceResponse> + Clone + 'static,
+    {
+        let (client, server) = transport::channel::unbounded();
+        tokio::task::spawn_local(
+            BaseChannel::with_defaults(server)
+                .execute(serve)
+                .for_each_concurrent(None, |response| response),
+        );
+        client
+    }
+
+    #[tokio::test]
+    async fn base_channel_conforms() {
+        let report = LocalSet::new()
+            .run_until(run(|| async { Ok(connect(ConformanceService)) }))
+            .await;
+        report.assert_passed();
+    }
+
+    #[tokio::test]
+    async fn reports_wrong_responses() {
+        let echo_uppercase = server::serve(|ctx, request| async move {
+            match request {
+                ConformanceRequest::Echo(message) => {
+                    Ok(ConformanceResponse::Echo(message.to_uppercase()))
+                }
+                request => ConformanceService.serve(ctx, request).await,
+            }
+        });
+        let report = LocalSet::new()
+            .run_until(run(|| async { Ok(connect(echo_uppercase)) }))
+            .await;
+        assert!(!report.passed());
+        let failed: Vec<_> = report
+            .results
+            .iter()
+            .filter(|result| result.failure.is_some())
+            .map(|result| result.name)
+            .collect();
+        assert_eq!(failed, ["echo"]);
+    }
+}
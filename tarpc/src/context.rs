@@ -6,14 +6,27 @@
 
 //! Provides a request context that carries a deadline and trace context. This context is sent from
 //! client to server and is used by the server to enforce response deadlines.
+//!
+//! This module also provides [`Cancellation`], which links the client calls a server makes while
+//! handling a request to the request itself, so that they are canceled along with it.
 
 use crate::trace::{self, TraceId};
+use crate::util::tracing;
+use futures::prelude::*;
+#[cfg(feature = "tracing")]
 use opentelemetry::trace::TraceContextExt;
+use pin_project::pin_project;
 use static_assertions::assert_impl_all;
 use std::{
+    cell::RefCell,
     convert::TryFrom,
+    pin::Pin,
+    task::Poll,
     time::{Duration, SystemTime},
 };
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
+#[cfg(feature = "tracing")]
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 /// A request context that carries request-scoped information like deadlines and trace information.
@@ -36,6 +49,43 @@ pub struct Context {
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// How long the request has waited in queues before being processed. Each side records its
+    /// own half; never sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub queue_time: QueueTime,
+    /// How the request arrived at the server. Set by the server's channel when it reads the
+    /// request; never sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// The raw facts about a request that a server's channel records as it reads the request, for
+/// middleware that routes or measures requests without unpacking them, e.g. to send large
+/// requests to a dedicated pool or to measure queueing delay from the moment of arrival.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestEnvelope {
+    /// The ID that the client assigned to the request.
+    pub request_id: u64,
+    /// The size of the request message in bytes, as measured by the channel's request length
+    /// function. To record serialized sizes, set that function to one that returns the
+    /// serialized size with [`BaseChannel::with_message_len`](crate::server::BaseChannel::with_message_len).
+    pub len: usize,
+    /// When the channel read the request from its transport.
+    pub received_at: Instant,
+}
+
+/// The time a request spent waiting in queues.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct QueueTime {
+    /// How long the request waited in the client's pending request buffer before being written
+    /// to the transport. Only the client knows it, so a server that reads the request from a
+    /// serialized transport sees zero.
+    pub client: Duration,
+    /// How long the request waited on the server between being read from the channel and the
+    /// start of its execution.
+    pub server: Duration,
 }
 
 #[cfg(feature = "serde1")]
@@ -48,7 +98,7 @@ mod absolute_to_relative_time {
         S: Serializer,
     {
         let deadline = deadline
-            .duration_since(SystemTime::now())
+            .duration_since(crate::clock::now())
             .unwrap_or(Duration::ZERO);
         deadline.serialize(serializer)
     }
@@ -58,7 +108,7 @@ mod absolute_to_relative_time {
         D: Deserializer<'de>,
     {
         let deadline = Duration::deserialize(deserializer)?;
-        Ok(SystemTime::now() + deadline)
+        Ok(crate::clock::now() + deadline)
     }
 
     #[cfg(test)]
@@ -89,7 +139,7 @@ mod absolute_to_relative_time {
 assert_impl_all!(Context: Send, Sync);
 
 fn ten_seconds_from_now() -> SystemTime {
-    SystemTime::now() + Duration::from_secs(10)
+    crate::clock::now() + Duration::from_secs(10)
 }
 
 /// Returns the context for the current request, or a default Context if no request is active.
@@ -97,9 +147,11 @@ pub fn current() -> Context {
     Context::current()
 }
 
+#[cfg(feature = "tracing")]
 #[derive(Clone)]
 struct Deadline(SystemTime);
 
+#[cfg(feature = "tracing")]
 impl Default for Deadline {
     fn default() -> Self {
         Self(ten_seconds_from_now())
@@ -108,17 +160,25 @@ impl Default for Deadline {
 
 impl Context {
     /// Returns the context for the current request, or a default Context if no request is active.
+    ///
+    /// The current request is tracked by its span, so without the `tracing` feature this always
+    /// returns a default Context.
     pub fn current() -> Self {
         let span = tracing::Span::current();
         Self {
             trace_context: trace::Context::try_from(&span)
                 .unwrap_or_else(|_| trace::Context::default()),
+            #[cfg(feature = "tracing")]
             deadline: span
                 .context()
                 .get::<Deadline>()
                 .cloned()
                 .unwrap_or_default()
                 .0,
+            #[cfg(not(feature = "tracing"))]
+            deadline: ten_seconds_from_now(),
+            queue_time: QueueTime::default(),
+            envelope: None,
         }
     }
 
@@ -128,6 +188,106 @@ impl Context {
     }
 }
 
+thread_local! {
+    static CANCELLATION: RefCell<Option<Cancellation>> = RefCell::new(None);
+}
+
+/// Signals that the request being handled by a server was canceled, either by the client or
+/// because its deadline passed.
+///
+/// Servers run each request handler [linked](Cancellation::link) to the request's cancellation.
+/// A client call made from a linked future fails with
+/// [`RpcError::Canceled`](crate::client::RpcError::Canceled) once the cancellation fires, and the
+/// client cancels the request it sent. Calls awaited directly by a handler are dropped, and so
+/// canceled, along with the handler anyway; linking matters for work the handler spawns:
+///
+/// ```rust
+/// use tarpc::context::{self, Cancellation};
+///
+/// # async fn handle(client: tarpc::client::Channel<String, String>) {
+/// // Within a request handler:
+/// if let Some(cancellation) = Cancellation::current() {
+///     tokio::spawn(cancellation.link(async move {
+///         client.call(context::current(), "Audit", "event".into()).await
+///     }));
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(CancellationToken);
+
+impl Cancellation {
+    /// Returns a new cancellation that has not fired.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cancellation of the future currently being polled, if it is
+    /// [linked](Cancellation::link) to one.
+    pub fn current() -> Option<Self> {
+        CANCELLATION.with(|current| current.borrow().clone())
+    }
+
+    /// Fires the cancellation.
+    pub fn cancel(&self) {
+        self.0.cancel()
+    }
+
+    /// Returns true if the cancellation has fired.
+    pub fn is_canceled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    /// Completes when the cancellation fires.
+    pub async fn canceled(&self) {
+        self.0.cancelled().await
+    }
+
+    /// Returns a guard that fires the cancellation when dropped, unless disarmed.
+    pub(crate) fn cancel_on_drop(self) -> DropGuard {
+        self.0.drop_guard()
+    }
+
+    /// Returns a future that runs `future`, making this the [current](Cancellation::current)
+    /// cancellation whenever `future` is polled.
+    pub fn link<F: Future>(self, future: F) -> Linked<F> {
+        Linked {
+            future,
+            cancellation: self,
+        }
+    }
+}
+
+/// A future linked to a [`Cancellation`]. Returned by [`Cancellation::link`].
+#[pin_project]
+#[derive(Debug)]
+pub struct Linked<F> {
+    #[pin]
+    future: F,
+    cancellation: Cancellation,
+}
+
+impl<F: Future> Future for Linked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<Cancellation>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CANCELLATION.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let this = self.project();
+        let previous =
+            CANCELLATION.with(|current| current.borrow_mut().replace(this.cancellation.clone()));
+        let _restore = Restore(previous);
+        this.future.poll(cx)
+    }
+}
+
 /// An extension trait for [`tracing::Span`] for propagating tarpc Contexts.
 pub(crate) trait SpanExt {
     /// Sets the given context on this span. Newly-created spans will be children of the given
@@ -135,6 +295,7 @@ pub(crate) trait SpanExt {
     fn set_context(&self, context: &Context);
 }
 
+#[cfg(feature = "tracing")]
 impl SpanExt for tracing::Span {
     fn set_context(&self, context: &Context) {
         self.set_parent(
@@ -150,3 +311,8 @@ impl SpanExt for tracing::Span {
         );
     }
 }
+
+#[cfg(not(feature = "tracing"))]
+impl SpanExt for tracing::Span {
+    fn set_context(&self, _: &Context) {}
+}
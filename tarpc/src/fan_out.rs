@@ -0,0 +1,278 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides helpers that fan a request out to several downstream calls within the request's
+//! deadline.
+//!
+//! A handler that calls several services and joins the calls has to decide how much of its own
+//! deadline each call gets. Passing its context to every call leaves it no time to respond once
+//! the calls run out their deadlines, and splitting the time by hand is easy to get wrong.
+//! [`join_with_budget`] and [`Budget::join`] run each branch of a fan-out with a context whose
+//! deadline is the branch's share of the time left, stop each branch once its share is used up,
+//! and return the results of the branches that finished alongside the errors of those that did
+//! not.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use tarpc::{context, fan_out::Budget};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let mut ctx = context::current();
+//! ctx.deadline = tarpc::clock::now() + Duration::from_secs(1);
+//! let joined = Budget::new()
+//!     .with_reserve(0.1)
+//!     .join(ctx, [10, 20, 30].map(|shard| {
+//!         move |_ctx: context::Context| async move {
+//!             // Calls the shard with `_ctx`, which leaves a tenth of the time for responding.
+//!             Ok(shard)
+//!         }
+//!     }))
+//!     .await;
+//! assert!(joined.is_complete());
+//! assert_eq!(joined.successes().sum::<u32>(), 60);
+//! # }
+//! ```
+
+use crate::util::tracing;
+use crate::{client::RpcError, clock, context, util::TimeUntil};
+use futures::prelude::*;
+use std::time::Duration;
+
+/// How the time left until a context's deadline is divided among the branches of a fan-out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    reserve: f64,
+    max_concurrent: Option<usize>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Budget {
+    /// Returns a budget that runs all branches at once and lets each run until the deadline.
+    pub fn new() -> Self {
+        Self {
+            reserve: 0.0,
+            max_concurrent: None,
+        }
+    }
+
+    /// Holds back `reserve`, a fraction of the time left, for the caller, so that it has time to
+    /// respond after the branches, e.g. with partial results.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reserve` is not between 0 and 1.
+    pub fn with_reserve(mut self, reserve: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&reserve),
+            "reserve must be between 0 and 1, got {reserve}"
+        );
+        self.reserve = reserve;
+        self
+    }
+
+    /// Runs at most `max_concurrent` branches at once, splitting the time left evenly among the
+    /// rounds of branches this takes. Each branch gets its round's share of the time from when it
+    /// starts, but never past the deadline.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent` is zero.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        assert!(max_concurrent > 0, "max_concurrent must be positive");
+        self.max_concurrent = Some(max_concurrent);
+        self
+    }
+
+    /// The fraction of the time left that is held back for the caller.
+    pub fn reserve(&self) -> f64 {
+        self.reserve
+    }
+
+    /// The maximum number of branches run at once, if limited.
+    pub fn max_concurrent(&self) -> Option<usize> {
+        self.max_concurrent
+    }
+
+    /// Runs each of `branches` with a copy of `ctx` whose deadline is the branch's share of the
+    /// time left until `ctx`'s deadline, and waits for them all.
+    ///
+    /// A branch that does not finish within its share is dropped, which cancels the calls it was
+    /// making, and fails with [`RpcError::DeadlineExceeded`]. So does a branch whose turn comes
+    /// after the time is used up; it is never run.
+    pub async fn join<T, F, Fut>(
+        &self,
+        ctx: context::Context,
+        branches: impl IntoIterator<Item = F>,
+    ) -> Joined<T>
+    where
+        F: FnOnce(context::Context) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        let branches = branches.into_iter().collect::<Vec<_>>();
+        let available = ctx.deadline.time_until().mul_f64(1.0 - self.reserve);
+        let deadline = clock::now() + available;
+        let max_concurrent = self
+            .max_concurrent
+            .unwrap_or(branches.len())
+            .clamp(1, branches.len().max(1));
+        let rounds = (branches.len() + max_concurrent - 1) / max_concurrent;
+        let share = available / u32::try_from(rounds.max(1)).unwrap_or(u32::MAX);
+
+        let mut results = stream::iter(branches.into_iter().enumerate())
+            .map(|(i, branch)| async move {
+                let mut ctx = ctx;
+                ctx.deadline = deadline.min(clock::now() + share);
+                let timeout = ctx.deadline.time_until();
+                if timeout == Duration::ZERO {
+                    tracing::info!(branch = i, "BranchOutOfBudget");
+                    return (i, Err(RpcError::DeadlineExceeded));
+                }
+                let result = tokio::time::timeout(timeout, branch(ctx))
+                    .await
+                    .unwrap_or_else(|_| {
+                        tracing::info!(branch = i, "BranchDeadlineExceeded");
+                        Err(RpcError::DeadlineExceeded)
+                    });
+                (i, result)
+            })
+            .buffer_unordered(max_concurrent)
+            .collect::<Vec<_>>()
+            .await;
+        results.sort_unstable_by_key(|(i, _)| *i);
+        Joined {
+            branches: results.into_iter().map(|(_, result)| result).collect(),
+        }
+    }
+}
+
+/// Runs each of `branches` with a copy of `ctx`, all at once, stopping each at `ctx`'s deadline,
+/// and waits for them all. See [`Budget::join`], which also holds back time for the caller and
+/// limits how many branches run at once.
+pub async fn join_with_budget<T, F, Fut>(
+    ctx: context::Context,
+    branches: impl IntoIterator<Item = F>,
+) -> Joined<T>
+where
+    F: FnOnce(context::Context) -> Fut,
+    Fut: Future<Output = Result<T, RpcError>>,
+{
+    Budget::new().join(ctx, branches).await
+}
+
+/// The outcome of each branch of a fan-out, in the order the branches were given.
+#[derive(Debug)]
+pub struct Joined<T> {
+    branches: Vec<Result<T, RpcError>>,
+}
+
+impl<T> Joined<T> {
+    /// Returns the outcome of each branch.
+    pub fn branches(&self) -> &[Result<T, RpcError>] {
+        &self.branches
+    }
+
+    /// Returns the outcome of each branch.
+    pub fn into_branches(self) -> Vec<Result<T, RpcError>> {
+        self.branches
+    }
+
+    /// Returns true iff every branch succeeded.
+    pub fn is_complete(&self) -> bool {
+        self.branches.iter().all(Result::is_ok)
+    }
+
+    /// Returns the results of the branches that succeeded, dropping the errors of the rest.
+    pub fn successes(self) -> impl Iterator<Item = T> {
+        self.branches.into_iter().filter_map(Result::ok)
+    }
+
+    /// Returns the results of all branches, or the error of the first branch that failed.
+    pub fn into_result(self) -> Result<Vec<T>, RpcError> {
+        self.branches.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join_with_budget, Budget};
+    use crate::{client::RpcError, clock, context};
+    use assert_matches::assert_matches;
+    use futures::future;
+    use std::time::Duration;
+
+    fn context_with_timeout(timeout: Duration) -> context::Context {
+        let mut ctx = context::current();
+        ctx.deadline = clock::now() + timeout;
+        ctx
+    }
+
+    /// Returns a branch that takes `delay` and returns the time its context gave it.
+    fn branch(
+        delay: Duration,
+    ) -> impl FnOnce(context::Context) -> future::BoxFuture<'static, Result<Duration, RpcError>>
+    {
+        move |ctx| {
+            Box::pin(async move {
+                let timeout = ctx
+                    .deadline
+                    .duration_since(clock::now())
+                    .unwrap_or_default();
+                tokio::time::sleep(delay).await;
+                Ok(timeout)
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shared_budget_stops_slow_branches_at_the_deadline() {
+        let _lock = clock::TEST_CLOCK_LOCK.lock().await;
+        let previous = clock::set_clock(clock::tokio_clock);
+        let ctx = context_with_timeout(Duration::from_secs(10));
+        let joined = join_with_budget(
+            ctx,
+            [Duration::from_secs(1), Duration::from_secs(20)].map(branch),
+        )
+        .await;
+        assert!(!joined.is_complete());
+        assert_matches!(joined.branches(), [Ok(timeout), Err(RpcError::DeadlineExceeded)]
+            if *timeout == Duration::from_secs(10));
+        assert_matches!(joined.into_result(), Err(RpcError::DeadlineExceeded));
+        clock::set_clock(previous);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn budget_holds_back_reserve_and_splits_time_among_rounds() {
+        let _lock = clock::TEST_CLOCK_LOCK.lock().await;
+        let previous = clock::set_clock(clock::tokio_clock);
+        let ctx = context_with_timeout(Duration::from_secs(10));
+        let joined = Budget::new()
+            .with_reserve(0.2)
+            .with_max_concurrent(2)
+            .join(
+                ctx,
+                [
+                    Duration::from_secs(1),
+                    Duration::from_secs(5),
+                    Duration::from_secs(1),
+                ]
+                .map(branch),
+            )
+            .await;
+        let branches = joined.into_branches();
+        // 8 seconds are split between two rounds.
+        assert_matches!(branches[0], Ok(timeout) if timeout == Duration::from_secs(4));
+        assert_matches!(branches[1], Err(RpcError::DeadlineExceeded));
+        // The third branch starts once the first finishes.
+        assert_matches!(branches[2], Ok(timeout) if timeout == Duration::from_secs(4));
+        clock::set_clock(previous);
+    }
+}
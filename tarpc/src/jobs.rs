@@ -0,0 +1,512 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides long-running jobs: work that a request handler starts and returns a [`JobId`] for,
+//! rather than holding the request open until the work is done.
+//!
+//! A handler [submits](Jobs::submit) a job to a server's [`Jobs`], which runs it in the
+//! background, and returns the job's ID to the client in its response. The job reports its
+//! [`Progress`] as it goes. Clients use a [`JobsClient`] to poll a job's [status](JobStatus),
+//! wait for it to finish, or cancel it, over a channel to the server's [`ServeJobs`].
+//!
+//! Cancellation is built on the usual request cancellation. A job runs
+//! [linked](Cancellation::link) to a cancellation of its own, which fires when the job is
+//! canceled, so the calls the job makes are canceled along with it. A client that
+//! [joins](JobsClient::join) a job cancels it by canceling the join request, e.g. by dropping the
+//! call or letting its deadline pass; a client that [waits](JobsClient::wait) for a job leaves it
+//! running.
+//!
+//! ```rust
+//! use tarpc::{
+//!     client::stub::local::Local,
+//!     context,
+//!     jobs::{JobStatus, Jobs, JobsClient, Progress},
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let jobs = Jobs::new();
+//! // Within a request handler:
+//! let id = jobs.submit(|progress| async move {
+//!     for done in 1..=3 {
+//!         progress.report(Progress::new(done, Some(3)));
+//!     }
+//!     "report.pdf".to_string()
+//! });
+//!
+//! let client = JobsClient::from(Local::new(jobs.serve()));
+//! assert_eq!(
+//!     client.wait(context::current(), id).await?,
+//!     Some(JobStatus::Completed("report.pdf".to_string()))
+//! );
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To serve jobs alongside other services in a [service group](crate::service_group), alias the
+//! job protocol's request and response types for the job output, e.g.
+//! `type ReportJobsRequest = JobsRequest;` and `type ReportJobsResponse = JobsResponse<String>;`,
+//! and add a `reports: ReportJobs` member to the group.
+
+use crate::util::tracing;
+use crate::{
+    client::{stub, RpcError},
+    context::{self, Cancellation},
+    server::Serve,
+    ServerError,
+};
+use futures::future::{self, Either};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Identifies a job among the jobs of a [`Jobs`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct JobId(u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// How far along a job is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Progress {
+    /// The units of work done.
+    pub done: u64,
+    /// The units of work in total, if known.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Returns progress of `done` units of work out of `total`.
+    pub fn new(done: u64, total: Option<u64>) -> Self {
+        Self { done, total }
+    }
+}
+
+/// The status of a job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum JobStatus<T> {
+    /// The job is running, and has made the given progress.
+    Running(Progress),
+    /// The job completed with the given output.
+    Completed(T),
+    /// The job was canceled, or panicked, before completing.
+    Aborted,
+}
+
+impl<T> JobStatus<T> {
+    /// Returns true if the job is no longer running.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::Running(_))
+    }
+}
+
+/// Reports the progress of a job. Passed to the job when it is [submitted](Jobs::submit).
+#[derive(Debug)]
+pub struct ProgressReporter {
+    progress: watch::Sender<Progress>,
+}
+
+impl ProgressReporter {
+    /// Reports that the job has made `progress`.
+    pub fn report(&self, progress: Progress) {
+        self.progress.send_replace(progress);
+    }
+
+    /// Returns the progress last reported.
+    pub fn progress(&self) -> Progress {
+        *self.progress.borrow()
+    }
+}
+
+#[derive(Debug)]
+struct Job<T> {
+    progress: watch::Receiver<Progress>,
+    outcome: watch::Receiver<Option<JobStatus<T>>>,
+    cancellation: Cancellation,
+}
+
+impl<T: Clone> Job<T> {
+    fn status(&self) -> JobStatus<T> {
+        match &*self.outcome.borrow() {
+            Some(outcome) => outcome.clone(),
+            // The job panicked, dropping its task.
+            None if self.outcome.has_changed().is_err() => JobStatus::Aborted,
+            None => JobStatus::Running(*self.progress.borrow()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    next_id: u64,
+    jobs: HashMap<JobId, Job<T>>,
+    /// Finished jobs, oldest first, whose statuses are kept until they are evicted.
+    finished: VecDeque<JobId>,
+    retention: usize,
+}
+
+/// The jobs of a server, which runs them in the background. Clones share the same jobs.
+///
+/// The statuses of finished jobs are kept until [`Jobs::with_retention`] more jobs finish, so
+/// that clients can collect their output.
+#[derive(Debug)]
+pub struct Jobs<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for Jobs<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Default for Jobs<T> {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                next_id: 0,
+                jobs: HashMap::new(),
+                finished: VecDeque::new(),
+                retention: 100,
+            })),
+        }
+    }
+}
+
+impl<T> Jobs<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// Returns an empty set of jobs, which keeps the statuses of the last 100 finished jobs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the statuses of the last `retention` finished jobs.
+    pub fn with_retention(self, retention: usize) -> Self {
+        self.state.lock().unwrap().retention = retention;
+        self
+    }
+
+    /// Runs the job returned by `job` in the background, returning its ID.
+    ///
+    /// The job is passed a reporter of its progress, and runs linked to a
+    /// [cancellation](Cancellation) that fires when the job is canceled.
+    pub fn submit<F, Fut>(&self, job: F) -> JobId
+    where
+        F: FnOnce(ProgressReporter) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let (progress_tx, progress) = watch::channel(Progress::default());
+        let (outcome_tx, outcome) = watch::channel(None);
+        let cancellation = Cancellation::new();
+        let job = cancellation.clone().link(job(ProgressReporter {
+            progress: progress_tx,
+        }));
+
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = JobId(state.next_id);
+            state.next_id += 1;
+            state.jobs.insert(
+                id,
+                Job {
+                    progress,
+                    outcome,
+                    cancellation: cancellation.clone(),
+                },
+            );
+            id
+        };
+
+        let state = self.state.clone();
+        crate::util::spawn(format_args!("tarpc::jobs::job({id})"), async move {
+            let aborted = cancellation.canceled();
+            futures::pin_mut!(job, aborted);
+            let outcome = match future::select(job, aborted).await {
+                Either::Left((output, _)) => JobStatus::Completed(output),
+                Either::Right(_) => JobStatus::Aborted,
+            };
+            outcome_tx.send_replace(Some(outcome));
+            let mut state = state.lock().unwrap();
+            state.finished.push_back(id);
+            while state.finished.len() > state.retention {
+                let evicted = state.finished.pop_front().unwrap();
+                state.jobs.remove(&evicted);
+            }
+        });
+        id
+    }
+
+    /// Returns the status of the job `id`, or `None` if there is no such job.
+    pub fn status(&self, id: JobId) -> Option<JobStatus<T>> {
+        Some(self.state.lock().unwrap().jobs.get(&id)?.status())
+    }
+
+    /// Waits for the job `id` to finish, returning its status, or `None` if there is no such job.
+    pub async fn wait(&self, id: JobId) -> Option<JobStatus<T>> {
+        let mut outcome = self.state.lock().unwrap().jobs.get(&id)?.outcome.clone();
+        loop {
+            if let Some(outcome) = &*outcome.borrow() {
+                return Some(outcome.clone());
+            }
+            if outcome.changed().await.is_err() {
+                return Some(JobStatus::Aborted);
+            }
+        }
+    }
+
+    /// Cancels the job `id`. Returns false if there is no such job, or it already finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.jobs.get(&id) {
+            Some(job) if job.outcome.borrow().is_none() => {
+                tracing::info!(%id, "CancelJob");
+                job.cancellation.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a serving function that lets clients poll, wait for, and cancel the jobs.
+    pub fn serve(&self) -> ServeJobs<T> {
+        ServeJobs { jobs: self.clone() }
+    }
+}
+
+/// The request sent over the wire from a [`JobsClient`] to a [`ServeJobs`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[allow(missing_docs)]
+pub enum JobsRequest {
+    Status { id: JobId },
+    Wait { id: JobId },
+    Join { id: JobId },
+    Cancel { id: JobId },
+}
+
+/// The response sent over the wire from a [`ServeJobs`] to a [`JobsClient`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[allow(missing_docs)]
+pub enum JobsResponse<T> {
+    Status(Option<JobStatus<T>>),
+    Wait(Option<JobStatus<T>>),
+    Join(Option<JobStatus<T>>),
+    Cancel(bool),
+}
+
+/// A serving function that lets clients poll, wait for, and cancel [`Jobs`]. Created by
+/// [`Jobs::serve`].
+#[derive(Clone, Debug)]
+pub struct ServeJobs<T> {
+    jobs: Jobs<T>,
+}
+
+impl<T> Serve for ServeJobs<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    type Req = JobsRequest;
+    type Resp = JobsResponse<T>;
+
+    fn method(&self, req: &JobsRequest) -> Option<&'static str> {
+        Some(match req {
+            JobsRequest::Status { .. } => "Jobs.status",
+            JobsRequest::Wait { .. } => "Jobs.wait",
+            JobsRequest::Join { .. } => "Jobs.join",
+            JobsRequest::Cancel { .. } => "Jobs.cancel",
+        })
+    }
+
+    async fn serve(
+        self,
+        _: context::Context,
+        req: JobsRequest,
+    ) -> Result<JobsResponse<T>, ServerError> {
+        Ok(match req {
+            JobsRequest::Status { id } => JobsResponse::Status(self.jobs.status(id)),
+            JobsRequest::Wait { id } => JobsResponse::Wait(self.jobs.wait(id).await),
+            JobsRequest::Join { id } => {
+                // Dropping the handler, as when the request is canceled, cancels the job.
+                struct CancelOnDrop<'a, T: Clone + Send + Sync + 'static>(&'a Jobs<T>, JobId);
+
+                impl<'a, T: Clone + Send + Sync + 'static> Drop for CancelOnDrop<'a, T> {
+                    fn drop(&mut self) {
+                        self.0.cancel(self.1);
+                    }
+                }
+
+                let _cancel_on_drop = CancelOnDrop(&self.jobs, id);
+                JobsResponse::Join(self.jobs.wait(id).await)
+            }
+            JobsRequest::Cancel { id } => JobsResponse::Cancel(self.jobs.cancel(id)),
+        })
+    }
+}
+
+/// The client stub that polls, waits for, and cancels the jobs of a server.
+#[derive(Clone, Debug)]
+pub struct JobsClient<Stub>(Stub);
+
+impl<Stub> From<Stub> for JobsClient<Stub> {
+    fn from(stub: Stub) -> Self {
+        JobsClient(stub)
+    }
+}
+
+impl<Stub, T> JobsClient<Stub>
+where
+    Stub: stub::Stub<Req = JobsRequest, Resp = JobsResponse<T>>,
+{
+    /// Returns the status of the job `id`, or `None` if there is no such job.
+    pub async fn status(
+        &self,
+        ctx: context::Context,
+        id: JobId,
+    ) -> Result<Option<JobStatus<T>>, RpcError> {
+        match self
+            .0
+            .call(ctx, "Jobs.status", JobsRequest::Status { id })
+            .await?
+        {
+            JobsResponse::Status(status) => Ok(status),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Waits for the job `id` to finish, returning its status, or `None` if there is no such job.
+    /// Canceling the call leaves the job running.
+    pub async fn wait(
+        &self,
+        ctx: context::Context,
+        id: JobId,
+    ) -> Result<Option<JobStatus<T>>, RpcError> {
+        match self
+            .0
+            .call(ctx, "Jobs.wait", JobsRequest::Wait { id })
+            .await?
+        {
+            JobsResponse::Wait(status) => Ok(status),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Waits for the job `id` to finish, like [`wait`](Self::wait), but cancels the job if the
+    /// call is canceled, e.g. because it is dropped or its deadline passes.
+    pub async fn join(
+        &self,
+        ctx: context::Context,
+        id: JobId,
+    ) -> Result<Option<JobStatus<T>>, RpcError> {
+        match self
+            .0
+            .call(ctx, "Jobs.join", JobsRequest::Join { id })
+            .await?
+        {
+            JobsResponse::Join(status) => Ok(status),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Cancels the job `id`. Returns false if there is no such job, or it already finished.
+    pub async fn cancel(&self, ctx: context::Context, id: JobId) -> Result<bool, RpcError> {
+        match self
+            .0
+            .call(ctx, "Jobs.cancel", JobsRequest::Cancel { id })
+            .await?
+        {
+            JobsResponse::Cancel(canceled) => Ok(canceled),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobStatus, Jobs, JobsClient, Progress};
+    use crate::{client::stub::local::Local, context};
+    use assert_matches::assert_matches;
+    use futures::channel::oneshot;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn reports_progress_then_output() {
+        let jobs = Jobs::new();
+        let (finish_tx, finish) = oneshot::channel::<()>();
+        let id = jobs.submit(|progress| async move {
+            progress.report(Progress::new(1, Some(2)));
+            finish.await.unwrap();
+            7
+        });
+        let client = JobsClient::from(Local::new(jobs.serve()));
+
+        tokio::task::yield_now().await;
+        assert_eq!(
+            client.status(context::current(), id).await.unwrap(),
+            Some(JobStatus::Running(Progress::new(1, Some(2))))
+        );
+        finish_tx.send(()).unwrap();
+        assert_eq!(
+            client.wait(context::current(), id).await.unwrap(),
+            Some(JobStatus::Completed(7))
+        );
+        assert!(!client.cancel(context::current(), id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancel_fires_the_job_cancellation() {
+        let jobs = Jobs::<()>::new();
+        let (linked_tx, linked) = oneshot::channel();
+        let id = jobs.submit(|_| async move {
+            linked_tx.send(context::Cancellation::current()).unwrap();
+            futures::future::pending().await
+        });
+        let cancellation = linked.await.unwrap().unwrap();
+        let client = JobsClient::from(Local::new(jobs.serve()));
+
+        assert!(client.cancel(context::current(), id).await.unwrap());
+        assert!(cancellation.is_canceled());
+        assert_eq!(jobs.wait(id).await, Some(JobStatus::Aborted));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn canceled_join_cancels_the_job() {
+        let jobs = Jobs::<()>::new();
+        let id = jobs.submit(|_| futures::future::pending());
+        let client = JobsClient::from(Local::new(jobs.serve()));
+
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(1);
+        assert_matches!(client.join(ctx, id).await, Err(_));
+        assert_eq!(jobs.wait(id).await, Some(JobStatus::Aborted));
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_finished_jobs() {
+        let jobs = Jobs::new().with_retention(1);
+        let first = jobs.submit(|_| async { 1 });
+        assert_eq!(jobs.wait(first).await, Some(JobStatus::Completed(1)));
+        let second = jobs.submit(|_| async { 2 });
+        assert_eq!(jobs.wait(second).await, Some(JobStatus::Completed(2)));
+        assert_eq!(jobs.status(first), None);
+        assert_eq!(jobs.status(second), Some(JobStatus::Completed(2)));
+    }
+}
@@ -202,12 +202,30 @@
 
 #![deny(missing_docs)]
 #![allow(clippy::type_complexity)]
+// Without the `tracing` feature, events compile to nothing, leaving unused the values that only
+// they read, and leaving behind code that only looks redundant.
+#![cfg_attr(
+    not(feature = "tracing"),
+    allow(
+        unused_variables,
+        unused_imports,
+        clippy::single_match,
+        clippy::drop_non_drop,
+        clippy::if_same_then_else
+    )
+)]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+// Lets the code that the service macro generates refer to this crate from within it.
+extern crate self as tarpc;
+
 #[cfg(feature = "serde1")]
 #[doc(hidden)]
 pub use serde;
 
+#[doc(hidden)]
+pub use futures;
+
 #[cfg(feature = "serde-transport")]
 pub use {tokio_serde, tokio_util};
 
@@ -236,31 +254,124 @@ pub use tarpc_plugins::derive_serde;
 /// will then be attached to the generated service traits'
 /// corresponding `fn`s, as well as to the client stubs' RPCs.
 ///
+/// An rpc that returns `impl Stream<Item = T>` is a streaming rpc: the server sends each item as
+/// a separate response to the one request, and the client stub's fn returns a stream of the
+/// items, which requires a stub that implements
+/// [`StreamStub`](client::stub::StreamStub), such as a [`Channel`](client::Channel):
+///
+/// ```
+/// # use futures::Stream;
+/// #[tarpc::service]
+/// trait Service {
+/// /// Count down to zero
+/// async fn countdown(from: u32) -> impl Stream<Item = u32>;
+/// }
+/// ```
+///
+/// Likewise, an rpc that takes one arg of type `impl Stream<Item = T>` is a client-streaming rpc:
+/// the client stub sends each item of the stream after the request, under the same request ID,
+/// and the server hands the items to the rpc as a stream.
+///
+/// ```
+/// # use futures::Stream;
+/// #[tarpc::service]
+/// trait Service {
+/// /// Sums the numbers
+/// async fn sum(numbers: impl Stream<Item = u32>) -> u64;
+/// }
+/// ```
+///
+/// An rpc that both takes and returns a stream is a duplex rpc: the client and server exchange
+/// streams under the one request, and each may end its stream while the other's continues. The
+/// client stub's fn returns the stream of responses, which sends the client's items as it is
+/// polled; see [`Channel::call_duplex`](client::Channel::call_duplex).
+///
+/// ```
+/// # use futures::Stream;
+/// #[tarpc::service]
+/// trait Service {
+/// /// Echoes each line back in upper case
+/// async fn shout(lines: impl Stream<Item = String>) -> impl Stream<Item = String>;
+/// }
+/// ```
+///
 /// The following items are expanded in the enclosing module:
 ///
 /// * `trait Service` -- defines the RPC service.
 ///   * `fn serve` -- turns a service impl into a request handler.
 /// * `Client` -- a client stub with a fn for each RPC.
 ///   * `fn new_stub` -- creates a new Client stub.
+///   * `fn sink_for` -- returns a [`CallSink`](client::sink::CallSink) of calls to one RPC.
+/// * `mod service_methods` -- a marker type for each RPC that neither takes nor returns a stream,
+///   to pass to `sink_for`.
 pub use tarpc_plugins::service;
 
-pub(crate) mod cancellations;
+/// Composes several services into one, so that a single
+/// [`BaseChannel`](server::BaseChannel) can serve them all over one connection.
+///
+/// A group is declared like a struct with a field per service, of the service trait's name:
+///
+/// ```
+/// # #[tarpc::service] trait Hello { async fn hello(name: String) -> String; }
+/// # #[tarpc::service] trait Add { async fn add(x: i32, y: i32) -> i32; }
+/// tarpc::service_group! {
+///     /// The services of the API server.
+///     pub struct Services {
+///         hello: Hello,
+///         add: Add,
+///     }
+/// }
+/// ```
+///
+/// The following items are expanded in the enclosing module:
+///
+/// * `struct Services<S0, S1>` -- holds a serving function per service, e.g. `Hello::serve(..)`,
+///   and implements [`Serve`](server::Serve) by dispatching each request to its service.
+/// * `ServicesRequest` and `ServicesResponse` -- a variant per service, wrapping the service's
+///   request or response, with conversions to and from them.
+///
+/// Clients of the group's services share a connection through
+/// [`Member`](client::stub::member::Member) stubs. Streaming methods are not supported by service
+/// groups.
+pub use tarpc_plugins::service_group;
+
+pub mod backoff;
+pub mod cancellations;
 pub mod client;
+pub mod clock;
+pub mod conformance;
 pub mod context;
+pub mod fan_out;
+#[cfg(feature = "jobs")]
+#[cfg_attr(docsrs, doc(cfg(feature = "jobs")))]
+pub mod jobs;
+#[cfg(feature = "pubsub")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pubsub")))]
+pub mod pubsub;
+pub mod qos;
+pub mod report;
+pub mod schema;
 pub mod server;
 pub mod transport;
 pub(crate) mod util;
+#[cfg(feature = "serde1")]
+mod wire;
 
 pub use crate::transport::sealed::Transport;
+pub use fan_out::join_with_budget;
 
-use anyhow::Context as _;
-use futures::task::*;
 use std::sync::Arc;
-use std::{error::Error, fmt::Display, io, time::SystemTime};
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime},
+};
 
 /// A message from a client to a server.
+///
+/// Messages are serialized as tarpc 0.34 serializes them, unless they use features added since,
+/// which older peers don't support.
 #[derive(Debug)]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum ClientMessage<T> {
     /// A request initiated by a user. The server responds to a request by invoking a
@@ -277,11 +388,54 @@ pub enum ClientMessage<T> {
     Cancel {
         /// The trace context associates the message with a specific chain of causally-related actions,
         /// possibly orchestrated across many distributed systems.
-        #[cfg_attr(feature = "serde1", serde(default))]
         trace_context: trace::Context,
         /// The ID of the request to cancel.
         request_id: u64,
     },
+    /// Tells the server why the client is closing the connection, sent by
+    /// [`Channel::close`](client::Channel::close) just before the client closes its write half.
+    /// The server records the reason, which
+    /// [`BaseChannel::peer_close_reason`](server::BaseChannel::peer_close_reason) returns.
+    Close {
+        /// Why the client is closing the connection.
+        reason: CloseReason,
+    },
+    /// An item of the stream of a [streamed](Request::streamed) request, sent by
+    /// [`Channel::call_with_stream`](client::Channel::call_with_stream) and
+    /// [`Channel::call_duplex`](client::Channel::call_duplex) after the request. The server hands
+    /// the item to the request's handler.
+    Item {
+        /// The ID of the request the item belongs to.
+        request_id: u64,
+        /// The item, or `None` once the stream has ended.
+        item: Option<T>,
+    },
+}
+
+/// Why a peer closed a connection, as carried by [`ClientMessage::Close`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The peer is shutting down in an orderly way.
+    Shutdown,
+    /// The peer received messages that violate the protocol.
+    ProtocolError,
+    /// The peer could not authenticate, or is not authorized.
+    AuthFailure,
+    /// The connection was idle for too long.
+    Idle,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Shutdown => write!(f, "shutdown"),
+            CloseReason::ProtocolError => write!(f, "protocol error"),
+            CloseReason::AuthFailure => write!(f, "authentication failure"),
+            CloseReason::Idle => write!(f, "idle"),
+        }
+    }
 }
 
 /// A request from a client to a server.
@@ -295,37 +449,52 @@ pub struct Request<T> {
     pub id: u64,
     /// The request body.
     pub message: T,
+    /// Whether the client expects no response, as for requests sent with
+    /// [`Channel::notify`](client::Channel::notify). The server handles a one-way request like any
+    /// other, but discards the response instead of sending it. Servers on tarpc 0.34 reject
+    /// one-way requests.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub one_way: bool,
+    /// Whether the client streams items to the request after sending it, as
+    /// [`ClientMessage::Item`]s, as it does for requests sent with
+    /// [`Channel::call_with_stream`](client::Channel::call_with_stream) and
+    /// [`Channel::call_duplex`](client::Channel::call_duplex). Servers on tarpc 0.34 reject
+    /// streamed requests.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub streamed: bool,
 }
 
 /// A response from a server to a client.
+///
+/// Responses are serialized as tarpc 0.34 serializes them, unless [more](Response::more) follow.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 #[non_exhaustive]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Response<T> {
     /// The ID of the request being responded to.
     pub request_id: u64,
     /// The response body, or an error if the request failed.
     pub message: Result<T, ServerError>,
+    /// Whether more responses to the request follow, as they do for all but the last response of
+    /// a [streaming](client::Channel::call_stream) request. The request stays in flight until a
+    /// response without this flag is sent. Clients on tarpc 0.34 reject responses with this flag.
+    pub more: bool,
 }
 
 /// An error indicating the server aborted the request early, e.g., due to request throttling.
+///
+/// The kind of the error is serialized as a number. [Resource exhausted](Self::resource_exhausted)
+/// errors are serialized with a number of their own, which peers on tarpc 0.34 read as
+/// [`Other`](io::ErrorKind::Other).
 #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Hash)]
 #[error("{kind:?}: {detail}")]
 #[non_exhaustive]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServerError {
-    #[cfg_attr(
-        feature = "serde1",
-        serde(serialize_with = "util::serde::serialize_io_error_kind_as_u32")
-    )]
-    #[cfg_attr(
-        feature = "serde1",
-        serde(deserialize_with = "util::serde::deserialize_io_error_kind_from_u32")
-    )]
     /// The type of error that occurred to fail the request.
     pub kind: io::ErrorKind,
     /// A message describing more detail about the error that occurred.
     pub detail: String,
+    /// Whether the server rejected the request, without handling it, for lack of capacity.
+    resource_exhausted: bool,
 }
 
 /// Critical errors that result in a Channel disconnecting.
@@ -349,12 +518,59 @@ where
     /// Could not close the write end of the transport.
     #[error("could not close the write end of the transport")]
     Close(#[source] E),
+    /// The transport made no progress flushing writes for the given duration, e.g. because the
+    /// peer stopped reading.
+    #[error("writes to the transport stalled")]
+    WriteStalled(Duration),
+    /// The transport produced no frames for the given duration while requests were in flight,
+    /// e.g. because the connection was half-open.
+    #[error("the transport was idle while requests were in flight")]
+    ReadIdle(Duration),
+}
+
+/// An error indicating that a [client](client::Config) or [server](server::Config) config has an
+/// invalid setting.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Hash)]
+#[error("invalid config: `{field}` {reason}")]
+#[non_exhaustive]
+pub struct InvalidConfig {
+    /// The name of the invalid setting.
+    pub field: &'static str,
+    /// Why the setting is invalid.
+    pub reason: &'static str,
+}
+
+impl InvalidConfig {
+    pub(crate) fn new(field: &'static str, reason: &'static str) -> Self {
+        Self { field, reason }
+    }
 }
 
 impl ServerError {
     /// Returns a new server error with `kind` and `detail`.
     pub fn new(kind: io::ErrorKind, detail: String) -> ServerError {
-        Self { kind, detail }
+        Self {
+            kind,
+            detail,
+            resource_exhausted: false,
+        }
+    }
+
+    /// Returns an error rejecting a request because the server is out of capacity for it, e.g.
+    /// because too many requests are in flight. The request was not handled, so the client can
+    /// retry it once load subsides. The error is of kind [`WouldBlock`](io::ErrorKind::WouldBlock),
+    /// and is marked as such, so that it can be told apart from other `WouldBlock` errors.
+    pub fn resource_exhausted(detail: String) -> ServerError {
+        Self {
+            resource_exhausted: true,
+            ..Self::new(io::ErrorKind::WouldBlock, detail)
+        }
+    }
+
+    /// Returns true iff the server rejected the request because it was out of capacity for it,
+    /// i.e. the error was created by [`ServerError::resource_exhausted`].
+    pub fn is_resource_exhausted(&self) -> bool {
+        self.resource_exhausted
     }
 }
 
@@ -364,34 +580,3 @@ impl<T> Request<T> {
         &self.context.deadline
     }
 }
-
-pub(crate) trait PollContext<T> {
-    fn context<C>(self, context: C) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static;
-
-    fn with_context<C, F>(self, f: F) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-        F: FnOnce() -> C;
-}
-
-impl<T, E> PollContext<T> for Poll<Option<Result<T, E>>>
-where
-    E: Error + Send + Sync + 'static,
-{
-    fn context<C>(self, context: C) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-    {
-        self.map(|o| o.map(|r| r.context(context)))
-    }
-
-    fn with_context<C, F>(self, f: F) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-        F: FnOnce() -> C,
-    {
-        self.map(|o| o.map(|r| r.with_context(f)))
-    }
-}
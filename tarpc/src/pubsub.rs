@@ -0,0 +1,883 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides publish/subscribe messaging over tarpc: a [`Server`] that broadcasts the messages
+//! published to a topic to the topic's subscribers, and the [`Publisher`] and [`Subscriber`]
+//! clients that publish and subscribe.
+//!
+//! Clients [`connect`] to a server over one [bidirectional](crate::transport::bidi) transport:
+//! they call the server's [`PubSub`](protocol::PubSub) service to publish, subscribe, and
+//! unsubscribe, and the server calls back their [`Subscriber`](protocol::Subscriber) service to
+//! deliver messages. Subscribers acknowledge a message by accepting it.
+//!
+//! - Servers scale horizontally by sharing a [`Broker`]. A server hands each published message to
+//!   the broker, and relays the messages of every topic that its own subscribers are interested
+//!   in from the broker to those subscribers, so publishers and subscribers can connect to any
+//!   server. [`LocalBroker`] connects servers in the same process; backends over Redis pub/sub or
+//!   Kafka implement the same trait.
+//!
+//! - Each topic is delivered either at most once, or at least once, in which case unacknowledged
+//!   messages are redelivered with backoff and finally published to a dead-letter topic; see
+//!   [`Delivery`].
+//!
+//! - Durable subscriptions have a name under which the server tracks the last message they
+//!   acknowledged. When a durable subscription is renewed, the server first replays what it
+//!   missed from a bounded buffer of recent messages.
+//!
+//! - A server unsubscribes a client from all its topics once the client's connection closes.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{client, pubsub, transport};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let server = pubsub::Server::new(pubsub::LocalBroker::default());
+//!
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//! tokio::spawn(server.clone().serve_connection(server_transport));
+//! let (publisher, subscriber) = pubsub::connect(client::Config::default(), client_transport);
+//!
+//! let mut news = subscriber.subscribe("news").await?;
+//! publisher.publish("news", "hello").await?;
+//! assert_eq!(news.next().await.as_deref(), Some("hello"));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing::info;
+use crate::{
+    backoff::Backoff,
+    client::{self, RpcError},
+    context,
+    server::{BaseChannel, Channel},
+    transport::bidi,
+    Transport,
+};
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle},
+    prelude::*,
+    stream::BoxStream,
+};
+use protocol::{
+    PubSub as _, PubSubClient, PubSubRequest, PubSubResponse, Subscriber as _, SubscriberClient,
+    SubscriberRequest, SubscriberResponse,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The services that pubsub servers and their clients call on each other.
+pub mod protocol {
+    /// The service of a pubsub server, which its clients call.
+    #[tarpc::service]
+    pub trait PubSub {
+        /// Publishes `message` to the subscribers of `topic`.
+        async fn publish(topic: String, message: String);
+        /// Subscribes the caller to `topic`, returning once the server delivers the topic's
+        /// messages to the caller. A subscription with a `durable_name` is first sent the
+        /// messages that the last subscription with that name missed.
+        async fn subscribe(topic: String, durable_name: Option<String>);
+        /// Unsubscribes the caller from `topic`.
+        async fn unsubscribe(topic: String);
+    }
+
+    /// The service of a pubsub client, which its server calls to deliver messages.
+    #[tarpc::service]
+    pub trait Subscriber {
+        /// Delivers a message published to `topic`. Acknowledges the message by returning `Ok`.
+        async fn receive(topic: String, message: String) -> Result<(), String>;
+    }
+}
+
+/// Carries published messages between pubsub servers, so that a message published to any server
+/// reaches the subscribers of every server.
+///
+/// A backend over Redis pub/sub maps `publish` to `PUBLISH` and `subscribe` to `SUBSCRIBE`, with
+/// one channel per topic. A backend over Kafka produces to, and consumes from, a Kafka topic of
+/// the same name, with a consumer group per server so that every server receives every message.
+pub trait Broker: Clone + Send + Sync + 'static {
+    /// Hands a message to every server subscribed to the topic.
+    fn publish(
+        &self,
+        topic: String,
+        message: String,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Returns the messages published to the topic, by any server, from now on.
+    fn subscribe(
+        &self,
+        topic: String,
+    ) -> impl Future<Output = io::Result<BoxStream<'static, String>>> + Send;
+}
+
+/// A broker for servers that run in the same process.
+#[derive(Clone, Debug, Default)]
+pub struct LocalBroker {
+    topics: Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<String>>>>>,
+}
+
+impl Broker for LocalBroker {
+    async fn publish(&self, topic: String, message: String) -> io::Result<()> {
+        if let Some(subscribers) = self.topics.lock().unwrap().get_mut(&topic) {
+            subscribers.retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: String) -> io::Result<BoxStream<'static, String>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(topic)
+            .or_default()
+            .push(tx);
+        Ok(rx.boxed())
+    }
+}
+
+/// How a server delivers the messages of a topic to its subscribers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// Each message is sent once. Subscribers that fail to acknowledge it miss it.
+    #[default]
+    AtMostOnce,
+    /// Each message is resent, with exponential backoff, until the subscriber acknowledges it.
+    /// After `max_attempts` failed attempts, the message is published to the topic's
+    /// [dead-letter topic](dead_letter_topic) instead. Later messages of the topic wait while a
+    /// message is redelivered, so that subscribers receive them in order.
+    AtLeastOnce {
+        /// The number of times a message is sent before it is dead-lettered.
+        max_attempts: u32,
+    },
+}
+
+/// Returns the name of the topic that receives the messages of `topic` that subscribers
+/// repeatedly failed to accept.
+pub fn dead_letter_topic(topic: &str) -> String {
+    format!("{topic}.dead-letters")
+}
+
+/// How long the redeliveries of an unacknowledged message wait.
+fn redelivery_backoff() -> Backoff {
+    Backoff::exponential(Duration::from_millis(100), Duration::from_secs(10)).with_jitter()
+}
+
+/// The recent messages of a topic, numbered in the order this server received them.
+#[derive(Debug, Default)]
+struct ReplayBuffer {
+    next_seq: u64,
+    messages: VecDeque<(u64, String)>,
+}
+
+impl ReplayBuffer {
+    /// Records a message, returning its sequence number.
+    fn push(&mut self, message: String, capacity: usize) -> u64 {
+        if self.messages.len() == capacity {
+            self.messages.pop_front();
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if capacity > 0 {
+            self.messages.push_back((seq, message));
+        }
+        seq
+    }
+
+    /// Returns the buffered messages numbered `cursor` or higher.
+    fn since(&self, topic: &str, cursor: u64) -> Vec<(u64, String)> {
+        if let Some(&(oldest, _)) = self.messages.front() {
+            if oldest > cursor {
+                info!(%topic, lost = oldest - cursor, "ReplayBufferOverflowed");
+            }
+        }
+        self.messages
+            .iter()
+            .filter(|(seq, _)| *seq >= cursor)
+            .cloned()
+            .collect()
+    }
+}
+
+/// A client connected to a server.
+struct Connection {
+    client: SubscriberClient,
+    /// The topics the client subscribed to, with the names of durable subscriptions.
+    topics: HashMap<String, Option<String>>,
+}
+
+/// A pubsub server, which serves the connections of [`Publisher`]s and [`Subscriber`]s. Clones
+/// share the same subscriptions.
+#[derive(Clone)]
+pub struct Server<B> {
+    connections: Arc<Mutex<HashMap<u64, Connection>>>,
+    next_connection: Arc<AtomicU64>,
+    /// The clients subscribed to each topic, by connection.
+    subscriptions: Arc<RwLock<HashMap<String, HashMap<u64, SubscriberClient>>>>,
+    /// The tasks relaying messages from the broker, by topic.
+    relays: Arc<Mutex<HashMap<String, AbortHandle>>>,
+    /// Recent messages by topic. Locked while subscribers join a topic, so that they don't miss
+    /// messages broadcast in the meantime.
+    history: Arc<Mutex<HashMap<String, ReplayBuffer>>>,
+    /// For each durable subscription, the sequence number of the next message it has yet to
+    /// acknowledge, by topic.
+    cursors: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+    /// The delivery policies of topics that aren't delivered at most once.
+    delivery: Arc<HashMap<String, Delivery>>,
+    replay_capacity: usize,
+    broker: B,
+}
+
+impl<B: Broker> Server<B> {
+    /// Returns a server that exchanges published messages with other servers through `broker`.
+    pub fn new(broker: B) -> Self {
+        Server {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            next_connection: Arc::new(AtomicU64::new(0)),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            relays: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(HashMap::new())),
+            cursors: Arc::new(Mutex::new(HashMap::new())),
+            delivery: Arc::new(HashMap::new()),
+            replay_capacity: 100,
+            broker,
+        }
+    }
+
+    /// Sets how the messages of `topic` are delivered. Topics are delivered
+    /// [at most once](Delivery::AtMostOnce) by default.
+    pub fn with_delivery(mut self, topic: impl Into<String>, delivery: Delivery) -> Self {
+        Arc::make_mut(&mut self.delivery).insert(topic.into(), delivery);
+        self
+    }
+
+    /// Keeps the `capacity` most recent messages of each topic for durable subscriptions to
+    /// catch up on, instead of 100.
+    pub fn with_replay_capacity(mut self, capacity: usize) -> Self {
+        self.replay_capacity = capacity;
+        self
+    }
+
+    /// Returns the topics that clients of this server are subscribed to.
+    pub fn topics(&self) -> Vec<String> {
+        self.subscriptions.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the number of clients of this server subscribed to `topic`.
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.subscriptions
+            .read()
+            .unwrap()
+            .get(topic)
+            .map_or(0, HashMap::len)
+    }
+
+    /// Serves a client connected over `transport`, until the connection closes. The client is
+    /// then unsubscribed from all its topics.
+    pub async fn serve_connection<T>(self, transport: T)
+    where
+        T: Transport<
+                bidi::Message<SubscriberRequest, PubSubResponse>,
+                bidi::Message<PubSubRequest, SubscriberResponse>,
+            > + Send
+            + 'static,
+    {
+        let bidi = bidi::new(bidi::Config::default(), transport);
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let client::NewClient { client, dispatch } =
+            SubscriberClient::new(client::Config::default(), bidi.client);
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                client,
+                topics: HashMap::new(),
+            },
+        );
+        let handler = ConnectionHandler {
+            server: self.clone(),
+            id,
+        };
+        let serving = BaseChannel::with_defaults(bidi.server)
+            .execute(handler.serve())
+            .for_each(|response| async {
+                crate::util::spawn(
+                    format_args!(
+                        "tarpc::pubsub::server::request {} {}",
+                        response.request_id(),
+                        response.trace_id()
+                    ),
+                    response,
+                );
+            });
+        let driver = bidi.driver.unwrap_or_else(|e| {
+            info!("Connection broken: {}", crate::util::print_err(&e));
+        });
+        let dispatch = dispatch.unwrap_or_else(|e| {
+            info!("Connection broken: {}", crate::util::print_err(&e));
+        });
+        future::join3(driver, dispatch, serving).await;
+        self.disconnect(id);
+    }
+
+    async fn subscribe(&self, id: u64, topic: String, durable_name: Option<String>) {
+        let client = {
+            let mut connections = self.connections.lock().unwrap();
+            let connection = match connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return,
+            };
+            connection
+                .topics
+                .insert(topic.clone(), durable_name.clone());
+            connection.client.clone()
+        };
+        info!(%topic, ?durable_name, "Subscribe");
+        if !self.relays.lock().unwrap().contains_key(&topic) {
+            self.start_relay(topic.clone()).await;
+        }
+        match &durable_name {
+            Some(name) => self.replay(name, id, &client, topic).await,
+            None => {
+                let _history = self.history.lock().unwrap();
+                self.subscriptions
+                    .write()
+                    .unwrap()
+                    .entry(topic)
+                    .or_default()
+                    .insert(id, client);
+            }
+        }
+    }
+
+    fn unsubscribe(&self, id: u64, topic: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.topics.remove(topic);
+        }
+        info!(%topic, "Unsubscribe");
+        self.remove_subscriber(id, topic);
+    }
+
+    /// Unsubscribes a closed connection from all its topics.
+    fn disconnect(&self, id: u64) {
+        if let Some(connection) = self.connections.lock().unwrap().remove(&id) {
+            info!(topics = ?connection.topics.keys(), "Disconnect");
+            for topic in connection.topics.keys() {
+                self.remove_subscriber(id, topic);
+            }
+        }
+    }
+
+    /// Stops delivering the messages of `topic` to a connection, and stops relaying them from
+    /// the broker if no one else needs them.
+    fn remove_subscriber(&self, id: u64, topic: &str) {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let subscribers = match subscriptions.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+        subscribers.remove(&id);
+        if !subscribers.is_empty() {
+            return;
+        }
+        subscriptions.remove(topic);
+        // Durable subscriptions expect the topic's messages to be kept for them.
+        let durable = self
+            .cursors
+            .lock()
+            .unwrap()
+            .values()
+            .any(|cursors| cursors.contains_key(topic));
+        if !durable {
+            if let Some(relay) = self.relays.lock().unwrap().remove(topic) {
+                relay.abort();
+            }
+        }
+    }
+
+    /// Sends a durable subscription the messages it has yet to acknowledge, then adds it to the
+    /// topic's subscribers once it has caught up.
+    async fn replay(&self, name: &str, id: u64, client: &SubscriberClient, topic: String) {
+        loop {
+            let backlog = {
+                let history = self.history.lock().unwrap();
+                let buffer = history.get(&topic);
+                let mut cursors = self.cursors.lock().unwrap();
+                let cursor = *cursors
+                    .entry(name.to_string())
+                    .or_default()
+                    .entry(topic.clone())
+                    .or_insert_with(|| buffer.map_or(0, |buffer| buffer.next_seq));
+                let backlog = buffer.map_or(vec![], |buffer| buffer.since(&topic, cursor));
+                if backlog.is_empty() {
+                    self.subscriptions
+                        .write()
+                        .unwrap()
+                        .entry(topic)
+                        .or_default()
+                        .insert(id, client.clone());
+                    return;
+                }
+                backlog
+            };
+            info!(%name, %topic, messages = backlog.len(), "Replay");
+            for (seq, message) in backlog {
+                if let Err(e) = send(client, &topic, &message).await {
+                    info!(%name, %topic, error = %e, "ReplayFailed");
+                    return;
+                }
+                self.acknowledge(name, &topic, seq);
+            }
+        }
+    }
+
+    /// Advances a durable subscription's cursor past a message it received.
+    fn acknowledge(&self, name: &str, topic: &str, seq: u64) {
+        if let Some(cursor) = self
+            .cursors
+            .lock()
+            .unwrap()
+            .get_mut(name)
+            .and_then(|cursors| cursors.get_mut(topic))
+        {
+            *cursor = (*cursor).max(seq + 1);
+        }
+    }
+
+    /// Relays the messages published to a topic from the broker to this server's subscribers.
+    async fn start_relay(&self, topic: String) {
+        let messages = match self.broker.subscribe(topic.clone()).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                info!(%topic, error = %e, "BrokerSubscribeFailed");
+                return;
+            }
+        };
+        let server = self.clone();
+        let relay_topic = topic.clone();
+        let (relay, abort_handle) = future::abortable(
+            messages
+                .for_each(move |message| server.clone().broadcast(relay_topic.clone(), message)),
+        );
+        // Another subscription may have started a relay for the topic in the meantime.
+        let mut relays = self.relays.lock().unwrap();
+        if relays.contains_key(&topic) {
+            return;
+        }
+        relays.insert(topic.clone(), abort_handle);
+        crate::util::spawn(format_args!("tarpc::pubsub::relay {topic}"), relay);
+    }
+
+    /// Sends a message from the broker to this server's subscribers of its topic.
+    async fn broadcast(self, topic: String, message: String) {
+        let (seq, subscribers) = {
+            let mut history = self.history.lock().unwrap();
+            let seq = history
+                .entry(topic.clone())
+                .or_default()
+                .push(message.clone(), self.replay_capacity);
+            match self.subscriptions.read().unwrap().get(&topic) {
+                None => return,
+                Some(subscribers) => (seq, subscribers.clone()),
+            }
+        };
+        let (this, topic, message) = (&self, &topic, &message);
+        let deliveries = subscribers.iter().map(|(&id, client)| async move {
+            let durable_name = this
+                .connections
+                .lock()
+                .unwrap()
+                .get(&id)
+                .and_then(|connection| connection.topics.get(topic).cloned().flatten());
+            if this
+                .deliver(id, client, durable_name.is_some(), topic, message)
+                .await
+            {
+                if let Some(name) = durable_name {
+                    this.acknowledge(&name, topic, seq);
+                }
+            }
+        });
+        future::join_all(deliveries).await;
+    }
+
+    /// Sends a message to a subscriber according to the topic's delivery policy, returning
+    /// whether the subscriber acknowledged it.
+    ///
+    /// Subscribers that disconnect are not retried. Durable subscriptions catch up on their
+    /// messages through replay; for other subscribers of at-least-once topics, as for subscribers
+    /// that exhaust their delivery attempts, the message is dead-lettered.
+    async fn deliver(
+        &self,
+        id: u64,
+        client: &SubscriberClient,
+        durable: bool,
+        topic: &str,
+        message: &str,
+    ) -> bool {
+        let delivery = self.delivery.get(topic).copied().unwrap_or_default();
+        let max_attempts = match delivery {
+            Delivery::AtMostOnce => 1,
+            Delivery::AtLeastOnce { max_attempts } => max_attempts,
+        };
+        let mut delays = redelivery_backoff().delays();
+        let mut attempt = 1;
+        let connected = loop {
+            match send(client, topic, message).await {
+                Ok(()) => return true,
+                Err(error) => info!(%topic, attempt, %error, "DeliveryFailed"),
+            }
+            if !self.connections.lock().unwrap().contains_key(&id) {
+                break false;
+            }
+            if attempt >= max_attempts {
+                break true;
+            }
+            tokio::time::sleep(delays.next_delay()).await;
+            attempt += 1;
+        };
+        if let Delivery::AtLeastOnce { .. } = delivery {
+            if connected || !durable {
+                self.dead_letter(topic, message).await;
+            }
+        }
+        false
+    }
+
+    /// Publishes a message that could not be delivered to the topic's dead-letter topic.
+    async fn dead_letter(&self, topic: &str, message: &str) {
+        let dead_letters = dead_letter_topic(topic);
+        info!(%topic, %dead_letters, "DeadLetter");
+        if let Err(e) = self.broker.publish(dead_letters, message.into()).await {
+            info!(error = %e, "BrokerPublishFailed");
+        }
+    }
+}
+
+impl<B> fmt::Debug for Server<B> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Server")
+            .field("delivery", &self.delivery)
+            .field("replay_capacity", &self.replay_capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sends a message to a subscriber, returning an error unless the subscriber acknowledged it.
+async fn send(client: &SubscriberClient, topic: &str, message: &str) -> Result<(), String> {
+    match client
+        .receive(context::current(), topic.into(), message.into())
+        .await
+    {
+        Ok(acknowledgment) => acknowledgment,
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Serves the [`PubSub`](protocol::PubSub) service to one connection.
+#[derive(Clone)]
+struct ConnectionHandler<B> {
+    server: Server<B>,
+    id: u64,
+}
+
+impl<B: Broker> protocol::PubSub for ConnectionHandler<B> {
+    async fn publish(self, _: context::Context, topic: String, message: String) {
+        if let Err(e) = self.server.broker.publish(topic, message).await {
+            info!(error = %e, "BrokerPublishFailed");
+        }
+    }
+
+    async fn subscribe(self, _: context::Context, topic: String, durable_name: Option<String>) {
+        self.server.subscribe(self.id, topic, durable_name).await
+    }
+
+    async fn unsubscribe(self, _: context::Context, topic: String) {
+        self.server.unsubscribe(self.id, &topic)
+    }
+}
+
+/// Connects a client to a server over `transport`, returning the client's [`Publisher`] and
+/// [`Subscriber`]. Spawns the tasks that drive the connection.
+pub fn connect<T>(config: client::Config, transport: T) -> (Publisher, Subscriber)
+where
+    T: Transport<
+            bidi::Message<PubSubRequest, SubscriberResponse>,
+            bidi::Message<SubscriberRequest, PubSubResponse>,
+        > + Send
+        + 'static,
+{
+    let (client_half, server_half) = bidi::new(bidi::Config::default(), transport).spawn();
+    let client = PubSubClient::new(config, client_half).spawn();
+    let routes = Routes::default();
+    let serving = BaseChannel::with_defaults(server_half)
+        .execute(routes.clone().serve())
+        .for_each(|response| async {
+            crate::util::spawn(
+                format_args!(
+                    "tarpc::pubsub::subscriber::request {} {}",
+                    response.request_id(),
+                    response.trace_id()
+                ),
+                response,
+            );
+        });
+    let closed = routes.clone();
+    crate::util::spawn(format_args!("tarpc::pubsub::subscriber"), async move {
+        serving.await;
+        // Ends the streams of the subscriptions.
+        closed.0.lock().unwrap().clear();
+    });
+    (
+        Publisher {
+            client: client.clone(),
+        },
+        Subscriber { client, routes },
+    )
+}
+
+/// Publishes messages to the topics of a server. Created by [`connect`].
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    client: PubSubClient,
+}
+
+impl Publisher {
+    /// Publishes `message` to the subscribers of `topic`.
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Result<(), RpcError> {
+        self.client
+            .publish(context::current(), topic.into(), message.into())
+            .await
+    }
+}
+
+/// The streams of the topics a client is subscribed to, by topic.
+#[derive(Clone, Default)]
+struct Routes(Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>);
+
+impl protocol::Subscriber for Routes {
+    async fn receive(
+        self,
+        _: context::Context,
+        topic: String,
+        message: String,
+    ) -> Result<(), String> {
+        let routes = self.0.lock().unwrap();
+        let route = routes
+            .get(&topic)
+            .ok_or_else(|| format!("not subscribed to {topic}"))?;
+        route
+            .unbounded_send(message)
+            .map_err(|_| format!("subscription to {topic} was dropped"))
+    }
+}
+
+/// Subscribes to the topics of a server. Created by [`connect`].
+#[derive(Clone)]
+pub struct Subscriber {
+    client: PubSubClient,
+    routes: Routes,
+}
+
+impl Subscriber {
+    /// Subscribes to `topic`, returning once the server has registered the subscription.
+    /// Subscribing to a topic again replaces the earlier subscription, whose stream ends.
+    pub async fn subscribe(&self, topic: impl Into<String>) -> Result<Subscription, RpcError> {
+        self.subscribe_with(topic.into(), None).await
+    }
+
+    /// Like [`Subscriber::subscribe`], but the server remembers the last message that a
+    /// subscription named `name` received from the topic. Subscribing again under that name,
+    /// even after the stream was dropped, first replays the messages published since, as far as
+    /// the server's replay buffer reaches back.
+    pub async fn subscribe_durable(
+        &self,
+        name: impl Into<String>,
+        topic: impl Into<String>,
+    ) -> Result<Subscription, RpcError> {
+        self.subscribe_with(topic.into(), Some(name.into())).await
+    }
+
+    async fn subscribe_with(
+        &self,
+        topic: String,
+        durable_name: Option<String>,
+    ) -> Result<Subscription, RpcError> {
+        // Routes the messages replayed before the server responds, too.
+        let (tx, messages) = mpsc::unbounded();
+        self.routes.0.lock().unwrap().insert(topic.clone(), tx);
+        let subscription = Subscription {
+            topic: topic.clone(),
+            messages,
+            client: self.client.clone(),
+            routes: self.routes.clone(),
+        };
+        self.client
+            .subscribe(context::current(), topic, durable_name)
+            .await?;
+        Ok(subscription)
+    }
+}
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Subscriber")
+            .field("topics", &self.routes.0.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+/// The messages published to a topic. Dropping it unsubscribes from the topic. The stream ends
+/// once the connection to the server closes.
+pub struct Subscription {
+    topic: String,
+    messages: mpsc::UnboundedReceiver<String>,
+    client: PubSubClient,
+    routes: Routes,
+}
+
+impl Subscription {
+    /// Returns the topic subscribed to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = String;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<String>> {
+        self.messages.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut routes = self.routes.0.lock().unwrap();
+        // The topic may have been subscribed to again, replacing this subscription.
+        match routes.get(&self.topic) {
+            Some(route) if route.is_connected_to(&self.messages) => {}
+            _ => return,
+        }
+        routes.remove(&self.topic);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            let topic = self.topic.clone();
+            runtime.spawn(async move {
+                let _ = client.unsubscribe(context::current(), topic).await;
+            });
+        }
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Subscription")
+            .field("topic", &self.topic)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, dead_letter_topic, Delivery, LocalBroker, Server};
+    use crate::{client, transport};
+    use futures::prelude::*;
+    use std::time::Duration;
+
+    fn connect_to(server: &Server<LocalBroker>) -> (super::Publisher, super::Subscriber) {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+        tokio::spawn(server.clone().serve_connection(server_transport));
+        connect(client::Config::default(), client_transport)
+    }
+
+    async fn wait_for_subscribers(server: &Server<LocalBroker>, topic: &str, subscribers: usize) {
+        while server.subscribers(topic) != subscribers {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn servers_sharing_a_broker_broadcast_to_all_subscribers() {
+        let broker = LocalBroker::default();
+        let (first, second) = (Server::new(broker.clone()), Server::new(broker));
+        let (publisher, alice) = connect_to(&first);
+        let (_, bob) = connect_to(&second);
+
+        let mut alice_news = alice.subscribe("news").await.unwrap();
+        let mut bob_news = bob.subscribe("news").await.unwrap();
+        let mut bob_sports = bob.subscribe("sports").await.unwrap();
+        assert_eq!(bob_sports.topic(), "sports");
+        publisher.publish("news", "extra").await.unwrap();
+        publisher.publish("sports", "goal").await.unwrap();
+        assert_eq!(alice_news.next().await.unwrap(), "extra");
+        assert_eq!(bob_news.next().await.unwrap(), "extra");
+        assert_eq!(bob_sports.next().await.unwrap(), "goal");
+
+        // Dropping a subscription unsubscribes from its topic.
+        drop(bob_news);
+        wait_for_subscribers(&second, "news", 0).await;
+        assert_eq!(second.topics(), vec!["sports".to_string()]);
+
+        // Closing a connection unsubscribes from all its topics.
+        drop((bob, bob_sports));
+        assert_eq!(first.subscribers("news"), 1);
+        drop((alice, alice_news));
+        wait_for_subscribers(&first, "news", 0).await;
+    }
+
+    #[tokio::test]
+    async fn durable_subscriptions_replay_missed_messages() {
+        let server = Server::new(LocalBroker::default());
+        let (publisher, subscriber) = connect_to(&server);
+
+        let mut archive = subscriber
+            .subscribe_durable("archivist", "history")
+            .await
+            .unwrap();
+        publisher.publish("history", "napoleon").await.unwrap();
+        assert_eq!(archive.next().await.unwrap(), "napoleon");
+        drop(archive);
+        wait_for_subscribers(&server, "history", 0).await;
+
+        publisher.publish("history", "waterloo").await.unwrap();
+        let mut archive = subscriber
+            .subscribe_durable("archivist", "history")
+            .await
+            .unwrap();
+        assert_eq!(archive.next().await.unwrap(), "waterloo");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacknowledged_messages_are_dead_lettered() {
+        let server = Server::new(LocalBroker::default())
+            .with_delivery("scores", Delivery::AtLeastOnce { max_attempts: 3 });
+        let (publisher, subscriber) = connect_to(&server);
+        let mut dead_letters = subscriber
+            .subscribe(dead_letter_topic("scores"))
+            .await
+            .unwrap();
+        let mut scores = subscriber.subscribe("scores").await.unwrap();
+        // Deliveries to a closed stream fail.
+        scores.messages.close();
+
+        publisher.publish("scores", "42").await.unwrap();
+        assert_eq!(dead_letters.next().await.unwrap(), "42");
+    }
+}
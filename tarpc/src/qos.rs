@@ -0,0 +1,479 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides classes of service, which keep kinds of traffic from exhausting each other's budgets.
+//!
+//! A [`Class`], e.g. one for interactive requests and one for batch requests, has its own budget
+//! of requests in flight and requests queued. Each class is carried by its own
+//! [sub-channel](crate::transport::mux) of a connection:
+//!
+//! - On the client, [`client`] creates one [`Channel`] per class, each with the class's
+//!   in-flight limit and a pending-request buffer the size of its queue. A [`QosChannel`] routes
+//!   each request to the channel of its class.
+//! - On the server, [`ServerBudgets`] wraps a service so that requests of a class are limited
+//!   to the class's budget across all connections. Requests beyond the in-flight limit wait for a
+//!   slot, and requests beyond the queue are rejected with a
+//!   [resource exhausted](crate::ServerError::resource_exhausted) error.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client::{self, stub::Stub},
+//!     context,
+//!     qos::{self, Class, ServerBudgets},
+//!     server::{self, BaseChannel, Channel},
+//!     transport::{self, mux},
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let classes = [
+//!     Class::new("interactive", 0, 100, 100)?,
+//!     Class::new("batch", 1, 10, 1000)?,
+//! ];
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//!
+//! let (_, incoming) = mux::new(mux::Config::default(), server_transport).spawn();
+//! let budgets = ServerBudgets::new(&classes);
+//! tokio::spawn(incoming.for_each(move |sub_channel| {
+//!     let double = server::serve(|_, x: u64| async move { Ok(x * 2) });
+//!     if let Some(double) = budgets.serve(sub_channel.id(), double) {
+//!         tokio::spawn(
+//!             BaseChannel::with_defaults(sub_channel)
+//!                 .execute(double)
+//!                 .for_each(|response| response),
+//!         );
+//!     }
+//!     async {}
+//! }));
+//!
+//! let (opener, _) = mux::new(mux::Config::default(), client_transport).spawn();
+//! // Large requests are batch requests.
+//! let client = qos::client(client::Config::default(), &classes, &opener)?
+//!     .spawn()
+//!     .with_classifier(|x: &u64| if *x >= 1000 { 1 } else { 0 });
+//! assert_eq!(client.call(context::current(), "", 2).await?, 4);
+//! assert_eq!(client.class(1).unwrap().call(context::current(), "", 3).await?, 6);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing;
+use crate::{
+    client::{self, stub::Stub, Channel, NewClient, RequestDispatch, RpcError},
+    context,
+    server::Serve,
+    transport::mux::{Opener, SubChannel},
+    ChannelError, ClientMessage, InvalidConfig, Response, ServerError,
+};
+use fnv::FnvHashMap;
+use futures::{future::TryJoinAll, prelude::*};
+use pin_project::pin_project;
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::Semaphore;
+
+/// A class of service with its own budgets of requests.
+#[derive(Clone, Debug)]
+pub struct Class {
+    name: &'static str,
+    id: u32,
+    max_in_flight_requests: usize,
+    max_queued_requests: usize,
+}
+
+impl Class {
+    /// Returns the class `name`, whose requests are carried by the sub-channel `id`. At most
+    /// `max_in_flight_requests` of the class's requests are in flight at once, and up to
+    /// `max_queued_requests` more wait for a slot. Clients and servers must agree on the IDs of
+    /// the classes.
+    pub fn new(
+        name: &'static str,
+        id: u32,
+        max_in_flight_requests: usize,
+        max_queued_requests: usize,
+    ) -> Result<Self, InvalidConfig> {
+        if max_in_flight_requests == 0 {
+            return Err(InvalidConfig::new(
+                "max_in_flight_requests",
+                "must be greater than zero",
+            ));
+        }
+        Ok(Self {
+            name,
+            id,
+            max_in_flight_requests,
+            max_queued_requests,
+        })
+    }
+
+    /// Returns the name of the class.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the ID of the sub-channel that carries the class's requests.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the most requests of the class in flight at once.
+    pub fn max_in_flight_requests(&self) -> usize {
+        self.max_in_flight_requests
+    }
+
+    /// Returns the most requests of the class waiting for a slot.
+    pub fn max_queued_requests(&self) -> usize {
+        self.max_queued_requests
+    }
+}
+
+type ClassTransport<Req, Resp> = SubChannel<Response<Resp>, ClientMessage<Req>>;
+
+/// Returns a channel that sends each class's requests over the class's sub-channel of `opener`,
+/// and the dispatch of all classes. Every class's channel uses `config`, except for the
+/// in-flight limit and the size of the pending-request buffer, which are taken from the class.
+/// Requests beyond a class's queue wait for room in it.
+///
+/// Fails if `classes` is empty or a class's sub-channel cannot be opened.
+pub fn client<Req, Resp>(
+    config: client::Config,
+    classes: &[Class],
+    opener: &Opener<Response<Resp>, ClientMessage<Req>>,
+) -> io::Result<NewClient<QosChannel<Req, Resp>, QosDispatch<Req, Resp>>> {
+    if classes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "at least one class is required",
+        ));
+    }
+    let mut channels = Vec::with_capacity(classes.len());
+    let mut dispatches = Vec::with_capacity(classes.len());
+    for class in classes {
+        let config = config
+            .clone()
+            .into_builder()
+            .max_in_flight_requests(class.max_in_flight_requests)
+            .pending_request_buffer(class.max_queued_requests.max(1))
+            .build()
+            .expect("the class's budgets were validated by Class::new");
+        let NewClient { client, dispatch } = client::new(config, opener.open(class.id)?);
+        channels.push((class.id, client));
+        dispatches.push(dispatch);
+    }
+    Ok(NewClient {
+        client: QosChannel {
+            channels: Arc::new(channels),
+            classify: Arc::new(|_| None),
+        },
+        dispatch: QosDispatch {
+            dispatches: future::try_join_all(dispatches),
+        },
+    })
+}
+
+/// A client that sends each request over the channel of its class.
+pub struct QosChannel<Req, Resp> {
+    channels: Arc<Vec<(u32, Channel<Req, Resp>)>>,
+    classify: Arc<dyn Fn(&Req) -> Option<u32> + Send + Sync>,
+}
+
+impl<Req, Resp> QosChannel<Req, Resp> {
+    /// Classifies each request with `classify`, which returns the ID of the request's class.
+    /// Requests whose ID does not belong to a class, and all requests of a channel without a
+    /// classifier, are sent in the first class.
+    pub fn with_classifier(
+        mut self,
+        classify: impl Fn(&Req) -> u32 + Send + Sync + 'static,
+    ) -> Self {
+        self.classify = Arc::new(move |request| Some(classify(request)));
+        self
+    }
+
+    /// Returns the channel of the class `id`, if there is such a class.
+    pub fn class(&self, id: u32) -> Option<&Channel<Req, Resp>> {
+        self.channels
+            .iter()
+            .find(|(class, _)| *class == id)
+            .map(|(_, channel)| channel)
+    }
+}
+
+impl<Req, Resp> Clone for QosChannel<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            channels: self.channels.clone(),
+            classify: self.classify.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for QosChannel<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QosChannel")
+            .field(
+                "classes",
+                &self.channels.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<Req, Resp> Stub for QosChannel<Req, Resp> {
+    type Req = Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        let channel = match (self.classify)(&request) {
+            Some(id) => self.class(id).unwrap_or_else(|| {
+                tracing::warn!(
+                    "UnknownClass: sending request of class {} in the first class",
+                    id
+                );
+                &self.channels[0].1
+            }),
+            None => &self.channels[0].1,
+        };
+        channel.call(ctx, request_name, request).await
+    }
+}
+
+/// Drives the dispatches of all classes of a [`QosChannel`]. Resolves once every class's
+/// dispatch has, or as soon as one fails.
+#[must_use]
+#[pin_project]
+pub struct QosDispatch<Req, Resp> {
+    #[pin]
+    dispatches: TryJoinAll<RequestDispatch<Req, Resp, ClassTransport<Req, Resp>>>,
+}
+
+impl<Req, Resp> Future for QosDispatch<Req, Resp> {
+    type Output = Result<(), ChannelError<io::Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().dispatches.poll(cx).map_ok(|_| ())
+    }
+}
+
+impl<Req, Resp> fmt::Debug for QosDispatch<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QosDispatch")
+    }
+}
+
+/// The server's budgets of each class, shared by all connections.
+#[derive(Clone, Debug)]
+pub struct ServerBudgets {
+    budgets: Arc<FnvHashMap<u32, Arc<Budget>>>,
+}
+
+impl ServerBudgets {
+    /// Returns a fresh budget for each of `classes`.
+    pub fn new(classes: &[Class]) -> Self {
+        let budgets = classes
+            .iter()
+            .map(|class| {
+                let budget = Budget {
+                    class: class.name,
+                    in_flight: Semaphore::new(class.max_in_flight_requests),
+                    queued: AtomicUsize::new(0),
+                    max_queued: class.max_queued_requests,
+                };
+                (class.id, Arc::new(budget))
+            })
+            .collect();
+        Self {
+            budgets: Arc::new(budgets),
+        }
+    }
+
+    /// Returns `serve`, limited to the budget of the class carried by the sub-channel `id`.
+    /// Returns `None` if no class has that ID, in which case the sub-channel should be dropped.
+    pub fn serve<S>(&self, id: u32, serve: S) -> Option<Budgeted<S>> {
+        let budget = self.budgets.get(&id)?.clone();
+        Some(Budgeted { serve, budget })
+    }
+}
+
+/// The requests of one class that are in flight or queued on the server.
+#[derive(Debug)]
+struct Budget {
+    class: &'static str,
+    in_flight: Semaphore,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+/// Counts a request as queued until it is dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A [`Serve`] whose requests are limited to the budget of their class.
+#[derive(Clone, Debug)]
+pub struct Budgeted<S> {
+    serve: S,
+    budget: Arc<Budget>,
+}
+
+impl<S> Serve for Budgeted<S>
+where
+    S: Serve,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let budget = &*self.budget;
+        let _permit = match budget.in_flight.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let queued = Queued(&budget.queued);
+                if budget.queued.fetch_add(1, Ordering::AcqRel) >= budget.max_queued {
+                    drop(queued);
+                    tracing::info!(class = budget.class, "ThrottleRequest");
+                    return Err(ServerError::resource_exhausted(format!(
+                        "the {} class is over its budget",
+                        budget.class
+                    )));
+                }
+                let permit = budget.in_flight.acquire().await;
+                drop(queued);
+                permit.expect("the semaphore is never closed")
+            }
+        };
+        self.serve.serve(ctx, req).await
+    }
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Some tests drive a spawned dispatch, which requires tokio1.
+    #![cfg_attr(not(feature = "tokio1"), allow(unused_imports))]
+
+    use super::{Class, ServerBudgets};
+    use crate::{
+        client::{self, stub::Stub},
+        context,
+        server::{BaseChannel, Channel, Serve},
+        transport::{self, mux},
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::{io, sync::Arc};
+    use tokio::sync::Semaphore;
+
+    /// A service that answers only once its semaphore has a permit for the request.
+    #[derive(Clone)]
+    struct Gated(Arc<Semaphore>);
+
+    impl Serve for Gated {
+        type Req = u32;
+        type Resp = u32;
+
+        async fn serve(self, _: context::Context, x: u32) -> Result<u32, ServerError> {
+            self.0.acquire().await.unwrap().forget();
+            Ok(x)
+        }
+    }
+
+    #[tokio::test]
+    async fn server_budget_queues_then_rejects() {
+        let release = Arc::new(Semaphore::new(0));
+        let budgets = ServerBudgets::new(&[Class::new("batch", 1, 1, 1).unwrap()]);
+        assert!(budgets.serve(0, Gated(release.clone())).is_none());
+        let serve = budgets.serve(1, Gated(release.clone())).unwrap();
+
+        let mut in_flight = Box::pin(serve.clone().serve(context::current(), 1));
+        let mut queued = Box::pin(serve.clone().serve(context::current(), 2));
+        assert!(futures::poll!(in_flight.as_mut()).is_pending());
+        assert!(futures::poll!(queued.as_mut()).is_pending());
+        assert_matches!(
+            serve.clone().serve(context::current(), 3).await,
+            Err(ServerError {
+                kind: io::ErrorKind::WouldBlock,
+                ..
+            })
+        );
+
+        release.add_permits(2);
+        assert_matches!(in_flight.await, Ok(1));
+        assert_matches!(queued.await, Ok(2));
+        release.add_permits(1);
+        assert_matches!(serve.serve(context::current(), 4).await, Ok(4));
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn batch_requests_do_not_take_interactive_slots() {
+        let classes = [
+            Class::new("interactive", 0, 1, 1).unwrap(),
+            Class::new("batch", 1, 1, 1).unwrap(),
+        ];
+        let (client_transport, server_transport) = transport::channel::unbounded();
+
+        let batch_release = Arc::new(Semaphore::new(0));
+        let server = mux::new(mux::Config::default(), server_transport);
+        tokio::spawn(server.driver);
+        let budgets = ServerBudgets::new(&classes);
+        tokio::spawn(server.incoming.for_each({
+            let batch_release = batch_release.clone();
+            move |sub_channel| {
+                // Interactive requests are answered right away.
+                let release = match sub_channel.id() {
+                    1 => batch_release.clone(),
+                    _ => Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+                };
+                let serve = budgets.serve(sub_channel.id(), Gated(release)).unwrap();
+                tokio::spawn(
+                    BaseChannel::with_defaults(sub_channel)
+                        .execute(serve)
+                        .for_each(|response| response),
+                );
+                async {}
+            }
+        }));
+
+        let client = mux::new(mux::Config::default(), client_transport);
+        tokio::spawn(client.driver);
+        let channel = super::client(client::Config::default(), &classes, &client.opener)
+            .unwrap()
+            .spawn()
+            .with_classifier(|x: &u32| if *x >= 100 { 1 } else { 0 });
+
+        // The first batch request is in flight and the second is queued in the client.
+        let mut batch = Box::pin(future::join(
+            channel.call(context::current(), "", 100),
+            channel.call(context::current(), "", 101),
+        ));
+        assert!(futures::poll!(batch.as_mut()).is_pending());
+        assert_matches!(channel.call(context::current(), "", 1).await, Ok(1));
+
+        batch_release.add_permits(2);
+        assert_matches!(batch.await, (Ok(100), Ok(101)));
+    }
+}
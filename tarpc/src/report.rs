@@ -0,0 +1,296 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides [`RuntimeReport`], a machine-readable report of the effective configuration of the
+//! clients and servers of a process.
+//!
+//! In a fleet that is upgraded or reconfigured gradually, processes drift apart: one runs an
+//! older tarpc, another lacks a feature, a third has a smaller in-flight limit. A report captures
+//! what a process actually runs with, i.e. the version of tarpc, its enabled features, the
+//! transport's codec and compression, and the settings of each client and server
+//! [`Config`](crate::client::Config). Log it at startup with [`RuntimeReport::log`], or, with the
+//! `serde1` feature, serve it serialized from an admin endpoint, so that reports can be collected
+//! and compared centrally.
+//!
+//! ```rust
+//! use tarpc::{client, report::RuntimeReport, server};
+//!
+//! let report = RuntimeReport::new()
+//!     .with_codec("bincode")
+//!     .with_client("inventory", &client::Config::low_latency())
+//!     .with_server("api", &server::Config::default());
+//! report.log();
+//! assert_eq!(report.clients["inventory"].max_in_flight_requests, 100);
+//! ```
+
+use crate::util::tracing;
+use crate::{client, server};
+use std::{collections::BTreeMap, time::Duration};
+
+/// The effective configuration of the clients and servers of a process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct RuntimeReport {
+    /// The version of tarpc, which determines the wire protocol.
+    pub tarpc_version: String,
+    /// The cargo features tarpc was built with.
+    pub features: Vec<String>,
+    /// The codec that the transports serialize messages with, if reported.
+    pub codec: Option<String>,
+    /// How the transports compress frames, if reported.
+    pub compression: Option<String>,
+    /// The settings of the clients, by name.
+    pub clients: BTreeMap<String, ClientReport>,
+    /// The settings of the servers, by name.
+    pub servers: BTreeMap<String, ServerReport>,
+}
+
+impl RuntimeReport {
+    /// Returns a report of the tarpc version and features, without clients or servers.
+    pub fn new() -> Self {
+        Self {
+            tarpc_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: enabled_features().iter().map(|f| f.to_string()).collect(),
+            codec: None,
+            compression: None,
+            clients: BTreeMap::new(),
+            servers: BTreeMap::new(),
+        }
+    }
+
+    /// Reports that the transports serialize messages with `codec`, e.g. `"json"`.
+    pub fn with_codec(mut self, codec: impl Into<String>) -> Self {
+        self.codec = Some(codec.into());
+        self
+    }
+
+    /// Reports that the transports compress frames with `compression`, e.g. `"zstd"`.
+    pub fn with_compression(mut self, compression: impl Into<String>) -> Self {
+        self.compression = Some(compression.into());
+        self
+    }
+
+    /// Reports the settings of the client named `name`.
+    pub fn with_client(mut self, name: impl Into<String>, config: &client::Config) -> Self {
+        self.clients.insert(name.into(), ClientReport::from(config));
+        self
+    }
+
+    /// Reports the settings of the server named `name`.
+    pub fn with_server(mut self, name: impl Into<String>, config: &server::Config) -> Self {
+        self.servers.insert(name.into(), ServerReport::from(config));
+        self
+    }
+
+    /// Logs the report as a `RuntimeConfiguration` event, e.g. at startup.
+    pub fn log(&self) {
+        tracing::info!(report = ?self, "RuntimeConfiguration");
+    }
+}
+
+impl Default for RuntimeReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The effective settings of a [`client::Config`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ClientReport {
+    /// See [`client::Config::max_in_flight_requests`].
+    pub max_in_flight_requests: usize,
+    /// See [`client::Config::pending_request_buffer`].
+    pub pending_request_buffer: usize,
+    /// See [`client::Config::malformed_frame_policy`].
+    pub malformed_frame_policy: String,
+    /// See [`client::Config::max_request_len`].
+    pub max_request_len: Option<usize>,
+    /// See [`client::Config::auto_tune`].
+    pub auto_tune: bool,
+    /// See [`client::Config::ordered_responses`].
+    pub ordered_responses: bool,
+    /// See [`client::Config::request_ids`].
+    pub request_ids: String,
+    /// See [`client::Config::write_timeout`].
+    pub write_timeout: Option<Duration>,
+    /// See [`WriteBatch::max_messages`](client::WriteBatch::max_messages).
+    pub write_batch_max_messages: Option<usize>,
+    /// See [`WriteBatch::max_delay`](client::WriteBatch::max_delay).
+    pub write_batch_max_delay: Option<Duration>,
+    /// Whether the client has a [dispatch log](client::Config::dispatch_log).
+    pub dispatch_log: bool,
+    /// Whether the client has a [journal](client::Config::journal).
+    pub journal: bool,
+    /// Whether the client has a [retry policy](client::Config::retry_policy).
+    pub retry_policy: bool,
+    /// Whether the client has a [circuit breaker](client::Config::circuit_breaker).
+    pub circuit_breaker: bool,
+    /// Whether the client has a [watchdog](client::Config::watchdog).
+    pub watchdog: bool,
+}
+
+impl From<&client::Config> for ClientReport {
+    fn from(config: &client::Config) -> Self {
+        Self {
+            max_in_flight_requests: config.max_in_flight_requests(),
+            pending_request_buffer: config.pending_request_buffer(),
+            malformed_frame_policy: format!("{:?}", config.malformed_frame_policy()),
+            max_request_len: config.max_request_len(),
+            auto_tune: config.auto_tune(),
+            ordered_responses: config.ordered_responses(),
+            request_ids: format!("{:?}", config.request_ids()),
+            write_timeout: config.write_timeout(),
+            write_batch_max_messages: config.write_batch().map(|batch| batch.max_messages()),
+            write_batch_max_delay: config.write_batch().map(|batch| batch.max_delay()),
+            dispatch_log: config.dispatch_log().is_some(),
+            journal: config.journal().is_some(),
+            retry_policy: config.retry_policy().is_some(),
+            circuit_breaker: config.circuit_breaker().is_some(),
+            watchdog: config.watchdog().is_some(),
+        }
+    }
+}
+
+/// The effective settings of a [`server::Config`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ServerReport {
+    /// See [`server::Config::pending_response_buffer`].
+    pub pending_response_buffer: usize,
+    /// See [`server::Config::malformed_frame_policy`].
+    pub malformed_frame_policy: String,
+    /// See [`server::Config::max_buffered_bytes`].
+    pub max_buffered_bytes: Option<usize>,
+    /// See [`server::Config::buffer_limit_policy`].
+    pub buffer_limit_policy: String,
+    /// The capacity of the server's [memory pool](server::Config::memory_pool), if any.
+    pub memory_pool_capacity: Option<usize>,
+    /// Whether the server has an [audit log](server::Config::audit_log).
+    pub audit_log: bool,
+    /// See [`server::Config::read_idle_timeout`].
+    pub read_idle_timeout: Option<Duration>,
+    /// See [`server::Config::response_order`].
+    pub response_order: String,
+    /// See [`server::Config::max_buffered_items`].
+    pub max_buffered_items: Option<usize>,
+    /// The maximum of the server's [concurrency limit](server::Config::concurrency_limit), if
+    /// any.
+    pub concurrency_limit: Option<usize>,
+}
+
+impl From<&server::Config> for ServerReport {
+    fn from(config: &server::Config) -> Self {
+        Self {
+            pending_response_buffer: config.pending_response_buffer(),
+            malformed_frame_policy: format!("{:?}", config.malformed_frame_policy()),
+            max_buffered_bytes: config.max_buffered_bytes(),
+            buffer_limit_policy: format!("{:?}", config.buffer_limit_policy()),
+            memory_pool_capacity: config.memory_pool().map(|pool| pool.capacity()),
+            audit_log: config.audit_log().is_some(),
+            read_idle_timeout: config.read_idle_timeout(),
+            response_order: format!("{:?}", config.response_order()),
+            max_buffered_items: config.max_buffered_items(),
+            concurrency_limit: config.concurrency_limit().map(|limit| limit.max()),
+        }
+    }
+}
+
+/// Returns the cargo features tarpc was built with.
+pub fn enabled_features() -> Vec<&'static str> {
+    [
+        ("random-ids", cfg!(feature = "random-ids")),
+        ("serde1", cfg!(feature = "serde1")),
+        ("tokio1", cfg!(feature = "tokio1")),
+        ("serde-transport", cfg!(feature = "serde-transport")),
+        (
+            "serde-transport-json",
+            cfg!(feature = "serde-transport-json"),
+        ),
+        (
+            "serde-transport-bincode",
+            cfg!(feature = "serde-transport-bincode"),
+        ),
+        ("tcp", cfg!(feature = "tcp")),
+        ("unix", cfg!(feature = "unix")),
+        ("turmoil", cfg!(feature = "turmoil")),
+        ("io-uring", cfg!(feature = "io-uring")),
+        ("shm", cfg!(feature = "shm")),
+        ("handoff", cfg!(feature = "handoff")),
+        ("zstd", cfg!(feature = "zstd")),
+        ("jobs", cfg!(feature = "jobs")),
+        ("pubsub", cfg!(feature = "pubsub")),
+        ("chaos", cfg!(feature = "chaos")),
+        ("tokio-console", cfg!(feature = "tokio-console")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enabled_features, RuntimeReport};
+    use crate::{client, server};
+    use std::time::Duration;
+
+    #[test]
+    fn report_reflects_configs_and_features() {
+        let client_config = client::Config::builder()
+            .max_in_flight_requests(7)
+            .write_timeout(Some(Duration::from_secs(3)))
+            .build()
+            .unwrap();
+        let server_config = server::Config::builder()
+            .max_buffered_items(Some(4))
+            .concurrency_limit(Some(server::limits::concurrency::ConcurrencyLimit::new(64)))
+            .build()
+            .unwrap();
+        let report = RuntimeReport::new()
+            .with_compression("zstd")
+            .with_client("a", &client_config)
+            .with_server("b", &server_config);
+
+        assert_eq!(report.tarpc_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.features.len(), enabled_features().len());
+        assert_eq!(
+            report.features.contains(&"tokio1".to_string()),
+            cfg!(feature = "tokio1")
+        );
+        assert_eq!(report.compression.as_deref(), Some("zstd"));
+        assert_eq!(report.clients["a"].max_in_flight_requests, 7);
+        assert_eq!(
+            report.clients["a"].write_timeout,
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(report.clients["a"].request_ids, "Sequential");
+        assert!(!report.clients["a"].retry_policy);
+        assert_eq!(report.servers["b"].max_buffered_items, Some(4));
+        assert_eq!(report.servers["b"].concurrency_limit, Some(64));
+        assert_ne!(
+            report,
+            RuntimeReport::new().with_client("a", &client::Config::default())
+        );
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn report_round_trips_through_json() {
+        let report = RuntimeReport::new()
+            .with_codec("json")
+            .with_client("a", &client::Config::default())
+            .with_server("b", &server::Config::default());
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RuntimeReport>(&json).unwrap(),
+            report
+        );
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a description of a service's wire format, and a check that a new version of a service
+//! is wire-compatible with an old one.
+//!
+//! Every [`service`](crate::service) exports its schema via the generated request type's
+//! `schema` function. With the `serde1` feature, a schema can be serialized, e.g. to JSON, and
+//! committed next to the service definition. A test in CI can then compare the schema of the
+//! current code against the committed schema of the last release, and fail on changes that
+//! clients or servers of the last release could not handle:
+//!
+//! ```rust
+//! use tarpc::schema::{MethodSchema, ServiceSchema};
+//!
+//! #[tarpc::service]
+//! trait World {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! // Normally deserialized from the schema of the last release.
+//! let released = ServiceSchema::new(
+//!     "World",
+//!     vec![MethodSchema::new(0, "hello", vec![("name", "String")], "String")],
+//! );
+//! let current = WorldRequest::schema();
+//! assert!(current.breaking_changes_since(&released).is_empty());
+//! ```
+//!
+//! Requests and responses are serialized as enums with one variant per method, in the order the
+//! methods are declared, whose fields are the method's arguments. Compact formats such as bincode
+//! identify variants and fields by position, and self-describing formats such as JSON identify them
+//! by name, so a change is only compatible if it is compatible for both: methods may be added at
+//! the end, but not removed, renamed, or reordered, and the arguments and return type of an
+//! existing method may not change at all.
+//!
+//! Types are compared by their names as written in the service definition. A change inside a type
+//! that a method uses, such as a field added to a struct argument, is not detected, nor is a type
+//! referred to by a different path.
+
+use std::fmt;
+
+/// The wire format of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ServiceSchema {
+    /// The name of the service.
+    pub name: String,
+    /// The service's methods, in the order they are declared.
+    pub methods: Vec<MethodSchema>,
+}
+
+/// The wire format of one method of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct MethodSchema {
+    /// The position of the method in the service, which identifies it in compact formats.
+    pub id: u32,
+    /// The name of the method.
+    pub name: String,
+    /// The method's arguments, in the order they are declared.
+    pub args: Vec<ArgSchema>,
+    /// The type the method returns.
+    pub output: String,
+}
+
+/// An argument of a method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ArgSchema {
+    /// The name of the argument.
+    pub name: String,
+    /// The type of the argument.
+    pub ty: String,
+}
+
+impl ServiceSchema {
+    /// Returns the schema of the service `name` with `methods`.
+    pub fn new(name: &str, methods: Vec<MethodSchema>) -> Self {
+        Self {
+            name: name.into(),
+            methods,
+        }
+    }
+
+    /// Returns the changes from `old` to this schema that break clients or servers that use
+    /// `old`, in the order of the methods of `old`. The schemas are compatible if none are
+    /// returned.
+    pub fn breaking_changes_since(&self, old: &ServiceSchema) -> Vec<BreakingChange> {
+        let mut changes = vec![];
+        for old_method in &old.methods {
+            let new_method = match self.methods.iter().find(|m| m.name == old_method.name) {
+                Some(new_method) => new_method,
+                None => {
+                    changes.push(BreakingChange::MethodRemoved {
+                        method: old_method.name.clone(),
+                    });
+                    continue;
+                }
+            };
+            if new_method.id != old_method.id {
+                changes.push(BreakingChange::MethodRenumbered {
+                    method: old_method.name.clone(),
+                    old: old_method.id,
+                    new: new_method.id,
+                });
+            }
+            if new_method.args != old_method.args {
+                changes.push(BreakingChange::ArgsChanged {
+                    method: old_method.name.clone(),
+                    old: old_method.args.clone(),
+                    new: new_method.args.clone(),
+                });
+            }
+            if new_method.output != old_method.output {
+                changes.push(BreakingChange::OutputChanged {
+                    method: old_method.name.clone(),
+                    old: old_method.output.clone(),
+                    new: new_method.output.clone(),
+                });
+            }
+        }
+        changes
+    }
+}
+
+impl MethodSchema {
+    /// Returns the schema of the method `name` at position `id` in its service, which takes
+    /// arguments of the given names and types and returns `output`.
+    pub fn new(id: u32, name: &str, args: Vec<(&str, &str)>, output: &str) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            args: args
+                .into_iter()
+                .map(|(name, ty)| ArgSchema {
+                    name: name.into(),
+                    ty: ty.into(),
+                })
+                .collect(),
+            output: output.into(),
+        }
+    }
+}
+
+/// A change to a service that breaks clients or servers of the previous version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BreakingChange {
+    /// A method was removed or renamed.
+    MethodRemoved {
+        /// The name of the method.
+        method: String,
+    },
+    /// A method moved to a different position in the service.
+    MethodRenumbered {
+        /// The name of the method.
+        method: String,
+        /// The position of the method in the old service.
+        old: u32,
+        /// The position of the method in the new service.
+        new: u32,
+    },
+    /// Arguments of a method were added, removed, renamed, reordered, or changed type.
+    ArgsChanged {
+        /// The name of the method.
+        method: String,
+        /// The arguments of the old method.
+        old: Vec<ArgSchema>,
+        /// The arguments of the new method.
+        new: Vec<ArgSchema>,
+    },
+    /// The return type of a method changed.
+    OutputChanged {
+        /// The name of the method.
+        method: String,
+        /// The return type of the old method.
+        old: String,
+        /// The return type of the new method.
+        new: String,
+    },
+}
+
+impl fmt::Display for BreakingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn args(args: &[ArgSchema]) -> String {
+            args.iter()
+                .map(|arg| format!("{}: {}", arg.name, arg.ty))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        match self {
+            BreakingChange::MethodRemoved { method } => write!(f, "method {method} was removed"),
+            BreakingChange::MethodRenumbered { method, old, new } => {
+                write!(f, "method {method} moved from position {old} to {new}")
+            }
+            BreakingChange::ArgsChanged { method, old, new } => write!(
+                f,
+                "arguments of method {method} changed from ({}) to ({})",
+                args(old),
+                args(new)
+            ),
+            BreakingChange::OutputChanged { method, old, new } => write!(
+                f,
+                "return type of method {method} changed from {old} to {new}"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakingChange, MethodSchema, ServiceSchema};
+
+    fn schema(methods: Vec<MethodSchema>) -> ServiceSchema {
+        ServiceSchema::new("Service", methods)
+    }
+
+    #[test]
+    fn appended_method_is_compatible() {
+        let old = schema(vec![MethodSchema::new(0, "a", vec![("x", "u32")], "()")]);
+        let new = schema(vec![
+            MethodSchema::new(0, "a", vec![("x", "u32")], "()"),
+            MethodSchema::new(1, "b", vec![], "String"),
+        ]);
+        assert_eq!(new.breaking_changes_since(&old), []);
+    }
+
+    #[test]
+    fn reports_breaking_changes() {
+        let old = schema(vec![
+            MethodSchema::new(0, "a", vec![("x", "u32")], "()"),
+            MethodSchema::new(1, "b", vec![], "String"),
+            MethodSchema::new(2, "c", vec![], "()"),
+        ]);
+        let new = schema(vec![
+            MethodSchema::new(0, "b", vec![], "Vec<u8>"),
+            MethodSchema::new(1, "a", vec![("x", "u64")], "()"),
+        ]);
+        let changes = new.breaking_changes_since(&old);
+        assert_eq!(
+            changes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "method a moved from position 0 to 1",
+                "arguments of method a changed from (x: u32) to (x: u64)",
+                "method b moved from position 1 to 0",
+                "return type of method b changed from String to Vec<u8>",
+                "method c was removed",
+            ]
+        );
+        assert!(matches!(changes[4], BreakingChange::MethodRemoved { .. }));
+    }
+}
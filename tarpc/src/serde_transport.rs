@@ -8,87 +8,277 @@
 
 #![deny(missing_docs)]
 
-use futures::{prelude::*, task::*};
+use crate::transport::MalformedFrame;
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, ready, task::*};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, io, pin::Pin};
+use std::{error::Error, io, marker::PhantomData, pin::Pin};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_serde::{Framed as SerdeFramed, *};
+use tokio_serde::*;
 use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Framed};
 
 /// A transport that serializes to, and deserializes from, a byte stream.
+///
+/// Frames that are read successfully but fail to deserialize are reported as
+/// [`InvalidData`](io::ErrorKind::InvalidData) errors wrapping a [`MalformedFrame`], which leaves
+/// the transport usable for reading subsequent frames.
 #[pin_project]
 pub struct Transport<S, Item, SinkItem, Codec> {
     #[pin]
-    inner: SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>,
+    inner: Framed<S, LengthDelimitedCodec>,
+    #[pin]
+    codec: Codec,
+    /// Whether frames are deserialized into an arena.
+    arena: bool,
+    /// Counts the frames and bytes read and written, if set.
+    stats: Option<stats::TransportStats>,
+    /// Logs snippets of the frames read and written, if set.
+    payload_log: Option<payload_log::Logger<Item, SinkItem>>,
+    /// Writes the frames read and written to a capture, if set.
+    capture: Option<capture::Tap>,
+    /// Splits long messages into chunks, and reassembles them, if set.
+    chunker: Option<chunking::Chunker>,
+    /// Recovers the request ID of frames that fail to deserialize, if set.
+    recover_request_id: Option<fn(&[u8]) -> Option<u64>>,
+    /// When a writer first found the transport not ready, if it is still waiting.
+    blocked_since: Option<tokio::time::Instant>,
+    ghost: PhantomData<(fn() -> Item, fn(SinkItem))>,
 }
 
 impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec> {
     /// Returns the inner transport over which messages are sent and received.
     pub fn get_ref(&self) -> &S {
-        self.inner.get_ref().get_ref()
+        self.inner.get_ref()
+    }
+
+    /// Deserializes each frame with the frame itself as its arena, so that the
+    /// [`ArenaStr`](arena::ArenaStr) and [`ArenaBytes`](arena::ArenaBytes) fields of a message
+    /// share one allocation. See [`arena`].
+    pub fn with_arena(mut self) -> Self {
+        self.arena = true;
+        self
+    }
+
+    /// Counts the frames and bytes the transport reads and writes in `stats`. See [`stats`].
+    pub fn with_stats(mut self, stats: stats::TransportStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Logs the first bytes of every frame the transport reads and writes, as configured by
+    /// `log`. See [`payload_log`].
+    pub fn with_payload_log(mut self, log: payload_log::PayloadLog) -> Self
+    where
+        Item: payload_log::LogKey,
+        SinkItem: payload_log::LogKey,
+    {
+        self.payload_log = Some(payload_log::Logger::new(log));
+        self
+    }
+
+    /// Writes every frame the transport reads and writes to `capture`, as one of its
+    /// connections, while the capture is enabled. See [`capture`].
+    pub fn with_capture(mut self, capture: capture::Capture) -> Self {
+        self.capture = Some(capture.connection());
+        self
+    }
+
+    /// Splits messages longer than the `chunking` threshold into several frames, and reassembles
+    /// the chunks it reads. The peer must use chunking, too. See [`chunking`].
+    pub fn with_chunking(mut self, chunking: chunking::Chunking) -> Self {
+        self.chunker = Some(chunking::Chunker::new(chunking));
+        self
+    }
+
+    /// Decodes the request ID of each frame that fails to deserialize with `recover`, and attaches
+    /// it to the [`MalformedFrame`], so that a channel configured with
+    /// [`MalformedFramePolicy::Respond`](crate::transport::MalformedFramePolicy::Respond) can fail
+    /// the request. See [`recovery`] for functions recovering the IDs of the built-in formats.
+    pub fn with_request_id_recovery(mut self, recover: fn(&[u8]) -> Option<u64>) -> Self {
+        self.recover_request_id = Some(recover);
+        self
     }
 }
 
-impl<S, Item, SinkItem, Codec, CodecError> Stream for Transport<S, Item, SinkItem, Codec>
+impl<S, Item, SinkItem, Codec> Stream for Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite + AsyncRead,
     Item: for<'a> Deserialize<'a>,
     Codec: Deserializer<Item>,
-    CodecError: Into<Box<dyn std::error::Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
-        Stream<Item = Result<Item, CodecError>>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
 {
     type Item = io::Result<Item>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
-        self.project()
-            .inner
-            .poll_next(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let this = self.project();
+        let frame = match ready!(poll_read_frame(
+            this.inner,
+            this.chunker,
+            this.stats,
+            this.capture,
+            cx
+        )) {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+            None => return Poll::Ready(None),
+        };
+        let codec = this.codec;
+        let (frame, item) = if *this.arena {
+            arena::deserialize_in_arena(frame, |frame| codec.deserialize(frame))
+        } else {
+            let item = codec.deserialize(&frame);
+            (frame.freeze(), item)
+        };
+        let item = item.map_err(|e| malformed_frame(e, &frame, *this.recover_request_id));
+        if let Some(payload_log) = this.payload_log {
+            match &item {
+                Ok(item) => payload_log.read(&frame, item),
+                Err(e) => payload_log.malformed(&frame, e),
+            }
+        }
+        Poll::Ready(Some(
+            item.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        ))
     }
 }
 
-impl<S, Item, SinkItem, Codec, CodecError> Sink<SinkItem> for Transport<S, Item, SinkItem, Codec>
+impl<S, Item, SinkItem, Codec> Sink<SinkItem> for Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite,
     SinkItem: Serialize,
     Codec: Serializer<SinkItem>,
-    CodecError: Into<Box<dyn Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
-        Sink<SinkItem, Error = CodecError>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
 {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_ready(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let mut this = self.project();
+        let ready = match write_pending_chunks(this.inner.as_mut(), this.chunker, cx) {
+            Poll::Ready(Ok(())) => Sink::<Bytes>::poll_ready(this.inner, cx)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            not_ready => not_ready,
+        };
+        if let Some(stats) = this.stats {
+            if ready.is_pending() {
+                this.blocked_since
+                    .get_or_insert_with(tokio::time::Instant::now);
+            } else if let Some(blocked_since) = this.blocked_since.take() {
+                stats.record_blocked_on_ready(blocked_since.elapsed());
+            }
+        }
+        ready
     }
 
     fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
-        self.project()
-            .inner
-            .start_send(item)
+        let this = self.project();
+        let frame = this
+            .codec
+            .serialize(&item)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if let Some(stats) = this.stats {
+            stats.record_frame_written(frame.len());
+        }
+        if let Some(capture) = this.capture {
+            capture.written(&frame);
+        }
+        if let Some(payload_log) = this.payload_log {
+            payload_log.written(&frame, &item);
+        }
+        let frame = match this.chunker {
+            Some(chunker) => chunker.split(frame),
+            None => frame,
+        };
+        this.inner
+            .start_send(frame)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_flush(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let mut this = self.project();
+        ready!(write_pending_chunks(this.inner.as_mut(), this.chunker, cx))?;
+        ready!(Sink::<Bytes>::poll_flush(this.inner, cx)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)))?;
+        if let Some(stats) = this.stats {
+            stats.record_flush();
+        }
+        Poll::Ready(Ok(()))
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_close(cx)
+        let mut this = self.project();
+        ready!(write_pending_chunks(this.inner.as_mut(), this.chunker, cx))?;
+        Sink::<Bytes>::poll_close(this.inner, cx)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
     }
 }
 
+/// Reads the next message, reassembling it from its chunks if chunking is enabled, and records it
+/// in the stats and capture. Shared by the transports that deserialize the message differently.
+fn poll_read_frame<S: AsyncRead>(
+    mut inner: Pin<&mut Framed<S, LengthDelimitedCodec>>,
+    chunker: &mut Option<chunking::Chunker>,
+    stats: &Option<stats::TransportStats>,
+    capture: &Option<capture::Tap>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<io::Result<BytesMut>>> {
+    let frame = loop {
+        let frame = match ready!(inner.as_mut().poll_next(cx)) {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e)))),
+            None => return Poll::Ready(None),
+        };
+        match chunker {
+            Some(chunker) => match chunker.reassemble(frame) {
+                Ok(Some(message)) => break message,
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            },
+            None => break frame,
+        }
+    };
+    if let Some(stats) = stats {
+        stats.record_frame_read(frame.len());
+    }
+    if let Some(capture) = capture {
+        capture.read(&frame);
+    }
+    Poll::Ready(Some(Ok(frame)))
+}
+
+/// Wraps the error of a frame that failed to deserialize, along with the request ID that
+/// `recover_request_id` decodes from the frame, if any.
+fn malformed_frame(
+    error: impl Into<Box<dyn Error + Send + Sync>>,
+    frame: &[u8],
+    recover_request_id: Option<fn(&[u8]) -> Option<u64>>,
+) -> MalformedFrame {
+    let malformed = MalformedFrame::new(error);
+    match recover_request_id.and_then(|recover| recover(frame)) {
+        Some(request_id) => malformed.with_request_id(request_id),
+        None => malformed,
+    }
+}
+
+/// Writes the chunks of the last message that are still pending, so that they precede the next
+/// message.
+fn write_pending_chunks<S: AsyncWrite>(
+    mut inner: Pin<&mut Framed<S, LengthDelimitedCodec>>,
+    chunker: &mut Option<chunking::Chunker>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    if let Some(chunker) = chunker {
+        loop {
+            ready!(Sink::<Bytes>::poll_ready(inner.as_mut(), cx))?;
+            match chunker.next_pending() {
+                Some(chunk) => inner.as_mut().start_send(chunk)?,
+                None => break,
+            }
+        }
+    }
+    Poll::Ready(Ok(()))
+}
+
 /// Constructs a new transport from a framed transport and a serialization codec.
 pub fn new<S, Item, SinkItem, Codec>(
     framed_io: Framed<S, LengthDelimitedCodec>,
@@ -101,7 +291,16 @@ where
     Codec: Serializer<SinkItem> + Deserializer<Item>,
 {
     Transport {
-        inner: SerdeFramed::new(framed_io, codec),
+        inner: framed_io,
+        codec,
+        arena: false,
+        stats: None,
+        payload_log: None,
+        capture: None,
+        chunker: None,
+        recover_request_id: None,
+        blocked_since: None,
+        ghost: PhantomData,
     }
 }
 
@@ -117,12 +316,48 @@ where
     }
 }
 
+pub mod arena;
+pub mod borrowed;
+pub mod capture;
+pub mod chunking;
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+pub mod compression;
+#[cfg(all(feature = "serde-transport-json", feature = "serde-transport-bincode"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(feature = "serde-transport-json", feature = "serde-transport-bincode")))
+)]
+pub mod detect;
+pub mod envelope;
+#[cfg(all(target_os = "linux", feature = "handoff"))]
+#[cfg_attr(docsrs, doc(cfg(all(target_os = "linux", feature = "handoff"))))]
+pub mod handoff;
+pub mod mq;
+pub mod payload_log;
+#[cfg(any(feature = "serde-transport-json", feature = "serde-transport-bincode"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "serde-transport-json", feature = "serde-transport-bincode")))
+)]
+pub mod recovery;
+#[cfg(all(target_os = "linux", feature = "shm"))]
+#[cfg_attr(docsrs, doc(cfg(all(target_os = "linux", feature = "shm"))))]
+pub mod shm;
+pub mod stats;
+pub mod streaming;
+pub mod throttle;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[cfg_attr(docsrs, doc(cfg(all(target_os = "linux", feature = "io-uring"))))]
+pub mod uring;
+
 #[cfg(feature = "tcp")]
 #[cfg_attr(docsrs, doc(cfg(feature = "tcp")))]
 /// TCP support for generic transport using Tokio.
 pub mod tcp {
     use {
         super::*,
+        crate::server::admission::Admission,
         futures::ready,
         std::{marker::PhantomData, net::SocketAddr},
         tokio::net::{TcpListener, TcpStream, ToSocketAddrs},
@@ -132,11 +367,11 @@ pub mod tcp {
     impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
         /// Returns the peer address of the underlying TcpStream.
         pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().peer_addr()
+            self.inner.get_ref().peer_addr()
         }
         /// Returns the local address of the underlying TcpStream.
         pub fn local_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().local_addr()
+            self.inner.get_ref().local_addr()
         }
     }
 
@@ -229,6 +464,7 @@ pub mod tcp {
             codec_fn,
             local_addr,
             config: LengthDelimitedCodec::builder(),
+            admission: None,
             ghost: PhantomData,
         })
     }
@@ -241,9 +477,172 @@ pub mod tcp {
         local_addr: SocketAddr,
         codec_fn: CodecFn,
         config: length_delimited::Builder,
+        admission: Option<Admission<SocketAddr>>,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Returns the underlying listener, e.g. to hand it off to another process.
+        pub fn listener(&self) -> &TcpListener {
+            &self.listener
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+
+        /// Closes connections from peers that `admission` rejects as soon as they are accepted,
+        /// before they are wrapped in transports. See the
+        /// [`admission`](crate::server::admission) module.
+        pub fn with_admission(mut self, admission: Admission<SocketAddr>) -> Self {
+            self.admission = Some(admission);
+            self
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let conn: TcpStream = loop {
+                let (conn, peer) =
+                    ready!(Pin::new(&mut self.as_mut().project().listener).poll_accept(cx)?);
+                match &self.admission {
+                    Some(admission) if !admission.admit(&peer) => continue,
+                    _ => break conn,
+                }
+            };
+            Poll::Ready(Some(Ok(new(
+                self.config.new_framed(conn),
+                (self.codec_fn)(),
+            ))))
+        }
+    }
+}
+
+#[cfg(feature = "turmoil")]
+#[cfg_attr(docsrs, doc(cfg(feature = "turmoil")))]
+/// TCP support for generic transport over [turmoil](::turmoil)'s simulated network.
+///
+/// The API mirrors [`crate::serde_transport::tcp`], so integration tests can exercise the full
+/// tarpc stack under simulated partitions and latency by swapping one module for the other.
+pub mod turmoil {
+    use {
+        super::*,
+        ::turmoil::{
+            net::{TcpListener, TcpStream},
+            ToSocketAddrs,
+        },
+        futures::{ready, stream::BoxStream},
+        std::{fmt, marker::PhantomData, net::SocketAddr, sync::Arc},
+        tokio_util::codec::length_delimited,
+    };
+
+    impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().local_addr()
+        }
+    }
+
+    /// Connects to `addr`, wrapping the connection in a TCP transport.
+    pub async fn connect<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<TcpStream, Item, SinkItem, Codec>>
+    where
+        A: ToSocketAddrs,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let io = TcpStream::connect(addr).await?;
+        Ok(new(
+            LengthDelimitedCodec::builder().new_framed(io),
+            codec_fn(),
+        ))
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in TCP transports.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Item: for<'de> Deserialize<'de>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        listen_on(TcpListener::bind(addr).await?, codec_fn).await
+    }
+
+    /// Wrap accepted connections from `listener` in TCP transports.
+    pub async fn listen_on<Item, SinkItem, Codec, CodecFn>(
+        listener: TcpListener,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let local_addr = listener.local_addr()?;
+        let accept = stream::unfold(Arc::new(listener), |listener| async move {
+            let conn = listener.accept().await.map(|(conn, _)| conn);
+            Some((conn, listener))
+        })
+        .boxed();
+        Ok(Incoming {
+            accept,
+            codec_fn,
+            local_addr,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`TcpListener`] that wraps connections in [transports](Transport).
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        accept: BoxStream<'static, io::Result<TcpStream>>,
+        local_addr: SocketAddr,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
         ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
     }
 
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("local_addr", &self.local_addr)
+                .field("config", &self.config)
+                .finish_non_exhaustive()
+        }
+    }
+
     impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
         /// Returns the address being listened on.
         pub fn local_addr(&self) -> SocketAddr {
@@ -271,8 +670,10 @@ pub mod tcp {
         type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
 
         fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let conn: TcpStream =
-                ready!(Pin::new(&mut self.as_mut().project().listener).poll_accept(cx)?).0;
+            let conn = match ready!(self.as_mut().project().accept.poll_next_unpin(cx)) {
+                Some(conn) => conn?,
+                None => return Poll::Ready(None),
+            };
             Poll::Ready(Some(Ok(new(
                 self.config.new_framed(conn),
                 (self.codec_fn)(),
@@ -296,11 +697,11 @@ pub mod unix {
     impl<Item, SinkItem, Codec> Transport<UnixStream, Item, SinkItem, Codec> {
         /// Returns the socket address of the remote half of the underlying [`UnixStream`].
         pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().peer_addr()
+            self.inner.get_ref().peer_addr()
         }
         /// Returns the socket address of the local half of the underlying [`UnixStream`].
         pub fn local_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().local_addr()
+            self.inner.get_ref().local_addr()
         }
     }
 
@@ -566,6 +967,7 @@ mod tests {
     use std::{
         io::{self, Cursor},
         pin::Pin,
+        time::Duration,
     };
     use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
     use tokio_serde::formats::SymmetricalJson;
@@ -627,6 +1029,116 @@ mod tests {
         assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
     }
 
+    #[test]
+    fn test_stream_malformed_frame_is_recoverable() {
+        let data: &[u8] = b"\x00\x00\x00\x04oops\x00\x00\x00\x04\"ok\"";
+        let transport = Transport::from((
+            TestIo(Cursor::new(Vec::from(data))),
+            SymmetricalJson::<String>::default(),
+        ));
+        pin_mut!(transport);
+
+        match transport.as_mut().poll_next(&mut ctx()) {
+            Poll::Ready(Some(Err(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                assert!(crate::transport::MalformedFrame::find(&e).is_some());
+            }
+            other => panic!("expected malformed frame error, got {other:?}"),
+        }
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "ok");
+        assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+    }
+
+    #[cfg(feature = "serde-transport-json")]
+    #[tokio::test]
+    async fn malformed_request_gets_error_response() {
+        use crate::{
+            context,
+            server::{self, BaseChannel, Channel},
+            transport::MalformedFramePolicy,
+            ClientMessage, Request, Response, ServerError,
+        };
+        use tokio_serde::formats::Json;
+
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let server_transport = Transport::from((
+            server_io,
+            Json::<ClientMessage<u32>, Response<u32>>::default(),
+        ))
+        .with_request_id_recovery(super::recovery::json_request_id);
+        let config = server::Config::builder()
+            .malformed_frame_policy(MalformedFramePolicy::Respond)
+            .build()
+            .unwrap();
+        tokio::spawn(
+            BaseChannel::new(config, server_transport)
+                .execute(server::serve(|_, n: u32| async move { Ok(n) }))
+                .for_each(|response| async {
+                    tokio::spawn(response);
+                }),
+        );
+
+        // The server expects a number, but the client sends a string.
+        let mut client_transport = Transport::from((
+            client_io,
+            Json::<Response<u32>, ClientMessage<String>>::default(),
+        ));
+        client_transport
+            .send(ClientMessage::Request(Request {
+                context: context::current(),
+                id: 7,
+                message: "seven".into(),
+                one_way: false,
+                streamed: false,
+            }))
+            .await
+            .unwrap();
+        assert_matches!(
+            client_transport.next().await,
+            Some(Ok(Response {
+                request_id: 7,
+                message: Err(ServerError {
+                    kind: io::ErrorKind::InvalidData,
+                    ..
+                }),
+                ..
+            }))
+        );
+    }
+
+    #[test]
+    fn test_stream_in_arena() {
+        use super::arena::ArenaStr;
+        use bincode::Options;
+        use tokio_serde::formats::SymmetricalBincode;
+
+        // The bincode codec uses varint length prefixes.
+        let message = bincode::DefaultOptions::new()
+            .serialize(&("one", "two"))
+            .unwrap();
+        let mut data = (message.len() as u32).to_be_bytes().to_vec();
+        data.extend(message);
+        let transport = Transport::from((
+            TestIo(Cursor::new(data)),
+            SymmetricalBincode::<(ArenaStr, ArenaStr)>::default(),
+        ))
+        .with_arena();
+        pin_mut!(transport);
+
+        let (one, two) = match transport.as_mut().poll_next(&mut ctx()) {
+            Poll::Ready(Some(Ok(message))) => message,
+            other => panic!("expected a message, got {other:?}"),
+        };
+        assert_eq!((&*one, &*two), ("one", "two"));
+        // Both are slices of the same arena, separated by the second string's length prefix.
+        assert_eq!(
+            two.as_bytes().as_ptr() as usize - one.as_bytes().as_ptr() as usize,
+            "one".len() + 1
+        );
+    }
+
     #[test]
     fn test_sink() {
         let writer = Cursor::new(vec![]);
@@ -655,6 +1167,79 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_stats() {
+        use super::stats::TransportStats;
+
+        let data: &[u8] = b"\x00\x00\x00\x04\"ok\"";
+        let stats = TransportStats::new();
+        let mut transport = Box::pin(
+            Transport::from((
+                TestIo(Cursor::new(Vec::from(data))),
+                SymmetricalJson::<String>::default(),
+            ))
+            .with_stats(stats.clone()),
+        );
+
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "ok");
+        for message in ["one", "two"] {
+            assert_matches!(
+                transport.as_mut().poll_ready(&mut ctx()),
+                Poll::Ready(Ok(()))
+            );
+            assert_matches!(transport.as_mut().start_send(message.into()), Ok(()));
+        }
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.frames_read, 1);
+        assert_eq!(snapshot.bytes_read, 4);
+        assert_eq!(snapshot.frames_written, 2);
+        assert_eq!(snapshot.bytes_written, 10);
+        assert_eq!(snapshot.flushes, 1);
+        assert_eq!(snapshot.blocked_on_ready, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn chunking() -> io::Result<()> {
+        use super::chunking::Chunking;
+        use tokio_util::codec::LengthDelimitedCodec;
+
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = Transport::from((client, SymmetricalJson::<String>::default()))
+            .with_chunking(Chunking::new(8));
+        // The server's frame limit is shorter than the long message.
+        let mut server = super::new::<_, String, String, _>(
+            LengthDelimitedCodec::builder()
+                .max_frame_length(16)
+                .new_framed(server),
+            SymmetricalJson::<String>::default(),
+        )
+        .with_chunking(Chunking::new(8));
+
+        let long = "a long message that spans several chunks".to_string();
+        tokio::spawn(async move {
+            client.send(long.clone()).await?;
+            client.send("short".into()).await?;
+            client.send(long).await
+        });
+        assert_eq!(
+            server.next().await.unwrap()?,
+            "a long message that spans several chunks"
+        );
+        assert_eq!(server.next().await.unwrap()?, "short");
+        assert_eq!(
+            server.next().await.unwrap()?,
+            "a long message that spans several chunks"
+        );
+        Ok(())
+    }
+
     #[cfg(feature = "tcp")]
     #[tokio::test]
     async fn tcp() -> io::Result<()> {
@@ -674,6 +1259,52 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn tcp_admission_closes_rejected_connections() -> io::Result<()> {
+        use super::tcp;
+        use crate::server::admission::Admission;
+
+        let admission = Admission::new(|_: &std::net::SocketAddr| false);
+        let mut listener = tcp::listen("127.0.0.1:0", SymmetricalJson::<String>::default)
+            .await?
+            .with_admission(admission.clone());
+        let addr = listener.local_addr();
+        tokio::spawn(async move { listener.next().await });
+        let mut transport = tcp::connect(addr, SymmetricalJson::<String>::default).await?;
+        assert_matches!(transport.next().await, None | Some(Err(_)));
+        assert_eq!(admission.stats().rejected, 1);
+        assert_eq!(admission.stats().accepted, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "turmoil")]
+    #[test]
+    fn turmoil() -> ::turmoil::Result {
+        use super::turmoil;
+
+        let mut sim = ::turmoil::Builder::new().build();
+        sim.host("server", || async {
+            let mut listener =
+                turmoil::listen(("0.0.0.0", 1234), SymmetricalJson::<String>::default).await?;
+            while let Some(transport) = listener.next().await {
+                let mut transport = transport?;
+                let message = transport.next().await.unwrap()?;
+                transport.send(message).await?;
+            }
+            Ok(())
+        });
+        sim.client("client", async {
+            let mut transport =
+                turmoil::connect(("server", 1234), SymmetricalJson::<String>::default).await?;
+            transport.send(String::from("test")).await?;
+            assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+            assert_matches!(transport.next().await, None);
+            Ok(())
+        });
+        sim.run()
+    }
+
     #[cfg(feature = "tcp")]
     #[tokio::test]
     async fn tcp_on_existing_transport() -> io::Result<()> {
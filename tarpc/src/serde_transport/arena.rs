@@ -0,0 +1,319 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides string and byte types that a transport deserializes into a per-frame arena.
+//!
+//! Deserializing a request with many `String` fields makes one allocation per field, which adds up
+//! for servers handling many small requests. A transport in arena mode, enabled with
+//! [`Transport::with_arena`](super::Transport::with_arena), uses each frame it reads as an arena.
+//! Fields of type [`ArenaStr`] and [`ArenaBytes`] that the codec can borrow from the frame are
+//! deserialized as reference-counted slices of the frame instead of being allocated one by one.
+//!
+//! The frame is freed once the last slice into it is dropped, so a slice that outlives its
+//! request keeps the whole frame alive, however small the slice. Convert such fields to owned
+//! values, e.g. with `String::from(&*field)`, if they are kept long after the request completes.
+//!
+//! Whether a field can be borrowed depends on the codec: the bincode and CBOR codecs borrow all
+//! strings and bytes, while the JSON and MessagePack codecs read frames through a reader and never
+//! borrow. Fields that cannot be borrowed, and fields deserialized outside an arena-mode
+//! transport, are allocated individually.
+//!
+//! ```rust
+//! use tarpc::serde_transport::arena::ArenaStr;
+//!
+//! #[tarpc::service]
+//! trait Index {
+//!     async fn insert(key: ArenaStr, value: ArenaStr);
+//! }
+//! ```
+
+use bytes::{Bytes, BytesMut};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{borrow::Borrow, cell::RefCell, fmt, ops::Deref, str, sync::Arc};
+
+thread_local! {
+    static ARENA: RefCell<Option<Arena>> = RefCell::new(None);
+}
+
+/// The frame being deserialized.
+struct Arena(Bytes);
+
+impl Arena {
+    /// Returns the slice of the arena that corresponds to `borrowed`, if it was borrowed from
+    /// the frame.
+    fn slice(&self, borrowed: &[u8]) -> Option<Bytes> {
+        let start = (borrowed.as_ptr() as usize).checked_sub(self.0.as_ptr() as usize)?;
+        let end = start + borrowed.len();
+        (end <= self.0.len()).then(|| self.0.slice(start..end))
+    }
+}
+
+/// Shares a frame between the codec, which reads it as a `BytesMut`, and the arena.
+struct Frame(Arc<BytesMut>);
+
+impl AsRef<[u8]> for Frame {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Runs `deserialize` on `frame` with `frame` as the arena for the [`ArenaStr`] and
+/// [`ArenaBytes`] it deserializes. Returns the frame, frozen without copying it, along with the
+/// result.
+pub(super) fn deserialize_in_arena<T>(
+    frame: BytesMut,
+    deserialize: impl FnOnce(&BytesMut) -> T,
+) -> (Bytes, T) {
+    struct Restore(Option<Arena>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            ARENA.with(|arena| *arena.borrow_mut() = self.0.take());
+        }
+    }
+
+    let frame = Arc::new(frame);
+    let arena = Bytes::from_owner(Frame(frame.clone()));
+    let _restore =
+        Restore(ARENA.with(|current| current.borrow_mut().replace(Arena(arena.clone()))));
+    let item = deserialize(&frame);
+    (arena, item)
+}
+
+/// Returns `borrowed` as a slice of the current arena, or copies it if it is not in the arena.
+fn from_arena(borrowed: &[u8]) -> Bytes {
+    ARENA
+        .with(|arena| {
+            arena
+                .borrow()
+                .as_ref()
+                .and_then(|arena| arena.slice(borrowed))
+        })
+        .unwrap_or_else(|| Bytes::copy_from_slice(borrowed))
+}
+
+/// An immutable string that is deserialized as a slice of the frame's arena when possible. See
+/// the [module documentation](self).
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArenaStr(Bytes);
+
+impl ArenaStr {
+    /// Returns the string as a slice.
+    pub fn as_str(&self) -> &str {
+        // SAFETY: every ArenaStr is created from a str.
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// Returns the underlying bytes, which may share their allocation with other slices of the
+    /// same arena.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl Deref for ArenaStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for ArenaStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for ArenaStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<String> for ArenaStr {
+    fn from(s: String) -> Self {
+        Self(s.into())
+    }
+}
+
+impl From<&'static str> for ArenaStr {
+    fn from(s: &'static str) -> Self {
+        Self(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+impl fmt::Debug for ArenaStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for ArenaStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for ArenaStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ArenaStr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = ArenaStr;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_str<E>(self, s: &'de str) -> Result<ArenaStr, E> {
+                Ok(ArenaStr(from_arena(s.as_bytes())))
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<ArenaStr, E> {
+                Ok(ArenaStr(Bytes::copy_from_slice(s.as_bytes())))
+            }
+
+            fn visit_string<E>(self, s: String) -> Result<ArenaStr, E> {
+                Ok(s.into())
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+/// An immutable byte string that is deserialized as a slice of the frame's arena when possible.
+/// See the [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArenaBytes(Bytes);
+
+impl ArenaBytes {
+    /// Returns the underlying bytes, which may share their allocation with other slices of the
+    /// same arena.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl Deref for ArenaBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for ArenaBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for ArenaBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl From<Bytes> for ArenaBytes {
+    fn from(bytes: Bytes) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<ArenaBytes> for Bytes {
+    fn from(bytes: ArenaBytes) -> Self {
+        bytes.0
+    }
+}
+
+impl Serialize for ArenaBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ArenaBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = ArenaBytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a byte string")
+            }
+
+            fn visit_borrowed_bytes<E>(self, bytes: &'de [u8]) -> Result<ArenaBytes, E> {
+                Ok(ArenaBytes(from_arena(bytes)))
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<ArenaBytes, E> {
+                Ok(ArenaBytes(Bytes::copy_from_slice(bytes)))
+            }
+
+            fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<ArenaBytes, E> {
+                Ok(bytes.into())
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<ArenaBytes, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes.into())
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deserialize_in_arena, ArenaBytes, ArenaStr};
+    use bytes::BytesMut;
+
+    #[test]
+    fn borrowed_fields_share_the_arena() {
+        let value = (ArenaStr::from("hello"), ArenaBytes::from(vec![1, 2, 3]));
+        let frame = BytesMut::from(&bincode::serialize(&value).unwrap()[..]);
+        let address = frame.as_ptr_range();
+
+        let (frame, (s, bytes)): (_, (ArenaStr, ArenaBytes)) =
+            deserialize_in_arena(frame, |frame| bincode::deserialize(frame).unwrap());
+        assert_eq!((&s, &bytes), (&value.0, &value.1));
+        // Both are slices of the frame itself: the string, then the bytes' length prefix, then
+        // the bytes.
+        assert_eq!(frame.as_ptr_range(), address);
+        assert!(address.contains(&s.as_bytes().as_ptr()));
+        assert_eq!(
+            bytes.as_bytes().as_ptr() as usize - s.as_bytes().as_ptr() as usize,
+            "hello".len() + 8
+        );
+    }
+
+    #[test]
+    fn unborrowable_fields_are_copied() {
+        let json = br#"["plain", "esc\"aped", [1, 2]]"#;
+        let frame = BytesMut::from(&json[..]);
+        let (_, (plain, escaped, bytes)): (_, (ArenaStr, ArenaStr, ArenaBytes)) =
+            deserialize_in_arena(frame, |frame| serde_json::from_slice(frame).unwrap());
+        assert_eq!(&*plain, "plain");
+        assert_eq!(&*escaped, "esc\"aped");
+        assert_eq!(&*bytes, [1, 2]);
+
+        let outside: ArenaStr = serde_json::from_slice(br#""plain""#).unwrap();
+        assert_eq!(outside, plain);
+    }
+}
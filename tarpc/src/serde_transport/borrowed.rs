@@ -0,0 +1,474 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a server transport whose requests borrow their fields from the frame they were read
+//! from.
+//!
+//! Servers usually deserialize requests into owned types, which allocates a `String` or `Vec<u8>`
+//! per field. A [`BorrowingTransport`] instead deserializes each request into a type with borrowed
+//! fields, such as `&'a str` and `&'a [u8]`, and hands it to the server as a [`Borrowed`] that
+//! keeps the frame alive for as long as the request is.
+//!
+//! Request types with borrowed fields are described to the transport by a [`Family`], which names
+//! the request type for every lifetime. Only codecs that implement [`BorrowingDeserializer`] can
+//! be used: the bincode codec borrows all strings and bytes, and the JSON codec borrows strings
+//! without escape sequences. Deserializing a borrowed field that the codec cannot borrow fails
+//! with a [`MalformedFrame`](crate::transport::MalformedFrame) error. The transport ignores the
+//! codec's `Item` type, so it may be `()`.
+//!
+//! ```rust
+//! # #[cfg(feature = "serde-transport-bincode")]
+//! # mod example {
+//! use serde::Deserialize;
+//! use tarpc::{
+//!     server::{self, Channel},
+//!     serde_transport::borrowed::{Borrowed, BorrowingTransport, Family},
+//!     tokio_serde::formats::Bincode,
+//!     Response,
+//! };
+//!
+//! #[derive(Deserialize)]
+//! struct Put<'a> {
+//!     key: &'a str,
+//!     #[serde(with = "serde_bytes")]
+//!     value: &'a [u8],
+//! }
+//!
+//! struct PutFamily;
+//!
+//! // SAFETY: `Put` is covariant in its lifetime.
+//! unsafe impl Family for PutFamily {
+//!     type Of<'a> = Put<'a>;
+//!
+//!     fn shorten<'a, 'b: 'a>(long: Put<'b>) -> Put<'a> {
+//!         long
+//!     }
+//! }
+//!
+//! fn serve(io: tokio::io::DuplexStream) -> impl futures::Stream {
+//!     let transport = BorrowingTransport::<_, PutFamily, Response<usize>, _>::from((
+//!         io,
+//!         Bincode::<(), _>::default(),
+//!     ));
+//!     server::BaseChannel::with_defaults(transport).execute(server::serve(
+//!         |_, put: Borrowed<PutFamily>| async move {
+//!             let put = put.get();
+//!             Ok(put.key.len() + put.value.len())
+//!         },
+//!     ))
+//! }
+//! # }
+//! ```
+
+use super::{payload_log, Transport};
+use crate::{ClientMessage, Request};
+use bytes::Bytes;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, io, marker::PhantomData, mem::ManuallyDrop, pin::Pin, ptr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_serde::Serializer;
+use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Framed};
+
+/// Names a request type with borrowed fields for every lifetime of the frame it borrows from.
+///
+/// # Safety
+///
+/// [`Of`](Self::Of) must be covariant in its lifetime: a `Self::Of<'b>` must be usable as a
+/// `Self::Of<'a>` for any `'b: 'a`, as for structs whose fields are `&'a str`, `&'a [u8]`, or
+/// other covariant types. [`Borrowed::get`] hands out the request with a shortened lifetime, which
+/// is undefined behavior for invariant types such as `Cell<&'a str>`.
+pub unsafe trait Family: 'static {
+    /// The request type borrowing from a frame that lives for `'a`.
+    type Of<'a>: Deserialize<'a>;
+
+    /// Shortens the lifetime of a request. Implementations must return `long` unchanged. The
+    /// function only compiles as `long` if [`Of`](Self::Of) is covariant in its lifetime, which
+    /// helps check the safety contract of the trait.
+    fn shorten<'a, 'b: 'a>(long: Self::Of<'b>) -> Self::Of<'a>;
+}
+
+/// A request that borrows its fields from the frame it was read from, which it keeps alive.
+pub struct Borrowed<F: Family> {
+    // Declared before `frame` so that it is dropped first.
+    value: F::Of<'static>,
+    frame: Bytes,
+}
+
+impl<F: Family> Borrowed<F> {
+    /// Attaches `value` to the frame it borrows from.
+    ///
+    /// # Safety
+    ///
+    /// All borrows in `value` must point into `frame`.
+    unsafe fn new(frame: Bytes, value: F::Of<'_>) -> Self {
+        let value = ManuallyDrop::new(value);
+        Self {
+            // SAFETY: the frame's buffer is kept alive, and never moved or mutated, for as long as
+            // `value` is, and `value` is only handed out with lifetimes bounded by `self`.
+            value: unsafe { ptr::read((&*value as *const F::Of<'_>).cast::<F::Of<'static>>()) },
+            frame,
+        }
+    }
+
+    /// Returns the request.
+    pub fn get(&self) -> &F::Of<'_> {
+        let value = (&self.value as *const F::Of<'static>).cast::<F::Of<'_>>();
+        // SAFETY: implementors of `Family` guarantee that `F::Of` is covariant in its lifetime,
+        // so the value may be viewed with the shorter lifetime of `self`.
+        unsafe { &*value }
+    }
+
+    /// Returns the frame the request borrows from.
+    pub fn frame(&self) -> &Bytes {
+        &self.frame
+    }
+}
+
+impl<F: Family> fmt::Debug for Borrowed<F>
+where
+    for<'a> F::Of<'a>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.get(), f)
+    }
+}
+
+/// A codec that can deserialize values borrowing from the frame.
+pub trait BorrowingDeserializer {
+    /// The error returned when a frame cannot be deserialized.
+    type Error: Into<Box<dyn Error + Send + Sync>>;
+
+    /// Deserializes a value that may borrow from `frame`.
+    fn deserialize_borrowed<'a, T: Deserialize<'a>>(
+        &self,
+        frame: &'a [u8],
+    ) -> Result<T, Self::Error>;
+}
+
+#[cfg(feature = "serde-transport-bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-bincode")))]
+impl<Item, SinkItem> BorrowingDeserializer for tokio_serde::formats::Bincode<Item, SinkItem> {
+    type Error = bincode::Error;
+
+    fn deserialize_borrowed<'a, T: Deserialize<'a>>(&self, frame: &'a [u8]) -> bincode::Result<T> {
+        use bincode::Options;
+
+        bincode::DefaultOptions::new().deserialize(frame)
+    }
+}
+
+#[cfg(feature = "serde-transport-json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-json")))]
+impl<Item, SinkItem> BorrowingDeserializer for tokio_serde::formats::Json<Item, SinkItem> {
+    type Error = serde_json::Error;
+
+    fn deserialize_borrowed<'a, T: Deserialize<'a>>(
+        &self,
+        frame: &'a [u8],
+    ) -> serde_json::Result<T> {
+        serde_json::from_slice(frame)
+    }
+}
+
+/// A server transport that reads requests of type [`Borrowed<F>`] and writes responses of type
+/// `SinkItem`.
+#[pin_project]
+pub struct BorrowingTransport<S, F: Family, SinkItem, Codec> {
+    #[pin]
+    inner: Transport<S, ClientMessage<Borrowed<F>>, SinkItem, Codec>,
+    ghost: PhantomData<fn() -> F>,
+}
+
+impl<S, F: Family, SinkItem, Codec> BorrowingTransport<S, F, SinkItem, Codec> {
+    /// Returns the inner transport over which messages are sent and received.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Decodes the request ID of each frame that fails to deserialize with `recover`. See
+    /// [`Transport::with_request_id_recovery`].
+    pub fn with_request_id_recovery(mut self, recover: fn(&[u8]) -> Option<u64>) -> Self {
+        self.inner = self.inner.with_request_id_recovery(recover);
+        self
+    }
+}
+
+/// Constructs a new borrowing transport from a framed transport and a serialization codec.
+pub fn new<S, F, SinkItem, Codec>(
+    framed_io: Framed<S, LengthDelimitedCodec>,
+    codec: Codec,
+) -> BorrowingTransport<S, F, SinkItem, Codec>
+where
+    S: AsyncWrite + AsyncRead,
+    F: Family,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + BorrowingDeserializer,
+{
+    BorrowingTransport {
+        inner: Transport {
+            inner: framed_io,
+            codec,
+            arena: false,
+            stats: None,
+            payload_log: None,
+            capture: None,
+            chunker: None,
+            recover_request_id: None,
+            blocked_since: None,
+            ghost: PhantomData,
+        },
+        ghost: PhantomData,
+    }
+}
+
+impl<S, F, SinkItem, Codec> From<(S, Codec)> for BorrowingTransport<S, F, SinkItem, Codec>
+where
+    S: AsyncWrite + AsyncRead,
+    F: Family,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + BorrowingDeserializer,
+{
+    fn from((io, codec): (S, Codec)) -> Self {
+        new(Framed::new(io, LengthDelimitedCodec::new()), codec)
+    }
+}
+
+/// Reuses the framing, codec, and configuration of a transport created by one of the
+/// [`serde_transport`](super) listeners. The [arena](Transport::with_arena) setting carries over
+/// but has no effect, as borrowed requests share their frame anyway.
+impl<S, Item, F, SinkItem, Codec> From<Transport<S, Item, SinkItem, Codec>>
+    for BorrowingTransport<S, F, SinkItem, Codec>
+where
+    F: Family,
+    Codec: BorrowingDeserializer,
+{
+    fn from(transport: Transport<S, Item, SinkItem, Codec>) -> Self {
+        BorrowingTransport {
+            inner: Transport {
+                inner: transport.inner,
+                codec: transport.codec,
+                arena: transport.arena,
+                stats: transport.stats,
+                payload_log: transport
+                    .payload_log
+                    .map(payload_log::Logger::with_read_item),
+                capture: transport.capture,
+                chunker: transport.chunker,
+                recover_request_id: transport.recover_request_id,
+                blocked_since: transport.blocked_since,
+                ghost: PhantomData,
+            },
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<S, F, SinkItem, Codec> Stream for BorrowingTransport<S, F, SinkItem, Codec>
+where
+    S: AsyncWrite + AsyncRead,
+    F: Family,
+    Codec: BorrowingDeserializer,
+{
+    type Item = io::Result<ClientMessage<Borrowed<F>>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<Borrowed<F>>>>> {
+        let inner = self.project().inner.project();
+        let frame = match ready!(super::poll_read_frame(
+            inner.inner,
+            inner.chunker,
+            inner.stats,
+            inner.capture,
+            cx
+        )) {
+            Some(Ok(frame)) => frame.freeze(),
+            Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+            None => return Poll::Ready(None),
+        };
+        let message = match inner.codec.deserialize_borrowed(&frame) {
+            Ok(message) => message,
+            Err(e) => {
+                let malformed = super::malformed_frame(e, &frame, *inner.recover_request_id);
+                if let Some(payload_log) = inner.payload_log {
+                    payload_log.malformed(&frame, &malformed);
+                }
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    malformed,
+                ))));
+            }
+        };
+        let message = match message {
+            ClientMessage::Request(Request {
+                context,
+                id,
+                message,
+                one_way,
+                streamed,
+            }) => ClientMessage::Request(Request {
+                context,
+                id,
+                // SAFETY: the message was deserialized from `frame`.
+                message: unsafe { Borrowed::new(frame.clone(), message) },
+                one_way,
+                streamed,
+            }),
+            ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            },
+            ClientMessage::Close { reason } => ClientMessage::Close { reason },
+            ClientMessage::Item { request_id, item } => ClientMessage::Item {
+                request_id,
+                // SAFETY: the item was deserialized from `frame`.
+                item: item.map(|item| unsafe { Borrowed::new(frame.clone(), item) }),
+            },
+        };
+        if let Some(payload_log) = inner.payload_log {
+            payload_log.read(&frame, &message);
+        }
+        Poll::Ready(Some(Ok(message)))
+    }
+}
+
+impl<S, F, SinkItem, Codec> Sink<SinkItem> for BorrowingTransport<S, F, SinkItem, Codec>
+where
+    S: AsyncWrite,
+    F: Family,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(all(test, feature = "serde-transport-bincode"))]
+mod tests {
+    use super::{Borrowed, BorrowingTransport, Family};
+    use crate::{
+        client, context, serde_transport,
+        server::{self, Channel},
+        ClientMessage, Response,
+    };
+    use futures::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use tokio_serde::formats::Bincode;
+
+    #[derive(Serialize)]
+    struct OwnedPut {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Put<'a> {
+        key: &'a str,
+        #[serde(with = "serde_bytes")]
+        value: &'a [u8],
+    }
+
+    struct PutFamily;
+
+    // SAFETY: `Put` is covariant in its lifetime.
+    unsafe impl Family for PutFamily {
+        type Of<'a> = Put<'a>;
+
+        fn shorten<'a, 'b: 'a>(long: Put<'b>) -> Put<'a> {
+            long
+        }
+    }
+
+    #[tokio::test]
+    async fn server_borrows_request_fields() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let transport = BorrowingTransport::<_, PutFamily, Response<usize>, _>::from((
+            server_io,
+            Bincode::<(), _>::default(),
+        ));
+        tokio::spawn(
+            server::BaseChannel::with_defaults(transport)
+                .execute(server::serve(|_, put: Borrowed<PutFamily>| async move {
+                    let frame = put.frame().as_ptr_range();
+                    let Put { key, value } = put.get();
+                    assert!(frame.contains(&key.as_ptr()));
+                    assert!(frame.contains(&value.as_ptr()));
+                    Ok(key.len() + value.len())
+                }))
+                .for_each(|response| response),
+        );
+
+        let transport = serde_transport::Transport::from((
+            client_io,
+            Bincode::<Response<usize>, ClientMessage<OwnedPut>>::default(),
+        ));
+        let client = client::new(client::Config::default(), transport).spawn();
+        let put = OwnedPut {
+            key: "key".into(),
+            value: vec![1, 2, 3, 4],
+        };
+        assert_eq!(
+            client.call(context::current(), "put", put).await.unwrap(),
+            7
+        );
+    }
+
+    #[cfg(feature = "serde-transport-json")]
+    #[tokio::test]
+    async fn unborrowable_field_is_malformed_frame() {
+        use crate::transport::MalformedFrame;
+        use tokio_serde::formats::Json;
+
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let mut transport = BorrowingTransport::<_, PutFamily, Response<usize>, _>::from((
+            server_io,
+            Json::<(), _>::default(),
+        ));
+        let mut client = serde_transport::Transport::from((
+            client_io,
+            Json::<Response<usize>, ClientMessage<OwnedPut>>::default(),
+        ));
+        let put = OwnedPut {
+            key: "esc\"aped".into(),
+            value: vec![],
+        };
+        client
+            .send(ClientMessage::Request(crate::Request {
+                context: context::current(),
+                id: 0,
+                message: put,
+                one_way: false,
+                streamed: false,
+            }))
+            .await
+            .unwrap();
+        let error = transport.next().await.unwrap().unwrap_err();
+        assert!(MalformedFrame::find(&error).is_some());
+    }
+}
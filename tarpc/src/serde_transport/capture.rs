@@ -0,0 +1,392 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a capture of the frames that transports read and write, for offline inspection.
+//!
+//! Debugging a protocol-level problem, such as a peer that sends frames out of order or encodes
+//! them unexpectedly, often calls for the exact bytes exchanged, which logs truncate and
+//! interleave with everything else. A [`Capture`], attached with
+//! [`Transport::with_capture`](super::Transport::with_capture), writes every frame of the
+//! transports it is attached to into a file, much like a packet capture. Attaching it to a
+//! transport costs little while it is [disabled](Capture::disable); it can be enabled and
+//! disabled at runtime, e.g. from an admin endpoint, so that capturing a misbehaving connection
+//! requires neither code changes nor a rebuild. The frames are written as they are read or
+//! written, with blocking I/O, so capture is meant for debugging, not for production traffic.
+//!
+//! A capture is read back with [`read`], and [`pretty_print`] prints it one frame per line.
+//!
+//! # Format
+//!
+//! A capture begins with the 8 bytes `TARPCCAP`, followed by a version byte, currently 1. Then
+//! follows one record per frame, with integers in little-endian byte order:
+//!
+//! | Field | Size | Meaning |
+//! |-------|------|---------|
+//! | connection | 8 bytes | The connection the frame belongs to, numbered from 0 in the order transports were attached. |
+//! | direction | 1 byte | 0 if the frame was read, 1 if it was written. |
+//! | timestamp | 8 bytes | When the frame was captured, in microseconds since the Unix epoch. |
+//! | length | 4 bytes | The length of the frame. |
+//! | frame | `length` bytes | The frame, as serialized by the transport's codec, without its length prefix. |
+//!
+//! ```rust
+//! # use tarpc::serde_transport::{self, capture::{self, Capture}};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # fn wrap(io: tokio::io::DuplexStream) -> std::io::Result<()> {
+//! let capture = Capture::create("tarpc.cap")?;
+//! let transport = serde_transport::Transport::<_, String, String, _>::from((io, Json::default()))
+//!     .with_capture(capture.clone());
+//! // ... later, e.g. from an admin endpoint:
+//! capture.enable();
+//! // ... and offline:
+//! let file = std::fs::File::open("tarpc.cap")?;
+//! capture::pretty_print(std::io::BufReader::new(file), std::io::stdout().lock())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use super::payload_log::Escaped;
+use crate::util::tracing;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// The bytes a capture begins with.
+const MAGIC: &[u8; 8] = b"TARPCCAP";
+
+/// The version of the format written.
+const VERSION: u8 = 1;
+
+/// The length of a record before its frame.
+const RECORD_HEADER_LEN: usize = 8 + 1 + 8 + 4;
+
+/// Whether a captured frame was read or written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The transport read the frame.
+    Read,
+    /// The transport wrote the frame.
+    Written,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Read => write!(f, "<-"),
+            Direction::Written => write!(f, "->"),
+        }
+    }
+}
+
+/// A frame read back from a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Record {
+    /// The connection the frame belongs to.
+    pub connection: u64,
+    /// Whether the frame was read or written.
+    pub direction: Direction,
+    /// When the frame was captured.
+    pub timestamp: SystemTime,
+    /// The frame, as serialized by the transport's codec.
+    pub frame: Vec<u8>,
+}
+
+/// Displays the record on one line, with the frame's non-printable bytes escaped.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:06} #{} {} {} bytes: {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            self.connection,
+            self.direction,
+            self.frame.len(),
+            Escaped(&self.frame)
+        )
+    }
+}
+
+struct Shared {
+    writer: Mutex<Box<dyn Write + Send>>,
+    enabled: AtomicBool,
+    next_connection: AtomicU64,
+}
+
+/// Writes the frames of the transports it is attached to into one capture. Cloning the capture
+/// produces a handle to the same capture. Captures start disabled.
+#[derive(Clone)]
+pub struct Capture {
+    shared: Arc<Shared>,
+}
+
+impl Capture {
+    /// Returns a capture written to `writer`, after writing the capture's header to it.
+    pub fn new(mut writer: impl Write + Send + 'static) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.flush()?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                writer: Mutex::new(Box::new(writer)),
+                enabled: AtomicBool::new(false),
+                next_connection: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Returns a capture written to the file at `path`, which is created or truncated.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Starts capturing frames.
+    pub fn enable(&self) {
+        self.shared.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops capturing frames. Frames already captured remain in the capture.
+    pub fn disable(&self) {
+        self.shared.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns whether frames are being captured.
+    pub fn is_enabled(&self) -> bool {
+        self.shared.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the handle through which one transport writes to the capture.
+    pub(super) fn connection(&self) -> Tap {
+        Tap {
+            capture: self.clone(),
+            connection: self.shared.next_connection.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn write(&self, connection: u64, direction: Direction, frame: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Ok(len) = u32::try_from(frame.len()) else {
+            tracing::warn!(len = frame.len(), "CaptureSkippedFrame: frame too long");
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let timestamp = u64::try_from(timestamp.as_micros()).unwrap_or(u64::MAX);
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + frame.len());
+        record.extend_from_slice(&connection.to_le_bytes());
+        record.push(match direction {
+            Direction::Read => 0,
+            Direction::Written => 1,
+        });
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(frame);
+
+        let mut writer = self.shared.writer.lock().unwrap();
+        if let Err(e) = writer.write_all(&record).and_then(|()| writer.flush()) {
+            // Keep a failing capture from failing again on every frame.
+            self.disable();
+            tracing::warn!(error = %e, "CaptureFailed: capture disabled");
+        }
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("enabled", &self.is_enabled())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Writes the frames of one transport to a capture.
+#[derive(Debug)]
+pub(super) struct Tap {
+    capture: Capture,
+    connection: u64,
+}
+
+impl Tap {
+    pub(super) fn read(&self, frame: &[u8]) {
+        self.capture.write(self.connection, Direction::Read, frame);
+    }
+
+    pub(super) fn written(&self, frame: &[u8]) {
+        self.capture
+            .write(self.connection, Direction::Written, frame);
+    }
+}
+
+/// Reads the records of the capture that `reader` yields, after checking its header.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Records<R>> {
+    let mut header = [0; MAGIC.len() + 1];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a tarpc capture",
+        ));
+    }
+    if header[MAGIC.len()] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported capture version {}", header[MAGIC.len()]),
+        ));
+    }
+    Ok(Records { reader })
+}
+
+/// Prints the records of the capture that `reader` yields to `writer`, one per line.
+pub fn pretty_print(reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+    for record in read(reader)? {
+        writeln!(writer, "{}", record?)?;
+    }
+    Ok(())
+}
+
+/// The records of a capture. Created by [`read`].
+#[derive(Debug)]
+pub struct Records<R> {
+    reader: R,
+}
+
+impl<R: Read> Records<R> {
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        // A capture may end only between records.
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let connection = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Read,
+            1 => Direction::Written,
+            direction => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {direction}"),
+                ))
+            }
+        };
+        let timestamp = u64::from_le_bytes(header[9..17].try_into().unwrap());
+        let len = u32::from_le_bytes(header[17..21].try_into().unwrap());
+        let mut frame = vec![0; len as usize];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some(Record {
+            connection,
+            direction,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_micros(timestamp),
+            frame,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, Capture, Direction};
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    /// A writer whose bytes can be inspected while the capture holds it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn captures_frames_of_each_connection_while_enabled() {
+        let written = Shared::default();
+        let capture = Capture::new(written.clone()).unwrap();
+        let first = capture.connection();
+        let second = capture.connection();
+
+        first.written(b"dropped");
+        capture.enable();
+        first.written(b"ping");
+        second.read(b"\x00pong");
+        capture.disable();
+        second.read(b"dropped");
+
+        let bytes = written.0.lock().unwrap().clone();
+        let records: Vec<_> = read(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (
+                records[0].connection,
+                records[0].direction,
+                &records[0].frame[..]
+            ),
+            (0, Direction::Written, &b"ping"[..])
+        );
+        assert_eq!(
+            (
+                records[1].connection,
+                records[1].direction,
+                &records[1].frame[..]
+            ),
+            (1, Direction::Read, &b"\x00pong"[..])
+        );
+        assert!(records[1]
+            .to_string()
+            .ends_with(" #1 <- 5 bytes: \\x00pong"));
+
+        // A truncated record is an error, not the end of the capture.
+        let truncated = &bytes[..bytes.len() - 1];
+        let records: Vec<_> = read(truncated).unwrap().collect();
+        assert_eq!(
+            records[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn read_rejects_other_files() {
+        assert_eq!(
+            read(&b"PCAPFILE\x01"[..]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides chunking, which splits serialized messages longer than a threshold into several
+//! frames, and reassembles them on the other side.
+//!
+//! A [`Transport`](super::Transport) writes each message as one frame, so a single huge message
+//! can exceed the frame limit of the
+//! [`LengthDelimitedCodec`](tokio_util::codec::LengthDelimitedCodec), 8 MiB by default, and is
+//! buffered whole on its way to the I/O. A transport
+//! [with chunking](super::Transport::with_chunking) instead writes a message longer than
+//! [`Chunking::new`]'s `chunk_len` as a sequence of frames, each carrying a chunk of at most
+//! `chunk_len` bytes. The chunks of a message are written back to back, so messages still arrive
+//! in the order they were sent.
+//!
+//! Both ends of a connection must enable chunking, since it prefixes every frame with a one-byte
+//! header:
+//!
+//! | Header | Frame                                                 |
+//! |--------|-------------------------------------------------------|
+//! | 0      | A whole message.                                      |
+//! | 1      | A chunk of a message, followed by more chunks.        |
+//! | 2      | The last chunk of a message.                          |
+//!
+//! Stats, payload logs, and captures see whole messages rather than chunks.
+//!
+//! ```rust
+//! # use tarpc::serde_transport::{self, chunking::Chunking};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # use tarpc::tokio_util::codec::LengthDelimitedCodec;
+//! # fn wrap(io: tokio::io::DuplexStream) {
+//! let transport = serde_transport::new::<_, String, String, _>(
+//!     LengthDelimitedCodec::builder().new_framed(io),
+//!     Json::default(),
+//! )
+//! .with_chunking(Chunking::new(1 << 20).with_max_message_len(1 << 30));
+//! # }
+//! ```
+
+use crate::transport::MalformedFrame;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{collections::VecDeque, io};
+
+const WHOLE: u8 = 0;
+const CHUNK: u8 = 1;
+const LAST_CHUNK: u8 = 2;
+
+/// How a transport splits long messages into chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunking {
+    chunk_len: usize,
+    max_message_len: Option<usize>,
+}
+
+impl Chunking {
+    /// Splits messages longer than `chunk_len` bytes into chunks of at most `chunk_len` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_len` is zero.
+    pub fn new(chunk_len: usize) -> Self {
+        assert!(chunk_len > 0, "chunk_len must be positive");
+        Self {
+            chunk_len,
+            max_message_len: None,
+        }
+    }
+
+    /// Rejects messages read that reassemble to more than `max_message_len` bytes, as malformed
+    /// frames, rather than buffering them. Unlimited by default.
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = Some(max_message_len);
+        self
+    }
+
+    /// Returns the longest message that is written as one frame.
+    pub fn chunk_len(&self) -> usize {
+        self.chunk_len
+    }
+
+    /// Returns the longest message that is reassembled, if limited.
+    pub fn max_message_len(&self) -> Option<usize> {
+        self.max_message_len
+    }
+}
+
+/// Splits the messages a transport writes, and reassembles the messages it reads.
+#[derive(Debug)]
+pub(super) struct Chunker {
+    config: Chunking,
+    /// The chunks of the last message written that are yet to be written.
+    pending: VecDeque<Bytes>,
+    /// The chunks of the message being read.
+    partial: BytesMut,
+    /// Whether the rest of the message being read is skipped, because it is too long.
+    skipping: bool,
+}
+
+impl Chunker {
+    pub(super) fn new(config: Chunking) -> Self {
+        Self {
+            config,
+            pending: VecDeque::new(),
+            partial: BytesMut::new(),
+            skipping: false,
+        }
+    }
+
+    /// Splits `message` into frames, returning the first and keeping the rest pending.
+    pub(super) fn split(&mut self, message: Bytes) -> Bytes {
+        if message.len() <= self.config.chunk_len {
+            return frame(WHOLE, &message);
+        }
+        let mut chunks = message.chunks(self.config.chunk_len).peekable();
+        while let Some(chunk) = chunks.next() {
+            let header = if chunks.peek().is_some() {
+                CHUNK
+            } else {
+                LAST_CHUNK
+            };
+            self.pending.push_back(frame(header, chunk));
+        }
+        self.pending.pop_front().unwrap()
+    }
+
+    /// Returns the next frame to write, if chunks are pending.
+    pub(super) fn next_pending(&mut self) -> Option<Bytes> {
+        self.pending.pop_front()
+    }
+
+    /// Reassembles the message that `frame` completes, if any.
+    pub(super) fn reassemble(&mut self, mut frame: BytesMut) -> io::Result<Option<BytesMut>> {
+        if frame.is_empty() {
+            return Err(malformed("frame has no chunk header"));
+        }
+        let header = frame.get_u8();
+        match header {
+            WHOLE if self.partial.is_empty() && !self.skipping => Ok(Some(frame)),
+            WHOLE => {
+                self.partial.clear();
+                self.skipping = false;
+                Err(malformed("message ended without its last chunk"))
+            }
+            CHUNK | LAST_CHUNK => {
+                let last = header == LAST_CHUNK;
+                if self.skipping {
+                    self.skipping = !last;
+                    return Ok(None);
+                }
+                let len = self.partial.len() + frame.len();
+                if self.config.max_message_len.map_or(false, |max| len > max) {
+                    self.partial = BytesMut::new();
+                    self.skipping = !last;
+                    return Err(malformed("chunked message is too long"));
+                }
+                self.partial.unsplit(frame);
+                Ok(last.then(|| self.partial.split()))
+            }
+            _ => Err(malformed("unknown chunk header")),
+        }
+    }
+}
+
+fn frame(header: u8, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(1 + payload.len());
+    frame.put_u8(header);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+fn malformed(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, MalformedFrame::new(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunker, Chunking};
+    use bytes::{Bytes, BytesMut};
+    use std::io;
+
+    fn write_all(chunker: &mut Chunker, message: &'static [u8]) -> Vec<Bytes> {
+        let first = chunker.split(Bytes::from_static(message));
+        std::iter::once(first)
+            .chain(std::iter::from_fn(|| chunker.next_pending()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_and_reassembles_long_messages() {
+        let mut writer = Chunker::new(Chunking::new(4));
+        let mut reader = Chunker::new(Chunking::new(4));
+
+        let frames = write_all(&mut writer, b"0123456789");
+        assert_eq!(frames, ["\x010123", "\x014567", "\x0289"]);
+        let messages: Vec<_> = frames
+            .into_iter()
+            .map(|frame| reader.reassemble(BytesMut::from(&frame[..])).unwrap())
+            .collect();
+        assert_eq!(messages, [None, None, Some(BytesMut::from("0123456789"))]);
+
+        let frames = write_all(&mut writer, b"0123");
+        assert_eq!(frames, ["\x000123"]);
+        assert_eq!(
+            reader.reassemble(BytesMut::from(&frames[0][..])).unwrap(),
+            Some(BytesMut::from("0123"))
+        );
+    }
+
+    #[test]
+    fn skips_messages_that_are_too_long() {
+        let mut writer = Chunker::new(Chunking::new(2));
+        let mut reader = Chunker::new(Chunking::new(2).with_max_message_len(3));
+
+        let mut results = write_all(&mut writer, b"012345")
+            .into_iter()
+            .chain(write_all(&mut writer, b"ab"))
+            .map(|frame| reader.reassemble(BytesMut::from(&frame[..])));
+        assert_eq!(results.next().unwrap().unwrap(), None);
+        assert_eq!(
+            results.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(results.next().unwrap().unwrap(), None);
+        assert_eq!(results.next().unwrap().unwrap(), Some(BytesMut::from("ab")));
+    }
+}
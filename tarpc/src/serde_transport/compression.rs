@@ -0,0 +1,456 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides [`Compressed`], a codec that compresses the frames of another codec with zstd
+//! dictionaries negotiated per channel.
+//!
+//! Compressing each frame on its own barely helps services whose messages are small: a frame of a
+//! few hundred bytes holds too little repetition for the compressor to exploit. But such messages
+//! usually repeat each other's structure, e.g. the field names of a JSON object. A [`Dictionary`]
+//! trained on sample frames primes the compressor with that structure, so that even small frames
+//! shrink several times over.
+//!
+//! Both peers wrap their codec in a [`Compressed`] holding the [`Dictionaries`] they know. Each
+//! peer lists the IDs of its dictionaries in the first frame it writes, and once it has read the
+//! other's list, compresses with the first of its dictionaries that the other also has: the
+//! channel's dictionary. A [selector](Compressed::with_selector) can pick another shared
+//! dictionary for each message, e.g. one trained on the messages of its method. Frames written
+//! before the peer's list arrives, or when the peers share no dictionary, are compressed without
+//! one, and frames that compression does not shrink are written as is. Both peers must use
+//! [`Compressed`], since its frames carry a header that other codecs do not expect.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     serde_transport::{self, compression::{Compressed, Dictionaries, Dictionary}},
+//!     server::{self, BaseChannel, Channel},
+//! };
+//! use tokio_serde::formats::Json;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! // Trains a dictionary on frames like those the service sends.
+//! let samples = (0..1000)
+//!     .map(|i| format!(r#"{{"Request":{{"id":{i},"message":"user-{i}@example.com"}}}}"#))
+//!     .collect::<Vec<_>>();
+//! let dictionaries = Dictionaries::new().with(Dictionary::train(1, &samples, 4096)?);
+//!
+//! let (client_io, server_io) = tokio::io::duplex(1024);
+//! let codec = Compressed::new(Json::default(), dictionaries.clone());
+//! let server_transport = serde_transport::Transport::from((server_io, codec));
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server_transport)
+//!         .execute(server::serve(|_, email: String| async move { Ok(email.len()) }))
+//!         .for_each(|response| response),
+//! );
+//!
+//! let codec = Compressed::new(Json::default(), dictionaries);
+//! let client_transport = serde_transport::Transport::from((client_io, codec));
+//! let client: client::Channel<String, usize> =
+//!     client::new(client::Config::default(), client_transport).spawn();
+//! let email = "user-7@example.com".to_string();
+//! assert_eq!(client.call(context::current(), "Len", email).await?, 18);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use fnv::FnvHashMap;
+use pin_project::pin_project;
+use std::{error::Error, fmt, io, marker::PhantomData, pin::Pin, sync::Arc};
+use tokio_serde::{Deserializer, Serializer};
+use zstd::bulk::{Compressor, Decompressor};
+
+/// The frame body is the inner codec's frame, uncompressed.
+const RAW: u8 = 0;
+/// The frame body is compressed without a dictionary.
+const ZSTD: u8 = 1;
+/// The frame body is compressed with the dictionary whose ID follows the header byte.
+const ZSTD_DICTIONARY: u8 = 2;
+/// The mask of the header byte that holds how the body is compressed.
+const MODE_MASK: u8 = 0b11;
+/// The bit of the header byte that says the writer's dictionary IDs follow.
+const ADVERTISEMENT: u8 = 0b100;
+
+/// A zstd dictionary, identified by an ID that peers agree on.
+#[derive(Clone)]
+pub struct Dictionary {
+    id: u32,
+    bytes: Arc<[u8]>,
+}
+
+impl Dictionary {
+    /// Returns the dictionary `bytes`, e.g. as trained by the `zstd` command line tool, under
+    /// `id`. Peers must give the same dictionary the same ID.
+    pub fn new(id: u32, bytes: impl Into<Arc<[u8]>>) -> Self {
+        Self {
+            id,
+            bytes: bytes.into(),
+        }
+    }
+
+    /// Trains a dictionary of at most `max_size` bytes on `samples`, which should be frames
+    /// written by the inner codec, e.g. of the messages of one method. Fails if there are too few
+    /// samples to train on; zstd suggests about a hundred times as many bytes of samples as
+    /// `max_size`.
+    pub fn train<S: AsRef<[u8]>>(id: u32, samples: &[S], max_size: usize) -> io::Result<Self> {
+        Ok(Self::new(id, zstd::dict::from_samples(samples, max_size)?))
+    }
+
+    /// Returns the ID of the dictionary.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the contents of the dictionary, e.g. to distribute it to peers.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// The dictionaries a peer can compress with, in order of preference.
+#[derive(Clone, Debug, Default)]
+pub struct Dictionaries {
+    dictionaries: Vec<Dictionary>,
+}
+
+impl Dictionaries {
+    /// Returns an empty set of dictionaries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `dictionary`, preferring it less than the dictionaries already added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a dictionary with the same ID was already added.
+    pub fn with(mut self, dictionary: Dictionary) -> Self {
+        assert!(
+            self.get(dictionary.id).is_none(),
+            "dictionary {} was already added",
+            dictionary.id
+        );
+        self.dictionaries.push(dictionary);
+        self
+    }
+
+    /// Returns the dictionary with ID `id`, if any.
+    pub fn get(&self, id: u32) -> Option<&Dictionary> {
+        self.dictionaries
+            .iter()
+            .find(|dictionary| dictionary.id == id)
+    }
+
+    /// Returns the IDs of the dictionaries, in order of preference.
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.dictionaries.iter().map(Dictionary::id)
+    }
+}
+
+/// A codec that compresses the frames of `Codec` with the dictionaries it shares with its peer.
+/// See the [module docs](self).
+#[pin_project]
+pub struct Compressed<Codec, SinkItem> {
+    #[pin]
+    inner: Codec,
+    dictionaries: Dictionaries,
+    level: i32,
+    max_frame_len: usize,
+    selector: Option<fn(&SinkItem) -> Option<u32>>,
+    /// Whether the dictionary IDs were written to the peer.
+    advertised: bool,
+    /// The dictionary IDs of the peer, once read.
+    peer: Option<Vec<u32>>,
+    compressors: FnvHashMap<Option<u32>, Compressor<'static>>,
+    decompressors: FnvHashMap<Option<u32>, Decompressor<'static>>,
+    ghost: PhantomData<fn(&SinkItem)>,
+}
+
+impl<Codec, SinkItem> Compressed<Codec, SinkItem> {
+    /// Returns a codec that compresses the frames of `inner` with zstd's default level, using
+    /// whichever of `dictionaries` the peer also has.
+    pub fn new(inner: Codec, dictionaries: Dictionaries) -> Self {
+        Self {
+            inner,
+            dictionaries,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            max_frame_len: 8 * 1024 * 1024,
+            selector: None,
+            advertised: false,
+            peer: None,
+            compressors: FnvHashMap::default(),
+            decompressors: FnvHashMap::default(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Compresses at zstd level `level`.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Fails to read frames that decompress to more than `max_frame_len` bytes, which defaults to
+    /// 8 MiB, the default frame limit of
+    /// [`LengthDelimitedCodec`](tokio_util::codec::LengthDelimitedCodec).
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Compresses each message with the dictionary whose ID `selector` returns for it, if the
+    /// peer also has it, instead of the channel's dictionary.
+    pub fn with_selector(mut self, selector: fn(&SinkItem) -> Option<u32>) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// Returns the ID of the channel's dictionary: the first dictionary that the peer also has.
+    /// Returns `None` until the peer's dictionary IDs are read, or if the peers share none.
+    pub fn dictionary(&self) -> Option<u32> {
+        let peer = self.peer.as_ref()?;
+        self.dictionaries.ids().find(|id| peer.contains(id))
+    }
+
+    /// Returns the dictionary IDs of the peer, or `None` until they are read.
+    pub fn peer_dictionaries(&self) -> Option<&[u32]> {
+        self.peer.as_deref()
+    }
+}
+
+impl<Codec, SinkItem> fmt::Debug for Compressed<Codec, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compressed")
+            .field("dictionaries", &self.dictionaries)
+            .field("level", &self.level)
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid_frame(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<Codec, Item, SinkItem> Deserializer<Item> for Compressed<Codec, SinkItem>
+where
+    Codec: Deserializer<Item>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        let this = self.project();
+        let mut frame = &src[..];
+        if !frame.has_remaining() {
+            return Err(invalid_frame("empty frame"));
+        }
+        let header = frame.get_u8();
+        if header & ADVERTISEMENT != 0 {
+            if frame.remaining() < 2 {
+                return Err(invalid_frame("truncated dictionary IDs"));
+            }
+            let count = usize::from(frame.get_u16_le());
+            if frame.remaining() < count * 4 {
+                return Err(invalid_frame("truncated dictionary IDs"));
+            }
+            let ids = (0..count).map(|_| frame.get_u32_le()).collect::<Vec<_>>();
+            tracing::debug!(?ids, "ReceivePeerDictionaries");
+            *this.peer = Some(ids);
+        }
+        let dictionary = match header & MODE_MASK {
+            RAW => {
+                return this
+                    .inner
+                    .deserialize(&BytesMut::from(frame))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            ZSTD => None,
+            ZSTD_DICTIONARY if frame.remaining() >= 4 => Some(frame.get_u32_le()),
+            ZSTD_DICTIONARY => return Err(invalid_frame("truncated dictionary ID")),
+            _ => return Err(invalid_frame("unknown compression mode")),
+        };
+        let len = match zstd::zstd_safe::get_frame_content_size(frame) {
+            Ok(Some(len)) if len <= *this.max_frame_len as u64 => len as usize,
+            Ok(Some(_)) => return Err(invalid_frame("frame decompresses past the limit")),
+            Ok(None) | Err(_) => return Err(invalid_frame("frame has no content size")),
+        };
+        let decompressor = match this.decompressors.entry(dictionary) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let decompressor = match dictionary {
+                    Some(id) => {
+                        let dictionary = this
+                            .dictionaries
+                            .get(id)
+                            .ok_or_else(|| invalid_frame("unknown dictionary"))?;
+                        Decompressor::with_dictionary(dictionary.as_bytes())?
+                    }
+                    None => Decompressor::new()?,
+                };
+                entry.insert(decompressor)
+            }
+        };
+        let decompressed = decompressor.decompress(frame, len)?;
+        this.inner
+            .deserialize(&BytesMut::from(&decompressed[..]))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<Codec, SinkItem> Serializer<SinkItem> for Compressed<Codec, SinkItem>
+where
+    Codec: Serializer<SinkItem>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        let this = self.project();
+        let payload = this
+            .inner
+            .serialize(item)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let dictionaries = &*this.dictionaries;
+        let dictionary = this.peer.as_ref().and_then(|peer| {
+            let shared = |id: &u32| peer.contains(id) && dictionaries.get(*id).is_some();
+            this.selector
+                .and_then(|selector| selector(item))
+                .filter(shared)
+                .or_else(|| dictionaries.ids().find(shared))
+        });
+        let compressor = match this.compressors.entry(dictionary) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let compressor = match dictionary.and_then(|id| dictionaries.get(id)) {
+                    Some(dictionary) => {
+                        Compressor::with_dictionary(*this.level, dictionary.as_bytes())?
+                    }
+                    None => Compressor::new(*this.level)?,
+                };
+                entry.insert(compressor)
+            }
+        };
+        let compressed = compressor.compress(&payload)?;
+
+        let mut frame = BytesMut::with_capacity(payload.len() + 16);
+        let mut header = match (compressed.len() < payload.len(), dictionary) {
+            (false, _) => RAW,
+            (true, None) => ZSTD,
+            (true, Some(_)) => ZSTD_DICTIONARY,
+        };
+        if !*this.advertised {
+            header |= ADVERTISEMENT;
+        }
+        frame.put_u8(header);
+        if !*this.advertised {
+            let ids = dictionaries.ids().collect::<Vec<_>>();
+            frame.put_u16_le(u16::try_from(ids.len()).unwrap_or(u16::MAX));
+            for id in ids.into_iter().take(usize::from(u16::MAX)) {
+                frame.put_u32_le(id);
+            }
+            *this.advertised = true;
+        }
+        match header & MODE_MASK {
+            RAW => frame.put_slice(&payload),
+            ZSTD => frame.put_slice(&compressed),
+            _ => {
+                frame.put_u32_le(dictionary.unwrap());
+                frame.put_slice(&compressed);
+            }
+        }
+        Ok(frame.freeze())
+    }
+}
+
+#[cfg(all(test, feature = "serde-transport-json"))]
+mod tests {
+    use super::{Compressed, Dictionaries, Dictionary, ADVERTISEMENT, MODE_MASK, ZSTD_DICTIONARY};
+    use assert_matches::assert_matches;
+    use bytes::BytesMut;
+    use std::pin::Pin;
+    use tokio_serde::{formats::Json, Deserializer, Serializer};
+
+    type Codec = Compressed<Json<String, String>, String>;
+
+    fn message(i: usize) -> String {
+        format!(r#"{{"user":"user-{i}","email":"user-{i}@example.com","active":true}}"#)
+    }
+
+    fn write(codec: &mut Codec, message: &str) -> BytesMut {
+        BytesMut::from(&Pin::new(codec).serialize(&message.to_string()).unwrap()[..])
+    }
+
+    fn read(codec: &mut Codec, frame: &BytesMut) -> String {
+        Pin::new(codec).deserialize(frame).unwrap()
+    }
+
+    #[test]
+    fn peers_negotiate_a_shared_dictionary() {
+        let samples = (0..1000)
+            .map(|i| serde_json::to_vec(&message(i)).unwrap())
+            .collect::<Vec<_>>();
+        let trained = Dictionary::train(7, &samples, 2048).unwrap();
+        let other = Dictionary::new(3, trained.as_bytes().to_vec());
+        let mut client = Codec::new(
+            Json::default(),
+            Dictionaries::new().with(other).with(trained.clone()),
+        );
+        let mut server = Codec::new(Json::default(), Dictionaries::new().with(trained));
+
+        // The first frame carries the client's dictionary IDs, but no dictionary is shared yet.
+        let first = write(&mut client, &message(1));
+        assert_eq!(first[0] & ADVERTISEMENT, ADVERTISEMENT);
+        assert_eq!(read(&mut server, &first), message(1));
+        assert_eq!(server.peer_dictionaries(), Some(&[3, 7][..]));
+        assert_eq!(server.dictionary(), Some(7));
+
+        let response = write(&mut server, &message(2));
+        assert_eq!(response[0] & MODE_MASK, ZSTD_DICTIONARY);
+        assert_eq!(read(&mut client, &response), message(2));
+        assert_eq!(client.dictionary(), Some(7));
+
+        // With the dictionary, a small message shrinks well below its uncompressed size.
+        let uncompressed = serde_json::to_vec(&message(3)).unwrap();
+        let compressed = write(&mut client, &message(3));
+        assert_eq!(compressed[0], ZSTD_DICTIONARY);
+        assert!(compressed.len() * 2 < uncompressed.len());
+        assert_eq!(read(&mut server, &compressed), message(3));
+    }
+
+    #[test]
+    fn unknown_dictionary_fails_the_frame() {
+        let dictionary = Dictionary::new(1, b"user email example.com".to_vec());
+        let mut client = Codec::new(Json::default(), Dictionaries::new().with(dictionary));
+        let mut server = Codec::new(Json::default(), Dictionaries::new());
+        let first = write(&mut server, &message(1));
+        assert_eq!(read(&mut client, &first), message(1));
+        // The client only compresses with dictionaries the server has.
+        assert_eq!(client.dictionary(), None);
+        let frame = write(&mut client, &message(2));
+        assert_eq!(read(&mut server, &frame), message(2));
+
+        let mut forged = BytesMut::from(&[ZSTD_DICTIONARY][..]);
+        forged.extend_from_slice(&1u32.to_le_bytes());
+        forged.extend_from_slice(&zstd::bulk::compress(b"\"hi\"", 0).unwrap());
+        assert_matches!(
+            Pin::new(&mut server).deserialize(&forged),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData
+        );
+    }
+}
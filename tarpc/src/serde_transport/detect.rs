@@ -0,0 +1,192 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a codec that speaks whichever of JSON and bincode the peer speaks.
+//!
+//! A server moving its clients from JSON to bincode would otherwise need a second port, or a flag
+//! day on which every client switches at once. [`Detect`] lets one listener serve both: it looks
+//! at the first frame of each connection, and reads and writes the rest of the connection in the
+//! format that frame was written in. Clients need no changes. Since a codec is created for each
+//! connection, a listener uses it by passing a codec factory such as
+//! `|| Detect::new(Format::Bincode)` to [`tcp::listen`](super::tcp::listen).
+//!
+//! Detection relies on tarpc messages being enums: in JSON, a frame holding one begins with `{`,
+//! while in bincode, it begins with the enum's variant index, a small integer.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     serde_transport::{self, detect::{Detect, Format}},
+//!     server::{self, BaseChannel, Channel},
+//! };
+//! use tokio_serde::formats::Json;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let (client_io, server_io) = tokio::io::duplex(1024);
+//! let server_transport =
+//!     serde_transport::Transport::from((server_io, Detect::new(Format::Bincode)));
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server_transport)
+//!         .execute(server::serve(|_, x: u64| async move { Ok(x + 1) }))
+//!         .for_each(|response| response),
+//! );
+//!
+//! // A client that still speaks JSON.
+//! let client_transport = serde_transport::Transport::from((client_io, Json::default()));
+//! let client: client::Channel<u64, u64> =
+//!     client::new(client::Config::default(), client_transport).spawn();
+//! assert_eq!(client.call(context::current(), "AddOne", 1).await?, 2);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing;
+use bytes::{Bytes, BytesMut};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, pin::Pin};
+use tokio_serde::{
+    formats::{Bincode, Json},
+    Deserializer, Serializer,
+};
+
+/// A serialization format recognized by [`Detect`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Format {
+    /// JSON, as written by [`Json`].
+    Json,
+    /// Bincode, as written by [`Bincode`] with its default options.
+    Bincode,
+}
+
+impl Format {
+    /// Returns the format `frame` appears to be written in.
+    fn of(frame: &[u8]) -> Self {
+        match frame.first() {
+            Some(b'{' | b'[' | b'"') => Format::Json,
+            _ => Format::Bincode,
+        }
+    }
+}
+
+/// A codec that reads and writes in the format of the first frame it reads. Until a frame is
+/// read, it writes in its fallback format.
+#[pin_project]
+pub struct Detect<Item, SinkItem> {
+    fallback: Format,
+    format: Option<Format>,
+    #[pin]
+    json: Json<Item, SinkItem>,
+    #[pin]
+    bincode: Bincode<Item, SinkItem>,
+}
+
+impl<Item, SinkItem> Detect<Item, SinkItem> {
+    /// Returns a codec that detects the peer's format, writing in `fallback` until it has.
+    pub fn new(fallback: Format) -> Self {
+        Self {
+            fallback,
+            format: None,
+            json: Json::default(),
+            bincode: Bincode::default(),
+        }
+    }
+
+    /// Returns the format detected, or `None` if no frame has been read yet.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for Detect<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Detect")
+            .field("fallback", &self.fallback)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for Detect<Item, SinkItem>
+where
+    for<'a> Item: Deserialize<'a>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        let this = self.project();
+        let format = *this.format.get_or_insert_with(|| {
+            let format = Format::of(src);
+            tracing::debug!("DetectedFormat: {:?}", format);
+            format
+        });
+        match format {
+            Format::Json => this.json.deserialize(src).map_err(io::Error::from),
+            Format::Bincode => this.bincode.deserialize(src),
+        }
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for Detect<Item, SinkItem>
+where
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        let this = self.project();
+        match this.format.unwrap_or(*this.fallback) {
+            Format::Json => this.json.serialize(item).map_err(io::Error::from),
+            Format::Bincode => this.bincode.serialize(item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detect, Format};
+    use crate::{context, ClientMessage, Request};
+    use bytes::BytesMut;
+    use std::pin::Pin;
+    use tokio_serde::{
+        formats::{Bincode, Json},
+        Deserializer, Serializer,
+    };
+
+    type Message = ClientMessage<String>;
+
+    #[test]
+    fn speaks_the_format_of_the_first_frame() {
+        let request = ClientMessage::Request(Request {
+            context: context::current(),
+            id: 1,
+            message: "hi".to_string(),
+            one_way: false,
+            streamed: false,
+        });
+        let json = Pin::new(&mut Json::<Message, Message>::default())
+            .serialize(&request)
+            .unwrap();
+        let bincode = Pin::new(&mut Bincode::<Message, Message>::default())
+            .serialize(&request)
+            .unwrap();
+
+        for (frame, format) in [(json, Format::Json), (bincode, Format::Bincode)] {
+            let mut codec = Detect::<Message, Message>::new(Format::Bincode);
+            assert_eq!(codec.format(), None);
+            let message = Pin::new(&mut codec)
+                .deserialize(&BytesMut::from(&frame[..]))
+                .unwrap();
+            assert!(matches!(message, ClientMessage::Request(r) if r.message == "hi"));
+            assert_eq!(codec.format(), Some(format));
+            let written = Pin::new(&mut codec).serialize(&request).unwrap();
+            assert_eq!(Format::of(&written), format);
+        }
+    }
+}
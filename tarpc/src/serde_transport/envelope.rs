@@ -0,0 +1,617 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Request envelopes that defer payload deserialization.
+//!
+//! A client wrapped in [`EncodePayload`] serializes each request body into an [`Envelope`] that
+//! carries the method name alongside the opaque payload bytes. On the server, the envelope is
+//! decoded together with the request ID and [`Context`](crate::context::Context), so channels
+//! and [`BeforeRequest`](crate::server::request_hook::BeforeRequest) hooks can route, limit,
+//! authorize, or reject a request based on its method and payload size before the payload is
+//! deserialized by [`DecodePayload`].
+//!
+//! Payloads are carried as [`RawPayload`]s, which pass through serialization as opaque bytes.
+//! A gateway can use [`Forward`] to relay envelopes to a backend that answers with
+//! [`EncodeResponse`], and log or inspect the serialized bytes, without ever decoding or
+//! re-encoding the request or response bodies.
+//!
+//! Each envelope's header carries a [`PayloadFormat`] flag, so that one connection can mix
+//! encodings: e.g., a client can send most calls through an [`EncodePayload`] that produces
+//! human-readable JSON, and bulk calls through a second [`EncodePayload`], marked with
+//! [`PayloadFormat::Alternate`], that produces compact bincode. On the server, [`ByFormat`]
+//! routes each envelope to the [`DecodePayload`] for its format.
+//!
+//! Both peers must agree to use envelopes; they are not wire-compatible with plain requests.
+
+use crate::{
+    client::{stub::Stub, RpcError},
+    context,
+    server::{forward, Serve},
+    ServerError,
+};
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, error::Error, io, marker::PhantomData, pin::Pin, sync::Arc};
+use tokio_serde::{Deserializer, Serializer};
+
+/// A serialized message body that is carried through without being decoded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawPayload(BytesMut);
+
+impl RawPayload {
+    /// Serializes `item` with `codec`.
+    pub fn encode<T, Codec>(codec: &mut Codec, item: &T) -> Result<Self, Codec::Error>
+    where
+        Codec: Serializer<T> + Unpin,
+    {
+        Ok(Self(BytesMut::from(Pin::new(codec).serialize(item)?)))
+    }
+
+    /// Deserializes the payload with `codec`.
+    pub fn decode<T, Codec>(&self, codec: &mut Codec) -> Result<T, Codec::Error>
+    where
+        Codec: Deserializer<T> + Unpin,
+    {
+        Pin::new(codec).deserialize(&self.0)
+    }
+
+    /// Returns the serialized bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the size of the payload, in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true iff the payload is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Consumes the payload, returning the serialized bytes.
+    pub fn into_inner(self) -> BytesMut {
+        self.0
+    }
+}
+
+impl From<BytesMut> for RawPayload {
+    fn from(bytes: BytesMut) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Bytes> for RawPayload {
+    fn from(bytes: Bytes) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl From<&[u8]> for RawPayload {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.into())
+    }
+}
+
+/// Identifies which of the encodings agreed on by client and server a payload is encoded with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum PayloadFormat {
+    /// The encoding used for most calls.
+    #[default]
+    Primary,
+    /// An encoding used for selected calls, e.g. a compact binary encoding for bulk methods.
+    Alternate,
+}
+
+/// A request body whose payload has not yet been deserialized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Envelope {
+    /// The name of the method being called.
+    pub method: Cow<'static, str>,
+    /// The encoding of the payload.
+    #[serde(default)]
+    pub format: PayloadFormat,
+    /// The serialized request body.
+    pub payload: RawPayload,
+}
+
+impl Envelope {
+    /// Returns a new envelope for a call to `method` with the serialized body `payload`, encoded
+    /// in the [primary](PayloadFormat::Primary) format.
+    pub fn new(method: impl Into<Cow<'static, str>>, payload: impl Into<RawPayload>) -> Self {
+        Self {
+            method: method.into(),
+            format: PayloadFormat::Primary,
+            payload: payload.into(),
+        }
+    }
+
+    /// Marks the payload as encoded in `format`.
+    pub fn with_format(mut self, format: PayloadFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Returns the name of the method being called.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Returns the size of the serialized request body, in bytes.
+    pub fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
+}
+
+/// A [`Stub`] that serializes request bodies into [`Envelope`]s before passing them to the
+/// underlying stub.
+#[derive(Clone, Debug)]
+pub struct EncodePayload<S, F, Req> {
+    stub: S,
+    codec_fn: F,
+    format: PayloadFormat,
+    ghost: PhantomData<fn(Req)>,
+}
+
+impl<S, F, Req> EncodePayload<S, F, Req> {
+    /// Returns a stub that serializes request bodies with codecs created by `codec_fn`, and marks
+    /// them as encoded in the [primary](PayloadFormat::Primary) format.
+    pub fn new(stub: S, codec_fn: F) -> Self {
+        Self {
+            stub,
+            codec_fn,
+            format: PayloadFormat::Primary,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Marks the payloads serialized by this stub as encoded in `format`.
+    pub fn with_format(mut self, format: PayloadFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl<S, F, Codec, Req> Stub for EncodePayload<S, F, Req>
+where
+    S: Stub<Req = Envelope>,
+    F: Fn() -> Codec,
+    Codec: Serializer<Req> + Unpin,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Req = Req;
+    type Resp = S::Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<S::Resp, RpcError> {
+        let payload = RawPayload::encode(&mut (self.codec_fn)(), &request)
+            .map_err(|e| RpcError::Send(e.into()))?;
+        self.stub
+            .call(
+                ctx,
+                request_name,
+                Envelope::new(request_name, payload).with_format(self.format),
+            )
+            .await
+    }
+}
+
+/// A [`Serve`] that deserializes [`Envelope`] payloads before passing them to the underlying
+/// service.
+///
+/// Payloads that cannot be deserialized are answered with an
+/// [`InvalidData`](io::ErrorKind::InvalidData) error; the channel stays open.
+#[derive(Clone, Debug)]
+pub struct DecodePayload<S, F> {
+    serve: S,
+    codec_fn: F,
+    max_payload_len: usize,
+}
+
+impl<S, F> DecodePayload<S, F> {
+    /// Returns a service that deserializes payloads with codecs created by `codec_fn`.
+    pub fn new(serve: S, codec_fn: F) -> Self {
+        Self {
+            serve,
+            codec_fn,
+            max_payload_len: usize::MAX,
+        }
+    }
+
+    /// Rejects requests whose payload is larger than `max_payload_len` bytes without
+    /// deserializing them.
+    pub fn max_payload_len(mut self, max_payload_len: usize) -> Self {
+        self.max_payload_len = max_payload_len;
+        self
+    }
+}
+
+impl<S, F, Codec> Serve for DecodePayload<S, F>
+where
+    S: Serve,
+    F: Fn() -> Codec,
+    Codec: Deserializer<S::Req> + Unpin,
+    Codec::Error: std::fmt::Display,
+{
+    type Req = Envelope;
+    type Resp = S::Resp;
+
+    async fn serve(self, ctx: context::Context, req: Envelope) -> Result<S::Resp, ServerError> {
+        if req.payload_len() > self.max_payload_len {
+            return Err(ServerError::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "request payload of {} bytes exceeds the limit of {} bytes",
+                    req.payload_len(),
+                    self.max_payload_len
+                ),
+            ));
+        }
+        let req = req.payload.decode(&mut (self.codec_fn)()).map_err(|e| {
+            ServerError::new(
+                io::ErrorKind::InvalidData,
+                format!("could not decode the request payload: {e}"),
+            )
+        })?;
+        self.serve.serve(ctx, req).await
+    }
+}
+
+/// A [`Serve`] that routes each [`Envelope`] to the service for its [`PayloadFormat`].
+///
+/// Typically, both services are [`DecodePayload`]s wrapping the same service with different
+/// codecs.
+#[derive(Clone, Debug)]
+pub struct ByFormat<P, A> {
+    primary: P,
+    alternate: A,
+}
+
+impl<P, A> ByFormat<P, A> {
+    /// Returns a service that routes [primary](PayloadFormat::Primary) payloads to `primary` and
+    /// [alternate](PayloadFormat::Alternate) payloads to `alternate`.
+    pub fn new(primary: P, alternate: A) -> Self {
+        Self { primary, alternate }
+    }
+}
+
+impl<P, A> Serve for ByFormat<P, A>
+where
+    P: Serve<Req = Envelope>,
+    A: Serve<Req = Envelope, Resp = P::Resp>,
+{
+    type Req = Envelope;
+    type Resp = P::Resp;
+
+    async fn serve(self, ctx: context::Context, req: Envelope) -> Result<P::Resp, ServerError> {
+        match req.format {
+            PayloadFormat::Primary => self.primary.serve(ctx, req).await,
+            PayloadFormat::Alternate => self.alternate.serve(ctx, req).await,
+        }
+    }
+}
+
+/// A [`Serve`] that serializes the responses of the underlying service into [`RawPayload`]s.
+#[derive(Clone, Debug)]
+pub struct EncodeResponse<S, F> {
+    serve: S,
+    codec_fn: F,
+}
+
+impl<S, F> EncodeResponse<S, F> {
+    /// Returns a service that serializes responses with codecs created by `codec_fn`.
+    pub fn new(serve: S, codec_fn: F) -> Self {
+        Self { serve, codec_fn }
+    }
+}
+
+impl<S, F, Codec> Serve for EncodeResponse<S, F>
+where
+    S: Serve,
+    F: Fn() -> Codec,
+    Codec: Serializer<S::Resp> + Unpin,
+    Codec::Error: std::fmt::Display,
+{
+    type Req = S::Req;
+    type Resp = RawPayload;
+
+    fn method(&self, req: &S::Req) -> Option<&'static str> {
+        self.serve.method(req)
+    }
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<RawPayload, ServerError> {
+        let resp = self.serve.serve(ctx, req).await?;
+        RawPayload::encode(&mut (self.codec_fn)(), &resp).map_err(|e| {
+            ServerError::new(
+                io::ErrorKind::Other,
+                format!("could not encode the response payload: {e}"),
+            )
+        })
+    }
+}
+
+/// A [`Stub`] that deserializes [`RawPayload`] responses from the underlying stub.
+#[derive(Clone, Debug)]
+pub struct DecodeResponse<S, F, Resp> {
+    stub: S,
+    codec_fn: F,
+    ghost: PhantomData<fn() -> Resp>,
+}
+
+impl<S, F, Resp> DecodeResponse<S, F, Resp> {
+    /// Returns a stub that deserializes responses with codecs created by `codec_fn`.
+    pub fn new(stub: S, codec_fn: F) -> Self {
+        Self {
+            stub,
+            codec_fn,
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<S, F, Codec, Resp> Stub for DecodeResponse<S, F, Resp>
+where
+    S: Stub<Resp = RawPayload>,
+    F: Fn() -> Codec,
+    Codec: Deserializer<Resp> + Unpin,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Req = S::Req;
+    type Resp = Resp;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: S::Req,
+    ) -> Result<Resp, RpcError> {
+        let resp = self.stub.call(ctx, request_name, request).await?;
+        resp.decode(&mut (self.codec_fn)())
+            .map_err(|e| RpcError::Receive(Arc::from(e.into())))
+    }
+}
+
+/// A [`Serve`] that relays [`Envelope`]s to another server through a [`Stub`], passing both the
+/// request and response payloads through undecoded.
+///
+/// Client-side spans for forwarded calls are named `"Forward"`, because the original method name
+/// is only known at runtime.
+#[derive(Clone, Debug)]
+pub struct Forward<S> {
+    stub: S,
+}
+
+impl<S> Forward<S> {
+    /// Returns a service that forwards all requests to `stub`.
+    pub fn new(stub: S) -> Self {
+        Self { stub }
+    }
+}
+
+impl<S> Serve for Forward<S>
+where
+    S: Stub<Req = Envelope>,
+{
+    type Req = Envelope;
+    type Resp = S::Resp;
+
+    async fn serve(self, ctx: context::Context, req: Envelope) -> Result<S::Resp, ServerError> {
+        self.stub
+            .call(ctx, "Forward", req)
+            .await
+            .map_err(forward::upstream_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ByFormat, DecodePayload, DecodeResponse, EncodePayload, EncodeResponse, Envelope, Forward,
+        PayloadFormat, RawPayload,
+    };
+    use crate::{
+        client::{self, stub::Stub, RpcError},
+        context,
+        server::{self, BaseChannel, Channel, Serve},
+        transport::channel,
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::io;
+    use tokio_serde::formats::{SymmetricalBincode, SymmetricalJson};
+
+    /// Returns a client connected to a server that runs `serve`, and the server future.
+    fn set_up<S>(serve: S) -> (client::Channel<Envelope, S::Resp>, impl Future<Output = ()>)
+    where
+        S: Serve<Req = Envelope> + Clone,
+        S::Resp: Send + 'static,
+    {
+        let (client_transport, server_transport) = channel::unbounded();
+        let server = BaseChannel::with_defaults(server_transport)
+            .execute(serve)
+            .for_each(|response| response);
+        let client = client::new(client::Config::default(), client_transport).spawn();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn payload_round_trip() {
+        let serve = DecodePayload::new(
+            server::serve(|_, i: u64| async move { Ok(i + 1) }),
+            SymmetricalJson::<u64>::default,
+        );
+        let (client, server) = set_up(serve);
+        tokio::spawn(server);
+        let client = EncodePayload::new(client, SymmetricalJson::<u64>::default);
+
+        assert_matches!(client.call(context::current(), "AddOne", 1).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn calls_choose_payload_format() {
+        let serve = server::serve(|_, s: String| async move { Ok(s.len()) });
+        let serve = ByFormat::new(
+            DecodePayload::new(serve, SymmetricalJson::<String>::default),
+            DecodePayload::new(serve, SymmetricalBincode::<String>::default),
+        );
+        let (client, server) = set_up(serve);
+        tokio::spawn(server);
+        let json = EncodePayload::new(client.clone(), SymmetricalJson::<String>::default);
+        let bincode = EncodePayload::new(client.clone(), SymmetricalBincode::<String>::default)
+            .with_format(PayloadFormat::Alternate);
+
+        assert_matches!(
+            json.call(context::current(), "Len", "abc".into()).await,
+            Ok(3)
+        );
+        assert_matches!(
+            bincode.call(context::current(), "Len", "abcd".into()).await,
+            Ok(4)
+        );
+        // A bincode payload marked as JSON cannot be decoded.
+        let mismatched = EncodePayload::new(client, SymmetricalBincode::<String>::default);
+        assert_matches!(
+            mismatched
+                .call(context::current(), "Len", "abcd".into())
+                .await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::InvalidData,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_payload_is_rejected_before_decoding() {
+        let serve = DecodePayload::new(
+            server::serve(|_, s: String| async move { Ok(s) }),
+            SymmetricalJson::<String>::default,
+        )
+        .max_payload_len(8);
+        let (client, server) = set_up(serve);
+        tokio::spawn(server);
+        let client = EncodePayload::new(client, SymmetricalJson::<String>::default);
+
+        assert_matches!(
+            client.call(context::current(), "Echo", "short".into()).await,
+            Ok(s) if s == "short"
+        );
+        assert_matches!(
+            client
+                .call(context::current(), "Echo", "much too long".into())
+                .await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::InvalidInput,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn undecodable_payload_is_answered() {
+        let serve = DecodePayload::new(
+            server::serve(|_, i: u64| async move { Ok(i) }),
+            SymmetricalJson::<u64>::default,
+        );
+        let (client, server) = set_up(serve);
+        tokio::spawn(server);
+
+        assert_matches!(
+            client
+                .call(
+                    context::current(),
+                    "Echo",
+                    Envelope::new("Echo", &b"nope"[..])
+                )
+                .await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::InvalidData,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_see_method_before_decoding() {
+        let serve = DecodePayload::new(
+            server::serve(|_, i: u64| async move { Ok(i) }),
+            SymmetricalJson::<u64>::default,
+        )
+        .before(|_: &mut context::Context, req: &Envelope| {
+            let allowed = req.method() != "Forbidden";
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(ServerError::new(
+                        io::ErrorKind::PermissionDenied,
+                        "not allowed".into(),
+                    ))
+                }
+            }
+        });
+        let (client, server) = set_up(serve);
+        tokio::spawn(server);
+
+        // The payload is not valid JSON, so the call only succeeds in failing with
+        // PermissionDenied if the hook runs before decoding.
+        assert_matches!(
+            client
+                .call(
+                    context::current(),
+                    "Forbidden",
+                    Envelope::new("Forbidden", &b"nope"[..])
+                )
+                .await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::PermissionDenied,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_passes_payloads_through() {
+        let backend = EncodeResponse::new(
+            DecodePayload::new(
+                server::serve(|_, i: u64| async move { Ok(i * 2) }),
+                SymmetricalJson::<u64>::default,
+            ),
+            SymmetricalJson::<u64>::default,
+        );
+        let (backend_client, backend_server) = set_up(backend);
+        tokio::spawn(backend_server);
+
+        let (gateway_client, gateway_server) = set_up(Forward::new(backend_client));
+        tokio::spawn(gateway_server);
+
+        let client = DecodeResponse::new(
+            EncodePayload::new(gateway_client, SymmetricalJson::<u64>::default),
+            SymmetricalJson::<u64>::default,
+        );
+        assert_matches!(client.call(context::current(), "Double", 21).await, Ok(42));
+    }
+
+    #[test]
+    fn raw_payload_serializes_as_bytes() {
+        let payload = RawPayload::from(&b"\x01\x02"[..]);
+        let bytes = bincode::serialize(&payload).unwrap();
+        assert_eq!(
+            bytes,
+            bincode::serialize(&serde_bytes::Bytes::new(b"\x01\x02")).unwrap()
+        );
+        assert_eq!(bincode::deserialize::<RawPayload>(&bytes).unwrap(), payload);
+    }
+}
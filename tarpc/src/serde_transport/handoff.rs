@@ -0,0 +1,332 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a handoff of listening sockets from a server process to its successor, for restarts
+//! that refuse no connections.
+//!
+//! A server that is restarted by binding its address anew refuses connections between the moment
+//! the old process closes its listeners and the moment the new process binds them. Instead, the
+//! old process binds a [`Handoff`] socket next to its listeners. The new process calls [`take`],
+//! which receives duplicates of the listeners' file descriptors over the handoff socket and
+//! resumes accepting on them with [`tcp::listen_on`](super::tcp::listen_on). Connections that
+//! arrive in the meantime wait in the listeners' backlog, which both processes share.
+//!
+//! Once [`Successor::hand_off`] returns, the new process is accepting, and the old process drains:
+//! it stops polling and drops its [`Incoming`](super::tcp::Incoming) stream, and lets the
+//! channels it already accepted run until their clients disconnect.
+//!
+//! ```no_run
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     serde_transport::{handoff, tcp},
+//!     tokio_serde::formats::Bincode,
+//! };
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let listener = match handoff::take("/tmp/tarpc-handoff.sock").await {
+//!     // Taking over from a running process.
+//!     Ok(mut listeners) => listeners.remove(0),
+//!     // The first process to run.
+//!     Err(_) => tokio::net::TcpListener::bind("0.0.0.0:5000").await?,
+//! };
+//! let mut incoming = tcp::listen_on(listener, Bincode::<String, String>::default).await?;
+//! let handoff = handoff::Handoff::bind("/tmp/tarpc-handoff.sock")?;
+//! loop {
+//!     tokio::select! {
+//!         transport = incoming.next() => match transport {
+//!             Some(Ok(transport)) => {
+//!                 // Serve the transport...
+//! #               drop(transport);
+//!             }
+//!             _ => break,
+//!         },
+//!         successor = handoff.accept() => {
+//!             successor?.hand_off(&[incoming.listener()]).await?;
+//!             break;
+//!         }
+//!     }
+//! }
+//! // Stop accepting; channels already being served keep running.
+//! drop(incoming);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::util::tracing;
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
+    ptr,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    net::{TcpListener, UnixListener, UnixStream},
+};
+
+/// The most listeners that can be handed off at once.
+pub const MAX_LISTENERS: usize = 16;
+
+/// The most bytes of listener state sent along with the descriptors.
+const MAX_STATE_LEN: usize = 4096;
+
+/// A buffer for one control message carrying up to `MAX_LISTENERS` descriptors, aligned for
+/// `cmsghdr`.
+type ControlBuf = [u64; 2 + MAX_LISTENERS / 2];
+
+/// Sent by the successor once it has adopted the listeners.
+const ACK: u8 = 1;
+
+/// A Unix domain socket on which a server process waits for its successor to take its listeners.
+/// The socket file is left in place when the handoff is dropped, since by then the successor has
+/// usually bound its own handoff at the same path.
+#[derive(Debug)]
+pub struct Handoff {
+    listener: UnixListener,
+}
+
+impl Handoff {
+    /// Binds the handoff socket at `path`, replacing a socket left there by a previous process,
+    /// which must already have handed off its listeners.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Waits for a successor to connect with [`take`].
+    pub async fn accept(&self) -> io::Result<Successor> {
+        let (socket, _) = self.listener.accept().await?;
+        Ok(Successor { socket })
+    }
+}
+
+/// A process waiting to take over listeners, as accepted by [`Handoff::accept`].
+#[derive(Debug)]
+pub struct Successor {
+    socket: UnixStream,
+}
+
+impl Successor {
+    /// Passes `listeners` to the successor, in order. Returns once the successor has adopted
+    /// them, at which point the caller should stop accepting connections. If the successor fails
+    /// before adopting the listeners, returns an error, and the caller may keep accepting and wait
+    /// for another successor.
+    pub async fn hand_off(mut self, listeners: &[&TcpListener]) -> io::Result<()> {
+        if listeners.len() > MAX_LISTENERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot hand off more than {MAX_LISTENERS} listeners"),
+            ));
+        }
+        let mut state = String::new();
+        let mut fds = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            state.push_str(&listener.local_addr()?.to_string());
+            state.push('\n');
+            fds.push(listener.as_raw_fd());
+        }
+        let socket = &self.socket;
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || {
+                send_fds(socket.as_raw_fd(), state.as_bytes(), &fds)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => break result?,
+            }
+        }
+        if self.socket.read_u8().await? != ACK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the successor did not adopt the listeners",
+            ));
+        }
+        tracing::info!(listeners = listeners.len(), "HandedOff");
+        Ok(())
+    }
+}
+
+/// Takes the listeners of the process whose [`Handoff`] is bound at `path`, in the order that
+/// process passed them. Fails if no process is waiting to hand off its listeners, in which case
+/// the caller should bind its listeners itself.
+pub async fn take<P: AsRef<Path>>(path: P) -> io::Result<Vec<TcpListener>> {
+    let mut predecessor = UnixStream::connect(path).await?;
+    let (state, fds) = loop {
+        predecessor.readable().await?;
+        match predecessor.try_io(Interest::READABLE, || recv_fds(predecessor.as_raw_fd())) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => break result?,
+        }
+    };
+    // Own every descriptor before validating anything, so that none leak on error.
+    let listeners: Vec<_> = fds
+        .into_iter()
+        // SAFETY: the descriptors were just received, so nothing else owns them.
+        .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        .collect();
+    let addrs = parse_state(&state)?;
+    if addrs.len() != listeners.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the number of listeners does not match their state",
+        ));
+    }
+    let mut adopted = Vec::with_capacity(listeners.len());
+    for (listener, addr) in listeners.into_iter().zip(addrs) {
+        if listener.local_addr()? != addr {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("listener for {addr} is bound elsewhere"),
+            ));
+        }
+        listener.set_nonblocking(true)?;
+        adopted.push(TcpListener::from_std(listener)?);
+    }
+    predecessor.write_u8(ACK).await?;
+    tracing::info!(listeners = adopted.len(), "TookOver");
+    Ok(adopted)
+}
+
+/// Parses the local addresses of the listeners, one per line.
+fn parse_state(state: &[u8]) -> io::Result<Vec<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed listener state");
+    std::str::from_utf8(state)
+        .map_err(|_| invalid())?
+        .lines()
+        .map(|line| line.parse().map_err(|_| invalid()))
+        .collect()
+}
+
+fn send_fds(socket: RawFd, state: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    // An empty message is indistinguishable from the socket closing.
+    let mut payload = [state, b"\n"].concat();
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let mut control: ControlBuf = [0; 2 + MAX_LISTENERS / 2];
+    let fds_len = mem::size_of_val(fds) as u32;
+    // SAFETY: msghdr is plain data, and the control buffer is large and aligned enough for one
+    // control message carrying up to `MAX_LISTENERS` descriptors.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        }
+        if libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fds(socket: RawFd) -> io::Result<(Vec<u8>, Vec<RawFd>)> {
+    let mut payload = vec![0u8; MAX_STATE_LEN];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let mut control: ControlBuf = [0; 2 + MAX_LISTENERS / 2];
+    // SAFETY: msghdr is plain data; recvmsg writes at most `msg_controllen` bytes of control
+    // messages, and descriptors are only read from a control message that carries them.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of::<ControlBuf>() as _;
+        let received = libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut fds = vec![];
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+            fds.resize(data_len / mem::size_of::<RawFd>(), 0);
+            ptr::copy_nonoverlapping(
+                libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+                fds.as_mut_ptr(),
+                fds.len(),
+            );
+        }
+        if received == 0 || msg.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+            for fd in fds {
+                libc::close(fd);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the predecessor did not pass its listeners",
+            ));
+        }
+        payload.truncate(received as usize - 1);
+        Ok((payload, fds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take, Handoff};
+    use crate::serde_transport::unix::TempPathBuf;
+    use std::io;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn successor_accepts_on_handed_off_listeners() -> io::Result<()> {
+        let path = TempPathBuf::with_random("handoff");
+        let first = TcpListener::bind("127.0.0.1:0").await?;
+        let second = TcpListener::bind("127.0.0.1:0").await?;
+        let addrs = [first.local_addr()?, second.local_addr()?];
+        let handoff = Handoff::bind(&path)?;
+
+        let hand_off = async {
+            let listeners = [&first, &second];
+            handoff.accept().await?.hand_off(&listeners).await
+        };
+        let (handed_off, taken) = tokio::join!(hand_off, take(&path));
+        handed_off?;
+        let taken = taken?;
+        drop((first, second));
+
+        assert_eq!(taken.len(), 2);
+        for (listener, addr) in taken.iter().zip(addrs) {
+            assert_eq!(listener.local_addr()?, addr);
+            let mut client = TcpStream::connect(addr).await?;
+            let (mut conn, _) = listener.accept().await?;
+            client.write_all(b"ping").await?;
+            let mut buf = [0; 4];
+            conn.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn take_fails_without_predecessor() {
+        let path = TempPathBuf::with_random("handoff");
+        assert!(take(&path).await.is_err());
+    }
+}
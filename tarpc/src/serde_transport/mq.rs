@@ -0,0 +1,454 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Transports over a message broker, such as NATS or AMQP.
+//!
+//! Clients publish requests to a subject (or queue) shared by all clients of a service, and
+//! receive responses on a reply subject of their own. Each published [`Message`] names the reply
+//! subject, and carries the tarpc request ID as its correlation ID, so that the traffic can be
+//! inspected with the broker's own tools.
+//!
+//! The transports are independent of any broker client library. They publish through any
+//! [`Sink`] of [`Message`]s and receive from any [`Stream`] of them, so adapting a broker client
+//! takes a few lines that convert its message type to and from [`Message`]:
+//!
+//! - a client calls [`connect`] with a publisher, a subscription to its reply subject, and the
+//!   service's request subject;
+//! - a server calls [`listen`] with a publisher and a subscription to the request subject, and
+//!   receives one transport per client, which it serves like any other connection, e.g. with
+//!   [`BaseChannel`](crate::server::BaseChannel).
+//!
+//! Brokers have no notion of connections, so a client announces that it is closing by
+//! publishing a message with an empty payload. Until then, the server keeps the client's
+//! transport open.
+
+use super::MalformedFrame;
+use crate::util::tracing;
+use crate::{util, ClientMessage, Response};
+use bytes::Bytes;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+};
+use tokio::sync::mpsc;
+use tokio_serde::{Deserializer, Serializer};
+
+/// A message published to, or received from, a broker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Message {
+    /// The subject, or queue, that the message is published to.
+    pub subject: String,
+    /// The subject that responses to the message should be published to.
+    pub reply_to: Option<String>,
+    /// The ID of the request that the message belongs to.
+    pub correlation_id: Option<u64>,
+    /// The serialized tarpc message.
+    pub payload: Bytes,
+}
+
+impl Message {
+    /// Returns a message to be published to `subject`.
+    pub fn new(subject: impl Into<String>, payload: impl Into<Bytes>) -> Self {
+        Self {
+            subject: subject.into(),
+            reply_to: None,
+            correlation_id: None,
+            payload: payload.into(),
+        }
+    }
+
+    /// Sets the subject that responses to the message should be published to.
+    pub fn with_reply_to(mut self, reply_to: impl Into<String>) -> Self {
+        self.reply_to = Some(reply_to.into());
+        self
+    }
+
+    /// Sets the ID of the request that the message belongs to.
+    pub fn with_correlation_id(mut self, correlation_id: u64) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+}
+
+/// Deserializes the payload of `message`. A payload that fails to deserialize is reported as a
+/// [`MalformedFrame`] carrying the message's correlation ID.
+fn deserialize<Codec, Item>(codec: Pin<&mut Codec>, message: Message) -> io::Result<Item>
+where
+    Codec: Deserializer<Item>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    codec.deserialize(&message.payload.into()).map_err(|e| {
+        let malformed = MalformedFrame::new(e);
+        let malformed = match message.correlation_id {
+            Some(request_id) => malformed.with_request_id(request_id),
+            None => malformed,
+        };
+        io::Error::new(io::ErrorKind::InvalidData, malformed)
+    })
+}
+
+fn other_error(e: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// The client end of a broker transport.
+#[pin_project]
+pub struct ClientTransport<P, R, Req, Resp, Codec> {
+    #[pin]
+    publisher: P,
+    #[pin]
+    replies: R,
+    #[pin]
+    codec: Codec,
+    request_subject: String,
+    reply_subject: String,
+    /// Whether the server was told that the client is closing.
+    goodbye_sent: bool,
+    ghost: PhantomData<(fn(Req), fn() -> Resp)>,
+}
+
+impl<P, R, Req, Resp, Codec> fmt::Debug for ClientTransport<P, R, Req, Resp, Codec> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientTransport")
+            .field("request_subject", &self.request_subject)
+            .field("reply_subject", &self.reply_subject)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns a transport that publishes requests to `request_subject` through `publisher`, and
+/// reads responses from `replies`, which must be a subscription to `reply_subject`. The reply
+/// subject must be unique to this client.
+pub fn connect<P, R, Req, Resp, Codec>(
+    publisher: P,
+    replies: R,
+    request_subject: impl Into<String>,
+    reply_subject: impl Into<String>,
+    codec: Codec,
+) -> ClientTransport<P, R, Req, Resp, Codec>
+where
+    P: Sink<Message>,
+    R: Stream<Item = Message>,
+    Codec: Serializer<ClientMessage<Req>> + Deserializer<Response<Resp>>,
+{
+    ClientTransport {
+        publisher,
+        replies,
+        codec,
+        request_subject: request_subject.into(),
+        reply_subject: reply_subject.into(),
+        goodbye_sent: false,
+        ghost: PhantomData,
+    }
+}
+
+impl<P, R, Req, Resp, Codec> Stream for ClientTransport<P, R, Req, Resp, Codec>
+where
+    R: Stream<Item = Message>,
+    Resp: for<'de> Deserialize<'de>,
+    Codec: Deserializer<Response<Resp>>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Item = io::Result<Response<Resp>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let message = match ready!(this.replies.poll_next(cx)) {
+            Some(message) => message,
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(deserialize(this.codec, message)))
+    }
+}
+
+impl<P, R, Req, Resp, Codec> Sink<ClientMessage<Req>> for ClientTransport<P, R, Req, Resp, Codec>
+where
+    P: Sink<Message>,
+    P::Error: Into<Box<dyn Error + Send + Sync>>,
+    Req: Serialize,
+    Codec: Serializer<ClientMessage<Req>>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().publisher.poll_ready(cx).map_err(other_error)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ClientMessage<Req>) -> io::Result<()> {
+        let this = self.project();
+        let correlation_id = match &item {
+            ClientMessage::Request(request) => request.id,
+            ClientMessage::Cancel { request_id, .. } | ClientMessage::Item { request_id, .. } => {
+                *request_id
+            }
+            // A close belongs to no request.
+            ClientMessage::Close { .. } => 0,
+        };
+        let payload = this.codec.serialize(&item).map_err(other_error)?;
+        this.publisher
+            .start_send(
+                Message::new(this.request_subject.clone(), payload)
+                    .with_reply_to(this.reply_subject.clone())
+                    .with_correlation_id(correlation_id),
+            )
+            .map_err(other_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().publisher.poll_flush(cx).map_err(other_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.goodbye_sent {
+            let mut this = self.as_mut().project();
+            ready!(this.publisher.as_mut().poll_ready(cx)).map_err(other_error)?;
+            this.publisher
+                .start_send(
+                    Message::new(this.request_subject.clone(), Bytes::new())
+                        .with_reply_to(this.reply_subject.clone()),
+                )
+                .map_err(other_error)?;
+            *this.goodbye_sent = true;
+        }
+        self.project().publisher.poll_close(cx).map_err(other_error)
+    }
+}
+
+/// The server end of a broker transport, which exchanges messages with a single client.
+#[pin_project]
+pub struct ServerTransport<P, Req, Resp, Codec> {
+    publisher: P,
+    requests: mpsc::UnboundedReceiver<Message>,
+    #[pin]
+    codec: Codec,
+    reply_subject: String,
+    ghost: PhantomData<(fn() -> Req, fn(Resp))>,
+}
+
+impl<P, Req, Resp, Codec> ServerTransport<P, Req, Resp, Codec> {
+    /// Returns the subject that the client receives responses on.
+    pub fn reply_subject(&self) -> &str {
+        &self.reply_subject
+    }
+}
+
+impl<P, Req, Resp, Codec> fmt::Debug for ServerTransport<P, Req, Resp, Codec> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTransport")
+            .field("reply_subject", &self.reply_subject)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, Req, Resp, Codec> Stream for ServerTransport<P, Req, Resp, Codec>
+where
+    Req: for<'de> Deserialize<'de>,
+    Codec: Deserializer<ClientMessage<Req>>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let message = match ready!(this.requests.poll_recv(cx)) {
+            Some(message) => message,
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(deserialize(this.codec, message)))
+    }
+}
+
+impl<P, Req, Resp, Codec> Sink<Response<Resp>> for ServerTransport<P, Req, Resp, Codec>
+where
+    P: Sink<Message> + Unpin,
+    P::Error: Into<Box<dyn Error + Send + Sync>>,
+    Resp: Serialize,
+    Codec: Serializer<Response<Resp>>,
+    Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project()
+            .publisher
+            .poll_ready_unpin(cx)
+            .map_err(other_error)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Response<Resp>) -> io::Result<()> {
+        let this = self.project();
+        let request_id = item.request_id;
+        let payload = this.codec.serialize(&item).map_err(other_error)?;
+        this.publisher
+            .start_send_unpin(
+                Message::new(this.reply_subject.clone(), payload).with_correlation_id(request_id),
+            )
+            .map_err(other_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project()
+            .publisher
+            .poll_flush_unpin(cx)
+            .map_err(other_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // The publisher is shared with the transports of other clients, so it is only flushed.
+        self.poll_flush(cx)
+    }
+}
+
+/// A stream of transports, one for each client that publishes to the request subject.
+#[derive(Debug)]
+pub struct Incoming<P, Req, Resp, Codec> {
+    transports: mpsc::UnboundedReceiver<ServerTransport<P, Req, Resp, Codec>>,
+}
+
+impl<P, Req, Resp, Codec> Stream for Incoming<P, Req, Resp, Codec> {
+    type Item = ServerTransport<P, Req, Resp, Codec>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.transports.poll_recv(cx)
+    }
+}
+
+/// Spawns a task that reads the messages of all clients from `requests`, which must be a
+/// subscription to the request subject, and returns a stream of transports, one per client.
+/// Responses are published through clones of `publisher`. Codecs are created by `codec_fn`.
+///
+/// The task stops when `requests` ends or the returned stream is dropped.
+pub fn listen<P, R, Req, Resp, Codec, CodecFn>(
+    publisher: P,
+    requests: R,
+    codec_fn: CodecFn,
+) -> Incoming<P, Req, Resp, Codec>
+where
+    P: Sink<Message> + Clone + Send + 'static,
+    R: Stream<Item = Message> + Send + 'static,
+    Req: 'static,
+    Resp: 'static,
+    Codec: Send + 'static,
+    CodecFn: Fn() -> Codec + Send + 'static,
+{
+    let (transports_tx, transports) = mpsc::unbounded_channel();
+    util::spawn(
+        format_args!("tarpc::serde_transport::mq::listen"),
+        async move {
+            let mut requests = std::pin::pin!(requests);
+            let mut clients: HashMap<String, mpsc::UnboundedSender<Message>> = HashMap::new();
+            while let Some(mut message) = requests.next().await {
+                let reply_to = match message.reply_to.take() {
+                    Some(reply_to) => reply_to,
+                    None => {
+                        tracing::warn!(subject = %message.subject, "DropMessageWithoutReplySubject");
+                        continue;
+                    }
+                };
+                if message.payload.is_empty() {
+                    tracing::info!(client = %reply_to, "ClientClosed");
+                    clients.remove(&reply_to);
+                    continue;
+                }
+                let client = match clients.entry(reply_to) {
+                    Entry::Occupied(client) if !client.get().is_closed() => client.into_mut(),
+                    entry => {
+                        let (tx, rx) = mpsc::unbounded_channel();
+                        let transport = ServerTransport {
+                            publisher: publisher.clone(),
+                            requests: rx,
+                            codec: codec_fn(),
+                            reply_subject: entry.key().clone(),
+                            ghost: PhantomData,
+                        };
+                        if transports_tx.send(transport).is_err() {
+                            break;
+                        }
+                        tracing::info!(client = %entry.key(), "ClientOpened");
+                        match entry {
+                            Entry::Occupied(mut client) => {
+                                client.insert(tx);
+                                client.into_mut()
+                            }
+                            Entry::Vacant(client) => client.insert(tx),
+                        }
+                    }
+                };
+                let _ = client.send(message);
+            }
+        },
+    );
+    Incoming { transports }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, listen, Message};
+    use crate::{
+        client, context,
+        server::{self, BaseChannel, Channel},
+    };
+    use futures::{channel::mpsc, prelude::*};
+    use std::collections::HashMap;
+    use tokio_serde::formats::Json;
+
+    #[tokio::test]
+    async fn requests_and_responses_are_routed_by_reply_subject() {
+        // An in-memory broker with a single request subject.
+        let (requests_tx, requests) = mpsc::unbounded::<Message>();
+        let (replies_tx, mut replies) = mpsc::unbounded::<Message>();
+        let mut subscribers = HashMap::new();
+        let mut clients: Vec<client::Channel<u32, String>> = vec![];
+        for name in ["a", "b"] {
+            let (tx, rx) = mpsc::unbounded();
+            subscribers.insert(format!("reply.{name}"), tx);
+            let transport = connect(
+                requests_tx.clone(),
+                rx,
+                "service",
+                format!("reply.{name}"),
+                Json::default(),
+            );
+            clients.push(client::new(client::Config::default(), transport).spawn());
+        }
+        tokio::spawn(async move {
+            while let Some(reply) = replies.next().await {
+                assert!(reply.correlation_id.is_some());
+                subscribers[&reply.subject].unbounded_send(reply).unwrap();
+            }
+        });
+
+        let incoming = listen(replies_tx, requests, Json::default);
+        tokio::spawn(incoming.for_each(|transport| async move {
+            let reply_subject = transport.reply_subject().to_string();
+            let serve = server::serve(move |_, i: u32| {
+                let reply_subject = reply_subject.clone();
+                async move { Ok(format!("{reply_subject}: {i}")) }
+            });
+            tokio::spawn(
+                BaseChannel::with_defaults(transport)
+                    .execute(serve)
+                    .for_each(|response| response),
+            );
+        }));
+
+        // Both clients use request ID 0; the server tells them apart by their reply subjects.
+        for (client, name) in clients.iter().zip(["a", "b"]) {
+            assert_eq!(
+                client.call(context::current(), "", 1).await.unwrap(),
+                format!("reply.{name}: 1")
+            );
+        }
+    }
+}
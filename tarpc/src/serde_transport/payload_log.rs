@@ -0,0 +1,282 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides logging of the serialized payloads that transports read and write.
+//!
+//! When a client and a server built from different versions of a service disagree on the wire
+//! format, the error surfaces far from its cause: a
+//! [`MalformedFrame`](crate::transport::MalformedFrame) on one side, or a response that
+//! deserialized into the wrong values. A [`PayloadLog`], attached with
+//! [`Transport::with_payload_log`](super::Transport::with_payload_log), logs the first bytes of
+//! every frame a transport reads and writes as a `debug` event with target `tarpc::payload`, keyed
+//! by the ID and trace ID of the request the frame belongs to, so the payloads of both sides can be
+//! lined up. Frames that fail to deserialize are logged as well, without a key.
+//!
+//! Payloads often contain data that must not end up in logs. The snippets are capped at
+//! [`PayloadLog::max_len`] bytes, and a [redaction](PayloadLog::with_redaction) function can mask
+//! sensitive bytes before they are logged.
+//!
+//! ```rust
+//! # use tarpc::{serde_transport::{self, payload_log::PayloadLog}, ClientMessage, Response};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # fn wrap(io: tokio::io::DuplexStream) {
+//! let log = PayloadLog::new(64).with_redaction(|snippet| {
+//!     // Mask everything after a password field.
+//!     let needle = b"\"password\":";
+//!     if let Some(at) = snippet.windows(needle.len()).position(|w| w == needle) {
+//!         snippet[at + needle.len()..].fill(b'*');
+//!     }
+//! });
+//! let transport: serde_transport::Transport<_, ClientMessage<String>, Response<String>, _> =
+//!     serde_transport::Transport::from((io, Json::default())).with_payload_log(log);
+//! # }
+//! ```
+
+use crate::util::tracing;
+use crate::{trace::TraceId, ClientMessage, Response};
+use fnv::FnvHashMap;
+use std::{fmt, sync::Arc};
+
+/// The most requests whose trace IDs a transport remembers in order to key their responses.
+const MAX_TRACKED_REQUESTS: usize = 4096;
+
+/// A message whose logged payload can be keyed by the request it belongs to.
+pub trait LogKey {
+    /// Returns the ID of the request the message belongs to.
+    fn request_id(&self) -> u64;
+
+    /// Returns the trace ID of the request the message belongs to, if the message carries it.
+    fn trace_id(&self) -> Option<TraceId>;
+}
+
+impl<T> LogKey for ClientMessage<T> {
+    fn request_id(&self) -> u64 {
+        match self {
+            ClientMessage::Request(request) => request.id,
+            ClientMessage::Cancel { request_id, .. } | ClientMessage::Item { request_id, .. } => {
+                *request_id
+            }
+            // A close belongs to no request.
+            ClientMessage::Close { .. } => 0,
+        }
+    }
+
+    fn trace_id(&self) -> Option<TraceId> {
+        match self {
+            ClientMessage::Request(request) => Some(*request.context.trace_id()),
+            ClientMessage::Cancel { trace_context, .. } => Some(trace_context.trace_id),
+            ClientMessage::Close { .. } | ClientMessage::Item { .. } => None,
+        }
+    }
+}
+
+impl<T> LogKey for Response<T> {
+    fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    fn trace_id(&self) -> Option<TraceId> {
+        None
+    }
+}
+
+/// Configures which part of each payload is logged. Cloning the log produces a log with the same
+/// settings.
+#[derive(Clone)]
+pub struct PayloadLog {
+    max_len: usize,
+    redact: Option<Arc<dyn Fn(&mut [u8]) + Send + Sync>>,
+}
+
+impl PayloadLog {
+    /// Returns a log of the first `max_len` bytes of each payload.
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            redact: None,
+        }
+    }
+
+    /// Returns the most bytes logged of each payload.
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Passes each snippet to `redact` before it is logged, so that it can overwrite sensitive
+    /// bytes in place. The snippet is already truncated to [`max_len`](Self::max_len), so it
+    /// may end in the middle of a field.
+    pub fn with_redaction(mut self, redact: impl Fn(&mut [u8]) + Send + Sync + 'static) -> Self {
+        self.redact = Some(Arc::new(redact));
+        self
+    }
+
+    fn snippet(&self, frame: &[u8]) -> Vec<u8> {
+        let mut snippet = frame[..frame.len().min(self.max_len)].to_vec();
+        if let Some(redact) = &self.redact {
+            redact(&mut snippet);
+        }
+        snippet
+    }
+}
+
+impl fmt::Debug for PayloadLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadLog")
+            .field("max_len", &self.max_len)
+            .field("redact", &self.redact.is_some())
+            .finish()
+    }
+}
+
+/// Displays bytes as ASCII, escaping the bytes that are not printable.
+pub(super) struct Escaped<'a>(pub(super) &'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in self.0 {
+            for c in std::ascii::escape_default(byte) {
+                fmt::Write::write_char(f, char::from(c))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs the payloads of one transport. Responses do not carry trace IDs, so the logger remembers
+/// the trace ID of each request it sees until it sees the response.
+pub(super) struct Logger<Item, SinkItem> {
+    log: PayloadLog,
+    read_key: fn(&Item) -> (u64, Option<TraceId>),
+    written_key: fn(&SinkItem) -> (u64, Option<TraceId>),
+    traces: FnvHashMap<u64, TraceId>,
+}
+
+fn key<T: LogKey>(message: &T) -> (u64, Option<TraceId>) {
+    (message.request_id(), message.trace_id())
+}
+
+impl<Item, SinkItem> Logger<Item, SinkItem> {
+    pub(super) fn new(log: PayloadLog) -> Self
+    where
+        Item: LogKey,
+        SinkItem: LogKey,
+    {
+        Self {
+            log,
+            read_key: key::<Item>,
+            written_key: key::<SinkItem>,
+            traces: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns a logger for a transport that reads `NewItem`s instead, e.g. one that deserializes
+    /// the same messages differently.
+    pub(super) fn with_read_item<NewItem: LogKey>(self) -> Logger<NewItem, SinkItem> {
+        Logger {
+            log: self.log,
+            read_key: key::<NewItem>,
+            written_key: self.written_key,
+            traces: self.traces,
+        }
+    }
+
+    pub(super) fn read(&mut self, frame: &[u8], item: &Item) {
+        let (request_id, trace_id) = (self.read_key)(item);
+        self.record("PayloadRead", frame, request_id, trace_id);
+    }
+
+    pub(super) fn written(&mut self, frame: &[u8], item: &SinkItem) {
+        let (request_id, trace_id) = (self.written_key)(item);
+        self.record("PayloadWritten", frame, request_id, trace_id);
+    }
+
+    pub(super) fn malformed(&self, frame: &[u8], error: &dyn fmt::Display) {
+        let snippet = self.log.snippet(frame);
+        tracing::debug!(
+            target: "tarpc::payload",
+            len = frame.len(),
+            snippet = %Escaped(&snippet),
+            %error,
+            "MalformedPayload"
+        );
+    }
+
+    fn record(
+        &mut self,
+        event: &'static str,
+        frame: &[u8],
+        request_id: u64,
+        trace_id: Option<TraceId>,
+    ) {
+        let trace_id = match trace_id {
+            Some(trace_id) => {
+                if self.traces.len() < MAX_TRACKED_REQUESTS {
+                    self.traces.insert(request_id, trace_id);
+                }
+                Some(trace_id)
+            }
+            None => self.traces.remove(&request_id),
+        };
+        if !tracing::enabled!(target: "tarpc::payload", tracing::Level::DEBUG) {
+            return;
+        }
+        let snippet = self.log.snippet(frame);
+        tracing::debug!(
+            target: "tarpc::payload",
+            request_id,
+            trace_id = trace_id.map(tracing::field::display),
+            len = frame.len(),
+            snippet = %Escaped(&snippet),
+            "{}",
+            event
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Escaped, Logger, PayloadLog};
+    use crate::{context, ClientMessage, Request, Response};
+
+    #[test]
+    fn snippet_is_truncated_then_redacted() {
+        let log = PayloadLog::new(8)
+            .with_redaction(|snippet| snippet.iter_mut().skip(4).for_each(|byte| *byte = b'*'));
+        assert_eq!(log.snippet(b"user=secret"), b"user****");
+        assert_eq!(log.snippet(b"ab"), b"ab");
+    }
+
+    #[test]
+    fn snippet_escapes_binary() {
+        assert_eq!(Escaped(b"a\"\x00\xff").to_string(), "a\\\"\\x00\\xff");
+    }
+
+    #[test]
+    fn responses_are_keyed_by_the_trace_of_their_request() {
+        let mut logger = Logger::<Response<()>, ClientMessage<()>>::new(PayloadLog::new(16));
+        let context = context::current();
+        let trace_id = *context.trace_id();
+        let request = ClientMessage::Request(Request {
+            context,
+            id: 7,
+            message: (),
+            one_way: false,
+            streamed: false,
+        });
+        assert_eq!((logger.written_key)(&request), (7, Some(trace_id)));
+        logger.written(b"request", &request);
+        assert_eq!(logger.traces.get(&7), Some(&trace_id));
+
+        let response = Response {
+            request_id: 7,
+            message: Ok(()),
+            more: false,
+        };
+        assert_eq!((logger.read_key)(&response), (7, None));
+        logger.read(b"response", &response);
+        assert!(logger.traces.is_empty());
+    }
+}
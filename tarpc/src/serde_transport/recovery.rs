@@ -0,0 +1,186 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides functions that recover the request ID of a frame that fails to deserialize.
+//!
+//! A frame usually fails to deserialize because of its message, e.g. a request whose arguments
+//! don't match the server's request type, while the header that precedes the message is intact.
+//! Given one of these functions, [`Transport::with_request_id_recovery`] attaches the ID decoded
+//! from the header to the [`MalformedFrame`], so that a channel configured with
+//! [`MalformedFramePolicy::Respond`] fails just the request carried by the frame.
+//!
+//! Servers read [`ClientMessage`](crate::ClientMessage) frames and clients read
+//! [`Response`](crate::Response) frames, so each format has one function for each side. The
+//! bincode functions expect the options of [`Bincode::default`](tokio_serde::formats::Bincode).
+//!
+//! ```rust
+//! # use tarpc::{serde_transport::{self, recovery}, ClientMessage, Response};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # use tarpc::tokio_util::codec::LengthDelimitedCodec;
+//! # fn wrap(io: tokio::io::DuplexStream) {
+//! let transport = serde_transport::new::<_, ClientMessage<String>, Response<String>, _>(
+//!     LengthDelimitedCodec::builder().new_framed(io),
+//!     Json::default(),
+//! )
+//! .with_request_id_recovery(recovery::json_request_id);
+//! # }
+//! ```
+//!
+//! [`Transport::with_request_id_recovery`]: super::Transport::with_request_id_recovery
+//! [`MalformedFrame`]: crate::transport::MalformedFrame
+//! [`MalformedFramePolicy::Respond`]: crate::transport::MalformedFramePolicy::Respond
+
+use crate::context;
+use serde::Deserialize;
+
+/// The leading fields of a [`ClientMessage`](crate::ClientMessage) carrying a request. Requests
+/// using fields added since tarpc 0.34 are followed by an `Extension`, which is skipped.
+#[derive(Deserialize)]
+enum ClientMessageHeader<Extension> {
+    Request(RequestHeader),
+    // Other messages carry no request to respond to, but hold their place among the variants.
+    Cancel,
+    Close,
+    Item,
+    ExtendedRequest(RequestHeader, Extension),
+}
+
+/// The leading fields of a [`Request`](crate::Request).
+#[derive(Deserialize)]
+struct RequestHeader {
+    // Decoded only to skip over it.
+    #[allow(dead_code)]
+    context: context::Context,
+    id: u64,
+}
+
+/// The leading fields of a [`Response`](crate::Response).
+#[derive(Deserialize)]
+struct ResponseHeader {
+    request_id: u64,
+}
+
+impl<Extension> ClientMessageHeader<Extension> {
+    fn request_id(self) -> Option<u64> {
+        match self {
+            ClientMessageHeader::Request(request)
+            | ClientMessageHeader::ExtendedRequest(request, _) => Some(request.id),
+            ClientMessageHeader::Cancel
+            | ClientMessageHeader::Close
+            | ClientMessageHeader::Item => None,
+        }
+    }
+}
+
+/// Returns the ID of the request carried by a JSON-serialized
+/// [`ClientMessage`](crate::ClientMessage) frame, if its header can be decoded.
+#[cfg(feature = "serde-transport-json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-json")))]
+pub fn json_request_id(frame: &[u8]) -> Option<u64> {
+    serde_json::from_slice::<ClientMessageHeader<serde::de::IgnoredAny>>(frame)
+        .ok()
+        .and_then(ClientMessageHeader::request_id)
+}
+
+/// Returns the ID of the request answered by a JSON-serialized [`Response`](crate::Response)
+/// frame, if its header can be decoded.
+#[cfg(feature = "serde-transport-json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-json")))]
+pub fn json_response_id(frame: &[u8]) -> Option<u64> {
+    serde_json::from_slice::<ResponseHeader>(frame)
+        .ok()
+        .map(|response| response.request_id)
+}
+
+/// Returns the ID of the request carried by a bincode-serialized
+/// [`ClientMessage`](crate::ClientMessage) frame, if its header can be decoded.
+#[cfg(feature = "serde-transport-bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-bincode")))]
+pub fn bincode_request_id(frame: &[u8]) -> Option<u64> {
+    use bincode::Options;
+
+    // Bincode can't skip values of unknown types, but needn't: the extension is the frame's tail.
+    bincode::DefaultOptions::new()
+        .allow_trailing_bytes()
+        .deserialize::<ClientMessageHeader<()>>(frame)
+        .ok()
+        .and_then(ClientMessageHeader::request_id)
+}
+
+/// Returns the ID of the request answered by a bincode-serialized [`Response`](crate::Response)
+/// frame, if its header can be decoded.
+#[cfg(feature = "serde-transport-bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-bincode")))]
+pub fn bincode_response_id(frame: &[u8]) -> Option<u64> {
+    use bincode::Options;
+
+    bincode::DefaultOptions::new()
+        .allow_trailing_bytes()
+        .deserialize::<ResponseHeader>(frame)
+        .ok()
+        .map(|response| response.request_id)
+}
+
+#[cfg(all(
+    test,
+    feature = "serde-transport-json",
+    feature = "serde-transport-bincode"
+))]
+mod tests {
+    use super::{bincode_request_id, bincode_response_id, json_request_id, json_response_id};
+    use crate::{context, ClientMessage, Request, Response};
+    use bincode::Options;
+
+    fn request(streamed: bool) -> ClientMessage<String> {
+        ClientMessage::Request(Request {
+            context: context::current(),
+            id: 7,
+            message: "hello".into(),
+            one_way: false,
+            streamed,
+        })
+    }
+
+    fn response() -> Response<String> {
+        Response {
+            request_id: 7,
+            message: Ok("hello".into()),
+            more: false,
+        }
+    }
+
+    #[test]
+    fn recovers_json_ids() {
+        for streamed in [false, true] {
+            assert_eq!(
+                json_request_id(&serde_json::to_vec(&request(streamed)).unwrap()),
+                Some(7)
+            );
+        }
+        assert_eq!(
+            json_response_id(&serde_json::to_vec(&response()).unwrap()),
+            Some(7)
+        );
+        assert_eq!(json_request_id(b"{\"Close\":{}}"), None);
+        assert_eq!(json_response_id(b"garbage"), None);
+    }
+
+    #[test]
+    fn recovers_bincode_ids() {
+        let options = bincode::DefaultOptions::new();
+        for streamed in [false, true] {
+            assert_eq!(
+                bincode_request_id(&options.serialize(&request(streamed)).unwrap()),
+                Some(7)
+            );
+        }
+        assert_eq!(
+            bincode_response_id(&options.serialize(&response()).unwrap()),
+            Some(7)
+        );
+        assert_eq!(bincode_request_id(&[2]), None);
+    }
+}
@@ -0,0 +1,807 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a transport between processes on the same host over shared memory.
+//!
+//! Even over loopback TCP or a Unix domain socket, every message costs system calls on both ends.
+//! A [`ShmStream`] instead exchanges bytes through two ring buffers in a memory region shared by
+//! both processes, one for each direction. Reading and writing copy bytes out of and into the
+//! rings without entering the kernel; a process only makes a system call, signaling an eventfd,
+//! when its peer is parked waiting for data or for space. The memory region and eventfds are
+//! passed to the connecting process over a Unix domain socket, which is closed once the
+//! connection is set up.
+//!
+//! Because nothing outside the shared memory tracks the connection, a peer that exits without
+//! closing its transport is not noticed; bound how long requests may wait with deadlines, and how
+//! long a server channel may idle with
+//! [`Config::read_idle_timeout`](crate::server::Config::read_idle_timeout).
+//!
+//! ```no_run
+//! use futures::prelude::*;
+//! use tarpc::{serde_transport::shm, tokio_serde::formats::Bincode};
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let mut listener = shm::listen("/tmp/tarpc.sock", Bincode::<String, String>::default).await?;
+//! tokio::spawn(async move {
+//!     while let Some(Ok(mut transport)) = listener.next().await {
+//!         tokio::spawn(async move {
+//!             while let Some(Ok(message)) = transport.next().await {
+//!                 let _ = transport.send(message).await;
+//!             }
+//!         });
+//!     }
+//! });
+//! let mut transport = shm::connect("/tmp/tarpc.sock", Bincode::<String, String>::default).await?;
+//! transport.send("ping".to_string()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{new, Transport};
+use futures::{prelude::*, ready, stream::BoxStream};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    mem,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+    pin::Pin,
+    ptr,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, Interest, ReadBuf},
+    net::{UnixListener, UnixStream},
+};
+use tokio_serde::{Deserializer, Serializer};
+use tokio_util::codec::length_delimited::{self, LengthDelimitedCodec};
+
+/// The default capacity of each ring buffer, in bytes.
+pub const DEFAULT_RING_CAPACITY: usize = 1 << 20;
+
+/// Identifies a memory region set up by this module.
+const MAGIC: u64 = u64::from_be_bytes(*b"tarpcshm");
+
+/// Both processes access the region through this layout: the region header, then the header of
+/// the ring carrying bytes from client to server, then that of the ring carrying bytes from
+/// server to client, then the data of each ring in the same order.
+#[repr(C, align(64))]
+struct RegionHeader {
+    magic: AtomicU64,
+    ring_capacity: AtomicU64,
+}
+
+/// The state of a ring, shared by the process writing to it and the process reading from it.
+/// Positions count the bytes written and read since the ring was created.
+#[repr(C, align(64))]
+struct RingHeader {
+    /// Advanced by the writer.
+    write_pos: AtomicU64,
+    /// Advanced by the reader.
+    read_pos: AtomicU64,
+    /// Set while the reader waits for the writer to signal that it wrote data.
+    reader_parked: AtomicU32,
+    /// Set while the writer waits for the reader to signal that it freed space.
+    writer_parked: AtomicU32,
+    /// Set once the writer will write no more.
+    writer_closed: AtomicU32,
+    /// Set once the reader will read no more.
+    reader_closed: AtomicU32,
+}
+
+const RING_DATA_OFFSET: usize = mem::size_of::<RegionHeader>() + 2 * mem::size_of::<RingHeader>();
+
+fn region_len(ring_capacity: usize) -> io::Result<usize> {
+    ring_capacity
+        .checked_mul(2)
+        .and_then(|data_len| data_len.checked_add(RING_DATA_OFFSET))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ring capacity too large"))
+}
+
+/// Converts the return value of a libc call to a result.
+fn cvt(ret: i32) -> io::Result<i32> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// An owned file descriptor, closed when dropped.
+#[derive(Debug)]
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned and not used after this.
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl Fd {
+    fn event() -> io::Result<Self> {
+        // SAFETY: eventfd takes no pointers.
+        cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) }).map(Fd)
+    }
+
+    /// Wakes the process waiting on this eventfd.
+    fn signal(&self) {
+        let one = 1u64;
+        // SAFETY: writes 8 bytes from a valid u64. The only possible failure is the counter
+        // overflowing, in which case the waiter is already woken.
+        unsafe { libc::write(self.0, ptr::addr_of!(one).cast(), mem::size_of::<u64>()) };
+    }
+
+    /// Resets this eventfd after a wakeup.
+    fn drain(&self) {
+        let mut count = 0u64;
+        // SAFETY: reads at most 8 bytes into a valid u64. Fails with EAGAIN if the counter is
+        // already zero.
+        unsafe {
+            libc::read(
+                self.0,
+                ptr::addr_of_mut!(count).cast(),
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+/// The shared memory region, mapped into this process.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &Fd, len: usize) -> io::Result<Self> {
+        // SAFETY: maps a fresh region; no existing memory is affected.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.0,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    fn header(&self) -> &RegionHeader {
+        // SAFETY: the region is at least RING_DATA_OFFSET bytes long and page aligned, and its
+        // headers consist of atomics, for which any bit pattern is valid.
+        unsafe { &*self.ptr.cast() }
+    }
+
+    fn ring(&self, index: usize, capacity: usize) -> Ring {
+        // SAFETY: see `header`; the data of both rings lies within the region.
+        unsafe {
+            Ring {
+                header: self
+                    .ptr
+                    .add(mem::size_of::<RegionHeader>() + index * mem::size_of::<RingHeader>())
+                    .cast(),
+                data: self.ptr.add(RING_DATA_OFFSET + index * capacity),
+                capacity: capacity as u64,
+            }
+        }
+    }
+}
+
+// SAFETY: the region is only accessed through the atomics in its headers and through the rings,
+// whose access is coordinated by those atomics.
+unsafe impl Send for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the region is not accessed after the mapping is dropped.
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// One of the ring buffers in the shared memory region.
+struct Ring {
+    header: *const RingHeader,
+    data: *mut u8,
+    capacity: u64,
+}
+
+impl Ring {
+    fn header(&self) -> &RingHeader {
+        // SAFETY: points into the mapping, which outlives the ring.
+        unsafe { &*self.header }
+    }
+
+    /// Returns the number of bytes written but not yet read, checking that the peer has not
+    /// corrupted the ring.
+    fn len(&self, write_pos: u64, read_pos: u64) -> io::Result<u64> {
+        let len = write_pos.wrapping_sub(read_pos);
+        if len > self.capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared memory ring is corrupt",
+            ));
+        }
+        Ok(len)
+    }
+
+    /// Copies `len` bytes between the ring, starting at position `pos`, and `buf`, in the
+    /// direction given by `copy`.
+    fn copy(&self, pos: u64, len: usize, mut copy: impl FnMut(*mut u8, usize, usize)) {
+        let start = (pos % self.capacity) as usize;
+        let first = len.min(self.capacity as usize - start);
+        // SAFETY: `start + first` and `len - first` are within the ring's data.
+        copy(unsafe { self.data.add(start) }, 0, first);
+        copy(self.data, first, len - first);
+    }
+}
+
+/// A byte stream to a process on the same host, through shared memory. Created by [`connect`]
+/// and [`listen`].
+pub struct ShmStream {
+    /// Keeps the region mapped while the rings are in use.
+    _mapping: Mapping,
+    inbound: Ring,
+    outbound: Ring,
+    /// Signaled by the peer when it writes to the inbound ring.
+    data_written: AsyncFd<Fd>,
+    /// Signaled by the peer when it reads from the outbound ring.
+    space_freed: AsyncFd<Fd>,
+    /// Signaled when writing to the outbound ring.
+    peer_data_written: Fd,
+    /// Signaled when reading from the inbound ring.
+    peer_space_freed: Fd,
+}
+
+// SAFETY: the rings point into the mapping owned by the stream, and the state the stream shares
+// with its peer is only accessed through atomics.
+unsafe impl Send for ShmStream {}
+
+impl ShmStream {
+    /// Returns the capacity of each ring buffer, in bytes.
+    pub fn ring_capacity(&self) -> usize {
+        self.inbound.capacity as usize
+    }
+}
+
+impl fmt::Debug for ShmStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShmStream")
+            .field("ring_capacity", &self.ring_capacity())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for ShmStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let ring = &self.inbound;
+        let header = ring.header();
+        loop {
+            let write_pos = header.write_pos.load(Ordering::Acquire);
+            let read_pos = header.read_pos.load(Ordering::Relaxed);
+            let available = ring.len(write_pos, read_pos)?;
+            if available > 0 {
+                let len = buf.remaining().min(available as usize);
+                let unfilled = buf.initialize_unfilled_to(len);
+                ring.copy(read_pos, len, |src, offset, count| {
+                    // SAFETY: the ring's data and `unfilled` don't overlap.
+                    unsafe { ptr::copy_nonoverlapping(src, unfilled[offset..].as_mut_ptr(), count) }
+                });
+                buf.advance(len);
+                header
+                    .read_pos
+                    .store(read_pos + len as u64, Ordering::Release);
+                fence(Ordering::SeqCst);
+                if header.writer_parked.load(Ordering::Relaxed) != 0 {
+                    self.peer_space_freed.signal();
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if header.writer_closed.load(Ordering::Acquire) != 0 {
+                return Poll::Ready(Ok(()));
+            }
+            // Announce that the reader is parking before checking once more for data, so that a
+            // write racing with the check signals the reader.
+            header.reader_parked.store(1, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            if header.write_pos.load(Ordering::Relaxed) == write_pos
+                && header.writer_closed.load(Ordering::Relaxed) == 0
+            {
+                let mut ready = ready!(self.data_written.poll_read_ready(cx))?;
+                ready.get_inner().drain();
+                ready.clear_ready();
+            }
+            header.reader_parked.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AsyncWrite for ShmStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ring = &self.outbound;
+        let header = ring.header();
+        loop {
+            if header.reader_closed.load(Ordering::Acquire) != 0
+                || header.writer_closed.load(Ordering::Relaxed) != 0
+            {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            let write_pos = header.write_pos.load(Ordering::Relaxed);
+            let read_pos = header.read_pos.load(Ordering::Acquire);
+            let free = ring.capacity - ring.len(write_pos, read_pos)?;
+            if free > 0 {
+                let len = data.len().min(free as usize);
+                ring.copy(write_pos, len, |dst, offset, count| {
+                    // SAFETY: the ring's data and `data` don't overlap.
+                    unsafe { ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, count) }
+                });
+                header
+                    .write_pos
+                    .store(write_pos + len as u64, Ordering::Release);
+                fence(Ordering::SeqCst);
+                if header.reader_parked.load(Ordering::Relaxed) != 0 {
+                    self.peer_data_written.signal();
+                }
+                return Poll::Ready(Ok(len));
+            }
+            // Announce that the writer is parking before checking once more for space, so that a
+            // read racing with the check signals the writer.
+            header.writer_parked.store(1, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            if header.read_pos.load(Ordering::Relaxed) == read_pos
+                && header.reader_closed.load(Ordering::Relaxed) == 0
+            {
+                let mut ready = ready!(self.space_freed.poll_read_ready(cx))?;
+                ready.get_inner().drain();
+                ready.clear_ready();
+            }
+            header.writer_parked.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Written bytes are visible to the reader immediately.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound
+            .header()
+            .writer_closed
+            .store(1, Ordering::Release);
+        self.peer_data_written.signal();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ShmStream {
+    fn drop(&mut self) {
+        self.outbound
+            .header()
+            .writer_closed
+            .store(1, Ordering::Release);
+        self.inbound
+            .header()
+            .reader_closed
+            .store(1, Ordering::Release);
+        self.peer_data_written.signal();
+        self.peer_space_freed.signal();
+    }
+}
+
+/// The number of descriptors passed to the connecting process: the memory region, then the
+/// eventfds signaled when the client writes and reads, then those signaled when the server writes
+/// and reads.
+const PASSED_FDS: usize = 5;
+
+/// The seals that keep the memory region of a connection from being resized.
+const RESIZE_SEALS: std::os::raw::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+/// Checks that `region` is sealed against resizing, so that its size, and with it the mapping of
+/// the region, stays valid.
+fn check_seals(region: &Fd) -> io::Result<()> {
+    // SAFETY: takes no pointers.
+    let seals = cvt(unsafe { libc::fcntl(region.0, libc::F_GET_SEALS) })?;
+    if seals & RESIZE_SEALS != RESIZE_SEALS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "shared memory region is not sealed against resizing",
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the memory region of a connection.
+fn create(ring_capacity: usize) -> io::Result<(Fd, Mapping)> {
+    if ring_capacity == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ring capacity must be greater than zero",
+        ));
+    }
+    let len = region_len(ring_capacity)?;
+    // SAFETY: the name is a valid C string.
+    let region = Fd(cvt(unsafe {
+        libc::memfd_create(
+            b"tarpc-shm\0".as_ptr().cast(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    })?);
+    // SAFETY: takes no pointers.
+    cvt(unsafe { libc::ftruncate(region.0, len as libc::off_t) })?;
+    // Neither process may resize the region once it is mapped: accessing a page past the end of
+    // a shrunk region raises SIGBUS.
+    // SAFETY: takes no pointers.
+    cvt(unsafe { libc::fcntl(region.0, libc::F_ADD_SEALS, RESIZE_SEALS) })?;
+    let mapping = Mapping::new(&region, len)?;
+    // A new memfd is zeroed, so the rings start empty and open.
+    mapping
+        .header()
+        .ring_capacity
+        .store(ring_capacity as u64, Ordering::Relaxed);
+    mapping.header().magic.store(MAGIC, Ordering::Release);
+    Ok((region, mapping))
+}
+
+/// Sets up the server's end of a connection accepted on `socket`.
+async fn accept(socket: UnixStream, ring_capacity: usize) -> io::Result<ShmStream> {
+    let (region, mapping) = create(ring_capacity)?;
+    let server_data_written = Fd::event()?;
+    let server_space_freed = Fd::event()?;
+    let client_data_written = Fd::event()?;
+    let client_space_freed = Fd::event()?;
+    let fds = [
+        region.0,
+        server_data_written.0,
+        server_space_freed.0,
+        client_data_written.0,
+        client_space_freed.0,
+    ];
+    loop {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || send_fds(socket.as_raw_fd(), &fds)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => break result?,
+        }
+    }
+    Ok(ShmStream {
+        inbound: mapping.ring(0, ring_capacity),
+        outbound: mapping.ring(1, ring_capacity),
+        _mapping: mapping,
+        data_written: AsyncFd::with_interest(server_data_written, Interest::READABLE)?,
+        space_freed: AsyncFd::with_interest(server_space_freed, Interest::READABLE)?,
+        peer_data_written: client_data_written,
+        peer_space_freed: client_space_freed,
+    })
+}
+
+/// Sets up the client's end of a connection over `socket`.
+async fn join(socket: UnixStream) -> io::Result<ShmStream> {
+    let fds = loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || recv_fds(socket.as_raw_fd())) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => break result?,
+        }
+    };
+    let [region, server_data_written, server_space_freed, client_data_written, client_space_freed] =
+        fds;
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    check_seals(&region)?;
+    // SAFETY: `stat` is plain data that fstat fills in.
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    cvt(unsafe { libc::fstat(region.0, &mut stat) })?;
+    let region_size = stat.st_size as usize;
+    if region_size < RING_DATA_OFFSET {
+        return Err(invalid("shared memory region too small"));
+    }
+    let header = Mapping::new(&region, RING_DATA_OFFSET)?;
+    if header.header().magic.load(Ordering::Acquire) != MAGIC {
+        return Err(invalid("not a tarpc shared memory region"));
+    }
+    let ring_capacity = header.header().ring_capacity.load(Ordering::Relaxed) as usize;
+    let len = region_len(ring_capacity)?;
+    if ring_capacity == 0 || region_size < len {
+        return Err(invalid("shared memory region too small"));
+    }
+    let mapping = Mapping::new(&region, len)?;
+    Ok(ShmStream {
+        inbound: mapping.ring(1, ring_capacity),
+        outbound: mapping.ring(0, ring_capacity),
+        _mapping: mapping,
+        data_written: AsyncFd::with_interest(client_data_written, Interest::READABLE)?,
+        space_freed: AsyncFd::with_interest(client_space_freed, Interest::READABLE)?,
+        peer_data_written: server_data_written,
+        peer_space_freed: server_space_freed,
+    })
+}
+
+/// A buffer for one control message carrying `PASSED_FDS` descriptors, aligned for `cmsghdr`.
+type ControlBuf = [u64; 8];
+
+fn send_fds(socket: RawFd, fds: &[RawFd; PASSED_FDS]) -> io::Result<()> {
+    let mut payload = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let mut control: ControlBuf = [0; 8];
+    let fds_len = mem::size_of_val(fds) as u32;
+    // SAFETY: msghdr is plain data, and the control buffer is large and aligned enough for one
+    // control message carrying `fds`.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        if libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fds(socket: RawFd) -> io::Result<[Fd; PASSED_FDS]> {
+    let mut payload = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let mut control: ControlBuf = [0; 8];
+    // Every descriptor received is owned from the start, so that those of a message that turns
+    // out to be invalid are closed.
+    let mut fds = Vec::with_capacity(PASSED_FDS);
+    // SAFETY: msghdr is plain data; recvmsg writes at most `msg_controllen` bytes of control
+    // messages, and descriptors are only read from control messages that carry them.
+    let received = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of::<ControlBuf>() as _;
+        let received = libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    fds.push(Fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        received
+    };
+    match <[Fd; PASSED_FDS]>::try_from(fds) {
+        Ok(fds) if received > 0 => Ok(fds),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the server did not pass a shared memory region",
+        )),
+    }
+}
+
+impl<Item, SinkItem, Codec> Transport<ShmStream, Item, SinkItem, Codec> {
+    /// Returns the capacity of each ring buffer of the underlying [`ShmStream`], in bytes.
+    pub fn ring_capacity(&self) -> usize {
+        self.inner.get_ref().ring_capacity()
+    }
+}
+
+/// Connects to the process listening on the Unix domain socket at `path`, wrapping the
+/// connection in a shared memory transport.
+pub async fn connect<P, Item, SinkItem, Codec, CodecFn>(
+    path: P,
+    codec_fn: CodecFn,
+) -> io::Result<Transport<ShmStream, Item, SinkItem, Codec>>
+where
+    P: AsRef<Path>,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    let io = join(UnixStream::connect(path).await?).await?;
+    Ok(new(
+        LengthDelimitedCodec::builder().new_framed(io),
+        codec_fn(),
+    ))
+}
+
+/// Listens on the Unix domain socket at `path`, wrapping accepted connections in shared memory
+/// transports.
+pub async fn listen<P, Item, SinkItem, Codec, CodecFn>(
+    path: P,
+    codec_fn: CodecFn,
+) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+where
+    P: AsRef<Path>,
+    Item: for<'de> Deserialize<'de>,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    let listener = UnixListener::bind(path)?;
+    let accept = stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(conn, _)| conn);
+        Some((conn, listener))
+    })
+    .boxed();
+    Ok(Incoming {
+        accept,
+        handshake: None,
+        ring_capacity: DEFAULT_RING_CAPACITY,
+        codec_fn,
+        config: LengthDelimitedCodec::builder(),
+        ghost: PhantomData,
+    })
+}
+
+/// A [`UnixListener`] that sets up shared memory for accepted connections and wraps them in
+/// [transports](Transport).
+#[pin_project]
+pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+    accept: BoxStream<'static, io::Result<UnixStream>>,
+    handshake: Option<future::BoxFuture<'static, io::Result<ShmStream>>>,
+    ring_capacity: usize,
+    codec_fn: CodecFn,
+    config: length_delimited::Builder,
+    ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+}
+
+impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("ring_capacity", &self.ring_capacity)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+    /// Sets the capacity of each ring buffer of connections accepted from now on, in bytes.
+    /// Defaults to [`DEFAULT_RING_CAPACITY`].
+    pub fn set_ring_capacity(&mut self, ring_capacity: usize) {
+        self.ring_capacity = ring_capacity;
+    }
+
+    /// Returns an immutable reference to the length-delimited codec's config.
+    pub fn config(&self) -> &length_delimited::Builder {
+        &self.config
+    }
+
+    /// Returns a mutable reference to the length-delimited codec's config.
+    pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+        &mut self.config
+    }
+}
+
+impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    type Item = io::Result<Transport<ShmStream, Item, SinkItem, Codec>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let handshake = match this.handshake {
+            Some(handshake) => handshake,
+            None => {
+                let conn = match ready!(this.accept.poll_next_unpin(cx)) {
+                    Some(conn) => conn?,
+                    None => return Poll::Ready(None),
+                };
+                this.handshake
+                    .insert(accept(conn, *this.ring_capacity).boxed())
+            }
+        };
+        let io = ready!(handshake.poll_unpin(cx));
+        *this.handshake = None;
+        Poll::Ready(Some(Ok(new(
+            this.config.new_framed(io?),
+            (this.codec_fn)(),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_seals, connect, create, cvt, listen, Fd};
+    use crate::serde_transport::unix::TempPathBuf;
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::io;
+    use tokio_serde::formats::SymmetricalJson;
+
+    #[test]
+    fn regions_must_be_sealed_against_resizing() -> io::Result<()> {
+        let (region, _mapping) = create(100)?;
+        check_seals(&region)?;
+
+        // SAFETY: the name is a valid C string.
+        let unsealed = Fd(cvt(unsafe {
+            libc::memfd_create(b"tarpc-shm\0".as_ptr().cast(), libc::MFD_CLOEXEC)
+        })?);
+        assert_matches!(check_seals(&unsealed), Err(e) if e.kind() == io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn echo_wraps_around_small_rings() -> io::Result<()> {
+        let path = TempPathBuf::with_random("shm");
+        let mut listener = listen(&path, SymmetricalJson::<String>::default).await?;
+        listener.set_ring_capacity(100);
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            while let Some(message) = transport.next().await {
+                transport.send(message.unwrap()).await.unwrap();
+            }
+        });
+
+        let mut transport = connect(&path, SymmetricalJson::<String>::default).await?;
+        assert_eq!(transport.ring_capacity(), 100);
+        for len in [1, 50, 300, 7] {
+            let message = "x".repeat(len);
+            transport.send(message.clone()).await?;
+            assert_matches!(transport.next().await, Some(Ok(s)) if s == message);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_peer_ends_stream() -> io::Result<()> {
+        let path = TempPathBuf::with_random("shm");
+        let mut listener = listen(&path, SymmetricalJson::<String>::default).await?;
+        let server = tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            transport.send("bye".to_string()).await.unwrap();
+        });
+
+        let mut transport = connect(&path, SymmetricalJson::<String>::default).await?;
+        server.await.unwrap();
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "bye");
+        assert_matches!(transport.next().await, None);
+        assert_matches!(transport.send("hello?".to_string()).await, Err(_));
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides counters of the wire-level work done by transports.
+//!
+//! A [`TransportStats`] handle, attached with
+//! [`Transport::with_stats`](super::Transport::with_stats), counts the frames and bytes a
+//! transport reads and writes, how often it flushes, and how long its writers wait for it to
+//! become ready. Comparing the counters makes wire-level inefficiencies observable: many frames
+//! per flush, or few bytes per frame, point to a storm of tiny writes. Transports attached to the
+//! same handle add to the same counters, so a handle can track a single connection or all
+//! connections of a server.
+//!
+//! ```rust
+//! # use tarpc::serde_transport::{self, stats::TransportStats};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # fn wrap(io: tokio::io::DuplexStream) {
+//! let stats = TransportStats::new();
+//! let transport = serde_transport::Transport::<_, String, String, _>::from((io, Json::default()))
+//!     .with_stats(stats.clone());
+//! // ... later, e.g. from an admin endpoint:
+//! let snapshot = stats.snapshot();
+//! println!("{} frames in {} flushes", snapshot.frames_written, snapshot.flushes);
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The counters of a [`TransportStats`] at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StatsSnapshot {
+    /// The number of frame bytes read, excluding length prefixes.
+    pub bytes_read: u64,
+    /// The number of frame bytes written, excluding length prefixes.
+    pub bytes_written: u64,
+    /// The number of frames read.
+    pub frames_read: u64,
+    /// The number of frames written.
+    pub frames_written: u64,
+    /// The number of completed flushes.
+    pub flushes: u64,
+    /// The total time writers waited for a transport to become ready to accept a frame.
+    pub blocked_on_ready: Duration,
+}
+
+#[derive(Default)]
+struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    frames_read: AtomicU64,
+    frames_written: AtomicU64,
+    flushes: AtomicU64,
+    blocked_on_ready_nanos: AtomicU64,
+}
+
+/// Counts the wire-level work done by the transports it is attached to. Cloning the handle
+/// produces a handle to the same counters.
+#[derive(Clone, Default)]
+pub struct TransportStats(Arc<Counters>);
+
+impl TransportStats {
+    /// Returns a handle to new counters, all zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let counters = &self.0;
+        StatsSnapshot {
+            bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            frames_read: counters.frames_read.load(Ordering::Relaxed),
+            frames_written: counters.frames_written.load(Ordering::Relaxed),
+            flushes: counters.flushes.load(Ordering::Relaxed),
+            blocked_on_ready: Duration::from_nanos(
+                counters.blocked_on_ready_nanos.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    pub(super) fn record_frame_read(&self, len: usize) {
+        self.0.frames_read.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_frame_written(&self, len: usize) {
+        self.0.frames_written.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .bytes_written
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_flush(&self) {
+        self.0.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_blocked_on_ready(&self, blocked: Duration) {
+        let nanos = u64::try_from(blocked.as_nanos()).unwrap_or(u64::MAX);
+        self.0
+            .blocked_on_ready_nanos
+            .fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for TransportStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TransportStats")
+            .field(&self.snapshot())
+            .finish()
+    }
+}
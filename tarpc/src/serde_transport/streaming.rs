@@ -0,0 +1,483 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Responses that are written to the wire in chunks as they are produced.
+//!
+//! A service whose response type is [`Body`] can return very large responses without
+//! materializing them in memory: a body is a stream of byte chunks, produced by an
+//! [`AsyncRead`], by any [`Stream`], or by a serializer writing to a [`Write`].
+//!
+//! On the server, [`StreamResponses`] wraps a transport of [`ResponseFrame`]s and writes each body
+//! as a sequence of [`Chunk`](ResponseFrame::Chunk) frames followed by an
+//! [`End`](ResponseFrame::End) frame. The chunks of concurrent bodies are interleaved, so one
+//! large response does not hold up the others. On the client, [`CollectResponses`] reassembles
+//! the frames into complete responses.
+//!
+//! Once a body has been handed to the transport, it is written out to completion even if the
+//! request is canceled.
+
+use crate::util::tracing;
+use crate::{Response, ServerError};
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHashMap;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+    pin::Pin,
+};
+use tokio::{io::AsyncRead, sync::mpsc};
+use tokio_util::io::ReaderStream;
+
+/// The maximum size of the chunks produced by [`Body::from_reader`] and [`Body::from_writer`].
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// A response body that is produced and sent one chunk at a time.
+pub struct Body(Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>);
+
+impl Body {
+    /// Returns a body that sends the chunks yielded by `stream`.
+    ///
+    /// If the stream yields an error, the response is completed with that error.
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self(Box::pin(stream))
+    }
+
+    /// Returns a body that sends the contents of `reader`.
+    pub fn from_reader<R>(reader: R) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        Self::from_stream(ReaderStream::with_capacity(reader, CHUNK_LEN))
+    }
+
+    /// Returns a body that sends everything written by `write`, which runs on Tokio's blocking
+    /// thread pool. Writes block while the chunks already produced have not been sent, so that at
+    /// most a few chunks are buffered at any time.
+    ///
+    /// This is meant for serializers that write to an [`io::Write`], e.g.
+    /// `bincode::serialize_into`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn from_writer<F>(write: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::task::spawn_blocking(move || {
+            let mut writer = ChunkWriter {
+                chunk: BytesMut::new(),
+                tx,
+            };
+            let result = write(&mut writer).and_then(|()| writer.flush());
+            if let Err(e) = result {
+                let _ = writer.tx.blocking_send(Err(e));
+            }
+        });
+        Self::from_stream(stream::poll_fn(move |cx| rx.poll_recv(cx)))
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        Self::from_stream(stream::iter([Ok(bytes)]))
+    }
+}
+
+impl Stream for Body {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Body")
+    }
+}
+
+/// Buffers writes into chunks of up to [`CHUNK_LEN`] bytes, and sends each full chunk to a
+/// [`Body`].
+struct ChunkWriter {
+    chunk: BytesMut,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_LEN - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..len]);
+        if self.chunk.len() == CHUNK_LEN {
+            self.flush()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .blocking_send(Ok(self.chunk.split().freeze()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the body was dropped"))
+    }
+}
+
+/// A piece of a streamed response, as sent over the wire.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseFrame {
+    /// The next bytes of the body of a response.
+    Chunk {
+        /// The ID of the request being responded to.
+        request_id: u64,
+        /// The bytes of the body.
+        data: Bytes,
+    },
+    /// The end of a response. No more frames are sent for the request.
+    End {
+        /// The ID of the request being responded to.
+        request_id: u64,
+        /// An error if the request failed, possibly after part of the body was sent.
+        result: Result<(), ServerError>,
+    },
+}
+
+/// A server transport that writes [`Body`] responses to a transport of [`ResponseFrame`]s.
+#[pin_project]
+#[derive(Debug)]
+pub struct StreamResponses<T> {
+    #[pin]
+    transport: T,
+    /// Bodies that are being written, in the order in which their next chunk will be sent.
+    bodies: VecDeque<(u64, Body)>,
+}
+
+impl<T> StreamResponses<T> {
+    /// Returns a transport that writes responses to `transport` in chunks.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            bodies: VecDeque::new(),
+        }
+    }
+
+    /// Returns the inner transport over which messages are sent and received.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Writes the available chunks of all bodies, round-robin. Returns Ready once every body has
+    /// been written in full.
+    fn poll_bodies(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>>
+    where
+        T: Sink<ResponseFrame>,
+    {
+        let mut this = self.project();
+        // The number of consecutive bodies that had nothing to send.
+        let mut idle = 0;
+        while idle < this.bodies.len() {
+            ready!(this.transport.as_mut().poll_ready(cx))?;
+            let (request_id, mut body) = this.bodies.pop_front().expect("bodies is not empty");
+            let frame = match body.poll_next_unpin(cx) {
+                Poll::Pending => {
+                    idle += 1;
+                    this.bodies.push_back((request_id, body));
+                    continue;
+                }
+                Poll::Ready(Some(Ok(data))) => {
+                    this.bodies.push_back((request_id, body));
+                    if data.is_empty() {
+                        continue;
+                    }
+                    ResponseFrame::Chunk { request_id, data }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    tracing::warn!(request_id, "BodyFailed: {}", e);
+                    ResponseFrame::End {
+                        request_id,
+                        result: Err(ServerError::new(e.kind(), e.to_string())),
+                    }
+                }
+                Poll::Ready(None) => ResponseFrame::End {
+                    request_id,
+                    result: Ok(()),
+                },
+            };
+            idle = 0;
+            this.transport.as_mut().start_send(frame)?;
+        }
+        if this.bodies.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T, Item> Stream for StreamResponses<T>
+where
+    T: Stream<Item = Item>,
+{
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        self.project().transport.poll_next(cx)
+    }
+}
+
+impl<T> Sink<Response<Body>> for StreamResponses<T>
+where
+    T: Sink<ResponseFrame>,
+{
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        // Make progress on the bodies being written, but don't wait for them to complete before
+        // accepting more responses.
+        if let Poll::Ready(Err(e)) = self.as_mut().poll_bodies(cx) {
+            return Poll::Ready(Err(e));
+        }
+        self.project().transport.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<Body>) -> Result<(), T::Error> {
+        let this = self.project();
+        match response.message {
+            Ok(body) => {
+                this.bodies.push_back((response.request_id, body));
+                Ok(())
+            }
+            Err(e) => this.transport.start_send(ResponseFrame::End {
+                request_id: response.request_id,
+                result: Err(e),
+            }),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        let bodies = self.as_mut().poll_bodies(cx)?;
+        ready!(self.project().transport.poll_flush(cx))?;
+        bodies.map(Ok)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.project().transport.poll_close(cx)
+    }
+}
+
+/// A client transport that reassembles the [`ResponseFrame`]s read from a transport into
+/// complete responses.
+#[pin_project]
+#[derive(Debug)]
+pub struct CollectResponses<T> {
+    #[pin]
+    transport: T,
+    /// The chunks received so far for responses that have not ended.
+    partial_responses: FnvHashMap<u64, BytesMut>,
+}
+
+impl<T> CollectResponses<T> {
+    /// Returns a transport that reassembles the responses read from `transport`.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            partial_responses: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns the inner transport over which messages are sent and received.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+}
+
+impl<T, E> Stream for CollectResponses<T>
+where
+    T: Stream<Item = Result<ResponseFrame, E>>,
+{
+    type Item = Result<Response<Bytes>, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match ready!(this.transport.as_mut().poll_next(cx)?) {
+                Some(ResponseFrame::Chunk { request_id, data }) => {
+                    this.partial_responses
+                        .entry(request_id)
+                        .or_default()
+                        .extend_from_slice(&data);
+                }
+                Some(ResponseFrame::End { request_id, result }) => {
+                    let body = this.partial_responses.remove(&request_id);
+                    return Poll::Ready(Some(Ok(Response {
+                        request_id,
+                        message: result.map(|()| body.unwrap_or_default().freeze()),
+                        more: false,
+                    })));
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<T, SinkItem> Sink<SinkItem> for CollectResponses<T>
+where
+    T: Sink<SinkItem>,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().transport.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), T::Error> {
+        self.project().transport.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().transport.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().transport.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Body, CollectResponses, ResponseFrame, StreamResponses, CHUNK_LEN};
+    use crate::{
+        client::{self, RpcError},
+        context,
+        server::{self, BaseChannel, Channel},
+        transport::channel,
+        ClientMessage, Response, ServerError,
+    };
+    use assert_matches::assert_matches;
+    use bytes::Bytes;
+    use futures::prelude::*;
+    use std::io;
+
+    /// Returns a client connected to a server that responds to each request `n` with a body
+    /// produced by `body(n)`.
+    fn set_up<F>(body: F) -> client::Channel<usize, Bytes>
+    where
+        F: Fn(usize) -> Body + Clone + Send + 'static,
+    {
+        let (client_transport, server_transport) = channel::unbounded();
+        let server = BaseChannel::with_defaults(StreamResponses::new(server_transport))
+            .execute(server::serve(move |_, n| {
+                let body = body.clone();
+                async move { Ok(body(n)) }
+            }))
+            .for_each(|response| async move {
+                tokio::spawn(response);
+            });
+        tokio::spawn(server);
+        client::new(
+            client::Config::default(),
+            CollectResponses::new(client_transport),
+        )
+        .spawn()
+    }
+
+    #[tokio::test]
+    async fn writer_body_is_chunked() {
+        let chunks: Vec<_> = Body::from_writer(|w| w.write_all(&vec![7; 2 * CHUNK_LEN + 1]))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks.iter().map(Bytes::len).collect::<Vec<_>>(),
+            [CHUNK_LEN, CHUNK_LEN, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn streamed_response_round_trip() {
+        let client = set_up(|n| Body::from_writer(move |w| w.write_all(&vec![1; n])));
+
+        let (small, large) = future::join(
+            client.call(context::current(), "Export", 3),
+            client.call(context::current(), "Export", 3 * CHUNK_LEN),
+        )
+        .await;
+        assert_eq!(small.unwrap(), vec![1; 3]);
+        assert_eq!(large.unwrap(), vec![1; 3 * CHUNK_LEN]);
+    }
+
+    #[tokio::test]
+    async fn body_error_fails_the_request() {
+        let client = set_up(|_| {
+            Body::from_stream(stream::iter([
+                Ok(Bytes::from_static(b"partial")),
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "export failed",
+                )),
+            ]))
+        });
+
+        assert_matches!(
+            client.call(context::current(), "Export", 0).await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::UnexpectedEof,
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_body_does_not_block_others() {
+        let (mut tx, rx) = channel::unbounded::<ResponseFrame, ClientMessage<()>>();
+        let mut transport = StreamResponses::new(rx);
+        let pending = || {
+            Body::from_stream(stream::iter([Ok(Bytes::from_static(b"a"))]).chain(stream::pending()))
+        };
+        transport
+            .send(Response {
+                request_id: 1,
+                message: Ok(pending()),
+                more: false,
+            })
+            .now_or_never();
+        transport
+            .send(Response {
+                request_id: 2,
+                message: Ok(pending()),
+                more: false,
+            })
+            .now_or_never();
+
+        let data = Bytes::from_static(b"a");
+        assert_eq!(
+            tx.next().await.unwrap().unwrap(),
+            ResponseFrame::Chunk {
+                request_id: 1,
+                data: data.clone()
+            }
+        );
+        assert_eq!(
+            tx.next().await.unwrap().unwrap(),
+            ResponseFrame::Chunk {
+                request_id: 2,
+                data
+            }
+        );
+    }
+}
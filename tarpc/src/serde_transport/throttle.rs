@@ -0,0 +1,255 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides an I/O wrapper that caps the rate at which bytes are read and written.
+//!
+//! Wrapping the I/O object of a [`Transport`](super::Transport) in a [`Throttled`] keeps a single
+//! bulk-transfer connection from saturating the network and starving latency-sensitive
+//! connections that share the host. Ingress and egress are limited independently with token
+//! buckets, so short bursts pass at full speed.
+//!
+//! ```rust
+//! # use tarpc::serde_transport::{self, throttle::{RateLimit, Throttled}};
+//! # use tarpc::tokio_serde::formats::Json;
+//! # use tarpc::tokio_util::codec::LengthDelimitedCodec;
+//! # fn wrap(io: tokio::io::DuplexStream) {
+//! let io = Throttled::new(io)
+//!     .with_egress_limit(RateLimit::new(1 << 20))
+//!     .with_ingress_limit(RateLimit::new(1 << 20));
+//! let transport = serde_transport::new::<_, String, String, _>(
+//!     LengthDelimitedCodec::builder().new_framed(io),
+//!     Json::default(),
+//! );
+//! # }
+//! ```
+
+use futures::ready;
+use pin_project::pin_project;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// A cap on the rate at which bytes flow in one direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    burst: u64,
+}
+
+impl RateLimit {
+    /// Returns a limit of `bytes_per_sec`, with a burst of one second's worth of bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "bytes_per_sec must be positive");
+        Self {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+        }
+    }
+
+    /// Sets the number of bytes that may be transferred at once after a period of inactivity.
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Returns the sustained number of bytes allowed per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Returns the number of bytes that may be transferred at once after a period of inactivity.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: now,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    /// Returns the number of bytes that may be transferred now, waiting for at least one.
+    fn poll_available(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.limit.bytes_per_sec as f64)
+                .min(self.limit.burst.max(1) as f64);
+            self.refilled_at = now;
+            if self.tokens >= 1. {
+                return Poll::Ready(self.tokens as usize);
+            }
+            let wait = (1. - self.tokens) / self.limit.bytes_per_sec as f64;
+            self.sleep
+                .as_mut()
+                .reset(now + Duration::from_secs_f64(wait));
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// An I/O object whose reads and writes are rate limited.
+#[pin_project]
+#[derive(Debug)]
+pub struct Throttled<S> {
+    #[pin]
+    io: S,
+    ingress: Option<TokenBucket>,
+    egress: Option<TokenBucket>,
+}
+
+impl<S> Throttled<S> {
+    /// Wraps `io` without limiting it.
+    pub fn new(io: S) -> Self {
+        Self {
+            io,
+            ingress: None,
+            egress: None,
+        }
+    }
+
+    /// Limits the rate at which bytes are read from the wrapped I/O object.
+    pub fn with_ingress_limit(mut self, limit: RateLimit) -> Self {
+        self.ingress = Some(TokenBucket::new(limit));
+        self
+    }
+
+    /// Limits the rate at which bytes are written to the wrapped I/O object.
+    pub fn with_egress_limit(mut self, limit: RateLimit) -> Self {
+        self.egress = Some(TokenBucket::new(limit));
+        self
+    }
+
+    /// Returns a reference to the wrapped I/O object.
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    /// Returns the wrapped I/O object.
+    pub fn into_inner(self) -> S {
+        self.io
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let ingress = match this.ingress {
+            Some(ingress) => ingress,
+            None => return this.io.poll_read(cx, buf),
+        };
+        let available = ready!(ingress.poll_available(cx)).min(buf.remaining());
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(available));
+        ready!(this.io.poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        ingress.consume(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let egress = match this.egress {
+            Some(egress) => egress,
+            None => return this.io.poll_write(cx, buf),
+        };
+        let available = ready!(egress.poll_available(cx)).min(buf.len());
+        let written = ready!(this.io.poll_write(cx, &buf[..available]))?;
+        egress.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().io.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().io.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, Throttled};
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn egress_is_limited() -> std::io::Result<()> {
+        let (a, mut b) = tokio::io::duplex(1 << 16);
+        let mut a = Throttled::new(a).with_egress_limit(RateLimit::new(1000));
+
+        let start = Instant::now();
+        a.write_all(&[0; 3000]).await?;
+        // The first 1000 bytes are the burst; the rest take a second per 1000 bytes.
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let mut received = [0; 3000];
+        b.read_exact(&mut received).await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ingress_is_limited() -> std::io::Result<()> {
+        let (a, mut b) = tokio::io::duplex(1 << 16);
+        let mut a = Throttled::new(a).with_ingress_limit(RateLimit::new(1000).with_burst(500));
+        b.write_all(&[0; 2500]).await?;
+
+        let start = Instant::now();
+        let mut received = [0; 2500];
+        a.read_exact(&mut received).await?;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        Ok(())
+    }
+    #[test]
+    #[should_panic(expected = "bytes_per_sec must be positive")]
+    fn zero_rate_is_rejected() {
+        RateLimit::new(0);
+    }
+}
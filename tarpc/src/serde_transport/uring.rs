@@ -0,0 +1,346 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides an experimental TCP transport driven by io_uring.
+//!
+//! With io_uring, reads and writes are submitted to the kernel through a shared ring rather than
+//! issued as a system call each time the socket becomes ready, which leaves more of the CPU for
+//! servers exchanging many small messages. The transport runs on a [`tokio_uring`] runtime and
+//! is not [`Send`], so its clients and channels must be spawned with [`tokio_uring::spawn`]
+//! inside [`tokio_uring::start`]. Otherwise, the API mirrors [`tcp`](super::tcp).
+//!
+//! ```no_run
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     serde_transport::uring,
+//!     server::{self, Channel},
+//!     tokio_serde::formats::Json,
+//! };
+//!
+//! # fn main() -> std::io::Result<()> {
+//! tokio_uring::start(async {
+//!     let mut listener = uring::listen("127.0.0.1:0".parse().unwrap(), Json::default)?;
+//!     let addr = listener.local_addr();
+//!     tokio_uring::spawn(async move {
+//!         while let Some(Ok(transport)) = listener.next().await {
+//!             let requests = server::BaseChannel::with_defaults(transport)
+//!                 .execute(server::serve(|_, name: String| async move {
+//!                     Ok(format!("Hello, {name}!"))
+//!                 }))
+//!                 .for_each(|response| async move {
+//!                     tokio_uring::spawn(response);
+//!                 });
+//!             tokio_uring::spawn(requests);
+//!         }
+//!     });
+//!
+//!     let transport = uring::connect(addr, Json::default).await?;
+//!     let client::NewClient { client, dispatch } =
+//!         client::new::<String, String, _>(client::Config::default(), transport);
+//!     tokio_uring::spawn(dispatch);
+//!     let greeting = client.call(context::current(), "Hello", "world".into()).await;
+//!     println!("{greeting:?}");
+//!     Ok(())
+//! })
+//! # }
+//! ```
+
+use super::{new, Transport};
+use futures::{future::LocalBoxFuture, prelude::*, ready, stream::LocalBoxStream};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    mem,
+    net::{Shutdown, SocketAddr},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_serde::{Deserializer, Serializer};
+use tokio_uring::{net, BufResult};
+use tokio_util::codec::length_delimited::{self, LengthDelimitedCodec};
+
+/// The size of the buffer each read fills.
+const READ_BUF_LEN: usize = 8 * 1024;
+
+/// A TCP stream that reads and writes through io_uring.
+///
+/// io_uring operations own their buffers until they complete, so the stream copies data between
+/// its own buffers and those of the caller. A write is accepted as soon as it is submitted; its
+/// result is reported by the next write or flush.
+pub struct TcpStream {
+    stream: Rc<net::TcpStream>,
+    peer_addr: SocketAddr,
+    /// Holds bytes read from the socket, starting at `read_pos`, that the reader has not yet
+    /// taken.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    reading: Option<LocalBoxFuture<'static, BufResult<usize, Vec<u8>>>>,
+    /// Reused for each write, once the previous one has completed.
+    write_buf: Vec<u8>,
+    writing: Option<LocalBoxFuture<'static, BufResult<(), Vec<u8>>>>,
+}
+
+impl TcpStream {
+    fn new(stream: net::TcpStream, peer_addr: SocketAddr) -> Self {
+        Self {
+            stream: Rc::new(stream),
+            peer_addr,
+            read_buf: Vec::with_capacity(READ_BUF_LEN),
+            read_pos: 0,
+            reading: None,
+            write_buf: Vec::new(),
+            writing: None,
+        }
+    }
+
+    /// Connects to `addr`.
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::new(net::TcpStream::connect(addr).await?, addr))
+    }
+
+    /// Returns the address of the remote peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Waits for the write in progress, if any, to complete.
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(writing) = &mut self.writing {
+            let (result, write_buf) = ready!(writing.as_mut().poll(cx));
+            self.writing = None;
+            self.write_buf = write_buf;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl fmt::Debug for TcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpStream")
+            .field("peer_addr", &self.peer_addr)
+            .field("reading", &self.reading.is_some())
+            .field("writing", &self.writing.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read_pos == this.read_buf.len() {
+            let reading = this.reading.get_or_insert_with(|| {
+                let stream = this.stream.clone();
+                let mut read_buf = mem::take(&mut this.read_buf);
+                this.read_pos = 0;
+                read_buf.clear();
+                read_buf.reserve(READ_BUF_LEN);
+                async move { stream.read(read_buf).await }.boxed_local()
+            });
+            let (result, read_buf) = ready!(reading.as_mut().poll(cx));
+            this.reading = None;
+            this.read_buf = read_buf;
+            // On error, nothing was read into the buffer. A read of zero bytes is the end of the
+            // stream, which is signaled by filling nothing.
+            result?;
+        }
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_written(cx))?;
+        let mut write_buf = mem::take(&mut this.write_buf);
+        write_buf.clear();
+        write_buf.extend_from_slice(data);
+        let stream = this.stream.clone();
+        this.writing = Some(async move { stream.write_all(write_buf).await }.boxed_local());
+        // Submits the write.
+        if let Poll::Ready(Err(e)) = this.poll_written(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_written(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_written(cx))?;
+        Poll::Ready(self.stream.shutdown(Shutdown::Write))
+    }
+}
+
+impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
+    /// Returns the peer address of the underlying TcpStream.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.inner.get_ref().peer_addr()
+    }
+}
+
+/// Connects to `addr`, wrapping the connection in an io_uring TCP transport.
+pub async fn connect<Item, SinkItem, Codec, CodecFn>(
+    addr: SocketAddr,
+    codec_fn: CodecFn,
+) -> io::Result<Transport<TcpStream, Item, SinkItem, Codec>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    let io = TcpStream::connect(addr).await?;
+    Ok(new(
+        LengthDelimitedCodec::builder().new_framed(io),
+        codec_fn(),
+    ))
+}
+
+/// Listens on `addr`, wrapping accepted connections in io_uring TCP transports.
+pub fn listen<Item, SinkItem, Codec, CodecFn>(
+    addr: SocketAddr,
+    codec_fn: CodecFn,
+) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+where
+    Item: for<'de> Deserialize<'de>,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    let listener = net::TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let accept = stream::unfold(Rc::new(listener), |listener| async move {
+        let conn = listener.accept().await;
+        Some((conn, listener))
+    })
+    .boxed_local();
+    Ok(Incoming {
+        accept,
+        local_addr,
+        codec_fn,
+        config: LengthDelimitedCodec::builder(),
+        ghost: PhantomData,
+    })
+}
+
+/// A [`TcpListener`](tokio_uring::net::TcpListener) that wraps connections in
+/// [transports](Transport).
+#[pin_project]
+pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+    accept: LocalBoxStream<'static, io::Result<(net::TcpStream, SocketAddr)>>,
+    local_addr: SocketAddr,
+    codec_fn: CodecFn,
+    config: length_delimited::Builder,
+    ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+}
+
+impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("local_addr", &self.local_addr)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns an immutable reference to the length-delimited codec's config.
+    pub fn config(&self) -> &length_delimited::Builder {
+        &self.config
+    }
+
+    /// Returns a mutable reference to the length-delimited codec's config.
+    pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+        &mut self.config
+    }
+}
+
+impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
+    type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (conn, peer_addr) = match ready!(self.as_mut().project().accept.poll_next_unpin(cx)) {
+            Some(conn) => conn?,
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(Ok(new(
+            self.config.new_framed(TcpStream::new(conn, peer_addr)),
+            (self.codec_fn)(),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, listen};
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::io;
+    use tokio_serde::formats::SymmetricalJson;
+
+    #[test]
+    fn echo() -> io::Result<()> {
+        tokio_uring::start(async {
+            let mut listener = listen(
+                "127.0.0.1:0".parse().unwrap(),
+                SymmetricalJson::<String>::default,
+            )?;
+            let addr = listener.local_addr();
+            tokio_uring::spawn(async move {
+                let mut transport = listener.next().await.unwrap().unwrap();
+                while let Some(message) = transport.next().await {
+                    transport.send(message.unwrap()).await.unwrap();
+                }
+            });
+
+            let mut transport = connect(addr, SymmetricalJson::<String>::default).await?;
+            for message in ["first", "second"] {
+                transport.send(message.to_string()).await?;
+                assert_matches!(transport.next().await, Some(Ok(s)) if s == message);
+            }
+            // Many small messages written without flushing in between.
+            let message = "x".repeat(100);
+            for _ in 0..200 {
+                transport.feed(message.clone()).await?;
+            }
+            transport.flush().await?;
+            for _ in 0..200 {
+                assert_matches!(transport.next().await, Some(Ok(s)) if s == message);
+            }
+            transport.close().await?;
+            Ok(())
+        })
+    }
+}
@@ -6,23 +6,43 @@
 
 //! Provides a server that concurrently handles many connections sending multiplexed requests.
 
+use crate::util::tracing::{self, info_span, instrument::Instrument, Span};
 use crate::{
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
-    context::{self, SpanExt},
-    trace, ChannelError, ClientMessage, Request, Response, ServerError, Transport,
+    clock,
+    context::{self, RequestEnvelope, SpanExt},
+    trace,
+    transport::{MalformedFrame, MalformedFramePolicy},
+    util::print_err,
+    ChannelError, ClientMessage, CloseReason, InvalidConfig, Request, Response, ServerError,
+    Transport,
 };
-use ::tokio::sync::mpsc;
+use ::tokio::{sync::mpsc, time::Sleep};
+use audit::{AuditEvent, AuditEventKind, AuditLog};
+use fnv::FnvHashMap;
 use futures::{
     future::{AbortRegistration, Abortable},
     prelude::*,
     ready,
-    stream::Fuse,
+    stream::{Fuse, FusedStream},
     task::*,
 };
 use in_flight_requests::{AlreadyExistsError, InFlightRequests};
+use limits::{
+    concurrency::{ConcurrencyLimit, Permits},
+    memory_pool::{MemoryPool, Reservation},
+};
 use pin_project::pin_project;
-use std::{convert::TryFrom, error::Error, fmt, marker::PhantomData, pin::Pin, sync::Arc};
-use tracing::{info_span, instrument::Instrument, Span};
+use std::{
+    collections::{BinaryHeap, VecDeque},
+    convert::TryFrom,
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 mod in_flight_requests;
 pub mod request_hook;
@@ -35,27 +55,303 @@ pub mod limits;
 /// Provides helper methods for streams of Channels.
 pub mod incoming;
 
+pub mod admission;
+
+pub mod audit;
+
+#[cfg(feature = "chaos")]
+#[cfg_attr(docsrs, doc(cfg(feature = "chaos")))]
+pub mod chaos;
+
+pub mod conformance;
+
+pub mod execution_limit;
+
+pub mod forward;
+
+pub mod request_stream;
+
+pub mod response_sink;
+
+pub mod route;
+
+pub mod shutdown;
+
+pub mod time_slice;
+
 use request_hook::{
     AfterRequest, BeforeRequest, HookThenServe, HookThenServeThenHook, ServeThenHook,
 };
+use request_stream::RequestStream;
+use response_sink::ResponseSink;
 
 /// Settings that control the behavior of [channels](Channel).
+///
+/// A config is created with [`Config::builder`], which validates the settings, or from one of the
+/// presets: [`Config::default`], [`Config::low_latency`], and [`Config::high_throughput`].
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Config {
-    /// Controls the buffer size of the in-process channel over which a server's handlers send
-    /// responses to the [`Channel`]. In other words, this is the number of responses that can sit
-    /// in the outbound queue before request handlers begin blocking.
-    pub pending_response_buffer: usize,
+    pending_response_buffer: usize,
+    malformed_frame_policy: MalformedFramePolicy,
+    max_buffered_bytes: Option<usize>,
+    buffer_limit_policy: BufferLimitPolicy,
+    memory_pool: Option<MemoryPool>,
+    audit_log: Option<AuditLog>,
+    read_idle_timeout: Option<Duration>,
+    response_order: ResponseOrder,
+    max_buffered_items: Option<usize>,
+    concurrency_limit: Option<ConcurrencyLimit>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             pending_response_buffer: 100,
+            malformed_frame_policy: MalformedFramePolicy::default(),
+            max_buffered_bytes: None,
+            buffer_limit_policy: BufferLimitPolicy::default(),
+            memory_pool: None,
+            audit_log: None,
+            read_idle_timeout: None,
+            response_order: ResponseOrder::default(),
+            max_buffered_items: None,
+            concurrency_limit: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns a builder initialized with the [default](Config::default) settings.
+    pub fn builder() -> ConfigBuilder {
+        Config::default().into_builder()
+    }
+
+    /// Returns a builder initialized with the settings of `self`.
+    pub fn into_builder(self) -> ConfigBuilder {
+        ConfigBuilder { config: self }
+    }
+
+    /// Settings for services with short requests that are sensitive to latency. The small response
+    /// buffer makes handlers feel backpressure as soon as the client stops reading responses.
+    pub fn low_latency() -> Self {
+        Config {
+            pending_response_buffer: 10,
+            ..Config::default()
+        }
+    }
+
+    /// Settings for services that execute many concurrent requests per channel. The large
+    /// response buffer lets the channel batch writes to the transport.
+    pub fn high_throughput() -> Self {
+        Config {
+            pending_response_buffer: 1_000,
+            ..Config::default()
+        }
+    }
+
+    /// Controls the buffer size of the in-process channel over which a server's handlers send
+    /// responses to the [`Channel`]. In other words, this is the number of responses that can sit
+    /// in the outbound queue before request handlers begin blocking. It also bounds the error
+    /// responses to requests that a [`BaseChannel`] rejected, e.g. for exceeding a limit: while as
+    /// many are waiting to be written, the channel stops reading requests.
+    pub fn pending_response_buffer(&self) -> usize {
+        self.pending_response_buffer
+    }
+
+    /// Controls what happens when the transport yields a frame that cannot be decoded.
+    pub fn malformed_frame_policy(&self) -> MalformedFramePolicy {
+        self.malformed_frame_policy
+    }
+
+    /// The approximate number of bytes that a [`BaseChannel`] may buffer before it stops
+    /// accepting new requests. Buffered bytes are those of in-flight requests and of responses
+    /// that have been written to the transport but not yet flushed, as measured by the functions
+    /// passed to [`BaseChannel::with_message_len`]. Bytes buffered inside the transport itself,
+    /// such as partially read frames, are not counted. If `None`, there is no limit.
+    pub fn max_buffered_bytes(&self) -> Option<usize> {
+        self.max_buffered_bytes
+    }
+
+    /// Controls what happens to requests that arrive while the channel is over
+    /// [`max_buffered_bytes`](Config::max_buffered_bytes).
+    pub fn buffer_limit_policy(&self) -> BufferLimitPolicy {
+        self.buffer_limit_policy
+    }
+
+    /// A byte budget that channels share with all other channels configured with the same pool,
+    /// in addition to [`max_buffered_bytes`](Config::max_buffered_bytes). If `None`, channels
+    /// don't draw from a shared budget.
+    pub fn memory_pool(&self) -> Option<&MemoryPool> {
+        self.memory_pool.as_ref()
+    }
+
+    /// Where channels record security-relevant events. If `None`, no events are recorded.
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_ref()
+    }
+
+    /// How long the transport of a [`BaseChannel`] with requests in flight may produce no frames
+    /// before the channel closes with [`ChannelError::ReadIdle`], aborting its in-flight requests.
+    /// This detects half-open connections when TCP keepalive is disabled or too slow. Channels
+    /// without requests in flight may stay idle indefinitely. If `None`, channels wait for the
+    /// transport indefinitely.
+    pub fn read_idle_timeout(&self) -> Option<Duration> {
+        self.read_idle_timeout
+    }
+
+    /// The order in which [`Requests`] writes responses that are ready at the same time.
+    pub fn response_order(&self) -> ResponseOrder {
+        self.response_order
+    }
+
+    /// The number of items a client may stream to one [streamed](crate::Request::streamed)
+    /// request ahead of the request's handler. This is the window of each request's stream: a
+    /// client that overruns it fails only that request, which is aborted and answered with a
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock) error, while the channel's other requests carry
+    /// on. If `None`, each stream buffers items until its handler reads them.
+    pub fn max_buffered_items(&self) -> Option<usize> {
+        self.max_buffered_items
+    }
+
+    /// A limit on requests in flight that channels share with all other channels configured with
+    /// the same limit. A request that arrives while the limit is reached is answered right away
+    /// with a [resource exhausted](ServerError::resource_exhausted) error instead of being
+    /// started; the connection stays open for further requests. The requests of each channel are
+    /// limited with [`Channel::max_concurrent_requests`]. If `None`, channels don't take from a
+    /// shared limit.
+    pub fn concurrency_limit(&self) -> Option<&ConcurrencyLimit> {
+        self.concurrency_limit.as_ref()
+    }
+}
+
+/// Builds a validated [`Config`].
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Sets [`Config::pending_response_buffer`]. Must be greater than zero.
+    pub fn pending_response_buffer(mut self, pending_response_buffer: usize) -> Self {
+        self.config.pending_response_buffer = pending_response_buffer;
+        self
+    }
+
+    /// Sets [`Config::malformed_frame_policy`].
+    pub fn malformed_frame_policy(mut self, malformed_frame_policy: MalformedFramePolicy) -> Self {
+        self.config.malformed_frame_policy = malformed_frame_policy;
+        self
+    }
+
+    /// Sets [`Config::max_buffered_bytes`]. Must be greater than zero, if set.
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: Option<usize>) -> Self {
+        self.config.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    /// Sets [`Config::buffer_limit_policy`].
+    pub fn buffer_limit_policy(mut self, buffer_limit_policy: BufferLimitPolicy) -> Self {
+        self.config.buffer_limit_policy = buffer_limit_policy;
+        self
+    }
+
+    /// Sets [`Config::memory_pool`].
+    pub fn memory_pool(mut self, memory_pool: Option<MemoryPool>) -> Self {
+        self.config.memory_pool = memory_pool;
+        self
+    }
+
+    /// Sets [`Config::audit_log`].
+    pub fn audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.config.audit_log = audit_log;
+        self
+    }
+
+    /// Sets [`Config::read_idle_timeout`]. Must be greater than zero, if set.
+    pub fn read_idle_timeout(mut self, read_idle_timeout: Option<Duration>) -> Self {
+        self.config.read_idle_timeout = read_idle_timeout;
+        self
+    }
+
+    /// Sets [`Config::response_order`].
+    pub fn response_order(mut self, response_order: ResponseOrder) -> Self {
+        self.config.response_order = response_order;
+        self
+    }
+
+    /// Sets [`Config::max_buffered_items`]. Must be greater than zero, if set.
+    pub fn max_buffered_items(mut self, max_buffered_items: Option<usize>) -> Self {
+        self.config.max_buffered_items = max_buffered_items;
+        self
+    }
+
+    /// Sets [`Config::concurrency_limit`].
+    pub fn concurrency_limit(mut self, concurrency_limit: Option<ConcurrencyLimit>) -> Self {
+        self.config.concurrency_limit = concurrency_limit;
+        self
+    }
+
+    /// Returns the config, or an error if any setting is invalid.
+    pub fn build(self) -> Result<Config, InvalidConfig> {
+        let config = self.config;
+        if config.pending_response_buffer == 0 {
+            return Err(InvalidConfig::new(
+                "pending_response_buffer",
+                "must be greater than zero",
+            ));
         }
+        if config.max_buffered_bytes == Some(0) {
+            return Err(InvalidConfig::new(
+                "max_buffered_bytes",
+                "must be greater than zero",
+            ));
+        }
+        if config.read_idle_timeout == Some(Duration::ZERO) {
+            return Err(InvalidConfig::new(
+                "read_idle_timeout",
+                "must be greater than zero",
+            ));
+        }
+        if config.max_buffered_items == Some(0) {
+            return Err(InvalidConfig::new(
+                "max_buffered_items",
+                "must be greater than zero",
+            ));
+        }
+        Ok(config)
     }
 }
 
+/// Controls how a [`BaseChannel`] reacts when it is buffering more than
+/// [`Config::max_buffered_bytes`], or more than its share of [`Config::memory_pool`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BufferLimitPolicy {
+    /// Stop reading from the transport until enough requests complete or responses are flushed,
+    /// which pushes back on the client.
+    #[default]
+    Backpressure,
+    /// Keep reading from the transport, but respond to each new request with a
+    /// [resource exhausted](ServerError::resource_exhausted) error instead of starting it.
+    Shed,
+}
+
+/// Controls the order in which [`Requests`] writes responses whose handlers completed while the
+/// transport was busy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResponseOrder {
+    /// Write responses in the order their handlers completed.
+    #[default]
+    Completion,
+    /// Write the smallest of the ready responses first, as measured by
+    /// [`Channel::response_len`], so that small responses don't wait behind a large one. Error
+    /// responses count as empty. Responses of equal size are written in completion order. At most
+    /// [`Config::pending_response_buffer`] responses are considered at once.
+    SmallestFirst,
+}
+
 impl Config {
     /// Returns a channel backed by `transport` and configured with `self`.
     pub fn channel<Req, Resp, T>(self, transport: T) -> BaseChannel<Req, Resp, T>
@@ -75,7 +371,8 @@ pub trait Serve {
     /// Type of response.
     type Resp;
 
-    /// Responds to a single request.
+    /// Responds to a single request. Requests read by a [`BaseChannel`] carry their raw
+    /// [`envelope`](context::Context::envelope) in `ctx`.
     async fn serve(self, ctx: context::Context, req: Self::Req) -> Result<Self::Resp, ServerError>;
 
     /// Extracts a method name from the request.
@@ -167,6 +464,39 @@ pub trait Serve {
         ServeThenHook::new(self, hook)
     }
 
+    /// Runs the request handler in time slices of length `slice`, so that a handler calling
+    /// [`time_slice::checkpoint`] between units of work yields to the executor once it has run
+    /// for `slice` without yielding. See the [`time_slice`] module.
+    fn time_sliced(self, slice: Duration) -> time_slice::TimeSliced<Self>
+    where
+        Self: Sized,
+    {
+        time_slice::TimeSliced::new(self, slice)
+    }
+
+    /// Aborts request handlers that run for longer than `limits` allow, regardless of the
+    /// requests' deadlines. See the [`execution_limit`] module.
+    fn execution_limits(
+        self,
+        limits: execution_limit::ExecutionLimits,
+    ) -> execution_limit::ExecutionLimited<Self>
+    where
+        Self: Sized,
+    {
+        execution_limit::ExecutionLimited::new(self, limits)
+    }
+
+    /// Injects the faults configured through `control` into the requests handled. See the
+    /// [`chaos`] module.
+    #[cfg(feature = "chaos")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "chaos")))]
+    fn chaos(self, control: chaos::ChaosControl) -> chaos::Chaos<Self>
+    where
+        Self: Sized,
+    {
+        chaos::Chaos::new(self, control)
+    }
+
     /// Runs a hook before and after execution of the request.
     ///
     /// If the hook returns an error, the request will not be executed and the error will be
@@ -291,6 +621,28 @@ pub struct BaseChannel<Req, Resp, T> {
     request_cancellation: RequestCancellation,
     /// Holds data necessary to clean up in-flight requests.
     in_flight_requests: InFlightRequests,
+    /// Requests that were rejected by the channel and are waiting to be sent an error response.
+    rejected_requests: VecDeque<(u64, ServerError)>,
+    /// Hands the items the client streams to each streamed request in flight to the request's
+    /// handler.
+    request_items: FnvHashMap<u64, mpsc::Sender<Req>>,
+    /// Estimates the number of bytes held by a request.
+    request_len: fn(&Req) -> usize,
+    /// Estimates the number of bytes held by a response.
+    response_len: fn(&Resp) -> usize,
+    /// The approximate number of bytes of responses written to the transport since the last
+    /// flush.
+    unflushed_response_len: usize,
+    /// The bytes drawn from the shared memory pool, if any.
+    memory_reservation: Option<Reservation>,
+    /// The permits taken from the shared concurrency limit, if any.
+    concurrency_permits: Option<Permits>,
+    /// The number of responses that were not sent because their request was canceled or expired.
+    dropped_responses: u64,
+    /// Fires when the transport has produced no frames for the read idle timeout.
+    read_idle: Option<Pin<Box<Sleep>>>,
+    /// Why the client said it closed the connection, if it did.
+    peer_close_reason: Option<CloseReason>,
     /// Types the request and response.
     ghost: PhantomData<(fn() -> Req, fn(Resp))>,
 }
@@ -302,12 +654,30 @@ where
     /// Creates a new channel backed by `transport` and configured with `config`.
     pub fn new(config: Config, transport: T) -> Self {
         let (request_cancellation, canceled_requests) = cancellations();
+        let memory_reservation = config.memory_pool.as_ref().map(MemoryPool::register);
+        let concurrency_permits = config
+            .concurrency_limit
+            .as_ref()
+            .map(ConcurrencyLimit::register);
+        if let Some(audit_log) = &config.audit_log {
+            audit_log.record(AuditEvent::new(AuditEventKind::ChannelOpened));
+        }
         BaseChannel {
             config,
             transport: transport.fuse(),
             canceled_requests,
             request_cancellation,
             in_flight_requests: InFlightRequests::default(),
+            rejected_requests: VecDeque::new(),
+            request_items: FnvHashMap::default(),
+            request_len: std::mem::size_of_val,
+            response_len: std::mem::size_of_val,
+            unflushed_response_len: 0,
+            memory_reservation,
+            concurrency_permits,
+            dropped_responses: 0,
+            read_idle: None,
+            peer_close_reason: None,
             ghost: PhantomData,
         }
     }
@@ -317,6 +687,131 @@ where
         Self::new(Config::default(), transport)
     }
 
+    /// Sets the functions used to estimate the number of bytes held by requests and responses,
+    /// which are counted against [`Config::max_buffered_bytes`]. By default, only the shallow
+    /// size of each message is counted.
+    pub fn with_message_len(
+        mut self,
+        request_len: fn(&Req) -> usize,
+        response_len: fn(&Resp) -> usize,
+    ) -> Self {
+        self.request_len = request_len;
+        self.response_len = response_len;
+        self
+    }
+
+    /// Returns the approximate number of bytes buffered by the channel.
+    pub fn buffered_len(&self) -> usize {
+        self.in_flight_requests.buffered_len() + self.unflushed_response_len
+    }
+
+    /// Returns the number of responses that were discarded instead of being sent, because their
+    /// request had been canceled or its deadline had passed by the time the response was ready.
+    pub fn dropped_responses(&self) -> u64 {
+        self.dropped_responses
+    }
+
+    /// Returns true once the client has finished sending requests, e.g. because it
+    /// [half-closed](crate::client::Channel::finish_sending) the connection. The channel still
+    /// writes the responses to requests in flight, and ends once none are left.
+    pub fn requests_finished(&self) -> bool {
+        self.transport.is_terminated()
+    }
+
+    /// Returns the reason the client gave for closing the connection, if it
+    /// [closed](crate::client::Channel::close) it with one. A client that closes the connection
+    /// this way finishes sending requests right after, so this is usually set together with
+    /// [`requests_finished`](Self::requests_finished).
+    pub fn peer_close_reason(&self) -> Option<CloseReason> {
+        self.peer_close_reason
+    }
+
+    /// Reports the bytes buffered by the channel to the shared memory pool, and returns the
+    /// permits of requests no longer in flight to the shared concurrency limit.
+    fn update_reservations(self: Pin<&mut Self>) {
+        let len = self.buffered_len();
+        let in_flight_requests = self.in_flight_requests.len();
+        let this = self.project();
+        if let Some(reservation) = this.memory_reservation {
+            reservation.set_len(len);
+        }
+        if let Some(permits) = this.concurrency_permits {
+            permits.release_to(in_flight_requests);
+        }
+    }
+
+    /// Takes a permit for a new request from the shared concurrency limit, or returns an error
+    /// rejecting the request if the server is at its limit of requests in flight.
+    fn admit_request(self: Pin<&mut Self>) -> Result<(), ServerError> {
+        let permits = match self.project().concurrency_permits {
+            Some(permits) => permits,
+            None => return Ok(()),
+        };
+        if permits.try_acquire() {
+            Ok(())
+        } else {
+            Err(ServerError::resource_exhausted(format!(
+                "server throttled the request: {} requests are in flight on the server, the most \
+                 it allows",
+                permits.max()
+            )))
+        }
+    }
+
+    /// Writes the error responses to rejected requests that the transport is ready for.
+    fn poll_send_rejected(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ChannelError<T::Error>>> {
+        while !self.rejected_requests.is_empty() {
+            ready!(self
+                .transport_pin_mut()
+                .poll_ready(cx)
+                .map_err(ChannelError::Ready)?);
+            let (request_id, error) = match self.as_mut().project().rejected_requests.pop_front() {
+                Some(rejected) => rejected,
+                None => break,
+            };
+            self.transport_pin_mut()
+                .start_send(Response {
+                    request_id,
+                    message: Err(error),
+                    more: false,
+                })
+                .map_err(ChannelError::Write)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns true iff the channel should stop reading requests, because as many rejected
+    /// requests are waiting for their error responses as [`Config::pending_response_buffer`]
+    /// allows, even after writing those the transport is ready for.
+    fn is_rejection_queue_full(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, ChannelError<T::Error>> {
+        let capacity = self.config.pending_response_buffer;
+        if self.rejected_requests.len() < capacity {
+            return Ok(false);
+        }
+        if let Poll::Ready(result) = self.as_mut().poll_send_rejected(cx) {
+            result?;
+        }
+        Ok(self.rejected_requests.len() >= capacity)
+    }
+
+    /// Returns true iff the channel should stop accepting requests.
+    fn is_over_budget(&self, cx: &mut Context<'_>) -> bool {
+        let len = self.buffered_len();
+        self.config
+            .max_buffered_bytes
+            .map_or(false, |max| len >= max)
+            || self
+                .memory_reservation
+                .as_ref()
+                .map_or(false, |reservation| reservation.is_over_budget(cx))
+    }
+
     /// Returns the inner transport over which messages are sent and received.
     pub fn get_ref(&self) -> &T {
         self.transport.get_ref()
@@ -341,6 +836,85 @@ where
         self.as_mut().project().transport
     }
 
+    /// Applies the configured [`MalformedFramePolicy`] to a transport read error. Returns an error
+    /// iff the channel should close.
+    fn handle_read_error(
+        mut self: Pin<&mut Self>,
+        e: T::Error,
+    ) -> Result<(), ChannelError<T::Error>> {
+        let malformed = MalformedFrame::find(&e).map(MalformedFrame::request_id);
+        match (self.config.malformed_frame_policy, malformed) {
+            (MalformedFramePolicy::Close, _) | (_, None) => Err(ChannelError::Read(Arc::new(e))),
+            (MalformedFramePolicy::Respond, Some(Some(request_id))) => {
+                tracing::warn!(request_id, "RespondMalformedRequest: {}", print_err(&e));
+                self.as_mut().project().rejected_requests.push_back((
+                    request_id,
+                    ServerError::new(
+                        io::ErrorKind::InvalidData,
+                        "the server could not decode the request".into(),
+                    ),
+                ));
+                Ok(())
+            }
+            (_, Some(_)) => {
+                tracing::warn!("DropMalformedFrame: {}", print_err(&e));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns an error iff the transport has been idle for the read idle timeout while requests
+    /// were in flight, in which case the in-flight requests are aborted.
+    fn poll_read_idle(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Result<(), ChannelError<T::Error>> {
+        let read_idle_timeout = match self.config.read_idle_timeout {
+            Some(read_idle_timeout) if self.in_flight_requests.len() > 0 => read_idle_timeout,
+            _ => {
+                *self.as_mut().project().read_idle = None;
+                return Ok(());
+            }
+        };
+        let this = self.as_mut().project();
+        let read_idle = this
+            .read_idle
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(read_idle_timeout)));
+        if read_idle.as_mut().poll(cx).is_pending() {
+            return Ok(());
+        }
+        tracing::warn!(
+            "ReadIdle: no frames for {:?} with {} requests in flight",
+            read_idle_timeout,
+            this.in_flight_requests.len()
+        );
+        this.in_flight_requests.abort_all();
+        this.request_items.clear();
+        *this.read_idle = None;
+        self.update_reservations();
+        Err(ChannelError::ReadIdle(read_idle_timeout))
+    }
+
+    /// Aborts a streamed request whose client streamed it more items than
+    /// [`Config::max_buffered_items`], and queues an error response to it.
+    fn overrun_stream(mut self: Pin<&mut Self>, request_id: u64) {
+        let window = self.config.max_buffered_items.unwrap_or_default();
+        let span = match self.in_flight_requests_mut().abort_request(request_id) {
+            Some(span) => span,
+            None => return,
+        };
+        let _entered = span.enter();
+        tracing::info!(window, "StreamWindowExceeded");
+        self.as_mut().update_reservations();
+        self.as_mut().project().rejected_requests.push_back((
+            request_id,
+            ServerError::new(
+                io::ErrorKind::WouldBlock,
+                format!("the client streamed more than {window} items ahead of the handler"),
+            ),
+        ));
+    }
+
     fn start_request(
         mut self: Pin<&mut Self>,
         mut request: Request<Req>,
@@ -348,7 +922,9 @@ where
         let span = info_span!(
             "RPC",
             rpc.trace_id = %request.context.trace_id(),
+            rpc.request_id = request.id,
             rpc.deadline = %humantime::format_rfc3339(request.context.deadline),
+            rpc.queue_time.server = tracing::field::Empty,
             otel.kind = "server",
             otel.name = tracing::field::Empty,
         );
@@ -362,17 +938,36 @@ where
         });
         let entered = span.enter();
         tracing::info!("ReceiveRequest");
+        let buffered_len = (self.request_len)(&request.message);
+        request.context.envelope = Some(RequestEnvelope {
+            request_id: request.id,
+            len: buffered_len,
+            received_at: tokio::time::Instant::now(),
+        });
         let start = self.in_flight_requests_mut().start_request(
             request.id,
             request.context.deadline,
             span.clone(),
+            buffered_len,
+            request.one_way,
         );
         match start {
             Ok(abort_registration) => {
                 drop(entered);
+                self.as_mut().update_reservations();
+                let items = request.streamed.then(|| {
+                    let window = self
+                        .config
+                        .max_buffered_items
+                        .unwrap_or(tokio::sync::Semaphore::MAX_PERMITS);
+                    let (tx, rx) = mpsc::channel(window);
+                    self.as_mut().project().request_items.insert(request.id, tx);
+                    RequestStream::new(request.id, rx)
+                });
                 Ok(TrackedRequest {
                     abort_registration,
                     span,
+                    items,
                     response_guard: ResponseGuard {
                         request_id: request.id,
                         request_cancellation: self.request_cancellation.clone(),
@@ -407,6 +1002,8 @@ pub struct TrackedRequest<Req> {
     pub span: Span,
     /// An inert response guard. Becomes active in an InFlightRequest.
     pub response_guard: ResponseGuard,
+    /// The items the client streams to the request, if it is [streamed](Request::streamed).
+    pub items: Option<RequestStream<Req>>,
 }
 
 /// The server end of an open connection with a client, receiving requests from, and sending
@@ -431,9 +1028,21 @@ pub struct TrackedRequest<Req> {
 ///    will clean up request state once either the deadline expires, or the response guard is
 ///    dropped, or a response is sent.
 ///
+/// # Implementing `Channel`
+///
 /// Channels must be implemented using the decorator pattern: the only way to create a
 /// `TrackedRequest` is to get one from another `Channel`. Ultimately, all `TrackedRequests` are
-/// created by [`BaseChannel`].
+/// created by [`BaseChannel`]. To receive requests from a different source, such as a message
+/// queue, implement a [`Transport`] of [`ClientMessage`]s and [`Response`]s for it and wrap it in
+/// a `BaseChannel`; to change how requests are admitted or answered, wrap an existing channel, as
+/// [`MaxRequests`](limits::requests_per_channel::MaxRequests) does.
+///
+/// A decorator implements [`Stream`] and [`Sink`] by delegating to the channel it wraps, and the
+/// required methods [`config`](Channel::config), [`in_flight_requests`](Channel::in_flight_requests),
+/// and [`transport`](Channel::transport) by reporting on it. The provided methods should not be
+/// overridden. The decorator then works with [`Channel::execute`], [`Channel::requests`], the
+/// [`incoming`] combinators, and generated `Serve` implementations, provided it passes the checks
+/// in [`conformance`].
 pub trait Channel
 where
     Self: Transport<Response<<Self as Channel>::Resp>, TrackedRequest<<Self as Channel>::Req>>,
@@ -456,6 +1065,12 @@ where
     /// Returns the transport underlying the channel.
     fn transport(&self) -> &Self::Transport;
 
+    /// Estimates the number of bytes held by `response`. By default, only the shallow size of the
+    /// response is counted.
+    fn response_len(&self, response: &Self::Resp) -> usize {
+        std::mem::size_of_val(response)
+    }
+
     /// Caps the number of concurrent requests to `limit`. An error will be returned for requests
     /// over the concurrency limit.
     ///
@@ -517,6 +1132,8 @@ where
             channel: self,
             pending_responses: responses,
             responses_tx,
+            staged_responses: BinaryHeap::new(),
+            staged_count: 0,
         }
     }
 
@@ -552,10 +1169,12 @@ where
     ///         MyInt(2));
     /// }
     /// ```
-    fn execute<S>(self, serve: S) -> impl Stream<Item = impl Future<Output = ()>>
+    fn execute<S>(self, serve: S) -> impl Stream<Item = ExecuteRequest<impl Future<Output = ()>>>
     where
         Self: Sized,
         S: Serve<Req = Self::Req, Resp = Self::Resp> + Clone,
+        Self::Req: Send + 'static,
+        Self::Resp: Send + 'static,
     {
         self.requests().execute(serve)
     }
@@ -591,6 +1210,7 @@ where
         loop {
             let cancellation_status = match self.canceled_requests_pin_mut().poll_recv(cx) {
                 Poll::Ready(Some(request_id)) => {
+                    self.as_mut().project().request_items.remove(&request_id);
                     if let Some(span) = self.in_flight_requests_mut().remove_request(request_id) {
                         let _entered = span.enter();
                         tracing::info!("ResponseCancelled");
@@ -609,33 +1229,106 @@ where
             let expiration_status = match self.in_flight_requests_mut().poll_expired(cx) {
                 // No need to send a response, since the client wouldn't be waiting for one
                 // anymore.
-                Poll::Ready(Some(_)) => Ready,
+                Poll::Ready(Some(request_id)) => {
+                    self.as_mut().project().request_items.remove(&request_id);
+                    Ready
+                }
                 Poll::Ready(None) => Closed,
                 Poll::Pending => Pending,
             };
 
-            let request_status = match self
-                .transport_pin_mut()
-                .poll_next(cx)
-                .map_err(|e| ChannelError::Read(Arc::new(e)))?
+            self.as_mut().update_reservations();
+            let over_budget = self.is_over_budget(cx);
+            let rejection_queue_full = self.as_mut().is_rejection_queue_full(cx)?;
+            let requests_finished = self.requests_finished();
+            let next_message = if over_budget
+                && self.config.buffer_limit_policy == BufferLimitPolicy::Backpressure
             {
-                Poll::Ready(Some(message)) => match message {
+                // Leave requests unread while over budget. Completing in-flight requests or
+                // flushing responses frees up buffer space, after which the channel is polled
+                // again.
+                Poll::Pending
+            } else if rejection_queue_full {
+                // Leave requests unread until the client reads the error responses it is owed.
+                // The transport wakes the channel once it is ready for more of them.
+                Poll::Pending
+            } else {
+                self.transport_pin_mut().poll_next(cx)
+            };
+            if next_message.is_pending() && !over_budget && !rejection_queue_full {
+                self.as_mut().poll_read_idle(cx)?;
+            } else {
+                *self.as_mut().project().read_idle = None;
+            }
+            let request_status = match next_message {
+                Poll::Ready(Some(Ok(message))) => match message {
+                    ClientMessage::Request(request) if request.context.deadline <= clock::now() => {
+                        // The client has already given up on the request, so don't start it.
+                        tracing::info!(
+                            rpc.trace_id = %request.context.trace_id(),
+                            request_id = request.id,
+                            "DropExpiredRequest"
+                        );
+                        Ready
+                    }
+                    ClientMessage::Request(request) if over_budget => {
+                        tracing::info!(
+                            request_id = request.id,
+                            buffered_len = self.buffered_len(),
+                            "ShedRequest"
+                        );
+                        if request.one_way {
+                            // The client isn't waiting for an error response.
+                            continue;
+                        }
+                        self.as_mut().project().rejected_requests.push_back((
+                            request.id,
+                            ServerError::resource_exhausted(
+                                "the server is buffering too much data".into(),
+                            ),
+                        ));
+                        Ready
+                    }
                     ClientMessage::Request(request) => {
-                        match self.as_mut().start_request(request) {
-                            Ok(request) => return Poll::Ready(Some(Ok(request))),
-                            Err(AlreadyExistsError) => {
-                                // Instead of closing the channel if a duplicate request is sent,
-                                // just ignore it, since it's already being processed. Note that we
-                                // cannot return Poll::Pending here, since nothing has scheduled a
-                                // wakeup yet.
+                        // A duplicate request is ignored below rather than throttled.
+                        let admitted = if self.in_flight_requests.contains(request.id) {
+                            Ok(())
+                        } else {
+                            self.as_mut().admit_request()
+                        };
+                        if let Err(error) = admitted {
+                            tracing::info!(
+                                request_id = request.id,
+                                in_flight_requests = self.in_flight_requests.len(),
+                                "ThrottleRequest"
+                            );
+                            if request.one_way {
+                                // The client isn't waiting for an error response.
                                 continue;
                             }
+                            self.as_mut()
+                                .project()
+                                .rejected_requests
+                                .push_back((request.id, error));
+                            Ready
+                        } else {
+                            match self.as_mut().start_request(request) {
+                                Ok(request) => return Poll::Ready(Some(Ok(request))),
+                                Err(AlreadyExistsError) => {
+                                    // Instead of closing the channel if a duplicate request is
+                                    // sent, just ignore it, since it's already being processed.
+                                    // Note that we cannot return Poll::Pending here, since nothing
+                                    // has scheduled a wakeup yet.
+                                    continue;
+                                }
+                            }
                         }
                     }
                     ClientMessage::Cancel {
                         trace_context,
                         request_id,
                     } => {
+                        self.as_mut().project().request_items.remove(&request_id);
                         if !self.in_flight_requests_mut().cancel_request(request_id) {
                             tracing::trace!(
                                 rpc.trace_id = %trace_context.trace_id,
@@ -644,8 +1337,45 @@ where
                         }
                         Ready
                     }
+                    ClientMessage::Close { reason } => {
+                        tracing::info!(%reason, "ReceiveClose");
+                        *self.as_mut().project().peer_close_reason = Some(reason);
+                        Ready
+                    }
+                    ClientMessage::Item { request_id, item } => {
+                        let request_items = self.as_mut().project().request_items;
+                        match (item, request_items.get(&request_id)) {
+                            (Some(item), Some(items)) => match items.try_send(item) {
+                                // The handler may have stopped reading items.
+                                Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+                                Err(mpsc::error::TrySendError::Full(_)) => {
+                                    request_items.remove(&request_id);
+                                    self.as_mut().overrun_stream(request_id);
+                                }
+                            },
+                            (Some(_), None) => {
+                                tracing::trace!(request_id, "DropRequestItem");
+                            }
+                            (None, _) => {
+                                request_items.remove(&request_id);
+                            }
+                        }
+                        Ready
+                    }
                 },
-                Poll::Ready(None) => Closed,
+                Poll::Ready(Some(Err(e))) => {
+                    self.as_mut().handle_read_error(e)?;
+                    Ready
+                }
+                Poll::Ready(None) => {
+                    if !requests_finished {
+                        tracing::info!(
+                            in_flight_requests = self.in_flight_requests.len(),
+                            "RequestsFinished"
+                        );
+                    }
+                    Closed
+                }
                 Poll::Pending => Pending,
             };
 
@@ -675,36 +1405,84 @@ where
 {
     type Error = ChannelError<T::Error>;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.project()
-            .transport
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        // Rejected requests are answered before any other responses are accepted, since there is
+        // no other place to buffer their error responses.
+        ready!(self.as_mut().poll_send_rejected(cx)?);
+        self.transport_pin_mut()
             .poll_ready(cx)
             .map_err(ChannelError::Ready)
     }
 
     fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        if let Some(span) = self
-            .in_flight_requests_mut()
-            .remove_request(response.request_id)
-        {
-            let _entered = span.enter();
-            tracing::info!("SendResponse");
-            self.project()
-                .transport
-                .start_send(response)
-                .map_err(ChannelError::Write)
+        let expired = self
+            .in_flight_requests
+            .deadline(response.request_id)
+            .map_or(false, |deadline| deadline <= clock::now());
+        let one_way = self.in_flight_requests.is_one_way(response.request_id);
+        let span = if response.more {
+            // A streaming request stays in flight until its final response is sent.
+            self.in_flight_requests.span(response.request_id)
         } else {
-            // If the request isn't tracked anymore, there's no need to send the response.
-            Ok(())
+            self.as_mut()
+                .project()
+                .request_items
+                .remove(&response.request_id);
+            self.in_flight_requests_mut()
+                .remove_request(response.request_id)
+        };
+        match span {
+            Some(span) if one_way => {
+                // The client expects no response, so don't spend time serializing and sending it.
+                let _entered = span.enter();
+                tracing::info!("DropOneWayResponse");
+                self.update_reservations();
+                Ok(())
+            }
+            Some(span) if !expired => {
+                let _entered = span.enter();
+                if response.more {
+                    tracing::debug!("SendStreamedResponse");
+                } else {
+                    tracing::info!("SendResponse");
+                }
+                if let Ok(message) = &response.message {
+                    *self.as_mut().project().unflushed_response_len += (self.response_len)(message);
+                }
+                self.as_mut().update_reservations();
+                self.project()
+                    .transport
+                    .start_send(response)
+                    .map_err(ChannelError::Write)
+            }
+            Some(span) => {
+                // The client has stopped waiting for the response, so don't spend time
+                // serializing and sending it.
+                let _entered = span.enter();
+                tracing::info!("DropExpiredResponse");
+                *self.as_mut().project().dropped_responses += 1;
+                self.update_reservations();
+                Ok(())
+            }
+            None => {
+                // If the request isn't tracked anymore, there's no need to send the response.
+                *self.project().dropped_responses += 1;
+                Ok(())
+            }
         }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         tracing::trace!("poll_flush");
-        self.project()
+        ready!(self
+            .as_mut()
+            .project()
             .transport
             .poll_flush(cx)
-            .map_err(ChannelError::Flush)
+            .map_err(ChannelError::Flush)?);
+        *self.as_mut().project().unflushed_response_len = 0;
+        self.update_reservations();
+        Poll::Ready(Ok(()))
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
@@ -740,6 +1518,10 @@ where
     fn transport(&self) -> &Self::Transport {
         self.get_ref()
     }
+
+    fn response_len(&self, response: &Resp) -> usize {
+        (self.response_len)(response)
+    }
 }
 
 /// A stream of requests coming over a channel. `Requests` also drives the sending of responses, so
@@ -755,6 +1537,43 @@ where
     pending_responses: mpsc::Receiver<Response<C::Resp>>,
     /// Handed out to request handlers to fan in responses.
     responses_tx: mpsc::Sender<Response<C::Resp>>,
+    /// Responses received from handlers and ordered by [`Config::response_order`].
+    staged_responses: BinaryHeap<StagedResponse<C::Resp>>,
+    /// The number of responses staged so far, which orders responses of equal size.
+    staged_count: u64,
+}
+
+/// A response waiting to be written, ordered so that the smallest, then oldest, is greatest.
+struct StagedResponse<Resp> {
+    len: usize,
+    seq: u64,
+    response: Response<Resp>,
+}
+
+impl<Resp> StagedResponse<Resp> {
+    fn key(&self) -> std::cmp::Reverse<(usize, u64)> {
+        std::cmp::Reverse((self.len, self.seq))
+    }
+}
+
+impl<Resp> PartialEq for StagedResponse<Resp> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<Resp> Eq for StagedResponse<Resp> {}
+
+impl<Resp> PartialOrd for StagedResponse<Resp> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Resp> Ord for StagedResponse<Resp> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
 }
 
 impl<C> Requests<C>
@@ -788,6 +1607,7 @@ where
                  abort_registration,
                  span,
                  mut response_guard,
+                 items,
              }| {
                 // The response guard becomes active once in an InFlightRequest.
                 response_guard.cancel = true;
@@ -800,7 +1620,9 @@ where
                     abort_registration,
                     span,
                     response_guard,
+                    items,
                     response_tx: self.responses_tx.clone(),
+                    read_at: tokio::time::Instant::now(),
                 }
             },
         )
@@ -849,12 +1671,46 @@ where
     ) -> Poll<Option<Result<Response<C::Resp>, C::Error>>> {
         ready!(self.ensure_writeable(cx)?);
 
-        match ready!(self.pending_responses_mut().poll_recv(cx)) {
-            Some(response) => Poll::Ready(Some(Ok(response))),
-            None => {
-                // This branch likely won't happen, since the Requests stream is holding a Sender.
-                Poll::Ready(None)
-            }
+        if self.channel.config().response_order == ResponseOrder::Completion {
+            return match ready!(self.pending_responses_mut().poll_recv(cx)) {
+                Some(response) => Poll::Ready(Some(Ok(response))),
+                None => {
+                    // This branch likely won't happen, since the Requests stream is holding a
+                    // Sender.
+                    Poll::Ready(None)
+                }
+            };
+        }
+
+        // Stage every response that is ready, so that the first one written is chosen among all
+        // of them.
+        let capacity = self.channel.config().pending_response_buffer;
+        let mut closed = false;
+        while self.staged_responses.len() < capacity {
+            let response = match self.pending_responses_mut().poll_recv(cx) {
+                Poll::Ready(Some(response)) => response,
+                Poll::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            };
+            let len = match &response.message {
+                Ok(message) => self.channel.response_len(message),
+                Err(_) => 0,
+            };
+            let this = self.as_mut().project();
+            this.staged_responses.push(StagedResponse {
+                len,
+                seq: *this.staged_count,
+                response,
+            });
+            *this.staged_count += 1;
+        }
+        match self.as_mut().project().staged_responses.pop() {
+            Some(staged) => Poll::Ready(Some(Ok(staged.response))),
+            None if closed => Poll::Ready(None),
+            None => Poll::Pending,
         }
     }
 
@@ -898,9 +1754,14 @@ where
     ///     assert_eq!(client.call(context::current(), "AddOne", 1).await.unwrap(), 2);
     /// }
     /// ```
-    pub fn execute<S>(self, serve: S) -> impl Stream<Item = impl Future<Output = ()>>
+    pub fn execute<S>(
+        self,
+        serve: S,
+    ) -> impl Stream<Item = ExecuteRequest<impl Future<Output = ()>>>
     where
         S: Serve<Req = C::Req, Resp = C::Resp> + Clone,
+        C::Req: Send + 'static,
+        C::Resp: Send + 'static,
     {
         self.take_while(|result| {
             if let Err(e) = result {
@@ -911,7 +1772,11 @@ where
         .filter_map(|result| async move { result.ok() })
         .map(move |request| {
             let serve = serve.clone();
-            request.execute(serve)
+            ExecuteRequest {
+                request_id: request.request.id,
+                trace_id: *request.request.context.trace_id(),
+                future: request.execute(serve),
+            }
         })
     }
 }
@@ -925,6 +1790,38 @@ where
     }
 }
 
+/// A future executing a request, yielded by [`Channel::execute`]. It carries the id and trace id
+/// of its request, which [`spawn_incoming`](incoming::spawn_incoming) uses to name the request's
+/// task.
+#[pin_project]
+#[derive(Debug)]
+pub struct ExecuteRequest<F> {
+    request_id: u64,
+    trace_id: trace::TraceId,
+    #[pin]
+    future: F,
+}
+
+impl<F> ExecuteRequest<F> {
+    /// Returns the id of the request being executed.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Returns the trace id of the request being executed.
+    pub fn trace_id(&self) -> trace::TraceId {
+        self.trace_id
+    }
+}
+
+impl<F: Future> Future for ExecuteRequest<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        self.project().future.poll(cx)
+    }
+}
+
 /// A fail-safe to ensure requests are properly canceled if request processing is aborted before
 /// completing.
 #[derive(Debug)]
@@ -952,7 +1849,10 @@ pub struct InFlightRequest<Req, Res> {
     abort_registration: AbortRegistration,
     response_guard: ResponseGuard,
     span: Span,
+    items: Option<RequestStream<Req>>,
     response_tx: mpsc::Sender<Response<Res>>,
+    /// When the request was read from the channel.
+    read_at: tokio::time::Instant,
 }
 
 impl<Req, Res> InFlightRequest<Req, Res> {
@@ -964,7 +1864,9 @@ impl<Req, Res> InFlightRequest<Req, Res> {
     /// Returns a [future](Future) that executes the request using the given [service
     /// function](Serve). The service function's output is automatically sent back to the [Channel]
     /// that yielded this request. The request will be executed in the scope of this request's
-    /// context.
+    /// context. The service function can send responses ahead of its output through the request's
+    /// [response sink](response_sink::ResponseSink), and receives the items the client streams to
+    /// the request through its [request stream](request_stream::RequestStream).
     ///
     /// The returned future will stop executing when the first of the following conditions is met:
     ///
@@ -1008,36 +1910,63 @@ impl<Req, Res> InFlightRequest<Req, Res> {
     pub async fn execute<S>(self, serve: S)
     where
         S: Serve<Req = Req, Resp = Res>,
+        Req: Send + 'static,
+        Res: Send + 'static,
     {
         let Self {
             response_tx,
             mut response_guard,
             abort_registration,
             span,
+            items,
             request:
                 Request {
-                    context,
+                    mut context,
                     message,
                     id: request_id,
+                    ..
                 },
+            read_at,
         } = self;
+        context.queue_time.server = read_at.elapsed();
+        span.record(
+            "rpc.queue_time.server",
+            tracing::field::debug(context.queue_time.server),
+        );
         let method = serve.method(&message);
         span.record("otel.name", method.unwrap_or(""));
-        let _ = Abortable::new(
-            async move {
-                let message = serve.serve(context, message).await;
-                tracing::info!("CompleteRequest");
-                let response = Response {
-                    request_id,
-                    message,
-                };
-                let _ = response_tx.send(response).await;
-                tracing::info!("BufferResponse");
-            },
-            abort_registration,
-        )
-        .instrument(span)
-        .await;
+        // Fires if the request is aborted or this future is dropped before the request completes,
+        // canceling client calls linked to the handler.
+        let cancellation = context::Cancellation::new();
+        let cancel_linked_calls = cancellation.clone().cancel_on_drop();
+        let sink = ResponseSink::new(request_id, response_tx.clone());
+        let handler = cancellation.link(async move {
+            if context.deadline <= clock::now() {
+                // The request expired while waiting to be executed. The channel cleans up
+                // the request once it notices the expiration.
+                tracing::info!("DeadlineExceeded");
+                return;
+            }
+            let message = serve.serve(context, message).await;
+            tracing::info!("CompleteRequest");
+            let response = Response {
+                request_id,
+                message,
+                more: false,
+            };
+            let _ = response_tx.send(response).await;
+            tracing::info!("BufferResponse");
+        });
+        let handler = match items {
+            Some(items) => items.link(handler),
+            None => request_stream::Linked::unlinked(handler),
+        };
+        let completed = Abortable::new(sink.link(handler), abort_registration)
+            .instrument(span)
+            .await;
+        if completed.is_ok() {
+            cancel_linked_calls.disarm();
+        }
         // Request processing has completed, meaning either the channel canceled the request or
         // a request was sent back to the channel. Either way, the channel will clean up the
         // request data, so the request does not need to be canceled.
@@ -1045,13 +1974,6 @@ impl<Req, Res> InFlightRequest<Req, Res> {
     }
 }
 
-fn print_err(e: &(dyn Error + 'static)) -> String {
-    anyhow::Chain::new(e)
-        .map(|e| e.to_string())
-        .collect::<Vec<_>>()
-        .join(": ")
-}
-
 impl<C> Stream for Requests<C>
 where
     C: Channel,
@@ -1097,13 +2019,16 @@ where
 #[cfg(test)]
 mod tests {
     use super::{
-        in_flight_requests::AlreadyExistsError, serve, AfterRequest, BaseChannel, BeforeRequest,
-        Channel, Config, Requests, Serve,
+        audit::{AuditEventKind, AuditLog},
+        in_flight_requests::AlreadyExistsError,
+        serve, AfterRequest, BaseChannel, BeforeRequest, BufferLimitPolicy, Channel,
+        ConcurrencyLimit, Config, MemoryPool, Requests, ResponseOrder, Serve,
     };
     use crate::{
         context, trace,
         transport::channel::{self, UnboundedChannel},
-        ClientMessage, Request, Response, ServerError,
+        transport::{MalformedFrame, MalformedFramePolicy},
+        ChannelError, ClientMessage, InvalidConfig, Request, Response, ServerError,
     };
     use assert_matches::assert_matches;
     use futures::{
@@ -1113,8 +2038,10 @@ mod tests {
     };
     use futures_test::task::noop_context;
     use std::{
+        collections::VecDeque,
         io,
         pin::Pin,
+        sync::{Arc, Mutex},
         task::Poll,
         time::{Duration, Instant, SystemTime},
     };
@@ -1160,6 +2087,7 @@ mod tests {
         // Add 1 because capacity 0 is not supported (but is supported by transport::channel::bounded).
         let config = Config {
             pending_response_buffer: capacity + 1,
+            ..Config::default()
         };
         (Box::pin(BaseChannel::new(config, rx).requests()), tx)
     }
@@ -1169,6 +2097,8 @@ mod tests {
             context: context::current(),
             id: 0,
             message: req,
+            one_way: false,
+            streamed: false,
         })
     }
 
@@ -1178,6 +2108,87 @@ mod tests {
         Abortable::new(pending(), abort_registration)
     }
 
+    #[test]
+    fn config_builder_validates_settings() {
+        let config = Config::builder()
+            .max_buffered_bytes(Some(1024))
+            .buffer_limit_policy(BufferLimitPolicy::Shed)
+            .build()
+            .unwrap();
+        assert_eq!(config.max_buffered_bytes(), Some(1024));
+        assert_eq!(config.buffer_limit_policy(), BufferLimitPolicy::Shed);
+
+        assert_matches!(
+            Config::high_throughput()
+                .into_builder()
+                .pending_response_buffer(0)
+                .build(),
+            Err(InvalidConfig {
+                field: "pending_response_buffer",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder().max_buffered_bytes(Some(0)).build(),
+            Err(InvalidConfig {
+                field: "max_buffered_bytes",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder()
+                .read_idle_timeout(Some(Duration::ZERO))
+                .build(),
+            Err(InvalidConfig {
+                field: "read_idle_timeout",
+                ..
+            })
+        );
+        assert_matches!(
+            Config::builder().max_buffered_items(Some(0)).build(),
+            Err(InvalidConfig {
+                field: "max_buffered_items",
+                ..
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_idle_channel_with_requests_in_flight_closes() {
+        let (tx, rx) = crate::transport::channel::unbounded();
+        let config = Config::builder()
+            .read_idle_timeout(Some(Duration::from_secs(5)))
+            .build()
+            .unwrap();
+        let mut channel = Box::pin(BaseChannel::<(), (), _>::new(config, rx));
+        let mut tx: UnboundedChannel<Response<()>, _> = tx;
+
+        // Idle channels without requests in flight stay open.
+        assert!(futures::poll!(channel.next()).is_pending());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(futures::poll!(channel.next()).is_pending());
+
+        tx.send(fake_request(())).await.unwrap();
+        let request = match channel.next().await {
+            Some(Ok(request)) => request,
+            other => panic!("expected a request, got {other:?}"),
+        };
+        assert!(futures::poll!(channel.next()).is_pending());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(futures::poll!(channel.next()).is_pending());
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert_matches!(
+            channel.next().await,
+            Some(Err(ChannelError::ReadIdle(timeout))) if timeout == Duration::from_secs(5)
+        );
+        assert_eq!(channel.in_flight_requests(), 0);
+        assert_matches!(
+            test_abortable(request.abort_registration).await,
+            Err(Aborted)
+        );
+    }
+
     #[tokio::test]
     async fn test_serve() {
         let serve = serve(|_, i| async move { Ok(i) });
@@ -1271,13 +2282,17 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         assert_matches!(
             channel.as_mut().start_request(Request {
                 id: 0,
                 context: context::current(),
-                message: ()
+                message: (),
+                one_way: false,
+                streamed: false,
             }),
             Err(AlreadyExistsError)
         );
@@ -1294,6 +2309,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         let req1 = channel
@@ -1302,6 +2319,8 @@ mod tests {
                 id: 1,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         tokio::time::advance(std::time::Duration::from_secs(1000)).await;
@@ -1325,6 +2344,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
 
@@ -1354,6 +2375,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
 
@@ -1396,6 +2419,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         tokio::time::advance(std::time::Duration::from_secs(1000)).await;
@@ -1419,6 +2444,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         assert_eq!(channel.in_flight_requests(), 1);
@@ -1427,11 +2454,41 @@ mod tests {
             .start_send(Response {
                 request_id: 0,
                 message: Ok(()),
+                more: false,
             })
             .unwrap();
         assert_eq!(channel.in_flight_requests(), 0);
     }
 
+    #[tokio::test]
+    async fn base_channel_start_send_discards_one_way_response() {
+        let (mut channel, mut tx) = test_channel::<(), ()>();
+
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: (),
+                one_way: true,
+                streamed: false,
+            })
+            .unwrap();
+        channel
+            .as_mut()
+            .start_send(Response {
+                request_id: 0,
+                message: Ok(()),
+                more: false,
+            })
+            .unwrap();
+        assert_eq!(channel.in_flight_requests(), 0);
+        assert_eq!(channel.dropped_responses(), 0);
+
+        drop(channel);
+        assert_matches!(tx.next().await, None);
+    }
+
     #[tokio::test]
     async fn in_flight_request_drop_cancels_request() {
         let (mut requests, mut tx) = test_requests::<(), ()>();
@@ -1482,6 +2539,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         requests
@@ -1490,6 +2549,7 @@ mod tests {
             .start_send(Response {
                 request_id: 0,
                 message: Ok(()),
+                more: false,
             })
             .unwrap();
 
@@ -1501,6 +2561,7 @@ mod tests {
             .send(Response {
                 request_id: 1,
                 message: Ok(()),
+                more: false,
             })
             .await
             .unwrap();
@@ -1512,6 +2573,8 @@ mod tests {
                 id: 1,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
 
@@ -1533,6 +2596,8 @@ mod tests {
                 id: 0,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         requests
@@ -1541,6 +2606,7 @@ mod tests {
             .start_send(Response {
                 request_id: 0,
                 message: Ok(()),
+                more: false,
             })
             .unwrap();
 
@@ -1552,6 +2618,8 @@ mod tests {
                 id: 1,
                 context: context::current(),
                 message: (),
+                one_way: false,
+                streamed: false,
             })
             .unwrap();
         requests
@@ -1561,6 +2629,7 @@ mod tests {
             .send(Response {
                 request_id: 1,
                 message: Ok(()),
+                more: false,
             })
             .await
             .unwrap();
@@ -1576,6 +2645,63 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn requests_write_smallest_response_first() {
+        let (mut tx, rx) = crate::transport::channel::unbounded::<Response<String>, _>();
+        let config = Config::builder()
+            .response_order(ResponseOrder::SmallestFirst)
+            .build()
+            .unwrap();
+        let mut requests = Box::pin(
+            BaseChannel::new(config, rx)
+                .with_message_len(|()| 0, String::len)
+                .requests(),
+        );
+
+        let responses = [
+            Ok(String::from("large response")),
+            Err(ServerError::new(io::ErrorKind::Other, String::new())),
+            Ok(String::from("small")),
+            Ok(String::from("smaller")),
+        ];
+        for (id, message) in (0..).zip(responses) {
+            requests
+                .as_mut()
+                .channel_pin_mut()
+                .start_request(Request {
+                    id,
+                    context: context::current(),
+                    message: (),
+                    one_way: false,
+                    streamed: false,
+                })
+                .unwrap();
+            requests
+                .as_mut()
+                .project()
+                .responses_tx
+                .send(Response {
+                    request_id: id,
+                    message,
+                    more: false,
+                })
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..4 {
+            assert_matches!(
+                requests.as_mut().pump_write(&mut noop_context(), false),
+                Poll::Ready(Some(Ok(())))
+            );
+        }
+        let mut order = vec![];
+        for _ in 0..4 {
+            order.push(tx.next().await.unwrap().unwrap().request_id);
+        }
+        assert_eq!(order, [1, 2, 3, 0]);
+    }
+
     #[tokio::test]
     async fn requests_pump_read() {
         let (mut requests, mut tx) = test_requests::<(), ()>();
@@ -1589,4 +2715,615 @@ mod tests {
         );
         assert_eq!(requests.channel.in_flight_requests(), 1);
     }
+
+    /// A transport that yields scripted reads and records everything written to it.
+    #[derive(Default)]
+    struct ScriptedTransport {
+        reads: VecDeque<io::Result<ClientMessage<()>>>,
+        writes: Vec<Response<()>>,
+        /// Whether the transport is not ready for writes, as when the client stops reading.
+        write_blocked: bool,
+    }
+
+    impl Stream for ScriptedTransport {
+        type Item = io::Result<ClientMessage<()>>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> Poll<Option<Self::Item>> {
+            match self.reads.pop_front() {
+                Some(read) => Poll::Ready(Some(read)),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl Sink<Response<()>> for ScriptedTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut std::task::Context) -> Poll<io::Result<()>> {
+            if self.write_blocked {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, response: Response<()>) -> io::Result<()> {
+            self.writes.push(response);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut std::task::Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut std::task::Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn malformed_read(request_id: Option<u64>) -> io::Result<ClientMessage<()>> {
+        let mut frame = MalformedFrame::new("bad frame");
+        if let Some(request_id) = request_id {
+            frame = frame.with_request_id(request_id);
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, frame))
+    }
+
+    fn scripted_channel(
+        policy: MalformedFramePolicy,
+        reads: impl IntoIterator<Item = io::Result<ClientMessage<()>>>,
+    ) -> Pin<Box<BaseChannel<(), (), ScriptedTransport>>> {
+        let config = Config {
+            malformed_frame_policy: policy,
+            ..Config::default()
+        };
+        let transport = ScriptedTransport {
+            reads: reads.into_iter().collect(),
+            ..ScriptedTransport::default()
+        };
+        Box::pin(BaseChannel::new(config, transport))
+    }
+
+    #[tokio::test]
+    async fn base_channel_malformed_frame_closes_by_default() {
+        let mut channel = scripted_channel(MalformedFramePolicy::Close, [malformed_read(Some(1))]);
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Err(ChannelError::Read(_))))
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_malformed_frame_drop() {
+        let mut channel = scripted_channel(
+            MalformedFramePolicy::Drop,
+            [malformed_read(Some(1)), Ok(fake_request(()))],
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert!(channel.transport().writes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn base_channel_malformed_frame_respond() {
+        let mut channel = scripted_channel(
+            MalformedFramePolicy::Respond,
+            [malformed_read(Some(7)), malformed_read(None)],
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            &channel.transport().writes[..],
+            [Response {
+                request_id: 7,
+                message: Err(ServerError {
+                    kind: io::ErrorKind::InvalidData,
+                    ..
+                }),
+                ..
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_stops_reading_while_rejections_are_unsent() {
+        let config = Config {
+            malformed_frame_policy: MalformedFramePolicy::Respond,
+            pending_response_buffer: 2,
+            ..Config::default()
+        };
+        let transport = ScriptedTransport {
+            reads: (0..4).map(|id| malformed_read(Some(id))).collect(),
+            write_blocked: true,
+            ..ScriptedTransport::default()
+        };
+        let mut channel = Box::pin(BaseChannel::new(config, transport));
+
+        // The client isn't reading its error responses, so the channel stops reading its requests.
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.transport().reads.len(), 2);
+        assert_eq!(channel.rejected_requests.len(), 2);
+
+        channel.as_mut().get_pin_ref().write_blocked = false;
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(
+            channel
+                .transport()
+                .writes
+                .iter()
+                .map(|response| response.request_id)
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+    }
+
+    fn budgeted_channel(
+        policy: BufferLimitPolicy,
+        reads: impl IntoIterator<Item = io::Result<ClientMessage<()>>>,
+    ) -> Pin<Box<BaseChannel<(), (), ScriptedTransport>>> {
+        let config = Config {
+            max_buffered_bytes: Some(10),
+            buffer_limit_policy: policy,
+            ..Config::default()
+        };
+        let transport = ScriptedTransport {
+            reads: reads.into_iter().collect(),
+            ..ScriptedTransport::default()
+        };
+        Box::pin(BaseChannel::new(config, transport).with_message_len(|()| 10, |()| 10))
+    }
+
+    fn request_with_id(id: u64) -> io::Result<ClientMessage<()>> {
+        Ok(ClientMessage::Request(Request {
+            context: context::current(),
+            id,
+            message: (),
+            one_way: false,
+            streamed: false,
+        }))
+    }
+
+    #[tokio::test]
+    async fn base_channel_over_budget_applies_backpressure() {
+        let mut channel = budgeted_channel(
+            BufferLimitPolicy::Backpressure,
+            [request_with_id(0), request_with_id(1)],
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_eq!(channel.buffered_len(), 10);
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.transport().reads.len(), 1);
+
+        channel
+            .as_mut()
+            .start_send(Response {
+                request_id: 0,
+                message: Ok(()),
+                more: false,
+            })
+            .unwrap();
+        // The response is still buffered until it is flushed.
+        assert_eq!(channel.buffered_len(), 10);
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(
+            channel.as_mut().poll_flush(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(channel.buffered_len(), 0);
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(request))) if request.request.id == 1
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_over_budget_sheds() {
+        let mut channel = budgeted_channel(
+            BufferLimitPolicy::Shed,
+            [request_with_id(0), request_with_id(1)],
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.in_flight_requests(), 1);
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            &channel.transport().writes[..],
+            [Response {
+                request_id: 1,
+                message: Err(ServerError {
+                    kind: io::ErrorKind::WouldBlock,
+                    ..
+                }),
+                ..
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channels_share_memory_pool() {
+        let pool = MemoryPool::new(10);
+        let channel = |reads: Vec<_>| {
+            let config = Config {
+                memory_pool: Some(pool.clone()),
+                ..Config::default()
+            };
+            let transport = ScriptedTransport {
+                reads: reads.into_iter().collect(),
+                ..ScriptedTransport::default()
+            };
+            Box::pin(BaseChannel::new(config, transport).with_message_len(|()| 10, |()| 0))
+        };
+        let mut busy = channel(vec![request_with_id(0), request_with_id(1)]);
+        let mut quiet = channel(vec![request_with_id(0)]);
+
+        assert_matches!(
+            busy.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_eq!(pool.used(), 10);
+        assert_matches!(busy.as_mut().poll_next(&mut noop_context()), Poll::Pending);
+        assert_eq!(busy.transport().reads.len(), 1);
+
+        // The quiet channel holds less than its fair share of the pool, so it is not limited.
+        assert_matches!(
+            quiet.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_eq!(pool.used(), 20);
+
+        drop(quiet);
+        assert_eq!(pool.used(), 10);
+    }
+
+    #[tokio::test]
+    async fn base_channels_share_concurrency_limit() {
+        let limit = ConcurrencyLimit::new(1);
+        let channel = |reads: Vec<_>| {
+            let config = Config::builder()
+                .concurrency_limit(Some(limit.clone()))
+                .build()
+                .unwrap();
+            let transport = ScriptedTransport {
+                reads: reads.into_iter().collect(),
+                ..ScriptedTransport::default()
+            };
+            Box::pin(BaseChannel::new(config, transport))
+        };
+        let mut a = channel(vec![request_with_id(0)]);
+        let mut b = channel(vec![request_with_id(0), request_with_id(1)]);
+
+        assert_matches!(
+            a.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_eq!(limit.in_flight(), 1);
+        assert_matches!(b.as_mut().poll_next(&mut noop_context()), Poll::Pending);
+        assert_eq!(b.in_flight_requests(), 0);
+        assert_matches!(
+            b.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            &b.transport().writes[..],
+            [Response { request_id: 0, message: Err(e), .. }, Response { request_id: 1, .. }]
+                if e.is_resource_exhausted()
+        );
+
+        a.as_mut()
+            .start_send(Response {
+                request_id: 0,
+                message: Ok(()),
+                more: false,
+            })
+            .unwrap();
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn base_channel_records_channel_opened() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = Config {
+            audit_log: Some(AuditLog::new({
+                let events = events.clone();
+                move |event| events.lock().unwrap().push(event.kind)
+            })),
+            ..Config::default()
+        };
+        let _channel = BaseChannel::<(), (), _>::new(config, ScriptedTransport::default());
+        assert_eq!(*events.lock().unwrap(), [AuditEventKind::ChannelOpened]);
+    }
+    #[tokio::test]
+    async fn base_channel_drops_expired_responses() {
+        let mut channel = scripted_channel(MalformedFramePolicy::Close, []);
+        let mut context = context::current();
+        context.deadline = SystemTime::now() - Duration::from_secs(1);
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context,
+                message: (),
+                one_way: false,
+                streamed: false,
+            })
+            .unwrap();
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 1,
+                context: context::current(),
+                message: (),
+                one_way: false,
+                streamed: false,
+            })
+            .unwrap();
+
+        for request_id in [0, 1, 2] {
+            channel
+                .as_mut()
+                .start_send(Response {
+                    request_id,
+                    message: Ok(()),
+                    more: false,
+                })
+                .unwrap();
+        }
+        assert_matches!(
+            &channel.transport().writes[..],
+            [Response { request_id: 1, .. }]
+        );
+        assert_eq!(channel.dropped_responses(), 2);
+        assert_eq!(channel.in_flight_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn base_channel_drops_expired_requests() {
+        let mut context = context::current();
+        context.deadline = SystemTime::now() - Duration::from_secs(1);
+        let mut channel = scripted_channel(
+            MalformedFramePolicy::Close,
+            [
+                Ok(ClientMessage::Request(Request {
+                    context,
+                    id: 0,
+                    message: (),
+                    one_way: false,
+                    streamed: false,
+                })),
+                request_with_id(1),
+            ],
+        );
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(request))) if request.request.id == 1
+        );
+        assert_eq!(channel.in_flight_requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_expired_is_not_executed() {
+        let (mut requests, mut tx) = test_requests::<(), ()>();
+        tx.send(fake_request(())).await.unwrap();
+        let mut request = match requests.as_mut().pump_read(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+        request.request.context.deadline = SystemTime::now() - Duration::from_secs(1);
+
+        request
+            .execute(serve(|_, ()| async move {
+                panic!("an expired request should not be executed")
+            }))
+            .await;
+        assert_matches!(requests.as_mut().pending_responses_mut().try_recv(), Err(_));
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_records_server_queue_time() {
+        let (mut requests, mut tx) = test_requests::<(), Duration>();
+        tx.send(fake_request(())).await.unwrap();
+        let mut request = match requests.as_mut().pump_read(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+        request.read_at -= Duration::from_secs(1);
+
+        request
+            .execute(serve(|ctx: context::Context, ()| async move {
+                Ok(ctx.queue_time.server)
+            }))
+            .await;
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().try_recv(),
+            Ok(Response { message: Ok(queue_time), .. }) if queue_time >= Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn aborted_request_cancels_linked_calls() {
+        let (mut requests, mut tx) = test_requests::<(), ()>();
+        tx.send(fake_request(())).await.unwrap();
+        tx.send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 1,
+            message: (),
+            one_way: false,
+            streamed: false,
+        }))
+        .await
+        .unwrap();
+        let mut next_request = || match requests.as_mut().pump_read(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+        let (aborted, completed) = (next_request(), next_request());
+
+        let (cancellation_tx, cancellation_rx) = futures::channel::oneshot::channel();
+        let mut execution = Box::pin(aborted.execute(serve(|_, ()| async move {
+            let _ = cancellation_tx.send(context::Cancellation::current().unwrap());
+            pending().await
+        })));
+        assert!(futures::poll!(execution.as_mut()).is_pending());
+        let cancellation = cancellation_rx.await.unwrap();
+        assert!(!cancellation.is_canceled());
+        drop(execution);
+        assert!(cancellation.is_canceled());
+
+        let (cancellation_tx, cancellation_rx) = futures::channel::oneshot::channel();
+        completed
+            .execute(serve(|_, ()| async move {
+                let _ = cancellation_tx.send(context::Cancellation::current().unwrap());
+                Ok(())
+            }))
+            .await;
+        assert!(!cancellation_rx.await.unwrap().is_canceled());
+    }
+
+    #[tokio::test]
+    async fn overrunning_a_stream_window_fails_only_that_request() {
+        let (tx, rx) = crate::transport::channel::unbounded();
+        let config = Config::builder()
+            .max_buffered_items(Some(2))
+            .build()
+            .unwrap();
+        let mut channel = Box::pin(BaseChannel::<u32, u32, _>::new(config, rx));
+        let mut tx: UnboundedChannel<Response<u32>, _> = tx;
+        for id in [0, 1] {
+            tx.send(ClientMessage::Request(Request {
+                context: context::current(),
+                id,
+                message: 0,
+                one_way: false,
+                streamed: true,
+            }))
+            .await
+            .unwrap();
+        }
+        for (request_id, item) in [(0, 1), (0, 2), (1, 7), (0, 3)] {
+            tx.send(ClientMessage::Item {
+                request_id,
+                item: Some(item),
+            })
+            .await
+            .unwrap();
+        }
+        let overrun = channel.next().await.unwrap().unwrap();
+        let within_window = channel.next().await.unwrap().unwrap();
+        assert!(futures::poll!(channel.next()).is_pending());
+        assert_eq!(channel.in_flight_requests.len(), 1);
+
+        // The overrun stream ends with the items that fit in its window.
+        let items = overrun.items.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(items, [1, 2]);
+        let mut items = within_window.items.unwrap();
+        assert_matches!(items.next().await, Some(7));
+
+        channel
+            .send(Response {
+                request_id: 1,
+                message: Ok(7),
+                more: false,
+            })
+            .await
+            .unwrap();
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(Response {
+                request_id: 0,
+                message: Err(ServerError {
+                    kind: io::ErrorKind::WouldBlock,
+                    ..
+                }),
+                ..
+            }))
+        );
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(Response {
+                request_id: 1,
+                message: Ok(7),
+                ..
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_sees_request_envelope() {
+        let (tx, rx) = crate::transport::channel::unbounded();
+        let channel = BaseChannel::<String, _, _>::new(Config::default(), rx)
+            .with_message_len(String::len, |_| 0);
+        let mut requests = Box::pin(channel.requests());
+        let mut tx: UnboundedChannel<Response<(u64, usize)>, _> = tx;
+        tx.send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 7,
+            message: "hello".to_string(),
+            one_way: false,
+            streamed: false,
+        }))
+        .await
+        .unwrap();
+        let request = match requests.as_mut().pump_read(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+
+        request
+            .execute(serve(|ctx: context::Context, _: String| async move {
+                let envelope = ctx.envelope.unwrap();
+                assert!(envelope.received_at <= tokio::time::Instant::now());
+                Ok((envelope.request_id, envelope.len))
+            }))
+            .await;
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().try_recv(),
+            Ok(Response {
+                message: Ok((7, 5)),
+                ..
+            })
+        );
+    }
 }
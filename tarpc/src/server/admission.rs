@@ -0,0 +1,193 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a hook that rejects connections before a channel is created for them.
+//!
+//! Fending off an abusive client is cheapest before the server spends anything on it. An
+//! [`Admission`] decides, from the peer's identity, whether a connection is accepted; it counts
+//! the connections it accepts and rejects, so that a spike in rejections is observable. It can be
+//! applied at accept time, e.g. by
+//! [`tcp::Incoming::with_admission`](crate::serde_transport::tcp::Incoming::with_admission),
+//! which closes a rejected connection before framing it, or to any stream of accepted transports
+//! with [`Admission::filter`], e.g. to check an identity established by a handshake.
+//!
+//! ```rust
+//! use std::{collections::HashSet, net::{IpAddr, SocketAddr}};
+//! use tarpc::server::admission::Admission;
+//!
+//! let banned: HashSet<IpAddr> = ["192.0.2.1".parse().unwrap()].into_iter().collect();
+//! let admission = Admission::new(move |peer: &SocketAddr| !banned.contains(&peer.ip()));
+//! assert!(!admission.admit(&"192.0.2.1:4000".parse().unwrap()));
+//! assert!(admission.admit(&"192.0.2.2:4000".parse().unwrap()));
+//! assert_eq!(admission.stats().rejected, 1);
+//! ```
+
+use crate::util::tracing;
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// The counters of an [`Admission`] at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AdmissionStats {
+    /// The number of connections accepted.
+    pub accepted: u64,
+    /// The number of connections rejected.
+    pub rejected: u64,
+}
+
+struct Shared<P> {
+    admit: Box<dyn Fn(&P) -> bool + Send + Sync>,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Decides which peers' connections are accepted. Cloning the admission produces a handle to the
+/// same hook and counters, so that listeners sharing it are counted together.
+pub struct Admission<P> {
+    shared: Arc<Shared<P>>,
+}
+
+impl<P> Admission<P> {
+    /// Returns an admission that accepts the connections of the peers `admit` returns true for.
+    pub fn new(admit: impl Fn(&P) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                admit: Box::new(admit),
+                accepted: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns whether to accept a connection from `peer`, counting the decision.
+    pub fn admit(&self, peer: &P) -> bool
+    where
+        P: fmt::Debug,
+    {
+        if (self.shared.admit)(peer) {
+            self.shared.accepted.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            self.shared.rejected.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(?peer, "RejectedConnection");
+            false
+        }
+    }
+
+    /// Returns the current values of the counters.
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            accepted: self.shared.accepted.load(Ordering::Relaxed),
+            rejected: self.shared.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops the transports of `incoming` whose peers are rejected, as identified by `identify`.
+    /// Errors are passed through.
+    pub fn filter<St, T, E, F>(self, incoming: St, identify: F) -> Admitted<St, F, P>
+    where
+        St: Stream<Item = Result<T, E>>,
+        F: Fn(&T) -> P,
+    {
+        Admitted {
+            incoming,
+            identify,
+            admission: self,
+        }
+    }
+}
+
+impl<P> Clone for Admission<P> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<P> fmt::Debug for Admission<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admission")
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A stream of the transports whose peers an [`Admission`] accepts. Created by
+/// [`Admission::filter`].
+#[pin_project]
+#[derive(Debug)]
+pub struct Admitted<St, F, P> {
+    #[pin]
+    incoming: St,
+    identify: F,
+    admission: Admission<P>,
+}
+
+impl<St, T, E, F, P> Stream for Admitted<St, F, P>
+where
+    St: Stream<Item = Result<T, E>>,
+    F: Fn(&T) -> P,
+    P: fmt::Debug,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match futures::ready!(this.incoming.as_mut().poll_next(cx)) {
+                Some(Ok(transport)) if !this.admission.admit(&(this.identify)(&transport)) => {}
+                item => return Poll::Ready(item),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Admission, AdmissionStats};
+    use futures::prelude::*;
+    use std::io;
+
+    #[tokio::test]
+    async fn filter_drops_rejected_transports() {
+        let admission = Admission::new(|peer: &u32| peer % 2 == 0);
+        let incoming = stream::iter(vec![
+            Ok(1),
+            Ok(2),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+            Ok(3),
+            Ok(4),
+        ]);
+        let admitted: Vec<_> = admission
+            .clone()
+            .filter(incoming, |transport: &u32| *transport)
+            .map(|transport| transport.map_err(|e| e.kind()))
+            .collect()
+            .await;
+        assert_eq!(
+            admitted,
+            [Ok(2), Err(io::ErrorKind::ConnectionReset), Ok(4)]
+        );
+        assert_eq!(
+            admission.stats(),
+            AdmissionStats {
+                accepted: 2,
+                rejected: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides an audit log of security-relevant events, kept separate from the access log.
+//!
+//! An [`AuditLog`] is configured via [`Config::audit_log`](super::Config::audit_log). Channels
+//! record the events they observe themselves, such as [`ChannelOpened`](AuditEventKind::ChannelOpened);
+//! authentication and authorization decisions are made by application code, e.g. in a
+//! [`BeforeRequest`](super::request_hook::BeforeRequest) hook, which records them on the same
+//! log.
+
+use crate::trace::TraceId;
+use crate::util::tracing;
+use std::{fmt, sync::Arc, time::SystemTime};
+
+/// The kind of a security-relevant event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditEventKind {
+    /// A channel was opened to serve a client connection.
+    ChannelOpened,
+    /// A client failed to prove its identity.
+    AuthenticationFailed,
+    /// An authenticated client was denied access to a resource.
+    AuthorizationDenied,
+    /// A client performed an administrative action.
+    AdminAction,
+}
+
+/// A security-relevant event.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AuditEvent {
+    /// What happened.
+    pub kind: AuditEventKind,
+    /// When it happened.
+    pub time: SystemTime,
+    /// The identity of the client, if known.
+    pub identity: Option<String>,
+    /// The trace of the request that caused the event, if any.
+    pub trace_id: Option<TraceId>,
+    /// A human-readable description of the event.
+    pub detail: String,
+}
+
+impl AuditEvent {
+    /// Returns a new event of the given kind that happened now.
+    pub fn new(kind: AuditEventKind) -> Self {
+        Self {
+            kind,
+            time: crate::clock::now(),
+            identity: None,
+            trace_id: None,
+            detail: String::new(),
+        }
+    }
+
+    /// Sets the identity of the client.
+    pub fn with_identity(mut self, identity: impl Into<String>) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+
+    /// Sets the trace of the request that caused the event.
+    pub fn with_trace_id(mut self, trace_id: TraceId) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
+    /// Sets the description of the event.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+}
+
+/// A destination for [audit events](AuditEvent). Cloning the log produces a handle to the same
+/// destination.
+#[derive(Clone)]
+pub struct AuditLog(Arc<dyn Fn(&AuditEvent) + Send + Sync>);
+
+impl AuditLog {
+    /// Returns a log that passes each event to `record`.
+    pub fn new<F>(record: F) -> Self
+    where
+        F: Fn(&AuditEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(record))
+    }
+
+    /// Returns a log that emits each event as a [`tracing`] event with the target
+    /// `tarpc::audit`, so that it can be routed separately from other logs.
+    pub fn tracing() -> Self {
+        Self::new(|event| {
+            tracing::info!(
+                target: "tarpc::audit",
+                kind = ?event.kind,
+                identity = event.identity.as_deref(),
+                trace_id = event.trace_id.map(tracing::field::display),
+                detail = %event.detail,
+                "AuditEvent"
+            )
+        })
+    }
+
+    /// Records `event`.
+    pub fn record(&self, event: AuditEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides fault injection for servers, to exercise the retry and deadline logic of clients
+//! against a real server, e.g. in staging.
+//!
+//! A [`Serve`] wrapped with [`Serve::chaos`] injects [`Faults`] into the requests it handles:
+//! with the configured probabilities, a request fails with an injected error before its handler
+//! runs, or its response is delayed, or dropped so that it never reaches the client, which
+//! eventually sees its deadline expire. Faults can be configured for all methods and for
+//! individual methods.
+//!
+//! Faults are configured through a [`ChaosControl`], which can be cloned and changed while the
+//! server runs, e.g. by the handlers of an admin service. It starts disabled, so that a server
+//! built with chaos support behaves normally until faults are turned on. Faults are drawn from
+//! [`rand::thread_rng`], unless the control is given a generator with [`ChaosControl::set_rng`],
+//! e.g. a seeded one, so that a test or simulation injects the same faults on every run.
+//!
+//! ```rust
+//! use std::io;
+//! use tarpc::{
+//!     context,
+//!     server::{chaos::{ChaosControl, Faults}, serve, Serve},
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let control = ChaosControl::new();
+//! let serve = serve(|_, x: i32| async move { Ok(x + 1) }).chaos(control.clone());
+//! assert_eq!(serve.clone().serve(context::current(), 1).await.unwrap(), 2);
+//!
+//! // Later, e.g. from an admin endpoint:
+//! control.set_default(Faults::none().with_error(1.0, io::ErrorKind::ConnectionReset));
+//! control.set_enabled(true);
+//! assert!(serve.serve(context::current(), 1).await.is_err());
+//! # }
+//! ```
+
+use super::Serve;
+use crate::util::tracing;
+use crate::{context, ServerError};
+use rand::{Rng, RngCore};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+/// The faults to inject into requests, each with the probability of injecting it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Faults {
+    delay: Option<(f64, Duration)>,
+    error: Option<(f64, io::ErrorKind)>,
+    drop: f64,
+}
+
+fn check_probability(probability: f64) {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "probability must be between 0 and 1, but was {probability}"
+    );
+}
+
+impl Faults {
+    /// Returns faults that are never injected.
+    pub fn none() -> Self {
+        Self {
+            delay: None,
+            error: None,
+            drop: 0.0,
+        }
+    }
+
+    /// Delays responses by `delay` with probability `probability`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_delay(mut self, probability: f64, delay: Duration) -> Self {
+        check_probability(probability);
+        self.delay = Some((probability, delay));
+        self
+    }
+
+    /// Fails requests with an error of kind `kind`, without running their handlers, with
+    /// probability `probability`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_error(mut self, probability: f64, kind: io::ErrorKind) -> Self {
+        check_probability(probability);
+        self.error = Some((probability, kind));
+        self
+    }
+
+    /// Drops responses with probability `probability`. The handler of a request whose response
+    /// is dropped still runs, but the request only completes once its deadline expires.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_drop(mut self, probability: f64) -> Self {
+        check_probability(probability);
+        self.drop = probability;
+        self
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Settings {
+    enabled: bool,
+    default: Faults,
+    methods: HashMap<String, Faults>,
+}
+
+/// A handle to the faults injected by [`Chaos`] servers. Clones share the same settings.
+#[derive(Clone, Default)]
+pub struct ChaosControl {
+    settings: Arc<RwLock<Settings>>,
+    rng: Arc<Mutex<Option<Box<dyn RngCore + Send>>>>,
+}
+
+impl fmt::Debug for ChaosControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaosControl")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChaosControl {
+    /// Returns a disabled control that injects no faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns fault injection on or off, keeping the configured faults.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.settings.write().unwrap().enabled = enabled;
+    }
+
+    /// Returns whether faults are injected.
+    pub fn is_enabled(&self) -> bool {
+        self.settings.read().unwrap().enabled
+    }
+
+    /// Injects `faults` into requests of methods without faults of their own.
+    pub fn set_default(&self, faults: Faults) {
+        self.settings.write().unwrap().default = faults;
+    }
+
+    /// Injects `faults` into requests of the method named `method`, as reported by
+    /// [`Serve::method`], instead of the default faults.
+    pub fn set_method(&self, method: impl Into<String>, faults: Faults) {
+        let mut settings = self.settings.write().unwrap();
+        settings.methods.insert(method.into(), faults);
+    }
+
+    /// Draws the faults to inject from `rng` instead of [`rand::thread_rng`]. Requests handled by
+    /// any clone of the control share the generator, so a seeded generator injects the same
+    /// faults into the same sequence of requests, whichever threads handle them.
+    pub fn set_rng(&self, rng: impl RngCore + Send + 'static) {
+        *self.rng.lock().unwrap() = Some(Box::new(rng));
+    }
+
+    /// Injects the default faults into requests of the method named `method` again.
+    pub fn clear_method(&self, method: &str) {
+        self.settings.write().unwrap().methods.remove(method);
+    }
+
+    /// Returns the faults injected into requests of `method`, or [`Faults::none`] if fault
+    /// injection is disabled.
+    pub fn faults(&self, method: Option<&str>) -> Faults {
+        let settings = self.settings.read().unwrap();
+        if !settings.enabled {
+            return Faults::none();
+        }
+        method
+            .and_then(|method| settings.methods.get(method).copied())
+            .unwrap_or(settings.default)
+    }
+
+    fn with_rng<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match &mut *self.rng.lock().unwrap() {
+            Some(rng) => f(rng),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+}
+
+/// A [`Serve`] that injects faults into the requests it handles. Created by [`Serve::chaos`].
+#[derive(Clone, Debug)]
+pub struct Chaos<S> {
+    serve: S,
+    control: ChaosControl,
+}
+
+impl<S> Chaos<S> {
+    pub(crate) fn new(serve: S, control: ChaosControl) -> Self {
+        Self { serve, control }
+    }
+
+    /// Returns the control of the injected faults.
+    pub fn control(&self) -> &ChaosControl {
+        &self.control
+    }
+}
+
+impl<S: Serve> Serve for Chaos<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let method = self.serve.method(&req);
+        let faults = self.control.faults(method);
+        // Decide all faults up front, since the rng is not held across awaits.
+        let (error, delay, drop) = self.control.with_rng(|rng| {
+            (
+                faults.error.filter(|(p, _)| rng.gen_bool(*p)),
+                faults.delay.filter(|(p, _)| rng.gen_bool(*p)),
+                rng.gen_bool(faults.drop),
+            )
+        });
+        let method = method.unwrap_or("handler");
+        if let Some((_, kind)) = error {
+            tracing::info!("ChaosInjectError: failing {} with {:?}", method, kind);
+            return Err(ServerError::new(kind, "chaos: injected error".into()));
+        }
+        let response = self.serve.serve(ctx, req).await;
+        if let Some((_, delay)) = delay {
+            tracing::info!("ChaosDelayResponse: delaying {} by {:?}", method, delay);
+            tokio::time::sleep(delay).await;
+        }
+        if drop {
+            tracing::info!("ChaosDropResponse: dropping the response of {}", method);
+            return futures::future::pending().await;
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChaosControl, Faults};
+    use crate::{context, server::Serve, ServerError};
+    use assert_matches::assert_matches;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{io, time::Duration};
+
+    /// A service whose requests name the method, which echoes the method.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Serve for Echo {
+        type Req = &'static str;
+        type Resp = &'static str;
+
+        fn method(&self, method: &&'static str) -> Option<&'static str> {
+            Some(method)
+        }
+
+        async fn serve(
+            self,
+            _: context::Context,
+            method: &'static str,
+        ) -> Result<&'static str, ServerError> {
+            Ok(method)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn injects_configured_faults_by_method() {
+        let control = ChaosControl::new();
+        control.set_default(Faults::none().with_error(1.0, io::ErrorKind::ConnectionReset));
+        control.set_method(
+            "slow",
+            Faults::none().with_delay(1.0, Duration::from_secs(5)),
+        );
+        control.set_method("lost", Faults::none().with_drop(1.0));
+        let serve = Echo.chaos(control.clone());
+
+        // Disabled chaos injects nothing.
+        assert_eq!(
+            serve.clone().serve(context::current(), "").await.unwrap(),
+            ""
+        );
+
+        control.set_enabled(true);
+        assert_matches!(
+            serve.clone().serve(context::current(), "").await,
+            Err(ServerError {
+                kind: io::ErrorKind::ConnectionReset,
+                ..
+            })
+        );
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            serve
+                .clone()
+                .serve(context::current(), "slow")
+                .await
+                .unwrap(),
+            "slow"
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        let lost = serve.clone().serve(context::current(), "lost");
+        assert_matches!(
+            tokio::time::timeout(Duration::from_secs(3600), lost).await,
+            Err(_)
+        );
+
+        control.clear_method("slow");
+        assert_matches!(serve.serve(context::current(), "slow").await, Err(_));
+    }
+
+    #[tokio::test]
+    async fn seeded_rng_injects_the_same_faults() {
+        async fn outcomes(seed: u64) -> Vec<bool> {
+            let control = ChaosControl::new();
+            control.set_default(Faults::none().with_error(0.5, io::ErrorKind::ConnectionReset));
+            control.set_enabled(true);
+            control.set_rng(StdRng::seed_from_u64(seed));
+            let serve = Echo.chaos(control);
+            let mut outcomes = vec![];
+            for _ in 0..32 {
+                let response = serve.clone().serve(context::current(), "").await;
+                outcomes.push(response.is_err());
+            }
+            outcomes
+        }
+
+        let first = outcomes(7).await;
+        assert_eq!(first, outcomes(7).await);
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+
+    #[test]
+    #[should_panic(expected = "probability must be between 0 and 1")]
+    fn rejects_invalid_probability() {
+        Faults::none().with_drop(1.5);
+    }
+}
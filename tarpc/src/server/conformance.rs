@@ -0,0 +1,267 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Conformance checks for custom [`Channel`] implementations.
+//!
+//! A custom channel decorates another channel, ultimately a [`BaseChannel`], as described in the
+//! [`Channel`] documentation. The checks in this module wrap a `BaseChannel` over an in-memory
+//! transport with the channel under test, drive it from the client side of the transport, and
+//! panic if the channel breaks a contract that [`Channel::execute`], [`Channel::requests`], and
+//! the [`incoming`](super::incoming) combinators rely on:
+//!
+//! - requests reach the channel's stream unchanged, and responses sent into the channel reach the
+//!   client;
+//! - [`Channel::in_flight_requests`] counts requests from the time they are yielded until they
+//!   are answered;
+//! - a cancellation sent by the client aborts the request's
+//!   [`abort_registration`](super::TrackedRequest::abort_registration);
+//! - the channel's stream terminates once the client disconnects.
+//!
+//! The checks use the tokio timer, so they must run within a tokio runtime.
+//!
+//! ```rust
+//! use tarpc::server::{conformance, Channel};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! // A decorator that is expected to behave exactly like the channel it wraps.
+//! conformance::check_channel(|channel| channel.max_concurrent_requests(10)).await;
+//! # }
+//! ```
+
+use crate::{
+    context,
+    server::{BaseChannel, Channel, Config, TrackedRequest},
+    transport::channel::{self, UnboundedChannel},
+    ClientMessage, Request, Response, Transport,
+};
+use futures::{future::Abortable, prelude::*};
+use std::{pin::pin, task::Poll};
+
+/// The base channel that the channel under test wraps.
+pub type InnerChannel = BaseChannel<u64, u64, UnboundedChannel<ClientMessage<u64>, Response<u64>>>;
+
+type ClientTransport = UnboundedChannel<Response<u64>, ClientMessage<u64>>;
+
+/// Runs every check in this module against the channels returned by `wrap`.
+pub async fn check_channel<C, F>(mut wrap: F)
+where
+    C: Channel<Req = u64, Resp = u64> + Transport<Response<u64>, TrackedRequest<u64>>,
+    F: FnMut(InnerChannel) -> C,
+{
+    check_request_response(&mut wrap).await;
+    check_cancellation(&mut wrap).await;
+    check_termination(&mut wrap).await;
+}
+
+/// Checks that requests pass through the channel unchanged, that responses reach the client, and
+/// that in-flight requests are counted until they are answered.
+pub async fn check_request_response<C, F>(wrap: F)
+where
+    C: Channel<Req = u64, Resp = u64> + Transport<Response<u64>, TrackedRequest<u64>>,
+    F: FnOnce(InnerChannel) -> C,
+{
+    let (mut client, channel) = set_up(wrap);
+    let mut channel = pin!(channel);
+
+    send_request(&mut client, 7, 42).await;
+    let request = channel
+        .next()
+        .await
+        .expect("channel terminated before yielding the request")
+        .unwrap_or_else(|_| panic!("channel failed to yield the request"));
+    assert_eq!(request.request.id, 7, "request ID changed");
+    assert_eq!(request.request.message, 42, "request message changed");
+    assert_eq!(
+        channel.in_flight_requests(),
+        1,
+        "yielded request is not counted as in flight"
+    );
+
+    channel
+        .send(Response {
+            request_id: 7,
+            message: Ok(43),
+            more: false,
+        })
+        .await
+        .unwrap_or_else(|_| panic!("channel failed to send the response"));
+    assert_eq!(
+        channel.in_flight_requests(),
+        0,
+        "answered request is still counted as in flight"
+    );
+    let response = client
+        .next()
+        .await
+        .expect("client did not receive the response")
+        .expect("client transport failed");
+    assert_eq!(response.request_id, 7, "response ID changed");
+    assert_eq!(response.message, Ok(43), "response message changed");
+}
+
+/// Checks that a cancellation sent by the client aborts the request.
+pub async fn check_cancellation<C, F>(wrap: F)
+where
+    C: Channel<Req = u64, Resp = u64> + Transport<Response<u64>, TrackedRequest<u64>>,
+    F: FnOnce(InnerChannel) -> C,
+{
+    let (mut client, channel) = set_up(wrap);
+    let mut channel = pin!(channel);
+
+    send_request(&mut client, 1, 0).await;
+    let request = channel
+        .next()
+        .await
+        .expect("channel terminated before yielding the request")
+        .unwrap_or_else(|_| panic!("channel failed to yield the request"));
+    let mut handler = pin!(Abortable::new(
+        future::pending::<()>(),
+        request.abort_registration
+    ));
+
+    client
+        .send(ClientMessage::Cancel {
+            trace_context: request.request.context.trace_context,
+            request_id: 1,
+        })
+        .await
+        .expect("client transport failed");
+    // Reading from the channel processes the cancellation.
+    assert!(
+        futures::poll!(channel.next()).is_pending(),
+        "channel yielded a message after a cancellation"
+    );
+    assert!(
+        matches!(futures::poll!(handler.as_mut()), Poll::Ready(Err(_))),
+        "cancellation did not abort the request"
+    );
+    assert_eq!(
+        channel.in_flight_requests(),
+        0,
+        "canceled request is still counted as in flight"
+    );
+}
+
+/// Checks that the channel terminates once the client disconnects.
+pub async fn check_termination<C, F>(wrap: F)
+where
+    C: Channel<Req = u64, Resp = u64> + Transport<Response<u64>, TrackedRequest<u64>>,
+    F: FnOnce(InnerChannel) -> C,
+{
+    let (client, channel) = set_up(wrap);
+    let mut channel = pin!(channel);
+
+    drop(client);
+    assert!(
+        channel.next().await.is_none(),
+        "channel did not terminate after the client disconnected"
+    );
+}
+
+fn set_up<C, F>(wrap: F) -> (ClientTransport, C)
+where
+    F: FnOnce(InnerChannel) -> C,
+{
+    let (client, server) = channel::unbounded();
+    (client, wrap(BaseChannel::new(Config::default(), server)))
+}
+
+async fn send_request(client: &mut ClientTransport, id: u64, message: u64) {
+    client
+        .send(ClientMessage::Request(Request {
+            context: context::current(),
+            id,
+            message,
+            one_way: false,
+            streamed: false,
+        }))
+        .await
+        .expect("client transport failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_channel, InnerChannel};
+    use crate::{
+        server::{limits::requests_per_channel::MaxRequests, Channel, Config, TrackedRequest},
+        Response,
+    };
+    use futures::{prelude::*, task::*};
+    use pin_project::pin_project;
+    use std::pin::Pin;
+
+    type Error = <InnerChannel as Sink<Response<u64>>>::Error;
+
+    /// A broken channel that changes the messages of requests.
+    #[pin_project]
+    struct AlterRequests(#[pin] InnerChannel);
+
+    impl Stream for AlterRequests {
+        type Item = Result<TrackedRequest<u64>, Error>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.project().0.poll_next(cx).map_ok(|mut request| {
+                request.request.message += 1;
+                request
+            })
+        }
+    }
+
+    impl Sink<Response<u64>> for AlterRequests {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.project().0.poll_ready(cx)
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Response<u64>) -> Result<(), Error> {
+            self.project().0.start_send(item)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.project().0.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.project().0.poll_close(cx)
+        }
+    }
+
+    impl Channel for AlterRequests {
+        type Req = u64;
+        type Resp = u64;
+        type Transport = <InnerChannel as Channel>::Transport;
+
+        fn config(&self) -> &Config {
+            self.0.config()
+        }
+
+        fn in_flight_requests(&self) -> usize {
+            self.0.in_flight_requests()
+        }
+
+        fn transport(&self) -> &Self::Transport {
+            self.0.transport()
+        }
+    }
+
+    #[tokio::test]
+    async fn base_channel_conforms() {
+        check_channel(|channel| channel).await;
+    }
+
+    #[tokio::test]
+    async fn max_requests_conforms() {
+        check_channel(|channel| MaxRequests::new(channel, 10)).await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "request message changed")]
+    async fn detects_altered_requests() {
+        check_channel(AlterRequests).await;
+    }
+}
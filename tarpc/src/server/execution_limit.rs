@@ -0,0 +1,188 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides hard limits on how long request handlers run, independent of request deadlines.
+//!
+//! A request's deadline is chosen by the client, so a server that trusts deadlines alone lets a
+//! client pin its resources for as long as the client likes. [`ExecutionLimits`], applied with
+//! [`Serve::execution_limits`], caps how long each handler may run, with a default limit and
+//! limits for individual methods. A handler that runs past its limit is aborted, and the request
+//! fails with [`TimedOut`](io::ErrorKind::TimedOut). The handler's context deadline is moved up
+//! to its limit, so that the calls it makes to other services give up in time, too.
+//!
+//! A handler is aborted when it next yields to the executor, so a handler that computes without
+//! yielding still runs until it does; see [`time_slice`](super::time_slice).
+//!
+//! ```rust
+//! use futures::executor::block_on;
+//! use std::time::Duration;
+//! use tarpc::{context, server::{execution_limit::ExecutionLimits, serve, Serve}};
+//!
+//! let limits = ExecutionLimits::new(Some(Duration::from_secs(30)))
+//!     .with_method("Reports.generate", Duration::from_secs(300));
+//! let serve = serve(|_, x: i32| async move { Ok(x + 1) }).execution_limits(limits);
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! assert_eq!(serve.serve(context::current(), 1).await.unwrap(), 2);
+//! # });
+//! ```
+
+use super::Serve;
+use crate::util::tracing;
+use crate::{clock, context, ServerError};
+use fnv::FnvHashMap;
+use std::{io, sync::Arc, time::Duration};
+
+/// How long request handlers may run, by method.
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionLimits {
+    default: Option<Duration>,
+    methods: FnvHashMap<&'static str, Duration>,
+}
+
+impl ExecutionLimits {
+    /// Returns limits under which handlers of methods without a limit of their own run for at
+    /// most `default`, or without a limit if `None`.
+    pub fn new(default: Option<Duration>) -> Self {
+        Self {
+            default,
+            methods: FnvHashMap::default(),
+        }
+    }
+
+    /// Limits handlers of the method named `method`, as reported by [`Serve::method`], to
+    /// `limit`, instead of the default.
+    pub fn with_method(mut self, method: &'static str, limit: Duration) -> Self {
+        self.methods.insert(method, limit);
+        self
+    }
+
+    /// Returns how long a handler of `method` may run, if it is limited.
+    pub fn limit(&self, method: Option<&str>) -> Option<Duration> {
+        method
+            .and_then(|method| self.methods.get(method).copied())
+            .or(self.default)
+    }
+}
+
+/// A [`Serve`] whose request handlers are aborted once they run past their limits. Created by
+/// [`Serve::execution_limits`].
+#[derive(Clone, Debug)]
+pub struct ExecutionLimited<S> {
+    serve: S,
+    limits: Arc<ExecutionLimits>,
+}
+
+impl<S> ExecutionLimited<S> {
+    pub(crate) fn new(serve: S, limits: ExecutionLimits) -> Self {
+        Self {
+            serve,
+            limits: Arc::new(limits),
+        }
+    }
+
+    /// Returns the limits of the handlers.
+    pub fn limits(&self) -> &ExecutionLimits {
+        &self.limits
+    }
+}
+
+impl<S: Serve> Serve for ExecutionLimited<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    async fn serve(self, mut ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let method = self.serve.method(&req);
+        let limit = match self.limits.limit(method) {
+            Some(limit) => limit,
+            None => return self.serve.serve(ctx, req).await,
+        };
+        ctx.deadline = ctx.deadline.min(clock::now() + limit);
+        match tokio::time::timeout(limit, self.serve.serve(ctx, req)).await {
+            Ok(response) => response,
+            Err(_) => {
+                tracing::warn!(
+                    "ExecutionLimitExceeded: {} ran for {:?}",
+                    method.unwrap_or("handler"),
+                    limit
+                );
+                Err(ServerError::new(
+                    io::ErrorKind::TimedOut,
+                    format!("the request handler exceeded its execution limit of {limit:?}"),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExecutionLimits;
+    use crate::{context, server::Serve, ServerError};
+    use assert_matches::assert_matches;
+    use std::{
+        io,
+        time::{Duration, SystemTime},
+    };
+
+    /// A service whose requests name the method and how long the handler takes, in seconds.
+    #[derive(Clone)]
+    struct Sleepy;
+
+    impl Serve for Sleepy {
+        type Req = (&'static str, u64);
+        type Resp = SystemTime;
+
+        fn method(&self, (method, _): &Self::Req) -> Option<&'static str> {
+            Some(method)
+        }
+
+        async fn serve(
+            self,
+            ctx: context::Context,
+            (_, secs): Self::Req,
+        ) -> Result<SystemTime, ServerError> {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            Ok(ctx.deadline)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborts_handlers_past_their_limit() {
+        let serve = Sleepy.execution_limits(
+            ExecutionLimits::new(Some(Duration::from_secs(5)))
+                .with_method("slow", Duration::from_secs(60)),
+        );
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(3600);
+
+        let start = tokio::time::Instant::now();
+        assert_matches!(
+            serve.clone().serve(ctx, ("fast", 3600)).await,
+            Err(ServerError {
+                kind: io::ErrorKind::TimedOut,
+                ..
+            })
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        let deadline = serve.clone().serve(ctx, ("slow", 30)).await.unwrap();
+        assert!(deadline < ctx.deadline);
+        assert_matches!(serve.serve(ctx, ("slow", 61)).await, Err(_));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_handlers_keep_their_deadline() {
+        let serve = Sleepy.execution_limits(
+            ExecutionLimits::new(None).with_method("slow", Duration::from_secs(60)),
+        );
+        let ctx = context::current();
+        assert_eq!(serve.serve(ctx, ("fast", 100)).await.unwrap(), ctx.deadline);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a service that forwards requests to another server.
+//!
+//! [`Forward`] re-issues each request it serves through a client [`Stub`], such as a
+//! [`Channel`](crate::client::Channel) connected to an upstream server. Executed by a
+//! [`Channel`](super::Channel), it keeps the forwarded request linked to the inbound one:
+//!
+//! - the upstream request carries the inbound context, so it has the same deadline and continues
+//!   the same trace;
+//! - if the downstream client cancels the inbound request, or its deadline passes, the channel
+//!   aborts the forwarding future, which cancels the upstream request;
+//! - the upstream client assigns its own request ID. Both IDs are recorded as `rpc.request_id`,
+//!   on the inbound request's span and on the upstream call's span nested inside it.
+
+use super::Serve;
+use crate::{
+    client::{stub::Stub, RpcError},
+    context, ServerError,
+};
+use std::io;
+
+/// A [`Serve`] that forwards requests to another server through a [`Stub`].
+#[derive(Clone, Debug)]
+pub struct Forward<S: Stub> {
+    stub: S,
+    method: fn(&S::Req) -> Option<&'static str>,
+}
+
+impl<S: Stub> Forward<S> {
+    /// Returns a service that forwards all requests to `stub`. Upstream calls are named
+    /// `"Forward"`, unless [`with_method`](Forward::with_method) tells how to name them.
+    pub fn new(stub: S) -> Self {
+        Self {
+            stub,
+            method: |_| None,
+        }
+    }
+
+    /// Sets the function that extracts a method name from a request, which names both the
+    /// inbound request's span and the upstream call. For services generated by
+    /// [`service`](crate::service), pass a function that calls the generated server's
+    /// [`method`](Serve::method).
+    pub fn with_method(mut self, method: fn(&S::Req) -> Option<&'static str>) -> Self {
+        self.method = method;
+        self
+    }
+}
+
+impl<S: Stub> Serve for Forward<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        (self.method)(request)
+    }
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let request_name = (self.method)(&req).unwrap_or("Forward");
+        self.stub
+            .call(ctx, request_name, req)
+            .await
+            .map_err(upstream_error)
+    }
+}
+
+/// Converts the error of an upstream call into the error returned to the downstream client.
+/// Errors returned by the upstream server pass through unchanged.
+pub(crate) fn upstream_error(e: RpcError) -> ServerError {
+    match e {
+        RpcError::Server(e) => e,
+        RpcError::DeadlineExceeded => ServerError::new(io::ErrorKind::TimedOut, e.to_string()),
+        e => ServerError::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Forward;
+    use crate::{
+        client::{self, RpcError},
+        context,
+        server::{BaseChannel, Channel},
+        transport::{self, channel::UnboundedChannel},
+        ClientMessage, Response, ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::{future::Either, prelude::*};
+    use std::{io, time::Duration};
+
+    type Upstream = UnboundedChannel<ClientMessage<String>, Response<String>>;
+
+    /// Returns a client of a proxy that forwards to the returned upstream transport.
+    fn set_up(
+        method: fn(&String) -> Option<&'static str>,
+    ) -> (client::Channel<String, String>, Upstream) {
+        let (upstream_client, upstream) = transport::channel::unbounded();
+        let client::NewClient { client, dispatch } =
+            client::new(client::Config::default(), upstream_client);
+        tokio::spawn(dispatch);
+        let forward = Forward::new(client).with_method(method);
+
+        let (proxy_client, proxy) = transport::channel::unbounded();
+        tokio::spawn(BaseChannel::with_defaults(proxy).execute(forward).for_each(
+            |response| async move {
+                tokio::spawn(response);
+            },
+        ));
+        let client::NewClient { client, dispatch } =
+            client::new(client::Config::default(), proxy_client);
+        tokio::spawn(dispatch);
+        (client, upstream)
+    }
+
+    #[tokio::test]
+    async fn forwards_request_with_context() {
+        let (client, mut upstream) = set_up(|_| Some("Echo"));
+        let mut ctx = context::current();
+        ctx.deadline += Duration::from_secs(50);
+        let deadline = ctx.deadline;
+
+        let upstream = async move {
+            let request = match upstream.next().await {
+                Some(Ok(ClientMessage::Request(request))) => request,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            upstream
+                .send(Response {
+                    request_id: request.id,
+                    message: Err(ServerError::new(
+                        io::ErrorKind::NotFound,
+                        request.message.clone(),
+                    )),
+                    more: false,
+                })
+                .await
+                .unwrap();
+            request
+        };
+        let (response, request) =
+            future::join(client.call(ctx, "Echo", "hi".into()), upstream).await;
+
+        assert_eq!(request.message, "hi");
+        assert_eq!(request.context.trace_id(), ctx.trace_id());
+        let skew = request
+            .context
+            .deadline
+            .duration_since(deadline)
+            .unwrap_or_else(|e| e.duration());
+        assert!(skew < Duration::from_secs(1));
+        assert_matches!(
+            response,
+            Err(RpcError::Server(ServerError { kind: io::ErrorKind::NotFound, detail, .. }))
+                if detail == "hi"
+        );
+    }
+
+    #[tokio::test]
+    async fn downstream_cancellation_cancels_upstream_request() {
+        let (client, mut upstream) = set_up(|_| None);
+
+        let call = Box::pin(client.call(context::current(), "", "hi".into()));
+        let (request, call) = match future::select(call, upstream.next()).await {
+            Either::Right((Some(Ok(ClientMessage::Request(request))), call)) => (request, call),
+            Either::Right((other, _)) => panic!("unexpected message: {other:?}"),
+            Either::Left(_) => panic!("call completed before being answered"),
+        };
+        drop(call);
+
+        assert_matches!(
+            upstream.next().await,
+            Some(Ok(ClientMessage::Cancel { request_id, .. })) if request_id == request.id
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::util::tracing;
+use crate::util::tracing::Span;
 use crate::util::{Compact, TimeUntil};
 use fnv::FnvHashMap;
 use futures::future::{AbortHandle, AbortRegistration};
@@ -7,7 +9,6 @@ use std::{
     time::SystemTime,
 };
 use tokio_util::time::delay_queue::{self, DelayQueue};
-use tracing::Span;
 
 /// A data structure that tracks in-flight requests. It aborts requests,
 /// either on demand or when a request deadline expires.
@@ -15,6 +16,8 @@ use tracing::Span;
 pub struct InFlightRequests {
     request_data: FnvHashMap<u64, RequestData>,
     deadlines: DelayQueue<u64>,
+    /// The sum of the buffered lengths of all in-flight requests.
+    buffered_len: usize,
 }
 
 /// Data needed to clean up a single in-flight request.
@@ -24,8 +27,14 @@ struct RequestData {
     abort_handle: AbortHandle,
     /// The key to remove the timer for the request's deadline.
     deadline_key: delay_queue::Key,
+    /// The time at which the client stops waiting for a response.
+    deadline: SystemTime,
     /// The client span.
     span: Span,
+    /// The approximate number of bytes held by the request.
+    buffered_len: usize,
+    /// Whether the client expects no response.
+    one_way: bool,
 }
 
 /// An error returned when a request attempted to start with the same ID as a request already
@@ -39,12 +48,46 @@ impl InFlightRequests {
         self.request_data.len()
     }
 
-    /// Starts a request, unless a request with the same ID is already in flight.
+    /// Returns the approximate number of bytes held by in-flight requests.
+    pub fn buffered_len(&self) -> usize {
+        self.buffered_len
+    }
+
+    /// Returns true iff a request with the ID is in flight.
+    pub fn contains(&self, request_id: u64) -> bool {
+        self.request_data.contains_key(&request_id)
+    }
+
+    /// Returns the deadline of an in-flight request.
+    pub fn deadline(&self, request_id: u64) -> Option<SystemTime> {
+        self.request_data
+            .get(&request_id)
+            .map(|request_data| request_data.deadline)
+    }
+
+    /// Returns true iff the in-flight request was sent [one-way](crate::Request::one_way).
+    pub fn is_one_way(&self, request_id: u64) -> bool {
+        self.request_data
+            .get(&request_id)
+            .map_or(false, |request_data| request_data.one_way)
+    }
+
+    /// Returns the span of an in-flight request.
+    pub fn span(&self, request_id: u64) -> Option<Span> {
+        self.request_data
+            .get(&request_id)
+            .map(|request_data| request_data.span.clone())
+    }
+
+    /// Starts a request, unless a request with the same ID is already in flight. The request is
+    /// counted as holding `buffered_len` bytes until it is no longer in flight.
     pub fn start_request(
         &mut self,
         request_id: u64,
         deadline: SystemTime,
         span: Span,
+        buffered_len: usize,
+        one_way: bool,
     ) -> Result<AbortRegistration, AlreadyExistsError> {
         match self.request_data.entry(request_id) {
             hash_map::Entry::Vacant(vacant) => {
@@ -54,8 +97,12 @@ impl InFlightRequests {
                 vacant.insert(RequestData {
                     abort_handle,
                     deadline_key,
+                    deadline,
                     span,
+                    buffered_len,
+                    one_way,
                 });
+                self.buffered_len += buffered_len;
                 Ok(abort_registration)
             }
             hash_map::Entry::Occupied(_) => Err(AlreadyExistsError),
@@ -64,16 +111,8 @@ impl InFlightRequests {
 
     /// Cancels an in-flight request. Returns true iff the request was found.
     pub fn cancel_request(&mut self, request_id: u64) -> bool {
-        if let Some(RequestData {
-            span,
-            abort_handle,
-            deadline_key,
-        }) = self.request_data.remove(&request_id)
-        {
+        if let Some(span) = self.abort_request(request_id) {
             let _entered = span.enter();
-            self.request_data.compact(0.1);
-            abort_handle.abort();
-            self.deadlines.remove(&deadline_key);
             tracing::info!("ReceiveCancel");
             true
         } else {
@@ -81,10 +120,29 @@ impl InFlightRequests {
         }
     }
 
+    /// Aborts an in-flight request that the server gives up on, e.g. because the client streamed
+    /// it more items than it buffers. Returns the request's span iff the request was found, in
+    /// which case the caller should send the client an error response.
+    pub fn abort_request(&mut self, request_id: u64) -> Option<Span> {
+        let RequestData {
+            span,
+            abort_handle,
+            deadline_key,
+            buffered_len,
+            ..
+        } = self.request_data.remove(&request_id)?;
+        self.buffered_len -= buffered_len;
+        self.request_data.compact(0.1);
+        abort_handle.abort();
+        self.deadlines.remove(&deadline_key);
+        Some(span)
+    }
+
     /// Removes a request without aborting. Returns true iff the request was found.
     /// This method should be used when a response is being sent.
     pub fn remove_request(&mut self, request_id: u64) -> Option<Span> {
         if let Some(request_data) = self.request_data.remove(&request_id) {
+            self.buffered_len -= request_data.buffered_len;
             self.request_data.compact(0.1);
             self.deadlines.remove(&request_data.deadline_key);
             Some(request_data.span)
@@ -93,6 +151,17 @@ impl InFlightRequests {
         }
     }
 
+    /// Aborts all in-flight requests, e.g. because the channel is closing.
+    pub fn abort_all(&mut self) {
+        self.deadlines.clear();
+        self.buffered_len = 0;
+        for (_, request_data) in self.request_data.drain() {
+            let _entered = request_data.span.enter();
+            request_data.abort_handle.abort();
+            tracing::info!("AbortRequest");
+        }
+    }
+
     /// Yields a request that has expired, aborting any ongoing processing of that request.
     pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<Option<u64>> {
         if self.deadlines.is_empty() {
@@ -103,10 +172,14 @@ impl InFlightRequests {
         self.deadlines.poll_expired(cx).map(|expired| {
             let expired = expired?;
             if let Some(RequestData {
-                abort_handle, span, ..
+                abort_handle,
+                span,
+                buffered_len,
+                ..
             }) = self.request_data.remove(expired.get_ref())
             {
                 let _entered = span.enter();
+                self.buffered_len -= buffered_len;
                 self.request_data.compact(0.1);
                 abort_handle.abort();
                 tracing::error!("DeadlineExceeded");
@@ -141,16 +214,34 @@ mod tests {
         let mut in_flight_requests = InFlightRequests::default();
         assert_eq!(in_flight_requests.len(), 0);
         in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(0, SystemTime::now(), Span::current(), 0, false)
             .unwrap();
         assert_eq!(in_flight_requests.len(), 1);
     }
 
+    #[tokio::test]
+    async fn buffered_len_is_released() {
+        let mut in_flight_requests = InFlightRequests::default();
+        let deadline = SystemTime::now() + std::time::Duration::from_secs(10);
+        in_flight_requests
+            .start_request(0, deadline, Span::current(), 3, false)
+            .unwrap();
+        in_flight_requests
+            .start_request(1, deadline, Span::current(), 5, false)
+            .unwrap();
+        assert_eq!(in_flight_requests.buffered_len(), 8);
+
+        assert!(in_flight_requests.cancel_request(0));
+        assert_eq!(in_flight_requests.buffered_len(), 5);
+        assert_matches!(in_flight_requests.remove_request(1), Some(_));
+        assert_eq!(in_flight_requests.buffered_len(), 0);
+    }
+
     #[tokio::test]
     async fn polling_expired_aborts() {
         let mut in_flight_requests = InFlightRequests::default();
         let abort_registration = in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(0, SystemTime::now(), Span::current(), 0, false)
             .unwrap();
         let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
 
@@ -172,7 +263,7 @@ mod tests {
     async fn cancel_request_aborts() {
         let mut in_flight_requests = InFlightRequests::default();
         let abort_registration = in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(0, SystemTime::now(), Span::current(), 0, false)
             .unwrap();
         let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
 
@@ -184,6 +275,24 @@ mod tests {
         assert_eq!(in_flight_requests.len(), 0);
     }
 
+    #[tokio::test]
+    async fn abort_request_aborts_and_returns_span() {
+        let mut in_flight_requests = InFlightRequests::default();
+        let abort_registration = in_flight_requests
+            .start_request(0, SystemTime::now(), Span::current(), 4, false)
+            .unwrap();
+        let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
+
+        assert_matches!(in_flight_requests.abort_request(0), Some(_));
+        assert_matches!(
+            abortable_future.poll_unpin(&mut noop_context()),
+            Poll::Ready(Err(_))
+        );
+        assert_eq!(in_flight_requests.len(), 0);
+        assert_eq!(in_flight_requests.buffered_len(), 0);
+        assert_matches!(in_flight_requests.abort_request(0), None);
+    }
+
     #[tokio::test]
     async fn remove_request_doesnt_abort() {
         let mut in_flight_requests = InFlightRequests::default();
@@ -194,6 +303,8 @@ mod tests {
                 0,
                 SystemTime::now() + std::time::Duration::from_secs(10),
                 Span::current(),
+                0,
+                false,
             )
             .unwrap();
         let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
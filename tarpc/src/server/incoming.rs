@@ -1,7 +1,10 @@
 use super::{
     limits::{channels_per_key::MaxChannelsPerKey, requests_per_channel::MaxRequestsPerChannel},
-    Channel, Serve,
+    shutdown::{Shutdown, WithShutdown},
+    Channel, ExecuteRequest, Serve,
 };
+#[cfg(feature = "tokio1")]
+use crate::util;
 use futures::prelude::*;
 use std::{fmt, hash::Hash};
 
@@ -25,14 +28,22 @@ where
         MaxRequestsPerChannel::new(self, n)
     }
 
+    /// Stops accepting channels once `shutdown` starts, and drains the channels accepted so far.
+    /// See [`shutdown`](super::shutdown).
+    fn with_shutdown(self, shutdown: Shutdown) -> WithShutdown<Self> {
+        WithShutdown::new(self, shutdown)
+    }
+
     /// Returns a stream of channels in execution. Each channel in execution is a stream of
     /// futures, where each future is an in-flight request being rsponded to.
     fn execute<S>(
         self,
         serve: S,
-    ) -> impl Stream<Item = impl Stream<Item = impl Future<Output = ()>>>
+    ) -> impl Stream<Item = impl Stream<Item = ExecuteRequest<impl Future<Output = ()>>>>
     where
         S: Serve<Req = C::Req, Resp = C::Resp> + Clone,
+        C::Req: Send + 'static,
+        C::Resp: Send + 'static,
     {
         self.map(move |channel| channel.execute(serve.clone()))
     }
@@ -41,8 +52,10 @@ where
 #[cfg(feature = "tokio1")]
 /// Spawns all channels-in-execution, delegating to the tokio runtime to manage their completion.
 /// Each channel is spawned, and each request from each channel is spawned.
-/// Note that this function is generic over any stream-of-streams-of-futures, but it is intended
-/// for spawning streams of channels.
+///
+/// With the `tokio-console` feature, the tasks are named so that tokio-console can tell them apart:
+/// the `n`th channel spawned is `tarpc::server::channel n`, and a request is
+/// `tarpc::server::request <id> <trace id>`, after the id and trace id it was sent with.
 ///
 /// # Example
 /// ```rust
@@ -69,18 +82,32 @@ where
 /// ```
 pub async fn spawn_incoming(
     incoming: impl Stream<
-        Item = impl Stream<Item = impl Future<Output = ()> + Send + 'static> + Send + 'static,
+        Item = impl Stream<Item = ExecuteRequest<impl Future<Output = ()> + Send + 'static>>
+                   + Send
+                   + 'static,
     >,
 ) {
     use futures::pin_mut;
     pin_mut!(incoming);
+    let mut channels = 0u64..;
     while let Some(channel) = incoming.next().await {
-        tokio::spawn(async move {
-            pin_mut!(channel);
-            while let Some(request) = channel.next().await {
-                tokio::spawn(request);
-            }
-        });
+        let channel_seq = channels.next().unwrap();
+        util::spawn(
+            format_args!("tarpc::server::channel {channel_seq}"),
+            async move {
+                pin_mut!(channel);
+                while let Some(request) = channel.next().await {
+                    util::spawn(
+                        format_args!(
+                            "tarpc::server::request {} {}",
+                            request.request_id(),
+                            request.trace_id()
+                        ),
+                        request,
+                    );
+                }
+            },
+        );
     }
 }
 
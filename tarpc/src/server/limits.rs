@@ -1,5 +1,14 @@
 /// Provides functionality to limit the number of active channels.
 pub mod channels_per_key;
 
+/// Provides a limit on the requests in flight across many [channels](crate::server::Channel).
+pub mod concurrency;
+
+/// Provides a byte budget shared by many [channels](crate::server::Channel).
+pub mod memory_pool;
+
 /// Provides a [channel](crate::server::Channel) that limits the number of in-flight requests.
 pub mod requests_per_channel;
+
+/// Provides request-rate and in-flight quotas enforced per tenant across channels.
+pub mod tenant_quota;
@@ -4,6 +4,7 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use crate::util::tracing::{debug, info, trace};
 use crate::{
     server::{self, Channel},
     util::Compact,
@@ -16,7 +17,6 @@ use std::{
     collections::hash_map::Entry, convert::TryFrom, fmt, hash::Hash, marker::Unpin, pin::Pin,
 };
 use tokio::sync::mpsc;
-use tracing::{debug, info, trace};
 
 /// An [`Incoming`](crate::server::incoming::Incoming) stream that drops new channels based on
 /// per-key limits.
@@ -119,6 +119,10 @@ where
     fn transport(&self) -> &Self::Transport {
         self.inner.transport()
     }
+
+    fn response_len(&self, response: &Self::Resp) -> usize {
+        self.inner.response_len(response)
+    }
 }
 
 impl<C, K> TrackedChannel<C, K> {
@@ -336,7 +340,7 @@ fn tracked_channel_sink() {
     assert_matches!(channel.as_mut().poll_ready(&mut ctx()), Poll::Ready(Ok(())));
     assert_matches!(channel.as_mut().start_send("test"), Ok(()));
     assert_matches!(channel.as_mut().poll_flush(&mut ctx()), Poll::Ready(Ok(())));
-    assert_matches!(chan_rx.try_next(), Ok(Some("test")));
+    assert_matches!(chan_rx.try_recv(), Ok("test"));
 }
 
 #[test]
@@ -0,0 +1,136 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A limit on the number of requests in flight across all the channels configured with it.
+///
+/// Each [`BaseChannel`](crate::server::BaseChannel) whose [`Config`](crate::server::Config) holds
+/// the limit takes a permit for each request it starts, and returns it once the request is no
+/// longer in flight. A request that arrives while no permits are left is answered right away with
+/// a [resource exhausted](crate::ServerError::resource_exhausted) error, without being started.
+///
+/// The channels of an [`Incoming`](crate::server::incoming::Incoming) stream are typically
+/// configured alike, so a limit in their config caps the requests of the whole server, whereas
+/// [`Channel::max_concurrent_requests`](crate::server::Channel::max_concurrent_requests) caps those
+/// of each connection. Together, they keep neither one noisy connection nor many connections from
+/// exhausting the server.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit {
+    state: Arc<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    max: usize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+    /// Returns a new limit of `max` requests in flight.
+    pub fn new(max: usize) -> Self {
+        Self {
+            state: Arc::new(State {
+                max,
+                in_flight: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the maximum number of requests in flight.
+    pub fn max(&self) -> usize {
+        self.state.max
+    }
+
+    /// Returns the number of requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Registers a new channel taking permits from the limit.
+    pub(crate) fn register(&self) -> Permits {
+        Permits {
+            limit: self.clone(),
+            held: 0,
+        }
+    }
+}
+
+/// The permits taken from a [`ConcurrencyLimit`] by a single channel.
+#[derive(Debug)]
+pub(crate) struct Permits {
+    limit: ConcurrencyLimit,
+    held: usize,
+}
+
+impl Permits {
+    /// Returns the maximum number of requests in flight.
+    pub fn max(&self) -> usize {
+        self.limit.max()
+    }
+
+    /// Takes a permit for a new request, returning false if none are left.
+    pub fn try_acquire(&mut self) -> bool {
+        let max = self.limit.state.max;
+        let acquired = self
+            .limit
+            .state
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < max).then(|| in_flight + 1)
+            })
+            .is_ok();
+        if acquired {
+            self.held += 1;
+        }
+        acquired
+    }
+
+    /// Returns the permits of requests that are no longer in flight, given that the channel now
+    /// has `in_flight` requests.
+    pub fn release_to(&mut self, in_flight: usize) {
+        if in_flight < self.held {
+            self.limit
+                .state
+                .in_flight
+                .fetch_sub(self.held - in_flight, Ordering::AcqRel);
+            self.held = in_flight;
+        }
+    }
+}
+
+impl Drop for Permits {
+    fn drop(&mut self) {
+        self.release_to(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConcurrencyLimit;
+
+    #[test]
+    fn channels_share_permits() {
+        let limit = ConcurrencyLimit::new(2);
+        let mut a = limit.register();
+        let mut b = limit.register();
+
+        assert!(a.try_acquire());
+        assert!(b.try_acquire());
+        assert!(!a.try_acquire());
+        assert_eq!(limit.in_flight(), 2);
+
+        b.release_to(0);
+        assert!(a.try_acquire());
+        assert_eq!(limit.in_flight(), 2);
+
+        drop(a);
+        assert_eq!(limit.in_flight(), 0);
+    }
+}
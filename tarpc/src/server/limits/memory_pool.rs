@@ -0,0 +1,169 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Waker},
+};
+
+/// A byte budget shared by all the channels configured with it.
+///
+/// Each [`BaseChannel`](crate::server::BaseChannel) whose [`Config`](crate::server::Config) holds
+/// the pool counts its buffered bytes against the pool's capacity. While the pool is exhausted,
+/// channels holding at least their fair share of the capacity, i.e. the capacity divided by the
+/// number of channels drawing from the pool, stop accepting requests as configured by
+/// [`BufferLimitPolicy`](crate::server::BufferLimitPolicy). Channels holding less than their fair
+/// share are not limited, so a few busy channels cannot starve the others. As the busy channels
+/// complete requests, the memory they release is reclaimed by the pool.
+///
+/// Because requests are admitted before their size is counted, the pool can be exceeded by about
+/// one request per channel.
+#[derive(Clone, Debug)]
+pub struct MemoryPool {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    capacity: usize,
+    used: usize,
+    channels: usize,
+    /// Channels waiting for memory to be released.
+    waiters: Vec<Waker>,
+}
+
+impl MemoryPool {
+    /// Returns a new pool of `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                capacity,
+                used: 0,
+                channels: 0,
+                waiters: vec![],
+            })),
+        }
+    }
+
+    /// Returns the number of bytes in the pool.
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Returns the number of bytes currently drawn from the pool.
+    pub fn used(&self) -> usize {
+        self.lock().used
+    }
+
+    /// Registers a new channel drawing from the pool.
+    pub(crate) fn register(&self) -> Reservation {
+        self.lock().channels += 1;
+        Reservation {
+            pool: self.clone(),
+            len: 0,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is left consistent even if a lock holder panics.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn wake_waiters(&mut self) {
+        self.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// The bytes drawn from a [`MemoryPool`] by a single channel.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    pool: MemoryPool,
+    len: usize,
+}
+
+impl Reservation {
+    /// Records that the channel now holds `len` bytes.
+    pub fn set_len(&mut self, len: usize) {
+        if len == self.len {
+            return;
+        }
+        let mut state = self.pool.lock();
+        state.used = state.used - self.len + len;
+        if len < self.len {
+            state.wake_waiters();
+        }
+        self.len = len;
+    }
+
+    /// Returns true iff the channel should stop accepting requests. If so, the current task is
+    /// woken when memory is released to the pool.
+    pub fn is_over_budget(&self, cx: &mut Context<'_>) -> bool {
+        let mut state = self.pool.lock();
+        let fair_share = state.capacity / state.channels.max(1);
+        if state.used < state.capacity || self.len < fair_share {
+            return false;
+        }
+        if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiters.push(cx.waker().clone());
+        }
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.pool.lock();
+        state.used -= self.len;
+        state.channels -= 1;
+        // Releasing memory and raising the fair share of the remaining channels can both unblock
+        // waiters.
+        state.wake_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryPool;
+    use futures_test::task::{new_count_waker, noop_context};
+    use std::task::Context;
+
+    #[test]
+    fn channels_over_fair_share_are_limited() {
+        let pool = MemoryPool::new(100);
+        let mut busy = pool.register();
+        let mut quiet = pool.register();
+
+        busy.set_len(90);
+        assert!(!busy.is_over_budget(&mut noop_context()));
+
+        quiet.set_len(10);
+        assert_eq!(pool.used(), 100);
+        assert!(busy.is_over_budget(&mut noop_context()));
+        assert!(!quiet.is_over_budget(&mut noop_context()));
+
+        drop(quiet);
+        assert_eq!(pool.used(), 90);
+        assert!(!busy.is_over_budget(&mut noop_context()));
+    }
+
+    #[test]
+    fn releasing_memory_wakes_waiters() {
+        let pool = MemoryPool::new(10);
+        let mut busy = pool.register();
+        let mut other = pool.register();
+        busy.set_len(10);
+
+        let (waker, count) = new_count_waker();
+        assert!(busy.is_over_budget(&mut Context::from_waker(&waker)));
+        other.set_len(1);
+        assert_eq!(count, 0);
+
+        other.set_len(0);
+        assert_eq!(count, 1);
+    }
+}
@@ -4,16 +4,42 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use crate::util::tracing;
 use crate::{
     server::{Channel, Config},
     Response, ServerError,
 };
 use futures::{prelude::*, ready, task::*};
 use pin_project::pin_project;
-use std::{io, pin::Pin};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Counts the requests rejected for exceeding an in-flight limit. Clones share the count.
+#[derive(Clone, Debug, Default)]
+pub struct ThrottledCount(Arc<AtomicU64>);
+
+impl ThrottledCount {
+    /// Returns the number of requests rejected so far.
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
 /// A [`Channel`] that limits the number of concurrent requests by throttling.
 ///
+/// A request that arrives while the channel is at its limit is answered right away with a
+/// [resource exhausted](ServerError::resource_exhausted) error, and counted in
+/// [`MaxRequests::throttled`]; the connection stays open for further requests.
+///
 /// Note that this is a very basic throttling heuristic. It is easy to set a number that is too low
 /// for the resources available to the server. For production use cases, a more advanced throttler
 /// is likely needed.
@@ -21,6 +47,7 @@ use std::{io, pin::Pin};
 #[derive(Debug)]
 pub struct MaxRequests<C> {
     max_in_flight_requests: usize,
+    throttled: ThrottledCount,
     #[pin]
     inner: C,
 }
@@ -30,6 +57,11 @@ impl<C> MaxRequests<C> {
     pub fn get_ref(&self) -> &C {
         &self.inner
     }
+
+    /// Returns a handle to the count of requests the channel rejected for exceeding its limit.
+    pub fn throttled(&self) -> ThrottledCount {
+        self.throttled.clone()
+    }
 }
 
 impl<C> MaxRequests<C>
@@ -39,8 +71,13 @@ where
     /// Returns a new `MaxRequests` that wraps the given channel and limits concurrent requests to
     /// `max_in_flight_requests`.
     pub fn new(inner: C, max_in_flight_requests: usize) -> Self {
+        Self::with_throttled(inner, max_in_flight_requests, ThrottledCount::default())
+    }
+
+    fn with_throttled(inner: C, max_in_flight_requests: usize, throttled: ThrottledCount) -> Self {
         MaxRequests {
             max_in_flight_requests,
+            throttled,
             inner,
         }
     }
@@ -60,17 +97,21 @@ where
             match ready!(self.as_mut().project().inner.poll_next(cx)?) {
                 Some(r) => {
                     let _entered = r.span.enter();
-                    tracing::info!(
-                        in_flight_requests = self.as_mut().in_flight_requests(),
-                        "ThrottleRequest",
-                    );
+                    let in_flight_requests = self.as_mut().in_flight_requests();
+                    tracing::info!(in_flight_requests, "ThrottleRequest");
+                    self.throttled.increment();
+                    if r.request.one_way {
+                        // The client isn't waiting for an error response.
+                        continue;
+                    }
 
                     self.as_mut().start_send(Response {
                         request_id: r.request.id,
-                        message: Err(ServerError {
-                            kind: io::ErrorKind::WouldBlock,
-                            detail: "server throttled the request.".into(),
-                        }),
+                        message: Err(ServerError::resource_exhausted(format!(
+                            "server throttled the request: {in_flight_requests} requests are \
+                             in flight on the connection, the most it allows"
+                        ))),
+                        more: false,
                     })?;
                 }
                 None => return Poll::Ready(None),
@@ -131,6 +172,10 @@ where
     fn transport(&self) -> &Self::Transport {
         self.inner.transport()
     }
+
+    fn response_len(&self, response: &Self::Resp) -> usize {
+        self.inner.response_len(response)
+    }
 }
 
 /// An [`Incoming`](crate::server::incoming::Incoming) stream of channels that enforce limits on
@@ -141,6 +186,7 @@ pub struct MaxRequestsPerChannel<S> {
     #[pin]
     inner: S,
     max_in_flight_requests: usize,
+    throttled: ThrottledCount,
 }
 
 impl<S> MaxRequestsPerChannel<S>
@@ -152,8 +198,15 @@ where
         Self {
             inner,
             max_in_flight_requests,
+            throttled: ThrottledCount::default(),
         }
     }
+
+    /// Returns a handle to the count of requests rejected by all of the channels, including
+    /// those yet to be accepted.
+    pub fn throttled(&self) -> ThrottledCount {
+        self.throttled.clone()
+    }
 }
 
 impl<S> Stream for MaxRequestsPerChannel<S>
@@ -165,9 +218,10 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         match ready!(self.as_mut().project().inner.poll_next(cx)) {
-            Some(channel) => Poll::Ready(Some(MaxRequests::new(
+            Some(channel) => Poll::Ready(Some(MaxRequests::with_throttled(
                 channel,
-                *self.project().max_in_flight_requests,
+                self.max_in_flight_requests,
+                self.throttled.clone(),
             ))),
             None => Poll::Ready(None),
         }
@@ -182,17 +236,20 @@ mod tests {
         testing::{self, FakeChannel, PollExt},
         TrackedRequest,
     };
+    use crate::util::tracing::Span;
+    use assert_matches::assert_matches;
     use pin_utils::pin_mut;
     use std::{
+        io,
         marker::PhantomData,
         time::{Duration, SystemTime},
     };
-    use tracing::Span;
 
     #[tokio::test]
     async fn throttler_in_flight_requests() {
         let throttler = MaxRequests {
             max_in_flight_requests: 0,
+            throttled: ThrottledCount::default(),
             inner: FakeChannel::default::<isize, isize>(),
         };
 
@@ -205,6 +262,8 @@ mod tests {
                     i,
                     SystemTime::now() + Duration::from_secs(1),
                     Span::current(),
+                    0,
+                    false,
                 )
                 .unwrap();
         }
@@ -215,6 +274,7 @@ mod tests {
     fn throttler_poll_next_done() {
         let throttler = MaxRequests {
             max_in_flight_requests: 0,
+            throttled: ThrottledCount::default(),
             inner: FakeChannel::default::<isize, isize>(),
         };
 
@@ -226,6 +286,7 @@ mod tests {
     fn throttler_poll_next_some() -> io::Result<()> {
         let throttler = MaxRequests {
             max_in_flight_requests: 1,
+            throttled: ThrottledCount::default(),
             inner: FakeChannel::default::<isize, isize>(),
         };
 
@@ -246,6 +307,7 @@ mod tests {
     fn throttler_poll_next_throttled() {
         let throttler = MaxRequests {
             max_in_flight_requests: 0,
+            throttled: ThrottledCount::default(),
             inner: FakeChannel::default::<isize, isize>(),
         };
 
@@ -253,15 +315,32 @@ mod tests {
         throttler.inner.push_req(1, 1);
         assert!(throttler.as_mut().poll_next(&mut testing::cx()).is_done());
         assert_eq!(throttler.inner.sink.len(), 1);
-        let resp = throttler.inner.sink.get(0).unwrap();
+        let resp = throttler.inner.sink.front().unwrap();
         assert_eq!(resp.request_id, 1);
-        assert!(resp.message.is_err());
+        assert_matches!(&resp.message, Err(e) if e.is_resource_exhausted());
+        assert_eq!(throttler.throttled().count(), 1);
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn resource_exhausted_marker_survives_serialization() {
+        let e = ServerError::resource_exhausted("busy".into());
+        let e: ServerError = serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap();
+        assert!(e.is_resource_exhausted());
+
+        // Other errors of the same kind, including those of peers that predate the marker, are
+        // not marked.
+        let e = ServerError::new(io::ErrorKind::WouldBlock, "busy".into());
+        assert!(!e.is_resource_exhausted());
+        let e: ServerError = serde_json::from_str(r#"{"kind":10,"detail":"busy"}"#).unwrap();
+        assert!(!e.is_resource_exhausted());
     }
 
     #[test]
     fn throttler_poll_next_throttled_sink_not_ready() {
         let throttler = MaxRequests {
             max_in_flight_requests: 0,
+            throttled: ThrottledCount::default(),
             inner: PendingSink::default::<isize, isize>(),
         };
         pin_mut!(throttler);
@@ -317,6 +396,7 @@ mod tests {
     async fn throttler_start_send() {
         let throttler = MaxRequests {
             max_in_flight_requests: 0,
+            throttled: ThrottledCount::default(),
             inner: FakeChannel::default::<isize, isize>(),
         };
 
@@ -328,6 +408,8 @@ mod tests {
                 0,
                 SystemTime::now() + Duration::from_secs(1),
                 Span::current(),
+                0,
+                false,
             )
             .unwrap();
         throttler
@@ -335,14 +417,16 @@ mod tests {
             .start_send(Response {
                 request_id: 0,
                 message: Ok(1),
+                more: false,
             })
             .unwrap();
         assert_eq!(throttler.inner.in_flight_requests.len(), 0);
         assert_eq!(
-            throttler.inner.sink.get(0),
+            throttler.inner.sink.front(),
             Some(&Response {
                 request_id: 0,
                 message: Ok(1),
+                more: false,
             })
         );
     }
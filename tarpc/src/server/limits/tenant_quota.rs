@@ -0,0 +1,455 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::util::tracing;
+use crate::{clock, context, server::Serve, ServerError};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+/// The limits applied to each tenant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Quota {
+    /// The sustained number of requests a tenant may start per second.
+    pub requests_per_sec: f64,
+    /// The number of requests a tenant may start at once after a period of inactivity.
+    pub burst: u32,
+    /// The number of requests a tenant may have executing at once.
+    pub max_in_flight: usize,
+}
+
+impl Quota {
+    /// Returns a quota of `requests_per_sec`, with a burst of one second's worth of requests, and
+    /// at most `max_in_flight` concurrently executing requests.
+    pub fn new(requests_per_sec: u32, max_in_flight: usize) -> Self {
+        Self {
+            requests_per_sec: requests_per_sec.into(),
+            burst: requests_per_sec,
+            max_in_flight,
+        }
+    }
+
+    /// Sets the number of requests a tenant may start at once.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// The reason a request was rejected by [`TenantQuotas`].
+///
+/// Rejected requests fail with a [resource exhausted](ServerError::resource_exhausted) error, from
+/// which clients recover the reason with [`QuotaExceeded::from_server_error`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum QuotaExceeded {
+    /// The tenant started requests faster than its quota allows.
+    #[error("tenant exceeded its request rate quota")]
+    RequestRate,
+    /// The tenant has as many requests executing as its quota allows.
+    #[error("tenant exceeded its in-flight request quota")]
+    InFlightRequests,
+}
+
+impl QuotaExceeded {
+    /// Returns the reason a request was rejected, if `e` was returned by [`TenantQuotas`].
+    pub fn from_server_error(e: &ServerError) -> Option<Self> {
+        if !e.is_resource_exhausted() {
+            return None;
+        }
+        [Self::RequestRate, Self::InFlightRequests]
+            .into_iter()
+            .find(|reason| e.detail == reason.to_string())
+    }
+}
+
+impl From<QuotaExceeded> for ServerError {
+    fn from(e: QuotaExceeded) -> Self {
+        ServerError::resource_exhausted(e.to_string())
+    }
+}
+
+/// Request-rate and in-flight quotas, enforced per tenant across every channel serving the
+/// tenant.
+///
+/// Cloning produces a handle to the same quotas, so a single instance can be shared by all the
+/// connections of a server, and a tenant's requests count against the same quota no matter which
+/// connection they arrive on. Request rates are measured with [`clock::now`].
+///
+/// A tenant is tracked only while it has requests executing or is refilling its burst. Idle
+/// tenants are evicted lazily, as new tenants arrive, so the memory used stays proportional to the
+/// number of recently active tenants, however many distinct tenants are seen.
+pub struct TenantQuotas<K> {
+    shared: Arc<Mutex<Shared<K>>>,
+}
+
+struct Shared<K> {
+    default_quota: Quota,
+    overrides: HashMap<K, Quota>,
+    tenants: HashMap<K, TenantState>,
+    /// The number of tracked tenants at which idle tenants are next evicted.
+    evict_at: usize,
+}
+
+/// The fewest tracked tenants at which idle tenants are evicted.
+const MIN_EVICT_AT: usize = 64;
+
+#[derive(Debug)]
+struct TenantState {
+    tokens: f64,
+    refilled_at: SystemTime,
+    in_flight: usize,
+}
+
+impl<K> Clone for TenantQuotas<K> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for TenantQuotas<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantQuotas").finish_non_exhaustive()
+    }
+}
+
+impl<K> TenantQuotas<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Returns quotas that apply `quota` to every tenant.
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                default_quota: quota,
+                overrides: HashMap::new(),
+                tenants: HashMap::new(),
+                evict_at: MIN_EVICT_AT,
+            })),
+        }
+    }
+
+    /// Applies `quota` to `tenant` instead of the default quota.
+    pub fn set_quota(&self, tenant: K, quota: Quota) {
+        self.lock().overrides.insert(tenant, quota);
+    }
+
+    /// Returns the number of requests `tenant` has executing.
+    pub fn in_flight(&self, tenant: &K) -> usize {
+        self.lock()
+            .tenants
+            .get(tenant)
+            .map_or(0, |state| state.in_flight)
+    }
+
+    /// Starts a request on behalf of `tenant`, if its quota allows. The request counts against
+    /// the tenant's in-flight quota until the returned permit is dropped.
+    pub fn try_acquire(&self, tenant: K) -> Result<QuotaPermit<K>, QuotaExceeded> {
+        let now = clock::now();
+        let mut shared = self.lock();
+        if shared.tenants.len() >= shared.evict_at && !shared.tenants.contains_key(&tenant) {
+            shared.evict_idle(now);
+        }
+        let quota = shared.quota(&tenant);
+        let state = shared
+            .tenants
+            .entry(tenant.clone())
+            .or_insert_with(|| TenantState {
+                tokens: quota.burst.into(),
+                refilled_at: now,
+                in_flight: 0,
+            });
+        state.refill(&quota, now);
+        if state.in_flight >= quota.max_in_flight {
+            return Err(QuotaExceeded::InFlightRequests);
+        }
+        if state.tokens < 1. {
+            return Err(QuotaExceeded::RequestRate);
+        }
+        state.tokens -= 1.;
+        state.in_flight += 1;
+        Ok(QuotaPermit {
+            quotas: self.clone(),
+            tenant: Some(tenant),
+        })
+    }
+
+    /// Returns a serve fn that rejects requests whose tenant, as identified by `tenant_of`, has
+    /// exceeded its quota, and otherwise runs `serve`.
+    pub fn serving<S, F>(self, tenant_of: F, serve: S) -> EnforceQuotas<S, K, F>
+    where
+        S: Serve,
+        F: Fn(&context::Context, &S::Req) -> K,
+    {
+        EnforceQuotas {
+            serve,
+            quotas: self,
+            tenant_of,
+        }
+    }
+
+    fn release(&self, tenant: &K) {
+        let now = clock::now();
+        let mut shared = self.lock();
+        let quota = shared.quota(tenant);
+        if let Some(state) = shared.tenants.get_mut(tenant) {
+            state.in_flight -= 1;
+            state.refill(&quota, now);
+            if state.is_idle(&quota) {
+                shared.tenants.remove(tenant);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared<K>> {
+        // The state is left consistent even if a lock holder panics.
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Eq + Hash> Shared<K> {
+    fn quota(&self, tenant: &K) -> Quota {
+        self.overrides
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.default_quota)
+    }
+
+    /// Stops tracking idle tenants. Evicting again only once the number of tenants doubles keeps
+    /// the amortized cost per request constant.
+    fn evict_idle(&mut self, now: SystemTime) {
+        let Shared {
+            default_quota,
+            overrides,
+            tenants,
+            evict_at,
+        } = self;
+        tenants.retain(|tenant, state| {
+            let quota = overrides.get(tenant).unwrap_or(default_quota);
+            state.refill(quota, now);
+            !state.is_idle(quota)
+        });
+        *evict_at = MIN_EVICT_AT.max(2 * tenants.len());
+    }
+}
+
+impl TenantState {
+    fn refill(&mut self, quota: &Quota, now: SystemTime) {
+        // If the clock went backwards, no tokens are added until it catches up.
+        let elapsed = now
+            .duration_since(self.refilled_at)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.requests_per_sec).min(quota.burst.into());
+        self.refilled_at = self.refilled_at.max(now);
+    }
+
+    /// Whether the tenant has no requests executing and a full bucket, which makes it
+    /// indistinguishable from an unknown tenant.
+    fn is_idle(&self, quota: &Quota) -> bool {
+        self.in_flight == 0 && self.tokens >= f64::from(quota.burst)
+    }
+}
+
+/// A request counted against its tenant's in-flight quota.
+pub struct QuotaPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    quotas: TenantQuotas<K>,
+    tenant: Option<K>,
+}
+
+impl<K> fmt::Debug for QuotaPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuotaPermit").finish_non_exhaustive()
+    }
+}
+
+impl<K> Drop for QuotaPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(tenant) = self.tenant.take() {
+            self.quotas.release(&tenant);
+        }
+    }
+}
+
+/// A serve fn that enforces [`TenantQuotas`] before running the wrapped serve fn.
+///
+/// The request counts against its tenant's in-flight quota until it completes or is cancelled.
+pub struct EnforceQuotas<S, K, F> {
+    serve: S,
+    quotas: TenantQuotas<K>,
+    tenant_of: F,
+}
+
+impl<S: Clone, K, F: Clone> Clone for EnforceQuotas<S, K, F> {
+    fn clone(&self) -> Self {
+        Self {
+            serve: self.serve.clone(),
+            quotas: self.quotas.clone(),
+            tenant_of: self.tenant_of.clone(),
+        }
+    }
+}
+
+impl<S, K, F> fmt::Debug for EnforceQuotas<S, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnforceQuotas").finish_non_exhaustive()
+    }
+}
+
+impl<S, K, F> Serve for EnforceQuotas<S, K, F>
+where
+    S: Serve,
+    K: Eq + Hash + Clone,
+    F: Fn(&context::Context, &S::Req) -> K,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let tenant = (self.tenant_of)(&ctx, &req);
+        let _permit = self.quotas.try_acquire(tenant).map_err(|e| {
+            tracing::info!(reason = %e, "QuotaExceeded");
+            ServerError::from(e)
+        })?;
+        self.serve.serve(ctx, req).await
+    }
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, QuotaExceeded, TenantQuotas, MIN_EVICT_AT};
+    use crate::{
+        clock, context,
+        server::{serve, Serve},
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+    use futures::{channel::oneshot, poll, task::Poll, FutureExt};
+    use std::{
+        pin::pin,
+        sync::atomic::{AtomicU64, Ordering},
+        time::{Duration, SystemTime},
+    };
+
+    /// Milliseconds that `test_clock` runs ahead of the system clock. The clock is process-wide,
+    /// so it stays close to the system clock for the sake of other tests.
+    static AHEAD_MS: AtomicU64 = AtomicU64::new(0);
+
+    fn test_clock() -> SystemTime {
+        SystemTime::now() + Duration::from_millis(AHEAD_MS.load(Ordering::SeqCst))
+    }
+
+    fn advance(duration: Duration) {
+        AHEAD_MS.fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn request_rate_is_limited_per_tenant() {
+        let _lock = clock::TEST_CLOCK_LOCK.blocking_lock();
+        AHEAD_MS.store(0, Ordering::SeqCst);
+        let previous = clock::set_clock(test_clock);
+        let quotas = TenantQuotas::new(Quota::new(1, 10).with_burst(2));
+
+        drop(quotas.try_acquire("a").unwrap());
+        drop(quotas.try_acquire("a").unwrap());
+        assert_matches!(quotas.try_acquire("a"), Err(QuotaExceeded::RequestRate));
+        assert_matches!(quotas.try_acquire("b"), Ok(_));
+
+        advance(Duration::from_secs(1));
+        assert_matches!(quotas.try_acquire("a"), Ok(_));
+        assert_matches!(quotas.try_acquire("a"), Err(QuotaExceeded::RequestRate));
+        clock::set_clock(previous);
+    }
+
+    #[test]
+    fn overridden_quota_applies_to_tenant() {
+        let quotas = TenantQuotas::new(Quota::new(100, 1));
+        quotas.set_quota("big", Quota::new(100, 2));
+
+        let _a = quotas.try_acquire("small").unwrap();
+        assert_matches!(
+            quotas.try_acquire("small"),
+            Err(QuotaExceeded::InFlightRequests)
+        );
+        let _b = quotas.try_acquire("big").unwrap();
+        let _c = quotas.try_acquire("big").unwrap();
+        assert_eq!(quotas.in_flight(&"big"), 2);
+    }
+
+    #[test]
+    fn idle_tenants_are_evicted() {
+        let _lock = clock::TEST_CLOCK_LOCK.blocking_lock();
+        AHEAD_MS.store(0, Ordering::SeqCst);
+        let previous = clock::set_clock(test_clock);
+        let quotas = TenantQuotas::new(Quota::new(1, 10));
+
+        // Each tenant leaves a drained bucket behind, so none of them is idle yet.
+        let _busy = quotas.try_acquire(0).unwrap();
+        for tenant in 1..1000 {
+            drop(quotas.try_acquire(tenant).unwrap());
+        }
+        assert_eq!(quotas.lock().tenants.len(), 1000);
+
+        // Once their buckets have refilled, new tenants evict all but the busy tenant.
+        advance(Duration::from_secs(1));
+        for tenant in 1000..1000 + MIN_EVICT_AT {
+            drop(quotas.try_acquire(tenant).unwrap());
+        }
+        assert!(quotas.lock().tenants.len() < 2 * MIN_EVICT_AT);
+        assert_eq!(quotas.in_flight(&0), 1);
+        clock::set_clock(previous);
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_are_limited_until_complete() {
+        let quotas = TenantQuotas::new(Quota::new(100, 1));
+        let (tx, rx) = oneshot::channel::<()>();
+        let rx = rx.shared();
+        let serve = quotas.clone().serving(
+            |_: &context::Context, tenant: &&'static str| *tenant,
+            serve(move |_, _| async move {
+                let _ = rx.await;
+                Ok(())
+            }),
+        );
+
+        let mut first = pin!(serve.clone().serve(context::current(), "a"));
+        assert_matches!(poll!(first.as_mut()), Poll::Pending);
+        assert_eq!(quotas.in_flight(&"a"), 1);
+
+        let rejected: Result<(), ServerError> = serve.clone().serve(context::current(), "a").await;
+        let rejected = rejected.unwrap_err();
+        assert_eq!(
+            QuotaExceeded::from_server_error(&rejected),
+            Some(QuotaExceeded::InFlightRequests)
+        );
+
+        drop(tx);
+        assert_matches!(first.await, Ok(()));
+        assert_eq!(quotas.in_flight(&"a"), 0);
+        assert_matches!(serve.serve(context::current(), "a").await, Ok(()));
+    }
+}
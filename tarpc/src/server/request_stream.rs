@@ -0,0 +1,185 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides [`RequestStream`], through which a request handler receives the items a client
+//! streams to one request.
+//!
+//! A service method with an argument of type `impl Stream<Item = T>` is a client-streaming
+//! method. Its generated client sends the request with
+//! [`Channel::call_with_stream`](crate::client::Channel::call_with_stream), followed by each item
+//! of the stream as a [`ClientMessage::Item`](crate::ClientMessage::Item) under the same request
+//! ID. The channel hands the items of a [streamed](crate::Request::streamed) request to the
+//! request's stream, which the generated serving function passes to the handler.
+//!
+//! [`InFlightRequest::execute`](super::InFlightRequest::execute) makes the request's stream
+//! [current](RequestStream::take) while the handler is polled, the way it does the request's
+//! [response sink](super::response_sink::ResponseSink). Items are buffered by the channel until
+//! the handler reads them, up to [`Config::max_buffered_items`](super::Config::max_buffered_items)
+//! per request; they do not count toward the channel's byte limits.
+
+use crate::ServerError;
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{
+    any::Any,
+    cell::RefCell,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+thread_local! {
+    static STREAM: RefCell<Option<Arc<dyn Any + Send + Sync>>> = RefCell::new(None);
+}
+
+/// The items a client streams to the request being handled.
+#[derive(Debug)]
+pub struct RequestStream<Req> {
+    request_id: u64,
+    items: mpsc::Receiver<Req>,
+}
+
+impl<Req> RequestStream<Req> {
+    pub(super) fn new(request_id: u64, items: mpsc::Receiver<Req>) -> Self {
+        Self { request_id, items }
+    }
+
+    /// Returns the ID of the request the items belong to.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+}
+
+impl<Req> RequestStream<Req>
+where
+    Req: Send + 'static,
+{
+    /// Takes the stream of the request whose handler is currently being polled, if it is a
+    /// streamed request with items of type `Req` whose stream was not already taken.
+    pub fn take() -> Option<Self> {
+        STREAM.with(|current| {
+            current
+                .borrow()
+                .as_ref()?
+                .downcast_ref::<Mutex<Option<Self>>>()?
+                .lock()
+                .unwrap()
+                .take()
+        })
+    }
+
+    /// Returns a future that runs `future`, making this the [current](RequestStream::take)
+    /// stream whenever `future` is polled.
+    pub(super) fn link<F: Future>(self, future: F) -> Linked<F> {
+        Linked {
+            future,
+            stream: Some(Arc::new(Mutex::new(Some(self)))),
+        }
+    }
+}
+
+impl<Req> Stream for RequestStream<Req> {
+    type Item = Req;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Req>> {
+        self.items.poll_recv(cx)
+    }
+}
+
+/// A future linked to a [`RequestStream`], or to none if the request is not streamed.
+#[pin_project]
+pub(super) struct Linked<F> {
+    #[pin]
+    future: F,
+    stream: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl<F> Linked<F> {
+    /// Returns a future that runs `future` with no current stream.
+    pub(super) fn unlinked(future: F) -> Self {
+        Self {
+            future,
+            stream: None,
+        }
+    }
+}
+
+impl<F: Future> Future for Linked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<Arc<dyn Any + Send + Sync>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                STREAM.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let this = self.project();
+        let previous = STREAM.with(|current| current.replace(this.stream.clone()));
+        let _restore = Restore(previous);
+        this.future.poll(cx)
+    }
+}
+
+/// Takes the [current](RequestStream::take) stream and returns its items, unwrapped by `unwrap`.
+/// Items that `unwrap` returns `None` for are skipped. Generated serving functions call this for
+/// client-streaming methods.
+///
+/// Fails with [`io::ErrorKind::Unsupported`] if the handler is not linked to a stream of `Req`s,
+/// e.g. because the client did not stream the request.
+pub fn items<Req, T>(
+    mut unwrap: impl FnMut(Req) -> Option<T>,
+) -> Result<impl Stream<Item = T>, ServerError>
+where
+    Req: Send + 'static,
+{
+    let stream = RequestStream::take().ok_or_else(|| {
+        ServerError::new(
+            io::ErrorKind::Unsupported,
+            "The request has no stream of items.".into(),
+        )
+    })?;
+    Ok(stream.filter_map(move |item| future::ready(unwrap(item))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{items, Linked, RequestStream};
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+    use std::io;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn items_takes_the_current_stream_once() {
+        let (tx, rx) = mpsc::channel::<Option<u32>>(3);
+        for item in [Some(1), None, Some(2)] {
+            tx.try_send(item).unwrap();
+        }
+        drop(tx);
+        let received = RequestStream::new(7, rx)
+            .link(async {
+                let stream = items(|item: Option<u32>| item).unwrap();
+                // The stream is taken only once.
+                assert_matches!(
+                    items::<Option<u32>, u32>(|item| item).err(),
+                    Some(e) if e.kind == io::ErrorKind::Unsupported
+                );
+                stream.collect::<Vec<_>>().await
+            })
+            .await;
+        assert_eq!(received, [1, 2]);
+        assert!(RequestStream::<Option<u32>>::take().is_none());
+
+        let unlinked = Linked::unlinked(async { items::<u32, u32>(Some).is_err() }).await;
+        assert!(unlinked);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides [`ResponseSink`], through which a request handler answers one request with a stream
+//! of responses.
+//!
+//! A service method declared to return `impl Stream<Item = T>` is a streaming method. Its
+//! generated serving function sends each item the stream yields through the sink of the request,
+//! as a response with [`more`](crate::Response::more) set, and finishes with a response that
+//! marks the end of the stream. The request stays in flight until then, so it is still canceled,
+//! and still expires at its deadline, while items are being sent.
+//!
+//! [`InFlightRequest::execute`](super::InFlightRequest::execute) makes the request's sink
+//! [current](ResponseSink::current) while the handler is polled, the way it does the request's
+//! [cancellation](crate::context::Cancellation). The sink is typed by the channel's response
+//! type, so a handler whose responses are converted before they reach the channel, e.g. by the
+//! envelope transport's `EncodeResponse`, finds no sink and fails the request.
+
+use crate::{Response, ServerError};
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{any::Any, cell::RefCell, io, pin::Pin, sync::Arc, task::Poll};
+use tokio::sync::mpsc;
+
+thread_local! {
+    static SINK: RefCell<Option<Arc<dyn Any + Send + Sync>>> = RefCell::new(None);
+}
+
+/// Sends responses to the request being handled ahead of its final response.
+#[derive(Debug)]
+pub struct ResponseSink<Resp> {
+    request_id: u64,
+    tx: mpsc::Sender<Response<Resp>>,
+}
+
+impl<Resp> Clone for ResponseSink<Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            request_id: self.request_id,
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<Resp> ResponseSink<Resp>
+where
+    Resp: Send + 'static,
+{
+    pub(super) fn new(request_id: u64, tx: mpsc::Sender<Response<Resp>>) -> Self {
+        Self { request_id, tx }
+    }
+
+    /// Returns the sink of the request whose handler is currently being polled, if its responses
+    /// are of type `Resp`.
+    pub fn current() -> Option<Self> {
+        SINK.with(|current| current.borrow().as_ref()?.downcast_ref::<Self>().cloned())
+    }
+
+    /// Returns the ID of the request the responses are sent to.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Sends `message` as a response to the request, to be followed by more. Waits while the
+    /// channel's response buffer is full, and fails once the channel is gone.
+    pub async fn send(&self, message: Resp) -> Result<(), ServerError> {
+        self.tx
+            .send(Response {
+                request_id: self.request_id,
+                message: Ok(message),
+                more: true,
+            })
+            .await
+            .map_err(|_| {
+                ServerError::new(io::ErrorKind::BrokenPipe, "The channel is closed.".into())
+            })
+    }
+
+    /// Returns a future that runs `future`, making this the [current](ResponseSink::current)
+    /// sink whenever `future` is polled.
+    pub(super) fn link<F: Future>(self, future: F) -> Linked<F> {
+        Linked {
+            future,
+            sink: Arc::new(self),
+        }
+    }
+}
+
+/// A future linked to a [`ResponseSink`].
+#[pin_project]
+pub(super) struct Linked<F> {
+    #[pin]
+    future: F,
+    sink: Arc<dyn Any + Send + Sync>,
+}
+
+impl<F: Future> Future for Linked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<Arc<dyn Any + Send + Sync>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                SINK.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let this = self.project();
+        let previous = SINK.with(|current| current.borrow_mut().replace(this.sink.clone()));
+        let _restore = Restore(previous);
+        this.future.poll(cx)
+    }
+}
+
+/// Sends each item of `items`, wrapped by `wrap`, through the [current](ResponseSink::current)
+/// sink, then returns the final response, `wrap(None)`. Generated serving functions call this
+/// for streaming methods.
+///
+/// Fails with [`io::ErrorKind::Unsupported`] if the handler is not linked to a sink of `Resp`s.
+pub async fn respond<Resp, T>(
+    items: impl Stream<Item = T>,
+    mut wrap: impl FnMut(Option<T>) -> Resp,
+) -> Result<Resp, ServerError>
+where
+    Resp: Send + 'static,
+{
+    let sink = ResponseSink::current().ok_or_else(|| {
+        ServerError::new(
+            io::ErrorKind::Unsupported,
+            "Responses cannot be streamed to this request.".into(),
+        )
+    })?;
+    futures::pin_mut!(items);
+    while let Some(item) = items.next().await {
+        sink.send(wrap(Some(item))).await?;
+    }
+    Ok(wrap(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{respond, ResponseSink};
+    use crate::Response;
+    use assert_matches::assert_matches;
+    use futures::stream;
+    use std::io;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn respond_sends_items_through_the_current_sink() {
+        let (tx, mut rx) = mpsc::channel::<Response<Option<u32>>>(10);
+        let response = ResponseSink::new(7, tx)
+            .link(respond(stream::iter([1, 2]), |item: Option<u32>| item))
+            .await;
+        assert_matches!(response, Ok(None));
+        assert_matches!(
+            rx.recv().await,
+            Some(Response {
+                request_id: 7,
+                message: Ok(Some(1)),
+                more: true
+            })
+        );
+        assert_matches!(
+            rx.recv().await,
+            Some(Response {
+                message: Ok(Some(2)),
+                ..
+            })
+        );
+        assert!(ResponseSink::<Option<u32>>::current().is_none());
+
+        // Without a sink of the right type, nothing is sent.
+        let (tx, _rx) = mpsc::channel::<Response<String>>(10);
+        let response = ResponseSink::new(7, tx)
+            .link(respond(stream::iter([1]), |item: Option<u32>| item))
+            .await;
+        assert_matches!(response, Err(e) if e.kind == io::ErrorKind::Unsupported);
+    }
+}
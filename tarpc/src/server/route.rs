@@ -0,0 +1,266 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides routing of accepted connections to different services, so that several logical
+//! services can share one listening port.
+//!
+//! Each service has its own request type, so connections must be routed before they are wrapped
+//! in channels. A [`Router`] splits a stream of accepted connections into one [`Route`] per
+//! service, by a key computed from each connection, such as the ALPN protocol or SNI hostname
+//! negotiated by a TLS handshake. Each route is a stream of connections that is served like the
+//! stream of a single-service listener. Connections whose key has no route are dropped.
+//!
+//! ```rust
+//! use futures::{prelude::*, stream};
+//! use tarpc::server::route::Router;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! // E.g. TLS streams, keyed by `tls_stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)`.
+//! let connections = stream::iter([("ping", 1), ("echo", 2), ("ping", 3)]);
+//! let mut router = Router::new(connections, |(protocol, _)| *protocol);
+//! let ping = router.route("ping");
+//! let echo = router.route("echo");
+//!
+//! let (ping, echo) = future::join(ping.collect::<Vec<_>>(), echo.collect::<Vec<_>>()).await;
+//! assert_eq!(ping, [("ping", 1), ("ping", 3)]);
+//! assert_eq!(echo, [("echo", 2)]);
+//! # }
+//! ```
+
+use crate::util::tracing;
+use futures::{prelude::*, task::*};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Splits a stream of connections into [routes](Route) by a key computed from each connection.
+///
+/// The routes share the underlying stream: whichever route is polled reads the next connection
+/// from it, and hands it to the route of its key.
+pub struct Router<S: Stream, K, F> {
+    shared: Arc<Mutex<Shared<S, K, F>>>,
+}
+
+struct Shared<S: Stream, K, F> {
+    connections: Pin<Box<S>>,
+    key_of: F,
+    routes: HashMap<K, RouteState<S::Item>>,
+    done: bool,
+}
+
+struct RouteState<C> {
+    /// Connections read by other routes, waiting to be taken by this one.
+    queued: VecDeque<C>,
+    /// Wakes the route's task, if it is waiting for a connection.
+    waker: Option<Waker>,
+}
+
+/// The stream of connections routed to one key by a [`Router`].
+pub struct Route<S: Stream, K, F>
+where
+    K: Eq + Hash,
+{
+    shared: Arc<Mutex<Shared<S, K, F>>>,
+    key: K,
+}
+
+impl<S: Stream, K, F> fmt::Debug for Router<S, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router").finish_non_exhaustive()
+    }
+}
+
+impl<S, K, F> fmt::Debug for Route<S, K, F>
+where
+    S: Stream,
+    K: Eq + Hash + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, K, F> Router<S, K, F>
+where
+    S: Stream,
+    K: Eq + Hash + Clone,
+    F: FnMut(&S::Item) -> K,
+{
+    /// Returns a router that routes each of `connections` by the key `key_of` returns for it.
+    pub fn new(connections: S, key_of: F) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                connections: Box::pin(connections),
+                key_of,
+                routes: HashMap::new(),
+                done: false,
+            })),
+        }
+    }
+
+    /// Returns the stream of connections whose key is `key`. Connections read before the route
+    /// is created are not routed to it, so create all routes before polling any of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a route for `key` already exists.
+    pub fn route(&mut self, key: K) -> Route<S, K, F> {
+        let mut shared = lock(&self.shared);
+        assert!(
+            !shared.routes.contains_key(&key),
+            "a route for the key already exists"
+        );
+        shared.routes.insert(
+            key.clone(),
+            RouteState {
+                queued: VecDeque::new(),
+                waker: None,
+            },
+        );
+        Route {
+            shared: self.shared.clone(),
+            key,
+        }
+    }
+}
+
+fn lock<T>(shared: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The state is left consistent even if a lock holder panics.
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<S, K, F> Stream for Route<S, K, F>
+where
+    S: Stream,
+    K: Eq + Hash,
+    F: FnMut(&S::Item) -> K,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let mut shared = lock(&self.shared);
+        let shared = &mut *shared;
+        if let Some(connection) = shared
+            .routes
+            .get_mut(&self.key)
+            .and_then(|route| route.queued.pop_front())
+        {
+            return Poll::Ready(Some(connection));
+        }
+        while !shared.done {
+            let connection = match shared.connections.as_mut().poll_next(cx) {
+                Poll::Ready(Some(connection)) => connection,
+                Poll::Ready(None) => {
+                    shared.done = true;
+                    for route in shared.routes.values_mut() {
+                        if let Some(waker) = route.waker.take() {
+                            waker.wake();
+                        }
+                    }
+                    break;
+                }
+                Poll::Pending => {
+                    if let Some(route) = shared.routes.get_mut(&self.key) {
+                        route.waker = Some(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+            };
+            let key = (shared.key_of)(&connection);
+            if key == self.key {
+                return Poll::Ready(Some(connection));
+            }
+            match shared.routes.get_mut(&key) {
+                Some(route) => {
+                    route.queued.push_back(connection);
+                    if let Some(waker) = route.waker.take() {
+                        waker.wake();
+                    }
+                }
+                None => tracing::info!("DropUnroutedConnection"),
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<S, K, F> Drop for Route<S, K, F>
+where
+    S: Stream,
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.routes.remove(&self.key);
+        // The dropped route may have been the one registered to wake when the next connection
+        // arrives, so the remaining routes poll for it themselves.
+        for route in shared.routes.values_mut() {
+            if let Some(waker) = route.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use assert_matches::assert_matches;
+    use futures::{channel::mpsc, prelude::*, task::*};
+    use std::pin::pin;
+
+    fn ctx() -> Context<'static> {
+        Context::from_waker(noop_waker_ref())
+    }
+
+    #[test]
+    fn routes_connections_by_key() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut router = Router::new(rx, |(key, _): &(&str, u32)| *key);
+        let mut a = pin!(router.route("a"));
+        let mut b = pin!(router.route("b"));
+
+        assert_matches!(b.as_mut().poll_next(&mut ctx()), Poll::Pending);
+        tx.unbounded_send(("a", 1)).unwrap();
+        tx.unbounded_send(("unknown", 2)).unwrap();
+        tx.unbounded_send(("b", 3)).unwrap();
+        tx.unbounded_send(("a", 4)).unwrap();
+
+        // Reading b's connection queues a's first connection, and drops the unroutable one.
+        assert_matches!(
+            b.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(("b", 3)))
+        );
+        assert_matches!(
+            a.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(("a", 1)))
+        );
+        assert_matches!(
+            a.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(("a", 4)))
+        );
+        assert_matches!(a.as_mut().poll_next(&mut ctx()), Poll::Pending);
+
+        drop(tx);
+        assert_matches!(a.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+        assert_matches!(b.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "a route for the key already exists")]
+    fn rejects_duplicate_route() {
+        let mut router = Router::new(stream::empty::<u32>(), |_| ());
+        let _first = router.route(());
+        let _second = router.route(());
+    }
+}
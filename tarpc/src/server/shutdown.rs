@@ -0,0 +1,449 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides graceful shutdown, which drains a server's channels before it exits.
+//!
+//! Stopping a server by dropping its channels aborts the requests in flight on them, failing
+//! those requests for their clients. A [`Shutdown`] handle instead drains the server: once
+//! [`Shutdown::shutdown`] is called, the server accepts no new channels, each
+//! [`Draining`] channel rejects newly arriving requests and closes once its in-flight requests
+//! are responded to and the responses flushed, and `shutdown` resolves once every channel has
+//! closed, or at a deadline. Rejected requests fail with a
+//! [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted) error without having been
+//! handled, so their clients can safely retry them against another server. Together, this allows
+//! rolling restarts without failing any requests.
+//!
+//! Attach the handle to a stream of channels with [`Incoming::with_shutdown`]:
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use std::time::Duration;
+//! use tarpc::{
+//!     server::{
+//!         self,
+//!         incoming::{spawn_incoming, Incoming},
+//!         shutdown::Shutdown,
+//!         BaseChannel,
+//!     },
+//!     transport::channel,
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let (_client_transport, server_transport) =
+//!     channel::unbounded::<tarpc::Response<i32>, tarpc::ClientMessage<i32>>();
+//! let shutdown = Shutdown::new();
+//! let incoming = stream::once(async move { BaseChannel::with_defaults(server_transport) })
+//!     .chain(stream::pending())
+//!     .with_shutdown(shutdown.clone())
+//!     .execute(server::serve(|_, i: i32| async move { Ok(i + 1) }));
+//! tokio::spawn(spawn_incoming(incoming));
+//!
+//! // E.g. upon SIGTERM:
+//! shutdown.shutdown(Duration::from_secs(30)).await.unwrap();
+//! # }
+//! ```
+//!
+//! [`Incoming::with_shutdown`]: crate::server::incoming::Incoming::with_shutdown
+
+use crate::util::tracing;
+use crate::{
+    server::{Channel, Config},
+    Response, ServerError,
+};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{io, pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+/// A handle to shut down a server gracefully. Clones share the shutdown.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Fires once the server starts draining.
+    draining: CancellationToken,
+    /// The number of channels yet to close.
+    open_channels: watch::Sender<usize>,
+}
+
+/// The error returned by [`Shutdown::shutdown`] when channels were still open at its deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{open_channels} channels were still draining at the shutdown deadline")]
+pub struct DrainTimedOut {
+    /// The number of channels that were still open.
+    pub open_channels: usize,
+}
+
+impl Shutdown {
+    /// Returns a new handle, for a server that is not yet shutting down.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                draining: CancellationToken::new(),
+                open_channels: watch::Sender::new(0),
+            }),
+        }
+    }
+
+    /// Returns true if the server has started shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.is_cancelled()
+    }
+
+    /// Returns the number of channels that have not yet closed.
+    pub fn open_channels(&self) -> usize {
+        *self.inner.open_channels.borrow()
+    }
+
+    /// Returns `channel` wrapped to drain when the server shuts down. Use it for channels not
+    /// accepted through [`Incoming::with_shutdown`].
+    ///
+    /// [`Incoming::with_shutdown`]: crate::server::incoming::Incoming::with_shutdown
+    pub fn drain<C: Channel>(&self, channel: C) -> Draining<C> {
+        Draining::new(channel, self.clone())
+    }
+
+    /// Shuts down the server: stops accepting channels, drains the open ones, and resolves once
+    /// they have all closed.
+    ///
+    /// Returns an error if channels are still open after `timeout`, e.g. because a request is
+    /// stuck. Those channels keep draining, and the caller typically exits anyway, aborting their
+    /// requests.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), DrainTimedOut> {
+        tracing::info!(open_channels = self.open_channels(), "BeginShutdown");
+        self.inner.draining.cancel();
+        let mut open_channels = self.inner.open_channels.subscribe();
+        let closed = open_channels.wait_for(|open_channels| *open_channels == 0);
+        // The sender is never dropped while `self` holds it, so waiting fails only on timeout.
+        let closed = tokio::time::timeout(timeout, closed).await.is_ok();
+        if closed {
+            tracing::info!("ShutdownComplete");
+            Ok(())
+        } else {
+            let open_channels = self.open_channels();
+            tracing::warn!(open_channels, "ShutdownTimedOut after {:?}", timeout);
+            Err(DrainTimedOut { open_channels })
+        }
+    }
+
+    fn draining(&self) -> Pin<Box<WaitForCancellationFutureOwned>> {
+        Box::pin(self.inner.draining.clone().cancelled_owned())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts a channel as open until dropped.
+#[derive(Debug)]
+struct OpenChannel(Shutdown);
+
+impl OpenChannel {
+    fn new(shutdown: Shutdown) -> Self {
+        shutdown
+            .inner
+            .open_channels
+            .send_modify(|open_channels| *open_channels += 1);
+        Self(shutdown)
+    }
+}
+
+impl Drop for OpenChannel {
+    fn drop(&mut self) {
+        self.0
+            .inner
+            .open_channels
+            .send_modify(|open_channels| *open_channels -= 1);
+    }
+}
+
+/// A [`Channel`] that drains once its server [shuts down](Shutdown::shutdown).
+///
+/// While draining, the channel answers each new request with a
+/// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) error, and ends once no requests are
+/// in flight, after which the [`Requests`](crate::server::Requests) stream over it flushes the
+/// remaining responses and ends. The channel counts as open to its [`Shutdown`] until dropped.
+#[pin_project]
+#[derive(Debug)]
+pub struct Draining<C> {
+    #[pin]
+    inner: C,
+    draining: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    _open: OpenChannel,
+}
+
+impl<C> Draining<C> {
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns true if the channel is draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_none()
+    }
+}
+
+impl<C> Draining<C>
+where
+    C: Channel,
+{
+    /// Returns a new `Draining` that wraps the given channel and drains it once `shutdown`
+    /// starts.
+    pub fn new(inner: C, shutdown: Shutdown) -> Self {
+        Self {
+            inner,
+            draining: Some(shutdown.draining()),
+            _open: OpenChannel::new(shutdown),
+        }
+    }
+}
+
+impl<C> Stream for Draining<C>
+where
+    C: Channel,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.as_mut().project();
+        if let Some(draining) = this.draining {
+            if draining.as_mut().poll(cx).is_pending() {
+                return this.inner.poll_next(cx);
+            }
+            tracing::info!(
+                in_flight_requests = this.inner.in_flight_requests(),
+                "DrainChannel"
+            );
+            *this.draining = None;
+        }
+
+        // Keep reading, so that the inner channel goes on tracking in-flight requests, but
+        // reject whatever requests arrive.
+        while self.in_flight_requests() > 0 {
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(r) => {
+                    let _entered = r.span.enter();
+                    tracing::info!("RejectRequestWhileDraining");
+                    // A one-way request's response is discarded by the channel.
+                    self.as_mut().start_send(Response {
+                        request_id: r.request.id,
+                        message: Err(ServerError::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "the server is shutting down".into(),
+                        )),
+                        more: false,
+                    })?;
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<C> Sink<Response<<C as Channel>::Resp>> for Draining<C>
+where
+    C: Channel,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: Response<<C as Channel>::Resp>,
+    ) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C> AsRef<C> for Draining<C> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> Channel for Draining<C>
+where
+    C: Channel,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+    type Transport = <C as Channel>::Transport;
+
+    fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests()
+    }
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn transport(&self) -> &Self::Transport {
+        self.inner.transport()
+    }
+
+    fn response_len(&self, response: &Self::Resp) -> usize {
+        self.inner.response_len(response)
+    }
+}
+
+/// An [`Incoming`](crate::server::incoming::Incoming) stream of [`Draining`] channels, which
+/// ends once its server [shuts down](Shutdown::shutdown).
+#[pin_project]
+#[derive(Debug)]
+pub struct WithShutdown<S> {
+    #[pin]
+    inner: S,
+    shutdown: Shutdown,
+    draining: Pin<Box<WaitForCancellationFutureOwned>>,
+}
+
+impl<S> WithShutdown<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    pub(crate) fn new(inner: S, shutdown: Shutdown) -> Self {
+        Self {
+            inner,
+            draining: shutdown.draining(),
+            shutdown,
+        }
+    }
+}
+
+impl<S> Stream for WithShutdown<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    type Item = Draining<<S as Stream>::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if this.draining.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        match ready!(this.inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(Draining::new(channel, this.shutdown.clone()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::testing::{self, FakeChannel, PollExt};
+    use crate::util::tracing::Span;
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn draining_channel_rejects_requests_and_ends_when_idle() {
+        let shutdown = Shutdown::new();
+        let channel = shutdown.drain(FakeChannel::default::<isize, isize>());
+        pin_mut!(channel);
+        assert_eq!(shutdown.open_channels(), 1);
+        channel
+            .inner
+            .in_flight_requests
+            .start_request(
+                0,
+                SystemTime::now() + Duration::from_secs(1),
+                Span::current(),
+                0,
+                false,
+            )
+            .unwrap();
+        assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+        assert!(!channel.is_draining());
+
+        shutdown.inner.draining.cancel();
+        channel.inner.push_req(1, 1);
+        assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+        assert!(channel.is_draining());
+        assert_eq!(channel.inner.sink.len(), 1);
+        let resp = channel.inner.sink.front().unwrap();
+        assert_eq!(resp.request_id, 1);
+        assert_matches!(
+            &resp.message,
+            Err(e) if e.kind == io::ErrorKind::ConnectionAborted
+        );
+        assert_eq!(channel.in_flight_requests(), 1);
+
+        channel
+            .as_mut()
+            .start_send(Response {
+                request_id: 0,
+                message: Ok(0),
+                more: false,
+            })
+            .unwrap();
+        assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+    }
+
+    #[tokio::test]
+    async fn with_shutdown_stops_accepting_channels() {
+        let shutdown = Shutdown::new();
+        let incoming = WithShutdown::new(
+            stream::repeat_with(FakeChannel::default::<isize, isize>),
+            shutdown.clone(),
+        );
+        pin_mut!(incoming);
+        let channel = incoming.next().await.unwrap();
+        assert_eq!(shutdown.open_channels(), 1);
+
+        shutdown.inner.draining.cancel();
+        assert!(incoming.next().await.is_none());
+        drop(channel);
+        assert_eq!(shutdown.open_channels(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_resolves_once_channels_close_or_times_out() {
+        let shutdown = Shutdown::new();
+        assert_eq!(shutdown.shutdown(Duration::from_secs(1)).await, Ok(()));
+
+        let shutdown = Shutdown::new();
+        let channel = shutdown.drain(FakeChannel::default::<isize, isize>());
+        assert_eq!(
+            shutdown.shutdown(Duration::from_secs(1)).await,
+            Err(DrainTimedOut { open_channels: 1 })
+        );
+        assert!(shutdown.is_draining());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(channel);
+        });
+        assert_eq!(shutdown.shutdown(Duration::from_secs(1)).await, Ok(()));
+    }
+}
@@ -4,6 +4,7 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use crate::util::tracing::Span;
 use crate::{
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
     context,
@@ -13,7 +14,6 @@ use crate::{
 use futures::{task::*, Sink, Stream};
 use pin_project::pin_project;
 use std::{collections::VecDeque, io, pin::Pin, time::SystemTime};
-use tracing::Span;
 
 #[pin_project]
 pub(crate) struct FakeChannel<In, Out> {
@@ -95,9 +95,13 @@ impl<Req, Resp> FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>> {
                 context: context::Context {
                     deadline: SystemTime::UNIX_EPOCH,
                     trace_context: Default::default(),
+                    queue_time: Default::default(),
+                    envelope: None,
                 },
                 id,
                 message,
+                one_way: false,
+                streamed: false,
             },
             abort_registration,
             span: Span::none(),
@@ -106,6 +110,7 @@ impl<Req, Resp> FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>> {
                 request_id: id,
                 cancel: false,
             },
+            items: None,
         }));
     }
 }
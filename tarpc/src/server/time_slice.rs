@@ -0,0 +1,242 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides time-sliced execution of request handlers.
+//!
+//! An executor can only switch tasks when a future returns [`Poll::Pending`], so a handler that
+//! computes for a long time without awaiting anything that is pending holds its worker thread,
+//! delaying every request scheduled on the same thread. [`TimeSliced`], created by
+//! [`Serve::time_sliced`], gives each poll of a handler a time slice. Long-running handlers call
+//! [`checkpoint`] between units of work; once the slice is used up, the checkpoint yields to the
+//! executor, and the handler resumes with a fresh slice when it is next polled. Polls that run past
+//! their slice are logged as `TimeSliceOverrun`, pointing at handlers that need more checkpoints.
+//!
+//! ```rust
+//! use futures::executor::block_on;
+//! use std::time::Duration;
+//! use tarpc::{context, server::{serve, time_slice, Serve}};
+//!
+//! let serve = serve(|_, n: u64| async move {
+//!     let mut sum = 0u64;
+//!     for i in 0..n {
+//!         sum = sum.wrapping_add(i * i);
+//!         time_slice::checkpoint().await;
+//!     }
+//!     Ok(sum)
+//! })
+//! .time_sliced(Duration::from_millis(1));
+//! assert_eq!(block_on(serve.serve(context::current(), 4)).unwrap(), 14);
+//! ```
+
+use super::Serve;
+use crate::util::tracing;
+use crate::{context, ServerError};
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{
+    cell::Cell,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static SLICE: Cell<Option<Slice>> = Cell::new(None);
+}
+
+/// The time slice of the handler currently being polled.
+#[derive(Clone, Copy, Debug)]
+struct Slice {
+    started_at: Instant,
+    length: Duration,
+}
+
+impl Slice {
+    fn is_exhausted(&self) -> bool {
+        self.started_at.elapsed() >= self.length
+    }
+}
+
+/// A [`Serve`] that runs each request handler in time slices. Created by
+/// [`Serve::time_sliced`].
+#[derive(Clone, Debug)]
+pub struct TimeSliced<S> {
+    serve: S,
+    slice: Duration,
+}
+
+impl<S> TimeSliced<S> {
+    pub(crate) fn new(serve: S, slice: Duration) -> Self {
+        Self { serve, slice }
+    }
+
+    /// Returns the length of the time slices given to handlers.
+    pub fn slice(&self) -> Duration {
+        self.slice
+    }
+}
+
+impl<S: Serve> Serve for TimeSliced<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    fn method(&self, request: &S::Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    async fn serve(self, ctx: context::Context, req: S::Req) -> Result<S::Resp, ServerError> {
+        let Self { serve, slice } = self;
+        Sliced {
+            future: serve.serve(ctx, req),
+            slice,
+        }
+        .await
+    }
+}
+
+/// Polls a future, starting a new time slice on each poll.
+#[pin_project]
+struct Sliced<F> {
+    #[pin]
+    future: F,
+    slice: Duration,
+}
+
+impl<F: Future> Future for Sliced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<Slice>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                SLICE.with(|slice| slice.set(self.0));
+            }
+        }
+
+        let this = self.project();
+        let slice = Slice {
+            started_at: Instant::now(),
+            length: *this.slice,
+        };
+        let _restore = Restore(SLICE.with(|current| current.replace(Some(slice))));
+        let poll = this.future.poll(cx);
+        let elapsed = slice.started_at.elapsed();
+        // Checkpoints yield at the first opportunity after the slice is used up, so allow for the
+        // work between checkpoints before reporting an overrun.
+        if elapsed > slice.length * 2 {
+            tracing::warn!(
+                "TimeSliceOverrun: handler ran for {:?} without yielding; its time slice is {:?}",
+                elapsed,
+                slice.length
+            );
+        }
+        poll
+    }
+}
+
+/// Yields to the executor if the handler being polled has used up its time slice. Completes
+/// immediately when the slice has time left, or when not called from a [`TimeSliced`] handler.
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint { yielded: false }
+}
+
+/// A future that yields once the current time slice is used up. Returned by [`checkpoint`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Checkpoint {
+    yielded: bool,
+}
+
+impl Future for Checkpoint {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        match SLICE.with(Cell::get) {
+            Some(slice) if slice.is_exhausted() => {
+                self.yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::checkpoint;
+    use crate::{
+        context,
+        server::{serve, Serve},
+    };
+    use futures::prelude::*;
+    use futures_test::task::noop_context;
+    use std::{
+        sync::{Arc, Mutex},
+        task::Poll,
+        time::Duration,
+    };
+
+    #[test]
+    fn checkpoint_yields_once_slice_is_used_up() {
+        let serve = serve(|_, ()| async {
+            checkpoint().await;
+            checkpoint().await;
+            Ok(())
+        });
+        let cx = &mut noop_context();
+
+        let mut exhausted = Box::pin(
+            serve
+                .time_sliced(Duration::ZERO)
+                .serve(context::current(), ()),
+        );
+        assert!(exhausted.as_mut().poll(cx).is_pending());
+        assert!(exhausted.as_mut().poll(cx).is_pending());
+        assert!(matches!(exhausted.as_mut().poll(cx), Poll::Ready(Ok(()))));
+
+        let mut roomy = Box::pin(
+            serve
+                .time_sliced(Duration::from_secs(60))
+                .serve(context::current(), ()),
+        );
+        assert!(matches!(roomy.as_mut().poll(cx), Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn checkpoint_outside_time_sliced_handler_completes() {
+        let mut checkpoint = checkpoint();
+        assert!(checkpoint.poll_unpin(&mut noop_context()).is_ready());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn time_sliced_handlers_interleave() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let handler = |name: &'static str| {
+            let order = order.clone();
+            serve(move |_, ()| {
+                let order = order.clone();
+                async move {
+                    for _ in 0..3 {
+                        order.lock().unwrap().push(name);
+                        checkpoint().await;
+                    }
+                    Ok(())
+                }
+            })
+            .time_sliced(Duration::ZERO)
+            .serve(context::current(), ())
+        };
+
+        let (a, b) = future::join(handler("a"), handler("b")).await;
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(*order.lock().unwrap(), ["a", "b", "a", "b", "a", "b"]);
+    }
+}
@@ -16,13 +16,23 @@
 //! This crate's design is based on [opencensus
 //! tracing](https://opencensus.io/core-concepts/tracing/).
 
+use crate::util::tracing;
+#[cfg(feature = "tracing")]
 use opentelemetry::trace::TraceContextExt;
-use rand::Rng;
+#[cfg(feature = "random-ids")]
+use rand::{Rng, RngCore};
+#[cfg(feature = "random-ids")]
+use std::num::{NonZeroU128, NonZeroU64};
+#[cfg(feature = "random-ids")]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, Once,
+};
 use std::{
     convert::TryFrom,
     fmt::{self, Formatter},
-    num::{NonZeroU128, NonZeroU64},
 };
+#[cfg(feature = "tracing")]
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 /// A context for tracing the execution of processes, distributed or otherwise.
@@ -78,22 +88,91 @@ pub enum SamplingDecision {
 
 impl Context {
     /// Constructs a new context with the trace ID and sampling decision inherited from the parent.
+    ///
+    /// Without the `random-ids` feature, the child's span ID is left unset.
     pub(crate) fn new_child(&self) -> Self {
         Self {
             trace_id: self.trace_id,
-            span_id: SpanId::random(&mut rand::thread_rng()),
+            #[cfg(feature = "random-ids")]
+            span_id: with_id_rng(|rng| SpanId::random(rng)),
+            #[cfg(not(feature = "random-ids"))]
+            span_id: SpanId::default(),
             sampling_decision: self.sampling_decision,
         }
     }
 }
 
+#[cfg(feature = "random-ids")]
+type IdRng = Mutex<Option<Box<dyn RngCore + Send>>>;
+
+/// Whether a generator is installed, so that IDs drawn from the default generator need not take
+/// the lock of the installed one.
+#[cfg(feature = "random-ids")]
+static ID_RNG_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "random-ids")]
+fn id_rng() -> &'static IdRng {
+    static ID_RNG_ONCE: Once = Once::new();
+    static mut ID_RNG: Option<IdRng> = None;
+    ID_RNG_ONCE.call_once(|| {
+        // SAFETY: ID_RNG is only written here, which `Once` runs exactly once, before any read.
+        unsafe { ID_RNG = Some(Mutex::new(None)) }
+    });
+    // SAFETY: `call_once` has returned, so ID_RNG was written and is never written again.
+    unsafe { (*std::ptr::addr_of!(ID_RNG)).as_ref() }.expect("ID_RNG is set by ID_RNG_ONCE")
+}
+
+/// Replaces the random number generator used to generate span IDs.
+///
+/// By default, IDs are drawn from [`rand::thread_rng`]. Deterministic simulations can install a
+/// seeded generator so that repeated runs produce identical traces.
+///
+/// The generator is shared by all threads, behind a lock, so IDs generated on any thread, including
+/// the worker threads of a multi-thread runtime, are drawn from it. The IDs are only reproducible
+/// if the order in which they are generated is, e.g. on a current-thread runtime.
+#[cfg(feature = "random-ids")]
+#[cfg_attr(docsrs, doc(cfg(feature = "random-ids")))]
+pub fn set_id_rng<R: RngCore + Send + 'static>(rng: R) {
+    let mut id_rng = id_rng().lock().unwrap();
+    *id_rng = Some(Box::new(rng));
+    ID_RNG_INSTALLED.store(true, Ordering::Release);
+}
+
+/// Restores the default random number generator for span IDs, returning the generator installed
+/// by [`set_id_rng`], if any.
+#[cfg(feature = "random-ids")]
+#[cfg_attr(docsrs, doc(cfg(feature = "random-ids")))]
+pub fn take_id_rng() -> Option<Box<dyn RngCore + Send>> {
+    let mut id_rng = id_rng().lock().unwrap();
+    ID_RNG_INSTALLED.store(false, Ordering::Release);
+    id_rng.take()
+}
+
+#[cfg(feature = "random-ids")]
+fn with_id_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    if !ID_RNG_INSTALLED.load(Ordering::Acquire) {
+        return f(&mut rand::thread_rng());
+    }
+    match &mut *id_rng().lock().unwrap() {
+        Some(rng) => f(rng),
+        None => f(&mut rand::thread_rng()),
+    }
+}
+
 impl TraceId {
     /// Returns a random trace ID that can be assumed to be globally unique if `rng` generates
     /// actually-random numbers.
-    pub fn random<R: Rng>(rng: &mut R) -> Self {
+    #[cfg(feature = "random-ids")]
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Self {
         TraceId(rng.gen::<NonZeroU128>().get())
     }
 
+    /// Returns the empty trace ID: without the `random-ids` feature, tarpc does not generate IDs.
+    #[cfg(not(feature = "random-ids"))]
+    pub fn random<R: ?Sized>(_rng: &mut R) -> Self {
+        TraceId::default()
+    }
+
     /// Returns true iff the trace ID is 0.
     pub fn is_none(&self) -> bool {
         self.0 == 0
@@ -102,10 +181,17 @@ impl TraceId {
 
 impl SpanId {
     /// Returns a random span ID that can be assumed to be unique within a single trace.
-    pub fn random<R: Rng>(rng: &mut R) -> Self {
+    #[cfg(feature = "random-ids")]
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Self {
         SpanId(rng.gen::<NonZeroU64>().get())
     }
 
+    /// Returns the empty span ID: without the `random-ids` feature, tarpc does not generate IDs.
+    #[cfg(not(feature = "random-ids"))]
+    pub fn random<R: ?Sized>(_rng: &mut R) -> Self {
+        SpanId::default()
+    }
+
     /// Returns true iff the span ID is 0.
     pub fn is_none(&self) -> bool {
         self.0 == 0
@@ -136,30 +222,40 @@ impl From<u64> for SpanId {
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<opentelemetry::trace::TraceId> for TraceId {
     fn from(trace_id: opentelemetry::trace::TraceId) -> Self {
         Self::from(u128::from_be_bytes(trace_id.to_bytes()))
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<TraceId> for opentelemetry::trace::TraceId {
     fn from(trace_id: TraceId) -> Self {
         Self::from_bytes(u128::from(trace_id).to_be_bytes())
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<opentelemetry::trace::SpanId> for SpanId {
     fn from(span_id: opentelemetry::trace::SpanId) -> Self {
         Self::from(u64::from_be_bytes(span_id.to_bytes()))
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<SpanId> for opentelemetry::trace::SpanId {
     fn from(span_id: SpanId) -> Self {
         Self::from_bytes(u64::from(span_id).to_be_bytes())
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl TryFrom<&tracing::Span> for Context {
     type Error = NoActiveSpan;
 
@@ -173,6 +269,18 @@ impl TryFrom<&tracing::Span> for Context {
     }
 }
 
+/// Without the `tracing` feature, spans carry no trace context.
+#[cfg(not(feature = "tracing"))]
+impl TryFrom<&tracing::Span> for Context {
+    type Error = NoActiveSpan;
+
+    fn try_from(_: &tracing::Span) -> Result<Self, NoActiveSpan> {
+        Err(NoActiveSpan)
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<opentelemetry::trace::SpanRef<'_>> for Context {
     fn from(span: opentelemetry::trace::SpanRef<'_>) -> Self {
         let otel_ctx = span.span_context();
@@ -184,6 +292,8 @@ impl From<opentelemetry::trace::SpanRef<'_>> for Context {
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<SamplingDecision> for opentelemetry::trace::TraceFlags {
     fn from(decision: SamplingDecision) -> Self {
         match decision {
@@ -193,6 +303,8 @@ impl From<SamplingDecision> for opentelemetry::trace::TraceFlags {
     }
 }
 
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 impl From<&opentelemetry::trace::SpanContext> for SamplingDecision {
     fn from(context: &opentelemetry::trace::SpanContext) -> Self {
         if context.is_sampled() {
@@ -9,7 +9,11 @@
 //! The rpc crate is transport- and protocol-agnostic. Any transport that impls [`Transport`](sealed::Transport)
 //! can be plugged in, using whatever protocol it wants.
 
+pub mod bidi;
 pub mod channel;
+pub mod mux;
+
+use std::{error::Error, io};
 
 pub(crate) mod sealed {
     use futures::prelude::*;
@@ -38,3 +42,102 @@ pub(crate) mod sealed {
         type TransportError = E;
     }
 }
+
+/// An error indicating that a transport read a frame from the wire but could not decode it.
+///
+/// Transports that can tell an undecodable frame apart from a broken connection should report
+/// the former with this error, either directly or as the payload of an [`io::Error`]. Channels
+/// then apply their [`MalformedFramePolicy`] instead of unconditionally disconnecting.
+#[derive(thiserror::Error, Debug)]
+#[error("could not decode frame")]
+pub struct MalformedFrame {
+    request_id: Option<u64>,
+    #[source]
+    source: Box<dyn Error + Send + Sync + 'static>,
+}
+
+impl MalformedFrame {
+    /// Returns a new error for a frame that failed to decode because of `source`.
+    pub fn new(source: impl Into<Box<dyn Error + Send + Sync + 'static>>) -> Self {
+        Self {
+            request_id: None,
+            source: source.into(),
+        }
+    }
+
+    /// Records the ID of the request or response carried by the undecodable frame.
+    pub fn with_request_id(mut self, request_id: u64) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    /// Returns the ID of the request or response carried by the frame, if it could be recovered.
+    pub fn request_id(&self) -> Option<u64> {
+        self.request_id
+    }
+
+    /// Searches the chain of errors starting at `error` for a `MalformedFrame`, looking through
+    /// any [`io::Error`] wrappers.
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a MalformedFrame> {
+        let mut next = Some(error);
+        while let Some(error) = next {
+            if let Some(malformed) = error.downcast_ref::<MalformedFrame>() {
+                return Some(malformed);
+            }
+            if let Some(malformed) = error
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref::<MalformedFrame>())
+            {
+                return Some(malformed);
+            }
+            next = error.source();
+        }
+        None
+    }
+}
+
+/// Controls how a channel reacts when its transport yields a [`MalformedFrame`].
+///
+/// Errors that are not `MalformedFrame`s always close the channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MalformedFramePolicy {
+    /// Treat the malformed frame like any other read error and close the channel.
+    #[default]
+    Close,
+    /// Log and discard the malformed frame, and keep reading subsequent frames.
+    Drop,
+    /// Fail the request carried by the malformed frame, if its ID could be recovered, and keep
+    /// reading subsequent frames. Servers respond to the request with an
+    /// [`InvalidData`](io::ErrorKind::InvalidData) error; clients complete the pending call with
+    /// an error. Frames whose request ID is unknown are dropped.
+    ///
+    /// Serde transports recover request IDs only when configured with
+    /// [`with_request_id_recovery`](crate::serde_transport::Transport::with_request_id_recovery);
+    /// broker transports take them from the message envelope. Without a recovered ID, this
+    /// policy behaves like `Drop`.
+    Respond,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MalformedFrame;
+    use std::io;
+
+    #[test]
+    fn malformed_frame_find_through_io_error() {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            MalformedFrame::new("bad bytes").with_request_id(7),
+        );
+        let malformed = MalformedFrame::find(&error).unwrap();
+        assert_eq!(malformed.request_id(), Some(7));
+    }
+
+    #[test]
+    fn malformed_frame_find_absent() {
+        let error = io::Error::new(io::ErrorKind::Other, "oops");
+        assert!(MalformedFrame::find(&error).is_none());
+    }
+}
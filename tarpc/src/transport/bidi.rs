@@ -0,0 +1,548 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Runs a client and a server over one transport, so that each peer can call the other.
+//!
+//! A server that needs to call back the clients connected to it, e.g. to push events to them,
+//! would otherwise need each client to serve a second connection. Instead, both peers split their
+//! transport into a [`ClientHalf`], for a client [`Channel`](crate::client::Channel), and a
+//! [`ServerHalf`], for a server [`BaseChannel`](crate::server::BaseChannel). Each half is a
+//! [`Transport`] of its own, so request IDs, cancellations, and in-flight limits of the calls made
+//! in one direction are independent of those made in the other. The services called in each
+//! direction may differ.
+//!
+//! Every message is wrapped in a [`Message`] that tells calls from replies, so both peers must
+//! use `bidi`. Calls that arrive after the server half is dropped, and replies that arrive after
+//! the client half is dropped, are discarded.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     server::{self, BaseChannel, Channel},
+//!     transport::{self, bidi},
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//!
+//! // The server serves Double and calls back its client's Negate.
+//! let (callback, server) = bidi::new(bidi::Config::default(), server_transport).spawn();
+//! let callback = client::new(client::Config::default(), callback).spawn();
+//! let double = server::serve(move |_, x: i64| {
+//!     let callback = callback.clone();
+//!     async move {
+//!         let negated: i64 = callback.call(context::current(), "Negate", x).await.unwrap();
+//!         Ok(-negated * 2)
+//!     }
+//! });
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server)
+//!         .execute(double)
+//!         .for_each(|response| async {
+//!             tokio::spawn(response);
+//!         }),
+//! );
+//!
+//! let (client, callbacks) = bidi::new(bidi::Config::default(), client_transport).spawn();
+//! let negate = server::serve(|_, x: i64| async move { Ok(-x) });
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(callbacks)
+//!         .execute(negate)
+//!         .for_each(|response| response),
+//! );
+//! let client: client::Channel<i64, i64> =
+//!     client::new(client::Config::default(), client).spawn();
+//! assert_eq!(client.call(context::current(), "Double", 21).await?, 42);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::sealed::Transport;
+use crate::util::tracing;
+use crate::{ClientMessage, Response};
+use futures::{channel::mpsc, prelude::*, ready, stream};
+use pin_project::pin_project;
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A message on a bidirectional transport.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum Message<Req, Resp> {
+    /// A message from the sender's client to the receiver's server.
+    Call(ClientMessage<Req>),
+    /// A response from the sender's server to the receiver's client.
+    Reply(Response<Resp>),
+}
+
+/// Settings that control the behavior of a bidirectional transport.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// The number of messages each half may buffer before the driver writes them to the
+    /// transport. Once the buffer is full, the half is not ready to send.
+    pub buffer: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { buffer: 100 }
+    }
+}
+
+/// Errors that end a bidirectional transport.
+#[derive(thiserror::Error, Debug)]
+pub enum BidiError<E> {
+    /// The underlying transport could not be read from.
+    #[error("could not read from the transport")]
+    Read(#[source] E),
+    /// The underlying transport could not be written to.
+    #[error("could not write to the transport")]
+    Write(#[source] E),
+}
+
+/// Returns the halves of a bidirectional transport over `transport`. The [`Driver`] must be
+/// polled continuously or spawned for the halves to make progress.
+pub fn new<T, ClientReq, ClientResp, ServerReq, ServerResp>(
+    config: Config,
+    transport: T,
+) -> Bidi<
+    ClientReq,
+    ClientResp,
+    ServerReq,
+    ServerResp,
+    Driver<T, ClientReq, ClientResp, ServerReq, ServerResp>,
+>
+where
+    T: Transport<Message<ClientReq, ServerResp>, Message<ServerReq, ClientResp>>,
+{
+    let (calls_tx, calls) = mpsc::channel(config.buffer);
+    let (replies_tx, replies) = mpsc::channel(config.buffer);
+    let (responses_tx, responses) = mpsc::unbounded();
+    let (requests_tx, requests) = mpsc::unbounded();
+    let calls: Calls<ClientReq, ServerResp> = calls.map(Message::Call as fn(_) -> _);
+    let replies: Replies<ClientReq, ServerResp> = replies.map(Message::Reply as fn(_) -> _);
+    Bidi {
+        client: ClientHalf {
+            inbound: responses,
+            outbound: calls_tx,
+        },
+        server: ServerHalf {
+            inbound: requests,
+            outbound: replies_tx,
+        },
+        driver: Driver {
+            transport,
+            outbound: stream::select(calls, replies),
+            responses: Some(responses_tx),
+            requests: Some(requests_tx),
+        },
+    }
+}
+
+/// The halves of a bidirectional transport and the driver that drives them.
+pub struct Bidi<ClientReq, ClientResp, ServerReq, ServerResp, D> {
+    /// The transport of the client that calls the peer's server.
+    pub client: ClientHalf<ClientReq, ClientResp>,
+    /// The transport of the server that the peer's client calls.
+    pub server: ServerHalf<ServerReq, ServerResp>,
+    /// Sends and receives the messages of both halves. It completes once the peer closes the
+    /// connection, or once both halves are closed or dropped.
+    pub driver: D,
+}
+
+impl<ClientReq, ClientResp, ServerReq, ServerResp, D, E>
+    Bidi<ClientReq, ClientResp, ServerReq, ServerResp, D>
+where
+    D: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    /// Helper method to spawn the driver on the default executor. The task is named
+    /// `tarpc::transport::bidi` in tokio-console; see the `tokio-console` feature.
+    #[cfg(feature = "tokio1")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
+    pub fn spawn(
+        self,
+    ) -> (
+        ClientHalf<ClientReq, ClientResp>,
+        ServerHalf<ServerReq, ServerResp>,
+    ) {
+        let driver = self.driver.unwrap_or_else(move |e| {
+            tracing::warn!("Connection broken: {}", crate::util::print_err(&e));
+        });
+        crate::util::spawn(format_args!("tarpc::transport::bidi"), driver);
+        (self.client, self.server)
+    }
+}
+
+impl<ClientReq, ClientResp, ServerReq, ServerResp, D> fmt::Debug
+    for Bidi<ClientReq, ClientResp, ServerReq, ServerResp, D>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Bidi")
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the connection is closed")
+}
+
+/// The half of a bidirectional transport that carries the calls made on the peer's server.
+pub struct ClientHalf<Req, Resp> {
+    inbound: mpsc::UnboundedReceiver<Response<Resp>>,
+    outbound: mpsc::Sender<ClientMessage<Req>>,
+}
+
+impl<Req, Resp> Stream for ClientHalf<Req, Resp> {
+    type Item = io::Result<Response<Resp>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let response = ready!(self.inbound.poll_next_unpin(cx));
+        Poll::Ready(response.map(Ok))
+    }
+}
+
+impl<Req, Resp> Sink<ClientMessage<Req>> for ClientHalf<Req, Resp> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound.poll_ready(cx).map_err(|_| closed_error())
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: ClientMessage<Req>) -> io::Result<()> {
+        self.outbound
+            .start_send(message)
+            .map_err(|_| closed_error())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Messages are flushed by the driver.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ClientHalf<Req, Resp> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "ClientHalf")
+    }
+}
+
+/// The half of a bidirectional transport that carries the calls made by the peer's client.
+pub struct ServerHalf<Req, Resp> {
+    inbound: mpsc::UnboundedReceiver<ClientMessage<Req>>,
+    outbound: mpsc::Sender<Response<Resp>>,
+}
+
+impl<Req, Resp> Stream for ServerHalf<Req, Resp> {
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let message = ready!(self.inbound.poll_next_unpin(cx));
+        Poll::Ready(message.map(Ok))
+    }
+}
+
+impl<Req, Resp> Sink<Response<Resp>> for ServerHalf<Req, Resp> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound.poll_ready(cx).map_err(|_| closed_error())
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
+        self.outbound
+            .start_send(response)
+            .map_err(|_| closed_error())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Messages are flushed by the driver.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ServerHalf<Req, Resp> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "ServerHalf")
+    }
+}
+
+type Calls<Req, Resp> =
+    stream::Map<mpsc::Receiver<ClientMessage<Req>>, fn(ClientMessage<Req>) -> Message<Req, Resp>>;
+type Replies<Req, Resp> =
+    stream::Map<mpsc::Receiver<Response<Resp>>, fn(Response<Resp>) -> Message<Req, Resp>>;
+
+/// Sends and receives the messages of both halves of a bidirectional transport.
+#[pin_project]
+pub struct Driver<T, ClientReq, ClientResp, ServerReq, ServerResp> {
+    #[pin]
+    transport: T,
+    outbound: stream::Select<Calls<ClientReq, ServerResp>, Replies<ClientReq, ServerResp>>,
+    responses: Option<mpsc::UnboundedSender<Response<ClientResp>>>,
+    requests: Option<mpsc::UnboundedSender<ClientMessage<ServerReq>>>,
+}
+
+impl<T, ClientReq, ClientResp, ServerReq, ServerResp>
+    Driver<T, ClientReq, ClientResp, ServerReq, ServerResp>
+where
+    T: Transport<Message<ClientReq, ServerResp>, Message<ServerReq, ClientResp>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<()>, BidiError<T::TransportError>>> {
+        let this = self.project();
+        match ready!(this.transport.poll_next(cx)) {
+            Some(Ok(Message::Call(message))) => {
+                let delivered = this
+                    .requests
+                    .as_ref()
+                    .map_or(false, |requests| requests.unbounded_send(message).is_ok());
+                if !delivered {
+                    tracing::debug!("DiscardCall: the server half was dropped.");
+                }
+                Poll::Ready(Ok(Some(())))
+            }
+            Some(Ok(Message::Reply(response))) => {
+                let delivered = this.responses.as_ref().map_or(false, |responses| {
+                    responses.unbounded_send(response).is_ok()
+                });
+                if !delivered {
+                    tracing::debug!("DiscardReply: the client half was dropped.");
+                }
+                Poll::Ready(Ok(Some(())))
+            }
+            Some(Err(e)) => Poll::Ready(Err(BidiError::Read(e))),
+            None => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<()>, BidiError<T::TransportError>>> {
+        let mut this = self.project();
+        ready!(this.transport.as_mut().poll_ready(cx)).map_err(BidiError::Write)?;
+        match this.outbound.poll_next_unpin(cx) {
+            Poll::Ready(Some(message)) => {
+                this.transport
+                    .as_mut()
+                    .start_send(message)
+                    .map_err(BidiError::Write)?;
+                Poll::Ready(Ok(Some(())))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(None)),
+            Poll::Pending => {
+                ready!(this.transport.as_mut().poll_flush(cx)).map_err(BidiError::Write)?;
+                Poll::Pending
+            }
+        }
+    }
+
+    fn run(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), BidiError<T::TransportError>>> {
+        loop {
+            let read = self.as_mut().poll_read(cx)?;
+            if let Poll::Ready(None) = read {
+                tracing::info!("Shutdown: peer closed the connection.");
+                return Poll::Ready(Ok(()));
+            }
+            let write = self.as_mut().poll_write(cx)?;
+            match (read, write) {
+                (_, Poll::Ready(None)) => {
+                    ready!(self.as_mut().project().transport.poll_close(cx))
+                        .map_err(BidiError::Write)?;
+                    tracing::info!("Shutdown: both halves were closed.");
+                    return Poll::Ready(Ok(()));
+                }
+                (Poll::Pending, Poll::Pending) => return Poll::Pending,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<T, ClientReq, ClientResp, ServerReq, ServerResp> Future
+    for Driver<T, ClientReq, ClientResp, ServerReq, ServerResp>
+where
+    T: Transport<Message<ClientReq, ServerResp>, Message<ServerReq, ClientResp>>,
+{
+    type Output = Result<(), BidiError<T::TransportError>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(self.as_mut().run(cx));
+        // Ends the streams of both halves.
+        let this = self.project();
+        *this.responses = None;
+        *this.requests = None;
+        Poll::Ready(result)
+    }
+}
+
+impl<T, ClientReq, ClientResp, ServerReq, ServerResp> fmt::Debug
+    for Driver<T, ClientReq, ClientResp, ServerReq, ServerResp>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Driver")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Some tests drive a spawned dispatch, which requires tokio1.
+    #![cfg_attr(not(feature = "tokio1"), allow(unused_imports))]
+
+    use super::{Config, Message};
+    use crate::{
+        client, context,
+        server::{self, BaseChannel, Channel},
+        transport, ClientMessage, Request, Response,
+    };
+    use assert_matches::assert_matches;
+    use futures::prelude::*;
+
+    type Transport<Item, SinkItem> = transport::channel::UnboundedChannel<Item, SinkItem>;
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn peers_call_each_other_over_one_transport() {
+        let (a_transport, b_transport) = transport::channel::unbounded();
+
+        // A serves lengths of strings and calls B to double numbers.
+        let a = super::new(Config::default(), a_transport);
+        tokio::spawn(a.driver);
+        let doubler: client::Channel<u64, u64> =
+            client::new(client::Config::default(), a.client).spawn();
+        let len = server::serve(move |_, s: String| {
+            let doubler = doubler.clone();
+            async move {
+                let len = doubler.call(context::current(), "Double", s.len() as u64);
+                Ok(len.await.unwrap())
+            }
+        });
+        tokio::spawn(BaseChannel::with_defaults(a.server).execute(len).for_each(
+            |response| async {
+                tokio::spawn(response);
+            },
+        ));
+
+        let b = super::new(Config::default(), b_transport);
+        tokio::spawn(b.driver);
+        let double = server::serve(|_, x: u64| async move { Ok(x * 2) });
+        tokio::spawn(
+            BaseChannel::with_defaults(b.server)
+                .execute(double)
+                .for_each(|response| response),
+        );
+        let len: client::Channel<String, u64> =
+            client::new(client::Config::default(), b.client).spawn();
+
+        for s in ["", "a", "abc"] {
+            let doubled_len = len.call(context::current(), "Len", s.to_string()).await;
+            assert_eq!(doubled_len.unwrap(), s.len() as u64 * 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn driver_routes_calls_and_replies() {
+        let (mut raw, transport): (Transport<Message<u32, u32>, Message<u32, u32>>, _) =
+            transport::channel::unbounded();
+        let bidi = super::new::<_, u32, u32, u32, u32>(Config::default(), transport);
+        tokio::spawn(bidi.driver);
+        let (mut client, mut server) = (bidi.client, bidi.server);
+
+        raw.send(Message::Reply(Response {
+            request_id: 1,
+            message: Ok(2),
+            more: false,
+        }))
+        .await
+        .unwrap();
+        raw.send(Message::Call(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 3,
+            message: 4,
+            one_way: false,
+            streamed: false,
+        })))
+        .await
+        .unwrap();
+        assert_matches!(
+            client.next().await,
+            Some(Ok(Response {
+                request_id: 1,
+                message: Ok(2),
+                ..
+            }))
+        );
+        assert_matches!(
+            server.next().await,
+            Some(Ok(ClientMessage::Request(Request {
+                id: 3,
+                message: 4,
+                ..
+            })))
+        );
+
+        server
+            .send(Response {
+                request_id: 3,
+                message: Ok(5),
+                more: false,
+            })
+            .await
+            .unwrap();
+        assert_matches!(
+            raw.next().await,
+            Some(Ok(Message::Reply(Response {
+                request_id: 3,
+                message: Ok(5),
+                ..
+            })))
+        );
+
+        // Once both halves are closed, the driver closes the transport.
+        drop((client, server));
+        assert_matches!(raw.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn peer_closing_ends_both_halves() {
+        let (raw, transport): (Transport<Message<u32, u32>, Message<u32, u32>>, _) =
+            transport::channel::unbounded();
+        let bidi = super::new::<_, u32, u32, u32, u32>(Config::default(), transport);
+        let (mut client, mut server) = (bidi.client, bidi.server);
+        drop(raw);
+        assert_matches!(bidi.driver.await, Ok(()));
+        assert_matches!(client.next().await, None);
+        assert_matches!(server.next().await, None);
+        assert_matches!(
+            client.send(ClientMessage::Cancel {
+                trace_context: Default::default(),
+                request_id: 1,
+            })
+            .await,
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -161,6 +161,7 @@ impl<Item, SinkItem> Sink<SinkItem> for Channel<Item, SinkItem> {
 
 #[cfg(all(test, feature = "tokio1"))]
 mod tests {
+    use crate::util::tracing::trace;
     use crate::{
         client::{self, RpcError},
         context,
@@ -174,7 +175,6 @@ mod tests {
     use assert_matches::assert_matches;
     use futures::{prelude::*, stream};
     use std::io;
-    use tracing::trace;
 
     #[test]
     fn ensure_is_transport() {
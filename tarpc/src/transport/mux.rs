@@ -0,0 +1,821 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Multiplexes independent logical channels over one transport.
+//!
+//! Each [`SubChannel`] is a [`Transport`](super::sealed::Transport) of its own, so one client
+//! [`Channel`](crate::client::Channel) and one server
+//! [`BaseChannel`](crate::server::BaseChannel) can be created per sub-channel. Request IDs,
+//! cancellations, and in-flight limits are therefore scoped to a sub-channel, while all
+//! sub-channels share one physical connection. A sub-channel is identified on the wire by an ID
+//! chosen by the side that opens it, e.g. a fixed ID per service or per class of traffic; the
+//! first message the peer receives on an unknown ID opens the sub-channel on its side, where it is
+//! yielded by [`Incoming`].
+//!
+//! Every message is wrapped in a [`Frame`] that carries the sub-channel's ID, so both peers must
+//! use a `mux`. Sub-channels that carry different request types, such as different services, can
+//! share a connection by sending [`Envelope`](crate::serde_transport::envelope::Envelope)s.
+//!
+//! Each sub-channel has its own flow control: a peer may send at most [`Config::window`]
+//! messages on a sub-channel that the receiving side has not yet read, so a sub-channel whose
+//! reader falls behind does not stall the other sub-channels' traffic on the connection.
+//!
+//! ```rust
+//! use futures::prelude::*;
+//! use tarpc::{
+//!     client, context,
+//!     server::{self, BaseChannel, Channel},
+//!     transport::{self, mux},
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> anyhow::Result<()> {
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//!
+//! let server = mux::new(mux::Config::default(), server_transport);
+//! tokio::spawn(server.driver);
+//! tokio::spawn(server.incoming.for_each(|sub_channel| async move {
+//!     let double = server::serve(|_, x: u64| async move { Ok(x * 2) });
+//!     tokio::spawn(
+//!         BaseChannel::with_defaults(sub_channel)
+//!             .execute(double)
+//!             .for_each(|response| response),
+//!     );
+//! }));
+//!
+//! let client = mux::new(mux::Config::default(), client_transport);
+//! tokio::spawn(client.driver);
+//! let interactive = client::new(client::Config::default(), client.opener.open(0)?).spawn();
+//! let batch = client::new(client::Config::default(), client.opener.open(1)?).spawn();
+//! assert_eq!(interactive.call(context::current(), "", 1).await?, 2);
+//! assert_eq!(batch.call(context::current(), "", 2).await?, 4);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::sealed::Transport;
+use crate::util::tracing;
+use fnv::FnvHashMap;
+use futures::{channel::mpsc, prelude::*, ready, task::AtomicWaker};
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll},
+};
+
+/// A message on a multiplexed transport.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum Frame<T> {
+    /// A message on a sub-channel. The first message on a sub-channel that the receiver does not
+    /// know opens the sub-channel.
+    Message {
+        /// The ID of the sub-channel.
+        channel: u32,
+        /// The message.
+        message: T,
+    },
+    /// Permits the receiver to send `credit` more messages on a sub-channel.
+    Credit {
+        /// The ID of the sub-channel.
+        channel: u32,
+        /// The number of messages the sender has read since it last granted credit.
+        credit: u32,
+    },
+    /// Tells the receiver that the sender will neither send nor read more messages on a
+    /// sub-channel. The ID may be reused once both peers have closed the sub-channel.
+    Close {
+        /// The ID of the sub-channel.
+        channel: u32,
+    },
+}
+
+/// Settings that control the behavior of a multiplexed transport.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// The number of messages a peer may send on a sub-channel before the receiving side has
+    /// read them. Both peers must use the same window.
+    pub window: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { window: 100 }
+    }
+}
+
+/// Errors that end a multiplexed transport.
+#[derive(thiserror::Error, Debug)]
+pub enum MuxError<E> {
+    /// The underlying transport could not be read from.
+    #[error("could not read from the transport")]
+    Read(#[source] E),
+    /// The underlying transport could not be written to.
+    #[error("could not write to the transport")]
+    Write(#[source] E),
+    /// The peer sent more messages on a sub-channel than the window permits.
+    #[error("the peer exceeded the window of sub-channel {0}")]
+    WindowExceeded(u32),
+}
+
+/// Returns a multiplexed transport over `transport`. The [`Driver`] must be polled continuously
+/// or spawned for the sub-channels to make progress.
+pub fn new<T, Item, SinkItem>(
+    config: Config,
+    transport: T,
+) -> Mux<Item, SinkItem, Driver<T, Item, SinkItem>>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    let shared = Arc::new(Shared {
+        window: config.window.max(1),
+        channels: Mutex::new(Channels {
+            entries: FnvHashMap::default(),
+            closed: false,
+        }),
+    });
+    let (commands_tx, commands) = mpsc::unbounded();
+    let (opened_tx, opened) = mpsc::unbounded();
+    Mux {
+        opener: Opener {
+            shared: shared.clone(),
+            commands: commands_tx.clone(),
+        },
+        incoming: Incoming {
+            opened,
+            commands: commands_tx,
+        },
+        driver: Driver {
+            transport,
+            state: State {
+                shared,
+                commands,
+                opened: Some(opened_tx),
+                outbox: VecDeque::new(),
+            },
+        },
+    }
+}
+
+/// The handles to a multiplexed transport and the driver that drives it.
+pub struct Mux<Item, SinkItem, D> {
+    /// Opens sub-channels.
+    pub opener: Opener<Item, SinkItem>,
+    /// Yields the sub-channels opened by the peer.
+    pub incoming: Incoming<Item, SinkItem>,
+    /// Sends and receives the messages of all sub-channels. It completes once the peer closes
+    /// the connection, or once the opener, the incoming stream, and all sub-channels are dropped.
+    pub driver: D,
+}
+
+impl<Item, SinkItem, D, E> Mux<Item, SinkItem, D>
+where
+    D: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    /// Helper method to spawn the driver on the default executor. The task is named
+    /// `tarpc::transport::mux` in tokio-console; see the `tokio-console` feature.
+    #[cfg(feature = "tokio1")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
+    pub fn spawn(self) -> (Opener<Item, SinkItem>, Incoming<Item, SinkItem>) {
+        let driver = self.driver.unwrap_or_else(move |e| {
+            tracing::warn!("Connection broken: {}", crate::util::print_err(&e));
+        });
+        crate::util::spawn(format_args!("tarpc::transport::mux"), driver);
+        (self.opener, self.incoming)
+    }
+}
+
+impl<Item, SinkItem, D> fmt::Debug for Mux<Item, SinkItem, D> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Mux")
+    }
+}
+
+/// Opens sub-channels of a multiplexed transport.
+pub struct Opener<Item, SinkItem> {
+    shared: Arc<Shared<Item>>,
+    commands: mpsc::UnboundedSender<Command<SinkItem>>,
+}
+
+impl<Item, SinkItem> Opener<Item, SinkItem> {
+    /// Opens the sub-channel `id`. Fails if a sub-channel with the same ID is open, or if the
+    /// connection is closed.
+    pub fn open(&self, id: u32) -> io::Result<SubChannel<Item, SinkItem>> {
+        let mut channels = self.shared.lock();
+        if channels.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection is closed",
+            ));
+        }
+        if channels.entries.contains_key(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("sub-channel {id} is already open"),
+            ));
+        }
+        let (entry, opened) = Entry::new(id, self.shared.window);
+        channels.entries.insert(id, entry);
+        Ok(opened.into_sub_channel(self.commands.clone()))
+    }
+}
+
+impl<Item, SinkItem> Clone for Opener<Item, SinkItem> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for Opener<Item, SinkItem> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Opener")
+    }
+}
+
+/// A stream of the sub-channels opened by the peer. Once it is dropped, sub-channels opened by
+/// the peer are closed immediately.
+pub struct Incoming<Item, SinkItem> {
+    opened: mpsc::UnboundedReceiver<Opened<Item>>,
+    commands: mpsc::UnboundedSender<Command<SinkItem>>,
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem> {
+    type Item = SubChannel<Item, SinkItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let opened = ready!(self.opened.poll_next_unpin(cx));
+        Poll::Ready(opened.map(|opened| opened.into_sub_channel(self.commands.clone())))
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Incoming")
+    }
+}
+
+/// A logical channel of a multiplexed transport. Dropping it closes the sub-channel.
+pub struct SubChannel<Item, SinkItem> {
+    id: u32,
+    inbound: mpsc::UnboundedReceiver<Item>,
+    flow: Arc<Flow>,
+    commands: mpsc::UnboundedSender<Command<SinkItem>>,
+}
+
+impl<Item, SinkItem> SubChannel<Item, SinkItem> {
+    /// Returns the ID of the sub-channel.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the sub-channel is closed")
+}
+
+impl<Item, SinkItem> Stream for SubChannel<Item, SinkItem> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let message = ready!(self.inbound.poll_next_unpin(cx));
+        if message.is_some() {
+            let _ = self
+                .commands
+                .unbounded_send(Command::Grant { channel: self.id });
+        }
+        Poll::Ready(message.map(Ok))
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for SubChannel<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.flow.poll_credit() {
+            return Poll::Ready(Ok(()));
+        }
+        self.flow.sender.register(cx.waker());
+        if self.flow.poll_credit() {
+            return Poll::Ready(Ok(()));
+        }
+        if self.flow.closed.load(Ordering::Acquire) {
+            return Poll::Ready(Err(closed_error()));
+        }
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: SinkItem) -> io::Result<()> {
+        if self.flow.closed.load(Ordering::Acquire) {
+            return Err(closed_error());
+        }
+        self.flow
+            .credit
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |credit| {
+                credit.checked_sub(1)
+            })
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "start_send was called without poll_ready",
+                )
+            })?;
+        self.commands
+            .unbounded_send(Command::Send {
+                channel: self.id,
+                message,
+            })
+            .map_err(|_| closed_error())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Messages are flushed by the driver.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // The sub-channel is closed when it is dropped.
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Item, SinkItem> Drop for SubChannel<Item, SinkItem> {
+    fn drop(&mut self) {
+        let _ = self
+            .commands
+            .unbounded_send(Command::Close { channel: self.id });
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for SubChannel<Item, SinkItem> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SubChannel")
+            .field("id", &self.id)
+            .field("credit", &self.flow.credit.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Sends and receives the messages of all sub-channels of a multiplexed transport.
+#[pin_project]
+pub struct Driver<T, Item, SinkItem> {
+    #[pin]
+    transport: T,
+    state: State<Item, SinkItem>,
+}
+
+impl<T, Item, SinkItem> Driver<T, Item, SinkItem>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<()>, MuxError<T::TransportError>>> {
+        let this = self.project();
+        match ready!(this.transport.poll_next(cx)) {
+            Some(Ok(frame)) => Poll::Ready(this.state.receive(frame).map(Some)),
+            Some(Err(e)) => Poll::Ready(Err(MuxError::Read(e))),
+            None => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<()>, MuxError<T::TransportError>>> {
+        let mut this = self.project();
+        loop {
+            ready!(this.transport.as_mut().poll_ready(cx)).map_err(MuxError::Write)?;
+            let frame = match this.state.outbox.pop_front() {
+                Some(frame) => Some(frame),
+                None => match this.state.commands.poll_next_unpin(cx) {
+                    Poll::Ready(Some(command)) => this.state.command(command),
+                    Poll::Ready(None) => return Poll::Ready(Ok(None)),
+                    Poll::Pending => {
+                        ready!(this.transport.as_mut().poll_flush(cx)).map_err(MuxError::Write)?;
+                        return Poll::Pending;
+                    }
+                },
+            };
+            if let Some(frame) = frame {
+                this.transport
+                    .as_mut()
+                    .start_send(frame)
+                    .map_err(MuxError::Write)?;
+                return Poll::Ready(Ok(Some(())));
+            }
+        }
+    }
+
+    fn run(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), MuxError<T::TransportError>>> {
+        loop {
+            let read = self.as_mut().poll_read(cx)?;
+            let write = self.as_mut().poll_write(cx)?;
+            match (read, write) {
+                (Poll::Ready(None), _) => {
+                    tracing::info!("Shutdown: peer closed the connection.");
+                    return Poll::Ready(Ok(()));
+                }
+                (_, Poll::Ready(None)) => {
+                    ready!(self.as_mut().project().transport.poll_close(cx))
+                        .map_err(MuxError::Write)?;
+                    tracing::info!("Shutdown: all sub-channels were dropped.");
+                    return Poll::Ready(Ok(()));
+                }
+                (Poll::Pending, Poll::Pending) => return Poll::Pending,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<T, Item, SinkItem> Future for Driver<T, Item, SinkItem>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    type Output = Result<(), MuxError<T::TransportError>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(self.as_mut().run(cx));
+        let state = self.project().state;
+        state.shared.close_all();
+        state.opened = None;
+        Poll::Ready(result)
+    }
+}
+
+impl<T, Item, SinkItem> fmt::Debug for Driver<T, Item, SinkItem> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Driver")
+    }
+}
+
+/// The driver's state that is not pinned.
+struct State<Item, SinkItem> {
+    shared: Arc<Shared<Item>>,
+    commands: mpsc::UnboundedReceiver<Command<SinkItem>>,
+    opened: Option<mpsc::UnboundedSender<Opened<Item>>>,
+    /// Frames originated by the driver itself, which are sent before any commands.
+    outbox: VecDeque<Frame<SinkItem>>,
+}
+
+impl<Item, SinkItem> State<Item, SinkItem> {
+    fn receive<E>(&mut self, frame: Frame<Item>) -> Result<(), MuxError<E>> {
+        let mut channels = self.shared.lock();
+        match frame {
+            Frame::Message { channel, message } => {
+                let entry = match channels.entries.get_mut(&channel) {
+                    Some(entry) => entry,
+                    None => {
+                        let (mut entry, opened) = Entry::new(channel, self.shared.window);
+                        let accepted = match &self.opened {
+                            Some(incoming) => incoming.unbounded_send(opened).is_ok(),
+                            None => false,
+                        };
+                        if !accepted {
+                            tracing::debug!(channel, "RejectedSubChannel");
+                            entry.local_closed = true;
+                            self.outbox.push_back(Frame::Close { channel });
+                        }
+                        channels.entries.entry(channel).or_insert(entry)
+                    }
+                };
+                if entry.recv_window == 0 {
+                    return Err(MuxError::WindowExceeded(channel));
+                }
+                entry.recv_window -= 1;
+                if let Some(inbound) = &entry.inbound {
+                    // Fails if the sub-channel was dropped, in which case the message is discarded.
+                    let _ = inbound.unbounded_send(message);
+                }
+            }
+            Frame::Credit { channel, credit } => {
+                if let Some(entry) = channels.entries.get(&channel) {
+                    entry.flow.grant(credit);
+                }
+            }
+            Frame::Close { channel } => {
+                if let Some(entry) = channels.entries.get_mut(&channel) {
+                    entry.inbound = None;
+                    entry.flow.close();
+                    if entry.local_closed {
+                        channels.entries.remove(&channel);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, command: Command<SinkItem>) -> Option<Frame<SinkItem>> {
+        let mut channels = self.shared.lock();
+        match command {
+            Command::Send { channel, message } => match channels.entries.get(&channel) {
+                Some(entry) if !entry.remote_closed() => Some(Frame::Message { channel, message }),
+                _ => None,
+            },
+            Command::Grant { channel } => {
+                let entry = channels.entries.get_mut(&channel)?;
+                if entry.local_closed || entry.remote_closed() {
+                    return None;
+                }
+                entry.ungranted += 1;
+                if entry.ungranted < (self.shared.window / 2).max(1) {
+                    return None;
+                }
+                let credit = std::mem::take(&mut entry.ungranted);
+                entry.recv_window += credit;
+                Some(Frame::Credit { channel, credit })
+            }
+            Command::Close { channel } => {
+                let entry = channels.entries.get_mut(&channel)?;
+                if entry.local_closed {
+                    return None;
+                }
+                entry.local_closed = true;
+                if entry.remote_closed() {
+                    channels.entries.remove(&channel);
+                }
+                Some(Frame::Close { channel })
+            }
+        }
+    }
+}
+
+/// The state shared by the driver and the opener.
+struct Shared<Item> {
+    window: u32,
+    channels: Mutex<Channels<Item>>,
+}
+
+struct Channels<Item> {
+    entries: FnvHashMap<u32, Entry<Item>>,
+    /// True once the driver has completed.
+    closed: bool,
+}
+
+impl<Item> Shared<Item> {
+    fn lock(&self) -> MutexGuard<'_, Channels<Item>> {
+        // The state is left consistent even if a lock holder panics.
+        self.channels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close_all(&self) {
+        let mut channels = self.lock();
+        channels.closed = true;
+        for (_, entry) in channels.entries.drain() {
+            entry.flow.close();
+        }
+    }
+}
+
+/// The driver's view of a sub-channel.
+struct Entry<Item> {
+    /// Delivers messages to the sub-channel; `None` once the peer has closed the sub-channel.
+    inbound: Option<mpsc::UnboundedSender<Item>>,
+    flow: Arc<Flow>,
+    /// The number of messages the peer may still send.
+    recv_window: u32,
+    /// The number of messages read since credit was last granted to the peer.
+    ungranted: u32,
+    /// True once the local side has closed the sub-channel.
+    local_closed: bool,
+}
+
+impl<Item> Entry<Item> {
+    fn new(id: u32, window: u32) -> (Self, Opened<Item>) {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let flow = Arc::new(Flow {
+            credit: AtomicU32::new(window),
+            closed: AtomicBool::new(false),
+            sender: AtomicWaker::new(),
+        });
+        let entry = Entry {
+            inbound: Some(inbound_tx),
+            flow: flow.clone(),
+            recv_window: window,
+            ungranted: 0,
+            local_closed: false,
+        };
+        (entry, Opened { id, inbound, flow })
+    }
+
+    fn remote_closed(&self) -> bool {
+        self.inbound.is_none()
+    }
+}
+
+/// A sub-channel that has been registered with the driver.
+struct Opened<Item> {
+    id: u32,
+    inbound: mpsc::UnboundedReceiver<Item>,
+    flow: Arc<Flow>,
+}
+
+impl<Item> Opened<Item> {
+    fn into_sub_channel<SinkItem>(
+        self,
+        commands: mpsc::UnboundedSender<Command<SinkItem>>,
+    ) -> SubChannel<Item, SinkItem> {
+        SubChannel {
+            id: self.id,
+            inbound: self.inbound,
+            flow: self.flow,
+            commands,
+        }
+    }
+}
+
+/// The number of messages a sub-channel may send.
+#[derive(Debug)]
+struct Flow {
+    credit: AtomicU32,
+    closed: AtomicBool,
+    sender: AtomicWaker,
+}
+
+impl Flow {
+    fn poll_credit(&self) -> bool {
+        !self.closed.load(Ordering::Acquire) && self.credit.load(Ordering::Acquire) > 0
+    }
+
+    fn grant(&self, credit: u32) {
+        self.credit.fetch_add(credit, Ordering::AcqRel);
+        self.sender.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.sender.wake();
+    }
+}
+
+/// A request from a sub-channel to its driver.
+enum Command<SinkItem> {
+    Send { channel: u32, message: SinkItem },
+    Grant { channel: u32 },
+    Close { channel: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    // Some tests drive a spawned dispatch, which requires tokio1.
+    #![cfg_attr(not(feature = "tokio1"), allow(unused_imports))]
+
+    use super::{Config, Frame, MuxError};
+    use crate::{
+        client, context,
+        server::{self, BaseChannel, Channel},
+        transport,
+    };
+    use assert_matches::assert_matches;
+    use futures::{prelude::*, task::noop_waker_ref};
+    use std::task::{Context, Poll};
+
+    type Transport<Item, SinkItem> =
+        transport::channel::UnboundedChannel<Frame<Item>, Frame<SinkItem>>;
+
+    fn config(window: u32) -> Config {
+        Config { window }
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn clients_share_a_connection() {
+        let (client_transport, server_transport) = transport::channel::unbounded();
+
+        let server = super::new(config(4), server_transport);
+        tokio::spawn(server.driver);
+        tokio::spawn(server.incoming.for_each(|sub_channel| async move {
+            let id = u64::from(sub_channel.id());
+            let serve = server::serve(move |_, x: u64| async move { Ok((id, x)) });
+            tokio::spawn(
+                BaseChannel::with_defaults(sub_channel)
+                    .execute(serve)
+                    .for_each(|response| response),
+            );
+        }));
+
+        let client = super::new(config(4), client_transport);
+        tokio::spawn(client.driver);
+        let first = client::new(client::Config::default(), client.opener.open(1).unwrap()).spawn();
+        let second = client::new(client::Config::default(), client.opener.open(2).unwrap()).spawn();
+        assert_matches!(client.opener.open(1), Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists);
+
+        // More calls than the window on each sub-channel, so that credit must be granted.
+        for x in 0..10 {
+            let (a, b) = futures::join!(
+                first.call(context::current(), "", x),
+                second.call(context::current(), "", x)
+            );
+            assert_eq!(a.unwrap(), (1, x));
+            assert_eq!(b.unwrap(), (2, x));
+        }
+    }
+
+    #[tokio::test]
+    async fn unread_sub_channel_does_not_block_others() {
+        let (client_transport, server_transport): (Transport<u32, u32>, _) =
+            transport::channel::unbounded();
+        let client = super::new(config(2), client_transport);
+        let server = super::new(config(2), server_transport);
+        tokio::spawn(client.driver);
+        tokio::spawn(server.driver);
+        let mut incoming = server.incoming;
+
+        let mut slow = client.opener.open(1).unwrap();
+        slow.send(1).await.unwrap();
+        slow.send(2).await.unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_matches!(slow.poll_ready_unpin(&mut cx), Poll::Pending);
+
+        let mut fast = client.opener.open(2).unwrap();
+        let mut slow_peer = incoming.next().await.unwrap();
+        assert_eq!(slow_peer.id(), 1);
+        let send = async {
+            for x in 0..10 {
+                fast.send(x).await.unwrap();
+            }
+        };
+        let receive = async {
+            let fast_peer = incoming.next().await.unwrap();
+            assert_eq!(fast_peer.id(), 2);
+            fast_peer
+                .take(10)
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await
+        };
+        let ((), received) = futures::join!(send, receive);
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+
+        // Reading from the slow sub-channel grants credit again.
+        assert_eq!(slow_peer.next().await.unwrap().unwrap(), 1);
+        slow.send(3).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_sub_channel_ends_peer_stream() {
+        let (client_transport, server_transport): (Transport<u32, u32>, _) =
+            transport::channel::unbounded();
+        let client = super::new(config(2), client_transport);
+        let server = super::new(config(2), server_transport);
+        tokio::spawn(client.driver);
+        tokio::spawn(server.driver);
+        let mut incoming = server.incoming;
+
+        let mut sub_channel = client.opener.open(7).unwrap();
+        sub_channel.send(1).await.unwrap();
+        let mut peer = incoming.next().await.unwrap();
+        drop(sub_channel);
+        assert_eq!(peer.next().await.unwrap().unwrap(), 1);
+        assert!(peer.next().await.is_none());
+        assert_matches!(peer.send(2).await, Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe);
+
+        // Once both sides have closed the sub-channel, its ID can be reused.
+        drop(peer);
+        let mut reopened = loop {
+            match client.opener.open(7) {
+                Ok(sub_channel) => break sub_channel,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        reopened.send(3).await.unwrap();
+        let mut peer = incoming.next().await.unwrap();
+        assert_eq!(peer.next().await.unwrap().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn peer_exceeding_window_is_an_error() {
+        let (mut raw, transport): (Transport<u32, u32>, _) = transport::channel::unbounded();
+        let mux = super::new::<_, u32, u32>(config(1), transport);
+        let _incoming = mux.incoming;
+        for message in 0..2 {
+            raw.send(Frame::Message {
+                channel: 0,
+                message,
+            })
+            .await
+            .unwrap();
+        }
+        assert_matches!(mux.driver.await, Err(MuxError::WindowExceeded(0)));
+        assert_matches!(mux.opener.open(0), Err(e) if e.kind() == std::io::ErrorKind::NotConnected);
+    }
+}
@@ -6,6 +6,7 @@
 
 use std::{
     collections::HashMap,
+    error::Error,
     hash::{BuildHasher, Hash},
     time::{Duration, SystemTime},
 };
@@ -14,6 +15,11 @@ use std::{
 #[cfg_attr(docsrs, doc(cfg(feature = "serde1")))]
 pub mod serde;
 
+#[cfg(feature = "tracing")]
+pub(crate) use ::tracing;
+#[cfg(not(feature = "tracing"))]
+pub(crate) mod tracing;
+
 /// Extension trait for [SystemTimes](SystemTime) in the future, i.e. deadlines.
 pub trait TimeUntil {
     /// How much time from now until this time is reached.
@@ -22,7 +28,39 @@ pub trait TimeUntil {
 
 impl TimeUntil for SystemTime {
     fn time_until(&self) -> Duration {
-        self.duration_since(SystemTime::now()).unwrap_or_default()
+        self.duration_since(crate::clock::now()).unwrap_or_default()
+    }
+}
+
+/// Formats an error along with its chain of sources, separated by colons.
+pub fn print_err(e: &(dyn Error + 'static)) -> String {
+    std::iter::successors(Some(e), |&e| e.source())
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Spawns `future` on the current tokio runtime. When built with the `tokio-console` feature and
+/// `--cfg tokio_unstable`, the task is named `name`, so that tokio-console can tell tarpc's tasks
+/// apart; otherwise, the name is ignored.
+#[cfg(feature = "tokio1")]
+pub(crate) fn spawn<F>(
+    name: std::fmt::Arguments<'_>,
+    future: F,
+) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(tokio_unstable, feature = "tokio-console"))]
+    return tokio::task::Builder::new()
+        .name(&name.to_string())
+        .spawn(future)
+        .expect("spawn must be called from within a tokio runtime");
+    #[cfg(not(all(tokio_unstable, feature = "tokio-console")))]
+    {
+        let _ = name;
+        tokio::spawn(future)
     }
 }
 
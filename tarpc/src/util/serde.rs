@@ -4,20 +4,12 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::io;
 
-/// Serializes [`io::ErrorKind`] as a `u32`.
-#[allow(clippy::trivially_copy_pass_by_ref)] // Exact fn signature required by serde derive
-pub fn serialize_io_error_kind_as_u32<S>(
-    kind: &io::ErrorKind,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
+/// Converts [`io::ErrorKind`] to the `u32` it is serialized as.
+pub fn io_error_kind_to_u32(kind: io::ErrorKind) -> u32 {
     use std::io::ErrorKind::*;
-    match *kind {
+    match kind {
         NotFound => 0,
         PermissionDenied => 1,
         ConnectionRefused => 2,
@@ -38,18 +30,12 @@ where
         UnexpectedEof => 17,
         _ => 16,
     }
-    .serialize(serializer)
 }
 
-/// Deserializes [`io::ErrorKind`] from a `u32`.
-pub fn deserialize_io_error_kind_from_u32<'de, D>(
-    deserializer: D,
-) -> Result<io::ErrorKind, D::Error>
-where
-    D: Deserializer<'de>,
-{
+/// Converts the `u32` that an [`io::ErrorKind`] is serialized as back to the kind.
+pub fn io_error_kind_from_u32(kind: u32) -> io::ErrorKind {
     use std::io::ErrorKind::*;
-    Ok(match u32::deserialize(deserializer)? {
+    match kind {
         0 => NotFound,
         1 => PermissionDenied,
         2 => ConnectionRefused,
@@ -69,5 +55,5 @@ where
         16 => Other,
         17 => UnexpectedEof,
         _ => Other,
-    })
+    }
 }
@@ -0,0 +1,109 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A stand-in for the parts of the `tracing` crate that tarpc uses, for builds without the
+//! `tracing` feature. Spans record nothing, and events compile to nothing.
+
+// Which of the stand-ins are used depends on the other features enabled.
+#![allow(dead_code)]
+
+/// A span that is never entered by a subscriber and records nothing.
+#[derive(Clone, Debug, Default)]
+pub struct Span;
+
+/// Returned by [`Span::enter`]; exits nothing when dropped.
+#[derive(Debug)]
+pub struct Entered;
+
+impl Span {
+    /// Returns a disabled span.
+    pub fn current() -> Self {
+        Span
+    }
+
+    /// Returns a disabled span.
+    pub fn none() -> Self {
+        Span
+    }
+
+    /// Does nothing.
+    pub fn enter(&self) -> Entered {
+        Entered
+    }
+
+    /// Discards `value`.
+    pub fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+}
+
+/// Stand-ins for `tracing::field`.
+pub mod field {
+    /// A field without a value.
+    #[derive(Debug)]
+    pub struct Empty;
+
+    /// Returns `value` unchanged.
+    pub fn display<T>(value: T) -> T {
+        value
+    }
+
+    /// Returns `value` unchanged.
+    pub fn debug<T>(value: T) -> T {
+        value
+    }
+}
+
+/// Stand-in for `tracing::Level`.
+#[derive(Debug)]
+pub struct Level;
+
+impl Level {
+    pub const DEBUG: Level = Level;
+}
+
+/// Stand-in for `tracing::instrument`.
+pub mod instrument {
+    use super::Span;
+
+    /// Leaves futures uninstrumented.
+    pub trait Instrument: Sized {
+        /// Returns `self` unchanged.
+        fn instrument(self, _span: Span) -> Self {
+            self
+        }
+    }
+
+    impl<T> Instrument for T {}
+}
+
+macro_rules! event {
+    ($($arg:tt)*) => {
+        ()
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! enabled {
+    ($($arg:tt)*) => {
+        false
+    };
+}
+
+macro_rules! span {
+    ($($arg:tt)*) => {
+        $crate::util::tracing::Span::none()
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use enabled;
+pub(crate) use event as debug;
+pub(crate) use event as error;
+pub(crate) use event as info;
+pub(crate) use event as trace;
+pub(crate) use event as warn;
+pub(crate) use span as info_span;
@@ -0,0 +1,507 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Defines how messages are encoded on the wire.
+//!
+//! Messages are encoded as tarpc 0.34 encodes them, so that peers on either version understand
+//! each other. Positional formats such as bincode can neither skip nor default a field they don't
+//! know, so additions to the protocol are not carried by new fields of existing messages. They are
+//! carried by variants appended after the existing ones instead, and a message takes its new form
+//! only when it uses an addition that its old form can't express. A peer on an older version thus
+//! decodes every message except those of features it doesn't support.
+//!
+//! Requests that set fields added since 0.34, such as [`Request::one_way`], are sent as
+//! `ExtendedRequest`, the request followed by its [`RequestExtension`]. The items of
+//! [streamed](Request::streamed) requests are sent as `Item`s, a message added since 0.34 too.
+//! Likewise, the message of a [`Response`] with [more](Response::more) to follow is sent as an
+//! `OkMore` or `ErrMore`, variants appended after those of `Result`, and a
+//! [resource exhausted](ServerError::resource_exhausted) error is sent with a kind number of its
+//! own, which older peers read as [`Other`](std::io::ErrorKind::Other).
+
+use crate::{
+    trace,
+    util::serde::{io_error_kind_from_u32, io_error_kind_to_u32},
+    ClientMessage, CloseReason, Request, Response, ServerError,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The fields of a [`Request`] added since 0.34.
+#[derive(Serialize, Deserialize)]
+struct RequestExtension {
+    one_way: bool,
+    streamed: bool,
+}
+
+impl RequestExtension {
+    fn of<T>(request: &Request<T>) -> Option<Self> {
+        (request.one_way || request.streamed).then(|| RequestExtension {
+            one_way: request.one_way,
+            streamed: request.streamed,
+        })
+    }
+
+    fn apply<T>(self, request: &mut Request<T>) {
+        request.one_way = self.one_way;
+        request.streamed = self.streamed;
+    }
+}
+
+/// A [`ClientMessage`] as it is serialized.
+#[derive(Serialize)]
+#[serde(rename = "ClientMessage")]
+enum ClientMessageRef<'a, T> {
+    Request(&'a Request<T>),
+    Cancel {
+        trace_context: &'a trace::Context,
+        request_id: u64,
+    },
+    Close {
+        reason: CloseReason,
+    },
+    Item {
+        request_id: u64,
+        item: &'a Option<T>,
+    },
+    ExtendedRequest(&'a Request<T>, RequestExtension),
+}
+
+/// A [`ClientMessage`] as it is deserialized.
+#[derive(Deserialize)]
+#[serde(rename = "ClientMessage")]
+enum WireClientMessage<T> {
+    Request(Request<T>),
+    Cancel {
+        #[serde(default)]
+        trace_context: trace::Context,
+        request_id: u64,
+    },
+    Close {
+        reason: CloseReason,
+    },
+    Item {
+        request_id: u64,
+        item: Option<T>,
+    },
+    ExtendedRequest(Request<T>, RequestExtension),
+}
+
+impl<T: Serialize> Serialize for ClientMessage<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ClientMessage::Request(request) => match RequestExtension::of(request) {
+                Some(extension) => ClientMessageRef::ExtendedRequest(request, extension),
+                None => ClientMessageRef::Request(request),
+            },
+            ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => ClientMessageRef::Cancel {
+                trace_context,
+                request_id: *request_id,
+            },
+            ClientMessage::Close { reason } => ClientMessageRef::Close { reason: *reason },
+            ClientMessage::Item { request_id, item } => ClientMessageRef::Item {
+                request_id: *request_id,
+                item,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for ClientMessage<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match WireClientMessage::deserialize(deserializer)? {
+            WireClientMessage::Request(request) => ClientMessage::Request(request),
+            WireClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            },
+            WireClientMessage::Close { reason } => ClientMessage::Close { reason },
+            WireClientMessage::Item { request_id, item } => {
+                ClientMessage::Item { request_id, item }
+            }
+            WireClientMessage::ExtendedRequest(mut request, extension) => {
+                extension.apply(&mut request);
+                ClientMessage::Request(request)
+            }
+        })
+    }
+}
+
+/// A [`Response`] as it is serialized.
+#[derive(Serialize)]
+#[serde(rename = "Response")]
+struct ResponseRef<'a, T> {
+    request_id: u64,
+    message: MessageRef<'a, T>,
+}
+
+/// The message of a [`Response`] as it is serialized: the variants of `Result`, followed by those
+/// of messages with more to follow.
+#[derive(Serialize)]
+#[serde(rename = "Result")]
+enum MessageRef<'a, T> {
+    Ok(&'a T),
+    Err(&'a ServerError),
+    OkMore(&'a T),
+    ErrMore(&'a ServerError),
+}
+
+/// A [`Response`] as it is deserialized.
+#[derive(Deserialize)]
+#[serde(rename = "Response")]
+struct WireResponse<T> {
+    request_id: u64,
+    message: WireMessage<T>,
+}
+
+/// The message of a [`Response`] as it is deserialized.
+#[derive(Deserialize)]
+#[serde(rename = "Result")]
+enum WireMessage<T> {
+    Ok(T),
+    Err(ServerError),
+    OkMore(T),
+    ErrMore(ServerError),
+}
+
+impl<T: Serialize> Serialize for Response<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ResponseRef {
+            request_id: self.request_id,
+            message: match (&self.message, self.more) {
+                (Ok(message), false) => MessageRef::Ok(message),
+                (Err(error), false) => MessageRef::Err(error),
+                (Ok(message), true) => MessageRef::OkMore(message),
+                (Err(error), true) => MessageRef::ErrMore(error),
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Response<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let WireResponse {
+            request_id,
+            message,
+        } = WireResponse::deserialize(deserializer)?;
+        let (message, more) = match message {
+            WireMessage::Ok(message) => (Ok(message), false),
+            WireMessage::Err(error) => (Err(error), false),
+            WireMessage::OkMore(message) => (Ok(message), true),
+            WireMessage::ErrMore(error) => (Err(error), true),
+        };
+        Ok(Response {
+            request_id,
+            message,
+            more,
+        })
+    }
+}
+
+/// The kind number of [resource exhausted](ServerError::resource_exhausted) errors, which follows
+/// those of [`io_error_kind_to_u32`].
+const RESOURCE_EXHAUSTED: u32 = 18;
+
+/// A [`ServerError`] as it is serialized.
+#[derive(Serialize)]
+#[serde(rename = "ServerError")]
+struct ServerErrorRef<'a> {
+    kind: u32,
+    detail: &'a str,
+}
+
+/// A [`ServerError`] as it is deserialized.
+#[derive(Deserialize)]
+#[serde(rename = "ServerError")]
+struct WireServerError {
+    kind: u32,
+    detail: String,
+}
+
+impl Serialize for ServerError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ServerErrorRef {
+            kind: if self.is_resource_exhausted() {
+                RESOURCE_EXHAUSTED
+            } else {
+                io_error_kind_to_u32(self.kind)
+            },
+            detail: &self.detail,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let WireServerError { kind, detail } = WireServerError::deserialize(deserializer)?;
+        Ok(match kind {
+            RESOURCE_EXHAUSTED => ServerError::resource_exhausted(detail),
+            kind => ServerError::new(io_error_kind_from_u32(kind), detail),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        context, trace, util::serde::io_error_kind_from_u32, ClientMessage, Request, Response,
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+    use serde::{Deserialize, Serialize};
+    use std::io;
+
+    /// The messages of tarpc 0.34.
+    mod v0_34 {
+        use crate::{context, trace};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub enum ClientMessage<T> {
+            Request(Request<T>),
+            Cancel {
+                #[serde(default)]
+                trace_context: trace::Context,
+                request_id: u64,
+            },
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Request<T> {
+            pub context: context::Context,
+            pub id: u64,
+            pub message: T,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Response<T> {
+            pub request_id: u64,
+            pub message: Result<T, ServerError>,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct ServerError {
+            pub kind: u32,
+            pub detail: String,
+        }
+    }
+
+    fn request(one_way: bool, streamed: bool) -> ClientMessage<String> {
+        ClientMessage::Request(Request {
+            context: context::current(),
+            id: 7,
+            message: "hello".into(),
+            one_way,
+            streamed,
+        })
+    }
+
+    fn v0_34_request() -> v0_34::ClientMessage<String> {
+        v0_34::ClientMessage::Request(v0_34::Request {
+            context: context::current(),
+            id: 7,
+            message: "hello".into(),
+        })
+    }
+
+    fn bincode_round_trip<In: Serialize, Out: for<'de> Deserialize<'de>>(
+        message: &In,
+    ) -> bincode::Result<Out> {
+        bincode::deserialize(&bincode::serialize(message)?)
+    }
+
+    #[test]
+    fn requests_are_compatible_with_v0_34() {
+        assert_matches!(
+            bincode_round_trip(&request(false, false)),
+            Ok(v0_34::ClientMessage::<String>::Request(v0_34::Request {
+                id: 7,
+                ..
+            }))
+        );
+        assert_matches!(
+            bincode_round_trip(&v0_34_request()),
+            Ok(ClientMessage::<String>::Request(Request {
+                id: 7,
+                one_way: false,
+                streamed: false,
+                ..
+            }))
+        );
+        let cancel = v0_34::ClientMessage::<String>::Cancel {
+            trace_context: trace::Context::default(),
+            request_id: 7,
+        };
+        assert_matches!(
+            bincode_round_trip(&cancel),
+            Ok(ClientMessage::<String>::Cancel { request_id: 7, .. })
+        );
+    }
+
+    #[test]
+    fn one_way_requests_round_trip() {
+        assert_matches!(
+            bincode_round_trip(&request(true, false)),
+            Ok(ClientMessage::<String>::Request(Request {
+                id: 7,
+                one_way: true,
+                streamed: false,
+                ..
+            }))
+        );
+        let json = serde_json::to_vec(&request(true, false)).unwrap();
+        assert_matches!(
+            serde_json::from_slice(&json),
+            Ok(ClientMessage::<String>::Request(Request {
+                id: 7,
+                one_way: true,
+                ..
+            }))
+        );
+        // Older peers reject them rather than mistaking them for requests expecting a response.
+        assert_matches!(
+            bincode_round_trip::<_, v0_34::ClientMessage<String>>(&request(true, false)),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn streamed_requests_round_trip() {
+        assert_matches!(
+            bincode_round_trip(&request(false, true)),
+            Ok(ClientMessage::<String>::Request(Request {
+                id: 7,
+                one_way: false,
+                streamed: true,
+                ..
+            }))
+        );
+        let item = ClientMessage::Item {
+            request_id: 7,
+            item: Some("chunk".to_string()),
+        };
+        assert_matches!(
+            bincode_round_trip(&item),
+            Ok(ClientMessage::<String>::Item { request_id: 7, item: Some(item) }) if item == "chunk"
+        );
+        assert_matches!(
+            bincode_round_trip::<_, v0_34::ClientMessage<String>>(&request(false, true)),
+            Err(_)
+        );
+    }
+
+    fn response(more: bool) -> Response<String> {
+        Response {
+            request_id: 7,
+            message: Ok("hello".into()),
+            more,
+        }
+    }
+
+    #[test]
+    fn responses_are_compatible_with_v0_34() {
+        assert_matches!(
+            bincode_round_trip(&response(false)),
+            Ok(v0_34::Response::<String> { request_id: 7, message: Ok(message) }) if message == "hello"
+        );
+        let response = v0_34::Response::<String> {
+            request_id: 7,
+            message: Ok("hello".into()),
+        };
+        assert_matches!(
+            bincode_round_trip(&response),
+            Ok(Response::<String> {
+                request_id: 7,
+                message: Ok(_),
+                more: false
+            })
+        );
+    }
+
+    #[test]
+    fn responses_with_more_round_trip() {
+        assert_matches!(
+            bincode_round_trip(&response(true)),
+            Ok(Response::<String> {
+                request_id: 7,
+                message: Ok(_),
+                more: true
+            })
+        );
+        let json = serde_json::to_vec(&response(true)).unwrap();
+        assert_matches!(
+            serde_json::from_slice(&json),
+            Ok(Response::<String> {
+                request_id: 7,
+                message: Ok(_),
+                more: true
+            })
+        );
+        // Older clients reject them rather than mistaking them for the final response.
+        assert_matches!(
+            bincode_round_trip::<_, v0_34::Response<String>>(&response(true)),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn errors_are_compatible_with_v0_34() {
+        let response = |error| Response::<String> {
+            request_id: 7,
+            message: Err(error),
+            more: false,
+        };
+        assert_matches!(
+            bincode_round_trip(&response(ServerError::new(
+                io::ErrorKind::WouldBlock,
+                "busy".into()
+            ))),
+            Ok(v0_34::Response::<String> {
+                message: Err(v0_34::ServerError { kind: 10, .. }),
+                ..
+            })
+        );
+        // 0.34 peers read resource exhausted errors, of an unknown kind number, as errors of kind
+        // Other.
+        assert_matches!(
+            bincode_round_trip(&response(ServerError::resource_exhausted("busy".into()))),
+            Ok(v0_34::Response::<String> {
+                message: Err(v0_34::ServerError { kind, .. }),
+                ..
+            }) if io_error_kind_from_u32(kind) == io::ErrorKind::Other
+        );
+        let response = v0_34::Response::<String> {
+            request_id: 7,
+            message: Err(v0_34::ServerError {
+                kind: 10,
+                detail: "busy".into(),
+            }),
+        };
+        assert_matches!(
+            bincode_round_trip(&response),
+            Ok(Response::<String> { message: Err(e), .. })
+                if e.kind == io::ErrorKind::WouldBlock && !e.is_resource_exhausted()
+        );
+    }
+
+    #[test]
+    fn resource_exhausted_errors_round_trip() {
+        let e = ServerError::resource_exhausted("busy".into());
+        assert_matches!(
+            bincode_round_trip::<_, ServerError>(&e),
+            Ok(e) if e.kind == io::ErrorKind::WouldBlock && e.is_resource_exhausted()
+        );
+        let e: ServerError = serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap();
+        assert!(e.is_resource_exhausted());
+    }
+}
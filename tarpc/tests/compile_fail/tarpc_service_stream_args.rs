@@ -0,0 +1,6 @@
+#[tarpc::service]
+trait World {
+    async fn zip(left: impl Stream<Item = u8>, right: impl Stream<Item = u8>);
+}
+
+fn main() {}
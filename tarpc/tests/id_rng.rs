@@ -0,0 +1,37 @@
+//! The span ID generator is shared by the whole process, so this test has a binary of its own:
+//! tests running alongside it would draw IDs from the generator it installs.
+
+use futures::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+use tarpc::{client, context, trace, transport::channel, ClientMessage, Response};
+
+/// Sends two requests and returns the span IDs they were sent with.
+async fn sent_span_ids() -> [trace::SpanId; 2] {
+    let (tx, mut rx) = channel::unbounded::<Response<()>, ClientMessage<()>>();
+    let client = client::new(client::Config::default(), tx).spawn();
+    let mut span_ids = [trace::SpanId::default(); 2];
+    for span_id in &mut span_ids {
+        let client = client.clone();
+        tokio::spawn(async move { client.call(context::current(), "", ()).await });
+        match rx.next().await {
+            Some(Ok(ClientMessage::Request(request))) => {
+                *span_id = request.context.trace_context.span_id
+            }
+            message => panic!("expected a request, got {message:?}"),
+        }
+    }
+    span_ids
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn seeded_id_rng_is_deterministic() {
+    trace::set_id_rng(StdRng::seed_from_u64(7));
+    let first = sent_span_ids().await;
+    trace::set_id_rng(StdRng::seed_from_u64(7));
+    let second = sent_span_ids().await;
+    assert!(trace::take_id_rng().is_some());
+
+    assert_eq!(first, second);
+    assert_ne!(first[0], first[1]);
+    assert!(trace::take_id_rng().is_none());
+}
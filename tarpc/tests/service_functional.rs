@@ -57,9 +57,6 @@ async fn dropped_channel_aborts_in_flight_requests() -> anyhow::Result<()> {
     #[derive(Clone)]
     struct LoopServer;
 
-    #[derive(Debug)]
-    struct AllHandlersComplete;
-
     impl Loop for LoopServer {
         async fn r#loop(self, _: context::Context) {
             loop {
@@ -273,3 +270,293 @@ async fn counter() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn streaming_method() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Countdown {
+        async fn countdown(from: u32) -> impl Stream<Item = u32>;
+        async fn len(from: u32) -> usize;
+    }
+
+    #[derive(Clone)]
+    struct CountdownService;
+
+    impl Countdown for CountdownService {
+        async fn countdown(self, _: context::Context, from: u32) -> impl Stream<Item = u32> {
+            stream::iter((0..from).rev())
+        }
+
+        async fn len(self, _: context::Context, from: u32) -> usize {
+            from as usize
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .execute(CountdownService.serve())
+            .for_each(spawn),
+    );
+
+    let client = CountdownClient::new(client::Config::default(), tx).spawn();
+    let items = client.countdown(context::current(), 3).await?;
+    assert_eq!(items.try_collect::<Vec<_>>().await?, [2, 1, 0]);
+    let items = client.countdown(context::current(), 0).await?;
+    assert!(items.try_collect::<Vec<_>>().await?.is_empty());
+    assert_matches!(client.len(context::current(), 3).await, Ok(3));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_streaming_method() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Summer {
+        async fn sum(offset: u64, numbers: impl Stream<Item = u32>) -> u64;
+    }
+
+    #[derive(Clone)]
+    struct SummerService;
+
+    impl Summer for SummerService {
+        async fn sum(
+            self,
+            _: context::Context,
+            offset: u64,
+            numbers: impl Stream<Item = u32>,
+        ) -> u64 {
+            numbers
+                .fold(offset, |sum, n| async move { sum + u64::from(n) })
+                .await
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .execute(SummerService.serve())
+            .for_each(spawn),
+    );
+
+    let client = SummerClient::new(client::Config::default(), tx).spawn();
+    let (first, second) = future::join(
+        client.sum(context::current(), 10, stream::iter(1..=3)),
+        client.sum(context::current(), 0, stream::iter(vec![5; 100])),
+    )
+    .await;
+    assert_matches!(first, Ok(16));
+    assert_matches!(second, Ok(500));
+    assert_matches!(
+        client.sum(context::current(), 1, stream::empty()).await,
+        Ok(1)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplex_method() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Shouter {
+        async fn shout(
+            suffix: String,
+            lines: impl Stream<Item = String>,
+        ) -> impl Stream<Item = String>;
+    }
+
+    #[derive(Clone)]
+    struct ShouterService;
+
+    impl Shouter for ShouterService {
+        async fn shout(
+            self,
+            _: context::Context,
+            suffix: String,
+            lines: impl Stream<Item = String>,
+        ) -> impl Stream<Item = String> {
+            lines.map(move |line| line.to_uppercase() + &suffix)
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .execute(ShouterService.serve())
+            .for_each(spawn),
+    );
+
+    let client = ShouterClient::new(client::Config::default(), tx).spawn();
+    let (lines_tx, lines) = futures::channel::mpsc::unbounded();
+    let shouts = client.shout(context::current(), "!".into(), lines).await?;
+    futures::pin_mut!(shouts);
+    lines_tx.unbounded_send("hi".to_string())?;
+    assert_eq!(shouts.next().await.transpose()?, Some("HI!".to_string()));
+    lines_tx.unbounded_send("bye".to_string())?;
+    assert_eq!(shouts.next().await.transpose()?, Some("BYE!".to_string()));
+    drop(lines_tx);
+    assert_matches!(shouts.next().await, None);
+
+    let shouts = client
+        .shout(
+            context::current(),
+            "?".into(),
+            stream::iter(["a", "b"].map(String::from)),
+        )
+        .await?;
+    assert_eq!(shouts.try_collect::<Vec<_>>().await?, ["A?", "B?"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sink_for_method() -> anyhow::Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    #[tarpc::service]
+    trait Store {
+        async fn put(key: String, value: u64);
+        async fn ping();
+    }
+
+    #[derive(Clone)]
+    struct StoreService(Arc<Mutex<Vec<(String, u64)>>>);
+
+    impl Store for StoreService {
+        async fn put(self, _: context::Context, key: String, value: u64) {
+            self.0.lock().unwrap().push((key, value));
+        }
+
+        async fn ping(self, _: context::Context) {}
+    }
+
+    let (tx, rx) = channel::unbounded();
+    let rows = Arc::new(Mutex::new(vec![]));
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .execute(StoreService(rows.clone()).serve())
+            .for_each(spawn),
+    );
+
+    let client = StoreClient::new(client::Config::default(), tx).spawn();
+    let mut sink = client.sink_for::<store_methods::Put>();
+    assert_eq!(
+        sink.credits(),
+        client::Config::default().max_in_flight_requests()
+    );
+    let mut puts = stream::iter(0..50).map(|i| Ok((context::current(), (i.to_string(), i))));
+    sink.send_all(&mut puts).await?;
+    sink.close().await?;
+    let mut rows = rows.lock().unwrap().clone();
+    rows.sort_by_key(|(_, value)| *value);
+    assert_eq!(rows.len(), 50);
+    assert_eq!(rows[49], ("49".to_string(), 49));
+
+    client
+        .sink_for::<store_methods::Ping>()
+        .send((context::current(), ()))
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn service_group() -> anyhow::Result<()> {
+    use tarpc::{client::stub::member::Member, server::Serve};
+
+    mod counter {
+        #[tarpc::service]
+        pub trait Counter {
+            async fn count(s: String) -> usize;
+        }
+    }
+
+    #[derive(Clone)]
+    struct CounterServer;
+
+    impl counter::Counter for CounterServer {
+        async fn count(self, _: context::Context, s: String) -> usize {
+            s.len()
+        }
+    }
+
+    tarpc::service_group! {
+        struct Services {
+            service: Service,
+            counter: counter::Counter,
+        }
+    }
+
+    let services = Services {
+        service: Service::serve(Server),
+        counter: counter::Counter::serve(CounterServer),
+    };
+    let request = ServicesRequest::from(counter::CounterRequest::Count { s: "".into() });
+    assert_eq!(services.method(&request), Some("Counter.count"));
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .execute(services)
+            .for_each(spawn),
+    );
+    let channel = client::new(client::Config::default(), tx).spawn();
+    let service = ServiceClient::from(Member::new(channel.clone()));
+    let counter = counter::CounterClient::from(Member::new(channel));
+
+    let (sum, count) = join!(
+        service.add(context::current(), 1, 2),
+        counter.count(context::current(), "four".into())
+    );
+    assert_eq!(sum?, 3);
+    assert_eq!(count?, 4);
+    assert_eq!(
+        service.hey(context::current(), "Tim".into()).await?,
+        "Hey, Tim."
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_drains_in_flight_requests() -> anyhow::Result<()> {
+    use tarpc::server::{incoming::spawn_incoming, shutdown::Shutdown};
+
+    let (tx, rx) = channel::unbounded();
+    let shutdown = Shutdown::new();
+    let incoming = stream::once(ready(BaseChannel::with_defaults(rx)))
+        .chain(stream::pending())
+        .with_shutdown(shutdown.clone())
+        .execute(tarpc::server::serve(|_, i: i32| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(i + 1)
+        }));
+    tokio::spawn(spawn_incoming(incoming));
+    let client = client::new(client::Config::default(), tx).spawn();
+
+    let call = tokio::spawn(async move { client.call(context::current(), "AddOne", 1).await });
+    // Let the request arrive before shutting down.
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(shutdown.open_channels(), 1);
+    shutdown.shutdown(Duration::from_secs(10)).await?;
+    assert_eq!(shutdown.open_channels(), 0);
+    assert_eq!(call.await??, 2);
+
+    Ok(())
+}
+
+#[test]
+fn schema() {
+    use tarpc::schema::{MethodSchema, ServiceSchema};
+
+    assert_eq!(
+        ServiceRequest::schema(),
+        ServiceSchema::new(
+            "Service",
+            vec![
+                MethodSchema::new(0, "add", vec![("x", "i32"), ("y", "i32")], "i32"),
+                MethodSchema::new(1, "hey", vec![("name", "String")], "String"),
+            ]
+        )
+    );
+}